@@ -0,0 +1,202 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao dispatch hot path benchmarks.
+//!
+//! $ cargo bench --features test-support
+//!
+//! The `instrument/*` benchmarks serve a request with and without the hot path
+//! instrumentation; built without it (`--no-default-features --features test-support`),
+//! both run the same code.
+
+use bao_sys::bus::BaoMmioBus;
+use bao_sys::defines::*;
+use bao_sys::hypervisor::Hypervisor;
+use bao_sys::stats::DeviceStats;
+use bao_sys::testing::MockHypervisor;
+use bao_sys::trace::{TracePhase, TraceSink};
+use bao_sys::types::{AbiEncode, BaoIoRequest, GuestAddress};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use vm_device::bus::{MmioAddress, MmioAddressOffset};
+use vm_device::MutDeviceMmio;
+use zerocopy::FromBytes;
+
+/// Device base address.
+const BASE: u64 = 0xa003e00;
+
+/// Register file of a virtio-mmio device (status register only).
+#[derive(Default)]
+struct Registers {
+    status: u32,
+}
+
+impl MutDeviceMmio for Registers {
+    fn mmio_read(&mut self, _base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        let value = match offset {
+            VIRTIO_MMIO_MAGIC_VALUE => VIRTIO_MMIO_MAGIC as u32,
+            VIRTIO_MMIO_STATUS => self.status,
+            _ => 0,
+        };
+        data.copy_from_slice(&value.to_le_bytes()[..data.len()]);
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        if offset == VIRTIO_MMIO_STATUS {
+            self.status = u32::from_le_bytes(data.try_into().unwrap());
+        }
+    }
+}
+
+fn request(op: u64, reg_off: u64, value: u64) -> BaoIoRequest {
+    BaoIoRequest {
+        virtio_id: 0,
+        reg_off,
+        addr: BASE + reg_off,
+        op,
+        value,
+        access_width: 4,
+        cpu_id: 0,
+        vcpu_id: 0,
+        ret: 0,
+    }
+}
+
+fn bus() -> BaoMmioBus {
+    let mut bus = BaoMmioBus::new();
+    bus.register(
+        GuestAddress(BASE),
+        VIRTIO_MMIO_IO_SIZE,
+        Arc::new(Mutex::new(Registers::default())),
+    )
+    .unwrap();
+    bus
+}
+
+fn bench_decode(c: &mut Criterion) {
+    let mut bytes = Vec::new();
+    request(BAO_IO_READ, VIRTIO_MMIO_STATUS, 0).encode(&mut bytes);
+
+    c.bench_function("decode/abi", |b| {
+        b.iter(|| BaoIoRequest::decode(black_box(&bytes)).unwrap())
+    });
+    c.bench_function("decode/zerocopy", |b| {
+        b.iter(|| BaoIoRequest::read_from(black_box(bytes.as_slice())).unwrap())
+    });
+}
+
+fn bench_mmio_dispatch(c: &mut Criterion) {
+    let bus = bus();
+
+    c.bench_function("mmio/read", |b| {
+        let mut req = request(BAO_IO_READ, VIRTIO_MMIO_MAGIC_VALUE, 0);
+        b.iter(|| bus.handle_request(black_box(&mut req)).unwrap())
+    });
+    c.bench_function("mmio/write", |b| {
+        let mut req = request(BAO_IO_WRITE, VIRTIO_MMIO_STATUS, 0xf);
+        b.iter(|| bus.handle_request(black_box(&mut req)).unwrap())
+    });
+}
+
+fn bench_hypervisor(c: &mut Criterion) {
+    let bus = bus();
+    let hypervisor = MockHypervisor::new();
+
+    // Fetch, dispatch and complete a request through the hypervisor interface
+    c.bench_function("hypervisor/round_trip", |b| {
+        let mut serve = |hv: &dyn Hypervisor| {
+            while let Some(mut req) = hv.next_request()? {
+                bus.handle_request(&mut req)?;
+                hv.complete_request(&req)?;
+            }
+            Ok(())
+        };
+        b.iter(|| {
+            let mut req = request(BAO_IO_READ, VIRTIO_MMIO_STATUS, 0);
+            hypervisor
+                .dispatch(black_box(&mut req), &mut serve)
+                .unwrap()
+        })
+    });
+    c.bench_function("hypervisor/notify_guest", |b| {
+        b.iter(|| hypervisor.notify_guest().unwrap())
+    });
+}
+
+/// Trace sink dropping its events (isolates the cost of the instrumentation itself).
+struct NullSink;
+
+impl TraceSink for NullSink {
+    fn span(
+        &self,
+        phase: TracePhase,
+        req: &BaoIoRequest,
+        start: Instant,
+    ) -> bao_sys::error::Result<()> {
+        black_box((phase, req, start));
+        Ok(())
+    }
+
+    fn instant(&self, phase: TracePhase, req: Option<&BaoIoRequest>) -> bao_sys::error::Result<()> {
+        black_box((phase, req));
+        Ok(())
+    }
+
+    fn finish(&self) -> bao_sys::error::Result<()> {
+        Ok(())
+    }
+}
+
+fn bench_instrumentation(c: &mut Criterion) {
+    let bus = bus();
+    let stats = DeviceStats::new("rng0", 1);
+    let sink = NullSink;
+
+    c.bench_function("instrument/baseline", |b| {
+        let mut req = request(BAO_IO_READ, VIRTIO_MMIO_STATUS, 0);
+        b.iter(|| bus.handle_request(black_box(&mut req)).unwrap())
+    });
+    c.bench_function("instrument/serve", |b| {
+        let mut req = request(BAO_IO_READ, VIRTIO_MMIO_STATUS, 0);
+        b.iter(|| {
+            let start = bao_sys::bao_now!();
+            bus.handle_request(black_box(&mut req)).unwrap();
+            bao_sys::bao_inc!(stats.mmio_reads);
+            bao_sys::bao_latency!(stats.latency, start);
+            bao_sys::bao_span!(sink, TracePhase::Dispatch, &req, start).unwrap();
+        })
+    });
+}
+
+fn bench_kick_queue(c: &mut Criterion) {
+    // Hand a kick from a queue kick receiver to the backend worker
+    let (mut producer, mut consumer) = bao_sys::spsc::ring(BAO_KICK_RING_SIZE);
+    c.bench_function("kick/spsc", |b| {
+        b.iter(|| {
+            producer.push(black_box(1u64)).unwrap();
+            consumer.pop().unwrap()
+        })
+    });
+    let queue = Mutex::new(std::collections::VecDeque::with_capacity(
+        BAO_KICK_RING_SIZE,
+    ));
+    c.bench_function("kick/mutex", |b| {
+        b.iter(|| {
+            queue.lock().unwrap().push_back(black_box(1u64));
+            queue.lock().unwrap().pop_front().unwrap()
+        })
+    });
+}
+
+criterion_group!(
+    benches,
+    bench_decode,
+    bench_mmio_dispatch,
+    bench_hypervisor,
+    bench_instrumentation,
+    bench_kick_queue
+);
+criterion_main!(benches);
@@ -0,0 +1,29 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Generates the C header of the `ffi` feature and the gRPC code of the `grpc` feature.
+
+fn main() {
+    #[cfg(feature = "ffi")]
+    {
+        let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+        println!("cargo:rerun-if-changed=src/ffi.rs");
+        println!("cargo:rerun-if-changed=src/types.rs");
+        println!("cargo:rerun-if-changed=cbindgen.toml");
+        cbindgen::generate(&crate_dir)
+            .expect("Unable to generate the C bindings")
+            .write_to_file(format!("{}/include/bao_sys.h", crate_dir));
+    }
+
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/bao_management.proto");
+        if std::env::var_os("PROTOC").is_none() {
+            std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path().unwrap());
+        }
+        tonic_build::compile_protos("proto/bao_management.proto")
+            .expect("Unable to generate the gRPC code");
+    }
+}
@@ -0,0 +1,20 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Fuzzes the command line parameters parser.
+//!
+//! $ cargo +nightly fuzz run command_line
+
+#![no_main]
+
+use bao_sys::utils::parse_parameters;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|args: Vec<String>| {
+    if let Some(parameters) = parse_parameters(&args) {
+        // Every device gets one value of each parameter
+        assert!(parameters.iter().all(|device| device.len() == 6));
+    }
+});
@@ -0,0 +1,19 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Fuzzes the YAML configuration parser.
+//!
+//! $ cargo +nightly fuzz run config_yaml
+
+#![no_main]
+
+use bao_sys::utils::parse_yaml_config;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(yaml_content) = std::str::from_utf8(data) {
+        let _ = parse_yaml_config(yaml_content);
+    }
+});
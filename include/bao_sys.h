@@ -0,0 +1,362 @@
+#ifndef BAO_SYS_H
+#define BAO_SYS_H
+
+/* Generated by cbindgen, do not edit. */
+
+#include <stdint.h>
+#include <stddef.h>
+
+/**
+ * Bao I/O Write Operation
+ */
+#define BAO_IO_WRITE 0
+
+/**
+ * Bao I/O Read Operation
+ */
+#define BAO_IO_READ 1
+
+/**
+ * Bao I/O Ask Operation
+ */
+#define BAO_IO_ASK 2
+
+/**
+ * Bao I/O Notify Operation
+ */
+#define BAO_IO_NOTIFY 3
+
+/**
+ * Bao Maximum Name Length
+ */
+#define BAO_NAME_LEN 16
+
+/**
+ * Bao Maximum I/O Requests
+ */
+#define BAO_IO_REQUEST_MAX 16
+
+/**
+ * Bao IOCTL Type
+ */
+#define BAO_IOCTL_TYPE 166
+
+/**
+ * Bao I/O Event File Descriptor Data Match Flag
+ */
+#define BAO_IOEVENTFD_FLAG_DATAMATCH (1 << 1)
+
+/**
+ * Bao I/O Event File Descriptor Deassign Flag
+ */
+#define BAO_IOEVENTFD_FLAG_DEASSIGN (1 << 2)
+
+/**
+ * Bao IRQ File Descriptor Assign Flag
+ */
+#define BAO_IRQFD_FLAG_ASSIGN 0
+
+/**
+ * Bao IRQ File Descriptor Deassign Flag
+ */
+#define BAO_IRQFD_FLAG_DEASSIGN 1
+
+/**
+ * Bao Default Backend Restart Delay (in milliseconds)
+ */
+#define BAO_RESTART_DELAY_MS 100
+
+/**
+ * Bao Default Backend Maximum Restart Delay (in milliseconds)
+ */
+#define BAO_RESTART_MAX_DELAY_MS 30000
+
+/**
+ * Bao Isolated Backend Channel File Descriptor
+ */
+#define BAO_ISOLATION_CHANNEL_FD 3
+
+/**
+ * Bao Audit Log Format Version
+ */
+#define BAO_AUDIT_VERSION 1
+
+/**
+ * Bao Audit Log Record Size
+ */
+#define BAO_AUDIT_RECORD_SIZE 64
+
+/**
+ * Bao Audit Log Default Maximum File Size
+ */
+#define BAO_AUDIT_MAX_SIZE ((16 * 1024) * 1024)
+
+/**
+ * Bao Audit Log Default Maximum Number of Files
+ */
+#define BAO_AUDIT_MAX_FILES 4
+
+/**
+ * VirtIO MMIO I/O Size
+ */
+#define VIRTIO_MMIO_IO_SIZE 512
+
+#if defined(BAO_SYS_FFI)
+/**
+ * Opaque handle to a loaded frontends configuration.
+ */
+typedef struct BaoConfig BaoConfig;
+#endif
+
+/**
+ * Struct representing a Bao I/O request.
+ *
+ * # Attributes
+ *
+ * * `virtio_id` - Virtio instance ID.
+ * * `reg_off` - Register offset.
+ * * `addr` - Address.
+ * * `op` - Operation.
+ * * `value` - Value.
+ * * `access_width` - Access width.
+ * * `cpu_id` - Frontend CPU ID of the I/O request.
+ * * `vcpu_id` - Frontend vCPU ID of the I/O request.
+ * * `ret` - Return value.
+ */
+typedef struct BaoIoRequest {
+  uint64_t virtio_id;
+  uint64_t reg_off;
+  uint64_t addr;
+  uint64_t op;
+  uint64_t value;
+  uint64_t access_width;
+  uint64_t cpu_id;
+  uint64_t vcpu_id;
+  uint64_t ret;
+} BaoIoRequest;
+
+#if defined(BAO_SYS_FFI)
+/**
+ * Struct representing a configured device, flattened for C consumers.
+ *
+ * # Attributes
+ *
+ * * `frontend_id` - Frontend ID.
+ * * `guest_id` - Guest ID.
+ * * `device_id` - Device ID.
+ * * `irq` - Device IRQ.
+ * * `addr` - Device address.
+ * * `ram_addr` - Guest RAM address.
+ * * `ram_size` - Guest RAM size.
+ * * `device_type` - Device type (NUL-terminated, truncated if longer).
+ */
+typedef struct BaoDeviceInfo {
+  uint32_t frontend_id;
+  uint32_t guest_id;
+  uint32_t device_id;
+  uint32_t irq;
+  uint64_t addr;
+  uint64_t ram_addr;
+  uint64_t ram_size;
+  char device_type[BAO_NAME_LEN];
+} BaoDeviceInfo;
+#endif
+
+/**
+ * Struct representing a Bao I/O event file descriptor.
+ *
+ * # Attributes
+ *
+ * * `fd` - File descriptor.
+ * * `flags` - Flags.
+ * * `addr` - Address.
+ * * `len` - Length.
+ * * `reserved` - Reserved.
+ * * `data` - Datamatch.
+ */
+typedef struct BaoIoEventFd {
+  uint32_t fd;
+  uint32_t flags;
+  uint64_t addr;
+  uint32_t len;
+  uint32_t reserved;
+  uint64_t data;
+} BaoIoEventFd;
+
+/**
+ * Struct representing a Bao IRQ file descriptor.
+ *
+ * # Attributes
+ *
+ * * `fd` - File descriptor.
+ * * `flags` - Flags.
+ */
+typedef struct BaoIrqFd {
+  int32_t fd;
+  uint32_t flags;
+} BaoIrqFd;
+
+#if defined(BAO_SYS_FFI)
+/**
+ * Creates the I/O client of a device model (backend).
+ *
+ * # Arguments
+ *
+ * * `dm_id` - Device model ID.
+ *
+ * # Returns
+ *
+ * * `c_int` - The device model file descriptor, or a negative errno value.
+ */
+int bao_open(uint32_t dm_id);
+#endif
+
+#if defined(BAO_SYS_FFI)
+/**
+ * Closes a device model file descriptor.
+ *
+ * # Arguments
+ *
+ * * `fd` - Device model file descriptor.
+ *
+ * # Returns
+ *
+ * * `c_int` - 0, or a negative errno value.
+ */
+int bao_close(int fd);
+#endif
+
+#if defined(BAO_SYS_FFI)
+/**
+ * Waits for the next I/O request of the device model and fetches it.
+ *
+ * # Arguments
+ *
+ * * `fd` - Device model file descriptor.
+ * * `req` - Filled with the pending I/O request.
+ *
+ * # Returns
+ *
+ * * `c_int` - 0, or a negative errno value.
+ *
+ * # Safety
+ *
+ * `req` must be null or valid for writes.
+ */
+int bao_attach_request(int fd, struct BaoIoRequest *req);
+#endif
+
+#if defined(BAO_SYS_FFI)
+/**
+ * Notifies the completion of an I/O request.
+ *
+ * # Arguments
+ *
+ * * `fd` - Device model file descriptor.
+ * * `req` - The completed I/O request (with `value` and `ret` set).
+ *
+ * # Returns
+ *
+ * * `c_int` - 0, or a negative errno value.
+ *
+ * # Safety
+ *
+ * `req` must be null or valid for reads.
+ */
+int bao_complete_request(int fd, const struct BaoIoRequest *req);
+#endif
+
+#if defined(BAO_SYS_FFI)
+/**
+ * Assigns (or deassigns) an eventfd that injects the device interrupt when signaled.
+ *
+ * # Arguments
+ *
+ * * `fd` - Device model file descriptor.
+ * * `eventfd` - Eventfd file descriptor.
+ * * `flags` - `BAO_IRQFD_FLAG_ASSIGN` or `BAO_IRQFD_FLAG_DEASSIGN`.
+ *
+ * # Returns
+ *
+ * * `c_int` - 0, or a negative errno value.
+ */
+int bao_register_irqfd(int fd, int eventfd, uint32_t flags);
+#endif
+
+#if defined(BAO_SYS_FFI)
+/**
+ * Loads a frontends configuration (YAML) file.
+ *
+ * # Arguments
+ *
+ * * `path` - Path of the configuration file (NUL-terminated).
+ *
+ * # Returns
+ *
+ * * `*mut BaoConfig` - The configuration (to release with `bao_config_free`), or null
+ *   if the file could not be read or parsed.
+ *
+ * # Safety
+ *
+ * `path` must be null or a valid NUL-terminated string.
+ */
+struct BaoConfig *bao_config_load(const char *path);
+#endif
+
+#if defined(BAO_SYS_FFI)
+/**
+ * Returns the number of devices of a configuration.
+ *
+ * # Arguments
+ *
+ * * `config` - The configuration.
+ *
+ * # Returns
+ *
+ * * `usize` - The number of devices (0 if `config` is null).
+ *
+ * # Safety
+ *
+ * `config` must be null or returned by `bao_config_load` (and not freed).
+ */
+size_t bao_config_num_devices(const struct BaoConfig *config);
+#endif
+
+#if defined(BAO_SYS_FFI)
+/**
+ * Returns a device of a configuration.
+ *
+ * # Arguments
+ *
+ * * `config` - The configuration.
+ * * `index` - Index of the device (below `bao_config_num_devices`).
+ * * `info` - Filled with the device.
+ *
+ * # Returns
+ *
+ * * `c_int` - 0, or a negative errno value.
+ *
+ * # Safety
+ *
+ * `config` must be null or returned by `bao_config_load` (and not freed), and
+ * `info` must be null or valid for writes.
+ */
+int bao_config_get_device(const struct BaoConfig *config, size_t index, struct BaoDeviceInfo *info);
+#endif
+
+#if defined(BAO_SYS_FFI)
+/**
+ * Releases a configuration.
+ *
+ * # Arguments
+ *
+ * * `config` - The configuration (may be null).
+ *
+ * # Safety
+ *
+ * `config` must be null or returned by `bao_config_load`, and not used afterwards.
+ */
+void bao_config_free(struct BaoConfig *config);
+#endif
+
+#endif /* BAO_SYS_H */
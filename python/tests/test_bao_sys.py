@@ -0,0 +1,40 @@
+# Copyright (c) Bao Project and Contributors. All rights reserved.
+#          João Peixoto <joaopeixotooficial@gmail.com>
+#
+# SPDX-License-Identifier: Apache-2.0
+
+import pytest
+
+import bao_sys
+
+CONFIG = """
+frontends:
+  - name: frontend0
+    id: 0
+    guests:
+      - name: guest0
+        id: 1
+        ram_addr: 0x50000000
+        ram_size: 0x1000000
+        shmem_path: /dev/baoipc0
+        socket_path: /tmp/
+        devices:
+          - {name: rng0, id: 0, type: rng, irq: 47, addr: 0xa003e00}
+"""
+
+
+def test_config_roundtrip():
+    config = bao_sys.Config.from_yaml(CONFIG)
+    assert config.devices() == [(0, 1, "rng0", "rng", 0, 47, 0xA003E00)]
+    assert bao_sys.Config.from_yaml(config.to_yaml()).devices() == config.devices()
+    with pytest.raises(ValueError):
+        bao_sys.Config.from_yaml("frontends: 3")
+
+
+def test_io_request_encoding():
+    req = bao_sys.IoRequest(reg_off=0x70, op=bao_sys.BAO_IO_READ, value=0xF)
+    encoded = req.encode()
+    assert len(encoded) == 72
+    assert bao_sys.IoRequest.decode(encoded) == req
+    with pytest.raises(ValueError):
+        bao_sys.IoRequest.decode(encoded[:8])
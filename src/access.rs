@@ -0,0 +1,354 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao MMIO access tracing.
+//!
+//! The register accesses of a device can be traced while the frontend runs, restricted to
+//! register offset ranges (e.g. only the feature and status registers, or only the config
+//! space), so feature negotiation can be watched without logging every queue notify.
+//! Unlike the audit log, tracing is toggled per device at runtime (see `Management`).
+
+#![allow(dead_code)]
+
+use super::defines::*;
+use super::error::Result;
+use super::hypervisor::Hypervisor;
+use super::management::DeviceRegistry;
+use super::types::{BaoIoEventFd, BaoIoRequest, BaoIrqFd, ConfigGuest, ConfigRegRange};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Returns the name of a virtio-mmio register.
+///
+/// # Arguments
+///
+/// * `reg_off` - Register offset.
+///
+/// # Returns
+///
+/// * `&'static str` - The register name (`Config` for the device config space).
+pub fn register_name(reg_off: u64) -> &'static str {
+    match reg_off {
+        VIRTIO_MMIO_MAGIC_VALUE => "MagicValue",
+        VIRTIO_MMIO_VERSION => "Version",
+        VIRTIO_MMIO_DEVICE_ID => "DeviceID",
+        VIRTIO_MMIO_VENDOR_ID => "VendorID",
+        VIRTIO_MMIO_DEVICE_FEATURES => "DeviceFeatures",
+        VIRTIO_MMIO_DEVICE_FEATURES_SEL => "DeviceFeaturesSel",
+        VIRTIO_MMIO_DRIVER_FEATURES => "DriverFeatures",
+        VIRTIO_MMIO_DRIVER_FEATURES_SEL => "DriverFeaturesSel",
+        VIRTIO_MMIO_QUEUE_SEL => "QueueSel",
+        VIRTIO_MMIO_QUEUE_NUM_MAX => "QueueNumMax",
+        VIRTIO_MMIO_QUEUE_NUM => "QueueNum",
+        VIRTIO_MMIO_QUEUE_READY => "QueueReady",
+        VIRTIO_MMIO_QUEUE_NOTIFY => "QueueNotify",
+        VIRTIO_MMIO_INTERRUPT_STATUS => "InterruptStatus",
+        VIRTIO_MMIO_INTERRUPT_ACK => "InterruptACK",
+        VIRTIO_MMIO_STATUS => "Status",
+        VIRTIO_MMIO_QUEUE_DESC_LOW => "QueueDescLow",
+        VIRTIO_MMIO_QUEUE_DESC_HIGH => "QueueDescHigh",
+        VIRTIO_MMIO_QUEUE_AVAIL_LOW => "QueueDriverLow",
+        VIRTIO_MMIO_QUEUE_AVAIL_HIGH => "QueueDriverHigh",
+        VIRTIO_MMIO_QUEUE_USED_LOW => "QueueDeviceLow",
+        VIRTIO_MMIO_QUEUE_USED_HIGH => "QueueDeviceHigh",
+        VIRTIO_MMIO_CONFIG_GENERATION => "ConfigGeneration",
+        off if off >= VIRTIO_MMIO_CONFIG => "Config",
+        _ => "Reserved",
+    }
+}
+
+/// Struct representing the access trace filter of a device, shared with the management
+/// interface.
+///
+/// # Attributes
+///
+/// * `enabled` - Whether the accesses are traced (checked without locking).
+/// * `ranges` - Traced register offset ranges (all registers if empty).
+#[derive(Debug, Default)]
+pub struct AccessFilter {
+    enabled: AtomicBool,
+    ranges: RwLock<Vec<ConfigRegRange>>,
+}
+
+impl AccessFilter {
+    /// Creates a filter.
+    ///
+    /// # Arguments
+    ///
+    /// * `ranges` - Traced register offset ranges (None to disable tracing, all registers
+    ///   if empty).
+    ///
+    /// # Returns
+    ///
+    /// * `AccessFilter` - The filter.
+    pub fn new(ranges: Option<Vec<ConfigRegRange>>) -> Self {
+        let filter = AccessFilter::default();
+        filter.set_ranges(ranges);
+        filter
+    }
+
+    /// Returns the traced register offset ranges.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Vec<ConfigRegRange>>` - The ranges (None if tracing is disabled).
+    pub fn ranges(&self) -> Option<Vec<ConfigRegRange>> {
+        let ranges = self.ranges.read().unwrap();
+        self.enabled.load(Ordering::Relaxed).then(|| ranges.clone())
+    }
+
+    /// Changes the traced register offset ranges.
+    ///
+    /// # Arguments
+    ///
+    /// * `ranges` - The ranges (None to disable tracing, all registers if empty).
+    pub fn set_ranges(&self, ranges: Option<Vec<ConfigRegRange>>) {
+        let mut current = self.ranges.write().unwrap();
+        self.enabled.store(ranges.is_some(), Ordering::Relaxed);
+        *current = ranges.unwrap_or_default();
+    }
+
+    /// Checks if an access to a register is traced.
+    ///
+    /// # Arguments
+    ///
+    /// * `reg_off` - Register offset.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - True if the access is traced.
+    pub fn matches(&self, reg_off: u64) -> bool {
+        if !self.enabled.load(Ordering::Relaxed) {
+            return false;
+        }
+        let ranges = self.ranges.read().unwrap();
+        ranges.is_empty() || ranges.iter().any(|r| (r.start..r.end).contains(&reg_off))
+    }
+}
+
+/// Struct representing a traced register access.
+///
+/// # Attributes
+///
+/// * `device` - Device name.
+/// * `reg_off` - Register offset.
+/// * `op` - Access direction (`BAO_IO_*`).
+/// * `value` - Value written, or read back.
+/// * `access_width` - Access width (in bytes).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccessRecord {
+    pub device: String,
+    pub reg_off: u64,
+    pub op: u64,
+    pub value: u64,
+    pub access_width: u64,
+}
+
+impl fmt::Display for AccessRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let direction = match self.op {
+            BAO_IO_WRITE => "write",
+            BAO_IO_READ => "read",
+            _ => "other",
+        };
+        write!(
+            f,
+            "mmio {} {} {} ({:#05x}) = {:#x} ({} bytes)",
+            self.device,
+            direction,
+            register_name(self.reg_off),
+            self.reg_off,
+            self.value,
+            self.access_width
+        )
+    }
+}
+
+/// Reports a traced register access (e.g. logs it).
+pub type AccessReporter = Arc<dyn Fn(&AccessRecord) + Send + Sync>;
+
+/// Struct representing a hypervisor tracing the register accesses of a guest.
+///
+/// # Attributes
+///
+/// * `inner` - The hypervisor.
+/// * `devices` - Virtio ID, name and filter of each device.
+/// * `reporter` - Reporter of the traced accesses.
+pub struct AccessTraceHypervisor<H: Hypervisor> {
+    inner: H,
+    devices: Vec<(u64, String, Arc<AccessFilter>)>,
+    reporter: AccessReporter,
+}
+
+impl<H: Hypervisor> AccessTraceHypervisor<H> {
+    /// Traces the register accesses of a guest, as filtered through the registry.
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - The hypervisor.
+    /// * `guest` - The guest configuration.
+    /// * `registry` - The device registry holding the filters.
+    /// * `reporter` - Reporter of the traced accesses.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<AccessTraceHypervisor>` - The tracing hypervisor, or `DeviceNotFound` if a
+    ///   device of the guest is not registered.
+    pub fn new(
+        inner: H,
+        guest: &ConfigGuest,
+        registry: &DeviceRegistry,
+        reporter: AccessReporter,
+    ) -> Result<Self> {
+        let devices = guest
+            .devices
+            .iter()
+            .map(|device| {
+                let filter = registry.access_filter(&device.name)?;
+                Ok((u64::from(device.id.raw()), device.name.clone(), filter))
+            })
+            .collect::<Result<_>>()?;
+        Ok(AccessTraceHypervisor {
+            inner,
+            devices,
+            reporter,
+        })
+    }
+}
+
+impl<H: Hypervisor> Hypervisor for AccessTraceHypervisor<H> {
+    fn attach_client(&self) -> Result<()> {
+        self.inner.attach_client()
+    }
+
+    fn wait_request(&self, timeout: Duration) -> Result<bool> {
+        self.inner.wait_request(timeout)
+    }
+
+    fn next_request(&self) -> Result<Option<BaoIoRequest>> {
+        self.inner.next_request()
+    }
+
+    fn complete_request(&self, req: &BaoIoRequest) -> Result<()> {
+        self.inner.complete_request(req)?;
+        // Traced once completed, so reads carry the value returned to the guest
+        let device = self
+            .devices
+            .iter()
+            .find(|(id, _, filter)| *id == req.virtio_id && filter.matches(req.reg_off));
+        if let Some((_, name, _)) = device {
+            (self.reporter)(&AccessRecord {
+                device: name.clone(),
+                reg_off: req.reg_off,
+                op: req.op,
+                value: req.value,
+                access_width: req.access_width,
+            });
+        }
+        Ok(())
+    }
+
+    fn notify_guest(&self) -> Result<()> {
+        self.inner.notify_guest()
+    }
+
+    fn register_ioeventfd(&self, ioeventfd: &BaoIoEventFd) -> Result<()> {
+        self.inner.register_ioeventfd(ioeventfd)
+    }
+
+    fn register_irqfd(&self, irqfd: &BaoIrqFd) -> Result<()> {
+        self.inner.register_irqfd(irqfd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::management::Management;
+    use crate::testing::MockHypervisor;
+    use crate::types::ConfigFrontends;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_access_trace() {
+        let config: ConfigFrontends = serde_yaml::from_str(
+            "frontends:
+  - name: frontend0
+    id: 0
+    guests:
+      - name: guest0
+        id: 1
+        ram_addr: 0x50000000
+        ram_size: 0x1000000
+        shmem_path: /dev/baoipc0
+        socket_path: /tmp/
+        devices:
+          - {name: rng0, id: 0, type: rng, irq: 47, addr: 0xa003e00}
+          - {name: i2c0, id: 1, type: i2c, irq: 46, addr: 0xa003c00,
+             mmio_trace: [{start: 0x10, end: 0x28}, {start: 0x70, end: 0x74}]}
+",
+        )
+        .unwrap();
+        let registry = DeviceRegistry::new(&config);
+        let traced = Arc::new(Mutex::new(Vec::new()));
+        let reported = traced.clone();
+        let mock = MockHypervisor::new();
+        let hypervisor = AccessTraceHypervisor::new(
+            &mock,
+            &config.frontends[0].guests[0],
+            &registry,
+            Arc::new(move |access: &AccessRecord| {
+                reported.lock().unwrap().push(access.to_string())
+            }),
+        )
+        .unwrap();
+        let access = |virtio_id, reg_off, value| {
+            mock.push_request(BaoIoRequest {
+                virtio_id,
+                reg_off,
+                addr: 0,
+                op: BAO_IO_WRITE,
+                value,
+                access_width: 4,
+                cpu_id: 0,
+                vcpu_id: 1,
+                ret: 0,
+            });
+            let req = hypervisor.next_request().unwrap().unwrap();
+            hypervisor.complete_request(&req).unwrap();
+        };
+
+        // Only the feature negotiation of the traced device is reported
+        for virtio_id in [0, 1] {
+            access(virtio_id, VIRTIO_MMIO_DRIVER_FEATURES, 0x1);
+            access(virtio_id, VIRTIO_MMIO_QUEUE_NOTIFY, 0);
+            access(virtio_id, VIRTIO_MMIO_STATUS, 0xb);
+        }
+        assert_eq!(
+            *traced.lock().unwrap(),
+            [
+                "mmio i2c0 write DriverFeatures (0x020) = 0x1 (4 bytes)",
+                "mmio i2c0 write Status (0x070) = 0xb (4 bytes)"
+            ]
+        );
+
+        // Tracing is toggled at runtime
+        registry.set_device_access_trace("i2c0", None).unwrap();
+        registry
+            .set_device_access_trace("rng0", Some(vec![]))
+            .unwrap();
+        traced.lock().unwrap().clear();
+        access(1, VIRTIO_MMIO_STATUS, 0xf);
+        access(0, VIRTIO_MMIO_QUEUE_NOTIFY, 0);
+        assert_eq!(
+            *traced.lock().unwrap(),
+            ["mmio rng0 write QueueNotify (0x050) = 0x0 (4 bytes)"]
+        );
+        assert_eq!(registry.device_access_trace("rng0").unwrap(), Some(vec![]));
+        assert_eq!(registry.device_access_trace("i2c0").unwrap(), None);
+    }
+}
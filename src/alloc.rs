@@ -0,0 +1,171 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao allocation accounting.
+//!
+//! `CountingAllocator` wraps the system allocator and counts the heap allocations, per
+//! process and per thread, to prove the request handling path does not allocate in steady
+//! state. It is only active once installed as the global allocator:
+//!
+//! ```text
+//! #[global_allocator]
+//! static ALLOCATOR: bao_sys::alloc::CountingAllocator = bao_sys::alloc::CountingAllocator;
+//! ```
+
+#![allow(dead_code)]
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Number of heap allocations of the process.
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    /// Number of heap allocations of the current thread.
+    static THREAD_ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Accounts an allocation.
+fn count() {
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    // The thread-local is unavailable while the thread is being torn down
+    let _ = THREAD_ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+}
+
+/// Struct representing the system allocator, counting the allocations.
+pub struct CountingAllocator;
+
+// SAFETY: Every operation is forwarded to the system allocator.
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count();
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count();
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+/// Returns the number of heap allocations (and reallocations) of the process.
+///
+/// # Returns
+///
+/// * `u64` - The number of allocations (zero if `CountingAllocator` is not installed).
+pub fn allocations() -> u64 {
+    ALLOCATIONS.load(Ordering::Relaxed)
+}
+
+/// Returns the number of heap allocations (and reallocations) of the current thread.
+///
+/// # Returns
+///
+/// * `u64` - The number of allocations (zero if `CountingAllocator` is not installed).
+pub fn thread_allocations() -> u64 {
+    THREAD_ALLOCATIONS.with(Cell::get)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::BaoMmioBus;
+    use crate::defines::{BAO_IO_READ, BAO_IO_WRITE, VIRTIO_MMIO_IO_SIZE};
+    use crate::hypervisor::Hypervisor;
+    use crate::instrument::INSTRUMENTED;
+    use crate::stats::DeviceStats;
+    use crate::testing::MockHypervisor;
+    use crate::types::{BaoIoRequest, GuestAddress};
+    use std::sync::{Arc, Mutex};
+    use vm_device::bus::{MmioAddress, MmioAddressOffset};
+    use vm_device::MutDeviceMmio;
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    /// Device exposing a single register.
+    struct Register(u64);
+
+    impl MutDeviceMmio for Register {
+        fn mmio_read(&mut self, _base: MmioAddress, _offset: MmioAddressOffset, data: &mut [u8]) {
+            data.copy_from_slice(&self.0.to_le_bytes()[..data.len()]);
+        }
+
+        fn mmio_write(&mut self, _base: MmioAddress, _offset: MmioAddressOffset, data: &[u8]) {
+            let mut bytes = [0u8; 8];
+            bytes[..data.len()].copy_from_slice(data);
+            self.0 = u64::from_le_bytes(bytes);
+        }
+    }
+
+    #[test]
+    fn test_request_path_does_not_allocate() {
+        let mut bus = BaoMmioBus::new();
+        bus.register(
+            GuestAddress(0xa003e00),
+            VIRTIO_MMIO_IO_SIZE,
+            Arc::new(Mutex::new(Register(0))),
+        )
+        .unwrap();
+        let hypervisor = MockHypervisor::new();
+        let stats = DeviceStats::new("rng0", 1);
+        let mut serve = |hv: &dyn Hypervisor| {
+            while let Some(mut req) = hv.next_request()? {
+                let start = crate::bao_now!();
+                bus.handle_request(&mut req)?;
+                match req.op {
+                    BAO_IO_WRITE => crate::bao_inc!(stats.mmio_writes),
+                    _ => crate::bao_inc!(stats.mmio_reads),
+                }
+                crate::bao_latency!(stats.latency, start);
+                hv.complete_request(&req)?;
+            }
+            hv.notify_guest()
+        };
+        let mut round_trip = |op, value| {
+            let mut req = BaoIoRequest {
+                virtio_id: 0,
+                reg_off: 0x70,
+                addr: 0xa003e70,
+                op,
+                value,
+                access_width: 4,
+                cpu_id: 0,
+                vcpu_id: 0,
+                ret: 0,
+            };
+            hypervisor.dispatch(&mut req, &mut serve).unwrap();
+            req.value
+        };
+
+        // Warm up (the request queues grow once), then measure the steady state
+        round_trip(BAO_IO_WRITE, 0xf);
+        let before = thread_allocations();
+        for value in 0..1000 {
+            round_trip(BAO_IO_WRITE, value);
+            assert_eq!(round_trip(BAO_IO_READ, 0), value);
+        }
+        assert_eq!(thread_allocations(), before);
+        assert_eq!(
+            stats.mmio_writes.load(Ordering::Relaxed),
+            1001 * INSTRUMENTED as u64
+        );
+
+        // Allocations are counted
+        let buffer = vec![0u8; 64];
+        assert_eq!(thread_allocations(), before + 1);
+        drop(buffer);
+    }
+}
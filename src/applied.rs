@@ -0,0 +1,299 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao applied configuration record.
+//!
+//! Once a configuration is applied, its hash and the placement of every device are
+//! recorded (see `ConfigFrontends::applied_config`). On the next startup, the effective
+//! configuration is compared with that record, so operators immediately see what an
+//! image update changed: devices added or removed, and devices whose type, ID, IRQ or
+//! address moved.
+
+#![allow(dead_code)]
+
+use super::error::{Error, Result};
+use super::types::{ConfigFrontends, DeviceId, GuestAddress, IrqLine};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// FNV-1a offset basis.
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+/// FNV-1a prime.
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Hashes a byte string with FNV-1a (stable across builds and runs).
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(FNV_OFFSET, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(FNV_PRIME)
+    })
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+/// Struct representing the placement of a device in an applied configuration.
+///
+/// # Attributes
+///
+/// * `device_type` - Device type.
+/// * `id` - Device ID.
+/// * `irq` - Device IRQ line.
+/// * `addr` - Device MMIO address.
+pub struct AppliedDevice {
+    #[serde(rename = "type")]
+    pub device_type: String,
+    pub id: DeviceId,
+    pub irq: IrqLine,
+    pub addr: GuestAddress,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+/// Struct representing the record of an applied configuration.
+///
+/// # Attributes
+///
+/// * `hash` - Hash of the whole effective configuration.
+/// * `devices` - Devices, by `frontend/guest/device` path.
+pub struct AppliedConfig {
+    pub hash: String,
+    pub devices: BTreeMap<String, AppliedDevice>,
+}
+
+impl AppliedConfig {
+    /// Creates the record of an effective configuration.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The frontends configuration.
+    ///
+    /// # Returns
+    ///
+    /// * `AppliedConfig` - The record.
+    pub fn new(config: &ConfigFrontends) -> Self {
+        // The maps of the configuration are ordered, so equal configurations serialize alike
+        let yaml = serde_yaml::to_string(config).unwrap_or_default();
+        let mut devices = BTreeMap::new();
+        for frontend in &config.frontends {
+            for guest in &frontend.guests {
+                for device in &guest.devices {
+                    devices.insert(
+                        format!("{}/{}/{}", frontend.name, guest.name, device.name),
+                        AppliedDevice {
+                            device_type: device.device_type.clone(),
+                            id: device.id,
+                            irq: device.irq,
+                            addr: device.addr,
+                        },
+                    );
+                }
+            }
+        }
+        AppliedConfig {
+            hash: format!("{:016x}", fnv1a(yaml.as_bytes())),
+            devices,
+        }
+    }
+
+    /// Reads the record of the previous run.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Record file path.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<AppliedConfig>>` - The record, `None` if no run was recorded yet,
+    ///   or `InvalidAppliedConfig` if it is malformed.
+    pub fn read(path: &Path) -> Result<Option<Self>> {
+        let yaml = match fs::read_to_string(path) {
+            Ok(yaml) => yaml,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(Error::AppliedConfigFailed(e)),
+        };
+        serde_yaml::from_str(&yaml)
+            .map(Some)
+            .map_err(|e| Error::InvalidAppliedConfig(e.to_string()))
+    }
+
+    /// Writes the record, replacing the one of the previous run atomically.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Record file path.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the record was written.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let yaml =
+            serde_yaml::to_string(self).map_err(|e| Error::InvalidAppliedConfig(e.to_string()))?;
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, yaml).map_err(Error::AppliedConfigFailed)?;
+        fs::rename(&tmp, path).map_err(Error::AppliedConfigFailed)
+    }
+
+    /// Describes the changes since a previous record, one line per change.
+    ///
+    /// # Arguments
+    ///
+    /// * `previous` - The record of the previous run, if any.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<String>` - The startup banner: the configuration hash, followed by the
+    ///   devices added, removed and changed.
+    pub fn diff(&self, previous: Option<&AppliedConfig>) -> Vec<String> {
+        let previous = match previous {
+            Some(previous) if previous.hash != self.hash => previous,
+            Some(_) => {
+                return vec![format!(
+                    "configuration {}: unchanged since the previous run",
+                    self.hash
+                )]
+            }
+            None => {
+                return vec![format!(
+                    "configuration {}: no previous run recorded",
+                    self.hash
+                )]
+            }
+        };
+
+        let mut lines = vec![format!(
+            "configuration {}: changed since the previous run ({})",
+            self.hash, previous.hash
+        )];
+        for (path, device) in &self.devices {
+            let old = match previous.devices.get(path) {
+                Some(old) => old,
+                None => {
+                    lines.push(format!(
+                        "  added {}: {} (id {}) at {} irq {}",
+                        path, device.device_type, device.id, device.addr, device.irq
+                    ));
+                    continue;
+                }
+            };
+            let mut changes = Vec::new();
+            if old.device_type != device.device_type {
+                changes.push(format!(
+                    "type {} -> {}",
+                    old.device_type, device.device_type
+                ));
+            }
+            if old.id != device.id {
+                changes.push(format!("id {} -> {}", old.id, device.id));
+            }
+            if old.addr != device.addr {
+                changes.push(format!("addr {} -> {}", old.addr, device.addr));
+            }
+            if old.irq != device.irq {
+                changes.push(format!("irq {} -> {}", old.irq, device.irq));
+            }
+            if !changes.is_empty() {
+                lines.push(format!("  changed {}: {}", path, changes.join(", ")));
+            }
+        }
+        for path in previous.devices.keys() {
+            if !self.devices.contains_key(path) {
+                lines.push(format!("  removed {}", path));
+            }
+        }
+        if lines.len() == 1 {
+            lines.push("  no device added, removed or moved".to_string());
+        }
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(devices: &str) -> ConfigFrontends {
+        serde_yaml::from_str(&format!(
+            "frontends:
+  - name: frontend0
+    id: 0
+    guests:
+      - name: guest0
+        id: 1
+        ram_addr: 0x60000000
+        ram_size: 0x1000000
+        shmem_path: /dev/baoipc0
+        socket_path: /tmp/
+        devices: [{}]
+",
+            devices
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_applied_config_diff() {
+        let previous = AppliedConfig::new(&config(
+            "{name: rng0, id: 0, type: rng, irq: 0x2f, addr: 0xa003e00},
+             {name: net0, id: 1, type: net, irq: 0x30, addr: 0xa003c00}",
+        ));
+        let current = AppliedConfig::new(&config(
+            "{name: rng0, id: 0, type: rng, irq: 0x2f, addr: 0xa003a00},
+             {name: blk0, id: 2, type: block, irq: 0x31, addr: 0xa003800}",
+        ));
+
+        assert_eq!(
+            current.diff(Some(&current.clone())),
+            [format!(
+                "configuration {}: unchanged since the previous run",
+                current.hash
+            )]
+        );
+        assert_eq!(
+            current.diff(None),
+            [format!(
+                "configuration {}: no previous run recorded",
+                current.hash
+            )]
+        );
+        assert_eq!(
+            current.diff(Some(&previous))[1..],
+            [
+                "  added frontend0/guest0/blk0: block (id 2) at 0xa003800 irq 49",
+                "  changed frontend0/guest0/rng0: addr 0xa003e00 -> 0xa003a00",
+                "  removed frontend0/guest0/net0",
+            ]
+        );
+
+        // A change outside the devices still changes the hash
+        let mut options = config("{name: rng0, id: 0, type: rng, irq: 0x2f, addr: 0xa003e00}");
+        let unchanged = AppliedConfig::new(&options);
+        options.init_concurrency = Some(2);
+        assert_eq!(
+            AppliedConfig::new(&options).diff(Some(&unchanged))[1],
+            "  no device added, removed or moved"
+        );
+    }
+
+    #[test]
+    fn test_applied_config_file() {
+        let path = std::env::temp_dir().join(format!("bao-applied-{}.yaml", std::process::id()));
+        let _ = fs::remove_file(&path);
+        assert_eq!(AppliedConfig::read(&path).unwrap(), None);
+
+        let applied = AppliedConfig::new(&config(
+            "{name: rng0, id: 0, type: rng, irq: 0x2f, addr: 0xa003e00}",
+        ));
+        applied.write(&path).unwrap();
+        assert_eq!(AppliedConfig::read(&path).unwrap(), Some(applied));
+
+        fs::write(&path, "hash: [").unwrap();
+        assert!(matches!(
+            AppliedConfig::read(&path),
+            Err(Error::InvalidAppliedConfig(_))
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+}
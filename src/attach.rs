@@ -0,0 +1,138 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao guest attachment.
+//!
+//! A device model learns that its guest is up from the first I/O request the guest issues
+//! (usually its driver probing the virtio-mmio magic value). `attach` waits for that
+//! request explicitly, with a timeout per attempt and a number of retries, so the caller
+//! decides when a guest is considered attached, or given up on, instead of blocking for
+//! good in `BAO_IOCTL_IO_ATTACH_CLIENT`.
+
+#![allow(dead_code)]
+
+use super::error::{Error, Result};
+use super::hypervisor::Hypervisor;
+use super::types::BaoIoRequest;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Struct representing how the attachment of a guest is waited for.
+///
+/// # Attributes
+///
+/// * `timeout` - Maximum time to wait for a request in each attempt (forever if None).
+/// * `retries` - Number of attempts after the first one.
+/// * `retry_delay` - Delay between attempts.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AttachOptions {
+    pub timeout: Option<Duration>,
+    pub retries: u32,
+    pub retry_delay: Duration,
+}
+
+/// Struct representing an attached guest.
+///
+/// # Attributes
+///
+/// * `request` - The first I/O request of the guest, to be served by the caller.
+/// * `attempts` - Number of attempts it took.
+/// * `elapsed` - Time it took.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attachment {
+    pub request: BaoIoRequest,
+    pub attempts: u32,
+    pub elapsed: Duration,
+}
+
+/// Waits for the first I/O request of a guest.
+///
+/// Attempts timing out, or failing with a transient error (EINTR, EAGAIN), are retried
+/// after `options.retry_delay`; any other error is returned right away.
+///
+/// # Arguments
+///
+/// * `hypervisor` - The I/O dispatcher of the device model.
+/// * `options` - The attachment options.
+///
+/// # Returns
+///
+/// * `Result<Attachment>` - The attached guest, `AttachTimedOut` once every attempt timed
+///   out, or the error of the last attempt.
+pub fn attach(hypervisor: &dyn Hypervisor, options: &AttachOptions) -> Result<Attachment> {
+    let start = Instant::now();
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let failure = match attempt(hypervisor, options.timeout) {
+            Ok(Some(request)) => {
+                return Ok(Attachment {
+                    request,
+                    attempts,
+                    elapsed: start.elapsed(),
+                })
+            }
+            Ok(None) => None,
+            Err(e) if matches!(e.errno(), libc::EINTR | libc::EAGAIN) => Some(e),
+            Err(e) => return Err(e),
+        };
+        if attempts > options.retries {
+            return Err(failure.unwrap_or(Error::AttachTimedOut(attempts)));
+        }
+        thread::sleep(options.retry_delay);
+    }
+}
+
+/// Waits for a pending request and fetches it.
+fn attempt(hypervisor: &dyn Hypervisor, timeout: Option<Duration>) -> Result<Option<BaoIoRequest>> {
+    if let Some(timeout) = timeout {
+        if !hypervisor.wait_request(timeout)? {
+            return Ok(None);
+        }
+    }
+    hypervisor.attach_client()?;
+    hypervisor.next_request()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::defines::{BAO_IO_READ, VIRTIO_MMIO_MAGIC_VALUE};
+    use crate::testing::MockHypervisor;
+
+    #[test]
+    fn test_attach() {
+        let hypervisor = MockHypervisor::new();
+        let options = AttachOptions {
+            timeout: Some(Duration::from_millis(10)),
+            retries: 2,
+            retry_delay: Duration::from_millis(1),
+        };
+
+        // The guest never shows up
+        assert!(matches!(
+            attach(&hypervisor, &options),
+            Err(Error::AttachTimedOut(3))
+        ));
+
+        // The guest driver probes the device
+        let probe = BaoIoRequest {
+            virtio_id: 0,
+            reg_off: VIRTIO_MMIO_MAGIC_VALUE,
+            addr: 0xa003e00,
+            op: BAO_IO_READ,
+            value: 0,
+            access_width: 4,
+            cpu_id: 0,
+            vcpu_id: 0,
+            ret: 0,
+        };
+        hypervisor.push_request(probe);
+        let attachment = attach(&hypervisor, &options).unwrap();
+        assert_eq!(attachment.request, probe);
+        assert_eq!(attachment.attempts, 1);
+        assert_eq!(hypervisor.pending(), 0);
+    }
+}
@@ -0,0 +1,459 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao MMIO access audit log.
+//!
+//! The requests served through an `AuditHypervisor` are recorded in the log once
+//! completed, so the reads carry the value returned to the guest.
+
+#![allow(dead_code)]
+
+use super::defines::{
+    BAO_AUDIT_MAGIC, BAO_AUDIT_MAX_FILES, BAO_AUDIT_MAX_SIZE, BAO_AUDIT_RECORD_SIZE,
+    BAO_AUDIT_VERSION,
+};
+use super::error::{Error, Result};
+use super::hypervisor::Hypervisor;
+use super::trace::LogFile;
+use super::types::{BaoIoEventFd, BaoIoRequest, BaoIrqFd, ConfigAudit};
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Struct representing an audited MMIO access.
+///
+/// # Attributes
+///
+/// * `timestamp_ns` - Time of the access (nanoseconds since the Unix epoch).
+/// * `virtio_id` - Virtio instance ID.
+/// * `reg_off` - Register offset.
+/// * `addr` - Address.
+/// * `op` - Operation (direction).
+/// * `value` - Value.
+/// * `access_width` - Access width.
+/// * `vcpu_id` - Frontend vCPU ID of the access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AuditRecord {
+    pub timestamp_ns: u64,
+    pub virtio_id: u64,
+    pub reg_off: u64,
+    pub addr: u64,
+    pub op: u64,
+    pub value: u64,
+    pub access_width: u64,
+    pub vcpu_id: u64,
+}
+
+impl AuditRecord {
+    /// Creates an audit record from an I/O request, timestamped now.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The I/O request.
+    ///
+    /// # Returns
+    ///
+    /// * `AuditRecord` - The audit record.
+    pub fn new(req: &BaoIoRequest) -> Self {
+        let timestamp_ns = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        AuditRecord {
+            timestamp_ns,
+            virtio_id: req.virtio_id,
+            reg_off: req.reg_off,
+            addr: req.addr,
+            op: req.op,
+            value: req.value,
+            access_width: req.access_width,
+            vcpu_id: req.vcpu_id,
+        }
+    }
+
+    /// Encodes the record (little-endian, fixed size).
+    ///
+    /// # Returns
+    ///
+    /// * `[u8; BAO_AUDIT_RECORD_SIZE]` - The encoded record.
+    pub fn to_bytes(&self) -> [u8; BAO_AUDIT_RECORD_SIZE] {
+        let mut bytes = [0u8; BAO_AUDIT_RECORD_SIZE];
+        let fields = [
+            self.timestamp_ns,
+            self.virtio_id,
+            self.reg_off,
+            self.addr,
+            self.op,
+            self.value,
+            self.access_width,
+            self.vcpu_id,
+        ];
+        for (chunk, field) in bytes.chunks_exact_mut(8).zip(fields) {
+            chunk.copy_from_slice(&field.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Decodes a record.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The encoded record.
+    ///
+    /// # Returns
+    ///
+    /// * `AuditRecord` - The decoded record.
+    pub fn from_bytes(bytes: &[u8; BAO_AUDIT_RECORD_SIZE]) -> Self {
+        let mut fields = bytes
+            .chunks_exact(8)
+            .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap()));
+        let mut next = || fields.next().unwrap();
+        AuditRecord {
+            timestamp_ns: next(),
+            virtio_id: next(),
+            reg_off: next(),
+            addr: next(),
+            op: next(),
+            value: next(),
+            access_width: next(),
+            vcpu_id: next(),
+        }
+    }
+}
+
+/// Struct representing a rotating binary log of MMIO accesses.
+///
+/// Each file starts with a header (magic, version, record size) followed by fixed-size
+/// records. Once a file exceeds `max_size` it is rotated (`path` -> `path.1` -> ... ->
+/// `path.<max_files - 1>`), the oldest file being discarded, so the log behaves as a
+/// ring buffer bounded to `max_size * max_files` bytes.
+///
+/// # Attributes
+///
+/// * `config` - Audit configuration (path, limits and filters).
+/// * `file` - Current log file.
+/// * `size` - Size of the current log file.
+pub struct AuditLog {
+    config: ConfigAudit,
+    file: BufWriter<File>,
+    size: u64,
+}
+
+impl AuditLog {
+    /// Opens a new audit log, rotating any existing one.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - Audit configuration.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<AuditLog>` - The audit log.
+    pub fn new(config: ConfigAudit) -> Result<Self> {
+        if fs::metadata(&config.path).is_ok() {
+            Self::rotate_files(&config)?;
+        }
+        let (file, size) = Self::create_file(&config.path)?;
+        Ok(AuditLog { config, file, size })
+    }
+
+    /// Creates a log file and writes its header.
+    fn create_file(path: &str) -> Result<(BufWriter<File>, u64)> {
+        let mut file = BufWriter::new(File::create(path).map_err(Error::AuditLogFailed)?);
+        let mut header = Vec::with_capacity(16);
+        header.extend_from_slice(BAO_AUDIT_MAGIC);
+        header.extend_from_slice(&BAO_AUDIT_VERSION.to_le_bytes());
+        header.extend_from_slice(&(BAO_AUDIT_RECORD_SIZE as u32).to_le_bytes());
+        file.write_all(&header).map_err(Error::AuditLogFailed)?;
+        Ok((file, header.len() as u64))
+    }
+
+    /// Shifts the rotated files by one, discarding the oldest.
+    fn rotate_files(config: &ConfigAudit) -> Result<()> {
+        let max_files = config.max_files.unwrap_or(BAO_AUDIT_MAX_FILES).max(1);
+        for index in (1..max_files).rev() {
+            let from = match index {
+                1 => config.path.clone(),
+                _ => format!("{}.{}", config.path, index - 1),
+            };
+            if fs::metadata(&from).is_ok() {
+                fs::rename(&from, format!("{}.{}", config.path, index))
+                    .map_err(Error::AuditLogFailed)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks whether an access matches the configured filters.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The I/O request.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - True if the access must be recorded.
+    pub fn matches(&self, req: &BaoIoRequest) -> bool {
+        (self.config.devices.is_empty()
+            || self
+                .config
+                .devices
+                .iter()
+                .any(|id| u64::from(id.raw()) == req.virtio_id))
+            && (self.config.ranges.is_empty()
+                || self
+                    .config
+                    .ranges
+                    .iter()
+                    .any(|r| (r.start..r.end).contains(&req.reg_off)))
+    }
+
+    /// Records an MMIO access, if it matches the configured filters.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The I/O request.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the access was recorded (or filtered out).
+    pub fn record(&mut self, req: &BaoIoRequest) -> Result<()> {
+        if !self.matches(req) {
+            return Ok(());
+        }
+        if self.size + BAO_AUDIT_RECORD_SIZE as u64
+            > self.config.max_size.unwrap_or(BAO_AUDIT_MAX_SIZE)
+        {
+            self.rotate()?;
+        }
+        self.file
+            .write_all(&AuditRecord::new(req).to_bytes())
+            .map_err(Error::AuditLogFailed)?;
+        self.size += BAO_AUDIT_RECORD_SIZE as u64;
+        Ok(())
+    }
+
+    /// Rotates the log, starting a new file.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the log was rotated.
+    pub fn rotate(&mut self) -> Result<()> {
+        self.flush()?;
+        Self::rotate_files(&self.config)?;
+        let (file, size) = Self::create_file(&self.config.path)?;
+        self.file = file;
+        self.size = size;
+        Ok(())
+    }
+
+    /// Flushes the buffered records to the current file.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the records were flushed.
+    pub fn flush(&mut self) -> Result<()> {
+        self.file.flush().map_err(Error::AuditLogFailed)
+    }
+}
+
+/// A shared audit log is flushed and rotated on demand (e.g. from the control socket).
+impl LogFile for Mutex<AuditLog> {
+    fn flush(&self) -> Result<()> {
+        self.lock().unwrap().flush()
+    }
+
+    fn rotate(&self) -> Result<()> {
+        self.lock().unwrap().rotate()
+    }
+}
+
+/// Struct representing a hypervisor whose completed requests are audited.
+///
+/// # Attributes
+///
+/// * `inner` - The audited hypervisor.
+/// * `log` - The audit log.
+pub struct AuditHypervisor<H: Hypervisor> {
+    inner: H,
+    log: Arc<Mutex<AuditLog>>,
+}
+
+impl<H: Hypervisor> AuditHypervisor<H> {
+    /// Audits the requests of a hypervisor.
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - The audited hypervisor.
+    /// * `log` - The audit log.
+    ///
+    /// # Returns
+    ///
+    /// * `AuditHypervisor` - The auditing hypervisor.
+    pub fn new(inner: H, log: Arc<Mutex<AuditLog>>) -> Self {
+        AuditHypervisor { inner, log }
+    }
+}
+
+impl<H: Hypervisor> Hypervisor for AuditHypervisor<H> {
+    fn attach_client(&self) -> Result<()> {
+        self.inner.attach_client()
+    }
+
+    fn wait_request(&self, timeout: Duration) -> Result<bool> {
+        self.inner.wait_request(timeout)
+    }
+
+    fn next_request(&self) -> Result<Option<BaoIoRequest>> {
+        self.inner.next_request()
+    }
+
+    fn complete_request(&self, req: &BaoIoRequest) -> Result<()> {
+        self.log.lock().unwrap().record(req)?;
+        self.inner.complete_request(req)
+    }
+
+    fn notify_guest(&self) -> Result<()> {
+        self.inner.notify_guest()
+    }
+
+    fn register_ioeventfd(&self, ioeventfd: &BaoIoEventFd) -> Result<()> {
+        self.inner.register_ioeventfd(ioeventfd)
+    }
+
+    fn register_irqfd(&self, irqfd: &BaoIrqFd) -> Result<()> {
+        self.inner.register_irqfd(irqfd)
+    }
+}
+
+/// Reads every record of an audit log file.
+///
+/// # Arguments
+///
+/// * `reader` - The audit log file.
+///
+/// # Returns
+///
+/// * `Result<Vec<AuditRecord>>` - The records, in the order they were written.
+pub fn read_audit_records<R: Read>(mut reader: R) -> Result<Vec<AuditRecord>> {
+    let mut header = [0u8; 16];
+    reader
+        .read_exact(&mut header)
+        .map_err(Error::AuditLogFailed)?;
+    if &header[0..8] != BAO_AUDIT_MAGIC
+        || header[8..12] != BAO_AUDIT_VERSION.to_le_bytes()
+        || header[12..16] != (BAO_AUDIT_RECORD_SIZE as u32).to_le_bytes()
+    {
+        return Err(Error::InvalidAuditLog);
+    }
+
+    let mut records = Vec::new();
+    let mut bytes = [0u8; BAO_AUDIT_RECORD_SIZE];
+    loop {
+        match reader.read_exact(&mut bytes) {
+            Ok(()) => records.push(AuditRecord::from_bytes(&bytes)),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(Error::AuditLogFailed(e)),
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::defines::BAO_IO_READ;
+    use crate::testing::MockHypervisor;
+    use crate::types::{ConfigRegRange, DeviceId};
+
+    fn request(virtio_id: u64, reg_off: u64) -> BaoIoRequest {
+        BaoIoRequest {
+            virtio_id,
+            reg_off,
+            addr: 0xa003e00 + reg_off,
+            op: 0,
+            value: 0x1,
+            access_width: 4,
+            cpu_id: 0,
+            vcpu_id: 1,
+            ret: 0,
+        }
+    }
+
+    #[test]
+    fn test_audit_log_filter_and_rotation() {
+        let dir = std::env::temp_dir().join(format!("bao-audit-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("audit.bin").to_str().unwrap().to_string();
+
+        let mut log = AuditLog::new(ConfigAudit {
+            path: path.clone(),
+            // Header plus two records per file
+            max_size: Some(16 + 2 * BAO_AUDIT_RECORD_SIZE as u64),
+            max_files: Some(2),
+            devices: vec![DeviceId(1)],
+            ranges: vec![ConfigRegRange {
+                start: 0x70,
+                end: 0x74,
+            }],
+        })
+        .unwrap();
+
+        // Filtered out (device and register range)
+        log.record(&request(2, 0x70)).unwrap();
+        log.record(&request(1, 0x50)).unwrap();
+        // Recorded
+        for value in 0..5 {
+            let mut req = request(1, 0x70);
+            req.value = value;
+            log.record(&req).unwrap();
+        }
+        log.flush().unwrap();
+
+        // The oldest file (values 0 and 1) was discarded
+        let current = read_audit_records(File::open(&path).unwrap()).unwrap();
+        let rotated = read_audit_records(File::open(format!("{}.1", path)).unwrap()).unwrap();
+        assert!(fs::metadata(format!("{}.2", path)).is_err());
+        assert_eq!(
+            rotated.iter().map(|r| r.value).collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        assert_eq!(current.iter().map(|r| r.value).collect::<Vec<_>>(), vec![4]);
+        assert_eq!(current[0].virtio_id, 1);
+        assert_eq!(current[0].reg_off, 0x70);
+        assert_eq!(current[0].vcpu_id, 1);
+
+        // The requests served through an auditing hypervisor are recorded once completed
+        let log = Arc::new(Mutex::new(
+            AuditLog::new(ConfigAudit {
+                path: path.clone(),
+                ..Default::default()
+            })
+            .unwrap(),
+        ));
+        let hypervisor = MockHypervisor::new();
+        let mut req = request(1, 0x70);
+        req.op = BAO_IO_READ;
+        hypervisor
+            .dispatch(&mut req, |hv| {
+                let hv = AuditHypervisor::new(hv, log.clone());
+                while let Some(mut req) = hv.next_request()? {
+                    req.value = 0xf;
+                    hv.complete_request(&req)?;
+                }
+                Ok(())
+            })
+            .unwrap();
+        log.lock().unwrap().flush().unwrap();
+        let current = read_audit_records(File::open(&path).unwrap()).unwrap();
+        assert_eq!(
+            current.iter().map(|r| r.value).collect::<Vec<_>>(),
+            vec![0xf]
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
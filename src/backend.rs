@@ -0,0 +1,641 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao in-process device backends.
+//!
+//! Backends from the rust-vmm `vhost-device` workspace (gpio, i2c, rng, scsi, sound, ...)
+//! register a factory under their name; a device selecting one through its `backend`
+//! field gets it served on a frontend thread, listening on the device socket, instead of
+//! requiring an external daemon. The block (`bao-blk`), CAN (`bao-can`), console
+//! (`bao-console`), GPIO (`bao-gpio`), I2C (`bao-i2c`) and sound (`bao-snd`) backends are
+//! built in.
+//!
+//! A device served in-process with `allowed_paths` has its backend thread confined to
+//! those paths (and its socket directory) with Landlock, once the backend is created.
+//!
+//! A device served in-process with `isolation: subprocess` has its backend served by its
+//! own child process instead (the frontend executable, re-executed), so a crash in one
+//! device implementation does not take down every guest served by the frontend.
+//!
+//! A device served in-process may have its TAP interface set up from its backend options
+//! (see the `tap` module), for as long as its backend serves it, and the frames crossing
+//! the interface captured (see the `capture` module).
+//!
+//! Devices served by an external daemon connect to its socket; with `wait_for_socket`,
+//! a backend started after the frontend is waited for (up to `connect_timeout_ms`)
+//! instead of failing the whole startup. A running device may also be moved to the
+//! socket of another daemon (see `Reconnect`), e.g. to upgrade its backend without the
+//! guest noticing.
+
+#![allow(dead_code)]
+
+use super::block::block_backend;
+use super::can::can_backend;
+use super::console::console_backend;
+use super::defines::{
+    BAO_BLOCK_BACKEND, BAO_CAN_BACKEND, BAO_CONNECT_RETRY_MS, BAO_CONSOLE_BACKEND,
+    BAO_GPIO_BACKEND, BAO_I2C_BACKEND, BAO_SOUND_BACKEND,
+};
+use super::error::{Error, Result};
+use super::gpio::gpio_backend;
+use super::i2c::i2c_backend;
+use super::sandbox::{isolated_backend_channel, restrict_paths, spawn_isolated_backend};
+use super::sound::sound_backend;
+use super::tap::{Tap, TapConfig};
+use super::types::{ConfigAllowedPath, ConfigDevice, ConfigGuest};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, Command};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// A vhost-user backend hosted inside the frontend process.
+pub trait InProcessBackend: Send {
+    /// Serves the vhost-user protocol on a socket until the frontend disconnects.
+    ///
+    /// # Arguments
+    ///
+    /// * `socket` - Path of the vhost-user socket to listen on.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok once the backend terminated gracefully.
+    fn serve(self: Box<Self>, socket: &Path) -> Result<()>;
+}
+
+/// Trait implemented by the devices whose vhost-user connection can be moved to another
+/// backend.
+pub trait Reconnect: Send {
+    /// Disconnects the device from its backend and connects it to another one.
+    ///
+    /// The device is drained beforehand (see `Pause`). The new backend is set up as the
+    /// previous one was (features, memory table and vrings, resuming from the vring
+    /// indices the previous backend stopped at).
+    ///
+    /// # Arguments
+    ///
+    /// * `socket` - The vhost-user socket of the new backend.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok once the device is served by the new backend.
+    fn reconnect(&mut self, socket: &Path) -> Result<()>;
+}
+
+/// Creates the backend of a device from its configuration.
+pub type BackendFactory = fn(&ConfigDevice) -> Result<Box<dyn InProcessBackend>>;
+
+lazy_static! {
+    /// Registered in-process backends, indexed by name (the built-in ones registered).
+    static ref BACKEND_FACTORIES: Mutex<BTreeMap<String, BackendFactory>> =
+        Mutex::new(BTreeMap::from([
+            (BAO_BLOCK_BACKEND.to_string(), block_backend as BackendFactory),
+            (BAO_CAN_BACKEND.to_string(), can_backend as BackendFactory),
+            (BAO_CONSOLE_BACKEND.to_string(), console_backend as BackendFactory),
+            (BAO_GPIO_BACKEND.to_string(), gpio_backend as BackendFactory),
+            (BAO_I2C_BACKEND.to_string(), i2c_backend as BackendFactory),
+            (BAO_SOUND_BACKEND.to_string(), sound_backend as BackendFactory),
+        ]));
+}
+
+/// Registers an in-process backend.
+///
+/// # Arguments
+///
+/// * `name` - Backend name (e.g. `vhost-device-gpio`), as referenced by `ConfigDevice::backend`.
+/// * `factory` - Backend factory.
+pub fn register_backend(name: &str, factory: BackendFactory) {
+    BACKEND_FACTORIES
+        .lock()
+        .unwrap()
+        .insert(name.to_string(), factory);
+}
+
+/// Returns the names of the registered in-process backends.
+///
+/// # Returns
+///
+/// * `Vec<String>` - The backend names.
+pub fn registered_backends() -> Vec<String> {
+    BACKEND_FACTORIES.lock().unwrap().keys().cloned().collect()
+}
+
+/// Returns the vhost-user socket path of a device.
+///
+/// # Arguments
+///
+/// * `guest` - Guest owning the device.
+/// * `device` - The device.
+///
+/// # Returns
+///
+/// * `PathBuf` - `<guest socket_path>/<device name>.sock`.
+pub fn device_socket_path(guest: &ConfigGuest, device: &ConfigDevice) -> PathBuf {
+    Path::new(&guest.socket_path).join(format!("{}.sock", device.name))
+}
+
+/// Connects to the vhost-user socket of a device.
+///
+/// With `wait_for_socket`, a socket that does not exist yet (or does not accept
+/// connections yet) is retried every `BAO_CONNECT_RETRY_MS` until `connect_timeout_ms`
+/// expires; otherwise the first failure is reported.
+///
+/// # Arguments
+///
+/// * `guest` - Guest owning the device.
+/// * `device` - The device.
+///
+/// # Returns
+///
+/// * `Result<UnixStream>` - The connection to the backend.
+pub fn connect_backend(guest: &ConfigGuest, device: &ConfigDevice) -> Result<UnixStream> {
+    connect_backend_socket(device, &device_socket_path(guest, device))
+}
+
+/// Connects to a vhost-user socket on behalf of a device (e.g. the socket of the backend
+/// it moves to), retrying as `connect_backend` does.
+///
+/// # Arguments
+///
+/// * `device` - The device.
+/// * `socket` - The vhost-user socket.
+///
+/// # Returns
+///
+/// * `Result<UnixStream>` - The connection to the backend.
+pub fn connect_backend_socket(device: &ConfigDevice, socket: &Path) -> Result<UnixStream> {
+    let deadline = Instant::now() + device.connect_timeout();
+    loop {
+        let err = match UnixStream::connect(socket) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => e,
+        };
+        let pending = matches!(
+            err.kind(),
+            io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+        );
+        if !device.wait_for_socket || !pending {
+            return Err(Error::BackendConnectFailed(device.name.clone(), err));
+        }
+        if Instant::now() >= deadline {
+            return Err(Error::BackendConnectTimedOut(device.name.clone()));
+        }
+        thread::sleep(Duration::from_millis(BAO_CONNECT_RETRY_MS));
+    }
+}
+
+/// Starts the in-process backend of a device, if the device selects one.
+///
+/// The TAP interface of the device, if any, is set up before the backend is created (so
+/// it can open the interface) and torn down once the backend terminates. The backend
+/// thread is then confined to the `allowed_paths` of the device, if any.
+///
+/// # Arguments
+///
+/// * `guest` - Guest owning the device.
+/// * `device` - The device.
+///
+/// # Returns
+///
+/// * `Result<Option<JoinHandle<Result<()>>>>` - The backend thread, or None if the device
+///   relies on an external backend.
+pub fn spawn_in_process_backend(
+    guest: &ConfigGuest,
+    device: &ConfigDevice,
+) -> Result<Option<JoinHandle<Result<()>>>> {
+    let name = match &device.backend {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+    let factory = *BACKEND_FACTORIES
+        .lock()
+        .unwrap()
+        .get(name)
+        .ok_or_else(|| Error::BackendNotRegistered(name.clone()))?;
+    let tap = match TapConfig::from_device(device)? {
+        Some(config) => Some(Tap::create(&config)?),
+        None => None,
+    };
+    let backend = factory(device)?;
+    let socket = device_socket_path(guest, device);
+    let mut allowed_paths = device.allowed_paths.clone();
+    if !allowed_paths.is_empty() {
+        // The backend still has to bind its socket
+        allowed_paths.push(ConfigAllowedPath {
+            path: guest.socket_path.clone(),
+            read_only: false,
+        });
+    }
+    thread::Builder::new()
+        .name(format!("backend-{}", device.name))
+        .spawn(move || {
+            if !allowed_paths.is_empty() {
+                restrict_paths(&allowed_paths)?;
+            }
+            let result = backend.serve(&socket);
+            drop(tap);
+            result
+        })
+        .map(Some)
+        .map_err(|e| Error::SpawnBackendFailed("thread", e))
+}
+
+/// Struct representing the device handed over to an isolated backend.
+///
+/// # Attributes
+///
+/// * `guest` - Guest owning the device (without its devices).
+/// * `device` - The device.
+#[derive(Deserialize, Serialize)]
+struct IsolatedDevice {
+    guest: ConfigGuest,
+    device: ConfigDevice,
+}
+
+/// Starts the in-process backend of a device in its own child process, if the device
+/// selects one (`isolation: subprocess`).
+///
+/// The child runs `command` (the frontend executable, see `serve_isolated_backend`) and
+/// receives the guest and device configuration over its private channel (see
+/// `spawn_isolated_backend`), so a crash in the device implementation only takes down its
+/// own process. The child exits once the frontend end of the channel is closed (e.g. the
+/// frontend exited).
+///
+/// # Arguments
+///
+/// * `guest` - Guest owning the device.
+/// * `device` - The device.
+/// * `command` - Command that runs the frontend executable.
+///
+/// # Returns
+///
+/// * `Result<Option<(Child, UnixStream)>>` - The backend process and the frontend end of
+///   its channel, or None if the device relies on an external backend.
+pub fn spawn_isolated_in_process_backend(
+    guest: &ConfigGuest,
+    device: &ConfigDevice,
+    command: Command,
+) -> Result<Option<(Child, UnixStream)>> {
+    let name = match &device.backend {
+        Some(name) => name,
+        None => return Ok(None),
+    };
+    if !BACKEND_FACTORIES.lock().unwrap().contains_key(name) {
+        return Err(Error::BackendNotRegistered(name.clone()));
+    }
+    let handover = IsolatedDevice {
+        guest: ConfigGuest {
+            devices: Vec::new(),
+            ..guest.clone()
+        },
+        device: device.clone(),
+    };
+    let yaml = serde_yaml::to_string(&handover)
+        .map_err(|e| Error::SpawnBackendFailed("encode", io::Error::other(e)))?;
+    let (mut child, mut channel) = spawn_isolated_backend(command)?;
+    // The channel is shut down once written, so the child reads the device to its end
+    if let Err(e) = channel
+        .write_all(yaml.as_bytes())
+        .and_then(|_| channel.shutdown(Shutdown::Write))
+    {
+        let _ = child.kill();
+        let _ = child.wait();
+        return Err(Error::SpawnBackendFailed("handover", e));
+    }
+    Ok(Some((child, channel)))
+}
+
+/// Serves the device handed over by the frontend, when running as an isolated device
+/// backend (see `spawn_isolated_in_process_backend`).
+///
+/// Called first thing by the frontend executable (after registering its in-process
+/// backends), which then exits with the returned result.
+///
+/// # Returns
+///
+/// * `Option<Result<()>>` - None if the process is not an isolated backend, otherwise the
+///   result of the backend once it terminated.
+pub fn serve_isolated_backend() -> Option<Result<()>> {
+    isolated_backend_channel().map(serve_handover)
+}
+
+/// Serves the device handed over on the channel to the frontend.
+///
+/// # Arguments
+///
+/// * `channel` - The channel to the frontend.
+///
+/// # Returns
+///
+/// * `Result<()>` - The result of the backend once it terminated.
+fn serve_handover(mut channel: UnixStream) -> Result<()> {
+    let mut yaml = String::new();
+    channel
+        .read_to_string(&mut yaml)
+        .map_err(|e| Error::SpawnBackendFailed("handover", e))?;
+    let handover: IsolatedDevice = serde_yaml::from_str(&yaml)
+        .map_err(|e| Error::SpawnBackendFailed("decode", io::Error::other(e)))?;
+
+    // The frontend keeps its end of the channel open for as long as it runs
+    thread::Builder::new()
+        .name("bao-frontend".to_string())
+        .spawn(move || {
+            let mut pollfd = libc::pollfd {
+                fd: channel.as_raw_fd(),
+                events: 0,
+                revents: 0,
+            };
+            // SAFETY: `pollfd` is valid for the duration of the call.
+            while unsafe { libc::poll(&mut pollfd, 1, -1) } <= 0 {}
+            process::exit(0)
+        })
+        .map_err(|e| Error::SpawnBackendFailed("thread", e))?;
+    match spawn_in_process_backend(&handover.guest, &handover.device)? {
+        Some(thread) => thread
+            .join()
+            .unwrap_or_else(|_| Err(Error::BackendPanicked(handover.device.name))),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::VmId;
+    use std::os::unix::net::UnixListener;
+
+    struct EchoBackend(String);
+
+    impl InProcessBackend for EchoBackend {
+        fn serve(self: Box<Self>, socket: &Path) -> Result<()> {
+            assert_eq!(socket, Path::new("/tmp/bao/rng0.sock"));
+            assert_eq!(self.0, "rng");
+            Ok(())
+        }
+    }
+
+    fn echo_factory(device: &ConfigDevice) -> Result<Box<dyn InProcessBackend>> {
+        Ok(Box::new(EchoBackend(device.device_type.clone())))
+    }
+
+    #[test]
+    fn test_spawn_in_process_backend() {
+        register_backend("echo", echo_factory);
+        assert!(registered_backends().contains(&"echo".to_string()));
+
+        let mut device = ConfigDevice {
+            name: "rng0".to_string(),
+            device_type: "rng".to_string(),
+            ..Default::default()
+        };
+        let guest = ConfigGuest {
+            name: "guest0".to_string(),
+            id: VmId(1),
+            ram_addr: Default::default(),
+            ram_size: 0,
+            shmem_path: "/dev/baoipc0".to_string(),
+            socket_path: "/tmp/bao/".to_string(),
+            devices: vec![],
+            sched: None,
+            watch_dir: None,
+            dt_overlay: None,
+            prefault: false,
+            thp: Default::default(),
+            dtb: None,
+            labels: Default::default(),
+        };
+
+        assert!(spawn_in_process_backend(&guest, &device).unwrap().is_none());
+
+        device.backend = Some("echo".to_string());
+        let handle = spawn_in_process_backend(&guest, &device).unwrap().unwrap();
+        handle.join().unwrap().unwrap();
+
+        device.backend = Some("missing".to_string());
+        assert!(matches!(
+            spawn_in_process_backend(&guest, &device),
+            Err(Error::BackendNotRegistered(_))
+        ));
+    }
+
+    struct ProbeBackend;
+
+    impl InProcessBackend for ProbeBackend {
+        fn serve(self: Box<Self>, socket: &Path) -> Result<()> {
+            // The socket directory stays reachable, the rest of the filesystem does not
+            std::fs::read_dir(socket.parent().unwrap()).unwrap();
+            std::fs::read_dir("/").map(|_| ()).map_err(Error::Io)
+        }
+    }
+
+    fn probe_factory(_device: &ConfigDevice) -> Result<Box<dyn InProcessBackend>> {
+        Ok(Box::new(ProbeBackend))
+    }
+
+    #[test]
+    fn test_in_process_backend_allowed_paths() {
+        register_backend("probe", probe_factory);
+        let dir = std::env::temp_dir().join(format!("bao-confined-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut device = ConfigDevice {
+            name: "probe0".to_string(),
+            device_type: "rng".to_string(),
+            backend: Some("probe".to_string()),
+            ..Default::default()
+        };
+        let guest = ConfigGuest {
+            name: "guest0".to_string(),
+            id: VmId(1),
+            ram_addr: Default::default(),
+            ram_size: 0,
+            shmem_path: "/dev/baoipc0".to_string(),
+            socket_path: dir.to_str().unwrap().to_string(),
+            devices: vec![],
+            sched: None,
+            watch_dir: None,
+            dt_overlay: None,
+            prefault: false,
+            thp: Default::default(),
+            dtb: None,
+            labels: Default::default(),
+        };
+
+        let handle = spawn_in_process_backend(&guest, &device).unwrap().unwrap();
+        handle.join().unwrap().unwrap();
+
+        device.allowed_paths = vec![ConfigAllowedPath {
+            path: "/dev/null".to_string(),
+            read_only: true,
+        }];
+        // Confinement is best-effort, depending on the running kernel
+        let enforced = thread::spawn(|| {
+            restrict_paths(&[ConfigAllowedPath {
+                path: "/".to_string(),
+                read_only: false,
+            }])
+            .unwrap()
+        })
+        .join()
+        .unwrap();
+        let handle = spawn_in_process_backend(&guest, &device).unwrap().unwrap();
+        assert_eq!(handle.join().unwrap().is_err(), enforced);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_connect_backend() {
+        let dir = std::env::temp_dir().join(format!("bao-connect-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut device = ConfigDevice {
+            name: "rng0".to_string(),
+            device_type: "rng".to_string(),
+            connect_timeout_ms: Some(100),
+            ..Default::default()
+        };
+        let guest = ConfigGuest {
+            name: "guest0".to_string(),
+            id: VmId(1),
+            ram_addr: Default::default(),
+            ram_size: 0,
+            shmem_path: "/dev/baoipc0".to_string(),
+            socket_path: dir.to_str().unwrap().to_string(),
+            devices: vec![],
+            sched: None,
+            watch_dir: None,
+            dt_overlay: None,
+            prefault: false,
+            thp: Default::default(),
+            dtb: None,
+            labels: Default::default(),
+        };
+
+        // A missing socket fails right away, or once the timeout expires when waited for
+        let err = connect_backend(&guest, &device).unwrap_err();
+        assert!(matches!(err, Error::BackendConnectFailed(..)));
+        assert_eq!(err.errno(), libc::ENOENT);
+        device.wait_for_socket = true;
+        let err = connect_backend(&guest, &device).unwrap_err();
+        assert!(matches!(err, Error::BackendConnectTimedOut(_)));
+        assert_eq!(err.errno(), libc::ETIMEDOUT);
+
+        // A backend started late is waited for
+        device.connect_timeout_ms = None;
+        let socket = device_socket_path(&guest, &device);
+        let backend = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            let listener = UnixListener::bind(&socket).unwrap();
+            listener.accept().unwrap();
+        });
+        connect_backend(&guest, &device).unwrap();
+        backend.join().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    struct PidBackend(String);
+
+    impl InProcessBackend for PidBackend {
+        fn serve(self: Box<Self>, socket: &Path) -> Result<()> {
+            std::fs::write(socket, std::process::id().to_string()).map_err(Error::Io)?;
+            match self.0.as_str() {
+                "crash0" => panic!("backend crashed"),
+                "hang0" => loop {
+                    thread::park();
+                },
+                _ => Ok(()),
+            }
+        }
+    }
+
+    fn pid_factory(device: &ConfigDevice) -> Result<Box<dyn InProcessBackend>> {
+        Ok(Box::new(PidBackend(device.name.clone())))
+    }
+
+    #[test]
+    fn test_serve_isolated_backend() {
+        // Only serves when spawned by test_spawn_isolated_in_process_backend
+        register_backend("pid", pid_factory);
+        if let Some(result) = serve_isolated_backend() {
+            result.unwrap();
+        }
+    }
+
+    #[test]
+    fn test_spawn_isolated_in_process_backend() {
+        register_backend("pid", pid_factory);
+        let dir = std::env::temp_dir().join(format!("bao-isolated-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut device = ConfigDevice {
+            name: "pid0".to_string(),
+            device_type: "rng".to_string(),
+            backend: Some("pid".to_string()),
+            ..Default::default()
+        };
+        let guest = ConfigGuest {
+            name: "guest0".to_string(),
+            id: VmId(1),
+            ram_addr: Default::default(),
+            ram_size: 0,
+            shmem_path: "/dev/baoipc0".to_string(),
+            socket_path: dir.to_str().unwrap().to_string(),
+            devices: vec![],
+            sched: None,
+            watch_dir: None,
+            dt_overlay: None,
+            prefault: false,
+            thp: Default::default(),
+            dtb: None,
+            labels: Default::default(),
+        };
+        let command = || {
+            let mut command = Command::new(std::env::current_exe().unwrap());
+            command
+                .args(["--exact", "backend::tests::test_serve_isolated_backend"])
+                .stdout(std::process::Stdio::null())
+                .stderr(std::process::Stdio::null());
+            command
+        };
+
+        // The backend serves the device from its own process
+        let (mut child, _channel) = spawn_isolated_in_process_backend(&guest, &device, command())
+            .unwrap()
+            .unwrap();
+        assert!(child.wait().unwrap().success());
+        let pid = std::fs::read_to_string(device_socket_path(&guest, &device)).unwrap();
+        assert_ne!(pid, std::process::id().to_string());
+
+        // A crashing backend only takes its own process down
+        device.name = "crash0".to_string();
+        let (mut child, _channel) = spawn_isolated_in_process_backend(&guest, &device, command())
+            .unwrap()
+            .unwrap();
+        assert!(!child.wait().unwrap().success());
+
+        // The backend goes away with the frontend end of its channel
+        device.name = "hang0".to_string();
+        let (mut child, channel) = spawn_isolated_in_process_backend(&guest, &device, command())
+            .unwrap()
+            .unwrap();
+        while !device_socket_path(&guest, &device).exists() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(child.try_wait().unwrap().is_none());
+        drop(channel);
+        assert!(child.wait().unwrap().success());
+
+        device.backend = Some("missing".to_string());
+        assert!(matches!(
+            spawn_isolated_in_process_backend(&guest, &device, command()),
+            Err(Error::BackendNotRegistered(_))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,483 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao command line frontend.
+//!
+//! Thin consumer of the `bao_sys` library: loads and validates the frontends
+//! configuration and prints the resulting topology, or summarizes a recorded I/O request
+//! stream.
+
+use bao_sys::alloc::CountingAllocator;
+use bao_sys::applied::AppliedConfig;
+use bao_sys::audit::AuditLog;
+use bao_sys::backend::serve_isolated_backend;
+use bao_sys::defines::{BAO_SUPERVISE_INTERVAL_MS, BAO_SYSFS_CPU_DIR};
+use bao_sys::diagnostics::{check_config, check_host_cpus, render, Diagnostic, OutputFormat};
+use bao_sys::error::{Error, ErrorClass};
+use bao_sys::handoff::{self, Handoff};
+use bao_sys::record::{read_recording, replay, RecordKind};
+use bao_sys::runtime::Runtime;
+use bao_sys::sandbox::enter_sandbox;
+use bao_sys::sched::HostCpus;
+use bao_sys::supervisor::Supervisor;
+use bao_sys::trace::{LogFile, TraceSink, Tracer};
+use bao_sys::types::{CommandLineArgs, ConfigFrontends};
+use bao_sys::utils::{daemonize, parse_arguments, write_pidfile};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::Path;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+// Counts the heap allocations, exported as the `bao.process.allocations` metric
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Reports an error as diagnostics and exits with its exit code.
+///
+/// # Arguments
+///
+/// * `e` - The error.
+/// * `output` - Format of the diagnostics.
+fn fail(e: &Error, output: OutputFormat) -> ! {
+    eprint!("{}", render(&Diagnostic::from_error(e), output));
+    process::exit(e.exit_code());
+}
+
+/// Prints the frontends, guests and devices of a configuration.
+///
+/// # Arguments
+///
+/// * `config` - The frontends configuration.
+fn print_topology(config: &ConfigFrontends) {
+    for frontend in &config.frontends {
+        println!("frontend {} (id {})", frontend.name, frontend.id);
+        for guest in &frontend.guests {
+            println!(
+                "  guest {} (id {}): ram {}+{:#x}",
+                guest.name, guest.id, guest.ram_addr, guest.ram_size
+            );
+            for device in &guest.devices {
+                println!(
+                    "    device {} (id {}): {} at {} irq {}",
+                    device.name, device.id, device.device_type, device.addr, device.irq
+                );
+            }
+        }
+    }
+}
+
+/// Prints what changed in the configuration since the previous run.
+///
+/// # Arguments
+///
+/// * `config` - The frontends configuration.
+///
+/// # Returns
+///
+/// * `Option<AppliedConfig>` - The record of the configuration, to write once it is
+///   applied (`None` if no record is kept).
+fn announce_config(config: &ConfigFrontends) -> Option<AppliedConfig> {
+    let path = config.applied_config.as_deref()?;
+    let applied = AppliedConfig::new(config);
+    // An unreadable record only loses the comparison
+    let previous = match AppliedConfig::read(Path::new(path)) {
+        Ok(previous) => previous,
+        Err(e) => {
+            eprintln!("bao-sys: {}", e.report());
+            None
+        }
+    };
+    for line in applied.diff(previous.as_ref()) {
+        println!("{}", line);
+    }
+    Some(applied)
+}
+
+/// Records the configuration as the last applied one.
+///
+/// # Arguments
+///
+/// * `config` - The frontends configuration.
+/// * `applied` - The record of the configuration, if any.
+///
+/// # Returns
+///
+/// * `Result<(), Error>` - Ok if the record was written (or is not kept).
+fn record_config(config: &ConfigFrontends, applied: Option<AppliedConfig>) -> Result<(), Error> {
+    match (&config.applied_config, applied) {
+        (Some(path), Some(applied)) => applied.write(Path::new(path)),
+        _ => Ok(()),
+    }
+}
+
+/// Replays a recording against its own completions and prints a summary per device.
+///
+/// # Arguments
+///
+/// * `path` - The recording file path.
+///
+/// # Returns
+///
+/// * `Result<(), Error>` - Ok if the recording was replayed.
+fn replay_recording(path: &str) -> Result<(), Error> {
+    let events = read_recording(File::open(path).map_err(Error::RecordingFailed)?)?;
+
+    // Requests and completions per device
+    let mut devices: BTreeMap<u64, (u64, u64)> = BTreeMap::new();
+    for event in &events {
+        let counts = devices.entry(event.req.virtio_id).or_default();
+        match event.kind {
+            RecordKind::Request => counts.0 += 1,
+            RecordKind::Completion => counts.1 += 1,
+        }
+    }
+    for (virtio_id, (requests, completions)) in &devices {
+        println!(
+            "virtio {}: {} requests, {} completions",
+            virtio_id, requests, completions
+        );
+    }
+
+    // Replay the recorded completions, checking the recording is consistent
+    let completions = events
+        .iter()
+        .filter(|e| e.kind == RecordKind::Completion)
+        .map(|e| e.req)
+        .collect::<Vec<_>>();
+    let mut completions = completions.iter();
+    let report = replay(&events, false, |req| {
+        if let Some(completed) = completions.next() {
+            *req = *completed;
+        }
+        Ok(())
+    })?;
+    println!(
+        "{} requests recorded over {:?}, replayed in {:?}",
+        report.requests, report.recorded, report.elapsed
+    );
+    Ok(())
+}
+
+/// Runs the frontends of a configuration.
+///
+/// The frontend is sandboxed (see `enter_sandbox`) once its files (e.g. the audit log)
+/// are opened and its runtime (e.g. the control sockets) is started. Unless simulating,
+/// it then serves until its runtime terminates.
+///
+/// # Arguments
+///
+/// * `config` - The frontends configuration.
+/// * `simulate` - Whether to simulate the guests.
+/// * `tracer` - The trace sink, if any.
+/// * `record` - Path of the file where the simulated requests are recorded, if any.
+///
+/// # Returns
+///
+/// * `Result<(), Error>` - Ok once the frontends are done.
+#[cfg_attr(not(feature = "simulate"), allow(unused_variables))]
+fn run_frontends(
+    config: &ConfigFrontends,
+    simulate: bool,
+    tracer: Option<Arc<dyn TraceSink>>,
+    record: Option<&str>,
+) -> Result<(), Error> {
+    let applied = announce_config(config);
+    print_topology(config);
+
+    #[cfg(feature = "simulate")]
+    let recorder = record
+        .filter(|_| simulate)
+        .map(|path| {
+            bao_sys::record::Recorder::new(path).map(|recorder| Arc::new(Mutex::new(recorder)))
+        })
+        .transpose()?;
+    let audit = config
+        .audit
+        .clone()
+        .map(|audit| AuditLog::new(audit).map(|log| Arc::new(Mutex::new(log))))
+        .transpose()?;
+    let logs = audit
+        .iter()
+        .map(|audit| audit.clone() as Arc<dyn LogFile>)
+        .collect();
+    let runtime = Runtime::start(config, logs)?;
+    enter_sandbox(config)?;
+
+    #[cfg(feature = "simulate")]
+    if simulate {
+        let reports = bao_sys::simulate::simulate(
+            config,
+            bao_sys::defines::BAO_SIMULATE_ROUNDS,
+            tracer,
+            recorder.clone(),
+            audit.clone(),
+        )?;
+        if let Some(recorder) = recorder {
+            recorder.lock().unwrap().flush()?;
+        }
+        for report in reports {
+            println!(
+                "simulated {}/{}: status {:#x}, {} requests, {} notifications",
+                report.guest, report.device, report.status, report.requests, report.notifications
+            );
+            if let Some(summary) = &report.summary {
+                print!("{}", summary);
+            }
+        }
+    }
+    if let Some(audit) = &audit {
+        audit.lock().unwrap().flush()?;
+    }
+    record_config(config, applied)?;
+    if !simulate {
+        runtime.wait()?;
+    }
+    Ok(())
+}
+
+/// Takes over the devices of the previous frontend, when started as its successor.
+///
+/// # Returns
+///
+/// * `Result<(), Error>` - Ok once the takeover was acknowledged (or if there is no
+///   previous frontend).
+fn take_over() -> Result<(), Error> {
+    let channel = match handoff::successor_channel() {
+        Some(channel) => channel,
+        None => return Ok(()),
+    };
+    let handoff = Handoff::receive(&channel)?;
+    println!(
+        "live update: took over {} file descriptors and {} device states",
+        handoff.fds.len(),
+        handoff.snapshot.devices.len()
+    );
+    handoff::complete(&channel)
+}
+
+/// Runs every frontend (or guest) in its own process, until none is left to restart.
+///
+/// # Arguments
+///
+/// * `args` - The command line arguments.
+///
+/// # Returns
+///
+/// * `Result<(), Error>` - Ok once every process exited for good.
+fn supervise(args: &CommandLineArgs) -> Result<(), Error> {
+    // The processes do not trace nor record, they would all write to the same file
+    let run = |config: &ConfigFrontends| match run_frontends(config, args.simulate, None, None) {
+        Ok(()) => 0,
+        Err(e) => {
+            eprintln!("bao-sys: {}", e.report());
+            e.exit_code()
+        }
+    };
+    let applied = announce_config(&args.frontends);
+    let mut supervisor = Supervisor::start(&args.frontends, &run)?;
+    record_config(&args.frontends, applied)?;
+    // The supervisor serves the process control socket (the frontend ones are served by
+    // their units), reporting the units
+    let runtime = Runtime::start(
+        &ConfigFrontends {
+            frontends: Vec::new(),
+            ..args.frontends.clone()
+        },
+        Vec::new(),
+    )?;
+    let registry = runtime.registry();
+    registry.set_units(supervisor.status());
+    while supervisor.active() {
+        for unit in supervisor.supervise(&run)? {
+            match unit.pid {
+                Some(pid) => println!(
+                    "supervisor: {} {} (pid {}, restart {})",
+                    unit.name, unit.state, pid, unit.restarts
+                ),
+                None => println!(
+                    "supervisor: {} {} (exit code {})",
+                    unit.name,
+                    unit.state,
+                    unit.exit_code.unwrap_or_default()
+                ),
+            }
+        }
+        registry.set_units(supervisor.status());
+        thread::sleep(Duration::from_millis(BAO_SUPERVISE_INTERVAL_MS));
+    }
+    Ok(())
+}
+
+/// Opens the trace sinks requested on the command line.
+///
+/// # Arguments
+///
+/// * `args` - The command line arguments.
+///
+/// # Returns
+///
+/// * `Result<Option<Arc<dyn TraceSink>>>` - The trace sink, if any.
+fn open_tracer(args: &CommandLineArgs) -> Result<Option<Arc<dyn TraceSink>>, Error> {
+    let mut sinks: Vec<Arc<dyn TraceSink>> = Vec::new();
+    if let Some(path) = &args.trace {
+        sinks.push(Arc::new(Tracer::new(path)?));
+    }
+    #[cfg(feature = "otel")]
+    if let Some(endpoint) = &args.otlp {
+        sinks.push(Arc::new(
+            bao_sys::otel::OtlpExporter::new(endpoint)?.with_frontends(&args.frontends),
+        ));
+    }
+    Ok(match sinks.len() {
+        0 | 1 => sinks.pop(),
+        _ => Some(Arc::new(sinks)),
+    })
+}
+
+/// Terminates the trace, if any.
+///
+/// # Arguments
+///
+/// * `tracer` - The trace sink.
+fn finish_trace(tracer: Option<&dyn TraceSink>) {
+    if let Some(Err(e)) = tracer.map(|tracer| tracer.finish()) {
+        eprintln!("bao-sys: {}", e.report());
+        process::exit(e.exit_code());
+    }
+}
+
+fn main() {
+    // Install the crash reporter
+    bao_sys::crash::install_panic_hook();
+
+    // Serve the device handed over by the frontend, when running as an isolated backend
+    if let Some(result) = serve_isolated_backend() {
+        match result {
+            Ok(()) => process::exit(0),
+            Err(e) => fail(&e, OutputFormat::default()),
+        }
+    }
+
+    // Parse the command line arguments and the configuration file
+    let args = match parse_arguments() {
+        Ok(args) => args,
+        Err(e) => {
+            // Report in the requested format, even if the arguments did not parse
+            let output = OutputFormat::from_args(std::env::args());
+            match e.downcast_ref::<Error>() {
+                Some(e) => fail(e, output),
+                None => {
+                    eprint!(
+                        "{}",
+                        render(&[Diagnostic::error(None, e.to_string())], output)
+                    );
+                    process::exit(ErrorClass::Config.exit_code());
+                }
+            }
+        }
+    };
+
+    // Report the configuration warnings, and the worker CPUs the host cannot honor
+    let mut warnings = check_config(&args.frontends);
+    let host = HostCpus::read(Path::new(BAO_SYSFS_CPU_DIR));
+    warnings.extend(check_host_cpus(&args.frontends, &host));
+    if !warnings.is_empty() {
+        eprint!("{}", render(&warnings, args.output));
+    }
+
+    if let Some(path) = &args.replay {
+        if let Err(e) = replay_recording(path) {
+            fail(&e, args.output);
+        }
+        return;
+    }
+
+    // Detach before any thread is spawned
+    if args.daemon {
+        if let Err(e) = daemonize(args.pidfile.as_deref()) {
+            fail(&e, args.output);
+        }
+    } else if let Some(path) = &args.pidfile {
+        if let Err(e) = write_pidfile(path) {
+            fail(&e, args.output);
+        }
+    }
+
+    if let Err(e) = take_over() {
+        fail(&e, args.output);
+    }
+
+    if args.supervise {
+        if let Err(e) = supervise(&args) {
+            fail(&e, args.output);
+        }
+        return;
+    }
+
+    // Open the trace sinks
+    let tracer = match open_tracer(&args) {
+        Ok(tracer) => tracer,
+        Err(e) => fail(&e, args.output),
+    };
+
+    #[cfg(feature = "simulate")]
+    if let Some(options) = &args.stress {
+        match bao_sys::simulate::stress(&args.frontends, options, tracer.clone()) {
+            Ok(reports) => {
+                for report in reports {
+                    println!(
+                        "stress {}/{}: {} requests ({} bytes) in {:?}, {:.0} req/s, latency p50 {:?} p99 {:?} max {:?}",
+                        report.guest,
+                        report.device,
+                        report.requests,
+                        report.bytes,
+                        report.elapsed,
+                        report.throughput(),
+                        report.latency(50.0),
+                        report.latency(99.0),
+                        report.latency(100.0)
+                    );
+                }
+            }
+            Err(e) => fail(&e, args.output),
+        }
+        finish_trace(tracer.as_deref());
+        return;
+    }
+
+    #[cfg(feature = "simulate")]
+    if let Some(options) = &args.self_test {
+        match bao_sys::simulate::self_test(options, tracer.clone()) {
+            Ok(report) => {
+                println!(
+                    "self-test passed: {} buffers ({} bytes) echoed in {} rounds, {} requests, {} interrupts, {:?}",
+                    report.buffers,
+                    report.bytes,
+                    report.rounds,
+                    report.requests,
+                    report.interrupts,
+                    report.elapsed
+                );
+            }
+            Err(e) => fail(&e, args.output),
+        }
+        finish_trace(tracer.as_deref());
+        return;
+    }
+
+    if let Err(e) = run_frontends(
+        &args.frontends,
+        args.simulate,
+        tracer.clone(),
+        args.record.as_deref(),
+    ) {
+        fail(&e, args.output);
+    }
+
+    finish_trace(tracer.as_deref());
+}
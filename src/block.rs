@@ -0,0 +1,687 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao built-in block backend.
+//!
+//! Serves a virtio-blk device from a disk image, in-process (`backend: bao-blk`), with
+//! the `path` of the image, its `cache` mode and whether it is `readonly` as backend
+//! options.
+//!
+//! A guest filesystem relies on flushes as write barriers: the writes completed before a
+//! flush must be on stable storage once the flush completes, or a power loss may leave
+//! the filesystem corrupted. Requests are served in order and a flush completes only once
+//! `fdatasync` of the image returned, so it covers every write completed before it. The
+//! `cache` mode sets where completed writes may still linger:
+//!
+//! * `writeback` (default) - In the host page cache: `VIRTIO_BLK_F_FLUSH` is offered, so
+//!   the guest flushes when it needs its writes to be durable.
+//! * `writethrough` - Nowhere: the image is opened with `O_DSYNC`, every write being on
+//!   stable storage once completed (as if forced unit access), and no flush is offered.
+//! * `directsync` - As `writethrough`, also bypassing the host page cache (`O_DIRECT`),
+//!   through aligned bounce buffers.
+//!
+//! A failed `fdatasync` may have dropped dirty pages of the image, which a later
+//! `fdatasync` would not report again; once a flush failed, every later flush fails too.
+//!
+//! The image may grow while the guest runs (`resize NAME [SIZE]` on the control socket):
+//! an image file is extended, a host block device grown underneath is sized again, and
+//! the frontend is told over the backend request channel so it raises a configuration
+//! change interrupt and the guest sees the new capacity live. Images never shrink, as
+//! the guest may hold data past the new end.
+
+#![allow(dead_code)]
+
+use super::backend::InProcessBackend;
+use super::defines::*;
+use super::error::{Error, Result};
+use super::poll::EventPoller;
+use super::types::ConfigDevice;
+use super::vhost_backend::{BackendChannel, VhostUserBackend, VirtioDevice};
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Seek, SeekFrom};
+use std::os::unix::fs::{FileExt, OpenOptionsExt};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+/// Enum representing where the completed writes of a block device may linger.
+///
+/// # Variants
+///
+/// * `Writeback` - In the host page cache, until the guest flushes.
+/// * `Writethrough` - Nowhere, every write is synchronous (`O_DSYNC`).
+/// * `Directsync` - Nowhere, every write is synchronous and bypasses the host page cache
+///   (`O_DSYNC | O_DIRECT`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CacheMode {
+    #[default]
+    Writeback,
+    Writethrough,
+    Directsync,
+}
+
+impl CacheMode {
+    /// Returns the name of the cache mode, as set by the `cache` backend option.
+    pub fn name(&self) -> &'static str {
+        match self {
+            CacheMode::Writeback => "writeback",
+            CacheMode::Writethrough => "writethrough",
+            CacheMode::Directsync => "directsync",
+        }
+    }
+
+    /// Returns the flags the image is opened with.
+    fn open_flags(&self) -> i32 {
+        match self {
+            CacheMode::Writeback => 0,
+            CacheMode::Writethrough => libc::O_DSYNC,
+            CacheMode::Directsync => libc::O_DSYNC | libc::O_DIRECT,
+        }
+    }
+
+    /// Checks whether completed writes may linger in a volatile cache.
+    pub fn write_cache(&self) -> bool {
+        *self == CacheMode::Writeback
+    }
+}
+
+impl fmt::Display for CacheMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for CacheMode {
+    type Err = Error;
+
+    fn from_str(mode: &str) -> Result<Self> {
+        [
+            CacheMode::Writeback,
+            CacheMode::Writethrough,
+            CacheMode::Directsync,
+        ]
+        .into_iter()
+        .find(|cache| cache.name() == mode)
+        .ok_or_else(|| {
+            Error::InvalidBackendOption("cache".to_string(), format!("unknown mode {}", mode))
+        })
+    }
+}
+
+/// Trait implemented by the device models whose capacity can change at runtime.
+pub trait Resize: Send {
+    /// Resizes the device.
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - New capacity (in bytes), or None to size the backing storage again
+    ///   (e.g. a host block device grown underneath).
+    ///
+    /// # Returns
+    ///
+    /// * `Result<u64>` - The capacity of the device (in bytes).
+    fn resize(&mut self, size: Option<u64>) -> Result<u64>;
+}
+
+/// Returns `len` bytes of a buffer, aligned for `O_DIRECT`.
+fn aligned(buffer: &mut Vec<u8>, len: usize) -> &mut [u8] {
+    buffer.resize(len + BAO_BLOCK_DIRECT_ALIGN, 0);
+    let start = buffer.as_ptr().align_offset(BAO_BLOCK_DIRECT_ALIGN);
+    &mut buffer[start..start + len]
+}
+
+/// Struct representing a virtio-blk device served from a disk image.
+///
+/// # Attributes
+///
+/// * `file` - The disk image.
+/// * `path` - Path of the image.
+/// * `cache` - Cache mode of the image.
+/// * `read_only` - Whether the guest may not write the image.
+/// * `sectors` - Capacity of the image (in 512-byte sectors).
+/// * `id` - Device ID reported to the guest (the device name).
+/// * `flush_failed` - Whether a flush failed, so writes may have been lost.
+/// * `bounce` - Bounce buffer of `directsync` images (over-allocated to be aligned).
+pub struct BlockDisk {
+    file: File,
+    path: String,
+    cache: CacheMode,
+    read_only: bool,
+    sectors: u64,
+    id: [u8; VIRTIO_BLK_ID_BYTES],
+    flush_failed: bool,
+    bounce: Vec<u8>,
+}
+
+impl BlockDisk {
+    /// Opens the disk image of a device.
+    ///
+    /// # Arguments
+    ///
+    /// * `device` - The device, whose `path`, `cache` and `readonly` options select the
+    ///   image and how it is accessed.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<BlockDisk>` - The disk, `InvalidBackendOption` if an option is missing or
+    ///   invalid, `BlockImageFailed` if the image cannot be opened.
+    pub fn open(device: &ConfigDevice) -> Result<Self> {
+        let option = |key: &str| device.options.get(key).map(String::as_str);
+        let path = option("path").ok_or_else(|| {
+            Error::InvalidBackendOption("path".to_string(), "missing".to_string())
+        })?;
+        let cache = option("cache").map_or(Ok(CacheMode::default()), str::parse)?;
+        let read_only = match option("readonly") {
+            None | Some("false") => false,
+            Some("true") => true,
+            Some(value) => {
+                return Err(Error::InvalidBackendOption(
+                    "readonly".to_string(),
+                    format!("{} is not a boolean", value),
+                ))
+            }
+        };
+
+        let failed = |e| Error::BlockImageFailed(path.to_string(), e);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(!read_only)
+            .custom_flags(cache.open_flags())
+            .open(path)
+            .map_err(failed)?;
+        // Seeking also sizes block devices, whose metadata reports no length
+        let size = file.seek(SeekFrom::End(0)).map_err(failed)?;
+        let mut id = [0; VIRTIO_BLK_ID_BYTES];
+        let name = &device.name.as_bytes()[..device.name.len().min(VIRTIO_BLK_ID_BYTES)];
+        id[..name.len()].copy_from_slice(name);
+        Ok(BlockDisk {
+            file,
+            path: path.to_string(),
+            cache,
+            read_only,
+            sectors: size / VIRTIO_BLK_SECTOR_SIZE,
+            id,
+            flush_failed: false,
+            bounce: Vec::new(),
+        })
+    }
+
+    /// Returns the cache mode of the image.
+    pub fn cache(&self) -> CacheMode {
+        self.cache
+    }
+
+    /// Returns the capacity of the image (in 512-byte sectors).
+    pub fn sectors(&self) -> u64 {
+        self.sectors
+    }
+
+    /// Resizes the image (see `Resize`).
+    ///
+    /// # Arguments
+    ///
+    /// * `size` - New size of the image (in bytes), or None to size the image again.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<u64>` - The capacity of the image (in bytes), `InvalidResize` if it would
+    ///   shrink, is read-only or the size is not a whole number of sectors,
+    ///   `BlockResizeFailed` if the image cannot be resized.
+    pub fn resize(&mut self, size: Option<u64>) -> Result<u64> {
+        let invalid = |reason: String| Error::InvalidResize(self.path.clone(), reason);
+        let capacity = self.sectors * VIRTIO_BLK_SECTOR_SIZE;
+        if let Some(size) = size {
+            if self.read_only {
+                return Err(invalid("the image is read-only".to_string()));
+            }
+            if size % VIRTIO_BLK_SECTOR_SIZE != 0 {
+                return Err(invalid(format!(
+                    "{} bytes is not a whole number of sectors",
+                    size
+                )));
+            }
+            if size < capacity {
+                return Err(invalid(format!(
+                    "it would shrink from {} to {} bytes",
+                    capacity, size
+                )));
+            }
+            self.file
+                .set_len(size)
+                .map_err(|e| Error::BlockResizeFailed(self.path.clone(), e))?;
+        }
+        let size = self
+            .file
+            .seek(SeekFrom::End(0))
+            .map_err(|e| Error::BlockResizeFailed(self.path.clone(), e))?;
+        // Keep serving the previous capacity, the guest holds data past the new end
+        if size < capacity {
+            return Err(invalid(format!(
+                "it shrank from {} to {} bytes",
+                capacity, size
+            )));
+        }
+        self.sectors = size / VIRTIO_BLK_SECTOR_SIZE;
+        Ok(self.sectors * VIRTIO_BLK_SECTOR_SIZE)
+    }
+
+    /// Returns the image offset of an access, if it lies within the image.
+    fn offset(&self, sector: u64, len: usize) -> Option<u64> {
+        let offset = sector.checked_mul(VIRTIO_BLK_SECTOR_SIZE)?;
+        let end = offset.checked_add(len as u64)?;
+        (end <= self.sectors * VIRTIO_BLK_SECTOR_SIZE).then_some(offset)
+    }
+
+    /// Reads sectors of the image.
+    fn read(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        if self.cache != CacheMode::Directsync {
+            return self.file.read_exact_at(buf, offset);
+        }
+        let bounce = aligned(&mut self.bounce, buf.len());
+        self.file.read_exact_at(bounce, offset)?;
+        buf.copy_from_slice(bounce);
+        Ok(())
+    }
+
+    /// Writes sectors of the image (durable on return, unless in `writeback` mode).
+    fn write(&mut self, offset: u64, buf: &[u8]) -> io::Result<()> {
+        if self.cache != CacheMode::Directsync {
+            return self.file.write_all_at(buf, offset);
+        }
+        let bounce = aligned(&mut self.bounce, buf.len());
+        bounce.copy_from_slice(buf);
+        self.file.write_all_at(bounce, offset)
+    }
+
+    /// Makes every completed write durable.
+    fn flush(&mut self) -> io::Result<()> {
+        if self.flush_failed {
+            return Err(io::Error::from_raw_os_error(libc::EIO));
+        }
+        let result = self.file.sync_data();
+        self.flush_failed = result.is_err();
+        result
+    }
+
+    /// Serves a virtio-blk request.
+    ///
+    /// # Arguments
+    ///
+    /// * `header` - The request header (type, reserved and sector).
+    /// * `data` - The data written by the guest (`VIRTIO_BLK_T_OUT`).
+    /// * `buf` - The buffer read by the guest (`VIRTIO_BLK_T_IN`, `VIRTIO_BLK_T_GET_ID`).
+    ///
+    /// # Returns
+    ///
+    /// * `u8` - The request status (`VIRTIO_BLK_S_*`).
+    fn serve(&mut self, header: &[u8], data: &[u8], buf: &mut [u8]) -> u8 {
+        let request = u32::from_le_bytes(header[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        let done = |result: io::Result<()>| match result {
+            Ok(()) => VIRTIO_BLK_S_OK,
+            Err(_) => VIRTIO_BLK_S_IOERR,
+        };
+        match request {
+            VIRTIO_BLK_T_IN => match self.offset(sector, buf.len()) {
+                Some(offset) => done(self.read(offset, buf)),
+                None => VIRTIO_BLK_S_IOERR,
+            },
+            VIRTIO_BLK_T_OUT => match self.offset(sector, data.len()) {
+                Some(offset) if !self.read_only => done(self.write(offset, data)),
+                _ => VIRTIO_BLK_S_IOERR,
+            },
+            VIRTIO_BLK_T_FLUSH => done(self.flush()),
+            VIRTIO_BLK_T_GET_ID => {
+                let len = buf.len().min(VIRTIO_BLK_ID_BYTES);
+                buf[..len].copy_from_slice(&self.id[..len]);
+                VIRTIO_BLK_S_OK
+            }
+            _ => VIRTIO_BLK_S_UNSUPP,
+        }
+    }
+}
+
+impl VirtioDevice for BlockDisk {
+    fn features(&self) -> u64 {
+        let mut features = VIRTIO_F_VERSION_1;
+        if self.cache.write_cache() {
+            features |= VIRTIO_BLK_F_FLUSH;
+        }
+        if self.read_only {
+            features |= VIRTIO_BLK_F_RO;
+        }
+        features
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn config(&self) -> Vec<u8> {
+        // The capacity leads the configuration space
+        self.sectors.to_le_bytes().to_vec()
+    }
+
+    fn process(&mut self, readable: &[u8], writable: &mut [u8]) -> u32 {
+        // The status is the last writable byte, a chain without one cannot be answered
+        let (status, buf) = match writable.split_last_mut() {
+            Some(split) => split,
+            None => return 0,
+        };
+        *status = match readable.len() >= VIRTIO_BLK_HEADER_SIZE {
+            true => {
+                let (header, data) = readable.split_at(VIRTIO_BLK_HEADER_SIZE);
+                self.serve(header, data, buf)
+            }
+            false => VIRTIO_BLK_S_IOERR,
+        };
+        writable.len() as u32
+    }
+}
+
+/// Struct representing the resizable model of a served disk image.
+///
+/// # Attributes
+///
+/// * `disk` - The disk, shared with its backend.
+/// * `channel` - Backend request channel of the backend.
+pub struct BlockResizer {
+    disk: Arc<Mutex<BlockDisk>>,
+    channel: BackendChannel,
+}
+
+impl Resize for BlockResizer {
+    fn resize(&mut self, size: Option<u64>) -> Result<u64> {
+        let mut disk = self.disk.lock().unwrap();
+        let sectors = disk.sectors();
+        let capacity = disk.resize(size)?;
+        // Tell the guest while the disk is locked, so no request sees the old capacity
+        // after the interrupt
+        if disk.sectors() != sectors {
+            self.channel.config_changed()?;
+        }
+        Ok(capacity)
+    }
+}
+
+/// Struct representing the built-in block backend of a device.
+///
+/// # Attributes
+///
+/// * `disk` - The disk, shared with its resizer.
+/// * `backend` - The vhost-user backend serving the disk.
+pub struct BlockBackend {
+    disk: Arc<Mutex<BlockDisk>>,
+    backend: VhostUserBackend,
+}
+
+impl BlockBackend {
+    /// Creates the block backend of a device (see `BlockDisk::open`).
+    ///
+    /// # Arguments
+    ///
+    /// * `device` - The device.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<BlockBackend>` - The backend serving the disk image.
+    pub fn open(device: &ConfigDevice) -> Result<Self> {
+        let disk = Arc::new(Mutex::new(BlockDisk::open(device)?));
+        Ok(BlockBackend {
+            backend: VhostUserBackend::new(Box::new(disk.clone()))
+                .with_poller(EventPoller::new(device)),
+            disk,
+        })
+    }
+
+    /// Returns the resizable model of the disk (see `DeviceRegistry::attach_resize`).
+    ///
+    /// # Returns
+    ///
+    /// * `BlockResizer` - The model, telling the guest about capacity changes.
+    pub fn resizer(&self) -> BlockResizer {
+        BlockResizer {
+            disk: self.disk.clone(),
+            channel: self.backend.channel(),
+        }
+    }
+}
+
+impl InProcessBackend for BlockBackend {
+    fn serve(self: Box<Self>, socket: &Path) -> Result<()> {
+        Box::new(self.backend).serve(socket)
+    }
+}
+
+/// Creates the built-in block backend of a device (see `BlockDisk::open`).
+///
+/// # Arguments
+///
+/// * `device` - The device.
+///
+/// # Returns
+///
+/// * `Result<Box<dyn InProcessBackend>>` - The backend serving the disk image.
+pub fn block_backend(device: &ConfigDevice) -> Result<Box<dyn InProcessBackend>> {
+    Ok(Box::new(BlockBackend::open(device)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::backend_device;
+    use crate::vhost_backend::{read_u64, recv_message, send_message};
+    use std::fs;
+    use std::os::unix::io::AsRawFd;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+    use std::time::Duration;
+
+    fn device(path: &str, options: &[(&str, &str)]) -> ConfigDevice {
+        let options = [&[("path", path)], options].concat();
+        backend_device("blk0", "block", Some(BAO_BLOCK_BACKEND), &options)
+    }
+
+    /// Serves a request, returning its status and the bytes read by the guest.
+    fn request(
+        disk: &mut BlockDisk,
+        kind: u32,
+        sector: u64,
+        data: &[u8],
+        len: usize,
+    ) -> (u8, Vec<u8>) {
+        let mut readable = kind.to_le_bytes().to_vec();
+        readable.extend_from_slice(&0u32.to_le_bytes());
+        readable.extend_from_slice(&sector.to_le_bytes());
+        readable.extend_from_slice(data);
+        let mut writable = vec![0xff; len + 1];
+        assert_eq!(disk.process(&readable, &mut writable), len as u32 + 1);
+        let status = writable.pop().unwrap();
+        (status, writable)
+    }
+
+    #[test]
+    fn test_block_requests() {
+        let image = std::env::temp_dir().join(format!("bao-blk-{}.img", std::process::id()));
+        fs::write(&image, vec![0u8; 8 * 512]).unwrap();
+        let path = image.to_str().unwrap();
+
+        let mut last = "";
+        for cache in ["writeback", "writethrough", "directsync"] {
+            let mut disk = match BlockDisk::open(&device(path, &[("cache", cache)])) {
+                Ok(disk) => disk,
+                // Not every filesystem supports O_DIRECT
+                Err(Error::BlockImageFailed(_, e)) if e.raw_os_error() == Some(libc::EINVAL) => {
+                    continue
+                }
+                Err(e) => panic!("{}: {}", cache, e),
+            };
+            assert_eq!(disk.cache().name(), cache);
+            last = cache;
+            assert_eq!(disk.sectors(), 8);
+            assert_eq!(disk.config(), 8u64.to_le_bytes());
+            // Only a volatile write cache needs flushes
+            let flush = disk.features() & VIRTIO_BLK_F_FLUSH != 0;
+            assert_eq!(flush, cache == "writeback", "{}", cache);
+
+            let data = vec![cache.len() as u8; 1024];
+            assert_eq!(
+                request(&mut disk, VIRTIO_BLK_T_OUT, 6, &data, 0).0,
+                VIRTIO_BLK_S_OK
+            );
+            assert_eq!(
+                request(&mut disk, VIRTIO_BLK_T_FLUSH, 0, &[], 0).0,
+                VIRTIO_BLK_S_OK
+            );
+            assert_eq!(
+                request(&mut disk, VIRTIO_BLK_T_IN, 6, &[], 1024),
+                (VIRTIO_BLK_S_OK, data)
+            );
+
+            // Past the end of the image
+            assert_eq!(
+                request(&mut disk, VIRTIO_BLK_T_IN, 7, &[], 1024).0,
+                VIRTIO_BLK_S_IOERR
+            );
+            assert_eq!(
+                request(&mut disk, VIRTIO_BLK_T_OUT, u64::MAX, &[0; 512], 0).0,
+                VIRTIO_BLK_S_IOERR
+            );
+        }
+        assert_eq!(fs::read(&image).unwrap()[6 * 512], last.len() as u8);
+
+        let mut disk = BlockDisk::open(&device(path, &[("readonly", "true")])).unwrap();
+        assert_ne!(disk.features() & VIRTIO_BLK_F_RO, 0);
+        assert_eq!(
+            request(&mut disk, VIRTIO_BLK_T_OUT, 0, &[1; 512], 0).0,
+            VIRTIO_BLK_S_IOERR
+        );
+        let (status, id) = request(&mut disk, VIRTIO_BLK_T_GET_ID, 0, &[], VIRTIO_BLK_ID_BYTES);
+        assert_eq!(status, VIRTIO_BLK_S_OK);
+        assert_eq!(&id[..5], b"blk0\0");
+        assert_eq!(request(&mut disk, 11, 0, &[], 0).0, VIRTIO_BLK_S_UNSUPP);
+        let mut writable = [0xff];
+        assert_eq!(disk.process(&[0; 4], &mut writable), 1);
+        assert_eq!(writable, [VIRTIO_BLK_S_IOERR]);
+        assert_eq!(disk.process(&[0; 16], &mut []), 0);
+        fs::remove_file(&image).unwrap();
+
+        assert!(matches!(
+            BlockDisk::open(&device(path, &[("cache", "none")])),
+            Err(Error::InvalidBackendOption(key, _)) if key == "cache"
+        ));
+        assert!(matches!(
+            BlockDisk::open(&device(path, &[])),
+            Err(Error::BlockImageFailed(..))
+        ));
+        let mut missing = device(path, &[]);
+        missing.options.clear();
+        assert!(matches!(
+            block_backend(&missing),
+            Err(Error::InvalidBackendOption(key, _)) if key == "path"
+        ));
+    }
+
+    #[test]
+    fn test_block_flush_failure() {
+        // Character devices cannot be synchronized, standing for a failing disk
+        let mut disk = BlockDisk::open(&device("/dev/null", &[])).unwrap();
+        assert_eq!(
+            request(&mut disk, VIRTIO_BLK_T_FLUSH, 0, &[], 0).0,
+            VIRTIO_BLK_S_IOERR
+        );
+        assert!(disk.flush_failed);
+        // Later flushes keep failing, whatever fdatasync reports
+        assert_eq!(
+            request(&mut disk, VIRTIO_BLK_T_FLUSH, 0, &[], 0).0,
+            VIRTIO_BLK_S_IOERR
+        );
+    }
+
+    #[test]
+    fn test_block_resize() {
+        let image = std::env::temp_dir().join(format!("bao-blk-rs-{}.img", std::process::id()));
+        fs::write(&image, vec![0u8; 4 * 512]).unwrap();
+        let path = image.to_str().unwrap();
+        let socket = image.with_extension("sock");
+        let backend = BlockBackend::open(&device(path, &[])).unwrap();
+        let mut resizer = backend.resizer();
+        let server = {
+            let socket = socket.clone();
+            thread::spawn(move || Box::new(backend).serve(&socket))
+        };
+        let stream = loop {
+            match UnixStream::connect(&socket) {
+                Ok(stream) => break stream,
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+        let get = |request: u32, payload: &[u8]| {
+            send_message(&stream, request, VHOST_USER_VERSION, payload, &[]).unwrap();
+            recv_message(&stream).unwrap().unwrap().2
+        };
+        let capacity = || {
+            let header = [0u32, 8, 0].map(u32::to_le_bytes).concat();
+            read_u64(
+                &get(VHOST_USER_GET_CONFIG, &header),
+                VHOST_USER_CONFIG_HEADER_SIZE,
+            )
+        };
+        let features = read_u64(&get(VHOST_USER_GET_PROTOCOL_FEATURES, &[]), 0).unwrap();
+        assert_ne!(features & VHOST_USER_PROTOCOL_F_BACKEND_REQ, 0);
+
+        // Without a backend request channel, the guest sees the capacity once it reads it
+        assert_eq!(resizer.resize(Some(8 * 512)).unwrap(), 8 * 512);
+        assert_eq!(fs::metadata(&image).unwrap().len(), 8 * 512);
+        assert_eq!(capacity(), Some(8));
+
+        let (channel, peer) = UnixStream::pair().unwrap();
+        send_message(
+            &stream,
+            VHOST_USER_SET_BACKEND_REQ_FD,
+            VHOST_USER_VERSION,
+            &[],
+            &[peer.as_raw_fd()],
+        )
+        .unwrap();
+        // Grown underneath, the image is sized again and the frontend told
+        OpenOptions::new()
+            .write(true)
+            .open(&image)
+            .unwrap()
+            .set_len(16 * 512)
+            .unwrap();
+        assert_eq!(capacity(), Some(8));
+        assert_eq!(resizer.resize(None).unwrap(), 16 * 512);
+        let (request, ..) = recv_message(&channel).unwrap().unwrap();
+        assert_eq!(request, VHOST_USER_BACKEND_CONFIG_CHANGE_MSG);
+        assert_eq!(capacity(), Some(16));
+
+        // Nothing is told when the capacity is unchanged, and images never shrink
+        channel.set_nonblocking(true).unwrap();
+        assert_eq!(resizer.resize(None).unwrap(), 16 * 512);
+        assert!(recv_message(&channel).is_err());
+        assert!(matches!(
+            resizer.resize(Some(4 * 512)),
+            Err(Error::InvalidResize(..))
+        ));
+        assert!(matches!(
+            resizer.resize(Some(16 * 512 + 1)),
+            Err(Error::InvalidResize(..))
+        ));
+        drop(stream);
+        server.join().unwrap().unwrap();
+
+        let mut disk = BlockDisk::open(&device(path, &[("readonly", "true")])).unwrap();
+        assert!(matches!(
+            disk.resize(Some(32 * 512)),
+            Err(Error::InvalidResize(..))
+        ));
+        assert_eq!(disk.resize(None).unwrap(), 16 * 512);
+        fs::remove_file(&image).unwrap();
+        let _ = fs::remove_file(&socket);
+    }
+}
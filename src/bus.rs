@@ -0,0 +1,175 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao MMIO bus.
+//!
+//! Dispatches the Bao I/O requests to devices registered on a `vm-device` MMIO bus, so
+//! any rust-vmm device implementing `DeviceMmio` (or `MutDeviceMmio` behind a `Mutex`)
+//! can be attached to a Bao guest unchanged.
+
+#![allow(dead_code)]
+
+use super::error::{Error, Result};
+use super::types::{BaoIoRequest, GuestAddress, IoOp};
+use std::sync::Arc;
+use vm_device::bus::{MmioAddress, MmioRange};
+use vm_device::device_manager::{IoManager, MmioManager};
+use vm_device::DeviceMmio;
+
+/// Struct representing the MMIO bus of a Bao guest.
+///
+/// # Attributes
+///
+/// * `manager` - The `vm-device` I/O manager holding the registered devices.
+#[derive(Default)]
+pub struct BaoMmioBus {
+    manager: IoManager,
+}
+
+impl BaoMmioBus {
+    /// Creates an empty MMIO bus.
+    ///
+    /// # Returns
+    ///
+    /// * `BaoMmioBus` - The MMIO bus.
+    pub fn new() -> Self {
+        BaoMmioBus::default()
+    }
+
+    /// Registers a device on the bus.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Base address of the device region.
+    /// * `size` - Size of the device region.
+    /// * `device` - The device.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the region is valid and does not overlap another device.
+    pub fn register(
+        &mut self,
+        addr: GuestAddress,
+        size: u64,
+        device: Arc<dyn DeviceMmio + Send + Sync>,
+    ) -> Result<()> {
+        let range = MmioRange::new(MmioAddress(addr.raw()), size).map_err(Error::MmioBusError)?;
+        self.manager
+            .register_mmio(range, device)
+            .map_err(Error::MmioBusError)
+    }
+
+    /// Returns the underlying `vm-device` I/O manager.
+    ///
+    /// # Returns
+    ///
+    /// * `&IoManager` - The I/O manager.
+    pub fn manager(&self) -> &IoManager {
+        &self.manager
+    }
+
+    /// Handles an I/O request, forwarding it to the device owning the address.
+    ///
+    /// The value of read requests is updated with the data returned by the device.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The I/O request.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the request was handled by a device.
+    pub fn handle_request(&self, req: &mut BaoIoRequest) -> Result<()> {
+        let addr = MmioAddress(req.addr);
+        let len = req.width()?.bytes();
+        let mut data = [0u8; 8];
+        match req.io_op()? {
+            IoOp::Read => {
+                self.manager
+                    .mmio_read(addr, &mut data[..len])
+                    .map_err(Error::MmioBusError)?;
+                req.value = u64::from_le_bytes(data);
+            }
+            IoOp::Write => {
+                data.copy_from_slice(&req.value.to_le_bytes());
+                self.manager
+                    .mmio_write(addr, &data[..len])
+                    .map_err(Error::MmioBusError)?;
+            }
+            IoOp::Ask | IoOp::Notify => return Err(Error::InvalidIoReqDirection(req.op)),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::defines::{BAO_IO_READ, BAO_IO_WRITE};
+    use std::sync::Mutex;
+    use vm_device::bus::MmioAddressOffset;
+    use vm_device::MutDeviceMmio;
+
+    /// Device exposing a single 32-bit register.
+    struct Register(u32);
+
+    impl MutDeviceMmio for Register {
+        fn mmio_read(&mut self, _base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+            assert_eq!(offset, 0x10);
+            data.copy_from_slice(&self.0.to_le_bytes()[..data.len()]);
+        }
+
+        fn mmio_write(&mut self, _base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+            assert_eq!(offset, 0x10);
+            let mut bytes = [0u8; 4];
+            bytes[..data.len()].copy_from_slice(data);
+            self.0 = u32::from_le_bytes(bytes);
+        }
+    }
+
+    fn request(op: u64, addr: u64, value: u64) -> BaoIoRequest {
+        BaoIoRequest {
+            virtio_id: 0,
+            reg_off: 0,
+            addr,
+            op,
+            value,
+            access_width: 4,
+            cpu_id: 0,
+            vcpu_id: 0,
+            ret: 0,
+        }
+    }
+
+    #[test]
+    fn test_mmio_bus_dispatch() {
+        let mut bus = BaoMmioBus::new();
+        bus.register(
+            GuestAddress(0xa003e00),
+            0x200,
+            Arc::new(Mutex::new(Register(0))),
+        )
+        .unwrap();
+        assert!(bus
+            .register(
+                GuestAddress(0xa003f00),
+                0x200,
+                Arc::new(Mutex::new(Register(0)))
+            )
+            .is_err());
+
+        let mut req = request(BAO_IO_WRITE, 0xa003e10, 0xcafe);
+        bus.handle_request(&mut req).unwrap();
+        let mut req = request(BAO_IO_READ, 0xa003e10, 0);
+        bus.handle_request(&mut req).unwrap();
+        assert_eq!(req.value, 0xcafe);
+
+        let mut req = request(BAO_IO_READ, 0xa004000, 0);
+        assert!(matches!(
+            bus.handle_request(&mut req),
+            Err(Error::MmioBusError(_))
+        ));
+    }
+}
@@ -0,0 +1,1021 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao built-in CAN backend.
+//!
+//! Bridges a virtio-can device to a SocketCAN interface of the host, in-process
+//! (`backend: bao-can`), the `interface` backend option naming it (e.g. `can0`). What
+//! crosses the bridge is set by the backend options:
+//!
+//! * `accept` - The identifiers the guest may send and receive, as identifiers (`0x123`)
+//!   or ranges (`0x100-0x1ff`), separated by commas. Without it, every identifier is.
+//! * `reject` - The identifiers the guest may neither send nor receive, taking precedence
+//!   over `accept`.
+//! * `fd` - Whether CAN FD frames are offered (`true`) or classic frames only (`false`,
+//!   the default).
+//!
+//! Frames the guest sends with a filtered identifier complete with
+//! `VIRTIO_CAN_RESULT_NOT_OK`; received frames with a filtered identifier are dropped.
+//!
+//! The state of the host controller follows its error frames: once bus-off, the device
+//! reports `VIRTIO_CAN_S_CTRL_BUSOFF` in its status (raising a configuration change
+//! interrupt) and fails every frame sent until the controller is restarted, rather than
+//! losing them silently. virtio-can has no error-passive status, so an error-passive
+//! controller keeps transmitting, as it would on a physical bus, the state being kept
+//! in the statistics of the device (see `CanDevice::stats`).
+//!
+//! Received frames are delivered as the guest makes receive buffers available, up to
+//! `BAO_CAN_RX_QUEUE_MAX` waiting frames, beyond which the oldest are dropped (and
+//! counted as overruns).
+
+#![allow(dead_code)]
+
+use super::backend::InProcessBackend;
+use super::defines::*;
+use super::error::{Error, Result};
+use super::poll::EventPoller;
+use super::types::ConfigDevice;
+use super::vhost_backend::{BackendChannel, VhostUserBackend, VirtioDevice};
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::io;
+use std::mem::size_of;
+use std::ops::RangeInclusive;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// Struct representing the identifier filters of a CAN device.
+///
+/// # Attributes
+///
+/// * `accept` - Identifiers that may cross (None for every identifier).
+/// * `reject` - Identifiers that may not cross, whether accepted or not.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CanFilter {
+    pub accept: Option<Vec<RangeInclusive<u32>>>,
+    pub reject: Vec<RangeInclusive<u32>>,
+}
+
+impl CanFilter {
+    /// Reads the filters of a device from its backend options.
+    ///
+    /// # Arguments
+    ///
+    /// * `device` - The device, whose `accept` and `reject` options list identifiers.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<CanFilter>` - The filters, or `InvalidBackendOption`.
+    pub fn from_device(device: &ConfigDevice) -> Result<Self> {
+        let ranges = |key: &str| -> Result<Option<Vec<RangeInclusive<u32>>>> {
+            let list = match device.options.get(key) {
+                Some(list) => list,
+                None => return Ok(None),
+            };
+            let invalid = |entry: &str| {
+                Error::InvalidBackendOption(
+                    key.to_string(),
+                    format!("{} is not an identifier or a range of identifiers", entry),
+                )
+            };
+            let id = |id: &str| {
+                u32::from_str_radix(id.trim().trim_start_matches("0x"), 16)
+                    .ok()
+                    .filter(|&id| id <= CAN_EFF_MASK)
+            };
+            list.split(',')
+                .map(|entry| {
+                    let (first, last) = entry.split_once('-').unwrap_or((entry, entry));
+                    match (id(first), id(last)) {
+                        (Some(first), Some(last)) if first <= last => Ok(first..=last),
+                        _ => Err(invalid(entry.trim())),
+                    }
+                })
+                .collect::<Result<Vec<_>>>()
+                .map(Some)
+        };
+        Ok(CanFilter {
+            accept: ranges("accept")?,
+            reject: ranges("reject")?.unwrap_or_default(),
+        })
+    }
+
+    /// Checks whether a frame identifier may cross.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The identifier.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - Whether it is accepted and not rejected.
+    pub fn permits(&self, id: u32) -> bool {
+        let within = |ranges: &[RangeInclusive<u32>]| ranges.iter().any(|r| r.contains(&id));
+        !within(&self.reject) && self.accept.as_deref().is_none_or(within)
+    }
+}
+
+/// Struct representing a CAN frame.
+///
+/// # Attributes
+///
+/// * `id` - Identifier.
+/// * `extended` - Whether the identifier is extended (29 bits) or standard (11 bits).
+/// * `rtr` - Whether the frame is a remote transmission request.
+/// * `fd` - Whether the frame is a CAN FD frame.
+/// * `data` - Payload (its length being the requested one for remote requests).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CanFrame {
+    pub id: u32,
+    pub extended: bool,
+    pub rtr: bool,
+    pub fd: bool,
+    pub data: Vec<u8>,
+}
+
+impl CanFrame {
+    /// Checks whether the frame is well formed.
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - Whether CAN FD frames are allowed.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - Whether the identifier and the payload fit the frame format.
+    pub fn valid(&self, fd: bool) -> bool {
+        let id = match self.extended {
+            true => self.id <= CAN_EFF_MASK,
+            false => self.id <= CAN_SFF_MASK,
+        };
+        let len = self.data.len();
+        let payload = match self.fd {
+            true => {
+                fd && !self.rtr
+                    && (len <= CAN_MAX_DLEN || [12, 16, 20, 24, 32, 48, 64].contains(&len))
+            }
+            false => len <= CAN_MAX_DLEN,
+        };
+        id && payload
+    }
+
+    /// Encodes the frame as a SocketCAN frame (`struct can_frame` or `struct canfd_frame`).
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<u8>` - The frame.
+    pub fn to_socketcan(&self) -> Vec<u8> {
+        let mut id = self.id;
+        if self.extended {
+            id |= CAN_EFF_FLAG;
+        }
+        if self.rtr {
+            id |= CAN_RTR_FLAG;
+        }
+        let mut frame = vec![0; if self.fd { CANFD_MTU } else { CAN_MTU }];
+        frame[..4].copy_from_slice(&id.to_ne_bytes());
+        frame[4] = self.data.len() as u8;
+        if self.fd {
+            frame[5] = CANFD_FDF;
+        }
+        if !self.rtr {
+            frame[8..8 + self.data.len()].copy_from_slice(&self.data);
+        }
+        frame
+    }
+
+    /// Decodes a SocketCAN frame.
+    ///
+    /// # Arguments
+    ///
+    /// * `frame` - The frame (`struct can_frame` or `struct canfd_frame`).
+    ///
+    /// # Returns
+    ///
+    /// * `Option<CanEvent>` - The frame, the controller state an error frame reports, or
+    ///   None if the frame is malformed or reports nothing about the state.
+    pub fn from_socketcan(frame: &[u8]) -> Option<CanEvent> {
+        let fd = match frame.len() {
+            CAN_MTU => false,
+            CANFD_MTU => true,
+            _ => return None,
+        };
+        let id = u32::from_ne_bytes(frame[..4].try_into().unwrap());
+        let len = usize::from(frame[4]).min(frame.len() - 8);
+        if id & CAN_ERR_FLAG != 0 {
+            return CanState::from_error(id, &frame[8..]).map(CanEvent::State);
+        }
+        let rtr = id & CAN_RTR_FLAG != 0;
+        Some(CanEvent::Frame(CanFrame {
+            id: id & CAN_EFF_MASK,
+            extended: id & CAN_EFF_FLAG != 0,
+            rtr,
+            fd,
+            data: match rtr {
+                true => vec![0; len],
+                false => frame[8..8 + len].to_vec(),
+            },
+        }))
+    }
+}
+
+/// Enum representing the error state of a CAN controller.
+///
+/// # Variants
+///
+/// * `ErrorActive` - Operating normally.
+/// * `ErrorPassive` - Past the error-passive threshold, still taking part in the bus.
+/// * `BusOff` - Disconnected from the bus until restarted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CanState {
+    #[default]
+    ErrorActive,
+    ErrorPassive,
+    BusOff,
+}
+
+impl CanState {
+    /// Reads the state reported by a SocketCAN error frame.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Identifier of the frame (its error classes).
+    /// * `data` - Payload of the frame.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<CanState>` - The state, or None if the frame reports no state change.
+    fn from_error(id: u32, data: &[u8]) -> Option<Self> {
+        let controller = data.get(1).copied().unwrap_or(0);
+        if id & CAN_ERR_BUSOFF != 0 {
+            Some(CanState::BusOff)
+        } else if id & CAN_ERR_RESTARTED != 0 {
+            Some(CanState::ErrorActive)
+        } else if id & CAN_ERR_CRTL == 0 {
+            None
+        } else if controller & CAN_ERR_CRTL_ACTIVE != 0 {
+            Some(CanState::ErrorActive)
+        } else if controller & (CAN_ERR_CRTL_RX_PASSIVE | CAN_ERR_CRTL_TX_PASSIVE) != 0 {
+            Some(CanState::ErrorPassive)
+        } else {
+            None
+        }
+    }
+}
+
+/// Enum representing what a CAN bus delivers.
+///
+/// # Variants
+///
+/// * `Frame` - A received frame.
+/// * `State` - A change of the controller state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CanEvent {
+    Frame(CanFrame),
+    State(CanState),
+}
+
+/// A CAN bus of the host.
+pub trait CanBus: Send {
+    /// Sends a frame.
+    ///
+    /// # Arguments
+    ///
+    /// * `frame` - The frame.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<()>` - Ok once the frame is queued for transmission.
+    fn send(&mut self, frame: &CanFrame) -> io::Result<()>;
+
+    /// Receives what the bus delivered, without blocking.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<Option<CanEvent>>` - The next event, or None if there is none.
+    fn recv(&mut self) -> io::Result<Option<CanEvent>>;
+}
+
+/// Struct representing a CAN socket address (`struct sockaddr_can`).
+#[repr(C)]
+struct CanSockAddr {
+    family: libc::sa_family_t,
+    ifindex: libc::c_int,
+    addr: [u64; 2],
+}
+
+/// Struct representing a raw SocketCAN socket.
+///
+/// # Attributes
+///
+/// * `socket` - The socket, non-blocking.
+#[derive(Debug)]
+pub struct SocketCan {
+    socket: OwnedFd,
+}
+
+impl SocketCan {
+    /// Opens a raw socket on a CAN interface, receiving its frames and the error frames
+    /// reporting the state of its controller.
+    ///
+    /// # Arguments
+    ///
+    /// * `interface` - Interface name.
+    /// * `fd` - Whether CAN FD frames are sent and received.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<SocketCan>` - The socket, or `CanSocketFailed`.
+    pub fn open(interface: &str, fd: bool) -> Result<Self> {
+        let failed = |e| Error::CanSocketFailed(interface.to_string(), e);
+        let name = CString::new(interface)
+            .map_err(|_| failed(io::Error::from_raw_os_error(libc::EINVAL)))?;
+        // SAFETY: The name is a valid C string.
+        let index = match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+            0 => return Err(failed(io::Error::last_os_error())),
+            index => index,
+        };
+
+        // SAFETY: The arguments are valid and the result is checked.
+        let fd_raw = unsafe {
+            libc::socket(
+                libc::AF_CAN,
+                libc::SOCK_RAW | libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC,
+                CAN_RAW,
+            )
+        };
+        if fd_raw < 0 {
+            return Err(failed(io::Error::last_os_error()));
+        }
+        // SAFETY: `fd_raw` is a valid file descriptor owned by nothing else.
+        let socket = SocketCan {
+            socket: unsafe { OwnedFd::from_raw_fd(fd_raw) },
+        };
+
+        let errors = CAN_ERR_CRTL | CAN_ERR_BUSOFF | CAN_ERR_RESTARTED;
+        socket
+            .set_option(CAN_RAW_ERR_FILTER, errors)
+            .map_err(failed)?;
+        if fd {
+            socket.set_option(CAN_RAW_FD_FRAMES, 1).map_err(failed)?;
+        }
+
+        let addr = CanSockAddr {
+            family: libc::AF_CAN as libc::sa_family_t,
+            ifindex: index as libc::c_int,
+            addr: [0; 2],
+        };
+        // SAFETY: `addr` is valid for reads and the length is its size.
+        let ret = unsafe {
+            libc::bind(
+                socket.socket.as_raw_fd(),
+                &addr as *const CanSockAddr as *const libc::sockaddr,
+                size_of::<CanSockAddr>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(failed(io::Error::last_os_error()));
+        }
+        Ok(socket)
+    }
+
+    /// Sets a raw socket option.
+    fn set_option(&self, option: i32, value: u32) -> io::Result<()> {
+        // SAFETY: `value` is valid for reads and the length is its size.
+        let ret = unsafe {
+            libc::setsockopt(
+                self.socket.as_raw_fd(),
+                SOL_CAN_RAW,
+                option,
+                &value as *const u32 as *const libc::c_void,
+                size_of::<u32>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl CanBus for SocketCan {
+    fn send(&mut self, frame: &CanFrame) -> io::Result<()> {
+        let frame = frame.to_socketcan();
+        // SAFETY: The frame is valid for reads of its length.
+        let ret = unsafe {
+            libc::write(
+                self.socket.as_raw_fd(),
+                frame.as_ptr() as *const libc::c_void,
+                frame.len(),
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn recv(&mut self) -> io::Result<Option<CanEvent>> {
+        let mut frame = [0u8; CANFD_MTU];
+        loop {
+            // SAFETY: The buffer is valid for writes of its length.
+            let ret = unsafe {
+                libc::read(
+                    self.socket.as_raw_fd(),
+                    frame.as_mut_ptr() as *mut libc::c_void,
+                    frame.len(),
+                )
+            };
+            if ret < 0 {
+                let e = io::Error::last_os_error();
+                return match e.kind() {
+                    io::ErrorKind::WouldBlock => Ok(None),
+                    io::ErrorKind::Interrupted => continue,
+                    _ => Err(e),
+                };
+            }
+            // Skip what reports nothing (e.g. error counter warnings)
+            if let Some(event) = CanFrame::from_socketcan(&frame[..ret as usize]) {
+                return Ok(Some(event));
+            }
+        }
+    }
+}
+
+/// Struct representing the statistics of a CAN device.
+///
+/// # Attributes
+///
+/// * `state` - State of the host controller.
+/// * `sent` - Frames sent by the guest.
+/// * `received` - Frames delivered to the guest.
+/// * `filtered` - Frames refused or dropped by the identifier filters.
+/// * `failed` - Frames the guest sent that failed (malformed, bus-off or not sent).
+/// * `overruns` - Received frames dropped while waiting for the guest.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CanStats {
+    pub state: CanState,
+    pub sent: u64,
+    pub received: u64,
+    pub filtered: u64,
+    pub failed: u64,
+    pub overruns: u64,
+}
+
+/// Struct representing a virtio-can device bridged to a host CAN bus.
+///
+/// # Attributes
+///
+/// * `bus` - The host bus.
+/// * `filter` - The identifier filters.
+/// * `fd` - Whether CAN FD frames are offered.
+/// * `started` - Whether the guest started the controller.
+/// * `pending` - Received frames waiting for the guest.
+/// * `stats` - Statistics of the device.
+/// * `channel` - Backend request channel, to tell the guest about status changes.
+pub struct CanDevice<B: CanBus> {
+    bus: B,
+    filter: CanFilter,
+    fd: bool,
+    started: bool,
+    pending: VecDeque<CanFrame>,
+    stats: CanStats,
+    channel: BackendChannel,
+}
+
+impl<B: CanBus> CanDevice<B> {
+    /// Creates a CAN device.
+    ///
+    /// # Arguments
+    ///
+    /// * `bus` - The host bus.
+    /// * `filter` - The identifier filters.
+    /// * `fd` - Whether CAN FD frames are offered.
+    ///
+    /// # Returns
+    ///
+    /// * `CanDevice<B>` - The device, its controller stopped.
+    pub fn new(bus: B, filter: CanFilter, fd: bool) -> Self {
+        CanDevice {
+            bus,
+            filter,
+            fd,
+            started: false,
+            pending: VecDeque::new(),
+            stats: CanStats::default(),
+            channel: BackendChannel::default(),
+        }
+    }
+
+    /// Returns the statistics of the device.
+    pub fn stats(&self) -> CanStats {
+        self.stats
+    }
+
+    /// Takes in what the host bus delivered: received frames the filters accept while the
+    /// controller is started, and changes of the controller state (telling the guest once
+    /// the bus-off status changes).
+    pub fn poll(&mut self) {
+        while let Ok(Some(event)) = self.bus.recv() {
+            match event {
+                CanEvent::Frame(frame) => {
+                    if !self.started || !frame.valid(self.fd) {
+                        continue;
+                    }
+                    if !self.filter.permits(frame.id) {
+                        self.stats.filtered += 1;
+                        continue;
+                    }
+                    if self.pending.len() == BAO_CAN_RX_QUEUE_MAX {
+                        self.pending.pop_front();
+                        self.stats.overruns += 1;
+                    }
+                    self.pending.push_back(frame);
+                }
+                CanEvent::State(state) => {
+                    let bus_off = self.stats.state == CanState::BusOff;
+                    self.stats.state = state;
+                    if bus_off != (state == CanState::BusOff) {
+                        // Without a channel, the guest sees it once it reads the status
+                        let _ = self.channel.config_changed();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Serves a frame sent by the guest.
+    ///
+    /// # Arguments
+    ///
+    /// * `header` - Header of the transmit message.
+    /// * `sdu` - Payload of the frame.
+    ///
+    /// # Returns
+    ///
+    /// * `u8` - The result of the transmission.
+    fn transmit(&mut self, header: &[u8], sdu: &[u8]) -> u8 {
+        let flags = u16::from_le_bytes([header[6], header[7]]);
+        let frame = CanFrame {
+            id: u32::from_le_bytes(header[8..12].try_into().unwrap()),
+            extended: flags & VIRTIO_CAN_FLAGS_EXTENDED != 0,
+            rtr: flags & VIRTIO_CAN_FLAGS_RTR != 0,
+            fd: flags & VIRTIO_CAN_FLAGS_FD != 0,
+            data: sdu.to_vec(),
+        };
+        if !self.started || self.stats.state == CanState::BusOff || !frame.valid(self.fd) {
+            self.stats.failed += 1;
+            return VIRTIO_CAN_RESULT_NOT_OK;
+        }
+        if !self.filter.permits(frame.id) {
+            self.stats.filtered += 1;
+            return VIRTIO_CAN_RESULT_NOT_OK;
+        }
+        match self.bus.send(&frame) {
+            Ok(()) => {
+                self.stats.sent += 1;
+                VIRTIO_CAN_RESULT_OK
+            }
+            Err(_) => {
+                self.stats.failed += 1;
+                VIRTIO_CAN_RESULT_NOT_OK
+            }
+        }
+    }
+
+    /// Fills a receive buffer of the guest with the oldest frame waiting.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf` - The buffer.
+    ///
+    /// # Returns
+    ///
+    /// * `u32` - The number of bytes written (none if no frame is waiting).
+    fn receive(&mut self, buf: &mut [u8]) -> u32 {
+        let len = match self.pending.front() {
+            Some(frame) if VIRTIO_CAN_HEADER_SIZE + frame.data.len() <= buf.len() => {
+                VIRTIO_CAN_HEADER_SIZE + frame.data.len()
+            }
+            _ => return 0,
+        };
+        let frame = self.pending.pop_front().unwrap();
+        let mut flags = 0;
+        if frame.extended {
+            flags |= VIRTIO_CAN_FLAGS_EXTENDED;
+        }
+        if frame.rtr {
+            flags |= VIRTIO_CAN_FLAGS_RTR;
+        }
+        if frame.fd {
+            flags |= VIRTIO_CAN_FLAGS_FD;
+        }
+        buf[..2].copy_from_slice(&VIRTIO_CAN_RX.to_le_bytes());
+        buf[6..8].copy_from_slice(&flags.to_le_bytes());
+        buf[8..12].copy_from_slice(&frame.id.to_le_bytes());
+        buf[VIRTIO_CAN_HEADER_SIZE..len].copy_from_slice(&frame.data);
+        self.stats.received += 1;
+        len as u32
+    }
+}
+
+impl<B: CanBus> VirtioDevice for CanDevice<B> {
+    fn features(&self) -> u64 {
+        let fd = if self.fd { VIRTIO_CAN_F_CAN_FD } else { 0 };
+        VIRTIO_F_VERSION_1 | VIRTIO_CAN_F_CAN_CLASSIC | VIRTIO_CAN_F_RTR_FRAMES | fd
+    }
+
+    fn num_queues(&self) -> usize {
+        // The transmit, receive and control queues
+        3
+    }
+
+    fn config(&self) -> Vec<u8> {
+        let status = match self.stats.state {
+            CanState::BusOff => VIRTIO_CAN_S_CTRL_BUSOFF,
+            _ => 0,
+        };
+        status.to_le_bytes().to_vec()
+    }
+
+    fn process(&mut self, readable: &[u8], writable: &mut [u8]) -> u32 {
+        self.poll();
+        // Receive buffers are writable only
+        if readable.is_empty() {
+            return self.receive(writable);
+        }
+        if writable.is_empty() || readable.len() < 2 {
+            return 0;
+        }
+        writable[0] = match u16::from_le_bytes([readable[0], readable[1]]) {
+            VIRTIO_CAN_TX if readable.len() >= VIRTIO_CAN_HEADER_SIZE => {
+                let (header, sdu) = readable.split_at(VIRTIO_CAN_HEADER_SIZE);
+                self.transmit(header, sdu)
+            }
+            VIRTIO_CAN_SET_CTRL_MODE_START => {
+                self.started = true;
+                VIRTIO_CAN_RESULT_OK
+            }
+            VIRTIO_CAN_SET_CTRL_MODE_STOP => {
+                self.started = false;
+                self.pending.clear();
+                VIRTIO_CAN_RESULT_OK
+            }
+            _ => VIRTIO_CAN_RESULT_NOT_OK,
+        };
+        1
+    }
+}
+
+/// Struct representing the built-in CAN backend of a device.
+///
+/// # Attributes
+///
+/// * `device` - The device, shared with its backend.
+/// * `backend` - The vhost-user backend serving the device.
+pub struct CanBackend {
+    device: Arc<Mutex<CanDevice<SocketCan>>>,
+    backend: VhostUserBackend,
+}
+
+impl CanBackend {
+    /// Creates the CAN backend of a device.
+    ///
+    /// # Arguments
+    ///
+    /// * `device` - The device, whose `interface` option names the host interface and
+    ///   whose `accept`, `reject` and `fd` options set what crosses.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<CanBackend>` - The backend, `InvalidBackendOption` if an option is missing
+    ///   or invalid, `CanSocketFailed` if the interface cannot be opened.
+    pub fn open(device: &ConfigDevice) -> Result<Self> {
+        let option = |key: &str| device.options.get(key).map(String::as_str);
+        let interface = option("interface").ok_or_else(|| {
+            Error::InvalidBackendOption("interface".to_string(), "missing".to_string())
+        })?;
+        let fd = match option("fd") {
+            None | Some("false") => false,
+            Some("true") => true,
+            Some(value) => {
+                return Err(Error::InvalidBackendOption(
+                    "fd".to_string(),
+                    format!("{} is not a boolean", value),
+                ))
+            }
+        };
+        let filter = CanFilter::from_device(device)?;
+        let bus = SocketCan::open(interface, fd)?;
+        let poller = EventPoller::new(device);
+
+        let device = Arc::new(Mutex::new(CanDevice::new(bus, filter, fd)));
+        let backend = VhostUserBackend::new(Box::new(device.clone())).with_poller(poller);
+        device.lock().unwrap().channel = backend.channel();
+        Ok(CanBackend { device, backend })
+    }
+
+    /// Returns the statistics of the device.
+    pub fn stats(&self) -> CanStats {
+        self.device.lock().unwrap().stats()
+    }
+}
+
+impl InProcessBackend for CanBackend {
+    fn serve(self: Box<Self>, socket: &Path) -> Result<()> {
+        Box::new(self.backend).serve(socket)
+    }
+}
+
+/// Creates the built-in CAN backend of a device (see `CanBackend::open`).
+///
+/// # Arguments
+///
+/// * `device` - The device.
+///
+/// # Returns
+///
+/// * `Result<Box<dyn InProcessBackend>>` - The backend bridging the device.
+pub fn can_backend(device: &ConfigDevice) -> Result<Box<dyn InProcessBackend>> {
+    Ok(Box::new(CanBackend::open(device)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::backend_device;
+
+    fn device(options: &[(&str, &str)]) -> ConfigDevice {
+        backend_device("can0", "can", Some(BAO_CAN_BACKEND), options)
+    }
+
+    /// A bus recording the frames sent, delivering the events queued.
+    #[derive(Default)]
+    struct FakeBus {
+        sent: Vec<CanFrame>,
+        events: VecDeque<CanEvent>,
+    }
+
+    impl CanBus for FakeBus {
+        fn send(&mut self, frame: &CanFrame) -> io::Result<()> {
+            self.sent.push(frame.clone());
+            Ok(())
+        }
+
+        fn recv(&mut self) -> io::Result<Option<CanEvent>> {
+            Ok(self.events.pop_front())
+        }
+    }
+
+    /// Builds a virtio-can message.
+    fn message(kind: u16, flags: u16, id: u32, sdu: &[u8]) -> Vec<u8> {
+        let mut message = vec![0; VIRTIO_CAN_HEADER_SIZE];
+        message[..2].copy_from_slice(&kind.to_le_bytes());
+        message[6..8].copy_from_slice(&flags.to_le_bytes());
+        message[8..12].copy_from_slice(&id.to_le_bytes());
+        message.extend_from_slice(sdu);
+        message
+    }
+
+    /// Sends a message on the transmit or control queue, returning its result.
+    fn send(device: &mut CanDevice<FakeBus>, message: &[u8]) -> u8 {
+        let mut result = [0xffu8];
+        assert_eq!(device.process(message, &mut result), 1);
+        result[0]
+    }
+
+    fn frame(id: u32, data: &[u8]) -> CanFrame {
+        CanFrame {
+            id,
+            data: data.to_vec(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_can_filter() {
+        let filter = CanFilter::from_device(&device(&[
+            ("accept", "0x100-0x1ff, 0x7df"),
+            ("reject", "0x180-0x18f"),
+        ]))
+        .unwrap();
+        assert_eq!(filter.accept, Some(vec![0x100..=0x1ff, 0x7df..=0x7df]));
+        for (id, permitted) in [
+            (0x0ff, false),
+            (0x100, true),
+            (0x17f, true),
+            (0x180, false),
+            (0x18f, false),
+            (0x190, true),
+            (0x1ff, true),
+            (0x7df, true),
+            (0x7e0, false),
+        ] {
+            assert_eq!(filter.permits(id), permitted, "{:#x}", id);
+        }
+        let filter = CanFilter::from_device(&device(&[("reject", "0x123")])).unwrap();
+        assert!(filter.permits(0x122) && !filter.permits(0x123));
+        assert!(CanFilter::from_device(&device(&[]))
+            .unwrap()
+            .permits(CAN_EFF_MASK));
+
+        for value in ["", "x", "0x200-0x100", "0x20000000", "0x1-0x2-0x3"] {
+            assert!(
+                matches!(
+                    CanFilter::from_device(&device(&[("accept", value)])),
+                    Err(Error::InvalidBackendOption(option, _)) if option == "accept"
+                ),
+                "{}",
+                value
+            );
+        }
+        assert!(matches!(
+            can_backend(&device(&[("fd", "yes"), ("interface", "can0")])),
+            Err(Error::InvalidBackendOption(option, _)) if option == "fd"
+        ));
+        assert!(matches!(
+            can_backend(&device(&[])),
+            Err(Error::InvalidBackendOption(option, _)) if option == "interface"
+        ));
+        assert!(matches!(
+            can_backend(&device(&[("interface", "nonexistent0")])),
+            Err(Error::CanSocketFailed(..))
+        ));
+    }
+
+    #[test]
+    fn test_can_frames() {
+        let classic = CanFrame {
+            id: 0x1234567,
+            extended: true,
+            data: vec![1, 2, 3],
+            ..Default::default()
+        };
+        assert!(classic.valid(false));
+        let encoded = classic.to_socketcan();
+        assert_eq!(encoded.len(), CAN_MTU);
+        assert_eq!(
+            CanFrame::from_socketcan(&encoded),
+            Some(CanEvent::Frame(classic))
+        );
+        let fd = CanFrame {
+            id: 0x123,
+            fd: true,
+            data: vec![7; 12],
+            ..Default::default()
+        };
+        assert!(fd.valid(true) && !fd.valid(false));
+        let encoded = fd.to_socketcan();
+        assert_eq!((encoded.len(), encoded[5]), (CANFD_MTU, CANFD_FDF));
+        assert_eq!(
+            CanFrame::from_socketcan(&encoded),
+            Some(CanEvent::Frame(fd))
+        );
+
+        // Malformed frames
+        assert!(!frame(0x800, &[]).valid(true));
+        assert!(!frame(0x123, &[0; 9]).valid(true));
+        let mut fd = frame(0x123, &[0; 9]);
+        fd.fd = true;
+        assert!(!fd.valid(true));
+        fd.data.truncate(8);
+        fd.rtr = true;
+        assert!(!fd.valid(true));
+        assert_eq!(CanFrame::from_socketcan(&[0; 15]), None);
+
+        // Error frames
+        let error = |id: u32, controller: u8| {
+            let mut frame = vec![0; CAN_MTU];
+            frame[..4].copy_from_slice(&(CAN_ERR_FLAG | id).to_ne_bytes());
+            frame[4] = 8;
+            frame[9] = controller;
+            CanFrame::from_socketcan(&frame)
+        };
+        let state = |state| Some(CanEvent::State(state));
+        assert_eq!(error(CAN_ERR_BUSOFF, 0), state(CanState::BusOff));
+        assert_eq!(error(CAN_ERR_RESTARTED, 0), state(CanState::ErrorActive));
+        let passive = CAN_ERR_CRTL_TX_PASSIVE;
+        assert_eq!(error(CAN_ERR_CRTL, passive), state(CanState::ErrorPassive));
+        let active = CAN_ERR_CRTL_ACTIVE;
+        assert_eq!(error(CAN_ERR_CRTL, active), state(CanState::ErrorActive));
+        assert_eq!(error(CAN_ERR_CRTL, 0x04), None);
+    }
+
+    #[test]
+    fn test_can_device() {
+        let filter = CanFilter::from_device(&device(&[("reject", "0x666")])).unwrap();
+        let mut device = CanDevice::new(FakeBus::default(), filter, false);
+        assert_eq!(device.features() & VIRTIO_CAN_F_CAN_FD, 0);
+        assert_eq!(device.config(), [0, 0]);
+        const OK: u8 = VIRTIO_CAN_RESULT_OK;
+        const NOT_OK: u8 = VIRTIO_CAN_RESULT_NOT_OK;
+
+        // Nothing crosses until the controller is started
+        assert_eq!(
+            send(&mut device, &message(VIRTIO_CAN_TX, 0, 0x123, &[1])),
+            NOT_OK
+        );
+        device
+            .bus
+            .events
+            .push_back(CanEvent::Frame(frame(0x100, &[])));
+        assert_eq!(device.process(&[], &mut [0; 76]), 0);
+        let start = message(VIRTIO_CAN_SET_CTRL_MODE_START, 0, 0, &[]);
+        assert_eq!(send(&mut device, &start[..2]), OK);
+
+        // Frames sent, filtered or malformed (FD frames are not offered)
+        assert_eq!(
+            send(&mut device, &message(VIRTIO_CAN_TX, 0, 0x123, &[1, 2])),
+            OK
+        );
+        assert_eq!(device.bus.sent, [frame(0x123, &[1, 2])]);
+        assert_eq!(
+            send(&mut device, &message(VIRTIO_CAN_TX, 0, 0x666, &[])),
+            NOT_OK
+        );
+        let fd = VIRTIO_CAN_FLAGS_FD;
+        assert_eq!(
+            send(&mut device, &message(VIRTIO_CAN_TX, fd, 0x123, &[])),
+            NOT_OK
+        );
+        assert_eq!(
+            send(&mut device, &message(VIRTIO_CAN_TX, 0, 0x800, &[])),
+            NOT_OK
+        );
+        assert_eq!(send(&mut device, &[0xff, 0xff]), NOT_OK);
+
+        // Frames received, filtered
+        let extended = CanFrame {
+            id: 0x1abcdef,
+            extended: true,
+            data: vec![9; 8],
+            ..Default::default()
+        };
+        device.bus.events.extend([
+            CanEvent::Frame(frame(0x666, &[])),
+            CanEvent::Frame(extended.clone()),
+        ]);
+        let mut buf = [0u8; 76];
+        assert_eq!(device.process(&[], &mut buf), 20);
+        let flags = VIRTIO_CAN_FLAGS_EXTENDED;
+        assert_eq!(buf[..20], message(VIRTIO_CAN_RX, flags, 0x1abcdef, &[9; 8]));
+        assert_eq!(device.process(&[], &mut buf), 0);
+
+        // Bus-off fails the frames sent and shows in the status, until restarted
+        device
+            .bus
+            .events
+            .push_back(CanEvent::State(CanState::ErrorPassive));
+        assert_eq!(
+            send(&mut device, &message(VIRTIO_CAN_TX, 0, 0x124, &[])),
+            OK
+        );
+        assert_eq!(device.config(), [0, 0]);
+        device
+            .bus
+            .events
+            .push_back(CanEvent::State(CanState::BusOff));
+        assert_eq!(
+            send(&mut device, &message(VIRTIO_CAN_TX, 0, 0x125, &[])),
+            NOT_OK
+        );
+        assert_eq!(device.config(), VIRTIO_CAN_S_CTRL_BUSOFF.to_le_bytes());
+        device
+            .bus
+            .events
+            .push_back(CanEvent::State(CanState::ErrorActive));
+        assert_eq!(
+            send(&mut device, &message(VIRTIO_CAN_TX, 0, 0x126, &[])),
+            OK
+        );
+        assert_eq!(device.config(), [0, 0]);
+
+        // Received frames beyond the limit overrun the oldest
+        for id in 0..BAO_CAN_RX_QUEUE_MAX as u32 + 2 {
+            device.bus.events.push_back(CanEvent::Frame(frame(id, &[])));
+        }
+        device.poll();
+        assert_eq!(device.process(&[], &mut buf), 12);
+        assert_eq!(buf[8..12], 2u32.to_le_bytes());
+
+        assert_eq!(
+            device.stats(),
+            CanStats {
+                state: CanState::ErrorActive,
+                sent: 3,
+                received: 2,
+                filtered: 2,
+                failed: 4,
+                overruns: 2,
+            }
+        );
+
+        // Stopping the controller drops the frames waiting
+        let stop = message(VIRTIO_CAN_SET_CTRL_MODE_STOP, 0, 0, &[]);
+        assert_eq!(send(&mut device, &stop[..2]), OK);
+        assert_eq!(device.process(&[], &mut buf), 0);
+    }
+}
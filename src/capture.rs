@@ -0,0 +1,602 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao packet captures.
+//!
+//! The frames crossing the TAP interface of a net device served in-process can be
+//! captured into a pcapng file from the control socket (`capture NAME PATH`), to debug the
+//! networking of a guest without installing a capture tool inside it. The frontend
+//! captures on the host side of the interface (an `AF_PACKET` socket bound to it), so the
+//! frames are recorded with their direction as seen by the interface: inbound for the
+//! frames sent by the guest, outbound for the frames delivered to it.
+//!
+//! A capture ends once its file would exceed its size limit (`BAO_CAPTURE_MAX_BYTES` by
+//! default) or its duration elapsed, or once stopped (`stop-capture NAME`). Frames are cut
+//! to the snapshot length, their original length being recorded.
+
+#![allow(dead_code)]
+
+use super::defines::*;
+use super::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::ffi::CString;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Trait implemented by the device models whose frames can be captured.
+pub trait Capture: Send {
+    /// Starts capturing the frames of the device.
+    ///
+    /// # Arguments
+    ///
+    /// * `options` - Where to write the capture, and its limits.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok once the capture started, `CaptureRunning` if one is running.
+    fn start(&mut self, options: &CaptureOptions) -> Result<()>;
+
+    /// Stops the capture (if it is still running).
+    ///
+    /// # Returns
+    ///
+    /// * `Result<CaptureStats>` - What was captured, `CaptureNotRunning` if no capture was
+    ///   started since the last one was stopped.
+    fn stop(&mut self) -> Result<CaptureStats>;
+}
+
+/// Struct representing the options of a packet capture.
+///
+/// # Attributes
+///
+/// * `path` - Path of the pcapng file (truncated if it exists).
+/// * `max_bytes` - Maximum file size.
+/// * `duration` - Maximum capture duration (None to capture until stopped).
+/// * `snaplen` - Number of bytes kept of every frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureOptions {
+    pub path: PathBuf,
+    pub max_bytes: u64,
+    pub duration: Option<Duration>,
+    pub snaplen: u32,
+}
+
+impl CaptureOptions {
+    /// Creates the options of a capture with the default limits.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of the pcapng file.
+    ///
+    /// # Returns
+    ///
+    /// * `CaptureOptions` - The options.
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        CaptureOptions {
+            path: path.into(),
+            max_bytes: BAO_CAPTURE_MAX_BYTES,
+            duration: None,
+            snaplen: BAO_CAPTURE_SNAPLEN,
+        }
+    }
+}
+
+/// Struct representing what a packet capture recorded.
+///
+/// # Attributes
+///
+/// * `frames` - Number of frames captured.
+/// * `truncated` - Number of frames cut to the snapshot length.
+/// * `bytes` - Size of the pcapng file.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CaptureStats {
+    pub frames: u64,
+    pub truncated: u64,
+    pub bytes: u64,
+}
+
+/// Appends a pcapng option, padded to 32 bits.
+fn option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    body.resize(body.len().next_multiple_of(4), 0);
+}
+
+/// Frames a pcapng block around its body (padded to 32 bits).
+fn block(kind: u32, body: &[u8]) -> Vec<u8> {
+    let len = (12 + body.len()) as u32;
+    let mut block = Vec::with_capacity(len as usize);
+    block.extend_from_slice(&kind.to_le_bytes());
+    block.extend_from_slice(&len.to_le_bytes());
+    block.extend_from_slice(body);
+    block.extend_from_slice(&len.to_le_bytes());
+    block
+}
+
+/// Struct representing a pcapng file of a single Ethernet interface.
+///
+/// # Attributes
+///
+/// * `out` - Where the file is written.
+/// * `stats` - What was written so far.
+/// * `max_bytes` - Maximum file size.
+/// * `snaplen` - Number of bytes kept of every frame.
+pub struct PcapngWriter<W: Write> {
+    out: W,
+    stats: CaptureStats,
+    max_bytes: u64,
+    snaplen: u32,
+}
+
+impl<W: Write> PcapngWriter<W> {
+    /// Starts a pcapng file, writing its section header and the description of the
+    /// interface.
+    ///
+    /// # Arguments
+    ///
+    /// * `out` - Where to write the file.
+    /// * `interface` - Name of the captured interface.
+    /// * `snaplen` - Number of bytes kept of every frame.
+    /// * `max_bytes` - Maximum file size (the headers are always written).
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<PcapngWriter<W>>` - The writer.
+    pub fn new(mut out: W, interface: &str, snaplen: u32, max_bytes: u64) -> io::Result<Self> {
+        let mut header = Vec::new();
+        header.extend_from_slice(&PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes()); // Major version
+        header.extend_from_slice(&0u16.to_le_bytes()); // Minor version
+        header.extend_from_slice(&(-1i64).to_le_bytes()); // Section length (unknown)
+        let header = block(PCAPNG_SECTION_HEADER, &header);
+
+        let mut description = Vec::new();
+        description.extend_from_slice(&LINKTYPE_ETHERNET.to_le_bytes());
+        description.extend_from_slice(&0u16.to_le_bytes()); // Reserved
+        description.extend_from_slice(&snaplen.to_le_bytes());
+        option(&mut description, PCAPNG_IF_NAME, interface.as_bytes());
+        option(&mut description, 0, &[]); // End of options
+        let description = block(PCAPNG_INTERFACE_DESCRIPTION, &description);
+
+        out.write_all(&header)?;
+        out.write_all(&description)?;
+        Ok(PcapngWriter {
+            out,
+            stats: CaptureStats {
+                bytes: (header.len() + description.len()) as u64,
+                ..Default::default()
+            },
+            max_bytes,
+            snaplen,
+        })
+    }
+
+    /// Records a frame, unless the file would exceed its maximum size.
+    ///
+    /// # Arguments
+    ///
+    /// * `frame` - The frame (cut to the snapshot length if longer).
+    /// * `len` - Original length of the frame.
+    /// * `outbound` - Whether the interface sent the frame (rather than received it).
+    /// * `timestamp` - Time elapsed since the Unix epoch.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<bool>` - Whether the frame was recorded (false once the file is full).
+    pub fn write_frame(
+        &mut self,
+        frame: &[u8],
+        len: usize,
+        outbound: bool,
+        timestamp: Duration,
+    ) -> io::Result<bool> {
+        let data = &frame[..frame.len().min(self.snaplen as usize)];
+        let micros = timestamp.as_micros() as u64;
+        let flags = match outbound {
+            true => PCAPNG_EPB_OUTBOUND,
+            false => PCAPNG_EPB_INBOUND,
+        };
+
+        let mut packet = Vec::with_capacity(48 + data.len());
+        packet.extend_from_slice(&0u32.to_le_bytes()); // Interface ID
+        packet.extend_from_slice(&((micros >> 32) as u32).to_le_bytes());
+        packet.extend_from_slice(&(micros as u32).to_le_bytes());
+        packet.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        packet.extend_from_slice(&(len as u32).to_le_bytes());
+        packet.extend_from_slice(data);
+        packet.resize(packet.len().next_multiple_of(4), 0);
+        option(&mut packet, PCAPNG_EPB_FLAGS, &flags.to_le_bytes());
+        option(&mut packet, 0, &[]); // End of options
+        let packet = block(PCAPNG_ENHANCED_PACKET, &packet);
+
+        if self.stats.bytes + packet.len() as u64 > self.max_bytes {
+            return Ok(false);
+        }
+        self.out.write_all(&packet)?;
+        self.stats.frames += 1;
+        self.stats.truncated += u64::from(data.len() < len);
+        self.stats.bytes += packet.len() as u64;
+        Ok(true)
+    }
+
+    /// Returns what was written so far.
+    pub fn stats(&self) -> CaptureStats {
+        self.stats
+    }
+
+    /// Flushes the file.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// Opens an `AF_PACKET` socket receiving every frame crossing an interface.
+///
+/// # Arguments
+///
+/// * `interface` - Interface name.
+///
+/// # Returns
+///
+/// * `io::Result<OwnedFd>` - The socket, whose receive calls time out every
+///   `BAO_CAPTURE_POLL_MS`.
+fn packet_socket(interface: &str) -> io::Result<OwnedFd> {
+    let name = CString::new(interface).map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+    // SAFETY: The name is a valid C string.
+    let index = match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => return Err(io::Error::last_os_error()),
+        index => index,
+    };
+
+    // No protocol until bound, so that no frame of another interface gets queued
+    // SAFETY: The arguments are valid and the result is checked.
+    let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_RAW | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `fd` is a valid file descriptor owned by nothing else.
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let timeout = libc::timeval {
+        tv_sec: 0,
+        tv_usec: (BAO_CAPTURE_POLL_MS * 1000) as libc::suseconds_t,
+    };
+    // SAFETY: `timeout` is valid for reads and the length is its size.
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVTIMEO,
+            &timeout as *const libc::timeval as *const libc::c_void,
+            size_of::<libc::timeval>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // SAFETY: `sockaddr_ll` is plain data, for which zeroes are valid.
+    let mut addr: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+    addr.sll_family = libc::AF_PACKET as u16;
+    addr.sll_protocol = (libc::ETH_P_ALL as u16).to_be();
+    addr.sll_ifindex = index as i32;
+    // SAFETY: `addr` is valid for reads and the length is its size.
+    let ret = unsafe {
+        libc::bind(
+            socket.as_raw_fd(),
+            &addr as *const libc::sockaddr_ll as *const libc::sockaddr,
+            size_of::<libc::sockaddr_ll>() as libc::socklen_t,
+        )
+    };
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(socket)
+}
+
+/// Records the frames received by a packet socket until the capture ends.
+///
+/// # Arguments
+///
+/// * `socket` - The packet socket.
+/// * `writer` - The pcapng file.
+/// * `deadline` - When the capture ends (None to capture until stopped).
+/// * `stop` - Set to stop the capture.
+///
+/// # Returns
+///
+/// * `io::Result<CaptureStats>` - What was captured.
+fn capture_frames(
+    socket: OwnedFd,
+    mut writer: PcapngWriter<BufWriter<File>>,
+    deadline: Option<Instant>,
+    stop: &AtomicBool,
+) -> io::Result<CaptureStats> {
+    let mut frame = vec![0u8; writer.snaplen as usize];
+    while !stop.load(Ordering::Relaxed) && deadline.is_none_or(|d| Instant::now() < d) {
+        // SAFETY: `sockaddr_ll` is plain data, for which zeroes are valid.
+        let mut addr: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+        let mut addr_len = size_of::<libc::sockaddr_ll>() as libc::socklen_t;
+        // SAFETY: The buffer and the address are valid for writes of their lengths.
+        // `MSG_TRUNC` returns the original length of the frames longer than the buffer.
+        let len = unsafe {
+            libc::recvfrom(
+                socket.as_raw_fd(),
+                frame.as_mut_ptr() as *mut libc::c_void,
+                frame.len(),
+                libc::MSG_TRUNC,
+                &mut addr as *mut libc::sockaddr_ll as *mut libc::sockaddr,
+                &mut addr_len,
+            )
+        };
+        if len < 0 {
+            let err = io::Error::last_os_error();
+            match err.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted => continue,
+                _ => return Err(err),
+            }
+        }
+        let len = len as usize;
+        let outbound = addr.sll_pkttype == libc::PACKET_OUTGOING;
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        if !writer.write_frame(&frame[..len.min(frame.len())], len, outbound, timestamp)? {
+            break;
+        }
+    }
+    writer.flush()?;
+    Ok(writer.stats())
+}
+
+/// Struct representing a running packet capture.
+///
+/// # Attributes
+///
+/// * `stop` - Set to stop the capture.
+/// * `thread` - The capturing thread.
+struct CaptureSession {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<io::Result<CaptureStats>>,
+}
+
+/// Struct representing the packet captures of a network interface (e.g. the TAP interface
+/// of a net device, see `Tap::capture`).
+///
+/// # Attributes
+///
+/// * `interface` - Interface name.
+/// * `session` - The current capture (running, or ended but not stopped yet).
+pub struct PacketCapture {
+    interface: String,
+    session: Option<CaptureSession>,
+}
+
+impl PacketCapture {
+    /// Creates the packet captures of a network interface.
+    ///
+    /// # Arguments
+    ///
+    /// * `interface` - Interface name.
+    ///
+    /// # Returns
+    ///
+    /// * `PacketCapture` - The packet captures, none running.
+    pub fn new(interface: &str) -> Self {
+        PacketCapture {
+            interface: interface.to_string(),
+            session: None,
+        }
+    }
+
+    /// Checks whether a capture is running.
+    pub fn running(&self) -> bool {
+        self.session
+            .as_ref()
+            .is_some_and(|session| !session.thread.is_finished())
+    }
+}
+
+impl Capture for PacketCapture {
+    fn start(&mut self, options: &CaptureOptions) -> Result<()> {
+        if self.running() {
+            return Err(Error::CaptureRunning(self.interface.clone()));
+        }
+        // An ended capture that was not stopped is superseded
+        if let Some(session) = self.session.take() {
+            let _ = session.thread.join();
+        }
+
+        let failed = |e| Error::CaptureFailed(self.interface.clone(), e);
+        let socket = packet_socket(&self.interface).map_err(failed)?;
+        let file = File::create(&options.path).map_err(failed)?;
+        let writer = PcapngWriter::new(
+            BufWriter::new(file),
+            &self.interface,
+            options.snaplen,
+            options.max_bytes,
+        )
+        .map_err(failed)?;
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let deadline = options.duration.map(|duration| Instant::now() + duration);
+        let thread = thread::Builder::new()
+            .name(format!("capture-{}", self.interface))
+            .spawn({
+                let stop = stop.clone();
+                move || capture_frames(socket, writer, deadline, &stop)
+            })
+            .map_err(failed)?;
+        self.session = Some(CaptureSession { stop, thread });
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<CaptureStats> {
+        let session = self
+            .session
+            .take()
+            .ok_or_else(|| Error::CaptureNotRunning(self.interface.clone()))?;
+        session.stop.store(true, Ordering::Relaxed);
+        let failed = |e| Error::CaptureFailed(self.interface.clone(), e);
+        match session.thread.join() {
+            Ok(result) => result.map_err(failed),
+            Err(_) => Err(failed(io::Error::other("capture thread panicked"))),
+        }
+    }
+}
+
+impl Drop for PacketCapture {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tap::{Tap, TapConfig};
+    use std::fs::{self, OpenOptions};
+
+    /// Splits a pcapng file into its blocks (type and body).
+    fn blocks(mut file: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let mut blocks = Vec::new();
+        while !file.is_empty() {
+            let kind = u32::from_le_bytes(file[..4].try_into().unwrap());
+            let len = u32::from_le_bytes(file[4..8].try_into().unwrap()) as usize;
+            assert_eq!(len % 4, 0);
+            assert_eq!(file[len - 4..len], file[4..8]);
+            blocks.push((kind, file[8..len - 4].to_vec()));
+            file = &file[len..];
+        }
+        blocks
+    }
+
+    #[test]
+    fn test_pcapng_writer() {
+        let mut writer = PcapngWriter::new(Vec::new(), "bao-tap0", 16, 200).unwrap();
+        let timestamp = Duration::from_micros(0x1_0000_0002);
+        assert!(writer.write_frame(&[0xab; 6], 6, false, timestamp).unwrap());
+        assert!(writer
+            .write_frame(&[0xcd; 20], 60, true, timestamp)
+            .unwrap());
+        // The file would exceed its maximum size
+        assert!(!writer.write_frame(&[0xef; 6], 6, false, timestamp).unwrap());
+        let stats = writer.stats();
+        assert_eq!(stats.frames, 2);
+        assert_eq!(stats.truncated, 1);
+
+        let file = writer.out;
+        assert_eq!(stats.bytes, file.len() as u64);
+        let blocks = blocks(&file);
+        assert_eq!(
+            blocks.iter().map(|(kind, _)| *kind).collect::<Vec<_>>(),
+            [
+                PCAPNG_SECTION_HEADER,
+                PCAPNG_INTERFACE_DESCRIPTION,
+                PCAPNG_ENHANCED_PACKET,
+                PCAPNG_ENHANCED_PACKET
+            ]
+        );
+        assert_eq!(blocks[0].1[..4], PCAPNG_BYTE_ORDER_MAGIC.to_le_bytes());
+        // Ethernet, 16-byte snapshots, and the interface name
+        assert_eq!(blocks[1].1[..8], [1, 0, 0, 0, 16, 0, 0, 0]);
+        assert_eq!(blocks[1].1[8..20], *b"\x02\x00\x08\x00bao-tap0");
+
+        // Timestamp, captured and original lengths, data and direction
+        let packet = &blocks[2].1;
+        assert_eq!(
+            packet[4..20],
+            [1, 0, 0, 0, 2, 0, 0, 0, 6, 0, 0, 0, 6, 0, 0, 0]
+        );
+        assert_eq!(packet[20..28], [0xab, 0xab, 0xab, 0xab, 0xab, 0xab, 0, 0]);
+        assert_eq!(packet[28..36], [2, 0, 4, 0, 1, 0, 0, 0]);
+        let packet = &blocks[3].1;
+        assert_eq!(packet[12..20], [16, 0, 0, 0, 60, 0, 0, 0]);
+        assert_eq!(packet[20..36], [0xcd; 16]);
+        assert_eq!(packet[36..44], [2, 0, 4, 0, 2, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_packet_capture() {
+        let config = TapConfig {
+            name: "bao-cap%d".to_string(),
+            ..Default::default()
+        };
+        let tap = match Tap::create(&config) {
+            Ok(tap) => tap,
+            // Creating interfaces needs CAP_NET_ADMIN and the tun module
+            Err(Error::TapFailed(_, e))
+                if matches!(
+                    e.raw_os_error(),
+                    Some(libc::EPERM | libc::EACCES | libc::ENOENT | libc::ENODEV)
+                ) =>
+            {
+                return
+            }
+            Err(e) => panic!("{}", e),
+        };
+        let mut capture = tap.capture();
+        assert!(matches!(capture.stop(), Err(Error::CaptureNotRunning(_))));
+
+        let path = std::env::temp_dir().join(format!("bao-capture-{}.pcapng", std::process::id()));
+        let options = CaptureOptions {
+            duration: Some(Duration::from_secs(10)),
+            ..CaptureOptions::new(&path)
+        };
+        capture.start(&options).unwrap();
+        assert!(capture.running());
+        assert!(matches!(
+            capture.start(&options),
+            Err(Error::CaptureRunning(_))
+        ));
+
+        // Send a frame as the guest would, through a queue of the backend
+        let mut queue = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(TUN_DEVICE_PATH)
+            .unwrap();
+        let mut ifreq = [0u8; IFREQ_SIZE];
+        ifreq[..tap.name().len()].copy_from_slice(tap.name().as_bytes());
+        let flags = TUN_IFF_TAP | TUN_IFF_NO_PI | TUN_IFF_VNET_HDR | TUN_IFF_MULTI_QUEUE;
+        ifreq[IFNAMSIZ..IFNAMSIZ + 2].copy_from_slice(&flags.to_ne_bytes());
+        crate::tap::tun_ioctl(&queue, crate::tap::TUNSETIFF(), &mut ifreq).unwrap();
+        let mut frame = vec![0u8; 10]; // virtio-net header
+        frame.extend_from_slice(&[0xff; 6]); // Broadcast destination
+        frame.extend_from_slice(&[0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+        frame.extend_from_slice(&[0x88, 0xb5]); // Local experimental EtherType
+        frame.extend_from_slice(&[0x42; 46]);
+        queue.write_all(&frame).unwrap();
+        thread::sleep(Duration::from_millis(2 * BAO_CAPTURE_POLL_MS));
+
+        let stats = capture.stop().unwrap();
+        assert!(!capture.running());
+        let file = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(stats.bytes, file.len() as u64);
+        // The frame was received by the interface (the host may send some of its own)
+        let inbound = blocks(&file)
+            .into_iter()
+            .filter(|(kind, _)| *kind == PCAPNG_ENHANCED_PACKET)
+            .find(|(_, packet)| packet[20..34] == frame[10..24])
+            .unwrap();
+        assert_eq!(inbound.1[12..20], [60, 0, 0, 0, 60, 0, 0, 0]);
+        assert_eq!(inbound.1[80..88], [2, 0, 4, 0, 1, 0, 0, 0]);
+        assert_eq!(stats.frames as usize, blocks(&file).len() - 2);
+
+        assert!(matches!(
+            PacketCapture::new("bao-missing0").start(&options),
+            Err(Error::CaptureFailed(_, e)) if e.raw_os_error() == Some(libc::ENODEV)
+        ));
+    }
+}
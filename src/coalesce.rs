@@ -0,0 +1,306 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao interrupt coalescing.
+//!
+//! Fast backends (e.g. virtio-net at small packet sizes) complete requests faster than the
+//! guest can take interrupts; coalescing several completions into one interrupt cuts the
+//! interrupt storm at the cost of a bounded delay. The parameters can be changed at
+//! runtime through the management interface.
+
+#![allow(dead_code)]
+
+use super::error::Result;
+use super::hypervisor::Hypervisor;
+use super::types::{BaoIoEventFd, BaoIoRequest, BaoIrqFd, ConfigCoalesce};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Struct representing the coalescing state of a device.
+///
+/// # Attributes
+///
+/// * `params` - Coalescing parameters (disabled if None).
+/// * `pending` - Number of completions not yet notified.
+/// * `since` - Time of the oldest completion not yet notified.
+/// * `completions` - Number of completions.
+/// * `interrupts` - Number of interrupts injected.
+#[derive(Debug, Default)]
+struct CoalesceState {
+    params: Option<ConfigCoalesce>,
+    pending: u32,
+    since: Option<Instant>,
+    completions: u64,
+    interrupts: u64,
+}
+
+impl CoalesceState {
+    /// Takes the pending completions, accounting the interrupt notifying them.
+    fn inject(&mut self) -> bool {
+        self.pending = 0;
+        self.since = None;
+        crate::bao_instrumented! {
+            self.interrupts += 1;
+        }
+        true
+    }
+
+    /// Returns when the pending completions must be notified.
+    fn deadline(&self) -> Option<Instant> {
+        let since = self.since?;
+        Some(match self.params {
+            Some(params) => since + Duration::from_micros(params.max_delay_us),
+            None => since,
+        })
+    }
+}
+
+/// Struct representing the interrupt coalescer of a device.
+#[derive(Debug, Default)]
+pub struct IrqCoalescer {
+    state: Mutex<CoalesceState>,
+}
+
+impl IrqCoalescer {
+    /// Creates a coalescer.
+    ///
+    /// # Arguments
+    ///
+    /// * `params` - Coalescing parameters (every completion is notified if None).
+    ///
+    /// # Returns
+    ///
+    /// * `IrqCoalescer` - The coalescer.
+    pub fn new(params: Option<ConfigCoalesce>) -> Self {
+        IrqCoalescer {
+            state: Mutex::new(CoalesceState {
+                params: params.filter(ConfigCoalesce::is_enabled),
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Returns the coalescing parameters.
+    pub fn params(&self) -> Option<ConfigCoalesce> {
+        self.state.lock().unwrap().params
+    }
+
+    /// Changes the coalescing parameters (the pending completions are kept, and notified
+    /// by the next completion or `expire`).
+    ///
+    /// # Arguments
+    ///
+    /// * `params` - Coalescing parameters (every completion is notified if None).
+    pub fn set_params(&self, params: Option<ConfigCoalesce>) {
+        self.state.lock().unwrap().params = params.filter(ConfigCoalesce::is_enabled);
+    }
+
+    /// Returns the number of completions and of interrupts injected for them.
+    pub fn stats(&self) -> (u64, u64) {
+        let state = self.state.lock().unwrap();
+        (state.completions, state.interrupts)
+    }
+
+    /// Accounts a completion.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - Time of the completion.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - True if the guest must be interrupted now.
+    pub fn complete(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        crate::bao_instrumented! {
+            state.completions += 1;
+        }
+        state.pending += 1;
+        let since = *state.since.get_or_insert(now);
+        match state.params {
+            Some(params)
+                if state.pending < params.max_completions
+                    && now.saturating_duration_since(since)
+                        < Duration::from_micros(params.max_delay_us) =>
+            {
+                false
+            }
+            _ => state.inject(),
+        }
+    }
+
+    /// Returns when the pending completions must be notified.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Instant>` - The deadline, if completions are pending.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.state.lock().unwrap().deadline()
+    }
+
+    /// Checks if the pending completions are due.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - Current time.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - True if the guest must be interrupted now.
+    pub fn expire(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.deadline() {
+            Some(deadline) if deadline <= now => state.inject(),
+            _ => false,
+        }
+    }
+}
+
+/// Struct representing a hypervisor whose guest notifications are coalesced.
+///
+/// The owner must call `flush` by the coalescer deadline (e.g. from an epoll timeout) so
+/// the last completions of a burst are notified.
+///
+/// # Attributes
+///
+/// * `inner` - The hypervisor.
+/// * `coalescer` - The coalescer.
+pub struct CoalescingHypervisor<H: Hypervisor> {
+    inner: H,
+    coalescer: Arc<IrqCoalescer>,
+}
+
+impl<H: Hypervisor> CoalescingHypervisor<H> {
+    /// Coalesces the guest notifications of a hypervisor.
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - The hypervisor.
+    /// * `coalescer` - The coalescer.
+    ///
+    /// # Returns
+    ///
+    /// * `CoalescingHypervisor` - The coalescing hypervisor.
+    pub fn new(inner: H, coalescer: Arc<IrqCoalescer>) -> Self {
+        CoalescingHypervisor { inner, coalescer }
+    }
+
+    /// Notifies the pending completions that are due.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<bool>` - True if the guest was interrupted.
+    pub fn flush(&self) -> Result<bool> {
+        if !self.coalescer.expire(Instant::now()) {
+            return Ok(false);
+        }
+        self.inner.notify_guest()?;
+        Ok(true)
+    }
+}
+
+impl<H: Hypervisor> Hypervisor for CoalescingHypervisor<H> {
+    fn attach_client(&self) -> Result<()> {
+        self.inner.attach_client()
+    }
+
+    fn wait_request(&self, timeout: Duration) -> Result<bool> {
+        self.inner.wait_request(timeout)
+    }
+
+    fn next_request(&self) -> Result<Option<BaoIoRequest>> {
+        self.inner.next_request()
+    }
+
+    fn complete_request(&self, req: &BaoIoRequest) -> Result<()> {
+        self.inner.complete_request(req)
+    }
+
+    fn notify_guest(&self) -> Result<()> {
+        if self.coalescer.complete(Instant::now()) {
+            self.inner.notify_guest()?;
+        }
+        Ok(())
+    }
+
+    fn register_ioeventfd(&self, ioeventfd: &BaoIoEventFd) -> Result<()> {
+        self.inner.register_ioeventfd(ioeventfd)
+    }
+
+    fn register_irqfd(&self, irqfd: &BaoIrqFd) -> Result<()> {
+        self.inner.register_irqfd(irqfd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instrument::INSTRUMENTED;
+    use crate::testing::MockHypervisor;
+
+    #[test]
+    fn test_irq_coalescing() {
+        let now = Instant::now();
+        let us = Duration::from_micros;
+
+        // Disabled coalescing notifies every completion
+        let coalescer = IrqCoalescer::new(Some(ConfigCoalesce {
+            max_completions: 1,
+            max_delay_us: 100,
+        }));
+        assert_eq!(coalescer.params(), None);
+        assert!(coalescer.complete(now));
+        assert_eq!(coalescer.deadline(), None);
+
+        // The count bound fires first
+        coalescer.set_params(Some(ConfigCoalesce {
+            max_completions: 3,
+            max_delay_us: 100,
+        }));
+        assert!(!coalescer.complete(now));
+        assert_eq!(coalescer.deadline(), Some(now + us(100)));
+        assert!(!coalescer.complete(now + us(10)));
+        assert!(coalescer.complete(now + us(20)));
+        assert_eq!(coalescer.deadline(), None);
+
+        // The delay bound fires first
+        assert!(!coalescer.complete(now));
+        assert!(!coalescer.expire(now + us(99)));
+        assert!(coalescer.expire(now + us(100)));
+        assert!(!coalescer.expire(now + us(200)));
+        assert!(!coalescer.complete(now + us(300)));
+        assert!(coalescer.complete(now + us(400)));
+        let n = INSTRUMENTED as u64;
+        assert_eq!(coalescer.stats(), (7 * n, 4 * n));
+
+        // Disabling coalescing at runtime flushes the pending completions
+        assert!(!coalescer.complete(now));
+        coalescer.set_params(None);
+        assert!(coalescer.expire(now));
+    }
+
+    #[test]
+    fn test_coalescing_hypervisor() {
+        let mock = MockHypervisor::new();
+        let coalescer = Arc::new(IrqCoalescer::new(Some(ConfigCoalesce {
+            max_completions: 4,
+            max_delay_us: 1_000_000,
+        })));
+        let hypervisor = CoalescingHypervisor::new(&mock, coalescer.clone());
+        for _ in 0..10 {
+            hypervisor.notify_guest().unwrap();
+        }
+        assert_eq!(mock.interrupts(), 2);
+        assert!(!hypervisor.flush().unwrap());
+
+        coalescer.set_params(Some(ConfigCoalesce {
+            max_completions: 4,
+            max_delay_us: 1,
+        }));
+        std::thread::sleep(Duration::from_millis(1));
+        assert!(hypervisor.flush().unwrap());
+        assert_eq!(mock.interrupts(), 3);
+    }
+}
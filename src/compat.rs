@@ -0,0 +1,358 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao backend compatibility check.
+//!
+//! A backend lacking a feature the device relies on (e.g. a multi-queue device served
+//! without `VHOST_USER_PROTOCOL_F_MQ`) used to surface as a generic activate error from
+//! the vhost-user frontend. Before the device goes live, the features offered by the
+//! backend are compared with those its device type requires (from a built-in table), and
+//! the missing ones are reported by name.
+
+#![allow(dead_code)]
+
+use super::defines::{
+    VHOST_USER_F_PROTOCOL_FEATURES, VHOST_USER_GET_FEATURES, VHOST_USER_GET_PROTOCOL_FEATURES,
+    VHOST_USER_HEADER_SIZE, VHOST_USER_PROTOCOL_F_BACKEND_REQ, VHOST_USER_PROTOCOL_F_CONFIG,
+    VHOST_USER_PROTOCOL_F_MQ, VHOST_USER_PROTOCOL_F_REPLY_ACK, VHOST_USER_REPLY_MASK,
+    VHOST_USER_VERSION, VIRTIO_F_ACCESS_PLATFORM, VIRTIO_F_VERSION_1,
+};
+use super::error::{Error, Result};
+use super::summary::{feature_names, protocol_feature_names};
+use super::types::ConfigDevice;
+use super::virtio_ids::DeviceType;
+use std::fmt;
+use std::io::{Read, Write};
+use std::os::unix::net::UnixStream;
+
+/// Struct representing virtio and vhost-user protocol feature bits.
+///
+/// # Attributes
+///
+/// * `features` - Virtio feature bits.
+/// * `protocol_features` - Vhost-user protocol feature bits.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FeatureSet {
+    pub features: u64,
+    pub protocol_features: u64,
+}
+
+/// Returns the features a backend must offer to serve a device type.
+///
+/// Every device needs `VIRTIO_F_VERSION_1` (the virtio-mmio transport is modern only) and
+/// the protocol features; devices with several queues need `MQ`, and devices whose
+/// configuration space is provided by the backend need `CONFIG`.
+///
+/// # Arguments
+///
+/// * `device_type` - The device type.
+///
+/// # Returns
+///
+/// * `FeatureSet` - The required features.
+pub fn required_features(device_type: DeviceType) -> FeatureSet {
+    let multi_queue = matches!(
+        device_type,
+        DeviceType::Net
+            | DeviceType::Console
+            | DeviceType::Scsi
+            | DeviceType::Gpu
+            | DeviceType::Input
+            | DeviceType::Vsock
+            | DeviceType::Sound
+            | DeviceType::Fs
+            | DeviceType::Scmi
+            | DeviceType::Can
+            | DeviceType::Bt
+            | DeviceType::Gpio
+    );
+    let config_space = matches!(
+        device_type,
+        DeviceType::Block
+            | DeviceType::Scsi
+            | DeviceType::Gpu
+            | DeviceType::Input
+            | DeviceType::Sound
+            | DeviceType::Gpio
+    );
+    let mut protocol_features = 0;
+    if multi_queue {
+        protocol_features |= VHOST_USER_PROTOCOL_F_MQ;
+    }
+    if config_space {
+        protocol_features |= VHOST_USER_PROTOCOL_F_CONFIG;
+    }
+    FeatureSet {
+        features: VIRTIO_F_VERSION_1 | VHOST_USER_F_PROTOCOL_FEATURES,
+        protocol_features,
+    }
+}
+
+/// Sends a vhost-user request without payload and reads its `u64` reply.
+fn get_u64(stream: &UnixStream, request: u32) -> Result<u64> {
+    let mut stream = stream;
+    let mut message = [0u8; VHOST_USER_HEADER_SIZE];
+    message[0..4].copy_from_slice(&request.to_le_bytes());
+    message[4..8].copy_from_slice(&VHOST_USER_VERSION.to_le_bytes());
+    stream.write_all(&message)?;
+
+    let mut reply = [0u8; VHOST_USER_HEADER_SIZE + 8];
+    stream.read_exact(&mut reply)?;
+    let field = |offset: usize| u32::from_le_bytes(reply[offset..offset + 4].try_into().unwrap());
+    if field(0) != request || field(4) & VHOST_USER_REPLY_MASK == 0 || field(8) != 8 {
+        return Err(Error::InvalidVhostUserMessage(request));
+    }
+    Ok(u64::from_le_bytes(reply[12..20].try_into().unwrap()))
+}
+
+/// Queries the features offered by a backend.
+///
+/// Only `GET_FEATURES` and `GET_PROTOCOL_FEATURES` are sent, so the connection can then
+/// be handed over to the frontend.
+///
+/// # Arguments
+///
+/// * `stream` - The vhost-user connection.
+///
+/// # Returns
+///
+/// * `Result<FeatureSet>` - The offered features (no protocol features if the backend
+///   does not offer `VHOST_USER_F_PROTOCOL_FEATURES`).
+pub fn backend_features(stream: &UnixStream) -> Result<FeatureSet> {
+    let features = get_u64(stream, VHOST_USER_GET_FEATURES)?;
+    let protocol_features = match features & VHOST_USER_F_PROTOCOL_FEATURES {
+        0 => 0,
+        _ => get_u64(stream, VHOST_USER_GET_PROTOCOL_FEATURES)?,
+    };
+    Ok(FeatureSet {
+        features,
+        protocol_features,
+    })
+}
+
+/// Struct representing the features of a backend against those of its device.
+///
+/// # Attributes
+///
+/// * `device` - Device name.
+/// * `device_type` - Device type.
+/// * `required` - Features required by the device type.
+/// * `offered` - Features offered by the backend.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FeatureReport {
+    pub device: String,
+    pub device_type: DeviceType,
+    pub required: FeatureSet,
+    pub offered: FeatureSet,
+}
+
+impl FeatureReport {
+    /// Compares the features offered by a backend with those its device requires.
+    ///
+    /// # Arguments
+    ///
+    /// * `device` - The device configuration.
+    /// * `offered` - Features offered by the backend.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<FeatureReport>` - The report, or `BaoDevNotSupported` if the device type
+    ///   is unknown.
+    pub fn new(device: &ConfigDevice, offered: FeatureSet) -> Result<Self> {
+        let device_type = DeviceType::from_name(&device.device_type)
+            .ok_or_else(|| Error::BaoDevNotSupported(device.device_type.clone()))?;
+        let mut required = required_features(device_type);
+        // A backend behind platform DMA translation asks for it over the backend channel
+        // (see the `iotlb` module), and its IOTLB updates are acknowledged
+        if offered.features & VIRTIO_F_ACCESS_PLATFORM != 0 {
+            required.protocol_features |=
+                VHOST_USER_PROTOCOL_F_BACKEND_REQ | VHOST_USER_PROTOCOL_F_REPLY_ACK;
+        }
+        Ok(FeatureReport {
+            device: device.name.clone(),
+            device_type,
+            required,
+            offered,
+        })
+    }
+
+    /// Returns the required features the backend does not offer.
+    pub fn missing(&self) -> FeatureSet {
+        FeatureSet {
+            features: self.required.features & !self.offered.features,
+            protocol_features: self.required.protocol_features & !self.offered.protocol_features,
+        }
+    }
+
+    /// Returns the names of the missing features (protocol features prefixed by
+    /// `VHOST_USER_PROTOCOL_F_`).
+    pub fn missing_names(&self) -> Vec<String> {
+        let missing = self.missing();
+        let protocol = protocol_feature_names(missing.protocol_features)
+            .into_iter()
+            .map(|name| format!("VHOST_USER_PROTOCOL_F_{}", name));
+        feature_names(missing.features)
+            .into_iter()
+            .chain(protocol)
+            .collect()
+    }
+
+    /// Checks that the backend offers every required feature.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the backend can serve the device, `BackendIncompatible`
+    ///   listing the missing features otherwise.
+    pub fn check(&self) -> Result<()> {
+        let missing = self.missing_names();
+        if missing.is_empty() {
+            return Ok(());
+        }
+        Err(Error::BackendIncompatible(
+            self.device.clone(),
+            missing.join(", "),
+        ))
+    }
+}
+
+impl fmt::Display for FeatureReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{} ({})", self.device, self.device_type.name())?;
+        writeln!(
+            f,
+            "  features {:#x} (required {:#x})",
+            self.offered.features, self.required.features
+        )?;
+        writeln!(
+            f,
+            "  protocol features {:#x} (required {:#x})",
+            self.offered.protocol_features, self.required.protocol_features
+        )?;
+        let missing = self.missing_names();
+        writeln!(f, "  missing {}", missing.len())?;
+        for name in missing {
+            writeln!(f, "    {}", name)?;
+        }
+        Ok(())
+    }
+}
+
+/// Checks that the backend of a device offers the features the device requires.
+///
+/// # Arguments
+///
+/// * `stream` - The vhost-user connection, before the frontend takes ownership.
+/// * `device` - The device configuration.
+///
+/// # Returns
+///
+/// * `Result<FeatureReport>` - The report, or `BackendIncompatible` listing the missing
+///   features.
+pub fn check_backend(stream: &UnixStream, device: &ConfigDevice) -> Result<FeatureReport> {
+    let report = FeatureReport::new(device, backend_features(stream)?)?;
+    report.check()?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::InProcessBackend;
+    use crate::fake_backend::FakeBackend;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_feature_report() {
+        let device = |device_type: &str| ConfigDevice {
+            name: "dev0".to_string(),
+            device_type: device_type.to_string(),
+            ..Default::default()
+        };
+        let report = FeatureReport::new(
+            &device("gpio"),
+            FeatureSet {
+                features: VHOST_USER_F_PROTOCOL_FEATURES,
+                protocol_features: VHOST_USER_PROTOCOL_F_MQ,
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            report.missing_names(),
+            ["VIRTIO_F_VERSION_1", "VHOST_USER_PROTOCOL_F_CONFIG"]
+        );
+        assert_eq!(
+            report.to_string(),
+            "dev0 (gpio)
+  features 0x40000000 (required 0x140000000)
+  protocol features 0x1 (required 0x201)
+  missing 2
+    VIRTIO_F_VERSION_1
+    VHOST_USER_PROTOCOL_F_CONFIG
+"
+        );
+        let err = report.check().unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("VIRTIO_F_VERSION_1, VHOST_USER_PROTOCOL_F_CONFIG"));
+        assert_eq!(err.errno(), libc::ENOTSUP);
+
+        // Platform DMA translation needs the backend channel and acknowledged updates
+        let report = FeatureReport::new(
+            &device("rng"),
+            FeatureSet {
+                features: VIRTIO_F_VERSION_1
+                    | VHOST_USER_F_PROTOCOL_FEATURES
+                    | VIRTIO_F_ACCESS_PLATFORM,
+                protocol_features: VHOST_USER_PROTOCOL_F_REPLY_ACK,
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            report.missing_names(),
+            ["VHOST_USER_PROTOCOL_F_BACKEND_REQ"]
+        );
+        assert!(matches!(
+            FeatureReport::new(&device("modem"), FeatureSet::default()),
+            Err(Error::BaoDevNotSupported(_))
+        ));
+    }
+
+    #[test]
+    fn test_check_backend() {
+        let socket = std::env::temp_dir().join(format!("bao-compat-{}.sock", std::process::id()));
+        for (device_type, compatible) in [("rng", true), ("gpio", false)] {
+            let backend = Box::new(FakeBackend::new(VIRTIO_F_VERSION_1, 1));
+            let server = {
+                let socket = socket.clone();
+                thread::spawn(move || backend.serve(&socket))
+            };
+            let stream = loop {
+                match UnixStream::connect(&socket) {
+                    Ok(stream) => break stream,
+                    Err(_) => thread::sleep(Duration::from_millis(10)),
+                }
+            };
+            let device = ConfigDevice {
+                name: "dev0".to_string(),
+                device_type: device_type.to_string(),
+                ..Default::default()
+            };
+
+            // The fake backend offers MQ but not CONFIG
+            let result = check_backend(&stream, &device);
+            assert_eq!(result.is_ok(), compatible, "{}: {:?}", device_type, result);
+            if let Err(err) = result {
+                assert_eq!(
+                    err.to_string(),
+                    "Backend of dev0 lacks the features required by the device: VHOST_USER_PROTOCOL_F_CONFIG"
+                );
+            }
+            drop(stream);
+            server.join().unwrap().unwrap();
+        }
+        let _ = std::fs::remove_file(&socket);
+    }
+}
@@ -0,0 +1,1164 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao frontend configuration types.
+
+#![allow(dead_code)]
+
+use super::defines::{
+    BAO_CONNECT_TIMEOUT_MS, BAO_INIT_CONCURRENCY, BAO_POLL_BUDGET_US, BAO_RESTART_DELAY_MS,
+    BAO_RESTART_MAX_DELAY_MS, BAO_SPAWN_READY_TIMEOUT_MS, BAO_STATS_FILE_INTERVAL_MS,
+};
+use super::diagnostics::OutputFormat;
+use super::error::{Error, Result};
+use super::mac::MacAddress;
+use super::types::{DeviceId, GuestAddress, IrqLine, VmId};
+use super::uuid::DeviceUuid;
+use super::virtio_ids::DeviceType;
+use clap::{Args, Parser, Subcommand};
+use clap_complete::Shell;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+/// Enum representing the restart policy of a device backend (mirrors systemd `Restart=`).
+///
+/// # Variants
+///
+/// * `Never` - Never restart the backend, the failure is propagated to the guest.
+/// * `OnFailure` - Restart the backend only if it terminated abnormally.
+/// * `Always` - Always restart the backend, regardless of how it terminated.
+pub enum RestartPolicy {
+    #[default]
+    Never,
+    OnFailure,
+    Always,
+}
+
+impl RestartPolicy {
+    /// Checks if a backend should be restarted according to the policy.
+    ///
+    /// # Arguments
+    ///
+    /// * `failed` - Whether the backend terminated abnormally.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - True if the backend should be restarted.
+    pub fn should_restart(&self, failed: bool) -> bool {
+        match self {
+            RestartPolicy::Never => false,
+            RestartPolicy::OnFailure => failed,
+            RestartPolicy::Always => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+/// Enum representing where a device backend runs.
+///
+/// # Variants
+///
+/// * `InProcess` - The backend runs inside the frontend process.
+/// * `Subprocess` - The backend runs in its own child process, talking to the frontend over
+///   a private channel.
+pub enum IsolationMode {
+    #[default]
+    InProcess,
+    Subprocess,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+/// Enum representing how the requests of a device are waited for.
+///
+/// # Variants
+///
+/// * `Irq` - Sleep in epoll until the request source or a queue is signaled.
+/// * `Busy` - Spin on the request source and the available ring for the poll budget
+///   before falling back to epoll.
+/// * `Adaptive` - Like `Busy`, but the budget shrinks while spinning finds nothing and
+///   grows back (up to the poll budget) while it does.
+/// * `Hybrid` - Sleep in epoll, but after an event keep polling its source for a window
+///   tuned to the observed inter-arrival time (up to the poll budget).
+pub enum PollMode {
+    #[default]
+    Irq,
+    Busy,
+    Adaptive,
+    Hybrid,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "kebab-case")]
+/// Enum representing the verbosity of log messages, from the least to the most verbose.
+///
+/// # Variants
+///
+/// * `Error` - Failures only.
+/// * `Warn` - Failures and anomalies.
+/// * `Info` - Lifecycle events (default).
+/// * `Debug` - Protocol-level detail.
+/// * `Trace` - Every request.
+pub enum LogLevel {
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+    Trace,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+/// Struct representing the interrupt coalescing of a device.
+///
+/// An interrupt is injected once `max_completions` completions are pending or the oldest
+/// pending completion is `max_delay_us` old, whichever comes first. Coalescing is disabled
+/// if either is zero (or `max_completions` is one).
+///
+/// # Attributes
+///
+/// * `max_completions` - Maximum number of completions per interrupt.
+/// * `max_delay_us` - Maximum delay of a completion interrupt (in microseconds).
+pub struct ConfigCoalesce {
+    pub max_completions: u32,
+    pub max_delay_us: u64,
+}
+
+impl ConfigCoalesce {
+    /// Checks if the parameters coalesce interrupts at all.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - True if several completions may share an interrupt.
+    pub fn is_enabled(&self) -> bool {
+        self.max_completions > 1 && self.max_delay_us > 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+/// Enum representing the scheduling policy of the device workers of a guest.
+///
+/// # Variants
+///
+/// * `Other` - Default time-sharing scheduling (SCHED_OTHER).
+/// * `Fifo` - Real-time first-in first-out scheduling (SCHED_FIFO).
+/// * `Rr` - Real-time round-robin scheduling (SCHED_RR).
+pub enum SchedPolicy {
+    #[default]
+    Other,
+    Fifo,
+    Rr,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq, Eq)]
+/// Struct representing the scheduling of the device workers of a guest.
+///
+/// # Attributes
+///
+/// * `policy` - Scheduling policy.
+/// * `priority` - Real-time priority (1 to 99, ignored by `other`).
+/// * `cpus` - CPUs the workers are pinned to, as a CPU list (e.g. `2-3,6`), or None to
+///   run on any CPU.
+/// * `isolated` - Whether the pinned CPUs are expected to be isolated from the host
+///   scheduler (`isolcpus` or `nohz_full`), warned about at startup otherwise.
+pub struct ConfigSched {
+    #[serde(default)]
+    pub policy: SchedPolicy,
+    #[serde(default)]
+    pub priority: u32,
+    #[serde(default)]
+    pub cpus: Option<String>,
+    #[serde(default)]
+    pub isolated: bool,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+/// Enum representing the interrupt controller of the device tree amended by the overlays.
+///
+/// # Variants
+///
+/// * `Gic` - Arm GIC (3 interrupt cells: SPI, SPI number, edge-triggered).
+/// * `Plic` - RISC-V PLIC (1 interrupt cell: interrupt number).
+pub enum InterruptController {
+    #[default]
+    Gic,
+    Plic,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+/// Enum representing the transparent huge page policy of the guest RAM mapping.
+///
+/// # Variants
+///
+/// * `Always` - Huge pages are requested (`MADV_HUGEPAGE`), even if the system only
+///   grants them on request.
+/// * `Never` - Huge pages are refused (`MADV_NOHUGEPAGE`).
+/// * `Madvise` - No advice is given, the system policy applies.
+pub enum ThpMode {
+    Always,
+    Never,
+    #[default]
+    Madvise,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+/// Struct representing the device tree overlays of the devices hot-plugged into a guest.
+///
+/// # Attributes
+///
+/// * `configfs_dir` - Overlay directory of configfs
+///   (`/sys/kernel/config/device-tree/overlays` if None).
+/// * `target_path` - Path of the node the devices are added to (the root if None).
+/// * `address_cells` - `#address-cells` of the target node (2 if None).
+/// * `size_cells` - `#size-cells` of the target node (2 if None).
+/// * `interrupt_controller` - Interrupt controller of the target node.
+pub struct ConfigDtOverlay {
+    #[serde(default)]
+    pub configfs_dir: Option<String>,
+    #[serde(default)]
+    pub target_path: Option<String>,
+    #[serde(default)]
+    pub address_cells: Option<u32>,
+    #[serde(default)]
+    pub size_cells: Option<u32>,
+    #[serde(default)]
+    pub interrupt_controller: InterruptController,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+/// Struct representing the device tree the devices of a guest are derived from.
+///
+/// # Attributes
+///
+/// * `path` - Path of the flattened device tree blob of the guest.
+/// * `compatibles` - Device type of the nodes with a nonstandard `compatible` string
+///   (e.g. `bao,virtio-net: net`), taking precedence over the `virtio,device<id>` hints.
+pub struct ConfigDtb {
+    pub path: String,
+    #[serde(default)]
+    pub compatibles: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+/// Struct representing a filesystem path a device backend is allowed to access.
+///
+/// # Attributes
+///
+/// * `path` - Path (access is granted to everything beneath it).
+/// * `read_only` - Whether the access is restricted to reads.
+pub struct ConfigAllowedPath {
+    pub path: String,
+    #[serde(default)]
+    pub read_only: bool,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+/// Struct representing a device backend launched and supervised by the frontend.
+///
+/// The backend inherits its listening vhost-user socket through systemd socket activation
+/// (`LISTEN_FDS`), so the frontend can connect as soon as it is spawned.
+///
+/// # Attributes
+///
+/// * `command` - Backend command line (program and arguments).
+/// * `notify` - Whether to wait for the backend to report `READY=1` on `NOTIFY_SOCKET`.
+/// * `ready_timeout_ms` - Time the backend has to report readiness (in milliseconds).
+pub struct ConfigSpawn {
+    pub command: Vec<String>,
+    #[serde(default)]
+    pub notify: bool,
+    #[serde(default)]
+    pub ready_timeout_ms: Option<u64>,
+}
+
+impl ConfigSpawn {
+    /// Returns the time the backend has to report readiness.
+    ///
+    /// # Returns
+    ///
+    /// * `Duration` - The readiness timeout.
+    pub fn ready_timeout(&self) -> Duration {
+        Duration::from_millis(self.ready_timeout_ms.unwrap_or(BAO_SPAWN_READY_TIMEOUT_MS))
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+/// Struct representing a Bao device configuration.
+///
+/// # Attributes
+///
+/// * `name` - Device name.
+/// * `id` - Device ID.
+/// * `type` - Device type.
+/// * `irq` - Device IRQ.
+/// * `addr` - Device address.
+/// * `restart` - Backend restart policy.
+/// * `restart_delay_ms` - Initial delay before restarting the backend (in milliseconds).
+/// * `restart_max_delay_ms` - Maximum delay before restarting the backend (in milliseconds).
+/// * `allowed_paths` - Filesystem paths the in-process device backend is confined to (not
+///   confined if empty).
+/// * `isolation` - Whether the device backend runs in its own process.
+/// * `backend` - In-process backend serving the device (external backend if unset).
+/// * `poll_mode` - How the requests of the device are waited for.
+/// * `poll_budget_us` - Time spent spinning before falling back to epoll (in microseconds).
+/// * `coalesce` - Interrupt coalescing (every completion is notified if unset).
+/// * `slow_request_ms` - Time after which a request is reported as slow (in milliseconds,
+///   no report if unset).
+/// * `options` - Backend options (values may be `secret://` references).
+/// * `spawn` - External backend launched and supervised by the frontend (started
+///   separately if unset).
+/// * `depends_on` - Devices of the guest initialized before this one.
+/// * `connect_timeout_ms` - Time the backend socket has to show up (in milliseconds).
+/// * `wait_for_socket` - Whether connecting retries until the backend socket accepts
+///   connections (fails right away if unset).
+/// * `log_level` - Verbosity of the device log messages (frontend verbosity if unset).
+/// * `log_file` - File receiving the device log messages (frontend log if unset).
+/// * `mmio_trace` - Register offset ranges whose accesses are traced from startup (all
+///   registers if empty, no tracing if unset).
+/// * `max_inflight` - Maximum number of descriptor chains outstanding on each queue
+///   (unlimited if unset).
+/// * `uuid` - Stable device identifier (derived from the guest ID and device name if
+///   unset).
+/// * `weight` - Share of a worker shared with other devices, as items served per round
+///   (1 if unset).
+/// * `queue_size_max` - Maximum size of each virtqueue, a power of 2 (the device maximum
+///   if unset).
+/// * `labels` - Deployment metadata (e.g. `{rack: a1, function: adas}`) attached to the
+///   metrics, trace spans and log messages of the device, on top of the guest labels.
+pub struct ConfigDevice {
+    pub name: String,
+    pub id: DeviceId,
+    #[serde(rename = "type")]
+    pub device_type: String,
+    pub irq: IrqLine,
+    pub addr: GuestAddress,
+    #[serde(default)]
+    pub restart: RestartPolicy,
+    #[serde(default)]
+    pub restart_delay_ms: Option<u64>,
+    #[serde(default)]
+    pub restart_max_delay_ms: Option<u64>,
+    #[serde(default)]
+    pub allowed_paths: Vec<ConfigAllowedPath>,
+    #[serde(default)]
+    pub isolation: IsolationMode,
+    #[serde(default)]
+    pub backend: Option<String>,
+    #[serde(default)]
+    pub poll_mode: PollMode,
+    #[serde(default)]
+    pub poll_budget_us: Option<u64>,
+    #[serde(default)]
+    pub coalesce: Option<ConfigCoalesce>,
+    #[serde(default)]
+    pub slow_request_ms: Option<u64>,
+    #[serde(default)]
+    pub options: BTreeMap<String, String>,
+    #[serde(default)]
+    pub spawn: Option<ConfigSpawn>,
+    #[serde(default)]
+    pub depends_on: Vec<String>,
+    #[serde(default)]
+    pub connect_timeout_ms: Option<u64>,
+    #[serde(default)]
+    pub wait_for_socket: bool,
+    #[serde(default)]
+    pub log_level: Option<LogLevel>,
+    #[serde(default)]
+    pub log_file: Option<String>,
+    #[serde(default)]
+    pub mmio_trace: Option<Vec<ConfigRegRange>>,
+    #[serde(default)]
+    pub max_inflight: Option<u16>,
+    #[serde(default)]
+    pub uuid: Option<DeviceUuid>,
+    #[serde(default)]
+    pub weight: Option<u32>,
+    #[serde(default)]
+    pub queue_size_max: Option<u16>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+/// Computes an exponential restart backoff, doubling from `delay_ms` (or
+/// `BAO_RESTART_DELAY_MS`) up to `max_delay_ms` (or `BAO_RESTART_MAX_DELAY_MS`).
+fn restart_backoff(delay_ms: Option<u64>, max_delay_ms: Option<u64>, attempt: u32) -> Duration {
+    let delay = delay_ms.unwrap_or(BAO_RESTART_DELAY_MS);
+    let max_delay = max_delay_ms.unwrap_or(BAO_RESTART_MAX_DELAY_MS);
+    let delay = delay.saturating_mul(1u64.checked_shl(attempt).unwrap_or(u64::MAX));
+    Duration::from_millis(delay.min(max_delay))
+}
+
+/// Checks a queue size written by the driver (`QueueNum`).
+///
+/// # Arguments
+///
+/// * `num` - The queue size.
+/// * `max` - The queue size advertised to the driver (see `ConfigDevice::queue_num_max`).
+///
+/// # Returns
+///
+/// * `Result<u16>` - The queue size, or `InvalidQueueSize` if it is not a power of 2 up to
+///   `max`.
+pub fn check_queue_num(num: u32, max: u16) -> Result<u16> {
+    match u16::try_from(num) {
+        Ok(num) if num.is_power_of_two() && num <= max => Ok(num),
+        _ => Err(Error::InvalidQueueSize(num, max)),
+    }
+}
+
+impl ConfigDevice {
+    /// Returns the stable identifier of the device.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_id` - ID of the guest owning the device.
+    ///
+    /// # Returns
+    ///
+    /// * `DeviceUuid` - The configured UUID, or the one derived from the guest ID and
+    ///   device name.
+    pub fn uuid(&self, guest_id: VmId) -> DeviceUuid {
+        self.uuid
+            .unwrap_or_else(|| DeviceUuid::derive(guest_id, &self.name))
+    }
+
+    /// Returns the MAC address of a net device.
+    ///
+    /// # Arguments
+    ///
+    /// * `frontend_id` - ID of the frontend of the guest.
+    /// * `guest_id` - ID of the guest owning the device.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<MacAddress>>` - The `mac` option, or the address derived from the
+    ///   frontend, guest and device IDs (None if the device is not a net device),
+    ///   `InvalidMacAddress` if the option is not a unicast MAC address.
+    pub fn mac(&self, frontend_id: VmId, guest_id: VmId) -> Result<Option<MacAddress>> {
+        if self.device_type != DeviceType::Net.name() {
+            return Ok(None);
+        }
+        match self.options.get("mac") {
+            Some(mac) => mac.parse().map(Some),
+            None => Ok(Some(MacAddress::derive(frontend_id, guest_id, self.id))),
+        }
+    }
+
+    /// Computes the delay before the next backend restart attempt.
+    ///
+    /// The delay doubles on every consecutive attempt, starting at `restart_delay_ms`
+    /// and saturating at `restart_max_delay_ms`.
+    ///
+    /// # Arguments
+    ///
+    /// * `attempt` - Number of consecutive restart attempts already performed.
+    ///
+    /// # Returns
+    ///
+    /// * `Duration` - Delay to wait before restarting the backend.
+    pub fn restart_delay(&self, attempt: u32) -> Duration {
+        restart_backoff(self.restart_delay_ms, self.restart_max_delay_ms, attempt)
+    }
+
+    /// Returns the time after which a request is reported as slow.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Duration>` - The threshold, if slow requests are reported.
+    pub fn slow_request_threshold(&self) -> Option<Duration> {
+        self.slow_request_ms.map(Duration::from_millis)
+    }
+
+    /// Returns the time the backend socket has to show up.
+    ///
+    /// # Returns
+    ///
+    /// * `Duration` - The connection timeout.
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_millis(self.connect_timeout_ms.unwrap_or(BAO_CONNECT_TIMEOUT_MS))
+    }
+
+    /// Returns the time spent spinning before falling back to epoll.
+    ///
+    /// # Returns
+    ///
+    /// * `Duration` - The poll budget (zero in `irq` mode).
+    pub fn poll_budget(&self) -> Duration {
+        match self.poll_mode {
+            PollMode::Irq => Duration::ZERO,
+            PollMode::Busy | PollMode::Adaptive | PollMode::Hybrid => {
+                Duration::from_micros(self.poll_budget_us.unwrap_or(BAO_POLL_BUDGET_US))
+            }
+        }
+    }
+
+    /// Returns the queue size advertised to the driver (`QueueNumMax`).
+    ///
+    /// # Arguments
+    ///
+    /// * `device_max` - Maximum queue size supported by the device.
+    ///
+    /// # Returns
+    ///
+    /// * `u16` - The device maximum, clamped to `queue_size_max`.
+    pub fn queue_num_max(&self, device_max: u16) -> u16 {
+        self.queue_size_max
+            .map_or(device_max, |max| device_max.min(max))
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+/// Struct representing a Bao guest configuration.
+///
+/// # Attributes
+///
+/// * `name` - Guest name.
+/// * `id` - Guest ID.
+/// * `ram_addr` - Guest RAM address.
+/// * `ram_size` - Guest RAM size.
+/// * `shmem_path` - Guest shared memory path.
+/// * `socket_path` - Guest socket path.
+/// * `devices` - Guest devices.
+/// * `sched` - Scheduling of the guest device workers (inherited from the frontend if None).
+/// * `watch_dir` - Directory of device fragments hot-plugged while running.
+/// * `dt_overlay` - Device tree overlays applied when devices are hot-plugged (none if None).
+/// * `prefault` - Whether the guest RAM is populated when mapped, rather than on first
+///   access.
+/// * `thp` - Transparent huge page policy of the guest RAM.
+/// * `dtb` - Device tree the devices missing from `devices` are derived from (none if
+///   None).
+/// * `labels` - Deployment metadata inherited by the devices of the guest (see
+///   `ConfigDevice::labels`).
+pub struct ConfigGuest {
+    pub name: String,
+    pub id: VmId,
+    pub ram_addr: GuestAddress,
+    pub ram_size: u64,
+    pub shmem_path: String,
+    pub socket_path: String,
+    #[serde(default)]
+    pub devices: Vec<ConfigDevice>,
+    #[serde(default)]
+    pub sched: Option<ConfigSched>,
+    #[serde(default)]
+    pub watch_dir: Option<String>,
+    #[serde(default)]
+    pub dt_overlay: Option<ConfigDtOverlay>,
+    #[serde(default)]
+    pub prefault: bool,
+    #[serde(default)]
+    pub thp: ThpMode,
+    #[serde(default)]
+    pub dtb: Option<ConfigDtb>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+impl ConfigGuest {
+    /// Copies the guest labels into its devices, the devices keeping their own value of a
+    /// label set on both.
+    pub fn inherit_labels(&mut self) {
+        for device in &mut self.devices {
+            for (key, value) in &self.labels {
+                device
+                    .labels
+                    .entry(key.clone())
+                    .or_insert_with(|| value.clone());
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+/// Struct representing a Bao frontend configuration.
+///
+/// # Attributes
+///
+/// * `name` - Frontend name.
+/// * `id` - Frontend ID.
+/// * `guests` - Frontend guests.
+/// * `control` - Control socket limited to the devices of the frontend.
+pub struct ConfigFrontend {
+    pub name: String,
+    pub id: VmId,
+    pub guests: Vec<ConfigGuest>,
+    #[serde(default)]
+    pub control: Option<ConfigControl>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+/// Enum representing the seccomp sandbox mode of the frontend.
+///
+/// # Variants
+///
+/// * `Off` - No seccomp filter is installed.
+/// * `Log` - Syscalls outside the allow-list are logged by the kernel but allowed.
+/// * `Enforce` - Syscalls outside the allow-list kill the process.
+pub enum SeccompMode {
+    #[default]
+    Off,
+    Log,
+    Enforce,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, Serialize, PartialEq, Eq)]
+/// Enum representing a Linux capability the frontend may retain after dropping privileges.
+///
+/// # Variants
+///
+/// * `NetAdmin` - CAP_NET_ADMIN (e.g. TAP device configuration).
+/// * `IpcLock` - CAP_IPC_LOCK (e.g. pinning guest memory).
+/// * `SysRawio` - CAP_SYS_RAWIO (e.g. raw device access).
+/// * `SysNice` - CAP_SYS_NICE (e.g. real-time scheduling).
+/// * `SysResource` - CAP_SYS_RESOURCE (e.g. raising resource limits).
+pub enum Capability {
+    #[serde(rename = "CAP_NET_ADMIN")]
+    NetAdmin,
+    #[serde(rename = "CAP_IPC_LOCK")]
+    IpcLock,
+    #[serde(rename = "CAP_SYS_RAWIO")]
+    SysRawio,
+    #[serde(rename = "CAP_SYS_NICE")]
+    SysNice,
+    #[serde(rename = "CAP_SYS_RESOURCE")]
+    SysResource,
+}
+
+impl Capability {
+    /// Returns the capability number (as defined in `linux/capability.h`).
+    ///
+    /// # Returns
+    ///
+    /// * `u32` - The capability number.
+    pub fn number(&self) -> u32 {
+        match self {
+            Capability::NetAdmin => 12,
+            Capability::IpcLock => 14,
+            Capability::SysRawio => 17,
+            Capability::SysNice => 23,
+            Capability::SysResource => 24,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+/// Struct representing a register offset range.
+///
+/// # Attributes
+///
+/// * `start` - First register offset (inclusive).
+/// * `end` - Last register offset (exclusive).
+pub struct ConfigRegRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+/// Struct representing the MMIO access audit configuration.
+///
+/// # Attributes
+///
+/// * `path` - Audit log file path.
+/// * `max_size` - Maximum size of each log file (in bytes).
+/// * `max_files` - Maximum number of log files kept (including the current one).
+/// * `devices` - Audited device IDs (all devices if empty).
+/// * `ranges` - Audited register offset ranges (all registers if empty).
+pub struct ConfigAudit {
+    pub path: String,
+    #[serde(default)]
+    pub max_size: Option<u64>,
+    #[serde(default)]
+    pub max_files: Option<u32>,
+    #[serde(default)]
+    pub devices: Vec<DeviceId>,
+    #[serde(default)]
+    pub ranges: Vec<ConfigRegRange>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+/// Struct representing the peers granted a class of control commands.
+///
+/// # Attributes
+///
+/// * `uids` - Allowed user IDs.
+/// * `gids` - Allowed group IDs (primary or supplementary).
+pub struct ConfigControlPeers {
+    #[serde(default)]
+    pub uids: Vec<u32>,
+    #[serde(default)]
+    pub gids: Vec<u32>,
+}
+
+impl ConfigControlPeers {
+    /// Checks if a peer is allowed.
+    ///
+    /// # Arguments
+    ///
+    /// * `uid` - User ID of the peer.
+    /// * `gids` - Group IDs of the peer (primary and supplementary).
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - True if the user or any of the groups is listed.
+    pub fn contains(&self, uid: u32, gids: &[u32]) -> bool {
+        self.uids.contains(&uid) || gids.iter().any(|gid| self.gids.contains(gid))
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+/// Struct representing the control socket configuration.
+///
+/// Root and the user of the frontend are always allowed every command.
+///
+/// # Attributes
+///
+/// * `path` - Control socket path.
+/// * `read` - Peers allowed the read-only commands (e.g. device stats).
+/// * `admin` - Peers allowed every command (e.g. device unplug).
+pub struct ConfigControl {
+    pub path: String,
+    #[serde(default)]
+    pub read: ConfigControlPeers,
+    #[serde(default)]
+    pub admin: ConfigControlPeers,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+/// Struct representing the periodic stats file output.
+///
+/// # Attributes
+///
+/// * `path` - Stats file path (replaced atomically on every write).
+/// * `interval_ms` - Interval between two writes (in milliseconds).
+pub struct ConfigStatsFile {
+    pub path: String,
+    #[serde(default)]
+    pub interval_ms: Option<u64>,
+}
+
+impl ConfigStatsFile {
+    /// Returns the interval between two writes of the stats file.
+    ///
+    /// # Returns
+    ///
+    /// * `Duration` - The interval (`BAO_STATS_FILE_INTERVAL_MS` if unset).
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(
+            self.interval_ms
+                .unwrap_or(BAO_STATS_FILE_INTERVAL_MS)
+                .max(1),
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+/// Enum representing what runs in its own process under the supervisor.
+///
+/// # Variants
+///
+/// * `Frontend` - One process per frontend.
+/// * `Guest` - One process per guest.
+pub enum SuperviseUnit {
+    #[default]
+    Frontend,
+    Guest,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+/// Struct representing the process supervisor (`--supervise`) configuration.
+///
+/// # Attributes
+///
+/// * `unit` - What runs in its own process.
+/// * `restart` - Restart policy of the processes.
+/// * `restart_delay_ms` - Initial delay before restarting a process (in milliseconds).
+/// * `restart_max_delay_ms` - Maximum delay before restarting a process (in milliseconds).
+pub struct ConfigSupervisor {
+    #[serde(default)]
+    pub unit: SuperviseUnit,
+    #[serde(default)]
+    pub restart: RestartPolicy,
+    #[serde(default)]
+    pub restart_delay_ms: Option<u64>,
+    #[serde(default)]
+    pub restart_max_delay_ms: Option<u64>,
+}
+
+impl ConfigSupervisor {
+    /// Computes the delay before the next restart of a process.
+    ///
+    /// # Arguments
+    ///
+    /// * `attempt` - Number of consecutive restarts already performed.
+    ///
+    /// # Returns
+    ///
+    /// * `Duration` - Delay to wait before restarting the process.
+    pub fn restart_delay(&self, attempt: u32) -> Duration {
+        restart_backoff(self.restart_delay_ms, self.restart_max_delay_ms, attempt)
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+/// Struct representing a Bao frontends configuration.
+///
+/// # Attributes
+///
+/// * `frontends` - Frontends.
+/// * `seccomp` - Seccomp sandbox mode.
+/// * `user` - Unprivileged user to switch to after initialization.
+/// * `group` - Unprivileged group to switch to after initialization (the primary group of
+///   `user` if unset).
+/// * `capabilities` - Capabilities retained after switching user (ignored, like `group`,
+///   if `user` is unset).
+/// * `audit` - MMIO access audit log.
+/// * `init_concurrency` - Maximum number of devices initialized concurrently at startup.
+/// * `supervisor` - Process supervisor settings (used with `--supervise`).
+/// * `control` - Control socket (not served if unset).
+/// * `stats_file` - Periodic stats file output (not written if unset).
+/// * `applied_config` - Path of the record of the last applied configuration, compared
+///   with the effective configuration on startup (not kept if unset).
+pub struct ConfigFrontends {
+    pub frontends: Vec<ConfigFrontend>,
+    #[serde(default)]
+    pub seccomp: SeccompMode,
+    #[serde(default)]
+    pub user: Option<String>,
+    #[serde(default)]
+    pub group: Option<String>,
+    #[serde(default)]
+    pub capabilities: Vec<Capability>,
+    #[serde(default)]
+    pub audit: Option<ConfigAudit>,
+    #[serde(default)]
+    pub init_concurrency: Option<usize>,
+    #[serde(default)]
+    pub supervisor: ConfigSupervisor,
+    #[serde(default)]
+    pub control: Option<ConfigControl>,
+    #[serde(default)]
+    pub stats_file: Option<ConfigStatsFile>,
+    #[serde(default)]
+    pub applied_config: Option<String>,
+}
+
+impl ConfigFrontends {
+    /// Returns the maximum number of devices initialized concurrently at startup.
+    ///
+    /// # Returns
+    ///
+    /// * `usize` - The concurrency (at least one).
+    pub fn init_concurrency(&self) -> usize {
+        self.init_concurrency.unwrap_or(BAO_INIT_CONCURRENCY).max(1)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Args)]
+/// Struct representing the options of a stress test.
+///
+/// # Attributes
+///
+/// * `devices` - Names of the devices under test (all devices if empty).
+/// * `requests` - Number of buffers sent to each device.
+/// * `rate` - Maximum number of buffers per second (unlimited if 0).
+/// * `payload` - Payload size of each buffer (in bytes).
+/// * `queue_depth` - Number of buffers made available per notification.
+#[command(about = None, long_about = None)]
+pub struct StressOptions {
+    #[arg(
+        long = "device",
+        value_name = "NAME",
+        help = "Device under test (all devices if not given)"
+    )]
+    pub devices: Vec<String>,
+    #[arg(
+        long,
+        value_name = "COUNT",
+        default_value_t = 10000,
+        help = "Number of buffers sent to each device"
+    )]
+    pub requests: u64,
+    #[arg(
+        long,
+        value_name = "PER_SECOND",
+        default_value_t = 0,
+        help = "Maximum number of buffers per second (0 for unlimited)"
+    )]
+    pub rate: u64,
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = 64,
+        help = "Payload size of each buffer"
+    )]
+    pub payload: usize,
+    #[arg(
+        long,
+        value_name = "BUFFERS",
+        default_value_t = 1,
+        help = "Number of buffers made available per notification"
+    )]
+    pub queue_depth: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Args)]
+/// Struct representing the options of a loopback self-test.
+///
+/// # Attributes
+///
+/// * `rounds` - Number of notification rounds.
+/// * `payload` - Payload size of each buffer (in bytes).
+#[command(about = None, long_about = None)]
+pub struct SelfTestOptions {
+    #[arg(
+        long,
+        value_name = "COUNT",
+        default_value_t = 64,
+        help = "Number of notification rounds"
+    )]
+    pub rounds: u16,
+    #[arg(
+        long,
+        value_name = "BYTES",
+        default_value_t = 256,
+        help = "Payload size of each buffer (at most 1024)"
+    )]
+    pub payload: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Subcommand)]
+/// Enum representing the subcommands of the frontend.
+///
+/// # Variants
+///
+/// * `Replay` - Replays a recorded I/O request stream.
+/// * `Completions` - Prints the shell completion script.
+/// * `Man` - Prints the manual page.
+/// * `Version` - Prints the version (or the supported versions and features, with `abi`).
+/// * `Stress` - Drives synthetic load against simulated devices (`simulate` feature).
+/// * `SelfTest` - Validates the transport, memory and interrupt path with a loopback
+///   device (`simulate` feature).
+pub enum FrontendCommand {
+    #[command(about = "Replays a recorded I/O request stream")]
+    Replay {
+        #[arg(value_name = "FILE", help = "Recording file")]
+        recording: String,
+    },
+    #[command(about = "Prints the shell completion script")]
+    Completions {
+        #[arg(value_name = "SHELL", help = "Target shell")]
+        shell: Shell,
+    },
+    #[command(about = "Prints the manual page (roff)")]
+    Man,
+    #[command(about = "Prints the version")]
+    Version {
+        #[arg(
+            long,
+            help = "Reports the supported schema, ABI and protocol versions, the backends and the features"
+        )]
+        abi: bool,
+    },
+    #[cfg(feature = "simulate")]
+    #[command(about = "Drives synthetic load against simulated devices")]
+    Stress {
+        #[arg(short, long, value_name = "FILE", help = "Sets a custom config file")]
+        config: String,
+        #[command(flatten)]
+        options: StressOptions,
+        #[arg(
+            long,
+            value_name = "FILE",
+            help = "Writes per-request trace events (Chrome trace format) to the given file"
+        )]
+        trace: Option<String>,
+    },
+    #[cfg(feature = "simulate")]
+    #[command(about = "Validates the transport, memory and interrupt path with a loopback device")]
+    SelfTest {
+        #[command(flatten)]
+        options: SelfTestOptions,
+        #[arg(
+            long,
+            value_name = "FILE",
+            help = "Writes per-request trace events (Chrome trace format) to the given file"
+        )]
+        trace: Option<String>,
+    },
+}
+
+#[derive(Debug, Default, PartialEq, Parser)]
+#[command(
+    name = "bao-sys",
+    about = "Bao Vhost Frontend",
+    long_about = None,
+    subcommand_negates_reqs = true
+)]
+/// Struct representing the parsed frontend command line arguments.
+///
+/// The command line interface is derived from this struct (see `utils::parse_arguments`),
+/// the fields without a flag being filled in once the arguments are parsed.
+///
+/// # Attributes
+///
+/// * `frontends` - Frontends configuration.
+/// * `config` - Path of the configuration file.
+/// * `daemon` - Whether to run in the background.
+/// * `pidfile` - Path of the file where the process ID is written.
+/// * `platform` - Path of the platform description the devices are validated against.
+/// * `record` - Path of the file where the I/O request stream of the simulated guests is
+///   recorded.
+/// * `trace` - Path of the file where the per-request trace events are written.
+/// * `otlp` - Endpoint of the OTLP collector receiving the traces and metrics.
+/// * `replay` - Path of the recording to replay (instead of running the frontends).
+/// * `simulate` - Whether to simulate the guests instead of attaching to the Bao module.
+/// * `stress` - Stress test to run against the simulated guests.
+/// * `self_test` - Loopback self-test to run (instead of running the frontends).
+/// * `supervise` - Whether to run every frontend (or guest) in its own supervised process.
+/// * `output` - Format of the diagnostics.
+/// * `command` - Subcommand, if any.
+pub struct CommandLineArgs {
+    #[arg(skip)]
+    pub frontends: ConfigFrontends,
+    #[arg(
+        short,
+        long,
+        value_name = "FILE",
+        required = true,
+        help = "Sets a custom config file"
+    )]
+    pub config: Option<String>,
+    #[arg(short, long, help = "Runs the frontend in the background")]
+    pub daemon: bool,
+    #[arg(
+        short,
+        long,
+        value_name = "FILE",
+        help = "Writes the process ID to the given file"
+    )]
+    pub pidfile: Option<String>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Validates the devices against the given platform description"
+    )]
+    pub platform: Option<String>,
+    #[cfg_attr(
+        feature = "simulate",
+        arg(
+            long,
+            value_name = "FILE",
+            requires = "simulate",
+            help = "Records the I/O request stream of the simulated devices to the given file"
+        )
+    )]
+    #[cfg_attr(not(feature = "simulate"), arg(skip))]
+    pub record: Option<String>,
+    #[arg(
+        long,
+        value_name = "FILE",
+        help = "Writes per-request trace events (Chrome trace format) to the given file"
+    )]
+    pub trace: Option<String>,
+    #[cfg_attr(
+        feature = "otel",
+        arg(
+            long,
+            value_name = "ENDPOINT",
+            global = true,
+            help = "Exports the request traces and metrics to the given OTLP/gRPC collector"
+        )
+    )]
+    #[cfg_attr(not(feature = "otel"), arg(skip))]
+    pub otlp: Option<String>,
+    #[arg(skip)]
+    pub replay: Option<String>,
+    #[cfg_attr(
+        feature = "simulate",
+        arg(
+            long,
+            help = "Serves the devices with an in-memory guest model (no Bao module needed)"
+        )
+    )]
+    #[cfg_attr(not(feature = "simulate"), arg(skip))]
+    pub simulate: bool,
+    #[arg(skip)]
+    pub stress: Option<StressOptions>,
+    #[arg(skip)]
+    pub self_test: Option<SelfTestOptions>,
+    #[arg(
+        long,
+        help = "Runs every frontend (or guest) in its own supervised process"
+    )]
+    pub supervise: bool,
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        value_enum,
+        default_value_t,
+        global = true,
+        help = "Sets the format of the diagnostics and reports"
+    )]
+    pub output: OutputFormat,
+    #[command(subcommand)]
+    pub command: Option<FrontendCommand>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_device_newtypes() {
+        let device: ConfigDevice =
+            serde_yaml::from_str("{name: device0, id: 3, type: rng, irq: 0x2f, addr: 0xa003e00}")
+                .unwrap();
+        assert_eq!(device.id, DeviceId(3));
+        assert_eq!(device.irq, IrqLine(47));
+        assert_eq!(device.addr, GuestAddress(0xa003e00));
+    }
+
+    #[test]
+    fn test_config_labels() {
+        let mut guest: ConfigGuest = serde_yaml::from_str(
+            "name: guest0
+id: 0
+ram_addr: 0x60000000
+ram_size: 0x1000000
+shmem_path: /dev/baoipc0
+socket_path: /tmp/
+labels: {rack: a1, function: adas}
+devices:
+  - {name: rng0, id: 0, type: rng, irq: 0x2f, addr: 0xa003e00}
+  - {name: net0, id: 1, type: net, irq: 0x30, addr: 0xa003c00, labels: {function: ivi, lane: 2}}
+",
+        )
+        .unwrap();
+        assert!(guest.devices[0].labels.is_empty());
+        guest.inherit_labels();
+        let labels = |device: &ConfigDevice| {
+            device
+                .labels
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(labels(&guest.devices[0]), ["function=adas", "rack=a1"]);
+        assert_eq!(
+            labels(&guest.devices[1]),
+            ["function=ivi", "lane=2", "rack=a1"]
+        );
+    }
+}
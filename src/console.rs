@@ -0,0 +1,527 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao built-in console backend.
+//!
+//! Serves a virtio-console device in-process (`backend: bao-console`), its single port
+//! being connected to a pseudo-terminal of the host: what the guest writes is written to
+//! the terminal, and what is written to the terminal is read by the guest. The `link`
+//! backend option names a symbolic link to the terminal (e.g. `/run/bao/guest0.console`),
+//! replacing an existing link.
+//!
+//! Output written while no client has the terminal open is kept by the terminal until it
+//! fills, then lost. So that it is not (early boot panics being printed long before
+//! anyone attaches), the backend keeps the most recent output of the guest in a log, of
+//! `log_size` bytes (`BAO_CONSOLE_LOG_SIZE` by default), returned by the `console-log`
+//! control command.
+//!
+//! Input is delivered as the guest makes receive buffers available.
+
+#![allow(dead_code)]
+
+use super::backend::InProcessBackend;
+use super::defines::*;
+use super::error::{Error, Result};
+use super::poll::EventPoller;
+use super::types::ConfigDevice;
+use super::vhost_backend::{VhostUserBackend, VirtioDevice};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::ffi::CStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{symlink, OpenOptionsExt};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Struct representing the console output of a guest, as kept by its backend.
+///
+/// # Attributes
+///
+/// * `output` - The most recent output (invalid UTF-8 being replaced).
+/// * `written` - Bytes written by the guest since the backend started.
+/// * `dropped` - Bytes written by the guest no longer kept.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ConsoleOutput {
+    pub output: String,
+    pub written: u64,
+    pub dropped: u64,
+}
+
+/// The console output log of a device.
+pub trait ConsoleLog: Send {
+    /// Returns the output kept.
+    ///
+    /// # Returns
+    ///
+    /// * `ConsoleOutput` - The most recent output of the guest.
+    fn output(&self) -> ConsoleOutput;
+}
+
+/// Struct representing a ring buffer of console output.
+///
+/// # Attributes
+///
+/// * `data` - The most recent output.
+/// * `capacity` - Number of bytes kept.
+/// * `written` - Bytes written since the ring was created.
+#[derive(Debug, Clone, Default)]
+pub struct ConsoleRing {
+    data: VecDeque<u8>,
+    capacity: usize,
+    written: u64,
+}
+
+impl ConsoleRing {
+    /// Creates a ring buffer.
+    ///
+    /// # Arguments
+    ///
+    /// * `capacity` - Number of bytes kept.
+    ///
+    /// # Returns
+    ///
+    /// * `ConsoleRing` - The (empty) ring.
+    pub fn new(capacity: usize) -> Self {
+        ConsoleRing {
+            data: VecDeque::with_capacity(capacity),
+            capacity,
+            written: 0,
+        }
+    }
+
+    /// Appends output, dropping the oldest beyond the capacity.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The output.
+    pub fn write(&mut self, bytes: &[u8]) {
+        self.written += bytes.len() as u64;
+        let bytes = &bytes[bytes.len().saturating_sub(self.capacity)..];
+        let excess = (self.data.len() + bytes.len()).saturating_sub(self.capacity);
+        self.data.drain(..excess);
+        self.data.extend(bytes);
+    }
+}
+
+impl ConsoleLog for ConsoleRing {
+    fn output(&self) -> ConsoleOutput {
+        let (first, second) = self.data.as_slices();
+        ConsoleOutput {
+            output: String::from_utf8_lossy(&[first, second].concat()).into_owned(),
+            written: self.written,
+            dropped: self.written - self.data.len() as u64,
+        }
+    }
+}
+
+/// The host end of a console port.
+pub trait ConsolePort: Send {
+    /// Writes output of the guest, without blocking.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf` - The output.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<usize>` - The number of bytes written (none if the port is full).
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>;
+
+    /// Reads input for the guest, without blocking.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf` - Where to read the input.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<usize>` - The number of bytes read (none if there is no input).
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+}
+
+/// Struct representing a pseudo-terminal of the host.
+///
+/// # Attributes
+///
+/// * `master` - The master end, non-blocking.
+/// * `slave` - The slave end, held open so that the terminal outlives its clients.
+/// * `path` - Path of the slave end, opened by the clients.
+#[derive(Debug)]
+pub struct Pty {
+    master: File,
+    slave: File,
+    path: PathBuf,
+}
+
+impl Pty {
+    /// Opens a pseudo-terminal in raw mode.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<Pty>` - The terminal.
+    pub fn open() -> io::Result<Self> {
+        let check = |ret: libc::c_int| match ret {
+            0.. => Ok(ret),
+            _ => Err(io::Error::last_os_error()),
+        };
+        // SAFETY: The flags are valid and the result is checked.
+        let fd =
+            check(unsafe { libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC) })?;
+        // SAFETY: `fd` is a valid file descriptor owned by nothing else.
+        let master = unsafe { File::from_raw_fd(fd) };
+        // SAFETY: The master is a valid pseudo-terminal master.
+        check(unsafe { libc::grantpt(master.as_raw_fd()) })?;
+        // SAFETY: The master is a valid pseudo-terminal master.
+        check(unsafe { libc::unlockpt(master.as_raw_fd()) })?;
+        let mut name = [0 as libc::c_char; 64];
+        // SAFETY: The buffer is valid for writes of its length.
+        let ret = unsafe { libc::ptsname_r(master.as_raw_fd(), name.as_mut_ptr(), name.len()) };
+        if ret != 0 {
+            return Err(io::Error::from_raw_os_error(ret));
+        }
+        // SAFETY: `ptsname_r` wrote a NUL-terminated string into the buffer.
+        let path = PathBuf::from(unsafe { CStr::from_ptr(name.as_ptr()) }.to_str().unwrap());
+        let slave = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&path)?;
+
+        // Raw mode, so that the terminal neither echoes nor translates what crosses it
+        // SAFETY: `termios` is plain data, for which zeroes are valid.
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        // SAFETY: `termios` is valid for writes.
+        check(unsafe { libc::tcgetattr(slave.as_raw_fd(), &mut termios) })?;
+        // SAFETY: `termios` is valid for reads and writes.
+        unsafe { libc::cfmakeraw(&mut termios) };
+        // SAFETY: `termios` is valid for reads.
+        check(unsafe { libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) })?;
+
+        // SAFETY: The arguments are valid and the result is checked.
+        let flags = check(unsafe { libc::fcntl(master.as_raw_fd(), libc::F_GETFL) })?;
+        // SAFETY: The arguments are valid and the result is checked.
+        check(unsafe { libc::fcntl(master.as_raw_fd(), libc::F_SETFL, flags | libc::O_NONBLOCK) })?;
+        Ok(Pty {
+            master,
+            slave,
+            path,
+        })
+    }
+
+    /// Returns the path of the terminal, opened by the clients.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl ConsolePort for Pty {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.master.write(buf) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(0),
+            result => result,
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.master.read(buf) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(0),
+            result => result,
+        }
+    }
+}
+
+/// Struct representing a virtio-console device with a single port.
+///
+/// # Attributes
+///
+/// * `port` - The host end of the port.
+/// * `log` - The output log, shared with the management.
+pub struct ConsoleDevice<P: ConsolePort> {
+    port: P,
+    log: Arc<Mutex<ConsoleRing>>,
+}
+
+impl<P: ConsolePort> ConsoleDevice<P> {
+    /// Creates a console device.
+    ///
+    /// # Arguments
+    ///
+    /// * `port` - The host end of the port.
+    /// * `log` - The output log.
+    ///
+    /// # Returns
+    ///
+    /// * `ConsoleDevice<P>` - The device.
+    pub fn new(port: P, log: Arc<Mutex<ConsoleRing>>) -> Self {
+        ConsoleDevice { port, log }
+    }
+}
+
+impl<P: ConsolePort> VirtioDevice for ConsoleDevice<P> {
+    fn features(&self) -> u64 {
+        VIRTIO_F_VERSION_1
+    }
+
+    fn num_queues(&self) -> usize {
+        // The receive and transmit queues of the port
+        2
+    }
+
+    fn process(&mut self, readable: &[u8], writable: &mut [u8]) -> u32 {
+        // Receive buffers are writable only
+        if readable.is_empty() {
+            return self.port.read(writable).unwrap_or(0) as u32;
+        }
+        self.log.lock().unwrap().write(readable);
+        // What the terminal cannot take is lost, but for the log
+        let mut output = readable;
+        while !output.is_empty() {
+            match self.port.write(output) {
+                Ok(n) if n > 0 => output = &output[n..],
+                _ => break,
+            }
+        }
+        0
+    }
+}
+
+/// Struct representing the built-in console backend of a device.
+///
+/// # Attributes
+///
+/// * `log` - The output log, shared with the device.
+/// * `path` - Path of the terminal of the port.
+/// * `backend` - The vhost-user backend serving the device.
+pub struct ConsoleBackend {
+    log: Arc<Mutex<ConsoleRing>>,
+    path: PathBuf,
+    backend: VhostUserBackend,
+}
+
+impl ConsoleBackend {
+    /// Creates the console backend of a device.
+    ///
+    /// # Arguments
+    ///
+    /// * `device` - The device, whose `log_size` option sets the size of the output log
+    ///   and whose `link` option names a link to the terminal.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<ConsoleBackend>` - The backend, `InvalidBackendOption` if an option is
+    ///   invalid, `ConsolePtyFailed` if the terminal (or its link) cannot be created.
+    pub fn open(device: &ConfigDevice) -> Result<Self> {
+        let option = |key: &str| device.options.get(key).map(String::as_str);
+        let size = match option("log_size") {
+            None => BAO_CONSOLE_LOG_SIZE,
+            Some(size) => size.parse().map_err(|_| {
+                Error::InvalidBackendOption(
+                    "log_size".to_string(),
+                    format!("{} is not a number of bytes", size),
+                )
+            })?,
+        };
+        let failed = |e| Error::ConsolePtyFailed(device.name.clone(), e);
+        let pty = Pty::open().map_err(failed)?;
+        let path = pty.path().to_path_buf();
+        if let Some(link) = option("link") {
+            // Replace a link left behind, never anything else
+            if fs::symlink_metadata(link).is_ok_and(|m| m.file_type().is_symlink()) {
+                fs::remove_file(link).map_err(failed)?;
+            }
+            symlink(&path, link).map_err(failed)?;
+        }
+
+        let log = Arc::new(Mutex::new(ConsoleRing::new(size)));
+        let poller = EventPoller::new(device);
+        let device = ConsoleDevice::new(pty, log.clone());
+        Ok(ConsoleBackend {
+            log,
+            path,
+            backend: VhostUserBackend::new(Box::new(device)).with_poller(poller),
+        })
+    }
+
+    /// Returns the output log of the device (see `DeviceRegistry::attach_console_log`).
+    pub fn log(&self) -> Arc<Mutex<ConsoleRing>> {
+        self.log.clone()
+    }
+
+    /// Returns the path of the terminal of the port.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl InProcessBackend for ConsoleBackend {
+    fn serve(self: Box<Self>, socket: &Path) -> Result<()> {
+        Box::new(self.backend).serve(socket)
+    }
+}
+
+/// Creates the built-in console backend of a device (see `ConsoleBackend::open`).
+///
+/// # Arguments
+///
+/// * `device` - The device.
+///
+/// # Returns
+///
+/// * `Result<Box<dyn InProcessBackend>>` - The backend serving the console.
+pub fn console_backend(device: &ConfigDevice) -> Result<Box<dyn InProcessBackend>> {
+    Ok(Box::new(ConsoleBackend::open(device)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A port taking up to `room` bytes of output.
+    #[derive(Default)]
+    struct FakePort {
+        output: Vec<u8>,
+        input: Vec<u8>,
+        room: usize,
+    }
+
+    impl ConsolePort for FakePort {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.room - self.output.len());
+            self.output.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.input.len());
+            buf[..n].copy_from_slice(&self.input[..n]);
+            self.input.drain(..n);
+            Ok(n)
+        }
+    }
+
+    #[test]
+    fn test_console_ring() {
+        let mut ring = ConsoleRing::new(8);
+        ring.write(b"boot");
+        assert_eq!(
+            ring.output(),
+            ConsoleOutput {
+                output: "boot".to_string(),
+                written: 4,
+                dropped: 0
+            }
+        );
+        ring.write(b"ing...");
+        assert_eq!(ring.output().output, "oting...");
+        ring.write(b"Kernel panic");
+        assert_eq!(
+            ring.output(),
+            ConsoleOutput {
+                output: "el panic".to_string(),
+                written: 22,
+                dropped: 14
+            }
+        );
+        let mut ring = ConsoleRing::new(0);
+        ring.write(b"lost");
+        assert_eq!(ring.output().dropped, 4);
+    }
+
+    #[test]
+    fn test_console_device() {
+        let log = Arc::new(Mutex::new(ConsoleRing::new(64)));
+        let port = FakePort {
+            input: b"root\n".to_vec(),
+            room: 6,
+            ..Default::default()
+        };
+        let mut device = ConsoleDevice::new(port, log.clone());
+
+        // Output the port cannot take is kept by the log only
+        assert_eq!(device.process(b"Booting", &mut []), 0);
+        assert_eq!(device.process(b" Linux", &mut []), 0);
+        assert_eq!(device.port.output, b"Bootin");
+        assert_eq!(log.lock().unwrap().output().output, "Booting Linux");
+
+        let mut buf = [0u8; 3];
+        assert_eq!(device.process(&[], &mut buf), 3);
+        assert_eq!(&buf, b"roo");
+        assert_eq!(device.process(&[], &mut buf), 2);
+        assert_eq!(&buf[..2], b"t\n");
+        assert_eq!(device.process(&[], &mut buf), 0);
+    }
+
+    #[test]
+    fn test_console_pty() {
+        let mut pty = Pty::open().unwrap();
+        let mut client = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(pty.path())
+            .unwrap();
+
+        // Output and input cross the terminal untranslated
+        assert_eq!(pty.write(b"panic\n").unwrap(), 6);
+        let mut buf = [0u8; 6];
+        client.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"panic\n");
+        client.write_all(b"ls\n").unwrap();
+        let mut buf = [0u8; 8];
+        let mut n = 0;
+        for _ in 0..100 {
+            n = pty.read(&mut buf).unwrap();
+            if n > 0 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(&buf[..n], b"ls\n");
+        assert_eq!(pty.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_console_backend() {
+        let dir = std::env::temp_dir().join(format!("bao-console-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let link = dir.join("console0");
+        symlink("/nonexistent", &link).unwrap();
+        let mut device = ConfigDevice {
+            name: "console0".to_string(),
+            device_type: "console".to_string(),
+            backend: Some(BAO_CONSOLE_BACKEND.to_string()),
+            ..Default::default()
+        };
+        let link_option = link.to_str().unwrap().to_string();
+        device.options.insert("link".to_string(), link_option);
+        device
+            .options
+            .insert("log_size".to_string(), "16".to_string());
+
+        // Links left behind are replaced
+        let backend = ConsoleBackend::open(&device).unwrap();
+        assert_eq!(fs::read_link(&link).unwrap(), backend.path());
+        assert_eq!(backend.log().lock().unwrap().capacity, 16);
+
+        // Anything else is not
+        fs::remove_file(&link).unwrap();
+        fs::write(&link, b"").unwrap();
+        assert!(matches!(
+            ConsoleBackend::open(&device),
+            Err(Error::ConsolePtyFailed(..))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+
+        device
+            .options
+            .insert("log_size".to_string(), "16K".to_string());
+        assert!(matches!(
+            ConsoleBackend::open(&device),
+            Err(Error::InvalidBackendOption(option, _)) if option == "log_size"
+        ));
+    }
+}
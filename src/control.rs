@@ -0,0 +1,1362 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao control socket.
+//!
+//! Exposes a `Management` implementation on a Unix socket, one command per line (e.g.
+//! `stats rng0`), each answered by a YAML document (`ok: ...` or `error: ...`) terminated
+//! by the `...` end-of-document marker. Once acknowledged, `subscribe` turns the connection
+//! into a stream of device events, one JSON object per line. A device may be named by its
+//! UUID instead (e.g. `stats 8c2f...`), which survives renaming it.
+//!
+//! Every user may connect: the credentials of the peer (`SO_PEERCRED`, and its
+//! supplementary groups from `SO_PEERGROUPS`) decide which commands it may issue. The read-only commands are allowed to the `read` and `admin`
+//! peers of the `ConfigControl`, the commands changing the devices only to the `admin`
+//! ones, so a monitoring agent can query the counters without being able to unplug a
+//! device.
+//!
+//! As any local user may connect, command lines are limited to `BAO_CONTROL_MAX_LINE`
+//! bytes (longer ones are answered with an error and the connection is closed), and at
+//! most `BAO_CONTROL_MAX_CONNECTIONS` connections are served at a time.
+//!
+//! A frontend may have a control socket of its own: its clients only see the devices of
+//! that frontend, so the tenants of a process serving several frontends are kept apart.
+
+#![allow(dead_code)]
+
+use super::capture::CaptureOptions;
+use super::defines::{BAO_CONTROL_MAX_CONNECTIONS, BAO_CONTROL_MAX_LINE};
+use super::error::{Error, Result};
+use super::events::DeviceEvent;
+#[cfg(feature = "fault-injection")]
+use super::fault::Fault;
+use super::management::{FrontendInfo, Management};
+use super::pause::PauseMode;
+use super::types::{ConfigCoalesce, ConfigControl, ConfigFrontends};
+use super::uuid::DeviceUuid;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// `SO_PEERGROUPS` socket option (Linux 4.13), not exported by `libc`.
+const SO_PEERGROUPS: libc::c_int = 59;
+
+/// Struct representing the credentials of a control socket peer.
+///
+/// # Attributes
+///
+/// * `pid` - Process ID.
+/// * `uid` - User ID.
+/// * `gid` - Primary group ID.
+/// * `groups` - Supplementary group IDs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerCredentials {
+    pub pid: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub groups: Vec<u32>,
+}
+
+impl PeerCredentials {
+    /// Returns the credentials of the peer of a connected socket.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The connected socket.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<PeerCredentials>` - The credentials of the peer at connection time.
+    pub fn of(stream: &UnixStream) -> Result<Self> {
+        let mut cred = libc::ucred {
+            pid: 0,
+            uid: 0,
+            gid: 0,
+        };
+        let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        // SAFETY: `cred` and `len` are valid for writes and `len` holds the size of `cred`.
+        let ret = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut cred as *mut libc::ucred as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret < 0 {
+            return Err(Error::ControlFailed(
+                "peer credentials",
+                io::Error::last_os_error(),
+            ));
+        }
+        Ok(PeerCredentials {
+            pid: cred.pid as u32,
+            uid: cred.uid,
+            gid: cred.gid,
+            groups: Self::groups_of(stream)?,
+        })
+    }
+
+    /// Returns the supplementary groups of the peer of a connected socket.
+    fn groups_of(stream: &UnixStream) -> Result<Vec<u32>> {
+        let size = std::mem::size_of::<libc::gid_t>();
+        let mut groups: Vec<libc::gid_t> = vec![0; 32];
+        loop {
+            let mut len = (groups.len() * size) as libc::socklen_t;
+            // SAFETY: `groups` is valid for writes of `len` bytes.
+            let ret = unsafe {
+                libc::getsockopt(
+                    stream.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    SO_PEERGROUPS,
+                    groups.as_mut_ptr() as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+            if ret == 0 {
+                groups.truncate(len as usize / size);
+                return Ok(groups);
+            }
+            // The peer has more groups than fit, `len` holds the size needed
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::ERANGE) || len as usize <= groups.len() * size {
+                return Err(Error::ControlFailed("peer groups", err));
+            }
+            groups.resize(len as usize / size, 0);
+        }
+    }
+
+    /// Returns the group IDs of the peer.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<u32>` - The primary group, then the supplementary ones.
+    pub fn gids(&self) -> Vec<u32> {
+        let mut gids = vec![self.gid];
+        gids.extend(&self.groups);
+        gids
+    }
+}
+
+/// Enum representing the class of a control command.
+///
+/// # Variants
+///
+/// * `Read` - Read-only commands (device list, state and counters).
+/// * `Admin` - Commands changing the devices (counter reset, unplug, restore, ...).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CommandClass {
+    Read,
+    Admin,
+}
+
+impl CommandClass {
+    /// Returns the name of the command class.
+    pub fn name(&self) -> &'static str {
+        match self {
+            CommandClass::Read => "read",
+            CommandClass::Admin => "admin",
+        }
+    }
+}
+
+impl fmt::Display for CommandClass {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Enum representing a control command.
+///
+/// # Variants
+///
+/// * `Devices` - `devices`: lists the devices.
+/// * `Stats` - `stats NAME`: returns the counters of a device.
+/// * `Resources` - `resources NAME`: returns the host resources held by a device.
+/// * `IrqStats` - `irq-stats`: returns the interrupts injected on each IRQ line.
+/// * `State` - `state NAME`: returns the state of a device.
+/// * `Coalescing` - `coalescing NAME`: returns the interrupt coalescing of a device.
+/// * `Units` - `units`: lists the supervised frontend processes.
+/// * `Subscribe` - `subscribe`: streams the device events until the client disconnects.
+/// * `ResetStats` - `reset-stats NAME`: resets the counters of a device.
+/// * `ResetAllStats` - `reset-stats`: resets the counters of every device.
+/// * `FlushLogs` - `flush-logs`: flushes the trace and audit files.
+/// * `RotateLogs` - `rotate-logs`: rotates the trace and audit files.
+/// * `Plug` - `plug NAME`: hot-plugs a device.
+/// * `Unplug` - `unplug NAME`: hot-unplugs a device.
+/// * `Pause` - `pause NAME [stall|needs-reset]`: pauses a device (stalling it by default).
+/// * `Resume` - `resume NAME`: resumes a paused device.
+/// * `Resize` - `resize NAME [SIZE]`: grows a device to SIZE bytes (or sizes its backing
+///   storage again), returning its capacity.
+/// * `Capture` - `capture NAME PATH [MAX_BYTES [SECONDS]]`: captures the frames of a device
+///   into a pcapng file, up to MAX_BYTES (`BAO_CAPTURE_MAX_BYTES` by default) and SECONDS.
+/// * `StopCapture` - `stop-capture NAME`: stops the packet capture of a device, returning
+///   what was captured.
+/// * `ConsoleLog` - `console-log NAME`: returns the most recent output of a console.
+/// * `QueueDump` - `queue-dump NAME QUEUE`: returns the indices, size and ring addresses
+///   of a virtqueue, and the heads of the last descriptor chains processed.
+/// * `MigrateBackend` - `migrate-backend NAME SOCKET`: moves a running device to the
+///   vhost-user backend listening on SOCKET.
+/// * `SetCoalescing` - `set-coalescing NAME COMPLETIONS DELAY_US`: changes the interrupt
+///   coalescing of a device.
+/// * `Snapshot` - `snapshot DIR`: snapshots the devices into a directory.
+/// * `Restore` - `restore DIR`: restores the devices from a snapshot.
+/// * `Frontends` - `frontends`: lists the frontends.
+/// * `Enable` - `enable FRONTEND`: resumes the devices of a disabled frontend.
+/// * `Disable` - `disable FRONTEND`: stalls the devices of a frontend.
+/// * `Faults` - `faults NAME`: returns the faults injected into a device
+///   (`fault-injection` feature).
+/// * `Fault` - `fault NAME FAULT...`: injects a fault into a device (see `Fault`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlCommand {
+    Devices,
+    Stats(String),
+    Resources(String),
+    IrqStats,
+    State(String),
+    Coalescing(String),
+    Units,
+    Subscribe,
+    ResetStats(String),
+    ResetAllStats,
+    FlushLogs,
+    RotateLogs,
+    Plug(String),
+    Unplug(String),
+    Pause(String, PauseMode),
+    Resume(String),
+    Resize(String, Option<u64>),
+    Capture(String, CaptureOptions),
+    StopCapture(String),
+    ConsoleLog(String),
+    QueueDump(String, usize),
+    MigrateBackend(String, String),
+    SetCoalescing(String, ConfigCoalesce),
+    Snapshot(String),
+    Restore(String),
+    Frontends,
+    Enable(String),
+    Disable(String),
+    #[cfg(feature = "fault-injection")]
+    Faults(String),
+    #[cfg(feature = "fault-injection")]
+    Fault(String, Fault),
+}
+
+impl ControlCommand {
+    /// Parses a command line.
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - The command line (command and whitespace separated arguments).
+    ///
+    /// # Returns
+    ///
+    /// * `Result<ControlCommand>` - The command, or `InvalidControlCommand`.
+    pub fn parse(line: &str) -> Result<Self> {
+        let invalid = || Error::InvalidControlCommand(line.trim().to_string());
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let name = || words[1].to_string();
+        let command = match (words.first().copied(), words.len()) {
+            (Some("devices"), 1) => ControlCommand::Devices,
+            (Some("stats"), 2) => ControlCommand::Stats(name()),
+            (Some("resources"), 2) => ControlCommand::Resources(name()),
+            (Some("irq-stats"), 1) => ControlCommand::IrqStats,
+            (Some("state"), 2) => ControlCommand::State(name()),
+            (Some("coalescing"), 2) => ControlCommand::Coalescing(name()),
+            (Some("units"), 1) => ControlCommand::Units,
+            (Some("subscribe"), 1) => ControlCommand::Subscribe,
+            (Some("reset-stats"), 2) => ControlCommand::ResetStats(name()),
+            (Some("reset-stats"), 1) => ControlCommand::ResetAllStats,
+            (Some("flush-logs"), 1) => ControlCommand::FlushLogs,
+            (Some("rotate-logs"), 1) => ControlCommand::RotateLogs,
+            (Some("plug"), 2) => ControlCommand::Plug(name()),
+            (Some("unplug"), 2) => ControlCommand::Unplug(name()),
+            (Some("pause"), 2) => ControlCommand::Pause(name(), PauseMode::default()),
+            (Some("pause"), 3) => ControlCommand::Pause(name(), words[2].parse()?),
+            (Some("resume"), 2) => ControlCommand::Resume(name()),
+            (Some("resize"), 2) => ControlCommand::Resize(name(), None),
+            (Some("resize"), 3) => {
+                ControlCommand::Resize(name(), Some(words[2].parse().map_err(|_| invalid())?))
+            }
+            (Some("capture"), 3..=5) => {
+                let mut options = CaptureOptions::new(words[2]);
+                if let Some(max_bytes) = words.get(3) {
+                    options.max_bytes = max_bytes.parse().map_err(|_| invalid())?;
+                }
+                if let Some(seconds) = words.get(4) {
+                    let seconds = seconds.parse().map_err(|_| invalid())?;
+                    options.duration = Some(Duration::from_secs(seconds));
+                }
+                ControlCommand::Capture(name(), options)
+            }
+            (Some("stop-capture"), 2) => ControlCommand::StopCapture(name()),
+            (Some("console-log"), 2) => ControlCommand::ConsoleLog(name()),
+            (Some("queue-dump"), 3) => {
+                ControlCommand::QueueDump(name(), words[2].parse().map_err(|_| invalid())?)
+            }
+            (Some("migrate-backend"), 3) => {
+                ControlCommand::MigrateBackend(name(), words[2].to_string())
+            }
+            (Some("set-coalescing"), 4) => ControlCommand::SetCoalescing(
+                name(),
+                ConfigCoalesce {
+                    max_completions: words[2].parse().map_err(|_| invalid())?,
+                    max_delay_us: words[3].parse().map_err(|_| invalid())?,
+                },
+            ),
+            (Some("snapshot"), 2) => ControlCommand::Snapshot(name()),
+            (Some("restore"), 2) => ControlCommand::Restore(name()),
+            (Some("frontends"), 1) => ControlCommand::Frontends,
+            (Some("enable"), 2) => ControlCommand::Enable(name()),
+            (Some("disable"), 2) => ControlCommand::Disable(name()),
+            #[cfg(feature = "fault-injection")]
+            (Some("faults"), 2) => ControlCommand::Faults(name()),
+            #[cfg(feature = "fault-injection")]
+            (Some("fault"), n) if n > 2 => {
+                ControlCommand::Fault(name(), words[2..].join(" ").parse()?)
+            }
+            _ => return Err(invalid()),
+        };
+        Ok(command)
+    }
+
+    /// Returns the class of the command.
+    pub fn class(&self) -> CommandClass {
+        match self {
+            ControlCommand::Devices
+            | ControlCommand::Stats(_)
+            | ControlCommand::Resources(_)
+            | ControlCommand::IrqStats
+            | ControlCommand::State(_)
+            | ControlCommand::Coalescing(_)
+            | ControlCommand::ConsoleLog(_)
+            | ControlCommand::QueueDump(..)
+            | ControlCommand::Units
+            | ControlCommand::Subscribe
+            | ControlCommand::Frontends => CommandClass::Read,
+            #[cfg(feature = "fault-injection")]
+            ControlCommand::Faults(_) => CommandClass::Read,
+            ControlCommand::ResetStats(_)
+            | ControlCommand::ResetAllStats
+            | ControlCommand::FlushLogs
+            | ControlCommand::RotateLogs
+            | ControlCommand::Plug(_)
+            | ControlCommand::Unplug(_)
+            | ControlCommand::Pause(..)
+            | ControlCommand::Resume(_)
+            | ControlCommand::Resize(..)
+            | ControlCommand::Capture(..)
+            | ControlCommand::StopCapture(_)
+            | ControlCommand::MigrateBackend(..)
+            | ControlCommand::SetCoalescing(..)
+            | ControlCommand::Snapshot(_)
+            | ControlCommand::Restore(_)
+            | ControlCommand::Enable(_)
+            | ControlCommand::Disable(_) => CommandClass::Admin,
+            #[cfg(feature = "fault-injection")]
+            ControlCommand::Fault(..) => CommandClass::Admin,
+        }
+    }
+
+    /// Returns the device the command is about, if any.
+    pub fn device(&self) -> Option<&str> {
+        match self {
+            ControlCommand::Stats(name)
+            | ControlCommand::Resources(name)
+            | ControlCommand::State(name)
+            | ControlCommand::Coalescing(name)
+            | ControlCommand::ResetStats(name)
+            | ControlCommand::Plug(name)
+            | ControlCommand::Unplug(name)
+            | ControlCommand::Pause(name, _)
+            | ControlCommand::Resume(name)
+            | ControlCommand::Resize(name, _)
+            | ControlCommand::Capture(name, _)
+            | ControlCommand::StopCapture(name)
+            | ControlCommand::ConsoleLog(name)
+            | ControlCommand::QueueDump(name, _)
+            | ControlCommand::MigrateBackend(name, _)
+            | ControlCommand::SetCoalescing(name, _) => Some(name),
+            #[cfg(feature = "fault-injection")]
+            ControlCommand::Faults(name) | ControlCommand::Fault(name, _) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Returns the device argument of the command, if any.
+    fn device_mut(&mut self) -> Option<&mut String> {
+        match self {
+            ControlCommand::Stats(name)
+            | ControlCommand::Resources(name)
+            | ControlCommand::State(name)
+            | ControlCommand::Coalescing(name)
+            | ControlCommand::ResetStats(name)
+            | ControlCommand::Plug(name)
+            | ControlCommand::Unplug(name)
+            | ControlCommand::Pause(name, _)
+            | ControlCommand::Resume(name)
+            | ControlCommand::Resize(name, _)
+            | ControlCommand::Capture(name, _)
+            | ControlCommand::StopCapture(name)
+            | ControlCommand::ConsoleLog(name)
+            | ControlCommand::QueueDump(name, _)
+            | ControlCommand::MigrateBackend(name, _)
+            | ControlCommand::SetCoalescing(name, _) => Some(name),
+            #[cfg(feature = "fault-injection")]
+            ControlCommand::Faults(name) | ControlCommand::Fault(name, _) => Some(name),
+            _ => None,
+        }
+    }
+
+    /// Replaces a device UUID argument with the name of the device.
+    ///
+    /// # Arguments
+    ///
+    /// * `management` - The management implementation.
+    ///
+    /// # Returns
+    ///
+    /// * `ControlCommand` - The command, naming its device.
+    pub fn resolve<M: Management>(mut self, management: &M) -> Self {
+        if let Some(device) = self.device_mut() {
+            if let Ok(uuid) = device.parse::<DeviceUuid>() {
+                let info = management.devices().into_iter().find(|d| d.uuid == uuid);
+                if let Some(info) = info {
+                    *device = info.name;
+                }
+            }
+        }
+        self
+    }
+
+    /// Checks if the command only concerns a frontend.
+    ///
+    /// The devices of the other frontends do not exist for the clients of a frontend
+    /// socket, and the commands about the whole process are refused.
+    ///
+    /// # Arguments
+    ///
+    /// * `management` - The management implementation.
+    /// * `frontend` - The frontend of the control socket.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the command may run, `DeviceNotFound`, `FrontendNotFound`
+    ///   or `OutOfFrontendScope` otherwise.
+    fn check_scope<M: Management>(&self, management: &M, frontend: &FrontendInfo) -> Result<()> {
+        match self {
+            ControlCommand::Units
+            | ControlCommand::Snapshot(_)
+            | ControlCommand::Restore(_)
+            | ControlCommand::FlushLogs
+            | ControlCommand::RotateLogs => Err(Error::OutOfFrontendScope(frontend.name.clone())),
+            ControlCommand::Enable(name) | ControlCommand::Disable(name)
+                if *name != frontend.name =>
+            {
+                Err(Error::FrontendNotFound(name.clone()))
+            }
+            _ => match self.device() {
+                Some(name) if !in_scope(management, Some(frontend), name) => {
+                    Err(Error::DeviceNotFound)
+                }
+                _ => Ok(()),
+            },
+        }
+    }
+
+    /// Runs the command.
+    ///
+    /// # Arguments
+    ///
+    /// * `management` - The management implementation.
+    /// * `scope` - Frontend the listings are limited to (every frontend if None).
+    ///
+    /// # Returns
+    ///
+    /// * `Result<serde_yaml::Value>` - The result of the command.
+    fn run<M: Management>(
+        &self,
+        management: &M,
+        scope: Option<&FrontendInfo>,
+    ) -> Result<serde_yaml::Value> {
+        let id = scope.map(|frontend| frontend.id);
+        let value = |value: std::result::Result<serde_yaml::Value, serde_yaml::Error>| {
+            value.map_err(|e| Error::ControlFailed("encode", io::Error::other(e)))
+        };
+        match self {
+            ControlCommand::Devices => {
+                let mut devices = management.devices();
+                devices.retain(|device| id.is_none_or(|id| device.frontend_id == id));
+                value(serde_yaml::to_value(devices))
+            }
+            ControlCommand::Stats(name) => {
+                value(serde_yaml::to_value(management.device_stats(name)?))
+            }
+            ControlCommand::Resources(name) => {
+                value(serde_yaml::to_value(management.device_resources(name)?))
+            }
+            ControlCommand::IrqStats => {
+                let mut irqs = management.irq_stats();
+                for irq in &mut irqs {
+                    irq.devices
+                        .retain(|device| in_scope(management, scope, device));
+                }
+                irqs.retain(|irq| !irq.devices.is_empty());
+                value(serde_yaml::to_value(irqs))
+            }
+            ControlCommand::State(name) => {
+                value(serde_yaml::to_value(management.device_state(name)?))
+            }
+            ControlCommand::Coalescing(name) => {
+                value(serde_yaml::to_value(management.device_coalescing(name)?))
+            }
+            ControlCommand::Units => value(serde_yaml::to_value(management.units())),
+            // The events follow the acknowledgement (see `handle_connection`)
+            ControlCommand::Subscribe => Ok(serde_yaml::Value::Null),
+            ControlCommand::ResetStats(name) => {
+                management.reset_device_stats(name)?;
+                Ok(serde_yaml::Value::Null)
+            }
+            ControlCommand::ResetAllStats => {
+                for device in management.devices() {
+                    if id.is_some_and(|id| device.frontend_id != id) {
+                        continue;
+                    }
+                    match management.reset_device_stats(&device.name) {
+                        // Devices without counters yet have nothing to reset
+                        Ok(()) | Err(Error::DeviceNotFound) => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                Ok(serde_yaml::Value::Null)
+            }
+            ControlCommand::FlushLogs => {
+                management.flush_logs()?;
+                Ok(serde_yaml::Value::Null)
+            }
+            ControlCommand::RotateLogs => {
+                management.rotate_logs()?;
+                Ok(serde_yaml::Value::Null)
+            }
+            ControlCommand::Plug(name) | ControlCommand::Unplug(name) => {
+                management.set_device_plugged(name, matches!(self, ControlCommand::Plug(_)))?;
+                Ok(serde_yaml::Value::Null)
+            }
+            ControlCommand::Pause(name, mode) => {
+                management.pause_device(name, *mode)?;
+                Ok(serde_yaml::Value::Null)
+            }
+            ControlCommand::Resume(name) => {
+                management.resume_device(name)?;
+                Ok(serde_yaml::Value::Null)
+            }
+            ControlCommand::Resize(name, size) => {
+                value(serde_yaml::to_value(management.resize_device(name, *size)?))
+            }
+            ControlCommand::Capture(name, options) => {
+                management.start_capture(name, options)?;
+                Ok(serde_yaml::Value::Null)
+            }
+            ControlCommand::StopCapture(name) => {
+                value(serde_yaml::to_value(management.stop_capture(name)?))
+            }
+            ControlCommand::ConsoleLog(name) => {
+                value(serde_yaml::to_value(management.console_log(name)?))
+            }
+            ControlCommand::QueueDump(name, queue) => {
+                value(serde_yaml::to_value(management.queue_dump(name, *queue)?))
+            }
+            ControlCommand::MigrateBackend(name, socket) => {
+                management.migrate_backend(name, socket)?;
+                Ok(serde_yaml::Value::Null)
+            }
+            ControlCommand::SetCoalescing(name, params) => {
+                management.set_device_coalescing(name, Some(*params))?;
+                Ok(serde_yaml::Value::Null)
+            }
+            ControlCommand::Snapshot(dir) => {
+                management.snapshot(dir)?;
+                Ok(serde_yaml::Value::Null)
+            }
+            ControlCommand::Restore(dir) => {
+                management.restore(dir)?;
+                Ok(serde_yaml::Value::Null)
+            }
+            ControlCommand::Frontends => {
+                let mut frontends = management.frontends();
+                frontends.retain(|frontend| id.is_none_or(|id| frontend.id == id));
+                value(serde_yaml::to_value(frontends))
+            }
+            ControlCommand::Enable(name) | ControlCommand::Disable(name) => {
+                management.set_frontend_enabled(name, matches!(self, ControlCommand::Enable(_)))?;
+                Ok(serde_yaml::Value::Null)
+            }
+            #[cfg(feature = "fault-injection")]
+            ControlCommand::Faults(name) => {
+                value(serde_yaml::to_value(management.device_faults(name)?))
+            }
+            #[cfg(feature = "fault-injection")]
+            ControlCommand::Fault(name, fault) => {
+                management.inject_fault(name, *fault)?;
+                Ok(serde_yaml::Value::Null)
+            }
+        }
+    }
+}
+
+/// Checks if a device belongs to a frontend.
+///
+/// # Arguments
+///
+/// * `management` - The management implementation.
+/// * `scope` - The frontend (every device belongs to None).
+/// * `device` - Device name.
+///
+/// # Returns
+///
+/// * `bool` - True if the device belongs to the frontend.
+fn in_scope<M: Management>(management: &M, scope: Option<&FrontendInfo>, device: &str) -> bool {
+    match scope {
+        Some(frontend) => management
+            .devices()
+            .iter()
+            .any(|info| info.name == device && info.frontend_id == frontend.id),
+        None => true,
+    }
+}
+
+/// Checks if a peer may issue a class of commands.
+///
+/// Root and the user of the frontend may issue every command; the other peers must be
+/// listed (by user, primary or supplementary group) in the `admin` peers, or in the `read` peers for the
+/// read-only commands.
+///
+/// # Arguments
+///
+/// * `config` - The control socket configuration.
+/// * `peer` - The credentials of the peer.
+/// * `class` - The class of the command.
+///
+/// # Returns
+///
+/// * `Result<()>` - Ok if the command is allowed, `ControlPermissionDenied` otherwise.
+pub fn authorize(
+    config: &ConfigControl,
+    peer: &PeerCredentials,
+    class: CommandClass,
+) -> Result<()> {
+    // SAFETY: `geteuid` has no preconditions.
+    let owner = unsafe { libc::geteuid() };
+    let gids = peer.gids();
+    let allowed = peer.uid == 0
+        || peer.uid == owner
+        || config.admin.contains(peer.uid, &gids)
+        || (class == CommandClass::Read && config.read.contains(peer.uid, &gids));
+    if !allowed {
+        return Err(Error::ControlPermissionDenied(peer.uid, class.name()));
+    }
+    Ok(())
+}
+
+/// Enum representing the answer to a control command.
+///
+/// # Variants
+///
+/// * `Ok` - The result of the command.
+/// * `Error` - The error of the command, with its errno value.
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ControlResponse {
+    Ok(serde_yaml::Value),
+    Error { message: String, errno: i32 },
+}
+
+impl From<Result<serde_yaml::Value>> for ControlResponse {
+    fn from(result: Result<serde_yaml::Value>) -> Self {
+        match result {
+            Ok(value) => ControlResponse::Ok(value),
+            Err(e) => ControlResponse::Error {
+                message: e.report(),
+                errno: e.errno(),
+            },
+        }
+    }
+}
+
+/// Answers the commands of a connection, until the peer closes it.
+///
+/// # Arguments
+///
+/// * `management` - The management implementation.
+/// * `config` - The control socket configuration.
+/// * `scope` - Frontend the connection is limited to (every frontend if None).
+/// * `stream` - The connection.
+///
+/// # Returns
+///
+/// * `Result<()>` - Ok once the peer closed the connection.
+fn handle_connection<M: Management>(
+    management: &M,
+    config: &ConfigControl,
+    scope: Option<&FrontendInfo>,
+    stream: UnixStream,
+) -> Result<()> {
+    let peer = PeerCredentials::of(&stream)?;
+    let writer = &stream;
+    let mut reader = BufReader::new(&stream);
+    let mut line = String::new();
+    loop {
+        // Never buffer more than a command line (plus its newline)
+        line.clear();
+        let read = (&mut reader)
+            .take(BAO_CONTROL_MAX_LINE as u64 + 1)
+            .read_line(&mut line)
+            .map_err(|e| Error::ControlFailed("read", e))?;
+        if read == 0 {
+            break;
+        }
+        if line.len() > BAO_CONTROL_MAX_LINE && !line.ends_with('\n') {
+            let response =
+                ControlResponse::from(Err(Error::ControlLineTooLong(BAO_CONTROL_MAX_LINE)));
+            return write_response(writer, &response);
+        }
+        let line = line.trim_end_matches('\n');
+        if line.trim().is_empty() {
+            continue;
+        }
+        let mut events = None;
+        let response = ControlResponse::from(ControlCommand::parse(line).and_then(|command| {
+            let command = command.resolve(management);
+            authorize(config, &peer, command.class())?;
+            if let Some(frontend) = scope {
+                command.check_scope(management, frontend)?;
+            }
+            if command == ControlCommand::Subscribe {
+                events = Some(management.subscribe_events());
+            }
+            command.run(management, scope)
+        }));
+        write_response(writer, &response)?;
+        if let Some(events) = events {
+            return stream_events(events, writer, |device| in_scope(management, scope, device));
+        }
+    }
+    Ok(())
+}
+
+/// Writes the answer to a command, terminated by the end-of-document marker.
+///
+/// # Arguments
+///
+/// * `writer` - The connection to the client.
+/// * `response` - The answer.
+///
+/// # Returns
+///
+/// * `Result<()>` - Ok if the answer was written.
+fn write_response(mut writer: &UnixStream, response: &ControlResponse) -> Result<()> {
+    let yaml = serde_yaml::to_string(response)
+        .map_err(|e| Error::ControlFailed("encode", io::Error::other(e)))?;
+    writer
+        .write_all(format!("{}\n...\n", yaml.trim_end()).as_bytes())
+        .map_err(|e| Error::ControlFailed("write", e))
+}
+
+/// Struct representing a connection counted against `BAO_CONTROL_MAX_CONNECTIONS`,
+/// released when dropped.
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    /// Takes a connection slot.
+    ///
+    /// # Arguments
+    ///
+    /// * `active` - Number of connections being served.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<ConnectionSlot>` - The slot, or None if every slot is taken.
+    fn take(active: &Arc<AtomicUsize>) -> Option<Self> {
+        active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                (count < BAO_CONTROL_MAX_CONNECTIONS).then_some(count + 1)
+            })
+            .ok()
+            .map(|_| ConnectionSlot(active.clone()))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Streams the device events to a subscribed client, one JSON object per line.
+///
+/// # Arguments
+///
+/// * `events` - The device events.
+/// * `writer` - The connection to the client.
+/// * `filter` - Selects the devices whose events are streamed.
+///
+/// # Returns
+///
+/// * `Result<()>` - Ok once the event source went away, or the write failure (e.g. the
+///   client disconnected).
+fn stream_events<F: Fn(&str) -> bool>(
+    events: Receiver<DeviceEvent>,
+    mut writer: &UnixStream,
+    filter: F,
+) -> Result<()> {
+    for event in events.iter().filter(|event| filter(event.device())) {
+        writer
+            .write_all(format!("{}\n", event.to_json()).as_bytes())
+            .map_err(|e| Error::ControlFailed("write", e))?;
+    }
+    Ok(())
+}
+
+/// Listens on a control socket.
+///
+/// # Arguments
+///
+/// * `management` - The management implementation.
+/// * `config` - The control socket configuration.
+/// * `scope` - Frontend the socket is limited to (every frontend if None).
+///
+/// # Returns
+///
+/// * `Result<JoinHandle<()>>` - The thread accepting the connections.
+fn listen<M: Management>(
+    management: Arc<M>,
+    config: &ConfigControl,
+    scope: Option<FrontendInfo>,
+) -> Result<JoinHandle<()>> {
+    let _ = fs::remove_file(&config.path);
+    let listener = UnixListener::bind(&config.path).map_err(|e| Error::ControlFailed("bind", e))?;
+    // The peer credentials, not the socket permissions, decide what each user may do
+    fs::set_permissions(&config.path, fs::Permissions::from_mode(0o666))
+        .map_err(|e| Error::ControlFailed("chmod", e))?;
+    let config = Arc::new(config.clone());
+    let scope = Arc::new(scope);
+    let active = Arc::new(AtomicUsize::new(0));
+    thread::Builder::new()
+        .name("control".to_string())
+        .spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let slot = match ConnectionSlot::take(&active) {
+                    Some(slot) => slot,
+                    None => {
+                        let response = ControlResponse::from(Err(Error::ControlBusy(
+                            BAO_CONTROL_MAX_CONNECTIONS,
+                        )));
+                        let _ = write_response(&stream, &response);
+                        continue;
+                    }
+                };
+                let management = management.clone();
+                let config = config.clone();
+                let scope = scope.clone();
+                let _ = thread::Builder::new()
+                    .name("control-conn".to_string())
+                    .spawn(move || {
+                        let _slot = slot;
+                        let _ = handle_connection(
+                            &*management,
+                            &config,
+                            scope.as_ref().as_ref(),
+                            stream,
+                        );
+                    });
+            }
+        })
+        .map_err(|e| Error::ControlFailed("spawn", e))
+}
+
+/// Serves the management operations on the control socket.
+///
+/// A stale socket left at the path is replaced. Every connection is served by a thread of
+/// its own, up to `BAO_CONTROL_MAX_CONNECTIONS` at a time.
+///
+/// # Arguments
+///
+/// * `management` - The management implementation.
+/// * `config` - The control socket configuration.
+///
+/// # Returns
+///
+/// * `Result<JoinHandle<()>>` - The thread accepting the connections.
+pub fn serve<M: Management>(management: Arc<M>, config: &ConfigControl) -> Result<JoinHandle<()>> {
+    listen(management, config, None)
+}
+
+/// Serves the management operations of a frontend on its own control socket.
+///
+/// The clients of the socket only see the devices of the frontend, so a tenant cannot
+/// query or change the devices of the other frontends served by the process.
+///
+/// # Arguments
+///
+/// * `management` - The management implementation.
+/// * `config` - The control socket configuration of the frontend.
+/// * `frontend` - Frontend name.
+///
+/// # Returns
+///
+/// * `Result<JoinHandle<()>>` - The thread accepting the connections, or
+///   `FrontendNotFound`.
+pub fn serve_frontend<M: Management>(
+    management: Arc<M>,
+    config: &ConfigControl,
+    frontend: &str,
+) -> Result<JoinHandle<()>> {
+    let scope = management
+        .frontends()
+        .into_iter()
+        .find(|info| info.name == frontend)
+        .ok_or_else(|| Error::FrontendNotFound(frontend.to_string()))?;
+    listen(management, config, Some(scope))
+}
+
+/// Serves every control socket of a configuration (the process one and those of the
+/// frontends).
+///
+/// # Arguments
+///
+/// * `management` - The management implementation.
+/// * `config` - The frontends configuration.
+///
+/// # Returns
+///
+/// * `Result<Vec<JoinHandle<()>>>` - The threads accepting the connections.
+pub fn serve_all<M: Management>(
+    management: Arc<M>,
+    config: &ConfigFrontends,
+) -> Result<Vec<JoinHandle<()>>> {
+    let mut threads = Vec::new();
+    if let Some(control) = &config.control {
+        threads.push(serve(management.clone(), control)?);
+    }
+    for frontend in &config.frontends {
+        if let Some(control) = &frontend.control {
+            threads.push(serve_frontend(management.clone(), control, &frontend.name)?);
+        }
+    }
+    Ok(threads)
+}
+
+/// Sends a command to a control socket and waits for its answer.
+///
+/// # Arguments
+///
+/// * `stream` - The connection to the control socket.
+/// * `command` - The command line.
+///
+/// # Returns
+///
+/// * `Result<ControlResponse>` - The answer to the command.
+pub fn send_command(stream: &mut UnixStream, command: &str) -> Result<ControlResponse> {
+    writeln!(stream, "{}", command).map_err(|e| Error::ControlFailed("write", e))?;
+    let mut reader = BufReader::new(&*stream);
+    let mut yaml = String::new();
+    loop {
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(0) => {
+                return Err(Error::ControlFailed(
+                    "read",
+                    io::ErrorKind::UnexpectedEof.into(),
+                ))
+            }
+            Ok(_) if line == "...\n" => break,
+            Ok(_) => yaml.push_str(&line),
+            Err(e) => return Err(Error::ControlFailed("read", e)),
+        }
+    }
+    serde_yaml::from_str(&yaml).map_err(|e| Error::ControlFailed("decode", io::Error::other(e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::management::{DeviceRegistry, DeviceState};
+    use crate::resources::DeviceResources;
+    use crate::stats::{inc, DeviceStats};
+    use crate::trace::Tracer;
+    use crate::types::{ConfigControlPeers, VmId};
+
+    #[test]
+    fn test_control_commands() {
+        assert_eq!(
+            ControlCommand::parse("stats rng0").unwrap(),
+            ControlCommand::Stats("rng0".to_string())
+        );
+        assert_eq!(
+            ControlCommand::parse(" set-coalescing rng0 16 100\n").unwrap(),
+            ControlCommand::SetCoalescing(
+                "rng0".to_string(),
+                ConfigCoalesce {
+                    max_completions: 16,
+                    max_delay_us: 100
+                }
+            )
+        );
+        assert_eq!(
+            ControlCommand::parse("pause rng0 needs-reset").unwrap(),
+            ControlCommand::Pause("rng0".to_string(), PauseMode::NeedsReset)
+        );
+        assert!(matches!(
+            ControlCommand::parse("pause rng0 offline"),
+            Err(Error::InvalidPauseMode(_))
+        ));
+        assert_eq!(
+            ControlCommand::parse("resize blk0 1073741824").unwrap(),
+            ControlCommand::Resize("blk0".to_string(), Some(1 << 30))
+        );
+        assert_eq!(
+            ControlCommand::parse("resize blk0").unwrap(),
+            ControlCommand::Resize("blk0".to_string(), None)
+        );
+        assert_eq!(
+            ControlCommand::parse("capture net0 /tmp/net0.pcapng 1048576 30").unwrap(),
+            ControlCommand::Capture(
+                "net0".to_string(),
+                CaptureOptions {
+                    max_bytes: 1 << 20,
+                    duration: Some(Duration::from_secs(30)),
+                    ..CaptureOptions::new("/tmp/net0.pcapng")
+                }
+            )
+        );
+        assert_eq!(
+            ControlCommand::parse("capture net0 /tmp/net0.pcapng").unwrap(),
+            ControlCommand::Capture("net0".to_string(), CaptureOptions::new("/tmp/net0.pcapng"))
+        );
+        assert_eq!(
+            ControlCommand::parse("stop-capture net0").unwrap(),
+            ControlCommand::StopCapture("net0".to_string())
+        );
+        assert_eq!(
+            ControlCommand::parse("console-log console0").unwrap(),
+            ControlCommand::ConsoleLog("console0".to_string())
+        );
+        assert_eq!(
+            ControlCommand::parse("queue-dump blk0 0").unwrap(),
+            ControlCommand::QueueDump("blk0".to_string(), 0)
+        );
+        assert_eq!(
+            ControlCommand::parse("migrate-backend blk0 /run/bao/blk0-v2.sock").unwrap(),
+            ControlCommand::MigrateBackend("blk0".to_string(), "/run/bao/blk0-v2.sock".to_string())
+        );
+        for line in [
+            "",
+            "stats",
+            "devices rng0",
+            "set-coalescing rng0 x 1",
+            "queue-dump blk0",
+            "queue-dump blk0 rx",
+            "resize blk0 1G",
+            "capture net0",
+            "capture net0 /tmp/net0.pcapng 1M",
+            "console-log",
+            "migrate-backend blk0",
+            "remove",
+        ] {
+            assert!(matches!(
+                ControlCommand::parse(line),
+                Err(Error::InvalidControlCommand(_))
+            ));
+        }
+        #[cfg(feature = "fault-injection")]
+        assert_eq!(
+            ControlCommand::parse("fault rng0 corrupt 0x70 4").unwrap(),
+            ControlCommand::Fault("rng0".to_string(), Fault::Corrupt(0x70, 4))
+        );
+        assert_eq!(
+            ControlCommand::parse("reset-stats").unwrap(),
+            ControlCommand::ResetAllStats
+        );
+        assert_eq!(ControlCommand::IrqStats.class(), CommandClass::Read);
+        assert_eq!(
+            ControlCommand::ConsoleLog("console0".to_string()).class(),
+            CommandClass::Read
+        );
+        assert_eq!(
+            ControlCommand::QueueDump("blk0".to_string(), 0).class(),
+            CommandClass::Read
+        );
+        assert_eq!(ControlCommand::RotateLogs.class(), CommandClass::Admin);
+        assert_eq!(
+            ControlCommand::MigrateBackend("blk0".to_string(), String::new()).class(),
+            CommandClass::Admin
+        );
+        assert_eq!(
+            ControlCommand::Unplug("rng0".to_string()).class(),
+            CommandClass::Admin
+        );
+
+        // Unlisted peers may not do anything, read peers may only read
+        let config = ConfigControl {
+            path: String::new(),
+            read: ConfigControlPeers {
+                uids: vec![],
+                gids: vec![4000],
+            },
+            admin: ConfigControlPeers {
+                uids: vec![5000],
+                gids: vec![],
+            },
+        };
+        // SAFETY: `geteuid` has no preconditions.
+        let owner = unsafe { libc::geteuid() };
+        let peer = |uid, gid, groups: &[u32]| PeerCredentials {
+            pid: 1,
+            uid,
+            gid,
+            groups: groups.to_vec(),
+        };
+        for (uid, gid, groups, read, admin) in [
+            (owner, owner, &[][..], true, true),
+            (0, 0, &[], true, true),
+            (5000, 5000, &[], true, true),
+            (4001, 4000, &[], true, false),
+            (4001, 4001, &[100, 4000], true, false),
+            (4000, 4001, &[], false, false),
+        ] {
+            let peer = peer(uid, gid, groups);
+            assert_eq!(authorize(&config, &peer, CommandClass::Read).is_ok(), read);
+            assert_eq!(
+                authorize(&config, &peer, CommandClass::Admin).is_ok(),
+                admin
+            );
+        }
+        let err = authorize(&config, &peer(4001, 4000, &[]), CommandClass::Admin).unwrap_err();
+        assert_eq!(err.errno(), libc::EACCES);
+    }
+
+    #[test]
+    fn test_control_socket() {
+        let config = serde_yaml::from_str(
+            "frontends:
+  - name: frontend0
+    id: 0
+    guests:
+      - name: guest0
+        id: 1
+        ram_addr: 0x50000000
+        ram_size: 0x1000000
+        shmem_path: /dev/baoipc0
+        socket_path: /tmp/
+        devices:
+          - {name: rng0, id: 0, type: rng, irq: 47, addr: 0xa003e00}
+",
+        )
+        .unwrap();
+        let registry = Arc::new(DeviceRegistry::new(&config));
+        let counters = Arc::new(DeviceStats::new("rng0", 1));
+        inc(&counters.interrupts);
+        registry.attach_stats(counters.clone());
+        let resources = Arc::new(DeviceResources::new("rng0"));
+        resources.track_mapping(0x1000);
+        registry.attach_resources(resources);
+        registry
+            .set_device_state("rng0", DeviceState::Running)
+            .unwrap();
+        let path = std::env::temp_dir().join(format!("bao-control-{}.sock", std::process::id()));
+        let config = ConfigControl {
+            path: path.to_str().unwrap().to_string(),
+            ..Default::default()
+        };
+        serve(registry.clone(), &config).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o666);
+
+        // The frontend user may issue every command, on a single connection
+        let mut stream = UnixStream::connect(&path).unwrap();
+        assert_eq!(
+            PeerCredentials::of(&stream).unwrap().pid,
+            std::process::id()
+        );
+        let response = send_command(&mut stream, "state rng0").unwrap();
+        assert_eq!(
+            response,
+            ControlResponse::Ok(serde_yaml::Value::String("running".to_string()))
+        );
+        let response = send_command(&mut stream, "stats rng0").unwrap();
+        let ControlResponse::Ok(stats) = response else {
+            panic!("unexpected response {:?}", response);
+        };
+        assert_eq!(stats["interrupts"], serde_yaml::Value::from(1));
+        let response = send_command(&mut stream, "resources rng0").unwrap();
+        let ControlResponse::Ok(resources) = response else {
+            panic!("unexpected response {:?}", response);
+        };
+        assert_eq!(resources["mapped_bytes"], serde_yaml::Value::from(0x1000));
+
+        // Devices may be named by their UUID
+        let uuid = DeviceUuid::derive(VmId(1), "rng0");
+        let response = send_command(&mut stream, &format!("state {}", uuid)).unwrap();
+        assert_eq!(
+            response,
+            ControlResponse::Ok(serde_yaml::Value::String("running".to_string()))
+        );
+
+        // Connections beyond the limit are refused
+        let mut streams: Vec<_> = (1..BAO_CONTROL_MAX_CONNECTIONS)
+            .map(|_| UnixStream::connect(&path).unwrap())
+            .collect();
+        for stream in &mut streams {
+            send_command(stream, "state rng0").unwrap();
+        }
+        let mut busy = String::new();
+        UnixStream::connect(&path)
+            .unwrap()
+            .read_to_string(&mut busy)
+            .unwrap();
+        assert!(busy.contains("errno: 16"));
+
+        // Over-long command lines are refused and close the connection
+        let mut long = streams.pop().unwrap();
+        // (the connection may be closed before the whole line is written)
+        let _ = writeln!(long, "{}", "a".repeat(BAO_CONTROL_MAX_LINE + 1));
+        let mut refused = String::new();
+        long.read_to_string(&mut refused).unwrap();
+        assert!(refused.contains("errno: 22"));
+        drop(streams);
+
+        send_command(&mut stream, "reset-stats rng0").unwrap();
+        assert_eq!(registry.device_stats("rng0").unwrap().interrupts, 0);
+        inc(&counters.interrupts);
+        send_command(&mut stream, "reset-stats").unwrap();
+        assert_eq!(registry.device_stats("rng0").unwrap().interrupts, 0);
+
+        // The trace files are rotated on demand
+        let trace = std::env::temp_dir().join(format!("bao-control-{}.json", std::process::id()));
+        let trace = trace.to_str().unwrap();
+        registry.attach_log(Arc::new(Tracer::new(trace).unwrap()));
+        for command in ["flush-logs", "rotate-logs"] {
+            let response = send_command(&mut stream, command).unwrap();
+            assert_eq!(response, ControlResponse::Ok(serde_yaml::Value::Null));
+        }
+        assert!(fs::read_to_string(format!("{}.1", trace))
+            .unwrap()
+            .ends_with("]\n"));
+        fs::remove_file(format!("{}.1", trace)).unwrap();
+        fs::remove_file(trace).unwrap();
+        let response = send_command(&mut stream, "unplug rng1").unwrap();
+        assert_eq!(
+            response,
+            ControlResponse::Error {
+                message: "Device not found".to_string(),
+                errno: libc::ENODEV
+            }
+        );
+        let response = send_command(&mut stream, "remove rng0").unwrap();
+        assert!(matches!(response, ControlResponse::Error { errno, .. } if errno == libc::EINVAL));
+
+        // Subscribed connections receive the device events
+        let response = send_command(&mut stream, "subscribe").unwrap();
+        assert_eq!(response, ControlResponse::Ok(serde_yaml::Value::Null));
+        registry
+            .set_device_state("rng0", DeviceState::Failed)
+            .unwrap();
+        registry.publish_event(DeviceEvent::BackendDisconnected("rng0".to_string()));
+        let mut lines = BufReader::new(&stream).lines();
+        let line = lines.next().unwrap().unwrap();
+        assert!(line
+            .starts_with("{\"event\":\"state-changed\",\"device\":\"rng0\",\"state\":\"failed\""));
+        let line = lines.next().unwrap().unwrap();
+        assert!(line.starts_with("{\"event\":\"backend-disconnected\",\"device\":\"rng0\""));
+        drop(lines);
+        drop(stream);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_frontend_control_socket() {
+        let path = std::env::temp_dir().join(format!("bao-control-{}-1.sock", std::process::id()));
+        let config: ConfigFrontends = serde_yaml::from_str(&format!(
+            "frontends:
+  - name: frontend0
+    id: 0
+    guests:
+      - name: guest0
+        id: 1
+        ram_addr: 0x50000000
+        ram_size: 0x1000000
+        shmem_path: /dev/baoipc0
+        socket_path: /tmp/
+        devices:
+          - {{name: rng0, id: 0, type: rng, irq: 47, addr: 0xa003e00}}
+  - name: frontend1
+    id: 2
+    control:
+      path: {}
+    guests:
+      - name: guest1
+        id: 3
+        ram_addr: 0x60000000
+        ram_size: 0x1000000
+        shmem_path: /dev/baoipc1
+        socket_path: /tmp/
+        devices:
+          - {{name: rng1, id: 1, type: rng, irq: 48, addr: 0xa003f00}}
+",
+            path.display()
+        ))
+        .unwrap();
+        let registry = Arc::new(DeviceRegistry::new(&config));
+        registry.attach_stats(Arc::new(DeviceStats::new("rng0", 0)));
+        registry.attach_stats(Arc::new(DeviceStats::new("rng1", 1)));
+        assert_eq!(serve_all(registry, &config).unwrap().len(), 1);
+
+        // The socket of a frontend only sees its own devices
+        let mut stream = UnixStream::connect(&path).unwrap();
+        let response = send_command(&mut stream, "devices").unwrap();
+        let ControlResponse::Ok(devices) = response else {
+            panic!("unexpected response {:?}", response);
+        };
+        assert_eq!(devices.as_sequence().unwrap().len(), 1);
+        assert_eq!(devices[0]["name"], serde_yaml::Value::from("rng1"));
+        let response = send_command(&mut stream, "frontends").unwrap();
+        let ControlResponse::Ok(frontends) = response else {
+            panic!("unexpected response {:?}", response);
+        };
+        assert_eq!(frontends.as_sequence().unwrap().len(), 1);
+        assert!(matches!(
+            send_command(&mut stream, "stats rng1").unwrap(),
+            ControlResponse::Ok(_)
+        ));
+        for (command, expected) in [
+            ("stats rng0", libc::ENODEV),
+            ("disable frontend0", libc::ENODEV),
+            ("units", libc::EACCES),
+            ("rotate-logs", libc::EACCES),
+        ] {
+            let response = send_command(&mut stream, command).unwrap();
+            assert!(
+                matches!(response, ControlResponse::Error { errno, .. } if errno == expected),
+                "{}: {:?}",
+                command,
+                response
+            );
+        }
+        drop(stream);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,193 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao crash reporting.
+
+#![allow(dead_code)]
+
+use super::types::BaoIoRequest;
+use lazy_static::lazy_static;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Struct representing an I/O request that has been fetched but not yet completed.
+///
+/// # Attributes
+///
+/// * `device` - Name of the device handling the request.
+/// * `request` - The I/O request.
+#[derive(Debug, Clone)]
+pub struct InFlightRequest {
+    pub device: String,
+    pub request: BaoIoRequest,
+}
+
+lazy_static! {
+    /// Outstanding I/O requests, indexed by tracking token.
+    static ref IN_FLIGHT_REQUESTS: Mutex<BTreeMap<u64, InFlightRequest>> =
+        Mutex::new(BTreeMap::new());
+    /// Last known status register of each device.
+    static ref DEVICE_STATUS: Mutex<BTreeMap<String, u32>> = Mutex::new(BTreeMap::new());
+}
+
+/// Next tracking token.
+static NEXT_TOKEN: AtomicU64 = AtomicU64::new(0);
+
+/// Tracks an I/O request as outstanding.
+///
+/// # Arguments
+///
+/// * `device` - Name of the device handling the request.
+/// * `request` - The I/O request.
+///
+/// # Returns
+///
+/// * `u64` - Token used to untrack the request once completed.
+pub fn track_request(device: &str, request: &BaoIoRequest) -> u64 {
+    let token = NEXT_TOKEN.fetch_add(1, Ordering::Relaxed);
+    if let Ok(mut requests) = IN_FLIGHT_REQUESTS.lock() {
+        requests.insert(
+            token,
+            InFlightRequest {
+                device: device.to_string(),
+                request: *request,
+            },
+        );
+    }
+    token
+}
+
+/// Untracks a completed I/O request.
+///
+/// # Arguments
+///
+/// * `token` - Token returned by `track_request`.
+pub fn complete_request(token: u64) {
+    if let Ok(mut requests) = IN_FLIGHT_REQUESTS.lock() {
+        requests.remove(&token);
+    }
+}
+
+/// Records the status register of a device.
+///
+/// # Arguments
+///
+/// * `device` - Name of the device.
+/// * `status` - Device status register value.
+pub fn set_device_status(device: &str, status: u32) {
+    if let Ok(mut devices) = DEVICE_STATUS.lock() {
+        devices.insert(device.to_string(), status);
+    }
+}
+
+/// Returns the outstanding I/O requests.
+///
+/// # Returns
+///
+/// * `Vec<InFlightRequest>` - The outstanding requests, oldest first.
+pub fn in_flight_requests() -> Vec<InFlightRequest> {
+    IN_FLIGHT_REQUESTS
+        .lock()
+        .map(|requests| requests.values().cloned().collect())
+        .unwrap_or_default()
+}
+
+/// Writes the outstanding I/O requests and the device status to a writer.
+///
+/// Locks are only tried (never awaited), since the dump may run while the panicking
+/// thread holds them.
+///
+/// # Arguments
+///
+/// * `out` - The writer.
+fn dump_state(out: &mut dyn Write) {
+    match IN_FLIGHT_REQUESTS.try_lock() {
+        Ok(requests) => {
+            let _ = writeln!(out, "In-flight I/O requests: {}", requests.len());
+            for (token, entry) in requests.iter() {
+                let req = &entry.request;
+                let _ = writeln!(
+                    out,
+                    "  [{}] device={} virtio_id={} reg_off={:#x} addr={:#x} op={} value={:#x} access_width={} cpu_id={} vcpu_id={}",
+                    token,
+                    entry.device,
+                    req.virtio_id,
+                    req.reg_off,
+                    req.addr,
+                    req.op,
+                    req.value,
+                    req.access_width,
+                    req.cpu_id,
+                    req.vcpu_id
+                );
+            }
+        }
+        Err(_) => {
+            let _ = writeln!(out, "In-flight I/O requests: unavailable (lock held)");
+        }
+    }
+    match DEVICE_STATUS.try_lock() {
+        Ok(devices) => {
+            for (device, status) in devices.iter() {
+                let _ = writeln!(out, "  device={} status={:#x}", device, status);
+            }
+        }
+        Err(_) => {
+            let _ = writeln!(out, "Device status: unavailable (lock held)");
+        }
+    }
+}
+
+/// Installs a panic hook that dumps the in-flight I/O requests and the device status.
+///
+/// The previous hook (e.g. the default backtrace printer) runs first, then the state is
+/// dumped to stderr and the process is aborted, so a panic in a device worker never leaves
+/// the guests served by the frontend silently stalled.
+pub fn install_panic_hook() {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        previous(info);
+        dump_state(&mut std::io::stderr());
+        std::process::abort();
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_track_and_dump_requests() {
+        let request = BaoIoRequest {
+            virtio_id: 7,
+            reg_off: 0x70,
+            addr: 0xa003e70,
+            op: 0,
+            value: 0xf,
+            access_width: 4,
+            cpu_id: 1,
+            vcpu_id: 0,
+            ret: 0,
+        };
+        let token = track_request("crash-test", &request);
+        set_device_status("crash-test", 0xf);
+
+        let mut out = Vec::new();
+        dump_state(&mut out);
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains(&format!(
+            "[{}] device=crash-test virtio_id=7 reg_off=0x70",
+            token
+        )));
+        assert!(out.contains("device=crash-test status=0xf"));
+
+        complete_request(token);
+        assert!(in_flight_requests()
+            .iter()
+            .all(|entry| entry.device != "crash-test"));
+    }
+}
@@ -0,0 +1,335 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao D-Bus management interface.
+//!
+//! Exposes a `Management` implementation on the system bus as `org.bao.Frontend1`, with a
+//! `DeviceStateChanged` signal emitted on every device state change.
+
+#![allow(dead_code)]
+
+use super::error::{Error, Result};
+use super::management::Management;
+use super::types::{ConfigCoalesce, ConfigRegRange};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use zbus::blocking::connection;
+use zbus::blocking::Connection;
+use zbus::{fdo, interface};
+
+/// D-Bus well-known name of the frontend.
+pub const BAO_DBUS_NAME: &str = "org.bao.Frontend";
+/// D-Bus object path of the frontend.
+pub const BAO_DBUS_PATH: &str = "/org/bao/Frontend";
+/// D-Bus interface of the frontend.
+pub const BAO_DBUS_INTERFACE: &str = "org.bao.Frontend1";
+
+impl From<Error> for fdo::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::DeviceNotFound => fdo::Error::UnknownObject(e.report()),
+            Error::HotplugNotSupported
+            | Error::SnapshotNotSupported(_)
+            | Error::PauseNotSupported(_) => fdo::Error::NotSupported(e.report()),
+            Error::InvalidPauseMode(_) => fdo::Error::InvalidArgs(e.report()),
+            _ => fdo::Error::Failed(e.report()),
+        }
+    }
+}
+
+/// Struct representing the D-Bus adapter of a management implementation.
+///
+/// # Attributes
+///
+/// * `management` - The management implementation.
+pub struct DbusManagement<M: Management> {
+    management: Arc<M>,
+}
+
+#[interface(name = "org.bao.Frontend1")]
+impl<M: Management> DbusManagement<M> {
+    /// Lists the devices as `(name, type, guest ID)`.
+    fn list_devices(&self) -> Vec<(String, String, u32)> {
+        self.management
+            .devices()
+            .into_iter()
+            .map(|device| (device.name, device.device_type, device.guest_id.raw()))
+            .collect()
+    }
+
+    /// Returns the state of a device.
+    fn device_state(&self, name: &str) -> fdo::Result<String> {
+        Ok(self.management.device_state(name)?.to_string())
+    }
+
+    /// Hot-plugs a device.
+    fn plug(&self, name: &str) -> fdo::Result<()> {
+        Ok(self.management.set_device_plugged(name, true)?)
+    }
+
+    /// Hot-unplugs a device.
+    fn unplug(&self, name: &str) -> fdo::Result<()> {
+        Ok(self.management.set_device_plugged(name, false)?)
+    }
+
+    /// Pauses a running device, `mode` being `stall` or `needs-reset`.
+    fn pause(&self, name: &str, mode: &str) -> fdo::Result<()> {
+        Ok(self.management.pause_device(name, mode.parse()?)?)
+    }
+
+    /// Resumes a paused device.
+    fn resume(&self, name: &str) -> fdo::Result<()> {
+        Ok(self.management.resume_device(name)?)
+    }
+
+    /// Returns the host resources held by a device as `(user CPU time, kernel CPU time,
+    /// worker threads, mapped bytes, file descriptors)`, CPU times in nanoseconds.
+    fn device_resources(&self, name: &str) -> fdo::Result<(u64, u64, u64, u64, u64)> {
+        let resources = self.management.device_resources(name)?;
+        Ok((
+            resources.cpu_user_ns,
+            resources.cpu_system_ns,
+            resources.workers,
+            resources.mapped_bytes,
+            resources.fds,
+        ))
+    }
+
+    /// Lists the interrupts injected on each IRQ line as `(IRQ line, devices, injections,
+    /// last injection time in nanoseconds since the Unix epoch)`.
+    fn irq_stats(&self) -> Vec<(u32, Vec<String>, u64, u64)> {
+        self.management
+            .irq_stats()
+            .into_iter()
+            .map(|line| {
+                (
+                    line.irq,
+                    line.devices,
+                    line.injections,
+                    line.last_injection_ns,
+                )
+            })
+            .collect()
+    }
+
+    /// Returns the interrupt coalescing of a device as `(max completions, max delay in
+    /// microseconds)`, zero if disabled.
+    fn coalescing(&self, name: &str) -> fdo::Result<(u32, u64)> {
+        let params = self.management.device_coalescing(name)?.unwrap_or_default();
+        Ok((params.max_completions, params.max_delay_us))
+    }
+
+    /// Changes the interrupt coalescing of a device (zero bounds disable it).
+    fn set_coalescing(
+        &self,
+        name: &str,
+        max_completions: u32,
+        max_delay_us: u64,
+    ) -> fdo::Result<()> {
+        let params = ConfigCoalesce {
+            max_completions,
+            max_delay_us,
+        };
+        Ok(self.management.set_device_coalescing(name, Some(params))?)
+    }
+
+    /// Returns the MMIO access tracing of a device as `(enabled, [(start, end)])`, every
+    /// register being traced if no range is given.
+    fn access_trace(&self, name: &str) -> fdo::Result<(bool, Vec<(u64, u64)>)> {
+        let ranges = self.management.device_access_trace(name)?;
+        let enabled = ranges.is_some();
+        let ranges = ranges.unwrap_or_default();
+        Ok((enabled, ranges.iter().map(|r| (r.start, r.end)).collect()))
+    }
+
+    /// Changes the MMIO access tracing of a device (every register if no range is given).
+    fn set_access_trace(
+        &self,
+        name: &str,
+        enabled: bool,
+        ranges: Vec<(u64, u64)>,
+    ) -> fdo::Result<()> {
+        let ranges = ranges
+            .into_iter()
+            .map(|(start, end)| ConfigRegRange { start, end })
+            .collect();
+        Ok(self
+            .management
+            .set_device_access_trace(name, enabled.then_some(ranges))?)
+    }
+
+    /// Lists the supervised frontend processes as `(name, state, PID, restarts, exit
+    /// code)`, zero standing for no PID or exit code.
+    fn units(&self) -> Vec<(String, String, u32, u32, i32)> {
+        self.management
+            .units()
+            .into_iter()
+            .map(|unit| {
+                (
+                    unit.name,
+                    unit.state.to_string(),
+                    unit.pid.unwrap_or(0),
+                    unit.restarts,
+                    unit.exit_code.unwrap_or(0),
+                )
+            })
+            .collect()
+    }
+
+    /// Snapshots the state of every device into a directory.
+    fn snapshot(&self, dir: &str) -> fdo::Result<()> {
+        Ok(self.management.snapshot(dir)?)
+    }
+
+    /// Restores the state of the devices from the snapshot of a directory.
+    fn restore(&self, dir: &str) -> fdo::Result<()> {
+        Ok(self.management.restore(dir)?)
+    }
+
+    /// Lists the frontends as `(name, ID, enabled)`.
+    fn frontends(&self) -> Vec<(String, u32, bool)> {
+        self.management
+            .frontends()
+            .into_iter()
+            .map(|frontend| (frontend.name, frontend.id.raw(), frontend.enabled))
+            .collect()
+    }
+
+    /// Enables (resumes the devices of) or disables (stalls the devices of) a frontend.
+    fn set_frontend_enabled(&self, name: &str, enabled: bool) -> fdo::Result<()> {
+        Ok(self.management.set_frontend_enabled(name, enabled)?)
+    }
+}
+
+/// Serves the management interface on the system bus.
+///
+/// # Arguments
+///
+/// * `management` - The management implementation.
+///
+/// # Returns
+///
+/// * `Result<(Connection, JoinHandle<()>)>` - The bus connection (the service stops when it
+///   is dropped) and the thread emitting the `DeviceStateChanged` signals.
+pub fn serve<M: Management>(management: Arc<M>) -> Result<(Connection, JoinHandle<()>)> {
+    let events = management.subscribe();
+    let connection = connection::Builder::system()
+        .and_then(|builder| builder.name(BAO_DBUS_NAME))
+        .and_then(|builder| builder.serve_at(BAO_DBUS_PATH, DbusManagement { management }))
+        .and_then(|builder| builder.build())
+        .map_err(|e| Error::ManagementServerFailed(e.to_string()))?;
+
+    let signals = connection.clone();
+    let handle = thread::Builder::new()
+        .name("dbus-signals".to_string())
+        .spawn(move || {
+            for (name, state) in events {
+                let _ = signals.emit_signal(
+                    None::<()>,
+                    BAO_DBUS_PATH,
+                    BAO_DBUS_INTERFACE,
+                    "DeviceStateChanged",
+                    &(name, state.to_string()),
+                );
+            }
+        })
+        .map_err(|e| Error::ManagementServerFailed(e.to_string()))?;
+    Ok((connection, handle))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::management::{DeviceRegistry, DeviceState};
+    use crate::resources::DeviceResources;
+    use crate::supervisor::{UnitState, UnitStatus};
+
+    #[test]
+    fn test_dbus_management() {
+        let config = serde_yaml::from_str(
+            "frontends:
+  - name: frontend0
+    id: 0
+    guests:
+      - name: guest0
+        id: 1
+        ram_addr: 0x50000000
+        ram_size: 0x1000000
+        shmem_path: /dev/baoipc0
+        socket_path: /tmp/
+        devices:
+          - {name: rng0, id: 0, type: rng, irq: 47, addr: 0xa003e00}
+",
+        )
+        .unwrap();
+        let registry = Arc::new(DeviceRegistry::new(&config));
+        registry
+            .set_device_state("rng0", DeviceState::Running)
+            .unwrap();
+        let resources = Arc::new(DeviceResources::new("rng0"));
+        resources.track_mapping(0x1000);
+        registry.attach_resources(resources);
+        let dbus = DbusManagement {
+            management: registry,
+        };
+
+        assert_eq!(
+            dbus.list_devices(),
+            vec![("rng0".to_string(), "rng".to_string(), 1)]
+        );
+        assert_eq!(dbus.device_state("rng0").unwrap(), "running");
+        assert_eq!(dbus.irq_stats(), vec![(47, vec!["rng0".to_string()], 0, 0)]);
+        assert_eq!(dbus.device_resources("rng0").unwrap(), (0, 0, 0, 0x1000, 0));
+        assert!(matches!(
+            dbus.device_state("rng1"),
+            Err(fdo::Error::UnknownObject(_))
+        ));
+        assert!(matches!(
+            dbus.plug("rng0"),
+            Err(fdo::Error::NotSupported(_))
+        ));
+        assert!(matches!(
+            dbus.pause("rng0", "offline"),
+            Err(fdo::Error::InvalidArgs(_))
+        ));
+        assert!(matches!(
+            dbus.pause("rng0", "stall"),
+            Err(fdo::Error::NotSupported(_))
+        ));
+
+        assert_eq!(dbus.coalescing("rng0").unwrap(), (0, 0));
+        dbus.set_coalescing("rng0", 16, 100).unwrap();
+        assert_eq!(dbus.coalescing("rng0").unwrap(), (16, 100));
+        dbus.set_coalescing("rng0", 0, 0).unwrap();
+        assert_eq!(dbus.coalescing("rng0").unwrap(), (0, 0));
+
+        assert_eq!(dbus.access_trace("rng0").unwrap(), (false, vec![]));
+        dbus.set_access_trace("rng0", true, vec![(0x10, 0x28)])
+            .unwrap();
+        assert_eq!(
+            dbus.access_trace("rng0").unwrap(),
+            (true, vec![(0x10, 0x28)])
+        );
+
+        assert!(dbus.units().is_empty());
+        dbus.management.set_units(vec![UnitStatus {
+            name: "frontend0".to_string(),
+            pid: None,
+            state: UnitState::Restarting,
+            restarts: 2,
+            exit_code: Some(-9),
+        }]);
+        assert_eq!(
+            dbus.units(),
+            vec![("frontend0".to_string(), "restarting".to_string(), 0, 2, -9)]
+        );
+
+        // The device runs without a snapshottable model
+        assert!(matches!(
+            dbus.snapshot("/tmp/bao-dbus-snapshot"),
+            Err(fdo::Error::NotSupported(_))
+        ));
+    }
+}
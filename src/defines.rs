@@ -7,6 +7,9 @@
 
 #![allow(dead_code)]
 
+#[cfg(feature = "std")]
+use super::virtio_ids::DeviceType;
+#[cfg(feature = "std")]
 use lazy_static::lazy_static;
 
 /// Bao I/O Write Operation
@@ -18,6 +21,9 @@ pub const BAO_IO_ASK: u64 = 0x2;
 /// Bao I/O Notify Operation
 pub const BAO_IO_NOTIFY: u64 = 0x3;
 
+/// Bao I/O Dispatcher Device Path
+pub const BAO_IO_DISPATCHER_PATH: &str = "/dev/bao-io-dispatcher";
+
 /// Bao Maximum Name Length
 pub const BAO_NAME_LEN: usize = 16;
 
@@ -27,6 +33,12 @@ pub const BAO_IO_REQUEST_MAX: usize = 16;
 /// Bao IOCTL Type
 pub const BAO_IOCTL_TYPE: u32 = 0xA6;
 
+/// Bao Kernel ABI Versions (I/O dispatcher ioctls and request layout) Supported
+pub const BAO_KERNEL_ABI_VERSIONS: &[u32] = &[1];
+
+/// Bao Configuration Schema Versions Supported
+pub const BAO_CONFIG_SCHEMA_VERSIONS: &[u32] = &[1];
+
 /// Bao I/O Event File Descriptor Data Match Flag
 pub const BAO_IOEVENTFD_FLAG_DATAMATCH: u32 = 1 << 1;
 /// Bao I/O Event File Descriptor Deassign Flag
@@ -36,11 +48,614 @@ pub const BAO_IRQFD_FLAG_ASSIGN: u32 = 0x00;
 /// Bao IRQ File Descriptor Deassign Flag
 pub const BAO_IRQFD_FLAG_DEASSIGN: u32 = 0x01;
 
+/// Bao Default Backend Restart Delay (in milliseconds)
+pub const BAO_RESTART_DELAY_MS: u64 = 100;
+/// Bao Default Backend Maximum Restart Delay (in milliseconds)
+pub const BAO_RESTART_MAX_DELAY_MS: u64 = 30000;
+
+/// Bao Default Busy-Poll Budget (in microseconds)
+pub const BAO_POLL_BUDGET_US: u64 = 50;
+
+/// Bao Default Number of Devices Initialized Concurrently
+pub const BAO_INIT_CONCURRENCY: usize = 8;
+
+/// Bao Isolated Backend Channel File Descriptor
+pub const BAO_ISOLATION_CHANNEL_FD: i32 = 3;
+/// Bao Isolated Backend Channel Environment Variable
+pub const BAO_ISOLATION_CHANNEL_ENV: &str = "BAO_ISOLATION_CHANNEL_FD";
+
+/// Bao Spawned Backend Listening Socket File Descriptor (SD_LISTEN_FDS_START)
+pub const BAO_SPAWN_LISTEN_FD: i32 = 3;
+/// Bao Default Spawned Backend Readiness Timeout (in milliseconds)
+pub const BAO_SPAWN_READY_TIMEOUT_MS: u64 = 5000;
+
+/// Bao Default Backend Connection Timeout (in milliseconds)
+pub const BAO_CONNECT_TIMEOUT_MS: u64 = 30000;
+/// Bao Backend Connection Retry Interval (in milliseconds)
+pub const BAO_CONNECT_RETRY_MS: u64 = 50;
+
+/// Bao Secret Reference Scheme
+pub const BAO_SECRET_SCHEME: &str = "secret://";
+/// Systemd Credentials Directory Environment Variable
+pub const BAO_CREDENTIALS_DIRECTORY_ENV: &str = "CREDENTIALS_DIRECTORY";
+
+/// Bao Audit Log Magic
+pub const BAO_AUDIT_MAGIC: &[u8; 8] = b"BAOAUDIT";
+/// Bao Audit Log Format Version
+pub const BAO_AUDIT_VERSION: u32 = 1;
+/// Bao Audit Log Record Size
+pub const BAO_AUDIT_RECORD_SIZE: usize = 64;
+/// Bao Audit Log Default Maximum File Size
+pub const BAO_AUDIT_MAX_SIZE: u64 = 16 * 1024 * 1024;
+/// Bao Audit Log Default Maximum Number of Files
+pub const BAO_AUDIT_MAX_FILES: u32 = 4;
+
+/// Bao Supervisor Polling Interval (in milliseconds)
+pub const BAO_SUPERVISE_INTERVAL_MS: u64 = 100;
+
+/// Bao Stats File Default Write Interval (in milliseconds)
+pub const BAO_STATS_FILE_INTERVAL_MS: u64 = 1000;
+
+/// Bao Control Socket Maximum Command Line Length (in bytes)
+pub const BAO_CONTROL_MAX_LINE: usize = 4096;
+/// Bao Control Socket Maximum Number of Concurrent Connections
+pub const BAO_CONTROL_MAX_CONNECTIONS: usize = 16;
+
+/// Bao Live Update Channel File Descriptor
+pub const BAO_HANDOFF_CHANNEL_FD: i32 = 3;
+/// Bao Live Update Channel Environment Variable
+pub const BAO_HANDOFF_CHANNEL_ENV: &str = "BAO_HANDOFF_CHANNEL_FD";
+/// Bao Live Update Protocol Version
+pub const BAO_HANDOFF_VERSION: u32 = 1;
+/// Bao Live Update Maximum File Descriptors per Message
+pub const BAO_HANDOFF_MAX_FDS: usize = 64;
+/// Bao Live Update Maximum Manifest Size
+pub const BAO_HANDOFF_MAX_MANIFEST: usize = 16 * 1024 * 1024;
+
+/// Bao Device Tree Overlay Directory (configfs)
+pub const BAO_DT_OVERLAY_DIR: &str = "/sys/kernel/config/device-tree/overlays";
+
+/// Bao Host CPUs Sysfs Directory (present, online, isolated and nohz_full CPU lists)
+pub const BAO_SYSFS_CPU_DIR: &str = "/sys/devices/system/cpu";
+/// Bao Device Tree Overlay Name Prefix
+pub const BAO_DT_OVERLAY_PREFIX: &str = "bao-";
+/// Bao Device Tree Default Address Cells
+pub const BAO_DT_ADDRESS_CELLS: u32 = 2;
+/// Bao Device Tree Default Size Cells
+pub const BAO_DT_SIZE_CELLS: u32 = 2;
+
+/// Bao Snapshot Format Version
+pub const BAO_SNAPSHOT_VERSION: u32 = 1;
+
+/// Bao Snapshot File Name (inside the snapshot directory)
+pub const BAO_SNAPSHOT_FILE: &str = "snapshot.yaml";
+
+/// Bao Simulation Queue Size
+pub const BAO_SIMULATE_QUEUE_SIZE: u16 = 256;
+/// Bao Simulation Maximum Guest RAM Size
+pub const BAO_SIMULATE_RAM_SIZE: u64 = 16 * 1024 * 1024;
+/// Bao Simulation Default Number of Traffic Rounds
+pub const BAO_SIMULATE_ROUNDS: u16 = 16;
+/// Bao Loopback Device Type
+pub const BAO_LOOPBACK_DEVICE_TYPE: &str = "loopback";
+/// Bao Loopback Virtio Device ID (outside the IDs assigned by the virtio specification)
+pub const BAO_LOOPBACK_DEVICE_ID: u32 = 0xffff;
+/// Bao Self-Test Default Number of Rounds
+pub const BAO_SELF_TEST_ROUNDS: u16 = 64;
+/// Bao Self-Test Maximum Number of Buffers per Round
+pub const BAO_SELF_TEST_DEPTH: u16 = 8;
+/// Bao Self-Test Maximum Payload Size
+pub const BAO_SELF_TEST_MAX_PAYLOAD: usize = 1024;
+
+/// Bao In-Flight Limiter Maximum Number of Queues per Device
+pub const BAO_INFLIGHT_MAX_QUEUES: usize = 64;
+/// Bao In-Flight Limiter Replayed Notification vCPU ID (no vCPU waits for its completion)
+pub const BAO_INFLIGHT_REPLAY_VCPU: u64 = u64::MAX;
+/// Bao Fair Scheduler Default Device Weight
+pub const BAO_FAIR_DEFAULT_WEIGHT: u32 = 1;
+
+/// Bao Request Recording Magic
+pub const BAO_RECORD_MAGIC: &[u8; 8] = b"BAORECRD";
+/// Bao Request Recording Format Version
+pub const BAO_RECORD_VERSION: u32 = 1;
+/// Bao Request Recording Entry Size (timestamp, kind and encoded request)
+pub const BAO_RECORD_ENTRY_SIZE: usize = 88;
+
 /// VirtIO MMIO I/O Size
 pub const VIRTIO_MMIO_IO_SIZE: u64 = 0x200;
 
+/// VirtIO MMIO Magic Value ("virt")
+pub const VIRTIO_MMIO_MAGIC: u64 = 0x7472_6976;
+/// VirtIO MMIO Version (modern)
+pub const VIRTIO_MMIO_VERSION_2: u64 = 2;
+
+/// VirtIO MMIO Magic Value Register
+pub const VIRTIO_MMIO_MAGIC_VALUE: u64 = 0x000;
+/// VirtIO MMIO Version Register
+pub const VIRTIO_MMIO_VERSION: u64 = 0x004;
+/// VirtIO MMIO Device ID Register
+pub const VIRTIO_MMIO_DEVICE_ID: u64 = 0x008;
+/// VirtIO MMIO Vendor ID Register
+pub const VIRTIO_MMIO_VENDOR_ID: u64 = 0x00c;
+/// VirtIO MMIO Device Features Register
+pub const VIRTIO_MMIO_DEVICE_FEATURES: u64 = 0x010;
+/// VirtIO MMIO Device Features Selection Register
+pub const VIRTIO_MMIO_DEVICE_FEATURES_SEL: u64 = 0x014;
+/// VirtIO MMIO Driver Features Register
+pub const VIRTIO_MMIO_DRIVER_FEATURES: u64 = 0x020;
+/// VirtIO MMIO Driver Features Selection Register
+pub const VIRTIO_MMIO_DRIVER_FEATURES_SEL: u64 = 0x024;
+/// VirtIO MMIO Queue Selection Register
+pub const VIRTIO_MMIO_QUEUE_SEL: u64 = 0x030;
+/// VirtIO MMIO Queue Maximum Size Register
+pub const VIRTIO_MMIO_QUEUE_NUM_MAX: u64 = 0x034;
+/// VirtIO MMIO Queue Size Register
+pub const VIRTIO_MMIO_QUEUE_NUM: u64 = 0x038;
+/// VirtIO MMIO Queue Ready Register
+pub const VIRTIO_MMIO_QUEUE_READY: u64 = 0x044;
+/// VirtIO MMIO Queue Notify Register
+pub const VIRTIO_MMIO_QUEUE_NOTIFY: u64 = 0x050;
+/// VirtIO MMIO Interrupt Status Register
+pub const VIRTIO_MMIO_INTERRUPT_STATUS: u64 = 0x060;
+/// VirtIO MMIO Used Buffer Notification Interrupt Bit
+pub const VIRTIO_MMIO_INT_VRING: u64 = 1;
+/// VirtIO MMIO Configuration Change Notification Interrupt Bit
+pub const VIRTIO_MMIO_INT_CONFIG: u64 = 2;
+/// VirtIO MMIO Interrupt Acknowledge Register
+pub const VIRTIO_MMIO_INTERRUPT_ACK: u64 = 0x064;
+/// VirtIO MMIO Device Status Register
+pub const VIRTIO_MMIO_STATUS: u64 = 0x070;
+/// VirtIO MMIO Queue Descriptor Table Address Register (low)
+pub const VIRTIO_MMIO_QUEUE_DESC_LOW: u64 = 0x080;
+/// VirtIO MMIO Queue Descriptor Table Address Register (high)
+pub const VIRTIO_MMIO_QUEUE_DESC_HIGH: u64 = 0x084;
+/// VirtIO MMIO Queue Available Ring Address Register (low)
+pub const VIRTIO_MMIO_QUEUE_AVAIL_LOW: u64 = 0x090;
+/// VirtIO MMIO Queue Available Ring Address Register (high)
+pub const VIRTIO_MMIO_QUEUE_AVAIL_HIGH: u64 = 0x094;
+/// VirtIO MMIO Queue Used Ring Address Register (low)
+pub const VIRTIO_MMIO_QUEUE_USED_LOW: u64 = 0x0a0;
+/// VirtIO MMIO Queue Used Ring Address Register (high)
+pub const VIRTIO_MMIO_QUEUE_USED_HIGH: u64 = 0x0a4;
+/// VirtIO MMIO Configuration Generation Register
+pub const VIRTIO_MMIO_CONFIG_GENERATION: u64 = 0x0fc;
+/// VirtIO MMIO Device Configuration Space
+pub const VIRTIO_MMIO_CONFIG: u64 = 0x100;
+
+/// VirtIO Device Status Acknowledge Bit
+pub const VIRTIO_CONFIG_S_ACKNOWLEDGE: u64 = 1;
+/// VirtIO Device Status Driver Bit
+pub const VIRTIO_CONFIG_S_DRIVER: u64 = 2;
+/// VirtIO Device Status Driver OK Bit
+pub const VIRTIO_CONFIG_S_DRIVER_OK: u64 = 4;
+/// VirtIO Device Status Features OK Bit
+pub const VIRTIO_CONFIG_S_FEATURES_OK: u64 = 8;
+/// VirtIO Device Status Needs Reset Bit
+pub const VIRTIO_CONFIG_S_NEEDS_RESET: u64 = 0x40;
+/// VirtIO Device Status Failed Bit
+pub const VIRTIO_CONFIG_S_FAILED: u64 = 0x80;
+
+/// VirtIO Version 1 Feature Bit
+pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+/// VirtIO Access Platform Feature Bit (device addresses are translated by the platform)
+pub const VIRTIO_F_ACCESS_PLATFORM: u64 = 1 << 33;
+
+/// VirtIO Descriptor Next Flag
+pub const VRING_DESC_F_NEXT: u16 = 1;
+/// VirtIO Descriptor Write Flag (device writable)
+pub const VRING_DESC_F_WRITE: u16 = 2;
+/// VirtIO Maximum Virtqueue Size
+pub const VIRTQUEUE_MAX_SIZE: u16 = 32768;
+
+/// Vhost-user Get Features Request
+pub const VHOST_USER_GET_FEATURES: u32 = 1;
+/// Vhost-user Set Features Request
+pub const VHOST_USER_SET_FEATURES: u32 = 2;
+/// Vhost-user Set Owner Request
+pub const VHOST_USER_SET_OWNER: u32 = 3;
+/// Vhost-user Reset Owner Request
+pub const VHOST_USER_RESET_OWNER: u32 = 4;
+/// Vhost-user Set Memory Table Request
+pub const VHOST_USER_SET_MEM_TABLE: u32 = 5;
+/// Vhost-user Set Vring Num Request
+pub const VHOST_USER_SET_VRING_NUM: u32 = 8;
+/// Vhost-user Set Vring Address Request
+pub const VHOST_USER_SET_VRING_ADDR: u32 = 9;
+/// Vhost-user Set Vring Base Request
+pub const VHOST_USER_SET_VRING_BASE: u32 = 10;
+/// Vhost-user Get Vring Base Request
+pub const VHOST_USER_GET_VRING_BASE: u32 = 11;
+/// Vhost-user Set Vring Kick Request
+pub const VHOST_USER_SET_VRING_KICK: u32 = 12;
+/// Vhost-user Set Vring Call Request
+pub const VHOST_USER_SET_VRING_CALL: u32 = 13;
+/// Vhost-user Get Protocol Features Request
+pub const VHOST_USER_GET_PROTOCOL_FEATURES: u32 = 15;
+/// Vhost-user Set Protocol Features Request
+pub const VHOST_USER_SET_PROTOCOL_FEATURES: u32 = 16;
+/// Vhost-user Get Queue Num Request
+pub const VHOST_USER_GET_QUEUE_NUM: u32 = 17;
+/// Vhost-user Set Vring Enable Request
+pub const VHOST_USER_SET_VRING_ENABLE: u32 = 18;
+/// Vhost-user Set Backend Request Channel Request
+pub const VHOST_USER_SET_BACKEND_REQ_FD: u32 = 21;
+/// Vhost-user IOTLB Message Request
+pub const VHOST_USER_IOTLB_MSG: u32 = 22;
+/// Vhost-user Get Config Request
+pub const VHOST_USER_GET_CONFIG: u32 = 24;
+/// Vhost-user Get Config Request Header Size (offset, size and flags)
+pub const VHOST_USER_CONFIG_HEADER_SIZE: usize = 12;
+/// Vhost-user Maximum Device Configuration Space Size
+pub const VHOST_USER_MAX_CONFIG_SIZE: usize = 256;
+/// Vhost-user Backend IOTLB Message Request (on the backend request channel)
+pub const VHOST_USER_BACKEND_IOTLB_MSG: u32 = 1;
+/// Vhost-user Backend Config Change Message Request (on the backend request channel)
+pub const VHOST_USER_BACKEND_CONFIG_CHANGE_MSG: u32 = 2;
+/// Vhost-user Message Header Size
+pub const VHOST_USER_HEADER_SIZE: usize = 12;
+/// Vhost-user Protocol Version Flag
+pub const VHOST_USER_VERSION: u32 = 0x1;
+/// Vhost-user Reply Flag
+pub const VHOST_USER_REPLY_MASK: u32 = 0x4;
+/// Vhost-user Need Reply Flag
+pub const VHOST_USER_NEED_REPLY_MASK: u32 = 0x8;
+/// Vhost-user Vring Index Mask
+pub const VHOST_USER_VRING_IDX_MASK: u64 = 0xff;
+/// Vhost-user Vring No File Descriptor Flag
+pub const VHOST_USER_VRING_NOFD_MASK: u64 = 0x100;
+/// Vhost-user Maximum File Descriptors per Message
+pub const VHOST_USER_MAX_FDS: usize = 8;
+/// Bao Deferred Descriptor Chain (left available by the device model until the next kick)
+pub const BAO_CHAIN_DEFERRED: u32 = u32::MAX;
+/// Bao Number of Descriptor Chain Heads Kept per Virtqueue (returned by `queue-dump`)
+pub const BAO_QUEUE_HEADS: usize = 16;
+/// Bao Number of Pending Kicks Held per Virtqueue (kicks past it are coalesced)
+pub const BAO_KICK_RING_SIZE: usize = 64;
+/// Vhost-user Protocol Features Feature Bit
+pub const VHOST_USER_F_PROTOCOL_FEATURES: u64 = 1 << 30;
+/// Vhost-user Multiple Queues Protocol Feature Bit
+pub const VHOST_USER_PROTOCOL_F_MQ: u64 = 1 << 0;
+/// Vhost-user Reply Acknowledge Protocol Feature Bit
+pub const VHOST_USER_PROTOCOL_F_REPLY_ACK: u64 = 1 << 3;
+/// Vhost-user Backend Request Channel Protocol Feature Bit
+pub const VHOST_USER_PROTOCOL_F_BACKEND_REQ: u64 = 1 << 5;
+/// Vhost-user Device Configuration Space Protocol Feature Bit
+pub const VHOST_USER_PROTOCOL_F_CONFIG: u64 = 1 << 9;
+
+/// VirtIO Net MAC Address Feature Bit
+pub const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+/// VirtIO Block Read-Only Feature Bit
+pub const VIRTIO_BLK_F_RO: u64 = 1 << 5;
+/// VirtIO Block Cache Flush Feature Bit
+pub const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+/// VirtIO Block Read Request
+pub const VIRTIO_BLK_T_IN: u32 = 0;
+/// VirtIO Block Write Request
+pub const VIRTIO_BLK_T_OUT: u32 = 1;
+/// VirtIO Block Flush Request
+pub const VIRTIO_BLK_T_FLUSH: u32 = 4;
+/// VirtIO Block Get Device ID Request
+pub const VIRTIO_BLK_T_GET_ID: u32 = 8;
+/// VirtIO Block Success Status
+pub const VIRTIO_BLK_S_OK: u8 = 0;
+/// VirtIO Block I/O Error Status
+pub const VIRTIO_BLK_S_IOERR: u8 = 1;
+/// VirtIO Block Unsupported Request Status
+pub const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+/// VirtIO Block Request Header Size (type, reserved and sector)
+pub const VIRTIO_BLK_HEADER_SIZE: usize = 16;
+/// VirtIO Block Sector Size
+pub const VIRTIO_BLK_SECTOR_SIZE: u64 = 512;
+/// VirtIO Block Device ID Size
+pub const VIRTIO_BLK_ID_BYTES: usize = 20;
+
+/// Bao Built-in Block Backend Name
+pub const BAO_BLOCK_BACKEND: &str = "bao-blk";
+/// Bao Block Backend Buffer Alignment (directsync images)
+pub const BAO_BLOCK_DIRECT_ALIGN: usize = 4096;
+
+/// VirtIO I2C Zero-Length Request Feature Bit
+pub const VIRTIO_I2C_F_ZERO_LENGTH_REQUEST: u64 = 1 << 0;
+/// VirtIO I2C Fail Next Request Flag (the request is followed by another of the transaction)
+pub const VIRTIO_I2C_FLAGS_FAIL_NEXT: u32 = 1 << 0;
+/// VirtIO I2C Read Request Flag
+pub const VIRTIO_I2C_FLAGS_M_RD: u32 = 1 << 1;
+/// VirtIO I2C Success Status
+pub const VIRTIO_I2C_MSG_OK: u8 = 0;
+/// VirtIO I2C Error Status
+pub const VIRTIO_I2C_MSG_ERR: u8 = 1;
+/// VirtIO I2C Request Header Size (address, padding and flags)
+pub const VIRTIO_I2C_OUT_HDR_SIZE: usize = 8;
+/// I2C Highest 7-bit Address
+pub const I2C_ADDR_7BIT_MAX: u16 = 0x7f;
+/// I2C Combined Transfer IOCTL
+pub const I2C_RDWR: u64 = 0x0707;
+/// I2C Read Message Flag
+pub const I2C_M_RD: u16 = 0x1;
+
+/// Bao Built-in I2C Backend Name
+pub const BAO_I2C_BACKEND: &str = "bao-i2c";
+
+/// VirtIO GPIO Request Size (type, line and value)
+pub const VIRTIO_GPIO_REQUEST_SIZE: usize = 8;
+/// VirtIO GPIO Get Line Names Request
+pub const VIRTIO_GPIO_MSG_GET_NAMES: u16 = 0x1;
+/// VirtIO GPIO Get Line Direction Request
+pub const VIRTIO_GPIO_MSG_GET_DIRECTION: u16 = 0x2;
+/// VirtIO GPIO Set Line Direction Request
+pub const VIRTIO_GPIO_MSG_SET_DIRECTION: u16 = 0x3;
+/// VirtIO GPIO Get Line Value Request
+pub const VIRTIO_GPIO_MSG_GET_VALUE: u16 = 0x4;
+/// VirtIO GPIO Set Line Value Request
+pub const VIRTIO_GPIO_MSG_SET_VALUE: u16 = 0x5;
+/// VirtIO GPIO Success Status
+pub const VIRTIO_GPIO_STATUS_OK: u8 = 0;
+/// VirtIO GPIO Error Status
+pub const VIRTIO_GPIO_STATUS_ERR: u8 = 1;
+/// VirtIO GPIO No Direction (line released)
+pub const VIRTIO_GPIO_DIRECTION_NONE: u8 = 0;
+/// VirtIO GPIO Output Direction
+pub const VIRTIO_GPIO_DIRECTION_OUT: u8 = 1;
+/// VirtIO GPIO Input Direction
+pub const VIRTIO_GPIO_DIRECTION_IN: u8 = 2;
+/// GPIO IOCTL Type
+pub const GPIO_IOCTL_TYPE: u32 = 0xb4;
+/// GPIO Line Request IOCTL Number (on the chip)
+pub const GPIO_V2_GET_LINE_NR: u32 = 0x07;
+/// GPIO Line Configuration IOCTL Number (on the line request)
+pub const GPIO_V2_LINE_SET_CONFIG_NR: u32 = 0x0d;
+/// GPIO Line Get Values IOCTL Number (on the line request)
+pub const GPIO_V2_LINE_GET_VALUES_NR: u32 = 0x0e;
+/// GPIO Line Set Values IOCTL Number (on the line request)
+pub const GPIO_V2_LINE_SET_VALUES_NR: u32 = 0x0f;
+/// GPIO Maximum Number of Lines per Request
+pub const GPIO_V2_LINES_MAX: usize = 64;
+/// GPIO Maximum Number of Line Configuration Attributes
+pub const GPIO_V2_LINE_NUM_ATTRS_MAX: usize = 10;
+/// GPIO Maximum Consumer Name Size
+pub const GPIO_MAX_NAME_SIZE: usize = 32;
+/// GPIO Input Line Flag
+pub const GPIO_V2_LINE_FLAG_INPUT: u64 = 1 << 2;
+/// GPIO Output Line Flag
+pub const GPIO_V2_LINE_FLAG_OUTPUT: u64 = 1 << 3;
+/// GPIO Line Flags Attribute
+pub const GPIO_V2_LINE_ATTR_ID_FLAGS: u32 = 1;
+/// GPIO Line Output Values Attribute
+pub const GPIO_V2_LINE_ATTR_ID_OUTPUT_VALUES: u32 = 2;
+
+/// Bao Built-in GPIO Backend Name
+pub const BAO_GPIO_BACKEND: &str = "bao-gpio";
+
+/// VirtIO CAN Classic Frames Feature Bit
+pub const VIRTIO_CAN_F_CAN_CLASSIC: u64 = 1 << 0;
+/// VirtIO CAN FD Frames Feature Bit
+pub const VIRTIO_CAN_F_CAN_FD: u64 = 1 << 1;
+/// VirtIO CAN Remote Transmission Request Frames Feature Bit
+pub const VIRTIO_CAN_F_RTR_FRAMES: u64 = 1 << 2;
+/// VirtIO CAN Transmit Message
+pub const VIRTIO_CAN_TX: u16 = 0x0001;
+/// VirtIO CAN Receive Message
+pub const VIRTIO_CAN_RX: u16 = 0x0101;
+/// VirtIO CAN Start Controller Message
+pub const VIRTIO_CAN_SET_CTRL_MODE_START: u16 = 0x0201;
+/// VirtIO CAN Stop Controller Message
+pub const VIRTIO_CAN_SET_CTRL_MODE_STOP: u16 = 0x0202;
+/// VirtIO CAN Success Result
+pub const VIRTIO_CAN_RESULT_OK: u8 = 0;
+/// VirtIO CAN Failure Result
+pub const VIRTIO_CAN_RESULT_NOT_OK: u8 = 1;
+/// VirtIO CAN Extended Identifier Flag
+pub const VIRTIO_CAN_FLAGS_EXTENDED: u16 = 0x8000;
+/// VirtIO CAN FD Frame Flag
+pub const VIRTIO_CAN_FLAGS_FD: u16 = 0x4000;
+/// VirtIO CAN Remote Transmission Request Flag
+pub const VIRTIO_CAN_FLAGS_RTR: u16 = 0x2000;
+/// VirtIO CAN Bus-Off Status Bit
+pub const VIRTIO_CAN_S_CTRL_BUSOFF: u16 = 1 << 0;
+/// VirtIO CAN Message Header Size (type, reserved, flags and identifier)
+pub const VIRTIO_CAN_HEADER_SIZE: usize = 12;
+/// CAN Highest Standard Identifier
+pub const CAN_SFF_MASK: u32 = 0x7ff;
+/// CAN Highest Extended Identifier
+pub const CAN_EFF_MASK: u32 = 0x1fff_ffff;
+/// CAN Classic Frame Maximum Data Length
+pub const CAN_MAX_DLEN: usize = 8;
+/// CAN FD Frame Maximum Data Length
+pub const CANFD_MAX_DLEN: usize = 64;
+/// SocketCAN Classic Frame Size
+pub const CAN_MTU: usize = 16;
+/// SocketCAN FD Frame Size
+pub const CANFD_MTU: usize = 72;
+/// SocketCAN Extended Identifier Flag
+pub const CAN_EFF_FLAG: u32 = 0x8000_0000;
+/// SocketCAN Remote Transmission Request Flag
+pub const CAN_RTR_FLAG: u32 = 0x4000_0000;
+/// SocketCAN Error Frame Flag
+pub const CAN_ERR_FLAG: u32 = 0x2000_0000;
+/// SocketCAN FD Frame Flag (of FD frames)
+pub const CANFD_FDF: u8 = 0x04;
+/// SocketCAN Raw Protocol
+pub const CAN_RAW: i32 = 1;
+/// SocketCAN Raw Socket Option Level
+pub const SOL_CAN_RAW: i32 = 101;
+/// SocketCAN Error Frame Filter Socket Option
+pub const CAN_RAW_ERR_FILTER: i32 = 2;
+/// SocketCAN FD Frames Socket Option
+pub const CAN_RAW_FD_FRAMES: i32 = 5;
+/// SocketCAN Controller Problem Error Class
+pub const CAN_ERR_CRTL: u32 = 0x0000_0004;
+/// SocketCAN Bus-Off Error Class
+pub const CAN_ERR_BUSOFF: u32 = 0x0000_0040;
+/// SocketCAN Controller Restarted Error Class
+pub const CAN_ERR_RESTARTED: u32 = 0x0000_0100;
+/// SocketCAN Controller Problem: Receive Error-Passive
+pub const CAN_ERR_CRTL_RX_PASSIVE: u8 = 0x10;
+/// SocketCAN Controller Problem: Transmit Error-Passive
+pub const CAN_ERR_CRTL_TX_PASSIVE: u8 = 0x20;
+/// SocketCAN Controller Problem: Back to Error-Active
+pub const CAN_ERR_CRTL_ACTIVE: u8 = 0x40;
+
+/// Bao Built-in CAN Backend Name
+pub const BAO_CAN_BACKEND: &str = "bao-can";
+/// Bao CAN Backend Maximum Number of Received Frames Waiting for the Guest
+pub const BAO_CAN_RX_QUEUE_MAX: usize = 256;
+
+/// Bao Built-in Console Backend Name
+pub const BAO_CONSOLE_BACKEND: &str = "bao-console";
+/// Bao Console Backend Default Output Log Size (bytes)
+pub const BAO_CONSOLE_LOG_SIZE: usize = 64 * 1024;
+
+/// VirtIO Sound Control Queue
+pub const VIRTIO_SND_VQ_CONTROL: usize = 0;
+/// VirtIO Sound Event Queue
+pub const VIRTIO_SND_VQ_EVENT: usize = 1;
+/// VirtIO Sound Transmit Queue
+pub const VIRTIO_SND_VQ_TX: usize = 2;
+/// VirtIO Sound Receive Queue
+pub const VIRTIO_SND_VQ_RX: usize = 3;
+/// VirtIO Sound Jack Information Request
+pub const VIRTIO_SND_R_JACK_INFO: u32 = 0x0001;
+/// VirtIO Sound PCM Stream Information Request
+pub const VIRTIO_SND_R_PCM_INFO: u32 = 0x0100;
+/// VirtIO Sound PCM Stream Parameters Request
+pub const VIRTIO_SND_R_PCM_SET_PARAMS: u32 = 0x0101;
+/// VirtIO Sound PCM Stream Prepare Request
+pub const VIRTIO_SND_R_PCM_PREPARE: u32 = 0x0102;
+/// VirtIO Sound PCM Stream Release Request
+pub const VIRTIO_SND_R_PCM_RELEASE: u32 = 0x0103;
+/// VirtIO Sound PCM Stream Start Request
+pub const VIRTIO_SND_R_PCM_START: u32 = 0x0104;
+/// VirtIO Sound PCM Stream Stop Request
+pub const VIRTIO_SND_R_PCM_STOP: u32 = 0x0105;
+/// VirtIO Sound Channel Map Information Request
+pub const VIRTIO_SND_R_CHMAP_INFO: u32 = 0x0200;
+/// VirtIO Sound Success Status
+pub const VIRTIO_SND_S_OK: u32 = 0x8000;
+/// VirtIO Sound Malformed Request Status
+pub const VIRTIO_SND_S_BAD_MSG: u32 = 0x8001;
+/// VirtIO Sound Unsupported Request Status
+pub const VIRTIO_SND_S_NOT_SUPP: u32 = 0x8002;
+/// VirtIO Sound I/O Error Status
+pub const VIRTIO_SND_S_IO_ERR: u32 = 0x8003;
+/// VirtIO Sound Output Stream Direction
+pub const VIRTIO_SND_D_OUTPUT: u8 = 0;
+/// VirtIO Sound Signed 16-bit PCM Format
+pub const VIRTIO_SND_PCM_FMT_S16: u8 = 5;
+/// VirtIO Sound 44100 Hz PCM Rate
+pub const VIRTIO_SND_PCM_RATE_44100: u8 = 6;
+/// VirtIO Sound 48000 Hz PCM Rate
+pub const VIRTIO_SND_PCM_RATE_48000: u8 = 7;
+/// VirtIO Sound Query Information Request Size (header, start ID, count and size)
+pub const VIRTIO_SND_QUERY_INFO_SIZE: usize = 16;
+/// VirtIO Sound PCM Stream Information Size
+pub const VIRTIO_SND_PCM_INFO_SIZE: usize = 32;
+/// VirtIO Sound PCM Request Header Size (header and stream ID)
+pub const VIRTIO_SND_PCM_HDR_SIZE: usize = 8;
+/// VirtIO Sound PCM Stream Parameters Request Size
+pub const VIRTIO_SND_PCM_SET_PARAMS_SIZE: usize = 24;
+/// VirtIO Sound PCM Transfer Status Size (status and latency)
+pub const VIRTIO_SND_PCM_STATUS_SIZE: usize = 8;
+
+/// Bao Built-in Sound Backend Name
+pub const BAO_SOUND_BACKEND: &str = "bao-snd";
+/// Bao Sound Backend Maximum Number of Streams
+pub const BAO_SOUND_STREAMS_MAX: usize = 16;
+
+/// Vhost IOTLB Message Size
+pub const VHOST_IOTLB_MSG_SIZE: usize = 32;
+/// Vhost IOTLB Miss Message Type
+pub const VHOST_IOTLB_MISS: u8 = 1;
+/// Vhost IOTLB Update Message Type
+pub const VHOST_IOTLB_UPDATE: u8 = 2;
+/// Vhost IOTLB Invalidate Message Type
+pub const VHOST_IOTLB_INVALIDATE: u8 = 3;
+/// Vhost IOTLB Access Fail Message Type
+pub const VHOST_IOTLB_ACCESS_FAIL: u8 = 4;
+/// Vhost IOTLB Read Access Permission
+pub const VHOST_ACCESS_RO: u8 = 0x1;
+/// Vhost IOTLB Write Access Permission
+pub const VHOST_ACCESS_WO: u8 = 0x2;
+/// Vhost IOTLB Read-Write Access Permission
+pub const VHOST_ACCESS_RW: u8 = 0x3;
+
+/// TUN/TAP Clone Device Path
+pub const TUN_DEVICE_PATH: &str = "/dev/net/tun";
+/// TUN/TAP IOCTL Type
+pub const TUN_IOCTL_TYPE: u32 = b'T' as u32;
+/// TUN/TAP Set Interface IOCTL Number
+pub const TUN_SETIFF_NR: u32 = 202;
+/// TUN/TAP Set Queue IOCTL Number
+pub const TUN_SETQUEUE_NR: u32 = 217;
+/// TUN/TAP Ethernet (TAP) Device Flag
+pub const TUN_IFF_TAP: u16 = 0x0002;
+/// TUN/TAP Multi-Queue Device Flag
+pub const TUN_IFF_MULTI_QUEUE: u16 = 0x0100;
+/// TUN/TAP Detach Queue Flag
+pub const TUN_IFF_DETACH_QUEUE: u16 = 0x0400;
+/// TUN/TAP No Packet Information Flag
+pub const TUN_IFF_NO_PI: u16 = 0x1000;
+/// TUN/TAP Virtio-Net Header Flag
+pub const TUN_IFF_VNET_HDR: u16 = 0x4000;
+/// Network Interface Name Size (including the NUL terminator)
+pub const IFNAMSIZ: usize = 16;
+/// Network Interface Request Size (`struct ifreq`, name and union)
+pub const IFREQ_SIZE: usize = 40;
+/// Netlink Set Link Request (`RTM_NEWLINK` on an existing interface)
+pub const RTM_NEWLINK: u16 = 16;
+/// Netlink Error (and Acknowledgement) Message Type
+pub const NLMSG_ERROR: u16 = 2;
+/// Netlink Request Flag
+pub const NLM_F_REQUEST: u16 = 0x1;
+/// Netlink Acknowledgement Request Flag
+pub const NLM_F_ACK: u16 = 0x4;
+/// Netlink Message Header Size
+pub const NLMSG_HEADER_SIZE: usize = 16;
+/// Netlink Interface Info Message Size (`struct ifinfomsg`)
+pub const IFINFOMSG_SIZE: usize = 16;
+/// Netlink Link Hardware Address Attribute
+pub const IFLA_ADDRESS: u16 = 1;
+/// Netlink Link MTU Attribute
+pub const IFLA_MTU: u16 = 4;
+/// Netlink Link Master Attribute
+pub const IFLA_MASTER: u16 = 10;
+/// Network Interface Up Flag
+pub const IFF_UP: u32 = 0x1;
+
+/// Bao Packet Capture Default Snapshot Length (bytes kept of every frame)
+pub const BAO_CAPTURE_SNAPLEN: u32 = 65535;
+/// Bao Packet Capture Default Maximum File Size
+pub const BAO_CAPTURE_MAX_BYTES: u64 = 64 << 20;
+/// Bao Packet Capture Polling Interval (in milliseconds)
+pub const BAO_CAPTURE_POLL_MS: u64 = 100;
+/// pcapng Section Header Block Type
+pub const PCAPNG_SECTION_HEADER: u32 = 0x0a0d_0d0a;
+/// pcapng Interface Description Block Type
+pub const PCAPNG_INTERFACE_DESCRIPTION: u32 = 0x1;
+/// pcapng Enhanced Packet Block Type
+pub const PCAPNG_ENHANCED_PACKET: u32 = 0x6;
+/// pcapng Byte-Order Magic
+pub const PCAPNG_BYTE_ORDER_MAGIC: u32 = 0x1a2b_3c4d;
+/// pcapng Interface Name Option
+pub const PCAPNG_IF_NAME: u16 = 2;
+/// pcapng Enhanced Packet Flags Option
+pub const PCAPNG_EPB_FLAGS: u16 = 2;
+/// pcapng Enhanced Packet Inbound Direction Flag
+pub const PCAPNG_EPB_INBOUND: u32 = 0x1;
+/// pcapng Enhanced Packet Outbound Direction Flag
+pub const PCAPNG_EPB_OUTBOUND: u32 = 0x2;
+/// pcap Ethernet Link Type
+pub const LINKTYPE_ETHERNET: u16 = 1;
+
+#[cfg(feature = "std")]
 lazy_static! {
     /// List of current supported devices.
-    pub static ref SUPPORTED_DEVICES: Vec<(&'static str, u32)> =
-        vec![("rng", 4), ("i2c", 22), ("fs", 26), ("gpio", 29)];
+    pub static ref SUPPORTED_DEVICES: Vec<(&'static str, u32)> = [
+        DeviceType::Rng,
+        DeviceType::I2c,
+        DeviceType::Fs,
+        DeviceType::Gpio
+    ]
+    .into_iter()
+    .map(|t| (t.name(), t.to_virtio_id()))
+    .collect();
 }
@@ -0,0 +1,667 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao configuration diagnostics.
+//!
+//! A configuration is checked as a whole before the frontend starts, so every problem is
+//! reported at once, tagged with the device it is about (`frontend0/guest1/rng0: irq 0x2f
+//! collides with frontend0/guest0/rng0`). Humans get the diagnostics grouped per device
+//! (colorized on a terminal); tooling requests `--output json` for the same diagnostics
+//! as a JSON document.
+
+#![allow(dead_code)]
+
+use super::defines::{VIRTIO_MMIO_IO_SIZE, VIRTQUEUE_MAX_SIZE};
+use super::error::{Error, ErrorContext};
+use super::sched::HostCpus;
+use super::stats_file::json_string;
+use super::types::{ConfigDevice, ConfigFrontends};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Enum representing the severity of a diagnostic.
+///
+/// # Variants
+///
+/// * `Error` - The configuration is rejected.
+/// * `Warning` - The configuration is accepted, but likely not what was meant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        write!(f, "{}", severity)
+    }
+}
+
+/// Struct representing a diagnostic.
+///
+/// # Attributes
+///
+/// * `severity` - Severity.
+/// * `location` - What the diagnostic is about (a device, or a position in the
+///   configuration file), if anything in particular.
+/// * `message` - Description of the problem.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub location: Option<String>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// Creates an error diagnostic.
+    pub fn error(location: Option<String>, message: String) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            location,
+            message,
+        }
+    }
+
+    /// Creates a warning diagnostic.
+    pub fn warning(location: Option<String>, message: String) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            location,
+            message,
+        }
+    }
+
+    /// Describes an error as diagnostics.
+    ///
+    /// # Arguments
+    ///
+    /// * `error` - The error.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<Diagnostic>` - The diagnostics of an `InvalidConfig` error, or a single
+    ///   error diagnostic (located at the device the error is tagged with, if any).
+    pub fn from_error(error: &Error) -> Vec<Self> {
+        match error {
+            Error::InvalidConfig(e) => match e.downcast_ref::<ConfigDiagnostics>() {
+                Some(diagnostics) => diagnostics.0.clone(),
+                None => vec![Diagnostic::error(None, error.report())],
+            },
+            Error::ConfigIo(path, e) => vec![Diagnostic::error(Some(path.clone()), e.to_string())],
+            Error::ConfigParse {
+                path,
+                line,
+                column,
+                message,
+            } => {
+                let location = match (line, column) {
+                    (0, _) => path.clone(),
+                    _ => format!("{}:{}:{}", path, line, column),
+                };
+                vec![Diagnostic::error(Some(location), message.clone())]
+            }
+            Error::Device { context, source } => {
+                vec![Diagnostic::error(
+                    Some(context.to_string()),
+                    source.report(),
+                )]
+            }
+            _ => vec![Diagnostic::error(None, error.report())],
+        }
+    }
+
+    /// Encodes the diagnostic as a JSON object.
+    fn to_json(&self) -> String {
+        let location = match &self.location {
+            Some(location) => json_string(location),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"severity\":{},\"location\":{},\"message\":{}}}",
+            json_string(&self.severity.to_string()),
+            location,
+            json_string(&self.message)
+        )
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "{}: {}", location, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Struct representing the diagnostics of an invalid configuration (carried by
+/// `Error::InvalidConfig`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigDiagnostics(pub Vec<Diagnostic>);
+
+impl fmt::Display for ConfigDiagnostics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let diagnostics = self.0.iter().map(|d| d.to_string()).collect::<Vec<_>>();
+        write!(f, "{}", diagnostics.join("; "))
+    }
+}
+
+impl std::error::Error for ConfigDiagnostics {}
+
+impl From<ConfigDiagnostics> for Error {
+    fn from(diagnostics: ConfigDiagnostics) -> Self {
+        Error::InvalidConfig(Box::new(diagnostics))
+    }
+}
+
+/// Enum representing the format of the diagnostics.
+///
+/// # Variants
+///
+/// * `Human` - Grouped per location, colorized on a terminal.
+/// * `Json` - A JSON document (`{"diagnostics": [...]}`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutputFormat {
+    #[default]
+    Human,
+    Json,
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let format = match self {
+            OutputFormat::Human => "human",
+            OutputFormat::Json => "json",
+        };
+        write!(f, "{}", format)
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(format: &str) -> Result<Self, Error> {
+        match format {
+            "human" => Ok(OutputFormat::Human),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(Error::InvalidOutputFormat(format.to_string())),
+        }
+    }
+}
+
+impl OutputFormat {
+    /// Finds the requested format in raw command line arguments.
+    ///
+    /// Used to report the failures of the command line parsing itself in the requested
+    /// format.
+    ///
+    /// # Arguments
+    ///
+    /// * `args` - The command line arguments.
+    ///
+    /// # Returns
+    ///
+    /// * `OutputFormat` - The format given with `--output` (human if none or invalid).
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Self {
+        let mut args = args.into_iter();
+        let mut format = None;
+        while let Some(arg) = args.next() {
+            if arg == "--output" {
+                format = args.next();
+            } else if let Some(value) = arg.strip_prefix("--output=") {
+                format = Some(value.to_string());
+            }
+        }
+        format.and_then(|f| f.parse().ok()).unwrap_or_default()
+    }
+}
+
+/// Checks whether two MMIO windows overlap.
+fn windows_overlap(a: &ConfigDevice, b: &ConfigDevice) -> bool {
+    a.addr.raw() < b.addr.raw().saturating_add(VIRTIO_MMIO_IO_SIZE)
+        && b.addr.raw() < a.addr.raw().saturating_add(VIRTIO_MMIO_IO_SIZE)
+}
+
+/// Checks the devices of a configuration against each other.
+///
+/// # Arguments
+///
+/// * `config` - The frontends configuration.
+///
+/// # Returns
+///
+/// * `Vec<Diagnostic>` - Every problem found, in configuration order: interrupt lines used
+///   by devices of different guests, overlapping MMIO windows, queue sizes that are not
+///   powers of 2 and invalid or colliding MAC addresses are errors, interrupt lines shared
+///   within a guest and device names used twice are warnings.
+pub fn check_config(config: &ConfigFrontends) -> Vec<Diagnostic> {
+    let devices = config
+        .frontends
+        .iter()
+        .flat_map(|frontend| {
+            frontend.guests.iter().flat_map(move |guest| {
+                guest.devices.iter().map(move |device| {
+                    let context = ErrorContext::new(frontend.id, guest.id, &device.name);
+                    (context, device)
+                })
+            })
+        })
+        .collect::<Vec<_>>();
+    let macs = devices
+        .iter()
+        .map(|(context, device)| device.mac(context.frontend_id, context.guest_id))
+        .collect::<Vec<_>>();
+
+    let mut diagnostics = Vec::new();
+    for (index, (context, device)) in devices.iter().enumerate() {
+        let location = Some(context.to_string());
+        let same_guest = |other: &ErrorContext| {
+            other.frontend_id == context.frontend_id && other.guest_id == context.guest_id
+        };
+        for (other_context, other) in &devices[..index] {
+            if other.irq == device.irq {
+                let diagnostic = if same_guest(other_context) {
+                    Diagnostic::warning
+                } else {
+                    Diagnostic::error
+                };
+                let verb = if same_guest(other_context) {
+                    "is shared with"
+                } else {
+                    "collides with"
+                };
+                diagnostics.push(diagnostic(
+                    location.clone(),
+                    format!("irq {:#x} {} {}", device.irq.raw(), verb, other_context),
+                ));
+            }
+            if same_guest(other_context) && windows_overlap(device, other) {
+                diagnostics.push(Diagnostic::error(
+                    location.clone(),
+                    format!(
+                        "window {}+{:#x} overlaps {}",
+                        device.addr, VIRTIO_MMIO_IO_SIZE, other_context
+                    ),
+                ));
+            }
+        }
+        if let Some(max) = device.queue_size_max {
+            if !max.is_power_of_two() || max > VIRTQUEUE_MAX_SIZE {
+                diagnostics.push(Diagnostic::error(
+                    location.clone(),
+                    format!(
+                        "queue_size_max {} is not a power of 2 up to {}",
+                        max, VIRTQUEUE_MAX_SIZE
+                    ),
+                ));
+            }
+        }
+        match &macs[index] {
+            Ok(Some(mac)) => {
+                let other = macs[..index].iter().position(|other| match other {
+                    Ok(other) => *other == Some(*mac),
+                    Err(_) => false,
+                });
+                if let Some(other) = other {
+                    diagnostics.push(Diagnostic::error(
+                        location.clone(),
+                        format!("mac {} collides with {}", mac, devices[other].0),
+                    ));
+                }
+            }
+            Ok(None) => {}
+            Err(e) => diagnostics.push(Diagnostic::error(location.clone(), e.report())),
+        }
+        if let Some((other_context, _)) = devices[..index]
+            .iter()
+            .find(|(_, other)| other.name == device.name)
+        {
+            diagnostics.push(Diagnostic::warning(
+                location.clone(),
+                format!(
+                    "name already used by {} (management requests reach that device only)",
+                    other_context
+                ),
+            ));
+        }
+    }
+    diagnostics
+}
+
+/// Checks the CPUs the guest device workers are pinned to against the host.
+///
+/// # Arguments
+///
+/// * `config` - The frontends configuration.
+/// * `host` - The host CPUs.
+///
+/// # Returns
+///
+/// * `Vec<Diagnostic>` - Every problem found, per guest: malformed CPU lists are errors,
+///   CPUs that do not exist, are offline or are not isolated as expected are warnings.
+pub fn check_host_cpus(config: &ConfigFrontends, host: &HostCpus) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    for frontend in &config.frontends {
+        for guest in &frontend.guests {
+            let sched = match &guest.sched {
+                Some(sched) => sched,
+                None => continue,
+            };
+            let location = Some(format!("frontend{}/guest{}", frontend.id, guest.id));
+            match host.check(sched) {
+                Ok(problems) => diagnostics.extend(
+                    problems
+                        .into_iter()
+                        .map(|problem| Diagnostic::warning(location.clone(), problem)),
+                ),
+                Err(e) => diagnostics.push(Diagnostic::error(location, e.report())),
+            }
+        }
+    }
+    diagnostics
+}
+
+/// Checks whether any diagnostic is an error.
+pub fn has_errors(diagnostics: &[Diagnostic]) -> bool {
+    diagnostics.iter().any(|d| d.severity == Severity::Error)
+}
+
+/// Renders diagnostics for humans, grouped per location.
+///
+/// # Arguments
+///
+/// * `diagnostics` - The diagnostics.
+/// * `color` - Whether to colorize the severities and locations (ANSI escapes).
+///
+/// # Returns
+///
+/// * `String` - The rendered diagnostics, followed by a summary line (empty if there are
+///   no diagnostics).
+pub fn render_human(diagnostics: &[Diagnostic], color: bool) -> String {
+    let paint = |text: &str, code: &str| {
+        if color {
+            format!("\x1b[{}m{}\x1b[0m", code, text)
+        } else {
+            text.to_string()
+        }
+    };
+    let severity = |severity: Severity| match severity {
+        Severity::Error => paint("error", "1;31"),
+        Severity::Warning => paint("warning", "1;33"),
+    };
+
+    // Diagnostics about nothing in particular first, then per location
+    let mut groups = BTreeMap::<Option<&str>, Vec<&Diagnostic>>::new();
+    for diagnostic in diagnostics {
+        groups
+            .entry(diagnostic.location.as_deref())
+            .or_default()
+            .push(diagnostic);
+    }
+    let mut output = String::new();
+    for (location, group) in groups {
+        let indent = match location {
+            Some(location) => {
+                output.push_str(&format!("{}:\n", paint(location, "1")));
+                "  "
+            }
+            None => "",
+        };
+        for diagnostic in group {
+            output.push_str(&format!(
+                "{}{}: {}\n",
+                indent,
+                severity(diagnostic.severity),
+                diagnostic.message
+            ));
+        }
+    }
+
+    let errors = diagnostics
+        .iter()
+        .filter(|d| d.severity == Severity::Error)
+        .count();
+    let warnings = diagnostics.len() - errors;
+    let plural = |count: usize, what: &str| match count {
+        1 => format!("1 {}", what),
+        _ => format!("{} {}s", count, what),
+    };
+    if !diagnostics.is_empty() {
+        output.push_str(&format!(
+            "{}, {}\n",
+            plural(errors, "error"),
+            plural(warnings, "warning")
+        ));
+    }
+    output
+}
+
+/// Renders diagnostics as a JSON document.
+///
+/// # Arguments
+///
+/// * `diagnostics` - The diagnostics.
+///
+/// # Returns
+///
+/// * `String` - `{"diagnostics":[{"severity":...,"location":...,"message":...}]}`.
+pub fn render_json(diagnostics: &[Diagnostic]) -> String {
+    let diagnostics = diagnostics
+        .iter()
+        .map(Diagnostic::to_json)
+        .collect::<Vec<_>>();
+    format!("{{\"diagnostics\":[{}]}}\n", diagnostics.join(","))
+}
+
+/// Renders diagnostics in a format, for the standard error.
+///
+/// # Arguments
+///
+/// * `diagnostics` - The diagnostics.
+/// * `format` - The format (human diagnostics are colorized if the standard error is a
+///   terminal and `NO_COLOR` is not set).
+///
+/// # Returns
+///
+/// * `String` - The rendered diagnostics.
+pub fn render(diagnostics: &[Diagnostic], format: OutputFormat) -> String {
+    match format {
+        OutputFormat::Human => {
+            // SAFETY: `isatty` has no preconditions.
+            let terminal = unsafe { libc::isatty(libc::STDERR_FILENO) } == 1;
+            render_human(
+                diagnostics,
+                terminal && std::env::var_os("NO_COLOR").is_none(),
+            )
+        }
+        OutputFormat::Json => render_json(diagnostics),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::VmId;
+
+    fn config() -> ConfigFrontends {
+        serde_yaml::from_str(
+            "frontends:
+  - name: frontend0
+    id: 0
+    guests:
+      - name: guest0
+        id: 0
+        ram_addr: 0x50000000
+        ram_size: 0x1000000
+        shmem_path: /dev/baoipc0
+        socket_path: /tmp/
+        devices:
+          - {name: rng0, id: 0, type: rng, irq: 0x2f, addr: 0xa003e00}
+          - {name: i2c0, id: 1, type: i2c, irq: 0x2f, addr: 0xa003c00}
+      - name: guest1
+        id: 1
+        ram_addr: 0x60000000
+        ram_size: 0x1000000
+        shmem_path: /dev/baoipc1
+        socket_path: /tmp/
+        devices:
+          - {name: rng0, id: 0, type: rng, irq: 0x2f, addr: 0xa003e00}
+          - {name: gpio0, id: 1, type: gpio, irq: 0x30, addr: 0xa003f00, queue_size_max: 96}
+          - {name: net0, id: 2, type: net, irq: 0x31, addr: 0xa004200}
+          - {name: net1, id: 3, type: net, irq: 0x32, addr: 0xa004400, options: {mac: \"02:ba:00:cd:07:4a\"}}
+          - {name: net2, id: 4, type: net, irq: 0x33, addr: 0xa004600, options: {mac: ff}}
+",
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_check_config() {
+        let diagnostics = check_config(&config());
+        assert_eq!(
+            diagnostics
+                .iter()
+                .map(|d| (d.severity, d.to_string()))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    Severity::Warning,
+                    "frontend0/guest0/i2c0: irq 0x2f is shared with frontend0/guest0/rng0"
+                        .to_string()
+                ),
+                (
+                    Severity::Error,
+                    "frontend0/guest1/rng0: irq 0x2f collides with frontend0/guest0/rng0"
+                        .to_string()
+                ),
+                (
+                    Severity::Error,
+                    "frontend0/guest1/rng0: irq 0x2f collides with frontend0/guest0/i2c0"
+                        .to_string()
+                ),
+                (
+                    Severity::Warning,
+                    "frontend0/guest1/rng0: name already used by frontend0/guest0/rng0 \
+                     (management requests reach that device only)"
+                        .to_string()
+                ),
+                (
+                    Severity::Error,
+                    "frontend0/guest1/gpio0: window 0xa003f00+0x200 overlaps frontend0/guest1/rng0"
+                        .to_string()
+                ),
+                (
+                    Severity::Error,
+                    "frontend0/guest1/gpio0: queue_size_max 96 is not a power of 2 up to 32768"
+                        .to_string()
+                ),
+                (
+                    Severity::Error,
+                    "frontend0/guest1/net1: mac 02:ba:00:cd:07:4a collides with \
+                     frontend0/guest1/net0"
+                        .to_string()
+                ),
+                (
+                    Severity::Error,
+                    "frontend0/guest1/net2: Invalid MAC address ff (a unicast address such as \
+                     52:54:00:12:34:56 is expected)"
+                        .to_string()
+                ),
+            ]
+        );
+        assert!(has_errors(&diagnostics));
+        assert!(!has_errors(&diagnostics[..1]));
+    }
+
+    #[test]
+    fn test_check_host_cpus() {
+        let mut config = config();
+        let host = HostCpus {
+            present: [0, 1, 2, 3].into(),
+            online: [0, 1, 2].into(),
+            isolated: [2].into(),
+            nohz_full: [].into(),
+        };
+        assert!(check_host_cpus(&config, &host).is_empty());
+
+        let guests = &mut config.frontends[0].guests;
+        guests[0].sched = Some(serde_yaml::from_str("{cpus: 2-4, isolated: true}").unwrap());
+        guests[1].sched = Some(serde_yaml::from_str("{cpus: 1-0}").unwrap());
+        assert_eq!(
+            check_host_cpus(&config, &host)
+                .iter()
+                .map(|d| (d.severity, d.to_string()))
+                .collect::<Vec<_>>(),
+            vec![
+                (
+                    Severity::Warning,
+                    "frontend0/guest0: pinned CPUs 4 do not exist".to_string()
+                ),
+                (
+                    Severity::Warning,
+                    "frontend0/guest0: pinned CPUs 3 are offline".to_string()
+                ),
+                (
+                    Severity::Warning,
+                    "frontend0/guest0: pinned CPUs 3,4 are in neither isolcpus nor nohz_full"
+                        .to_string()
+                ),
+                (
+                    Severity::Error,
+                    "frontend0/guest1: Invalid scheduling CPU list 1-0".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        let diagnostics = vec![
+            Diagnostic::error(None, "no frontend".to_string()),
+            Diagnostic::warning(
+                Some("frontend0/guest0/rng0".to_string()),
+                "a \"b\"".to_string(),
+            ),
+            Diagnostic::error(Some("frontend0/guest0/rng0".to_string()), "c".to_string()),
+        ];
+        assert_eq!(
+            render_human(&diagnostics, false),
+            "error: no frontend\nfrontend0/guest0/rng0:\n  warning: a \"b\"\n  error: c\n\
+             2 errors, 1 warning\n"
+        );
+        assert!(render_human(&diagnostics, true).contains("\x1b[1;31merror\x1b[0m: c"));
+        assert_eq!(render_human(&[], false), "");
+        assert_eq!(
+            render_json(&diagnostics[..2]),
+            "{\"diagnostics\":[{\"severity\":\"error\",\"location\":null,\"message\":\"no frontend\"},\
+             {\"severity\":\"warning\",\"location\":\"frontend0/guest0/rng0\",\"message\":\"a \\\"b\\\"\"}]}\n"
+        );
+
+        let error =
+            Error::DeviceNotFound.with_context(&ErrorContext::new(VmId(0), VmId(1), "rng0"));
+        assert_eq!(
+            Diagnostic::from_error(&error),
+            vec![Diagnostic::error(
+                Some("frontend0/guest1/rng0".to_string()),
+                "Device not found".to_string()
+            )]
+        );
+
+        let args = ["bao-sys", "-c", "config.yaml", "--output", "json"].map(String::from);
+        assert_eq!(OutputFormat::from_args(args), OutputFormat::Json);
+        let args = ["bao-sys", "--output=human"].map(String::from);
+        assert_eq!(OutputFormat::from_args(args), OutputFormat::Human);
+        assert_eq!(OutputFormat::from_args(Vec::new()), OutputFormat::Human);
+    }
+}
@@ -0,0 +1,546 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao device tree device discovery.
+//!
+//! Guests with a `dtb` get the devices missing from their configuration derived from
+//! their device tree. A `virtio,mmio` node becomes a device of the type named by a
+//! `virtio,device<id>` hint (the virtio device ID in hexadecimal, on the node itself or
+//! on a subnode, as in the Linux virtio binding); nodes with a nonstandard `compatible`
+//! (e.g. those of the existing Bao demos) are typed through the override table of the
+//! configuration. The device takes the address of the `reg` of the node and the
+//! interrupt of its `interrupts`. `virtio,mmio` nodes without a hint are free slots and
+//! left alone, and configured devices take precedence over the nodes at their address.
+
+#![allow(dead_code)]
+
+use super::error::{Error, Result};
+use super::overlay::{
+    FDT_BEGIN_NODE, FDT_END, FDT_END_NODE, FDT_HEADER_SIZE, FDT_MAGIC, FDT_PROP, GIC_SPI,
+    GIC_SPI_BASE,
+};
+use super::types::{ConfigDevice, ConfigDtb, ConfigGuest, DeviceId, GuestAddress, IrqLine};
+use super::virtio_ids::DeviceType;
+use std::collections::BTreeMap;
+use std::fs;
+
+/// Structure block no-op token.
+const FDT_NOP: u32 = 0x4;
+/// Deepest node nesting accepted.
+const DT_MAX_DEPTH: usize = 32;
+/// Default `#address-cells` and `#size-cells` of a node (devicetree specification).
+const DT_ADDRESS_CELLS: u32 = 2;
+const DT_SIZE_CELLS: u32 = 1;
+/// GIC private peripheral interrupt type, and its first interrupt ID.
+const GIC_PPI: u32 = 1;
+const GIC_PPI_BASE: u32 = 16;
+/// Compatible of the virtio-mmio transport nodes.
+const VIRTIO_MMIO_COMPATIBLE: &str = "virtio,mmio";
+/// Prefix of the compatible naming a virtio device ID (in hexadecimal).
+const VIRTIO_DEVICE_COMPATIBLE: &str = "virtio,device";
+
+/// Struct representing a device tree node.
+///
+/// # Attributes
+///
+/// * `name` - Node name (unit address included).
+/// * `properties` - Properties, by name.
+/// * `children` - Subnodes.
+#[derive(Debug, Default)]
+struct DtNode {
+    name: String,
+    properties: Vec<(String, Vec<u8>)>,
+    children: Vec<DtNode>,
+}
+
+impl DtNode {
+    /// Returns the value of a property.
+    fn property(&self, name: &str) -> Option<&[u8]> {
+        self.properties
+            .iter()
+            .find(|(property, _)| property == name)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// Returns the cells of a property.
+    fn cells(&self, name: &str) -> Option<Vec<u32>> {
+        let value = self.property(name)?;
+        Some(
+            value
+                .chunks_exact(4)
+                .map(|cell| u32::from_be_bytes(cell.try_into().unwrap()))
+                .collect(),
+        )
+    }
+
+    /// Returns the strings of the `compatible` property.
+    fn compatibles(&self) -> Vec<&str> {
+        self.property("compatible")
+            .map(|value| {
+                value
+                    .split(|&b| b == 0)
+                    .filter_map(|s| std::str::from_utf8(s).ok())
+                    .filter(|s| !s.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+}
+
+/// Struct representing a flattened device tree being read.
+///
+/// # Attributes
+///
+/// * `blob` - The blob.
+/// * `offset` - Offset of the next token.
+/// * `strings` - Offset of the strings block.
+struct FdtReader<'a> {
+    blob: &'a [u8],
+    offset: usize,
+    strings: usize,
+}
+
+impl<'a> FdtReader<'a> {
+    /// Reads a big-endian word at an offset.
+    fn word_at(&self, offset: usize) -> std::result::Result<u32, &'static str> {
+        let bytes = self.blob.get(offset..offset + 4).ok_or("truncated blob")?;
+        Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+    }
+
+    /// Reads the next word of the structure block.
+    fn word(&mut self) -> std::result::Result<u32, &'static str> {
+        let word = self.word_at(self.offset)?;
+        self.offset += 4;
+        Ok(word)
+    }
+
+    /// Reads the next token, skipping the no-op ones.
+    fn token(&mut self) -> std::result::Result<u32, &'static str> {
+        loop {
+            match self.word()? {
+                FDT_NOP => {}
+                token => return Ok(token),
+            }
+        }
+    }
+
+    /// Reads a NUL-terminated string at an offset.
+    fn c_str(&self, offset: usize) -> std::result::Result<&'a str, &'static str> {
+        let bytes = self.blob.get(offset..).ok_or("truncated blob")?;
+        let end = bytes
+            .iter()
+            .position(|&b| b == 0)
+            .ok_or("unterminated string")?;
+        std::str::from_utf8(&bytes[..end]).map_err(|_| "invalid string")
+    }
+
+    /// Reads a node, its `FDT_BEGIN_NODE` token being consumed.
+    fn node(&mut self, depth: usize) -> std::result::Result<DtNode, &'static str> {
+        let name = self.c_str(self.offset)?;
+        self.offset = (self.offset + name.len() + 1).next_multiple_of(4);
+        let mut node = DtNode {
+            name: name.to_string(),
+            ..Default::default()
+        };
+        loop {
+            match self.token()? {
+                FDT_PROP => {
+                    let len = self.word()? as usize;
+                    let name_offset = self.strings + self.word()? as usize;
+                    let name = self.c_str(name_offset)?;
+                    let value = self
+                        .blob
+                        .get(self.offset..self.offset + len)
+                        .ok_or("truncated property")?;
+                    node.properties.push((name.to_string(), value.to_vec()));
+                    self.offset = (self.offset + len).next_multiple_of(4);
+                }
+                FDT_BEGIN_NODE if depth < DT_MAX_DEPTH => node.children.push(self.node(depth + 1)?),
+                FDT_BEGIN_NODE => return Err("nodes nested too deep"),
+                FDT_END_NODE => return Ok(node),
+                _ => return Err("unexpected token"),
+            }
+        }
+    }
+}
+
+/// Parses a flattened device tree.
+fn parse(blob: &[u8]) -> std::result::Result<DtNode, &'static str> {
+    let mut reader = FdtReader {
+        blob,
+        offset: 0,
+        strings: 0,
+    };
+    if blob.len() < FDT_HEADER_SIZE || reader.word_at(0)? != FDT_MAGIC {
+        return Err("not a flattened device tree");
+    }
+    if reader.word_at(4)? as usize > blob.len() {
+        return Err("truncated blob");
+    }
+    reader.offset = reader.word_at(8)? as usize;
+    reader.strings = reader.word_at(12)? as usize;
+    if reader.token()? != FDT_BEGIN_NODE {
+        return Err("missing root node");
+    }
+    let root = reader.node(0)?;
+    if reader.token()? != FDT_END {
+        return Err("missing end token");
+    }
+    Ok(root)
+}
+
+/// Struct representing a device found in a device tree.
+///
+/// # Attributes
+///
+/// * `path` - Path of the node.
+/// * `device_type` - Device type.
+/// * `addr` - Device address.
+/// * `irq` - Device IRQ.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DtDevice {
+    pub path: String,
+    pub device_type: DeviceType,
+    pub addr: GuestAddress,
+    pub irq: IrqLine,
+}
+
+/// Returns the device type of a `compatible` string.
+///
+/// # Arguments
+///
+/// * `compatible` - The `compatible` string.
+/// * `overrides` - Device type of the nonstandard `compatible` strings.
+///
+/// # Returns
+///
+/// * `std::result::Result<Option<DeviceType>, String>` - The device type (None if the
+///   string names none), or the reason it is invalid.
+fn compatible_type(
+    compatible: &str,
+    overrides: &BTreeMap<String, String>,
+) -> std::result::Result<Option<DeviceType>, String> {
+    if let Some(name) = overrides.get(compatible) {
+        return DeviceType::from_name(name).map(Some).ok_or_else(|| {
+            format!(
+                "unknown device type {} for the compatible {}",
+                name, compatible
+            )
+        });
+    }
+    Ok(compatible
+        .strip_prefix(VIRTIO_DEVICE_COMPATIBLE)
+        .and_then(|id| u32::from_str_radix(id, 16).ok())
+        .and_then(DeviceType::from_virtio_id))
+}
+
+/// Returns the device type of a node, if it is a device.
+fn node_type(
+    node: &DtNode,
+    overrides: &BTreeMap<String, String>,
+) -> std::result::Result<Option<DeviceType>, String> {
+    let compatibles = node.compatibles();
+    let device = compatibles
+        .iter()
+        .any(|c| *c == VIRTIO_MMIO_COMPATIBLE || overrides.contains_key(*c));
+    if !device {
+        return Ok(None);
+    }
+    // The overrides come first, then the hints of the node and of its subnodes
+    let hints = node.children.iter().flat_map(|child| child.compatibles());
+    let mut found = None;
+    for compatible in compatibles.iter().copied().chain(hints) {
+        match compatible_type(compatible, overrides)? {
+            Some(device_type) if overrides.contains_key(compatible) => {
+                return Ok(Some(device_type))
+            }
+            Some(device_type) => found = found.or(Some(device_type)),
+            None => {}
+        }
+    }
+    Ok(found)
+}
+
+/// Returns the address and interrupt of a device node.
+fn node_resources(
+    node: &DtNode,
+    address_cells: u32,
+    size_cells: u32,
+) -> std::result::Result<(GuestAddress, IrqLine), String> {
+    let reg = node.cells("reg").unwrap_or_default();
+    if address_cells == 0 || address_cells > 2 || reg.len() < (address_cells + size_cells) as usize
+    {
+        return Err(format!("{} has no usable reg", node.name));
+    }
+    let addr = reg[..address_cells as usize]
+        .iter()
+        .fold(0u64, |addr, &cell| (addr << 32) | u64::from(cell));
+    let irq = match node.cells("interrupts").unwrap_or_default().as_slice() {
+        [GIC_SPI, irq, _] => irq.checked_add(GIC_SPI_BASE),
+        [GIC_PPI, irq, _] => irq.checked_add(GIC_PPI_BASE),
+        [irq] | [irq, _] => Some(*irq),
+        _ => None,
+    }
+    .ok_or_else(|| format!("{} has no usable interrupts", node.name))?;
+    Ok((GuestAddress(addr), IrqLine(irq)))
+}
+
+/// Collects the devices beneath a node.
+fn collect(
+    node: &DtNode,
+    path: &str,
+    overrides: &BTreeMap<String, String>,
+    devices: &mut Vec<DtDevice>,
+) -> std::result::Result<(), String> {
+    let cell_count = |name, default| {
+        node.cells(name)
+            .and_then(|cells| cells.first().copied())
+            .unwrap_or(default)
+    };
+    let address_cells = cell_count("#address-cells", DT_ADDRESS_CELLS);
+    let size_cells = cell_count("#size-cells", DT_SIZE_CELLS);
+    for child in &node.children {
+        let path = format!("{}/{}", path.trim_end_matches('/'), child.name);
+        match node_type(child, overrides)? {
+            Some(device_type) => {
+                let (addr, irq) = node_resources(child, address_cells, size_cells)?;
+                devices.push(DtDevice {
+                    path,
+                    device_type,
+                    addr,
+                    irq,
+                });
+            }
+            // Devices and free slots are leaves: their subnodes only carry hints
+            None if !child.compatibles().contains(&VIRTIO_MMIO_COMPATIBLE) => {
+                collect(child, &path, overrides, devices)?
+            }
+            None => {}
+        }
+    }
+    Ok(())
+}
+
+/// Finds the devices described by a device tree.
+///
+/// # Arguments
+///
+/// * `blob` - The flattened device tree blob.
+/// * `config` - Device tree configuration of the guest.
+///
+/// # Returns
+///
+/// * `Result<Vec<DtDevice>>` - The devices, in device tree order, or `InvalidDtb`.
+pub fn dt_devices(blob: &[u8], config: &ConfigDtb) -> Result<Vec<DtDevice>> {
+    let invalid = |reason: String| Error::InvalidDtb(config.path.clone(), reason);
+    let root = parse(blob).map_err(|reason| invalid(reason.to_string()))?;
+    let mut devices = Vec::new();
+    collect(&root, "/", &config.compatibles, &mut devices).map_err(invalid)?;
+    Ok(devices)
+}
+
+/// Adds the devices of the device tree of a guest missing from its configuration.
+///
+/// Derived devices are named after their type (e.g. `net0`) and take the next free
+/// device IDs.
+///
+/// # Arguments
+///
+/// * `guest` - The guest configuration.
+///
+/// # Returns
+///
+/// * `Result<usize>` - The number of devices added (none if the guest has no `dtb`),
+///   `ConfigIo` if the blob cannot be read, `InvalidDtb` if it is invalid.
+pub fn derive_devices(guest: &mut ConfigGuest) -> Result<usize> {
+    let config = match &guest.dtb {
+        Some(config) => config.clone(),
+        None => return Ok(0),
+    };
+    let blob = fs::read(&config.path).map_err(|e| Error::ConfigIo(config.path.clone(), e))?;
+    let mut added = 0;
+    for device in dt_devices(&blob, &config)? {
+        if guest.devices.iter().any(|d| d.addr == device.addr) {
+            continue;
+        }
+        let type_name = device.device_type.name();
+        let name = (0..)
+            .map(|index| format!("{}{}", type_name, index))
+            .find(|name| guest.devices.iter().all(|d| &d.name != name))
+            .unwrap();
+        let id = guest
+            .devices
+            .iter()
+            .map(|d| d.id.raw() + 1)
+            .max()
+            .unwrap_or(0);
+        guest.devices.push(ConfigDevice {
+            name,
+            id: DeviceId(id),
+            device_type: type_name.to_string(),
+            irq: device.irq,
+            addr: device.addr,
+            ..Default::default()
+        });
+        added += 1;
+    }
+    Ok(added)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::overlay::FdtWriter;
+    use crate::types::VmId;
+
+    /// Writes a tree of 4 virtio-mmio slots: rng (subnode hint), i2c (node hint), a free
+    /// slot and a nonstandard net node, the first nested in a bus.
+    fn blob() -> Vec<u8> {
+        let mut fdt = FdtWriter::default();
+        fdt.begin_node("");
+        fdt.property_cells("#address-cells", &[2]);
+        fdt.property_cells("#size-cells", &[2]);
+        fdt.begin_node("soc");
+        fdt.property_cells("#address-cells", &[1]);
+        fdt.property_cells("#size-cells", &[1]);
+        fdt.begin_node("virtio_mmio@a000000");
+        fdt.property_string("compatible", "virtio,mmio");
+        fdt.property_cells("reg", &[0xa000000, 0x200]);
+        fdt.property_cells("interrupts", &[0, 16, 1]);
+        fdt.begin_node("device");
+        fdt.property_string("compatible", "virtio,device4");
+        fdt.end_node();
+        fdt.end_node();
+        fdt.end_node();
+        fdt.begin_node("virtio_mmio@a000200");
+        fdt.property("compatible", b"virtio,device22\0virtio,mmio\0");
+        fdt.property_cells("reg", &[0, 0xa000200, 0, 0x200]);
+        fdt.property_cells("interrupts", &[0, 17, 1]);
+        fdt.end_node();
+        fdt.begin_node("virtio_mmio@a000400");
+        fdt.property_string("compatible", "virtio,mmio");
+        fdt.property_cells("reg", &[0, 0xa000400, 0, 0x200]);
+        fdt.property_cells("interrupts", &[0, 18, 1]);
+        fdt.end_node();
+        fdt.begin_node("bao-net@a000600");
+        fdt.property_string("compatible", "bao,virtio-net");
+        fdt.property_cells("reg", &[0, 0xa000600, 0, 0x200]);
+        fdt.property_cells("interrupts", &[0, 19, 4]);
+        fdt.end_node();
+        fdt.end_node();
+        fdt.finish()
+    }
+
+    fn config(path: &str) -> ConfigDtb {
+        ConfigDtb {
+            path: path.to_string(),
+            compatibles: [("bao,virtio-net".to_string(), "net".to_string())].into(),
+        }
+    }
+
+    #[test]
+    fn test_dt_devices() {
+        let device = |path: &str, device_type, addr, irq| DtDevice {
+            path: path.to_string(),
+            device_type,
+            addr: GuestAddress(addr),
+            irq: IrqLine(irq),
+        };
+        assert_eq!(
+            dt_devices(&blob(), &config("guest.dtb")).unwrap(),
+            [
+                device("/soc/virtio_mmio@a000000", DeviceType::Rng, 0xa000000, 48),
+                device("/virtio_mmio@a000200", DeviceType::I2c, 0xa000200, 49),
+                device("/bao-net@a000600", DeviceType::Net, 0xa000600, 51),
+            ]
+        );
+
+        // Without the override, the nonstandard node is not a device
+        let devices = dt_devices(&blob(), &ConfigDtb::default()).unwrap();
+        assert_eq!(devices.len(), 2);
+
+        let mut unknown = config("guest.dtb");
+        unknown
+            .compatibles
+            .insert("bao,virtio-net".to_string(), "modem".to_string());
+        let err = dt_devices(&blob(), &unknown).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid device tree guest.dtb: unknown device type modem for the compatible bao,virtio-net"
+        );
+        let mut truncated = blob();
+        truncated.truncate(100);
+        assert!(matches!(
+            dt_devices(&truncated, &ConfigDtb::default()),
+            Err(Error::InvalidDtb(..))
+        ));
+        assert!(matches!(
+            dt_devices(
+                b"not a device tree, but long enough for a header",
+                &ConfigDtb::default()
+            ),
+            Err(Error::InvalidDtb(..))
+        ));
+    }
+
+    #[test]
+    fn test_derive_devices() {
+        let path = std::env::temp_dir().join(format!("bao-dtb-{}.dtb", std::process::id()));
+        fs::write(&path, blob()).unwrap();
+        let mut guest = ConfigGuest {
+            name: "guest0".to_string(),
+            id: VmId(1),
+            ram_addr: GuestAddress(0x50000000),
+            ram_size: 0x1000000,
+            shmem_path: "/dev/baoipc0".to_string(),
+            socket_path: "/tmp/".to_string(),
+            devices: vec![ConfigDevice {
+                name: "rng0".to_string(),
+                id: DeviceId(0),
+                device_type: "rng".to_string(),
+                irq: IrqLine(0x2f),
+                addr: GuestAddress(0xa000000),
+                ..Default::default()
+            }],
+            sched: None,
+            watch_dir: None,
+            dt_overlay: None,
+            prefault: false,
+            thp: Default::default(),
+            dtb: Some(config(path.to_str().unwrap())),
+            labels: Default::default(),
+        };
+
+        // The configured device takes precedence over the node at its address
+        assert_eq!(derive_devices(&mut guest).unwrap(), 2);
+        let devices = guest
+            .devices
+            .iter()
+            .map(|d| {
+                (
+                    d.name.as_str(),
+                    d.id.raw(),
+                    d.device_type.as_str(),
+                    d.irq.raw(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            devices,
+            [
+                ("rng0", 0, "rng", 0x2f),
+                ("i2c0", 1, "i2c", 49),
+                ("net0", 2, "net", 51)
+            ]
+        );
+        assert_eq!(derive_devices(&mut guest).unwrap(), 0);
+        fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            derive_devices(&mut guest),
+            Err(Error::ConfigIo(..))
+        ));
+    }
+}
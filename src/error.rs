@@ -7,13 +7,80 @@
 
 #![allow(dead_code)]
 
-use std::{io, num::ParseIntError, str};
+use super::types::VmId;
+use std::{fmt, io, num::ParseIntError, str};
 
 /// Result code.
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Identity of the device an error originates from.
+///
+/// # Attributes
+///
+/// * `frontend_id` - Frontend ID.
+/// * `guest_id` - Guest ID.
+/// * `device` - Device name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ErrorContext {
+    pub frontend_id: VmId,
+    pub guest_id: VmId,
+    pub device: String,
+}
+
+impl ErrorContext {
+    /// Creates a new error context.
+    ///
+    /// # Arguments
+    ///
+    /// * `frontend_id` - Frontend ID.
+    /// * `guest_id` - Guest ID.
+    /// * `device` - Device name.
+    ///
+    /// # Returns
+    ///
+    /// * `ErrorContext` - The error context.
+    pub fn new(frontend_id: VmId, guest_id: VmId, device: &str) -> Self {
+        ErrorContext {
+            frontend_id,
+            guest_id,
+            device: device.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "frontend{}/guest{}/{}",
+            self.frontend_id, self.guest_id, self.device
+        )
+    }
+}
+
+/// Extension trait to tag device-path errors with the device identity.
+pub trait ResultExt<T> {
+    /// Tags the error (if any) with the device identity.
+    ///
+    /// # Arguments
+    ///
+    /// * `context` - Identity of the device.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<T>` - The result, with the error wrapped in `Error::Device`.
+    fn with_context(self, context: &ErrorContext) -> Result<T>;
+}
+
+impl<T> ResultExt<T> for Result<T> {
+    fn with_context(self, context: &ErrorContext) -> Result<T> {
+        self.map_err(|e| e.with_context(context))
+    }
+}
+
 /// Error codes.
 #[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
 pub enum Error {
     #[error("Invalid Frontend ID {0:?}")]
     InvalidFrontendId(u16),
@@ -29,34 +96,735 @@ pub enum Error {
     InvalidMmioDir(u8),
     #[error("Device not supported: {0:}")]
     BaoDevNotSupported(String),
-    #[error("Bao IOCTL error: {0:?} - {1:?}")]
-    BaoIoctlError(io::Error, &'static str),
-    #[error("Vhost user frontend error")]
-    VhostFrontendError(vhost_user_frontend::Error),
-    #[error("Vhost user frontend activate error")]
-    VhostFrontendActivateError(vhost_user_frontend::ActivateError),
-    #[error("Invalid String: {0:?}")]
-    InvalidString(str::Utf8Error),
-    #[error("Failed while parsing to integer: {0:?}")]
-    ParseFailure(ParseIntError),
-    #[error("Failed to create epoll context: {0:?}")]
-    EpollCreateFd(io::Error),
-    #[error("Failed to add event to epoll: {0:?}")]
-    RegisterExitEvent(io::Error),
-    #[error("Failed while waiting on epoll: {0:?}")]
-    EpollWait(io::Error),
+    #[error("Bao IOCTL {1:} failed")]
+    BaoIoctlError(#[source] io::Error, &'static str),
+    #[error("Vhost user frontend error on {context:} ({request:})")]
+    VhostFrontendError {
+        context: ErrorContext,
+        request: &'static str,
+        #[source]
+        source: vhost_user_frontend::Error,
+    },
+    #[error("Vhost user frontend failed to activate {context:}")]
+    VhostFrontendActivateError {
+        context: ErrorContext,
+        #[source]
+        source: vhost_user_frontend::ActivateError,
+    },
+    #[error("Invalid UTF-8 string")]
+    InvalidString(#[from] str::Utf8Error),
+    #[error("Failed to parse an integer")]
+    ParseFailure(#[from] ParseIntError),
+    #[error("Failed to create epoll context")]
+    EpollCreateFd(#[source] io::Error),
+    #[error("Failed to add event to epoll")]
+    RegisterExitEvent(#[source] io::Error),
+    #[error("Failed while waiting on epoll")]
+    EpollWait(#[source] io::Error),
     #[error("Bao Bus Invalid State")]
     BaoBusInvalidState,
-    #[error("Failed to kick backend: {0:?}")]
-    EventFdWriteFailed(io::Error),
-    #[error("Failed to open the file descriptor {0:?}: {1:?}")]
-    OpenFdFailed(&'static str, io::Error),
+    #[error("Failed to kick backend")]
+    EventFdWriteFailed(#[source] io::Error),
+    #[error("Failed to open the file descriptor {0:?}")]
+    OpenFdFailed(&'static str, #[source] io::Error),
     #[error("Invalid IO Request Direction: {0:?}")]
     InvalidIoReqDirection(u64),
+    #[error("Invalid IO Request Access Width: {0:?}")]
+    InvalidAccessWidth(u64),
     #[error("HandleIoEventFailed")]
     HandleIoEventFailed,
     #[error("Device not found")]
     DeviceNotFound,
     #[error("Mmap guest memory failed")]
     MmapGuestMemoryFailed,
+    #[error("Failed to prepare the guest RAM ({0:})")]
+    GuestRamFailed(&'static str, #[source] io::Error),
+    #[error("Failed to daemonize ({0:})")]
+    DaemonizeFailed(&'static str, #[source] io::Error),
+    #[error("Failed to write the pidfile")]
+    PidFileFailed(#[source] io::Error),
+    #[error("Failed to install the seccomp filter")]
+    SeccompError(#[from] seccompiler::Error),
+    #[error("User not found: {0:}")]
+    UserNotFound(String),
+    #[error("Group not found: {0:}")]
+    GroupNotFound(String),
+    #[error("Failed to drop privileges ({0:})")]
+    DropPrivilegesFailed(&'static str, #[source] io::Error),
+    #[error("Invalid {0:} scheduling priority {1:}")]
+    InvalidSchedPriority(&'static str, u32),
+    #[error("Invalid scheduling CPU list {0:}")]
+    InvalidSchedCpus(String),
+    #[error("Failed to set the worker scheduling ({0:})")]
+    SchedulingFailed(&'static str, #[source] io::Error),
+    #[error("Failed to read the secret {0:}")]
+    SecretFailed(String, #[source] io::Error),
+    #[error("Secret {0:} must be owned by root and not accessible by group or others")]
+    InsecureSecret(String),
+    #[error("Failed to restrict the filesystem access")]
+    LandlockError(#[from] landlock::RulesetError),
+    #[error("Failed to spawn the device backend ({0:})")]
+    SpawnBackendFailed(&'static str, #[source] io::Error),
+    #[error("Backend of {0:} did not report readiness in time")]
+    BackendNotReady(String),
+    #[error("Backend of {0:} exited ({1:})")]
+    BackendExited(String, std::process::ExitStatus),
+    #[error("Backend of {0:} panicked")]
+    BackendPanicked(String),
+    #[error("Backend of {0:} lacks the features required by the device: {1:}")]
+    BackendIncompatible(String, String),
+    #[error("Failed to access the audit log")]
+    AuditLogFailed(#[source] io::Error),
+    #[error("Invalid audit log")]
+    InvalidAuditLog,
+    #[error("Failed to access the request recording")]
+    RecordingFailed(#[source] io::Error),
+    #[error("Invalid request recording")]
+    InvalidRecording,
+    #[error("Failed to open the log file {0:}")]
+    LogFileFailed(String, #[source] io::Error),
+    #[error("Failed to write the trace")]
+    TraceFailed(#[source] io::Error),
+    #[error("Invalid {0:} encoding length {1:}")]
+    InvalidEncoding(&'static str, usize),
+    #[error("MMIO bus error")]
+    MmioBusError(#[source] vm_device::bus::Error),
+    #[error("Device {0:} depends on the unknown device {1:}")]
+    UnknownDependency(String, String),
+    #[error("Dependency cycle between devices {0:}")]
+    DependencyCycle(String),
+    #[error("Invalid platform description {0:}: {1:}")]
+    InvalidPlatform(String, String),
+    #[error("Device collides with the platform: {0:}")]
+    PlatformConflict(String),
+    #[error("Invalid configuration: {0:}")]
+    InvalidConfig(Box<dyn std::error::Error + Send + Sync>),
+    #[error("Invalid output format: {0:}")]
+    InvalidOutputFormat(String),
+    #[error("Failed to read the configuration {0:}")]
+    ConfigIo(String, #[source] io::Error),
+    #[error("Invalid configuration {path:} (line {line:}, column {column:}): {message:}")]
+    ConfigParse {
+        path: String,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    #[error("Backend {0:} is not registered")]
+    BackendNotRegistered(String),
+    #[error("Failed to connect to the backend of {0:}")]
+    BackendConnectFailed(String, #[source] io::Error),
+    #[error("Backend socket of {0:} did not show up in time")]
+    BackendConnectTimedOut(String),
+    #[error("Invalid backend option {0:} ({1:})")]
+    InvalidBackendOption(String, String),
+    #[error("Failed to open block image {0:}")]
+    BlockImageFailed(String, #[source] io::Error),
+    #[error("Failed to resize block image {0:}")]
+    BlockResizeFailed(String, #[source] io::Error),
+    #[error("Failed to open CAN interface {0:}")]
+    CanSocketFailed(String, #[source] io::Error),
+    #[error("Failed to set up the terminal of console {0:}")]
+    ConsolePtyFailed(String, #[source] io::Error),
+    #[error("Failed to request the lines of GPIO chip {0:}")]
+    GpioRequestFailed(String, #[source] io::Error),
+    #[error("Failed to open I2C adapter {0:}")]
+    I2cAdapterFailed(String, #[source] io::Error),
+    #[error("Failed to set up TAP interface {0:}")]
+    TapFailed(String, #[source] io::Error),
+    #[error("Packet capture of {0:} failed")]
+    CaptureFailed(String, #[source] io::Error),
+    #[error("Cannot resize block image {0:} ({1:})")]
+    InvalidResize(String, String),
+    #[error("Management server failed: {0:}")]
+    ManagementServerFailed(String),
+    #[error("OTLP export failed: {0:}")]
+    OtlpExportFailed(String),
+    #[error("Device hot-plug is not supported")]
+    HotplugNotSupported,
+    #[error("Device {0:} already exists")]
+    DeviceExists(String),
+    #[error("Control socket failed ({0:})")]
+    ControlFailed(&'static str, #[source] io::Error),
+    #[error("Invalid control command: {0:}")]
+    InvalidControlCommand(String),
+    #[error("User {0:} may not issue {1:} commands")]
+    ControlPermissionDenied(u32, &'static str),
+    #[error("Control command longer than {0:} bytes")]
+    ControlLineTooLong(usize),
+    #[error("Too many control connections (at most {0:})")]
+    ControlBusy(usize),
+    #[error("Frontend {0:} not found")]
+    FrontendNotFound(String),
+    #[error("Command not available on the control socket of frontend {0:}")]
+    OutOfFrontendScope(String),
+    #[error("Failed to write the stats file")]
+    StatsFileFailed(#[source] io::Error),
+    #[error("Invalid pause mode {0:}")]
+    InvalidPauseMode(String),
+    #[error("Device {0:} cannot be paused")]
+    PauseNotSupported(String),
+    #[error("Device {0:} cannot be resized")]
+    ResizeNotSupported(String),
+    #[error("Frames of device {0:} cannot be captured")]
+    CaptureNotSupported(String),
+    #[error("Console output of device {0:} is not kept")]
+    ConsoleLogNotSupported(String),
+    #[error("Device {0:} cannot move to another backend")]
+    MigrationNotSupported(String),
+    #[error("Queues of device {0:} cannot be inspected")]
+    QueueDumpNotSupported(String),
+    #[error("Device {0:} has no queue {1:}")]
+    QueueNotFound(String, usize),
+    #[error("A packet capture of {0:} is already running")]
+    CaptureRunning(String),
+    #[error("No packet capture of {0:} to stop")]
+    CaptureNotRunning(String),
+    #[error("Invalid fault {0:} (expected drop-every N, delay US, corrupt REG MASK, disconnect or clear)")]
+    InvalidFault(String),
+    #[error("Invalid device UUID: {0:}")]
+    InvalidUuid(String),
+    #[error("Invalid MAC address {0:} (a unicast address such as 52:54:00:12:34:56 is expected)")]
+    InvalidMacAddress(String),
+    #[error("Device {0:} is {1:}")]
+    InvalidDeviceState(String, &'static str),
+    #[error("Failed to supervise the frontend processes ({0:})")]
+    SuperviseFailed(&'static str, #[source] io::Error),
+    #[error("Live update failed ({0:})")]
+    HandoffFailed(&'static str, #[source] io::Error),
+    #[error("Invalid live update message: {0:}")]
+    InvalidHandoff(String),
+    #[error("Failed to access the snapshot")]
+    SnapshotFailed(#[source] io::Error),
+    #[error("Invalid snapshot: {0:}")]
+    InvalidSnapshot(String),
+    #[error("Failed to access the applied configuration record")]
+    AppliedConfigFailed(#[source] io::Error),
+    #[error("Invalid applied configuration record: {0:}")]
+    InvalidAppliedConfig(String),
+    #[error("Device {0:} does not support snapshots")]
+    SnapshotNotSupported(String),
+    #[error("Failed to watch the device fragments")]
+    WatchFailed(#[source] io::Error),
+    #[error("Invalid device fragment {0:}: {1:}")]
+    InvalidDeviceFragment(String, String),
+    #[error("Invalid device tree overlay of {0:}: {1:}")]
+    InvalidDtOverlay(String, String),
+    #[error("Invalid device tree {0:}: {1:}")]
+    InvalidDtb(String, String),
+    #[error("Failed to apply the device tree overlay of {0:}")]
+    DtOverlayFailed(String, #[source] io::Error),
+    #[error("Device tree overlay of {0:} was not applied (status {1:})")]
+    DtOverlayRejected(String, String),
+    #[error(
+        "Unexpected value {actual:#x} read from register {reg_off:#x} (expected {expected:#x})"
+    )]
+    ScriptMismatch {
+        reg_off: u64,
+        expected: u64,
+        actual: u64,
+    },
+    #[error("Self-test failed: {0:}")]
+    SelfTestFailed(String),
+    #[error("Invalid guest memory access at {0:#x} ({1:} bytes)")]
+    InvalidGuestAddress(u64, usize),
+    #[error("Guest issued no I/O request after {0:} attempts")]
+    AttachTimedOut(u32),
+    #[error("I/O request to register {0:#x} was not completed")]
+    RequestNotCompleted(u64),
+    #[error("Invalid queue size {0:} (a power of 2 up to {1:} is expected)")]
+    InvalidQueueSize(u32, u16),
+    #[error("Invalid vhost-user message (request {0:})")]
+    InvalidVhostUserMessage(u32),
+    #[error("IOTLB translation fault at IOVA {0:#x} ({1:} bytes)")]
+    IotlbFault(u64, u64),
+    #[error("IOTLB mapping at IOVA {0:#x} ({1:} bytes) overlaps an existing one")]
+    IotlbConflict(u64, u64),
+    #[error("I/O error")]
+    Io(#[from] io::Error),
+    #[error("{context}")]
+    Device {
+        context: ErrorContext,
+        source: Box<Error>,
+    },
+}
+
+/// Failure classes, used to derive the process exit code.
+///
+/// # Variants
+///
+/// * `Config` - Invalid configuration or command line arguments.
+/// * `Kernel` - Failure of the Bao kernel interface (IOCTLs, guest memory mapping).
+/// * `Backend` - Failure of a device backend (vhost-user frontend, device emulation).
+/// * `System` - Failure of an operating system resource (files, epoll, eventfds, processes).
+/// * `Guest` - Invalid access performed by the guest driver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorClass {
+    Config,
+    Kernel,
+    Backend,
+    System,
+    Guest,
+}
+
+impl ErrorClass {
+    /// Returns the process exit code of the failure class.
+    ///
+    /// The codes follow `sysexits.h`:
+    ///
+    /// * `Config` - 78 (EX_CONFIG)
+    /// * `Kernel` - 69 (EX_UNAVAILABLE)
+    /// * `Backend` - 76 (EX_PROTOCOL)
+    /// * `System` - 71 (EX_OSERR)
+    /// * `Guest` - 65 (EX_DATAERR)
+    ///
+    /// # Returns
+    ///
+    /// * `i32` - The exit code.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ErrorClass::Config => 78,
+            ErrorClass::Kernel => 69,
+            ErrorClass::Backend => 76,
+            ErrorClass::System => 71,
+            ErrorClass::Guest => 65,
+        }
+    }
+}
+
+impl Error {
+    /// Tags the error with the identity of the device it originates from.
+    ///
+    /// Errors already tagged keep their original context.
+    ///
+    /// # Arguments
+    ///
+    /// * `context` - Identity of the device.
+    ///
+    /// # Returns
+    ///
+    /// * `Error` - The tagged error.
+    pub fn with_context(self, context: &ErrorContext) -> Self {
+        match self {
+            Error::Device { .. }
+            | Error::VhostFrontendError { .. }
+            | Error::VhostFrontendActivateError { .. } => self,
+            _ => Error::Device {
+                context: context.clone(),
+                source: Box::new(self),
+            },
+        }
+    }
+
+    /// Describes the error along with its causes.
+    ///
+    /// The message of an error leaves its source out, this joins the messages of the
+    /// whole chain, as reported to users.
+    ///
+    /// # Returns
+    ///
+    /// * `String` - The messages of the error and of its sources, separated by colons.
+    pub fn report(&self) -> String {
+        let mut report = self.to_string();
+        let mut source = std::error::Error::source(self);
+        while let Some(e) = source {
+            report.push_str(&format!(": {}", e));
+            source = e.source();
+        }
+        report
+    }
+
+    /// Wraps an error of the vhost-user frontend of a device.
+    ///
+    /// Nothing in this crate drives a backend through `vhost_user_frontend` yet (the
+    /// compatibility probe speaks the protocol directly), so these errors are only built
+    /// by frontends linking the crate.
+    ///
+    /// # Arguments
+    ///
+    /// * `context` - Identity of the device.
+    /// * `request` - Name of the vhost-user request in flight (see `summary::request_name`).
+    /// * `source` - The frontend error.
+    ///
+    /// # Returns
+    ///
+    /// * `Error` - The `VhostFrontendError`.
+    pub fn vhost_frontend(
+        context: &ErrorContext,
+        request: &'static str,
+        source: vhost_user_frontend::Error,
+    ) -> Self {
+        Error::VhostFrontendError {
+            context: context.clone(),
+            request,
+            source,
+        }
+    }
+
+    /// Wraps an activation error of the vhost-user frontend of a device.
+    ///
+    /// # Arguments
+    ///
+    /// * `context` - Identity of the device.
+    /// * `source` - The activation error.
+    ///
+    /// # Returns
+    ///
+    /// * `Error` - The `VhostFrontendActivateError`.
+    pub fn vhost_frontend_activate(
+        context: &ErrorContext,
+        source: vhost_user_frontend::ActivateError,
+    ) -> Self {
+        Error::VhostFrontendActivateError {
+            context: context.clone(),
+            source,
+        }
+    }
+
+    /// Returns the identity of the device the error originates from.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<&ErrorContext>` - The device identity, if the error was tagged.
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match self {
+            Error::Device { context, .. }
+            | Error::VhostFrontendError { context, .. }
+            | Error::VhostFrontendActivateError { context, .. } => Some(context),
+            _ => None,
+        }
+    }
+
+    /// Returns the failure class of the error.
+    ///
+    /// # Returns
+    ///
+    /// * `ErrorClass` - The failure class.
+    pub fn class(&self) -> ErrorClass {
+        match self {
+            Error::Device { source, .. } => source.class(),
+            Error::InvalidFrontendId(_)
+            | Error::BaoDevNotSupported(_)
+            | Error::InvalidString(_)
+            | Error::ParseFailure(_)
+            | Error::DeviceNotFound
+            | Error::UserNotFound(_)
+            | Error::GroupNotFound(_)
+            | Error::InvalidSchedPriority(..)
+            | Error::InvalidSchedCpus(_)
+            | Error::InsecureSecret(_)
+            | Error::DeviceExists(_)
+            | Error::InvalidDeviceFragment(..)
+            | Error::InvalidDtOverlay(..)
+            | Error::InvalidDtb(..)
+            | Error::UnknownDependency(..)
+            | Error::DependencyCycle(_)
+            | Error::InvalidPlatform(..)
+            | Error::InvalidControlCommand(_)
+            | Error::InvalidPauseMode(_)
+            | Error::InvalidFault(_)
+            | Error::InvalidUuid(_)
+            | Error::InvalidMacAddress(_)
+            | Error::QueueNotFound(..)
+            | Error::InvalidDeviceState(..)
+            | Error::ControlPermissionDenied(..)
+            | Error::ControlLineTooLong(_)
+            | Error::FrontendNotFound(_)
+            | Error::OutOfFrontendScope(_)
+            | Error::PlatformConflict(_)
+            | Error::InvalidConfig(_)
+            | Error::ConfigIo(..)
+            | Error::ConfigParse { .. }
+            | Error::InvalidOutputFormat(_)
+            | Error::InvalidSnapshot(_)
+            | Error::InvalidAppliedConfig(_)
+            | Error::BackendNotRegistered(_)
+            | Error::InvalidBackendOption(..)
+            | Error::InvalidResize(..)
+            | Error::CaptureRunning(_)
+            | Error::CaptureNotRunning(_) => ErrorClass::Config,
+            Error::HotplugNotSupported
+            | Error::ScriptMismatch { .. }
+            | Error::SelfTestFailed(_)
+            | Error::SnapshotNotSupported(_)
+            | Error::PauseNotSupported(_)
+            | Error::ResizeNotSupported(_)
+            | Error::CaptureNotSupported(_)
+            | Error::ConsoleLogNotSupported(_)
+            | Error::MigrationNotSupported(_)
+            | Error::QueueDumpNotSupported(_)
+            | Error::RequestNotCompleted(_)
+            | Error::InvalidVhostUserMessage(_) => ErrorClass::Backend,
+            Error::BaoIoctlError(..)
+            | Error::MmapGuestMemoryFailed
+            | Error::GuestRamFailed(..)
+            | Error::DtOverlayRejected(..) => ErrorClass::Kernel,
+            Error::VhostFrontendError { .. }
+            | Error::VhostFrontendActivateError { .. }
+            | Error::HandleIoEventFailed
+            | Error::BaoBusInvalidState
+            | Error::SpawnBackendFailed(..)
+            | Error::BackendConnectFailed(..)
+            | Error::BlockImageFailed(..)
+            | Error::BlockResizeFailed(..)
+            | Error::CanSocketFailed(..)
+            | Error::ConsolePtyFailed(..)
+            | Error::GpioRequestFailed(..)
+            | Error::I2cAdapterFailed(..)
+            | Error::TapFailed(..)
+            | Error::CaptureFailed(..)
+            | Error::BackendConnectTimedOut(_)
+            | Error::BackendNotReady(_)
+            | Error::BackendExited(..)
+            | Error::BackendPanicked(_)
+            | Error::BackendIncompatible(..) => ErrorClass::Backend,
+            Error::InvalidMmioAddr(..)
+            | Error::MmioLegacyNotSupported
+            | Error::IommuPlatformNotSupported
+            | Error::InvalidFeatureSel(_)
+            | Error::InvalidMmioDir(_)
+            | Error::InvalidIoReqDirection(_)
+            | Error::InvalidAccessWidth(_)
+            | Error::AttachTimedOut(_)
+            | Error::InvalidGuestAddress(..)
+            | Error::InvalidQueueSize(..)
+            | Error::IotlbFault(..)
+            | Error::IotlbConflict(..)
+            | Error::MmioBusError(_) => ErrorClass::Guest,
+            Error::EpollCreateFd(_)
+            | Error::RegisterExitEvent(_)
+            | Error::EpollWait(_)
+            | Error::EventFdWriteFailed(_)
+            | Error::OpenFdFailed(..)
+            | Error::DaemonizeFailed(..)
+            | Error::PidFileFailed(_)
+            | Error::SeccompError(_)
+            | Error::DropPrivilegesFailed(..)
+            | Error::SchedulingFailed(..)
+            | Error::SecretFailed(..)
+            | Error::WatchFailed(_)
+            | Error::DtOverlayFailed(..)
+            | Error::SuperviseFailed(..)
+            | Error::ControlFailed(..)
+            | Error::StatsFileFailed(_)
+            | Error::ControlBusy(_)
+            | Error::SnapshotFailed(_)
+            | Error::AppliedConfigFailed(_)
+            | Error::HandoffFailed(..)
+            | Error::InvalidHandoff(_)
+            | Error::LandlockError(_)
+            | Error::AuditLogFailed(_)
+            | Error::InvalidAuditLog
+            | Error::RecordingFailed(_)
+            | Error::InvalidRecording
+            | Error::TraceFailed(_)
+            | Error::LogFileFailed(..)
+            | Error::InvalidEncoding(..)
+            | Error::ManagementServerFailed(_)
+            | Error::OtlpExportFailed(_)
+            | Error::Io(_) => ErrorClass::System,
+        }
+    }
+
+    /// Returns the errno value that best describes the error.
+    ///
+    /// Errors wrapping an OS error return its errno; the remaining ones are mapped to
+    /// the closest errno value.
+    ///
+    /// # Returns
+    ///
+    /// * `i32` - The (positive) errno value.
+    pub fn errno(&self) -> i32 {
+        let os_error = match self {
+            Error::Device { source, .. } => return source.errno(),
+            Error::BaoIoctlError(e, _)
+            | Error::EpollCreateFd(e)
+            | Error::RegisterExitEvent(e)
+            | Error::EpollWait(e)
+            | Error::EventFdWriteFailed(e)
+            | Error::OpenFdFailed(_, e)
+            | Error::DaemonizeFailed(_, e)
+            | Error::PidFileFailed(e)
+            | Error::DropPrivilegesFailed(_, e)
+            | Error::SchedulingFailed(_, e)
+            | Error::SecretFailed(_, e)
+            | Error::ConfigIo(_, e)
+            | Error::WatchFailed(e)
+            | Error::DtOverlayFailed(_, e)
+            | Error::GuestRamFailed(_, e)
+            | Error::SuperviseFailed(_, e)
+            | Error::ControlFailed(_, e)
+            | Error::StatsFileFailed(e)
+            | Error::SnapshotFailed(e)
+            | Error::AppliedConfigFailed(e)
+            | Error::HandoffFailed(_, e)
+            | Error::SpawnBackendFailed(_, e)
+            | Error::BackendConnectFailed(_, e)
+            | Error::BlockImageFailed(_, e)
+            | Error::BlockResizeFailed(_, e)
+            | Error::CanSocketFailed(_, e)
+            | Error::ConsolePtyFailed(_, e)
+            | Error::GpioRequestFailed(_, e)
+            | Error::I2cAdapterFailed(_, e)
+            | Error::TapFailed(_, e)
+            | Error::CaptureFailed(_, e)
+            | Error::AuditLogFailed(e)
+            | Error::RecordingFailed(e)
+            | Error::TraceFailed(e)
+            | Error::LogFileFailed(_, e)
+            | Error::Io(e) => e.raw_os_error(),
+            _ => None,
+        };
+        if let Some(errno) = os_error {
+            return errno;
+        }
+        match self {
+            Error::BaoDevNotSupported(_)
+            | Error::MmioLegacyNotSupported
+            | Error::IommuPlatformNotSupported
+            | Error::HotplugNotSupported
+            | Error::SnapshotNotSupported(_)
+            | Error::PauseNotSupported(_)
+            | Error::ResizeNotSupported(_)
+            | Error::CaptureNotSupported(_)
+            | Error::ConsoleLogNotSupported(_)
+            | Error::MigrationNotSupported(_)
+            | Error::QueueDumpNotSupported(_)
+            | Error::BackendIncompatible(..) => libc::ENOTSUP,
+            Error::DeviceNotFound
+            | Error::FrontendNotFound(_)
+            | Error::MmioBusError(vm_device::bus::Error::DeviceNotFound) => libc::ENODEV,
+            Error::UserNotFound(_) | Error::GroupNotFound(_) | Error::BackendNotRegistered(_) => {
+                libc::ENOENT
+            }
+            Error::MmapGuestMemoryFailed => libc::ENOMEM,
+            Error::InvalidGuestAddress(..) | Error::IotlbFault(..) => libc::EFAULT,
+            Error::SeccompError(_) | Error::LandlockError(_) => libc::EPERM,
+            Error::InsecureSecret(_)
+            | Error::ControlPermissionDenied(..)
+            | Error::OutOfFrontendScope(_) => libc::EACCES,
+            Error::DeviceExists(_) => libc::EEXIST,
+            Error::BaoBusInvalidState
+            | Error::InvalidDeviceState(..)
+            | Error::CaptureRunning(_)
+            | Error::ControlBusy(_) => libc::EBUSY,
+            Error::BackendNotReady(_)
+            | Error::BackendConnectTimedOut(_)
+            | Error::AttachTimedOut(_) => libc::ETIMEDOUT,
+            _ => match self.class() {
+                ErrorClass::Config | ErrorClass::Guest => libc::EINVAL,
+                _ => libc::EIO,
+            },
+        }
+    }
+
+    /// Returns the process exit code of the error.
+    ///
+    /// # Returns
+    ///
+    /// * `i32` - The exit code (see `ErrorClass::exit_code`).
+    pub fn exit_code(&self) -> i32 {
+        self.class().exit_code()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_errno_and_exit_code() {
+        let err = Error::OpenFdFailed("/dev/bao", io::Error::from_raw_os_error(libc::EACCES));
+        assert_eq!(err.errno(), libc::EACCES);
+        assert_eq!(err.exit_code(), 71);
+
+        let err = Error::BaoIoctlError(io::Error::from_raw_os_error(libc::ENOTTY), "attach");
+        assert_eq!(err.class(), ErrorClass::Kernel);
+        assert_eq!(err.errno(), libc::ENOTTY);
+        assert_eq!(err.exit_code(), 69);
+
+        let err = Error::BaoDevNotSupported("net".to_string());
+        assert_eq!(err.errno(), libc::ENOTSUP);
+        assert_eq!(err.exit_code(), 78);
+
+        let err = Error::InvalidMmioDir(3);
+        assert_eq!(err.errno(), libc::EINVAL);
+        assert_eq!(err.exit_code(), 65);
+
+        assert_eq!(Error::HandleIoEventFailed.errno(), libc::EIO);
+        assert_eq!(Error::HandleIoEventFailed.exit_code(), 76);
+    }
+
+    #[test]
+    fn test_error_context() {
+        let context = ErrorContext::new(VmId(0), VmId(1), "device1");
+        let result: Result<()> = Err(Error::EventFdWriteFailed(io::Error::from_raw_os_error(
+            libc::EAGAIN,
+        )));
+        let err = result.with_context(&context).unwrap_err();
+        assert_eq!(err.context(), Some(&context));
+        assert_eq!(err.to_string(), "frontend0/guest1/device1");
+        assert!(err
+            .report()
+            .starts_with("frontend0/guest1/device1: Failed to kick backend: "));
+        assert_eq!(err.errno(), libc::EAGAIN);
+        assert_eq!(err.class(), ErrorClass::System);
+
+        // The original context is preserved
+        let err = err.with_context(&ErrorContext::new(VmId(0), VmId(0), "device0"));
+        assert_eq!(err.context(), Some(&context));
+    }
+
+    #[test]
+    fn test_error_conversions_and_sources() {
+        use std::error::Error as _;
+
+        fn parse(value: &str) -> Result<u32> {
+            Ok(value.parse::<u32>()?)
+        }
+        assert!(matches!(parse("bao"), Err(Error::ParseFailure(_))));
+
+        fn read(path: &str) -> Result<Vec<u8>> {
+            Ok(std::fs::read(path)?)
+        }
+        let err = read("/nonexistent/bao").unwrap_err();
+        assert!(matches!(err, Error::Io(_)));
+        assert_eq!(err.errno(), libc::ENOENT);
+
+        let err = Error::OpenFdFailed("/dev/bao", io::Error::from_raw_os_error(libc::ENOENT));
+        assert!(err.source().is_some());
+        // The cause is only reported once, after the message
+        let cause = io::Error::from_raw_os_error(libc::ENOENT).to_string();
+        assert_eq!(
+            err.report(),
+            format!("Failed to open the file descriptor \"/dev/bao\": {}", cause)
+        );
+        assert!(Error::MmioBusError(vm_device::bus::Error::DeviceNotFound)
+            .source()
+            .is_some());
+        let err = err.with_context(&ErrorContext::new(VmId(0), VmId(0), "device0"));
+        assert!(err.source().unwrap().source().is_some());
+
+        // The vhost-user frontend errors carry the device and the underlying error
+        let context = ErrorContext::new(VmId(0), VmId(1), "gpio0");
+        let err = Error::vhost_frontend_activate(
+            &context,
+            vhost_user_frontend::ActivateError::BadActivate,
+        );
+        let source = err.source().unwrap().to_string();
+        assert_eq!(
+            err.to_string(),
+            "Vhost user frontend failed to activate frontend0/guest1/gpio0"
+        );
+        assert_eq!(
+            err.report(),
+            format!(
+                "Vhost user frontend failed to activate frontend0/guest1/gpio0: {}",
+                source
+            )
+        );
+        let err = err.with_context(&ErrorContext::new(VmId(0), VmId(0), "device0"));
+        assert_eq!(err.context(), Some(&context));
+        assert_eq!(err.class(), ErrorClass::Backend);
+    }
 }
@@ -0,0 +1,106 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao device events.
+//!
+//! Besides the device state changes, the frontend publishes what happens to the devices
+//! (a backend going away, the guest resetting a device, a watchdog expiring) as events,
+//! so orchestrators can react to them instead of polling the counters. The control socket
+//! streams them as JSON lines to the clients that `subscribe`.
+
+#![allow(dead_code)]
+
+use super::management::DeviceState;
+use super::stats_file::json_string;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Enum representing a device event.
+///
+/// # Variants
+///
+/// * `DeviceReady` - The device is serving the guest.
+/// * `StateChanged` - The device changed state.
+/// * `BackendDisconnected` - The device backend closed its connection.
+/// * `GuestReset` - The guest reset the device (wrote 0 to its status register).
+/// * `WatchdogFired` - The device did not complete a request in time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceEvent {
+    DeviceReady(String),
+    StateChanged(String, DeviceState),
+    BackendDisconnected(String),
+    GuestReset(String),
+    WatchdogFired(String),
+}
+
+impl DeviceEvent {
+    /// Returns the name of the device the event is about.
+    pub fn device(&self) -> &str {
+        match self {
+            DeviceEvent::DeviceReady(name)
+            | DeviceEvent::StateChanged(name, _)
+            | DeviceEvent::BackendDisconnected(name)
+            | DeviceEvent::GuestReset(name)
+            | DeviceEvent::WatchdogFired(name) => name,
+        }
+    }
+
+    /// Encodes the event as a single-line JSON object.
+    ///
+    /// # Returns
+    ///
+    /// * `String` - The object (`event`, `device`, `timestamp_ns`, and `state` for the
+    ///   state changes).
+    pub fn to_json(&self) -> String {
+        let timestamp_ns = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let state = match self {
+            DeviceEvent::StateChanged(_, state) => {
+                format!(",\"state\":{}", json_string(&state.to_string()))
+            }
+            _ => String::new(),
+        };
+        format!(
+            "{{\"event\":{},\"device\":{}{},\"timestamp_ns\":{}}}",
+            json_string(&self.to_string()),
+            json_string(self.device()),
+            state,
+            timestamp_ns
+        )
+    }
+}
+
+impl fmt::Display for DeviceEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let event = match self {
+            DeviceEvent::DeviceReady(_) => "device-ready",
+            DeviceEvent::StateChanged(..) => "state-changed",
+            DeviceEvent::BackendDisconnected(_) => "backend-disconnected",
+            DeviceEvent::GuestReset(_) => "guest-reset",
+            DeviceEvent::WatchdogFired(_) => "watchdog-fired",
+        };
+        write!(f, "{}", event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_event_json() {
+        let json = DeviceEvent::StateChanged("rng0".to_string(), DeviceState::Paused).to_json();
+        assert!(json.starts_with(
+            "{\"event\":\"state-changed\",\"device\":\"rng0\",\"state\":\"paused\",\"timestamp_ns\":"
+        ));
+        let json = DeviceEvent::WatchdogFired("i2c0".to_string()).to_json();
+        assert!(
+            json.starts_with("{\"event\":\"watchdog-fired\",\"device\":\"i2c0\",\"timestamp_ns\":")
+        );
+        assert!(json.ends_with('}') && !json.contains('\n'));
+    }
+}
@@ -0,0 +1,208 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao weighted fair scheduling between devices.
+//!
+//! When several devices share a worker thread (e.g. on a single-core host), serving their
+//! pending work in arrival order lets a chatty device (a console flooding its queue)
+//! delay the completions of the others. The worker instead queues the work of each device
+//! apart and serves them in weighted round-robin: in each round, a device is served up to
+//! its `weight` items before the next one gets its turn, so every device with pending work
+//! gets its share, whatever the others queued.
+
+#![allow(dead_code)]
+
+use super::defines::BAO_FAIR_DEFAULT_WEIGHT;
+use super::error::{Error, Result};
+use super::types::{ConfigDevice, DeviceId};
+use std::collections::VecDeque;
+
+/// Struct representing the pending work of a device.
+///
+/// # Attributes
+///
+/// * `id` - Device ID.
+/// * `weight` - Items served per round.
+/// * `pending` - Pending work, in arrival order.
+/// * `served` - Items served since the round started.
+struct FairQueue<T> {
+    id: DeviceId,
+    weight: u32,
+    pending: VecDeque<T>,
+    served: u32,
+}
+
+/// Struct representing the weighted round-robin scheduler of a shared worker.
+///
+/// # Attributes
+///
+/// * `queues` - Pending work of the devices, in configuration order.
+/// * `current` - Index of the device whose turn it is.
+pub struct FairScheduler<T> {
+    queues: Vec<FairQueue<T>>,
+    current: usize,
+}
+
+impl<T> FairScheduler<T> {
+    /// Creates the scheduler of the devices sharing a worker.
+    ///
+    /// # Arguments
+    ///
+    /// * `devices` - The devices, weighted by their `weight` (1 if unset, at least 1).
+    ///
+    /// # Returns
+    ///
+    /// * `FairScheduler<T>` - The scheduler (without pending work).
+    pub fn new<'a>(devices: impl IntoIterator<Item = &'a ConfigDevice>) -> Self {
+        let queues = devices
+            .into_iter()
+            .map(|device| FairQueue {
+                id: device.id,
+                weight: device.weight.unwrap_or(BAO_FAIR_DEFAULT_WEIGHT).max(1),
+                pending: VecDeque::new(),
+                served: 0,
+            })
+            .collect();
+        FairScheduler { queues, current: 0 }
+    }
+
+    /// Queues work for a device.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Device ID.
+    /// * `item` - The work (e.g. a queue notification).
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the device shares the worker, `DeviceNotFound` otherwise.
+    pub fn push(&mut self, id: DeviceId, item: T) -> Result<()> {
+        let queue = self
+            .queues
+            .iter_mut()
+            .find(|queue| queue.id == id)
+            .ok_or(Error::DeviceNotFound)?;
+        queue.pending.push_back(item);
+        Ok(())
+    }
+
+    /// Takes the next work to serve.
+    ///
+    /// A device keeps its turn until it served its weight or ran out of work; the next
+    /// device with pending work then starts its own turn.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<(DeviceId, T)>` - The device and its work, or None if nothing is pending.
+    pub fn pop(&mut self) -> Option<(DeviceId, T)> {
+        for _ in 0..=self.queues.len() {
+            let queue = self.queues.get_mut(self.current)?;
+            if queue.served < queue.weight {
+                if let Some(item) = queue.pending.pop_front() {
+                    queue.served += 1;
+                    return Some((queue.id, item));
+                }
+            }
+            queue.served = 0;
+            self.current = (self.current + 1) % self.queues.len();
+        }
+        None
+    }
+
+    /// Returns the amount of pending work.
+    pub fn len(&self) -> usize {
+        self.queues.iter().map(|queue| queue.pending.len()).sum()
+    }
+
+    /// Checks if no work is pending.
+    pub fn is_empty(&self) -> bool {
+        self.queues.iter().all(|queue| queue.pending.is_empty())
+    }
+
+    /// Returns the amount of pending work of a device.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Device ID.
+    ///
+    /// # Returns
+    ///
+    /// * `usize` - The pending work (0 if the device does not share the worker).
+    pub fn pending(&self, id: DeviceId) -> usize {
+        self.queues
+            .iter()
+            .find(|queue| queue.id == id)
+            .map_or(0, |queue| queue.pending.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fair_scheduler() {
+        let device = |id, weight| ConfigDevice {
+            id: DeviceId(id),
+            weight,
+            ..Default::default()
+        };
+        let devices = [device(0, None), device(1, Some(3)), device(2, Some(0))];
+        let mut scheduler = FairScheduler::new(&devices);
+        // Looking for work ends the turn of the idle device
+        assert_eq!(scheduler.pop(), None);
+
+        // The console floods its queue before the others kick
+        for i in 0..20 {
+            scheduler.push(DeviceId(0), i).unwrap();
+        }
+        for i in 0..6 {
+            scheduler.push(DeviceId(1), 100 + i).unwrap();
+        }
+        scheduler.push(DeviceId(2), 200).unwrap();
+        assert_eq!(scheduler.len(), 27);
+        assert_eq!(scheduler.pending(DeviceId(1)), 6);
+        assert!(matches!(
+            scheduler.push(DeviceId(3), 0),
+            Err(Error::DeviceNotFound)
+        ));
+
+        // Each round serves every device up to its weight (a zero weight counts as 1)
+        let served = (0..12)
+            .map(|_| scheduler.pop().unwrap())
+            .map(|(id, item)| (id.raw(), item))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            served,
+            [
+                (1, 100),
+                (1, 101),
+                (1, 102),
+                (2, 200),
+                (0, 0),
+                (1, 103),
+                (1, 104),
+                (1, 105),
+                (0, 1),
+                (0, 2),
+                (0, 3),
+                (0, 4)
+            ]
+        );
+
+        // Once alone, the console is served until it runs out of work
+        assert_eq!(scheduler.len(), 15);
+        assert!((0..15).all(|_| scheduler.pop().unwrap().0 == DeviceId(0)));
+        assert!(scheduler.is_empty());
+        assert_eq!(scheduler.pop(), None);
+
+        // A device kicking again takes its turn in the next round
+        scheduler.push(DeviceId(2), 201).unwrap();
+        scheduler.push(DeviceId(0), 20).unwrap();
+        assert_eq!(scheduler.pop(), Some((DeviceId(2), 201)));
+        assert_eq!(scheduler.pop(), Some((DeviceId(0), 20)));
+        assert!(FairScheduler::<u32>::new([]).pop().is_none());
+    }
+}
@@ -0,0 +1,295 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao fake vhost-user backend.
+//!
+//! A vhost-user backend (see the `vhost_backend` module) whose device echoes every
+//! available descriptor chain (the readable buffers are copied into the writable ones),
+//! so the frontend/backend handshake can be exercised without an external daemon.
+
+#![allow(dead_code)]
+
+use super::backend::InProcessBackend;
+use super::error::Result;
+use super::vhost_backend::{VhostUserBackend, VirtioDevice};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+pub use super::vhost_backend::{
+    recv_message, send_message, BackendState as FakeBackendState, MemoryRegion, VhostUserMessage,
+    VringState,
+};
+
+/// Struct representing a device echoing its descriptor chains.
+///
+/// # Attributes
+///
+/// * `features` - Virtio features offered.
+/// * `num_queues` - Number of virtqueues.
+struct Echo {
+    features: u64,
+    num_queues: usize,
+}
+
+impl VirtioDevice for Echo {
+    fn features(&self) -> u64 {
+        self.features
+    }
+
+    fn num_queues(&self) -> usize {
+        self.num_queues
+    }
+
+    fn process(&mut self, readable: &[u8], writable: &mut [u8]) -> u32 {
+        let count = readable.len().min(writable.len());
+        writable[..count].copy_from_slice(&readable[..count]);
+        count as u32
+    }
+}
+
+/// Struct representing a fake vhost-user backend.
+pub struct FakeBackend(VhostUserBackend);
+
+impl FakeBackend {
+    /// Creates a fake backend.
+    ///
+    /// # Arguments
+    ///
+    /// * `features` - Virtio features offered (`VHOST_USER_F_PROTOCOL_FEATURES` is always
+    ///   offered).
+    /// * `num_queues` - Number of virtqueues.
+    ///
+    /// # Returns
+    ///
+    /// * `FakeBackend` - The fake backend.
+    pub fn new(features: u64, num_queues: usize) -> Self {
+        FakeBackend(VhostUserBackend::new(Box::new(Echo {
+            features,
+            num_queues,
+        })))
+    }
+
+    /// Returns the state of the backend, updated while it is served.
+    ///
+    /// # Returns
+    ///
+    /// * `Arc<Mutex<FakeBackendState>>` - The backend state.
+    pub fn state(&self) -> Arc<Mutex<FakeBackendState>> {
+        self.0.state()
+    }
+}
+
+impl InProcessBackend for FakeBackend {
+    fn serve(self: Box<Self>, socket: &Path) -> Result<()> {
+        Box::new(self.0).serve(socket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::defines::*;
+    use crate::vhost_backend::{map_region, read_u64, Queues};
+    use std::fs::{self, File};
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+    use std::os::unix::net::UnixStream;
+    use std::ptr;
+    use std::thread;
+    use std::time::Duration;
+    use vmm_sys_util::eventfd::EventFd;
+
+    fn send(stream: &UnixStream, request: u32, payload: &[u8], fds: &[RawFd]) {
+        send_message(stream, request, VHOST_USER_VERSION, payload, fds).unwrap();
+    }
+
+    fn get(stream: &UnixStream, request: u32, payload: &[u8]) -> Vec<u8> {
+        send(stream, request, payload, &[]);
+        let (reply, flags, payload, _) = recv_message(stream).unwrap().unwrap();
+        assert_eq!(reply, request);
+        assert_ne!(flags & VHOST_USER_REPLY_MASK, 0);
+        payload
+    }
+
+    fn vring_payload(index: u32, value: u32) -> Vec<u8> {
+        [index.to_le_bytes(), value.to_le_bytes()].concat()
+    }
+
+    #[test]
+    fn test_fake_backend_handshake_and_echo() {
+        const USERSPACE_ADDR: u64 = 0x7f00_0000_0000;
+        const MEMORY_SIZE: usize = 0x10000;
+
+        let socket = std::env::temp_dir().join(format!("bao-fake-{}.sock", std::process::id()));
+        let backend = FakeBackend::new(1 << 32, 1);
+        let state = backend.state();
+        let server = {
+            let socket = socket.clone();
+            thread::spawn(move || Box::new(backend).serve(&socket))
+        };
+        let stream = loop {
+            match UnixStream::connect(&socket) {
+                Ok(stream) => break stream,
+                Err(_) => thread::sleep(Duration::from_millis(10)),
+            }
+        };
+
+        // Guest memory (descriptors at 0x0, available ring at 0x1000, used ring at 0x2000)
+        // SAFETY: The name is a valid C string.
+        let memfd = unsafe { libc::memfd_create(c"bao-fake".as_ptr(), libc::MFD_CLOEXEC) };
+        assert!(memfd >= 0);
+        // SAFETY: `memfd` is a valid file descriptor owned by this test.
+        let memfd = unsafe { OwnedFd::from_raw_fd(memfd) };
+        File::from(memfd.try_clone().unwrap())
+            .set_len(MEMORY_SIZE as u64)
+            .unwrap();
+        let region = MemoryRegion {
+            guest_phys_addr: 0,
+            memory_size: MEMORY_SIZE as u64,
+            userspace_addr: USERSPACE_ADDR,
+            mmap_offset: 0,
+        };
+        let guest = map_region(region, &memfd).unwrap();
+
+        // Handshake
+        send(&stream, VHOST_USER_SET_OWNER, &[], &[]);
+        let features = read_u64(&get(&stream, VHOST_USER_GET_FEATURES, &[]), 0).unwrap();
+        assert_eq!(features, 1 << 32 | VHOST_USER_F_PROTOCOL_FEATURES);
+        send(
+            &stream,
+            VHOST_USER_SET_FEATURES,
+            &features.to_le_bytes(),
+            &[],
+        );
+        let protocol = read_u64(&get(&stream, VHOST_USER_GET_PROTOCOL_FEATURES, &[]), 0).unwrap();
+        assert_ne!(protocol & VHOST_USER_PROTOCOL_F_REPLY_ACK, 0);
+        send(
+            &stream,
+            VHOST_USER_SET_PROTOCOL_FEATURES,
+            &protocol.to_le_bytes(),
+            &[],
+        );
+        assert_eq!(
+            get(&stream, VHOST_USER_GET_QUEUE_NUM, &[]),
+            1u64.to_le_bytes()
+        );
+
+        let mut mem_table = vring_payload(1, 0);
+        for field in [0, MEMORY_SIZE as u64, USERSPACE_ADDR, 0] {
+            mem_table.extend_from_slice(&field.to_le_bytes());
+        }
+        send_message(
+            &stream,
+            VHOST_USER_SET_MEM_TABLE,
+            VHOST_USER_VERSION | VHOST_USER_NEED_REPLY_MASK,
+            &mem_table,
+            &[memfd.as_raw_fd()],
+        )
+        .unwrap();
+        let (_, _, ack, _) = recv_message(&stream).unwrap().unwrap();
+        assert_eq!(ack, 0u64.to_le_bytes());
+
+        send(&stream, VHOST_USER_SET_VRING_NUM, &vring_payload(0, 8), &[]);
+        let mut vring_addr = vring_payload(0, 0);
+        for addr in [
+            USERSPACE_ADDR,
+            USERSPACE_ADDR + 0x2000,
+            USERSPACE_ADDR + 0x1000,
+            0,
+        ] {
+            vring_addr.extend_from_slice(&addr.to_le_bytes());
+        }
+        send(&stream, VHOST_USER_SET_VRING_ADDR, &vring_addr, &[]);
+        send(
+            &stream,
+            VHOST_USER_SET_VRING_BASE,
+            &vring_payload(0, 0),
+            &[],
+        );
+        let kick = EventFd::new(0).unwrap();
+        let call = EventFd::new(0).unwrap();
+        send(
+            &stream,
+            VHOST_USER_SET_VRING_CALL,
+            &0u64.to_le_bytes(),
+            &[call.as_raw_fd()],
+        );
+        send(
+            &stream,
+            VHOST_USER_SET_VRING_KICK,
+            &0u64.to_le_bytes(),
+            &[kick.as_raw_fd()],
+        );
+        send(
+            &stream,
+            VHOST_USER_SET_VRING_ENABLE,
+            &vring_payload(0, 1),
+            &[],
+        );
+
+        // Chain a readable buffer (0x4000) and a writable one (0x5000), then kick
+        // SAFETY: Every access is within the guest memory mapping.
+        unsafe {
+            ptr::copy_nonoverlapping(b"hello".as_ptr(), guest.addr.add(0x4000), 5);
+            let desc = guest.addr;
+            ptr::write_unaligned(desc as *mut u64, 0x4000);
+            ptr::write_unaligned(desc.add(8) as *mut u32, 5);
+            ptr::write_unaligned(desc.add(12) as *mut u16, VRING_DESC_F_NEXT);
+            ptr::write_unaligned(desc.add(14) as *mut u16, 1);
+            ptr::write_unaligned(desc.add(16) as *mut u64, 0x5000);
+            ptr::write_unaligned(desc.add(24) as *mut u32, 16);
+            ptr::write_unaligned(desc.add(28) as *mut u16, VRING_DESC_F_WRITE);
+            ptr::write_unaligned(guest.addr.add(0x1004) as *mut u16, 0);
+            ptr::write_volatile(guest.addr.add(0x1002) as *mut u16, 1);
+        }
+        kick.write(1).unwrap();
+        assert_eq!(call.read().unwrap(), 1);
+
+        // SAFETY: Every access is within the guest memory mapping.
+        unsafe {
+            assert_eq!(ptr::read_volatile(guest.addr.add(0x2002) as *const u16), 1);
+            assert_eq!(ptr::read_unaligned(guest.addr.add(0x2004) as *const u32), 0);
+            assert_eq!(ptr::read_unaligned(guest.addr.add(0x2008) as *const u32), 5);
+            assert_eq!(
+                std::slice::from_raw_parts(guest.addr.add(0x5000), 5),
+                b"hello"
+            );
+        }
+
+        // Stop the queue and disconnect
+        let base = get(&stream, VHOST_USER_GET_VRING_BASE, &vring_payload(0, 0));
+        assert_eq!(base, vring_payload(0, 1));
+        drop(stream);
+        server.join().unwrap().unwrap();
+
+        let state = state.lock().unwrap();
+        assert!(state.owner);
+        assert_eq!(state.acked_features, features);
+        assert_eq!(state.regions, vec![region]);
+        assert_eq!(state.vrings[0].num, 8);
+        assert_eq!(state.served, 1);
+        let dump = state.dump_queue(0).unwrap();
+        assert_eq!(
+            (dump.size, dump.desc_addr, dump.avail_addr, dump.used_addr),
+            (
+                8,
+                USERSPACE_ADDR,
+                USERSPACE_ADDR + 0x1000,
+                USERSPACE_ADDR + 0x2000
+            )
+        );
+        assert_eq!(
+            (
+                dump.avail_idx,
+                dump.last_avail,
+                dump.used_idx,
+                dump.processed
+            ),
+            (1, 1, 1, 1)
+        );
+        assert_eq!(dump.heads, [0]);
+        assert_eq!(state.dump_queue(1), None);
+        fs::remove_file(&socket).unwrap();
+    }
+}
@@ -0,0 +1,358 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao request-level fault injection.
+//!
+//! Guest drivers are rarely exercised against a misbehaving device. With the
+//! `fault-injection` feature, the faults of each device are set at runtime through the
+//! control socket (e.g. `fault rng0 drop-every 10`): dropping every Nth completion
+//! interrupt, delaying the completion interrupts, corrupting the value read from a
+//! register, or simulating a backend disconnect. The faults of a device stay in place
+//! until they are cleared (`fault rng0 clear`).
+
+#![allow(dead_code)]
+
+use super::defines::BAO_IO_READ;
+use super::error::{Error, Result};
+use super::hypervisor::Hypervisor;
+use super::types::{BaoIoEventFd, BaoIoRequest, BaoIrqFd};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Struct representing the corruption of the reads of a register.
+///
+/// # Attributes
+///
+/// * `reg_off` - Register offset.
+/// * `mask` - Bits flipped in the value returned to the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RegisterCorruption {
+    pub reg_off: u64,
+    pub mask: u64,
+}
+
+/// Struct representing the faults injected into a device.
+///
+/// # Attributes
+///
+/// * `drop_every` - Every Nth completion interrupt is dropped (disabled if 0).
+/// * `delay_us` - Delay of the completion interrupts (in microseconds).
+/// * `corrupt` - Corrupted register reads (disabled if None).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct FaultPlan {
+    pub drop_every: u64,
+    pub delay_us: u64,
+    pub corrupt: Option<RegisterCorruption>,
+}
+
+/// Enum representing a fault injection request.
+///
+/// # Variants
+///
+/// * `DropEvery` - `drop-every N`: drops every Nth completion interrupt (0 to stop).
+/// * `Delay` - `delay US`: delays the completion interrupts (0 to stop).
+/// * `Corrupt` - `corrupt REG MASK`: flips the bits of MASK in the reads of a register.
+/// * `Disconnect` - `disconnect`: fails the device as if its backend disconnected.
+/// * `Clear` - `clear`: removes the faults of the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    DropEvery(u64),
+    Delay(u64),
+    Corrupt(u64, u64),
+    Disconnect,
+    Clear,
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Fault::DropEvery(n) => write!(f, "drop-every {}", n),
+            Fault::Delay(us) => write!(f, "delay {}", us),
+            Fault::Corrupt(reg_off, mask) => write!(f, "corrupt {:#x} {:#x}", reg_off, mask),
+            Fault::Disconnect => write!(f, "disconnect"),
+            Fault::Clear => write!(f, "clear"),
+        }
+    }
+}
+
+impl FromStr for Fault {
+    type Err = Error;
+
+    fn from_str(fault: &str) -> Result<Self> {
+        let invalid = || Error::InvalidFault(fault.trim().to_string());
+        // Register offsets and masks are usually given in hexadecimal
+        let number = |word: &str| match word.strip_prefix("0x") {
+            Some(hex) => u64::from_str_radix(hex, 16).map_err(|_| invalid()),
+            None => word.parse().map_err(|_| invalid()),
+        };
+        let words = fault.split_whitespace().collect::<Vec<_>>();
+        match words.as_slice() {
+            ["drop-every", n] => Ok(Fault::DropEvery(number(n)?)),
+            ["delay", us] => Ok(Fault::Delay(number(us)?)),
+            ["corrupt", reg_off, mask] => Ok(Fault::Corrupt(number(reg_off)?, number(mask)?)),
+            ["disconnect"] => Ok(Fault::Disconnect),
+            ["clear"] => Ok(Fault::Clear),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Struct representing the fault injector of a device, shared with the management
+/// interface.
+///
+/// # Attributes
+///
+/// * `plan` - Faults injected into the device.
+/// * `interrupts` - Completion interrupts seen since `drop_every` was set.
+/// * `injected` - Number of faults injected.
+#[derive(Debug, Default)]
+pub struct FaultInjector {
+    plan: Mutex<FaultPlan>,
+    interrupts: AtomicU64,
+    injected: AtomicU64,
+}
+
+impl FaultInjector {
+    /// Returns the faults injected into the device.
+    pub fn plan(&self) -> FaultPlan {
+        *self.plan.lock().unwrap()
+    }
+
+    /// Returns the number of faults injected.
+    pub fn injected(&self) -> u64 {
+        self.injected.load(Ordering::Relaxed)
+    }
+
+    /// Changes the faults injected into the device.
+    ///
+    /// `Disconnect` does not change the plan (see `Management::inject_fault`).
+    ///
+    /// # Arguments
+    ///
+    /// * `fault` - The fault injection request.
+    pub fn apply(&self, fault: Fault) {
+        let mut plan = self.plan.lock().unwrap();
+        match fault {
+            Fault::DropEvery(n) => {
+                plan.drop_every = n;
+                self.interrupts.store(0, Ordering::Relaxed);
+            }
+            Fault::Delay(us) => plan.delay_us = us,
+            Fault::Corrupt(reg_off, mask) => {
+                plan.corrupt = Some(RegisterCorruption { reg_off, mask });
+            }
+            Fault::Disconnect => {}
+            Fault::Clear => *plan = FaultPlan::default(),
+        }
+    }
+
+    /// Accounts a completion interrupt.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - True if the interrupt must be dropped.
+    fn drop_interrupt(&self) -> bool {
+        let drop_every = self.plan().drop_every;
+        let count = self.interrupts.fetch_add(1, Ordering::Relaxed) + 1;
+        let dropped = drop_every != 0 && count.is_multiple_of(drop_every);
+        if dropped {
+            crate::bao_inc!(self.injected);
+        }
+        dropped
+    }
+
+    /// Returns the delay of the completion interrupts, if any.
+    fn delay(&self) -> Option<Duration> {
+        let delay_us = self.plan().delay_us;
+        (delay_us != 0).then(|| {
+            crate::bao_inc!(self.injected);
+            Duration::from_micros(delay_us)
+        })
+    }
+
+    /// Corrupts the completion of a request, if it reads the corrupted register.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The completed request.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<BaoIoRequest>` - The corrupted request, or None if it is left alone.
+    fn corrupt(&self, req: &BaoIoRequest) -> Option<BaoIoRequest> {
+        let corrupt = self.plan().corrupt?;
+        if req.op != BAO_IO_READ || req.reg_off != corrupt.reg_off {
+            return None;
+        }
+        crate::bao_inc!(self.injected);
+        Some(BaoIoRequest {
+            value: req.value ^ corrupt.mask,
+            ..*req
+        })
+    }
+}
+
+/// Struct representing a hypervisor injecting the faults of a device.
+///
+/// Delayed interrupts hold the calling thread, as a slow backend would.
+///
+/// # Attributes
+///
+/// * `inner` - The hypervisor.
+/// * `virtio_id` - Virtio ID of the device.
+/// * `injector` - The fault injector of the device.
+pub struct FaultInjectionHypervisor<H: Hypervisor> {
+    inner: H,
+    virtio_id: u64,
+    injector: Arc<FaultInjector>,
+}
+
+impl<H: Hypervisor> FaultInjectionHypervisor<H> {
+    /// Injects the faults of a device.
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - The hypervisor.
+    /// * `virtio_id` - Virtio ID of the device.
+    /// * `injector` - The fault injector of the device (see `DeviceRegistry::fault_injector`).
+    ///
+    /// # Returns
+    ///
+    /// * `FaultInjectionHypervisor` - The fault injecting hypervisor.
+    pub fn new(inner: H, virtio_id: u64, injector: Arc<FaultInjector>) -> Self {
+        FaultInjectionHypervisor {
+            inner,
+            virtio_id,
+            injector,
+        }
+    }
+}
+
+impl<H: Hypervisor> Hypervisor for FaultInjectionHypervisor<H> {
+    fn attach_client(&self) -> Result<()> {
+        self.inner.attach_client()
+    }
+
+    fn wait_request(&self, timeout: Duration) -> Result<bool> {
+        self.inner.wait_request(timeout)
+    }
+
+    fn next_request(&self) -> Result<Option<BaoIoRequest>> {
+        self.inner.next_request()
+    }
+
+    fn complete_request(&self, req: &BaoIoRequest) -> Result<()> {
+        let corrupted = if req.virtio_id == self.virtio_id {
+            self.injector.corrupt(req)
+        } else {
+            None
+        };
+        self.inner
+            .complete_request(corrupted.as_ref().unwrap_or(req))
+    }
+
+    fn notify_guest(&self) -> Result<()> {
+        if let Some(delay) = self.injector.delay() {
+            thread::sleep(delay);
+        }
+        if self.injector.drop_interrupt() {
+            return Ok(());
+        }
+        self.inner.notify_guest()
+    }
+
+    fn register_ioeventfd(&self, ioeventfd: &BaoIoEventFd) -> Result<()> {
+        self.inner.register_ioeventfd(ioeventfd)
+    }
+
+    fn register_irqfd(&self, irqfd: &BaoIrqFd) -> Result<()> {
+        self.inner.register_irqfd(irqfd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::defines::{BAO_IO_WRITE, VIRTIO_MMIO_STATUS};
+    use crate::instrument::INSTRUMENTED;
+    use crate::testing::MockHypervisor;
+    use std::time::Instant;
+
+    #[test]
+    fn test_fault_parse() {
+        assert_eq!(
+            "drop-every 3".parse::<Fault>().unwrap(),
+            Fault::DropEvery(3)
+        );
+        assert_eq!(
+            " corrupt 0x70 0xff ".parse::<Fault>().unwrap(),
+            Fault::Corrupt(0x70, 0xff)
+        );
+        assert_eq!(Fault::Corrupt(0x70, 0xff).to_string(), "corrupt 0x70 0xff");
+        for fault in ["", "delay", "delay x", "drop-every 1 2", "unplug"] {
+            assert!(matches!(
+                fault.parse::<Fault>(),
+                Err(Error::InvalidFault(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn test_fault_injection() {
+        let mock = MockHypervisor::new();
+        let injector = Arc::new(FaultInjector::default());
+        let hypervisor = FaultInjectionHypervisor::new(&mock, 1, injector.clone());
+
+        // Every third interrupt is dropped
+        injector.apply(Fault::DropEvery(3));
+        for _ in 0..7 {
+            hypervisor.notify_guest().unwrap();
+        }
+        assert_eq!(mock.interrupts(), 5);
+        assert_eq!(injector.injected(), 2 * INSTRUMENTED as u64);
+
+        // Only the reads of the corrupted register of the device are changed
+        injector.apply(Fault::Corrupt(VIRTIO_MMIO_STATUS, 0x4));
+        let complete = |virtio_id, op, reg_off| {
+            let req = BaoIoRequest {
+                virtio_id,
+                reg_off,
+                addr: 0,
+                op,
+                value: 0xb,
+                access_width: 4,
+                cpu_id: 0,
+                vcpu_id: 1,
+                ret: 0,
+            };
+            hypervisor.complete_request(&req).unwrap();
+            mock.pop_completed().unwrap().value
+        };
+        assert_eq!(complete(1, BAO_IO_READ, VIRTIO_MMIO_STATUS), 0xf);
+        assert_eq!(complete(1, BAO_IO_WRITE, VIRTIO_MMIO_STATUS), 0xb);
+        assert_eq!(complete(0, BAO_IO_READ, VIRTIO_MMIO_STATUS), 0xb);
+        assert_eq!(complete(1, BAO_IO_READ, 0), 0xb);
+
+        // Delayed interrupts are still delivered
+        injector.apply(Fault::Clear);
+        injector.apply(Fault::Delay(2000));
+        let start = Instant::now();
+        hypervisor.notify_guest().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(2));
+        assert_eq!(mock.interrupts(), 6);
+        assert_eq!(
+            injector.plan(),
+            FaultPlan {
+                delay_us: 2000,
+                ..Default::default()
+            }
+        );
+    }
+}
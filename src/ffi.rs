@@ -0,0 +1,367 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao C API.
+//!
+//! Functions return a non-negative value on success and a negative errno value on
+//! failure. The header is generated with cbindgen (`include/bao_sys.h`).
+
+#![allow(dead_code)]
+
+use super::defines::BAO_NAME_LEN;
+use super::error::Result;
+use super::hypervisor;
+use super::types::{BaoIoRequest, BaoIrqFd, ConfigFrontends, IrqFdFlags};
+use std::ffi::CStr;
+use std::fs;
+use std::io;
+use std::os::raw::{c_char, c_int};
+use std::os::unix::io::{BorrowedFd, IntoRawFd};
+use std::ptr;
+
+/// Opaque handle to a loaded frontends configuration.
+pub struct BaoConfig {
+    devices: Vec<BaoDeviceInfo>,
+}
+
+/// Struct representing a configured device, flattened for C consumers.
+///
+/// # Attributes
+///
+/// * `frontend_id` - Frontend ID.
+/// * `guest_id` - Guest ID.
+/// * `device_id` - Device ID.
+/// * `irq` - Device IRQ.
+/// * `addr` - Device address.
+/// * `ram_addr` - Guest RAM address.
+/// * `ram_size` - Guest RAM size.
+/// * `device_type` - Device type (NUL-terminated, truncated if longer).
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct BaoDeviceInfo {
+    pub frontend_id: u32,
+    pub guest_id: u32,
+    pub device_id: u32,
+    pub irq: u32,
+    pub addr: u64,
+    pub ram_addr: u64,
+    pub ram_size: u64,
+    pub device_type: [c_char; BAO_NAME_LEN],
+}
+
+/// Converts an I/O error into a negative errno value.
+fn neg_errno(e: io::Error) -> c_int {
+    -e.raw_os_error().unwrap_or(libc::EIO)
+}
+
+/// Runs an operation on a file descriptor owned by the caller.
+fn with_fd<F: FnOnce(&BorrowedFd) -> Result<()>>(fd: c_int, f: F) -> c_int {
+    if fd < 0 {
+        return -libc::EBADF;
+    }
+    // SAFETY: The caller guarantees the file descriptor stays open for the call.
+    let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+    match f(&fd) {
+        Ok(()) => 0,
+        Err(e) => -e.errno(),
+    }
+}
+
+/// Creates the I/O client of a device model (backend).
+///
+/// # Arguments
+///
+/// * `dm_id` - Device model ID.
+///
+/// # Returns
+///
+/// * `c_int` - The device model file descriptor, or a negative errno value.
+#[no_mangle]
+pub extern "C" fn bao_open(dm_id: u32) -> c_int {
+    match hypervisor::create_backend(dm_id) {
+        Ok(file) => file.into_raw_fd(),
+        Err(e) => -e.errno(),
+    }
+}
+
+/// Closes a device model file descriptor.
+///
+/// # Arguments
+///
+/// * `fd` - Device model file descriptor.
+///
+/// # Returns
+///
+/// * `c_int` - 0, or a negative errno value.
+#[no_mangle]
+pub extern "C" fn bao_close(fd: c_int) -> c_int {
+    // SAFETY: The caller relinquishes the file descriptor.
+    match unsafe { libc::close(fd) } {
+        0 => 0,
+        _ => neg_errno(io::Error::last_os_error()),
+    }
+}
+
+/// Waits for the next I/O request of the device model and fetches it.
+///
+/// # Arguments
+///
+/// * `fd` - Device model file descriptor.
+/// * `req` - Filled with the pending I/O request.
+///
+/// # Returns
+///
+/// * `c_int` - 0, or a negative errno value.
+///
+/// # Safety
+///
+/// `req` must be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn bao_attach_request(fd: c_int, req: *mut BaoIoRequest) -> c_int {
+    // SAFETY: The caller guarantees `req` is either null or valid for writes.
+    let Some(req) = (unsafe { req.as_mut() }) else {
+        return -libc::EINVAL;
+    };
+    with_fd(fd, |fd| {
+        // Block until a request is pending, then fetch it
+        hypervisor::attach_client(fd)?;
+        hypervisor::request(fd, req)
+    })
+}
+
+/// Notifies the completion of an I/O request.
+///
+/// # Arguments
+///
+/// * `fd` - Device model file descriptor.
+/// * `req` - The completed I/O request (with `value` and `ret` set).
+///
+/// # Returns
+///
+/// * `c_int` - 0, or a negative errno value.
+///
+/// # Safety
+///
+/// `req` must be null or valid for reads.
+#[no_mangle]
+pub unsafe extern "C" fn bao_complete_request(fd: c_int, req: *const BaoIoRequest) -> c_int {
+    // SAFETY: The caller guarantees `req` is either null or valid for reads.
+    let Some(req) = (unsafe { req.as_ref() }) else {
+        return -libc::EINVAL;
+    };
+    with_fd(fd, |fd| hypervisor::notify_completed(fd, req))
+}
+
+/// Assigns (or deassigns) an eventfd that injects the device interrupt when signaled.
+///
+/// # Arguments
+///
+/// * `fd` - Device model file descriptor.
+/// * `eventfd` - Eventfd file descriptor.
+/// * `flags` - `BAO_IRQFD_FLAG_ASSIGN` or `BAO_IRQFD_FLAG_DEASSIGN`.
+///
+/// # Returns
+///
+/// * `c_int` - 0, or a negative errno value.
+#[no_mangle]
+pub extern "C" fn bao_register_irqfd(fd: c_int, eventfd: c_int, flags: u32) -> c_int {
+    let Some(flags) = IrqFdFlags::from_bits(flags) else {
+        return -libc::EINVAL;
+    };
+    let irqfd = BaoIrqFd::new(eventfd, flags);
+    with_fd(fd, |fd| hypervisor::irqfd(fd, &irqfd))
+}
+
+/// Flattens the devices of a configuration.
+fn flatten_devices(config: &ConfigFrontends) -> Vec<BaoDeviceInfo> {
+    let mut devices = Vec::new();
+    for frontend in &config.frontends {
+        for guest in &frontend.guests {
+            for device in &guest.devices {
+                let mut device_type = [0 as c_char; BAO_NAME_LEN];
+                for (dst, src) in device_type
+                    .iter_mut()
+                    .zip(device.device_type.bytes().take(BAO_NAME_LEN - 1))
+                {
+                    *dst = src as c_char;
+                }
+                devices.push(BaoDeviceInfo {
+                    frontend_id: frontend.id.raw(),
+                    guest_id: guest.id.raw(),
+                    device_id: device.id.raw(),
+                    irq: device.irq.raw(),
+                    addr: device.addr.raw(),
+                    ram_addr: guest.ram_addr.raw(),
+                    ram_size: guest.ram_size,
+                    device_type,
+                });
+            }
+        }
+    }
+    devices
+}
+
+/// Loads a frontends configuration (YAML) file.
+///
+/// # Arguments
+///
+/// * `path` - Path of the configuration file (NUL-terminated).
+///
+/// # Returns
+///
+/// * `*mut BaoConfig` - The configuration (to release with `bao_config_free`), or null
+///   if the file could not be read or parsed.
+///
+/// # Safety
+///
+/// `path` must be null or a valid NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn bao_config_load(path: *const c_char) -> *mut BaoConfig {
+    if path.is_null() {
+        return ptr::null_mut();
+    }
+    // SAFETY: The caller guarantees `path` is a valid NUL-terminated string.
+    let path = match unsafe { CStr::from_ptr(path) }.to_str() {
+        Ok(path) => path,
+        Err(_) => return ptr::null_mut(),
+    };
+    let config: ConfigFrontends = match fs::read_to_string(path)
+        .ok()
+        .and_then(|content| serde_yaml::from_str(&content).ok())
+    {
+        Some(config) => config,
+        None => return ptr::null_mut(),
+    };
+    Box::into_raw(Box::new(BaoConfig {
+        devices: flatten_devices(&config),
+    }))
+}
+
+/// Returns the number of devices of a configuration.
+///
+/// # Arguments
+///
+/// * `config` - The configuration.
+///
+/// # Returns
+///
+/// * `usize` - The number of devices (0 if `config` is null).
+///
+/// # Safety
+///
+/// `config` must be null or returned by `bao_config_load` (and not freed).
+#[no_mangle]
+pub unsafe extern "C" fn bao_config_num_devices(config: *const BaoConfig) -> usize {
+    // SAFETY: The caller guarantees `config` was returned by `bao_config_load`.
+    unsafe { config.as_ref() }.map_or(0, |config| config.devices.len())
+}
+
+/// Returns a device of a configuration.
+///
+/// # Arguments
+///
+/// * `config` - The configuration.
+/// * `index` - Index of the device (below `bao_config_num_devices`).
+/// * `info` - Filled with the device.
+///
+/// # Returns
+///
+/// * `c_int` - 0, or a negative errno value.
+///
+/// # Safety
+///
+/// `config` must be null or returned by `bao_config_load` (and not freed), and
+/// `info` must be null or valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn bao_config_get_device(
+    config: *const BaoConfig,
+    index: usize,
+    info: *mut BaoDeviceInfo,
+) -> c_int {
+    // SAFETY: The caller guarantees `config` was returned by `bao_config_load` and `info`
+    // is either null or valid for writes.
+    let (Some(config), Some(info)) = (unsafe { config.as_ref() }, unsafe { info.as_mut() }) else {
+        return -libc::EINVAL;
+    };
+    match config.devices.get(index) {
+        Some(device) => {
+            *info = *device;
+            0
+        }
+        None => -libc::ENOENT,
+    }
+}
+
+/// Releases a configuration.
+///
+/// # Arguments
+///
+/// * `config` - The configuration (may be null).
+///
+/// # Safety
+///
+/// `config` must be null or returned by `bao_config_load`, and not used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn bao_config_free(config: *mut BaoConfig) {
+    if !config.is_null() {
+        // SAFETY: The caller guarantees `config` was returned by `bao_config_load` and
+        // is not used afterwards.
+        drop(unsafe { Box::from_raw(config) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CString;
+    use std::mem::MaybeUninit;
+
+    #[test]
+    fn test_config_api() {
+        let path = std::env::temp_dir().join(format!("bao-ffi-{}.yaml", std::process::id()));
+        fs::write(
+            &path,
+            "frontends:
+  - name: frontend0
+    id: 0
+    guests:
+      - name: guest0
+        id: 1
+        ram_addr: 0x50000000
+        ram_size: 0x1000000
+        shmem_path: /dev/baoipc0
+        socket_path: /tmp/
+        devices:
+          - {name: rng0, id: 0, type: rng, irq: 47, addr: 0xa003e00}
+",
+        )
+        .unwrap();
+        let c_path = CString::new(path.to_str().unwrap()).unwrap();
+
+        // SAFETY: Every pointer is either null or valid.
+        unsafe {
+            let config = bao_config_load(c_path.as_ptr());
+            assert!(!config.is_null());
+            assert_eq!(bao_config_num_devices(config), 1);
+
+            let mut info = MaybeUninit::<BaoDeviceInfo>::uninit();
+            assert_eq!(bao_config_get_device(config, 0, info.as_mut_ptr()), 0);
+            let info = info.assume_init();
+            assert_eq!((info.guest_id, info.irq, info.addr), (1, 47, 0xa003e00));
+            let device_type = CStr::from_ptr(info.device_type.as_ptr());
+            assert_eq!(device_type.to_str().unwrap(), "rng");
+
+            let mut other = info;
+            assert_eq!(bao_config_get_device(config, 1, &mut other), -libc::ENOENT);
+            bao_config_free(config);
+
+            assert!(bao_config_load(ptr::null()).is_null());
+            assert_eq!(bao_attach_request(-1, ptr::null_mut()), -libc::EINVAL);
+        }
+        fs::remove_file(&path).unwrap();
+        assert_eq!(bao_register_irqfd(-1, 0, 0), -libc::EBADF);
+        assert_eq!(bao_register_irqfd(0, 0, 0x80), -libc::EINVAL);
+    }
+}
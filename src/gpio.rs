@@ -0,0 +1,883 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao built-in GPIO backend.
+//!
+//! Exports lines of a GPIO chip of the host to a virtio-gpio device, in-process
+//! (`backend: bao-gpio`). The `chip` backend option names the character device of the
+//! chip (e.g. `/dev/gpiochip0`) and `lines` enumerates exactly the lines exported, the
+//! guest seeing them in that order (its line 0 being the first listed), each with the
+//! direction it may take:
+//!
+//! * `OFFSET:in` - An input.
+//! * `OFFSET:out[=VALUE]` - An output, driving VALUE (0 by default) until the guest sets
+//!   it.
+//! * `OFFSET:any[=VALUE]` - Either, as the guest sets it: an input at first, or an output
+//!   driving VALUE if given.
+//!
+//! For example `lines: "17:out=1, 18:in, 22:any"`. A request the constraints refuse (an
+//! input made an output, or set, or the other way around) fails with
+//! `VIRTIO_GPIO_STATUS_ERR`; releasing a line (`VIRTIO_GPIO_DIRECTION_NONE`) leaves it as
+//! it is.
+//!
+//! The lines are requested from the kernel when the backend is created, as a single
+//! request labelled `bao-<device name>`, and held until the backend terminates: the
+//! kernel grants a line to a single request, so host processes cannot drive (or even
+//! request) the lines of the guest, and a line already in use fails the backend.
+
+#![allow(dead_code)]
+
+use super::backend::InProcessBackend;
+use super::defines::*;
+use super::error::{Error, Result};
+use super::poll::EventPoller;
+use super::types::ConfigDevice;
+use super::vhost_backend::{VhostUserBackend, VirtioDevice};
+use std::fs::OpenOptions;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use vmm_sys_util::{ioctl_ioc_nr, ioctl_iowr_nr};
+
+/// Enum representing the directions a GPIO line may take.
+///
+/// # Variants
+///
+/// * `In` - Input only.
+/// * `Out` - Output only.
+/// * `Any` - Input or output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GpioConstraint {
+    In,
+    Out,
+    Any,
+}
+
+impl GpioConstraint {
+    /// Checks whether the line may take a direction.
+    ///
+    /// # Arguments
+    ///
+    /// * `output` - Whether the direction is output.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - Whether the direction is allowed.
+    pub fn allows(&self, output: bool) -> bool {
+        match self {
+            GpioConstraint::In => !output,
+            GpioConstraint::Out => output,
+            GpioConstraint::Any => true,
+        }
+    }
+}
+
+/// Struct representing a GPIO line exported to the guest.
+///
+/// # Attributes
+///
+/// * `offset` - Offset of the line on the chip.
+/// * `constraint` - Directions the line may take.
+/// * `initial` - Value driven once the line is requested (None if it starts as an input).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GpioLine {
+    pub offset: u32,
+    pub constraint: GpioConstraint,
+    pub initial: Option<bool>,
+}
+
+impl GpioLine {
+    /// Parses a line of the `lines` backend option.
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - The line (`OFFSET:in`, `OFFSET:out[=VALUE]` or `OFFSET:any[=VALUE]`).
+    ///
+    /// # Returns
+    ///
+    /// * `Result<GpioLine>` - The line, or `InvalidBackendOption`.
+    pub fn parse(line: &str) -> Result<Self> {
+        let invalid = |reason: String| Error::InvalidBackendOption("lines".to_string(), reason);
+        let (offset, spec) = line
+            .split_once(':')
+            .ok_or_else(|| invalid(format!("{} is not OFFSET:DIRECTION", line)))?;
+        let offset = offset
+            .parse()
+            .map_err(|_| invalid(format!("{} is not a line offset", offset)))?;
+        let (direction, value) = match spec.split_once('=') {
+            Some((direction, value)) => (direction, Some(value)),
+            None => (spec, None),
+        };
+        let initial = match value {
+            None => None,
+            Some("0") => Some(false),
+            Some("1") => Some(true),
+            Some(value) => return Err(invalid(format!("{} is not 0 or 1", value))),
+        };
+        let (constraint, initial) = match (direction, initial) {
+            ("in", None) => (GpioConstraint::In, None),
+            ("out", initial) => (GpioConstraint::Out, Some(initial.unwrap_or(false))),
+            ("any", initial) => (GpioConstraint::Any, initial),
+            _ => {
+                return Err(invalid(format!(
+                    "{} is not in, out[=VALUE] or any[=VALUE]",
+                    spec
+                )))
+            }
+        };
+        Ok(GpioLine {
+            offset,
+            constraint,
+            initial,
+        })
+    }
+}
+
+/// Reads the lines a device exports from its backend options.
+///
+/// # Arguments
+///
+/// * `device` - The device, whose `lines` option enumerates the lines.
+///
+/// # Returns
+///
+/// * `Result<Vec<GpioLine>>` - The lines, in the order the guest sees them, or
+///   `InvalidBackendOption`.
+pub fn gpio_lines(device: &ConfigDevice) -> Result<Vec<GpioLine>> {
+    let invalid = |reason: String| Error::InvalidBackendOption("lines".to_string(), reason);
+    let list = device
+        .options
+        .get("lines")
+        .ok_or_else(|| invalid("missing".to_string()))?;
+    let lines = list
+        .split(',')
+        .map(|line| GpioLine::parse(line.trim()))
+        .collect::<Result<Vec<_>>>()?;
+    if lines.len() > GPIO_V2_LINES_MAX {
+        return Err(invalid(format!("more than {} lines", GPIO_V2_LINES_MAX)));
+    }
+    for (index, line) in lines.iter().enumerate() {
+        if lines[..index]
+            .iter()
+            .any(|other| other.offset == line.offset)
+        {
+            return Err(invalid(format!("line {} is listed twice", line.offset)));
+        }
+    }
+    Ok(lines)
+}
+
+/// Struct representing the state of a GPIO line.
+///
+/// # Attributes
+///
+/// * `output` - Whether the line is an output.
+/// * `value` - Value driven as an output (kept while an input, as set by the guest).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GpioLineState {
+    pub output: bool,
+    pub value: bool,
+}
+
+impl From<&GpioLine> for GpioLineState {
+    fn from(line: &GpioLine) -> Self {
+        GpioLineState {
+            output: line.initial.is_some(),
+            value: line.initial.unwrap_or(false),
+        }
+    }
+}
+
+/// The host GPIO lines exported to a device, held on its behalf.
+pub trait GpioLines: Send {
+    /// Configures the lines.
+    ///
+    /// # Arguments
+    ///
+    /// * `lines` - The state of every line, in the order of the request.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<()>` - Ok once the lines are configured.
+    fn configure(&mut self, lines: &[GpioLineState]) -> io::Result<()>;
+
+    /// Reads the values of the lines.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<u64>` - The values, bit N being the value of line N of the request.
+    fn values(&mut self) -> io::Result<u64>;
+
+    /// Sets the value of an output line.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - Index of the line in the request.
+    /// * `value` - The value.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<()>` - Ok once the value is driven.
+    fn set_value(&mut self, index: usize, value: bool) -> io::Result<()>;
+}
+
+/// Struct representing a line attribute (`struct gpio_v2_line_attribute`), whose value
+/// holds flags or output values.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct GpioV2LineAttribute {
+    id: u32,
+    padding: u32,
+    value: u64,
+}
+
+/// Struct representing a line attribute applying to some lines
+/// (`struct gpio_v2_line_config_attribute`).
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct GpioV2LineConfigAttribute {
+    attr: GpioV2LineAttribute,
+    mask: u64,
+}
+
+/// Struct representing a line configuration (`struct gpio_v2_line_config`).
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct GpioV2LineConfig {
+    flags: u64,
+    num_attrs: u32,
+    padding: [u32; 5],
+    attrs: [GpioV2LineConfigAttribute; GPIO_V2_LINE_NUM_ATTRS_MAX],
+}
+
+/// Struct representing a line request (`struct gpio_v2_line_request`).
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct GpioV2LineRequest {
+    offsets: [u32; GPIO_V2_LINES_MAX],
+    consumer: [u8; GPIO_MAX_NAME_SIZE],
+    config: GpioV2LineConfig,
+    num_lines: u32,
+    event_buffer_size: u32,
+    padding: [u32; 5],
+    fd: i32,
+}
+
+/// Struct representing line values (`struct gpio_v2_line_values`).
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct GpioV2LineValues {
+    bits: u64,
+    mask: u64,
+}
+
+ioctl_iowr_nr!(
+    GPIO_V2_GET_LINE,
+    GPIO_IOCTL_TYPE,
+    GPIO_V2_GET_LINE_NR,
+    GpioV2LineRequest
+);
+ioctl_iowr_nr!(
+    GPIO_V2_LINE_SET_CONFIG,
+    GPIO_IOCTL_TYPE,
+    GPIO_V2_LINE_SET_CONFIG_NR,
+    GpioV2LineConfig
+);
+ioctl_iowr_nr!(
+    GPIO_V2_LINE_GET_VALUES,
+    GPIO_IOCTL_TYPE,
+    GPIO_V2_LINE_GET_VALUES_NR,
+    GpioV2LineValues
+);
+ioctl_iowr_nr!(
+    GPIO_V2_LINE_SET_VALUES,
+    GPIO_IOCTL_TYPE,
+    GPIO_V2_LINE_SET_VALUES_NR,
+    GpioV2LineValues
+);
+
+/// Builds the configuration of the lines of a request: inputs, but for the outputs
+/// driving their values.
+fn line_config(lines: &[GpioLineState]) -> GpioV2LineConfig {
+    let mut config = GpioV2LineConfig {
+        flags: GPIO_V2_LINE_FLAG_INPUT,
+        ..Default::default()
+    };
+    let bits = |f: fn(&GpioLineState) -> bool| {
+        lines
+            .iter()
+            .enumerate()
+            .filter(|(_, line)| f(line))
+            .fold(0u64, |bits, (index, _)| bits | 1 << index)
+    };
+    let outputs = bits(|line| line.output);
+    if outputs != 0 {
+        config.attrs[0] = GpioV2LineConfigAttribute {
+            attr: GpioV2LineAttribute {
+                id: GPIO_V2_LINE_ATTR_ID_FLAGS,
+                padding: 0,
+                value: GPIO_V2_LINE_FLAG_OUTPUT,
+            },
+            mask: outputs,
+        };
+        config.attrs[1] = GpioV2LineConfigAttribute {
+            attr: GpioV2LineAttribute {
+                id: GPIO_V2_LINE_ATTR_ID_OUTPUT_VALUES,
+                padding: 0,
+                value: bits(|line| line.value),
+            },
+            mask: outputs,
+        };
+        config.num_attrs = 2;
+    }
+    config
+}
+
+/// Struct representing lines of a host GPIO chip, requested from the kernel.
+///
+/// # Attributes
+///
+/// * `request` - The line request, holding the lines until closed.
+#[derive(Debug)]
+pub struct GpioChipLines {
+    request: OwnedFd,
+}
+
+impl GpioChipLines {
+    /// Requests lines of a GPIO chip.
+    ///
+    /// # Arguments
+    ///
+    /// * `chip` - Path of the character device of the chip.
+    /// * `consumer` - Label of the request (truncated to 31 bytes).
+    /// * `offsets` - Offsets of the lines.
+    /// * `lines` - Initial state of the lines.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<GpioChipLines>` - The lines, or `GpioRequestFailed` (`EBUSY` if a line is
+    ///   already requested).
+    pub fn request(
+        chip: &str,
+        consumer: &str,
+        offsets: &[u32],
+        lines: &[GpioLineState],
+    ) -> Result<Self> {
+        let failed = |e| Error::GpioRequestFailed(chip.to_string(), e);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(chip)
+            .map_err(failed)?;
+
+        let mut request = GpioV2LineRequest {
+            offsets: [0; GPIO_V2_LINES_MAX],
+            consumer: [0; GPIO_MAX_NAME_SIZE],
+            config: line_config(lines),
+            num_lines: offsets.len() as u32,
+            event_buffer_size: 0,
+            padding: [0; 5],
+            fd: -1,
+        };
+        request.offsets[..offsets.len()].copy_from_slice(offsets);
+        let len = consumer.len().min(GPIO_MAX_NAME_SIZE - 1);
+        request.consumer[..len].copy_from_slice(&consumer.as_bytes()[..len]);
+        // SAFETY: The request is valid for reads and writes of its size.
+        if unsafe { libc::ioctl(file.as_raw_fd(), GPIO_V2_GET_LINE() as _, &mut request) } < 0 {
+            return Err(failed(io::Error::last_os_error()));
+        }
+        // SAFETY: The kernel returned a new file descriptor, owned by nothing else.
+        let request = unsafe { OwnedFd::from_raw_fd(request.fd) };
+        Ok(GpioChipLines { request })
+    }
+
+    /// Issues an ioctl on the line request.
+    fn ioctl<T>(&self, request: libc::c_ulong, arg: &mut T) -> io::Result<()> {
+        // SAFETY: The argument is the structure the request expects, valid for reads and
+        // writes of its size.
+        if unsafe { libc::ioctl(self.request.as_raw_fd(), request as _, arg as *mut T) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+impl GpioLines for GpioChipLines {
+    fn configure(&mut self, lines: &[GpioLineState]) -> io::Result<()> {
+        self.ioctl(GPIO_V2_LINE_SET_CONFIG(), &mut line_config(lines))
+    }
+
+    fn values(&mut self) -> io::Result<u64> {
+        let mut values = GpioV2LineValues {
+            bits: 0,
+            mask: u64::MAX,
+        };
+        self.ioctl(GPIO_V2_LINE_GET_VALUES(), &mut values)?;
+        Ok(values.bits)
+    }
+
+    fn set_value(&mut self, index: usize, value: bool) -> io::Result<()> {
+        let mut values = GpioV2LineValues {
+            bits: u64::from(value) << index,
+            mask: 1 << index,
+        };
+        self.ioctl(GPIO_V2_LINE_SET_VALUES(), &mut values)
+    }
+}
+
+/// Struct representing a virtio-gpio device exporting host lines.
+///
+/// # Attributes
+///
+/// * `lines` - The host lines.
+/// * `exported` - The lines, as configured.
+/// * `state` - Current state of the lines.
+/// * `denied` - Number of requests refused by the constraints of the lines.
+pub struct GpioDevice<L: GpioLines> {
+    lines: L,
+    exported: Vec<GpioLine>,
+    state: Vec<GpioLineState>,
+    denied: u64,
+}
+
+impl<L: GpioLines> GpioDevice<L> {
+    /// Creates a GPIO device.
+    ///
+    /// # Arguments
+    ///
+    /// * `lines` - The host lines, configured as `exported`.
+    /// * `exported` - The lines, as configured.
+    ///
+    /// # Returns
+    ///
+    /// * `GpioDevice<L>` - The device.
+    pub fn new(lines: L, exported: Vec<GpioLine>) -> Self {
+        GpioDevice {
+            lines,
+            state: exported.iter().map(GpioLineState::from).collect(),
+            exported,
+            denied: 0,
+        }
+    }
+
+    /// Returns the number of requests refused by the constraints of the lines.
+    pub fn denied(&self) -> u64 {
+        self.denied
+    }
+
+    /// Serves a request.
+    ///
+    /// # Arguments
+    ///
+    /// * `kind` - Request type.
+    /// * `gpio` - Line, as seen by the guest.
+    /// * `value` - Request value.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<u8>` - The response value, or None if the request failed.
+    fn serve(&mut self, kind: u16, gpio: usize, value: u32) -> Option<u8> {
+        let line = *self.exported.get(gpio)?;
+        let state = self.state[gpio];
+        match kind {
+            VIRTIO_GPIO_MSG_GET_DIRECTION => match state.output {
+                true => Some(VIRTIO_GPIO_DIRECTION_OUT),
+                false => Some(VIRTIO_GPIO_DIRECTION_IN),
+            },
+            VIRTIO_GPIO_MSG_SET_DIRECTION => {
+                let output = match u8::try_from(value).ok()? {
+                    // Released lines keep their direction (and value)
+                    VIRTIO_GPIO_DIRECTION_NONE => return Some(0),
+                    VIRTIO_GPIO_DIRECTION_OUT => true,
+                    VIRTIO_GPIO_DIRECTION_IN => false,
+                    _ => return None,
+                };
+                if !line.constraint.allows(output) {
+                    self.denied += 1;
+                    return None;
+                }
+                if output != state.output {
+                    self.state[gpio].output = output;
+                    if self.lines.configure(&self.state).is_err() {
+                        self.state[gpio] = state;
+                        return None;
+                    }
+                }
+                Some(0)
+            }
+            VIRTIO_GPIO_MSG_GET_VALUE => {
+                let values = self.lines.values().ok()?;
+                Some((values >> gpio & 1) as u8)
+            }
+            VIRTIO_GPIO_MSG_SET_VALUE => {
+                let value = match value {
+                    0 => false,
+                    1 => true,
+                    _ => return None,
+                };
+                // Drivers set the value of a line before making it an output
+                if !line.constraint.allows(true) {
+                    self.denied += 1;
+                    return None;
+                }
+                if state.output {
+                    self.lines.set_value(gpio, value).ok()?;
+                }
+                self.state[gpio].value = value;
+                Some(0)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl<L: GpioLines> VirtioDevice for GpioDevice<L> {
+    fn features(&self) -> u64 {
+        VIRTIO_F_VERSION_1
+    }
+
+    fn num_queues(&self) -> usize {
+        // The request queue and the (unused) event queue
+        2
+    }
+
+    fn config(&self) -> Vec<u8> {
+        // Number of lines, padding and size of the line names (none)
+        let mut config = (self.exported.len() as u16).to_le_bytes().to_vec();
+        config.extend_from_slice(&[0; 6]);
+        config
+    }
+
+    fn process(&mut self, readable: &[u8], writable: &mut [u8]) -> u32 {
+        // The response holds the status and the value
+        if writable.len() < 2 {
+            return 0;
+        }
+        let response = match readable.len() >= VIRTIO_GPIO_REQUEST_SIZE {
+            true => {
+                let kind = u16::from_le_bytes([readable[0], readable[1]]);
+                let gpio = u16::from_le_bytes([readable[2], readable[3]]);
+                let value = u32::from_le_bytes(readable[4..8].try_into().unwrap());
+                self.serve(kind, usize::from(gpio), value)
+            }
+            false => None,
+        };
+        (writable[0], writable[1]) = match response {
+            Some(value) => (VIRTIO_GPIO_STATUS_OK, value),
+            None => (VIRTIO_GPIO_STATUS_ERR, 0),
+        };
+        2
+    }
+}
+
+/// Creates the built-in GPIO backend of a device, requesting its lines.
+///
+/// # Arguments
+///
+/// * `device` - The device, whose `chip` option names the host GPIO chip and whose
+///   `lines` option enumerates the lines exported.
+///
+/// # Returns
+///
+/// * `Result<Box<dyn InProcessBackend>>` - The backend serving the device,
+///   `InvalidBackendOption` if an option is missing or invalid, `GpioRequestFailed` if
+///   the lines cannot be requested.
+pub fn gpio_backend(device: &ConfigDevice) -> Result<Box<dyn InProcessBackend>> {
+    let chip = device
+        .options
+        .get("chip")
+        .ok_or_else(|| Error::InvalidBackendOption("chip".to_string(), "missing".to_string()))?;
+    let exported = gpio_lines(device)?;
+    let offsets = exported.iter().map(|line| line.offset).collect::<Vec<_>>();
+    let state = exported.iter().map(GpioLineState::from).collect::<Vec<_>>();
+    let consumer = format!("bao-{}", device.name);
+    let lines = GpioChipLines::request(chip, &consumer, &offsets, &state)?;
+    let poller = EventPoller::new(device);
+    let device = GpioDevice::new(lines, exported);
+    Ok(Box::new(
+        VhostUserBackend::new(Box::new(device)).with_poller(poller),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::backend_device;
+    use std::mem::size_of;
+
+    fn device(options: &[(&str, &str)]) -> ConfigDevice {
+        backend_device("gpio0", "gpio", Some(BAO_GPIO_BACKEND), options)
+    }
+
+    /// Lines looping their outputs back as inputs, which fail to configure on demand.
+    #[derive(Default)]
+    struct FakeLines {
+        state: Vec<GpioLineState>,
+        broken: bool,
+    }
+
+    impl GpioLines for FakeLines {
+        fn configure(&mut self, lines: &[GpioLineState]) -> io::Result<()> {
+            if self.broken {
+                return Err(io::Error::from_raw_os_error(libc::EIO));
+            }
+            self.state = lines.to_vec();
+            Ok(())
+        }
+
+        fn values(&mut self) -> io::Result<u64> {
+            Ok(self
+                .state
+                .iter()
+                .enumerate()
+                .fold(0, |bits, (index, line)| {
+                    bits | u64::from(line.value) << index
+                }))
+        }
+
+        fn set_value(&mut self, index: usize, value: bool) -> io::Result<()> {
+            assert!(self.state[index].output);
+            self.state[index].value = value;
+            Ok(())
+        }
+    }
+
+    /// Serves a request, returning its status and value.
+    fn request(device: &mut GpioDevice<FakeLines>, kind: u16, gpio: u16, value: u32) -> (u8, u8) {
+        let mut readable = Vec::new();
+        readable.extend_from_slice(&kind.to_le_bytes());
+        readable.extend_from_slice(&gpio.to_le_bytes());
+        readable.extend_from_slice(&value.to_le_bytes());
+        let mut writable = [0xffu8; 2];
+        assert_eq!(device.process(&readable, &mut writable), 2);
+        (writable[0], writable[1])
+    }
+
+    #[test]
+    fn test_gpio_lines() {
+        let lines = gpio_lines(&device(&[(
+            "lines",
+            "17:out=1, 18:in,22:any,23:any=0,24:out",
+        )]));
+        assert_eq!(
+            lines.unwrap(),
+            [
+                GpioLine {
+                    offset: 17,
+                    constraint: GpioConstraint::Out,
+                    initial: Some(true)
+                },
+                GpioLine {
+                    offset: 18,
+                    constraint: GpioConstraint::In,
+                    initial: None
+                },
+                GpioLine {
+                    offset: 22,
+                    constraint: GpioConstraint::Any,
+                    initial: None
+                },
+                GpioLine {
+                    offset: 23,
+                    constraint: GpioConstraint::Any,
+                    initial: Some(false)
+                },
+                GpioLine {
+                    offset: 24,
+                    constraint: GpioConstraint::Out,
+                    initial: Some(false)
+                },
+            ]
+        );
+
+        let many = (0..65)
+            .map(|i| format!("{}:in", i))
+            .collect::<Vec<_>>()
+            .join(",");
+        for value in [
+            "",
+            "17",
+            "x:in",
+            "17:in=1",
+            "17:out=2",
+            "17:inout",
+            "17:in,17:out",
+            &many,
+        ] {
+            assert!(
+                matches!(
+                    gpio_lines(&device(&[("lines", value)])),
+                    Err(Error::InvalidBackendOption(option, _)) if option == "lines"
+                ),
+                "{}",
+                value
+            );
+        }
+        assert!(matches!(
+            gpio_lines(&device(&[])),
+            Err(Error::InvalidBackendOption(option, _)) if option == "lines"
+        ));
+
+        assert!(matches!(
+            gpio_backend(&device(&[("lines", "17:in")])),
+            Err(Error::InvalidBackendOption(option, _)) if option == "chip"
+        ));
+        assert!(matches!(
+            gpio_backend(&device(&[
+                ("chip", "/nonexistent/gpiochip0"),
+                ("lines", "17:in")
+            ])),
+            Err(Error::GpioRequestFailed(..))
+        ));
+
+        // The layouts of the kernel structures
+        assert_eq!(size_of::<GpioV2LineConfig>(), 272);
+        assert_eq!(size_of::<GpioV2LineRequest>(), 592);
+        let config = line_config(&[
+            GpioLineState::default(),
+            GpioLineState {
+                output: true,
+                value: true,
+            },
+            GpioLineState {
+                output: true,
+                value: false,
+            },
+        ]);
+        assert_eq!(config.flags, GPIO_V2_LINE_FLAG_INPUT);
+        assert_eq!(config.num_attrs, 2);
+        assert_eq!(
+            (config.attrs[0].attr.value, config.attrs[0].mask),
+            (GPIO_V2_LINE_FLAG_OUTPUT, 0b110)
+        );
+        assert_eq!(
+            (config.attrs[1].attr.value, config.attrs[1].mask),
+            (0b010, 0b110)
+        );
+        assert_eq!(line_config(&[GpioLineState::default()]).num_attrs, 0);
+    }
+
+    #[test]
+    fn test_gpio_requests() {
+        let exported = gpio_lines(&device(&[("lines", "17:out=1,18:in,22:any")])).unwrap();
+        let state = exported.iter().map(GpioLineState::from).collect::<Vec<_>>();
+        let lines = FakeLines {
+            state,
+            broken: false,
+        };
+        let mut device = GpioDevice::new(lines, exported);
+        assert_eq!(device.config(), [3, 0, 0, 0, 0, 0, 0, 0]);
+
+        const OK: u8 = VIRTIO_GPIO_STATUS_OK;
+        const ERR: u8 = VIRTIO_GPIO_STATUS_ERR;
+        let (out, input) = (VIRTIO_GPIO_DIRECTION_OUT, VIRTIO_GPIO_DIRECTION_IN);
+        assert_eq!(
+            request(&mut device, VIRTIO_GPIO_MSG_GET_DIRECTION, 0, 0),
+            (OK, out)
+        );
+        assert_eq!(
+            request(&mut device, VIRTIO_GPIO_MSG_GET_DIRECTION, 1, 0),
+            (OK, input)
+        );
+        assert_eq!(
+            request(&mut device, VIRTIO_GPIO_MSG_GET_DIRECTION, 2, 0),
+            (OK, input)
+        );
+        assert_eq!(
+            request(&mut device, VIRTIO_GPIO_MSG_GET_VALUE, 0, 0),
+            (OK, 1)
+        );
+
+        // Outputs may be set, not turned into inputs
+        assert_eq!(
+            request(&mut device, VIRTIO_GPIO_MSG_SET_VALUE, 0, 0),
+            (OK, 0)
+        );
+        assert_eq!(
+            request(&mut device, VIRTIO_GPIO_MSG_GET_VALUE, 0, 0),
+            (OK, 0)
+        );
+        assert_eq!(
+            request(&mut device, VIRTIO_GPIO_MSG_SET_DIRECTION, 0, input.into()),
+            (ERR, 0)
+        );
+        // Inputs may be neither set nor turned into outputs
+        assert_eq!(
+            request(&mut device, VIRTIO_GPIO_MSG_SET_VALUE, 1, 1),
+            (ERR, 0)
+        );
+        assert_eq!(
+            request(&mut device, VIRTIO_GPIO_MSG_SET_DIRECTION, 1, out.into()),
+            (ERR, 0)
+        );
+        assert_eq!(device.denied(), 3);
+
+        // Lines that may take either direction drive the value set beforehand
+        assert_eq!(
+            request(&mut device, VIRTIO_GPIO_MSG_SET_VALUE, 2, 1),
+            (OK, 0)
+        );
+        assert_eq!(
+            request(&mut device, VIRTIO_GPIO_MSG_GET_VALUE, 2, 0),
+            (OK, 0)
+        );
+        assert_eq!(
+            request(&mut device, VIRTIO_GPIO_MSG_SET_DIRECTION, 2, out.into()),
+            (OK, 0)
+        );
+        assert_eq!(
+            request(&mut device, VIRTIO_GPIO_MSG_GET_VALUE, 2, 0),
+            (OK, 1)
+        );
+        assert_eq!(
+            device.lines.state[2],
+            GpioLineState {
+                output: true,
+                value: true
+            }
+        );
+        // Released lines are left as they are
+        assert_eq!(
+            request(&mut device, VIRTIO_GPIO_MSG_SET_DIRECTION, 2, 0),
+            (OK, 0)
+        );
+        assert_eq!(
+            request(&mut device, VIRTIO_GPIO_MSG_GET_DIRECTION, 2, 0),
+            (OK, out)
+        );
+
+        // A line the host fails to configure keeps its state
+        device.lines.broken = true;
+        assert_eq!(
+            request(&mut device, VIRTIO_GPIO_MSG_SET_DIRECTION, 2, input.into()),
+            (ERR, 0)
+        );
+        assert_eq!(
+            request(&mut device, VIRTIO_GPIO_MSG_GET_DIRECTION, 2, 0),
+            (OK, out)
+        );
+
+        // Unknown lines, requests and values
+        assert_eq!(
+            request(&mut device, VIRTIO_GPIO_MSG_GET_VALUE, 3, 0),
+            (ERR, 0)
+        );
+        assert_eq!(
+            request(&mut device, VIRTIO_GPIO_MSG_GET_NAMES, 0, 0),
+            (ERR, 0)
+        );
+        assert_eq!(
+            request(&mut device, VIRTIO_GPIO_MSG_SET_VALUE, 0, 2),
+            (ERR, 0)
+        );
+        assert_eq!(
+            request(&mut device, VIRTIO_GPIO_MSG_SET_DIRECTION, 2, 3),
+            (ERR, 0)
+        );
+        let mut writable = [0xffu8; 2];
+        assert_eq!(device.process(&[0; 4], &mut writable), 2);
+        assert_eq!(writable, [ERR, 0]);
+        assert_eq!(device.process(&[0; 8], &mut []), 0);
+    }
+}
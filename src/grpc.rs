@@ -0,0 +1,264 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao gRPC management service.
+//!
+//! Exposes a `Management` implementation over gRPC (see `proto/bao_management.proto`).
+
+#![allow(dead_code)]
+
+use super::error::{Error, Result};
+use super::management::{DeviceInfo, Management};
+use super::stats::DeviceStatsSnapshot;
+use super::types::ConfigCoalesce;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tonic::{Request, Response, Status};
+
+/// Generated gRPC messages and service.
+pub mod pb {
+    tonic::include_proto!("bao.management");
+}
+
+use pb::bao_management_server::{BaoManagement, BaoManagementServer};
+
+impl From<Error> for Status {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::DeviceNotFound => Status::not_found(e.report()),
+            _ => Status::internal(e.report()),
+        }
+    }
+}
+
+impl From<DeviceInfo> for pb::Device {
+    fn from(device: DeviceInfo) -> Self {
+        pb::Device {
+            frontend_id: device.frontend_id.raw(),
+            guest_id: device.guest_id.raw(),
+            name: device.name,
+            device_type: device.device_type,
+            id: device.id.raw(),
+            irq: device.irq.raw(),
+            addr: device.addr.raw(),
+        }
+    }
+}
+
+impl From<DeviceStatsSnapshot> for pb::DeviceStats {
+    fn from(stats: DeviceStatsSnapshot) -> Self {
+        pb::DeviceStats {
+            name: stats.name,
+            mmio_reads: stats.mmio_reads,
+            mmio_writes: stats.mmio_writes,
+            interrupts: stats.interrupts,
+            latency: Some(pb::Latency {
+                count: stats.latency.count,
+                p50_ns: stats.latency.p50_ns,
+                p95_ns: stats.latency.p95_ns,
+                p99_ns: stats.latency.p99_ns,
+                max_ns: stats.latency.max_ns,
+            }),
+            queues: stats
+                .queues
+                .into_iter()
+                .map(|q| pb::QueueStats {
+                    avail_notifications: q.avail_notifications,
+                    used_completions: q.used_completions,
+                    interrupt_suppressions: q.interrupt_suppressions,
+                    descriptor_errors: q.descriptor_errors,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Converts coalescing parameters to their message.
+fn coalescing(name: &str, params: Option<ConfigCoalesce>) -> pb::DeviceCoalescing {
+    let params = params.unwrap_or_default();
+    pb::DeviceCoalescing {
+        name: name.to_string(),
+        max_completions: params.max_completions,
+        max_delay_us: params.max_delay_us,
+    }
+}
+
+/// Struct representing the gRPC adapter of a management implementation.
+///
+/// # Attributes
+///
+/// * `management` - The management implementation.
+pub struct GrpcManagement<M: Management> {
+    management: Arc<M>,
+}
+
+impl<M: Management> GrpcManagement<M> {
+    /// Creates the gRPC service of a management implementation.
+    ///
+    /// # Arguments
+    ///
+    /// * `management` - The management implementation.
+    ///
+    /// # Returns
+    ///
+    /// * `BaoManagementServer<GrpcManagement<M>>` - The service, ready to be added to a server.
+    pub fn service(management: Arc<M>) -> BaoManagementServer<Self> {
+        BaoManagementServer::new(GrpcManagement { management })
+    }
+}
+
+#[tonic::async_trait]
+impl<M: Management> BaoManagement for GrpcManagement<M> {
+    async fn list_devices(
+        &self,
+        _request: Request<pb::ListDevicesRequest>,
+    ) -> std::result::Result<Response<pb::ListDevicesResponse>, Status> {
+        Ok(Response::new(pb::ListDevicesResponse {
+            devices: self
+                .management
+                .devices()
+                .into_iter()
+                .map(pb::Device::from)
+                .collect(),
+        }))
+    }
+
+    async fn get_device_stats(
+        &self,
+        request: Request<pb::DeviceRequest>,
+    ) -> std::result::Result<Response<pb::DeviceStats>, Status> {
+        let stats = self.management.device_stats(&request.get_ref().name)?;
+        Ok(Response::new(stats.into()))
+    }
+
+    async fn reset_device_stats(
+        &self,
+        request: Request<pb::DeviceRequest>,
+    ) -> std::result::Result<Response<pb::ResetDeviceStatsResponse>, Status> {
+        self.management
+            .reset_device_stats(&request.get_ref().name)?;
+        Ok(Response::new(pb::ResetDeviceStatsResponse {}))
+    }
+
+    async fn get_device_coalescing(
+        &self,
+        request: Request<pb::DeviceRequest>,
+    ) -> std::result::Result<Response<pb::DeviceCoalescing>, Status> {
+        let name = &request.get_ref().name;
+        let params = self.management.device_coalescing(name)?;
+        Ok(Response::new(coalescing(name, params)))
+    }
+
+    async fn set_device_coalescing(
+        &self,
+        request: Request<pb::DeviceCoalescing>,
+    ) -> std::result::Result<Response<pb::DeviceCoalescing>, Status> {
+        let request = request.get_ref();
+        let params = ConfigCoalesce {
+            max_completions: request.max_completions,
+            max_delay_us: request.max_delay_us,
+        };
+        self.management
+            .set_device_coalescing(&request.name, Some(params))?;
+        let params = self.management.device_coalescing(&request.name)?;
+        Ok(Response::new(coalescing(&request.name, params)))
+    }
+}
+
+/// Serves the management service over gRPC until the server fails.
+///
+/// # Arguments
+///
+/// * `management` - The management implementation.
+/// * `addr` - Address to listen on.
+///
+/// # Returns
+///
+/// * `Result<()>` - Error if the server could not be started.
+pub async fn serve<M: Management>(management: Arc<M>, addr: SocketAddr) -> Result<()> {
+    tonic::transport::Server::builder()
+        .add_service(GrpcManagement::service(management))
+        .serve(addr)
+        .await
+        .map_err(|e| Error::ManagementServerFailed(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::management::DeviceRegistry;
+    use crate::stats::{inc, DeviceStats};
+
+    #[test]
+    fn test_grpc_management() {
+        let config = serde_yaml::from_str(
+            "frontends:
+  - name: frontend0
+    id: 0
+    guests:
+      - name: guest0
+        id: 1
+        ram_addr: 0x50000000
+        ram_size: 0x1000000
+        shmem_path: /dev/baoipc0
+        socket_path: /tmp/
+        devices:
+          - {name: rng0, id: 0, type: rng, irq: 47, addr: 0xa003e00}
+",
+        )
+        .unwrap();
+        let registry = Arc::new(DeviceRegistry::new(&config));
+        let stats = Arc::new(DeviceStats::new("rng0", 1));
+        inc(&stats.interrupts);
+        registry.attach_stats(stats);
+        let service = GrpcManagement {
+            management: registry,
+        };
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let devices = service
+                .list_devices(Request::new(pb::ListDevicesRequest {}))
+                .await
+                .unwrap()
+                .into_inner()
+                .devices;
+            assert_eq!(devices[0].addr, 0xa003e00);
+
+            let request = |name: &str| {
+                Request::new(pb::DeviceRequest {
+                    name: name.to_string(),
+                })
+            };
+            let stats = service
+                .get_device_stats(request("rng0"))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(stats.interrupts, 1);
+            let status = service.get_device_stats(request("rng1")).await.unwrap_err();
+            assert_eq!(status.code(), tonic::Code::NotFound);
+
+            let coalescing = service
+                .set_device_coalescing(Request::new(pb::DeviceCoalescing {
+                    name: "rng0".to_string(),
+                    max_completions: 16,
+                    max_delay_us: 100,
+                }))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(coalescing.max_completions, 16);
+            let coalescing = service
+                .get_device_coalescing(request("rng0"))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(coalescing.max_delay_us, 100);
+        });
+    }
+}
@@ -0,0 +1,480 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao live update.
+//!
+//! A running frontend hands its devices over to a new version of its binary without
+//! rebooting the guests: it spawns the successor with `spawn_successor`, quiesces the
+//! devices, and sends over the private channel a manifest (the state snapshot of every
+//! device, see `snapshot`) followed by the file descriptors the devices are served with
+//! (Bao device models, guest memory, ioeventfds, irqfds and vhost-user sockets), passed
+//! with SCM_RIGHTS. The successor takes the channel with `successor_channel`, receives
+//! the handoff, maps the guest memory again, restores the device states and acknowledges
+//! the takeover, at which point the previous frontend exits. The devices only stall from
+//! the quiescing to the acknowledgement.
+//!
+//! Seccomp filters are inherited across exec, and the frontend profile does not allow
+//! spawning processes, so a frontend running under a seccomp filter cannot spawn its
+//! successor.
+//!
+//! Only the successor side runs in the frontend binary so far (it takes over when started
+//! with a channel). Nothing triggers the hand over from a running frontend yet: it needs
+//! the state of every running device, and the devices served by the runtime cannot be
+//! snapshotted (see `Management::snapshot`).
+//!
+//! Wire format: the manifest is a little-endian `u32` length followed by its YAML
+//! encoding; the file descriptors follow, in manifest order, in messages of up to
+//! `BAO_HANDOFF_MAX_FDS` descriptors whose data is their count (a little-endian `u32`);
+//! the takeover is acknowledged with a single byte.
+
+#![allow(dead_code)]
+
+use super::defines::{
+    BAO_HANDOFF_CHANNEL_ENV, BAO_HANDOFF_CHANNEL_FD, BAO_HANDOFF_MAX_FDS, BAO_HANDOFF_MAX_MANIFEST,
+    BAO_HANDOFF_VERSION,
+};
+use super::error::{Error, Result};
+use super::snapshot::FrontendSnapshot;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command};
+use std::ptr;
+use std::time::Duration;
+
+/// Takeover acknowledgement.
+const TAKEOVER_ACK: u8 = 1;
+
+/// Enum representing what a handed over file descriptor is.
+///
+/// # Variants
+///
+/// * `Backend` - Bao device model (I/O client of a guest).
+/// * `GuestMemory` - Guest memory (shared memory device), to be mapped again.
+/// * `IoEventFd` - Queue notification eventfd.
+/// * `IrqFd` - Interrupt injection eventfd.
+/// * `VhostUser` - Connection to a vhost-user device backend.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HandoffKind {
+    Backend,
+    GuestMemory,
+    IoEventFd,
+    IrqFd,
+    VhostUser,
+}
+
+/// Struct representing a handed over file descriptor.
+///
+/// # Attributes
+///
+/// * `kind` - What the file descriptor is.
+/// * `name` - Owner of the file descriptor (guest or device name).
+/// * `addr` - Guest address of the memory or of the notified register (if any).
+/// * `size` - Size of the memory (if any).
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct HandoffFd {
+    pub kind: HandoffKind,
+    pub name: String,
+    #[serde(default)]
+    pub addr: u64,
+    #[serde(default)]
+    pub size: u64,
+}
+
+/// Struct representing the manifest of a handoff.
+///
+/// # Attributes
+///
+/// * `version` - Protocol version.
+/// * `fds` - The file descriptors that follow, in order.
+/// * `snapshot` - State of the devices.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+struct HandoffManifest {
+    version: u32,
+    fds: Vec<HandoffFd>,
+    snapshot: FrontendSnapshot,
+}
+
+/// Struct representing what a frontend hands over to its successor.
+///
+/// # Attributes
+///
+/// * `snapshot` - State of the devices.
+/// * `fds` - The file descriptors the devices are served with.
+#[derive(Debug)]
+pub struct Handoff {
+    pub snapshot: FrontendSnapshot,
+    pub fds: Vec<(HandoffFd, OwnedFd)>,
+}
+
+impl Handoff {
+    /// Returns the first file descriptor of a kind owned by a guest or device.
+    ///
+    /// # Arguments
+    ///
+    /// * `kind` - What the file descriptor is.
+    /// * `name` - Owner of the file descriptor.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<&OwnedFd>` - The file descriptor, if handed over.
+    pub fn fd(&self, kind: HandoffKind, name: &str) -> Option<&OwnedFd> {
+        self.fds
+            .iter()
+            .find(|(fd, _)| fd.kind == kind && fd.name == name)
+            .map(|(_, fd)| fd)
+    }
+
+    /// Sends the handoff to the successor.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The channel to the successor.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok once everything was sent.
+    pub fn send(&self, channel: &UnixStream) -> Result<()> {
+        let manifest = HandoffManifest {
+            version: BAO_HANDOFF_VERSION,
+            fds: self.fds.iter().map(|(fd, _)| fd.clone()).collect(),
+            snapshot: self.snapshot.clone(),
+        };
+        let yaml =
+            serde_yaml::to_string(&manifest).map_err(|e| Error::InvalidHandoff(e.to_string()))?;
+        let mut writer = channel;
+        writer
+            .write_all(&(yaml.len() as u32).to_le_bytes())
+            .and_then(|_| writer.write_all(yaml.as_bytes()))
+            .map_err(|e| Error::HandoffFailed("send", e))?;
+
+        let fds = self
+            .fds
+            .iter()
+            .map(|(_, fd)| fd.as_raw_fd())
+            .collect::<Vec<_>>();
+        for chunk in fds.chunks(BAO_HANDOFF_MAX_FDS) {
+            send_fds(channel, chunk).map_err(|e| Error::HandoffFailed("send", e))?;
+        }
+        Ok(())
+    }
+
+    /// Receives the handoff of the previous frontend.
+    ///
+    /// # Arguments
+    ///
+    /// * `channel` - The channel to the previous frontend.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Handoff>` - The handoff, or `InvalidHandoff` if it is malformed or of
+    ///   another protocol version.
+    pub fn receive(channel: &UnixStream) -> Result<Self> {
+        let mut reader = channel;
+        let mut len = [0u8; 4];
+        reader
+            .read_exact(&mut len)
+            .map_err(|e| Error::HandoffFailed("receive", e))?;
+        let len = u32::from_le_bytes(len) as usize;
+        if len > BAO_HANDOFF_MAX_MANIFEST {
+            return Err(Error::InvalidHandoff(format!("{} bytes manifest", len)));
+        }
+        let mut yaml = vec![0u8; len];
+        reader
+            .read_exact(&mut yaml)
+            .map_err(|e| Error::HandoffFailed("receive", e))?;
+        let manifest: HandoffManifest =
+            serde_yaml::from_slice(&yaml).map_err(|e| Error::InvalidHandoff(e.to_string()))?;
+        if manifest.version != BAO_HANDOFF_VERSION {
+            return Err(Error::InvalidHandoff(format!(
+                "unsupported version {}",
+                manifest.version
+            )));
+        }
+
+        let mut fds = Vec::with_capacity(manifest.fds.len());
+        while fds.len() < manifest.fds.len() {
+            let expected = (manifest.fds.len() - fds.len()).min(BAO_HANDOFF_MAX_FDS);
+            let received = recv_fds(channel).map_err(|e| Error::HandoffFailed("receive", e))?;
+            if received.len() != expected {
+                return Err(Error::InvalidHandoff(format!(
+                    "{} file descriptors instead of {}",
+                    received.len(),
+                    expected
+                )));
+            }
+            fds.extend(received);
+        }
+        Ok(Handoff {
+            snapshot: manifest.snapshot,
+            fds: manifest.fds.into_iter().zip(fds).collect(),
+        })
+    }
+}
+
+/// Spawns the successor of the frontend (e.g. its upgraded binary).
+///
+/// The successor inherits one end of a private socket pair as file descriptor
+/// `BAO_HANDOFF_CHANNEL_FD` (advertised in the `BAO_HANDOFF_CHANNEL_ENV` environment
+/// variable), over which the handoff is sent.
+///
+/// # Arguments
+///
+/// * `command` - Command that runs the successor.
+///
+/// # Returns
+///
+/// * `Result<(Child, UnixStream)>` - The successor process and the channel to it.
+pub fn spawn_successor(mut command: Command) -> Result<(Child, UnixStream)> {
+    let (frontend, successor) =
+        UnixStream::pair().map_err(|e| Error::HandoffFailed("socketpair", e))?;
+    let successor_fd = successor.as_raw_fd();
+
+    // SAFETY: Only async-signal-safe functions are called between fork and exec.
+    unsafe {
+        command.pre_exec(move || {
+            // Move the channel to its well-known descriptor (dup2 clears FD_CLOEXEC,
+            // which must be cleared by hand if it is already there)
+            let result = match successor_fd == BAO_HANDOFF_CHANNEL_FD {
+                true => libc::fcntl(successor_fd, libc::F_SETFD, 0),
+                false => libc::dup2(successor_fd, BAO_HANDOFF_CHANNEL_FD),
+            };
+            if result < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        })
+    };
+    let child = command
+        .env(BAO_HANDOFF_CHANNEL_ENV, BAO_HANDOFF_CHANNEL_FD.to_string())
+        .spawn()
+        .map_err(|e| Error::HandoffFailed("spawn", e))?;
+
+    Ok((child, frontend))
+}
+
+/// Returns the channel to the previous frontend, when running as its successor.
+///
+/// # Returns
+///
+/// * `Option<UnixStream>` - The channel, if the process was spawned by `spawn_successor`.
+pub fn successor_channel() -> Option<UnixStream> {
+    let fd = std::env::var(BAO_HANDOFF_CHANNEL_ENV)
+        .ok()?
+        .parse::<i32>()
+        .ok()?;
+    std::env::remove_var(BAO_HANDOFF_CHANNEL_ENV);
+    // SAFETY: The descriptor was set up by the previous frontend and is owned by this
+    // process; it must not leak into the processes spawned from now on.
+    unsafe {
+        libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        Some(UnixStream::from_raw_fd(fd))
+    }
+}
+
+/// Acknowledges the takeover to the previous frontend (once the devices are served).
+///
+/// # Arguments
+///
+/// * `channel` - The channel to the previous frontend.
+///
+/// # Returns
+///
+/// * `Result<()>` - Ok if the acknowledgement was sent.
+pub fn complete(channel: &UnixStream) -> Result<()> {
+    let mut writer = channel;
+    writer
+        .write_all(&[TAKEOVER_ACK])
+        .map_err(|e| Error::HandoffFailed("acknowledge", e))
+}
+
+/// Waits for the successor to acknowledge the takeover.
+///
+/// # Arguments
+///
+/// * `channel` - The channel to the successor.
+/// * `timeout` - Maximum time to wait (forever if None).
+///
+/// # Returns
+///
+/// * `Result<()>` - Ok once the successor took over; the previous frontend keeps the
+///   devices (and should resume them) on error.
+pub fn wait_takeover(channel: &UnixStream, timeout: Option<Duration>) -> Result<()> {
+    channel
+        .set_read_timeout(timeout)
+        .map_err(|e| Error::HandoffFailed("takeover", e))?;
+    let mut ack = [0u8; 1];
+    let mut reader = channel;
+    reader
+        .read_exact(&mut ack)
+        .map_err(|e| Error::HandoffFailed("takeover", e))?;
+    match ack[0] {
+        TAKEOVER_ACK => Ok(()),
+        ack => Err(Error::InvalidHandoff(format!("acknowledgement {:#x}", ack))),
+    }
+}
+
+/// Returns the size of the control buffer holding `BAO_HANDOFF_MAX_FDS` descriptors, in
+/// 64-bit words (for the alignment of the control message headers).
+fn control_words() -> usize {
+    // SAFETY: `CMSG_SPACE` has no memory safety requirements.
+    let space = unsafe { libc::CMSG_SPACE((BAO_HANDOFF_MAX_FDS * mem::size_of::<RawFd>()) as u32) };
+    (space as usize).div_ceil(mem::size_of::<u64>())
+}
+
+/// Sends a message of file descriptors.
+fn send_fds(channel: &UnixStream, fds: &[RawFd]) -> io::Result<()> {
+    let mut count = (fds.len() as u32).to_le_bytes();
+    let mut iov = libc::iovec {
+        iov_base: count.as_mut_ptr() as *mut libc::c_void,
+        iov_len: count.len(),
+    };
+    let mut control = vec![0u64; control_words()];
+    let len = mem::size_of_val(fds) as u32;
+    // SAFETY: An all-zero `msghdr` is valid.
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    // SAFETY: `CMSG_SPACE` has no memory safety requirements.
+    msg.msg_controllen = unsafe { libc::CMSG_SPACE(len) } as _;
+    // SAFETY: The control buffer is large enough for `BAO_HANDOFF_MAX_FDS` descriptors.
+    unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN(len) as _;
+        ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg) as *mut RawFd, fds.len());
+    }
+
+    // SAFETY: `msg` points to buffers valid for their advertised lengths.
+    let sent = unsafe { libc::sendmsg(channel.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut writer = channel;
+    writer.write_all(&count[sent as usize..])
+}
+
+/// Receives a message of file descriptors.
+fn recv_fds(channel: &UnixStream) -> io::Result<Vec<OwnedFd>> {
+    let mut count = [0u8; 4];
+    let mut iov = libc::iovec {
+        iov_base: count.as_mut_ptr() as *mut libc::c_void,
+        iov_len: count.len(),
+    };
+    let mut control = vec![0u64; control_words()];
+    // SAFETY: An all-zero `msghdr` is valid.
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(control.as_slice()) as _;
+
+    // SAFETY: `msg` points to buffers valid for their advertised lengths.
+    let received = unsafe { libc::recvmsg(channel.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if received == 0 {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+    }
+
+    let mut fds = Vec::new();
+    // SAFETY: `msg` was filled by `recvmsg`.
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        // SAFETY: `cmsg` points to a control message header of `msg`.
+        unsafe {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let count = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize)
+                    / mem::size_of::<RawFd>();
+                for index in 0..count {
+                    fds.push(OwnedFd::from_raw_fd(ptr::read_unaligned(data.add(index))));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 {
+        return Err(io::Error::from(io::ErrorKind::InvalidData));
+    }
+
+    let mut reader = channel;
+    reader.read_exact(&mut count[received as usize..])?;
+    if u32::from_le_bytes(count) as usize != fds.len() {
+        return Err(io::Error::from(io::ErrorKind::InvalidData));
+    }
+    Ok(fds)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::BorrowedFd;
+    use std::thread;
+    use vmm_sys_util::eventfd::EventFd;
+
+    #[test]
+    fn test_handoff() {
+        // More eventfds than fit in a single message
+        let eventfds = (0..BAO_HANDOFF_MAX_FDS + 6)
+            .map(|_| EventFd::new(0).unwrap())
+            .collect::<Vec<_>>();
+        let handoff = Handoff {
+            snapshot: FrontendSnapshot::new(vec![]),
+            fds: eventfds
+                .iter()
+                .enumerate()
+                .map(|(index, eventfd)| {
+                    let fd = HandoffFd {
+                        kind: HandoffKind::IrqFd,
+                        name: format!("dev{}", index),
+                        addr: 0,
+                        size: 0,
+                    };
+                    // SAFETY: The eventfd outlives the borrow.
+                    let eventfd = unsafe { BorrowedFd::borrow_raw(eventfd.as_raw_fd()) };
+                    (fd, eventfd.try_clone_to_owned().unwrap())
+                })
+                .collect(),
+        };
+
+        let (frontend, successor) = UnixStream::pair().unwrap();
+        let receiver = thread::spawn(move || {
+            let handoff = Handoff::receive(&successor).unwrap();
+            complete(&successor).unwrap();
+            handoff
+        });
+        handoff.send(&frontend).unwrap();
+        wait_takeover(&frontend, Some(Duration::from_secs(5))).unwrap();
+        let received = receiver.join().unwrap();
+        assert_eq!(received.snapshot, handoff.snapshot);
+        assert_eq!(received.fds.len(), eventfds.len());
+
+        // The successor signals the same eventfds
+        let last = format!("dev{}", eventfds.len() - 1);
+        let fd = received.fd(HandoffKind::IrqFd, &last).unwrap();
+        let mut file = std::fs::File::from(fd.try_clone().unwrap());
+        file.write_all(&7u64.to_ne_bytes()).unwrap();
+        assert_eq!(eventfds.last().unwrap().read().unwrap(), 7);
+        assert!(received.fd(HandoffKind::Backend, &last).is_none());
+    }
+
+    #[test]
+    fn test_spawn_successor() {
+        // The successor acknowledges the takeover on its channel
+        let mut command = Command::new("sh");
+        command.args(["-c", "printf '\\001' >&$BAO_HANDOFF_CHANNEL_FD"]);
+        let (mut child, channel) = spawn_successor(command).unwrap();
+        wait_takeover(&channel, Some(Duration::from_secs(5))).unwrap();
+        assert!(child.wait().unwrap().success());
+    }
+}
@@ -0,0 +1,377 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao hypervisor interface.
+//!
+//! The `Hypervisor` trait abstracts the I/O dispatcher of the Bao kernel module, so the
+//! device model can run against the real kernel interface (`BaoHypervisor`) or against
+//! the mock of the `testing` module.
+
+#![allow(dead_code)]
+
+use super::defines::{BAO_IO_ASK, BAO_IO_DISPATCHER_PATH};
+use super::error::{Error, Result};
+use super::ioctl::*;
+use super::types::{BaoIoEventFd, BaoIoRequest, BaoIrqFd};
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use vmm_sys_util::ioctl::{ioctl, ioctl_with_mut_ref, ioctl_with_ref};
+
+/// Operations of the Bao I/O dispatcher used by a device model.
+pub trait Hypervisor: Send + Sync {
+    /// Waits until an I/O request is pending.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok once a request is pending.
+    fn attach_client(&self) -> Result<()>;
+
+    /// Waits up to a timeout for an I/O request to be pending.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout` - Maximum time to wait.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<bool>` - True if a request is pending, false on timeout.
+    fn wait_request(&self, timeout: Duration) -> Result<bool>;
+
+    /// Fetches the next pending I/O request.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<BaoIoRequest>>` - The request, or None if no request is pending.
+    fn next_request(&self) -> Result<Option<BaoIoRequest>>;
+
+    /// Notifies the completion of an I/O request.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The completed I/O request.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the completion was delivered.
+    fn complete_request(&self, req: &BaoIoRequest) -> Result<()>;
+
+    /// Injects the device interrupt into the guest.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the interrupt was injected.
+    fn notify_guest(&self) -> Result<()>;
+
+    /// Assigns (or deassigns) an I/O eventfd.
+    ///
+    /// # Arguments
+    ///
+    /// * `ioeventfd` - The I/O eventfd.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the eventfd was (de)assigned.
+    fn register_ioeventfd(&self, ioeventfd: &BaoIoEventFd) -> Result<()>;
+
+    /// Assigns (or deassigns) an IRQ eventfd.
+    ///
+    /// # Arguments
+    ///
+    /// * `irqfd` - The IRQ eventfd.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the eventfd was (de)assigned.
+    fn register_irqfd(&self, irqfd: &BaoIrqFd) -> Result<()>;
+}
+
+impl<T: Hypervisor + ?Sized> Hypervisor for &T {
+    fn attach_client(&self) -> Result<()> {
+        (**self).attach_client()
+    }
+
+    fn wait_request(&self, timeout: Duration) -> Result<bool> {
+        (**self).wait_request(timeout)
+    }
+
+    fn next_request(&self) -> Result<Option<BaoIoRequest>> {
+        (**self).next_request()
+    }
+
+    fn complete_request(&self, req: &BaoIoRequest) -> Result<()> {
+        (**self).complete_request(req)
+    }
+
+    fn notify_guest(&self) -> Result<()> {
+        (**self).notify_guest()
+    }
+
+    fn register_ioeventfd(&self, ioeventfd: &BaoIoEventFd) -> Result<()> {
+        (**self).register_ioeventfd(ioeventfd)
+    }
+
+    fn register_irqfd(&self, irqfd: &BaoIrqFd) -> Result<()> {
+        (**self).register_irqfd(irqfd)
+    }
+}
+
+impl<T: Hypervisor + ?Sized> Hypervisor for Arc<T> {
+    fn attach_client(&self) -> Result<()> {
+        (**self).attach_client()
+    }
+
+    fn wait_request(&self, timeout: Duration) -> Result<bool> {
+        (**self).wait_request(timeout)
+    }
+
+    fn next_request(&self) -> Result<Option<BaoIoRequest>> {
+        (**self).next_request()
+    }
+
+    fn complete_request(&self, req: &BaoIoRequest) -> Result<()> {
+        (**self).complete_request(req)
+    }
+
+    fn notify_guest(&self) -> Result<()> {
+        (**self).notify_guest()
+    }
+
+    fn register_ioeventfd(&self, ioeventfd: &BaoIoEventFd) -> Result<()> {
+        (**self).register_ioeventfd(ioeventfd)
+    }
+
+    fn register_irqfd(&self, irqfd: &BaoIrqFd) -> Result<()> {
+        (**self).register_irqfd(irqfd)
+    }
+}
+
+/// Checks the return value of an ioctl.
+fn check_ioctl(ret: i32, name: &'static str) -> Result<i32> {
+    match ret {
+        ret if ret < 0 => Err(Error::BaoIoctlError(io::Error::last_os_error(), name)),
+        ret => Ok(ret),
+    }
+}
+
+/// Creates the I/O client of a device model.
+///
+/// # Arguments
+///
+/// * `dm_id` - Device model ID.
+///
+/// # Returns
+///
+/// * `Result<File>` - The device model file.
+pub fn create_backend(dm_id: u32) -> Result<File> {
+    let dispatcher = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(BAO_IO_DISPATCHER_PATH)
+        .map_err(|e| Error::OpenFdFailed("io-dispatcher", e))?;
+    // SAFETY: The ioctl only reads the device model ID.
+    let fd = check_ioctl(
+        unsafe { ioctl_with_ref(&dispatcher, BAO_IOCTL_VM_VIRTIO_BACKEND_CREATE(), &dm_id) },
+        "BAO_IOCTL_VM_VIRTIO_BACKEND_CREATE",
+    )?;
+    // SAFETY: The ioctl returned a new file descriptor, owned by the caller.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+/// Waits until an I/O request of a device model is pending.
+///
+/// # Arguments
+///
+/// * `fd` - Device model file descriptor.
+pub fn attach_client(fd: &impl AsRawFd) -> Result<()> {
+    // SAFETY: The ioctl takes no argument.
+    check_ioctl(
+        unsafe { ioctl(fd, BAO_IOCTL_IO_ATTACH_CLIENT()) },
+        "BAO_IOCTL_IO_ATTACH_CLIENT",
+    )
+    .map(|_| ())
+}
+
+/// Waits up to a timeout for an I/O request of a device model to be pending.
+///
+/// # Arguments
+///
+/// * `fd` - Device model file descriptor.
+/// * `timeout` - Maximum time to wait.
+///
+/// # Returns
+///
+/// * `Result<bool>` - True if a request is pending, false on timeout.
+pub fn wait_request(fd: &impl AsRawFd, timeout: Duration) -> Result<bool> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let mut pollfd = libc::pollfd {
+            fd: fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // Round up, so short timeouts do not turn into a non-blocking check
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let millis = remaining
+            .as_nanos()
+            .div_ceil(1_000_000)
+            .min(i32::MAX as u128) as i32;
+        // SAFETY: `pollfd` is valid for the duration of the call.
+        match unsafe { libc::poll(&mut pollfd, 1, millis) } {
+            ret if ret < 0 => {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::Interrupted {
+                    return Err(Error::EpollWait(err));
+                }
+            }
+            _ => return Ok(pollfd.revents & libc::POLLIN != 0),
+        }
+    }
+}
+
+/// Fetches the next pending I/O request of a device model.
+///
+/// # Arguments
+///
+/// * `fd` - Device model file descriptor.
+/// * `req` - Filled with the pending I/O request.
+pub fn request(fd: &impl AsRawFd, req: &mut BaoIoRequest) -> Result<()> {
+    req.op = BAO_IO_ASK;
+    // SAFETY: The ioctl fills a `BaoIoRequest`.
+    check_ioctl(
+        unsafe { ioctl_with_mut_ref(fd, BAO_IOCTL_IO_REQUEST(), req) },
+        "BAO_IOCTL_IO_REQUEST",
+    )
+    .map(|_| ())
+}
+
+/// Notifies the completion of an I/O request.
+///
+/// # Arguments
+///
+/// * `fd` - Device model file descriptor.
+/// * `req` - The completed I/O request.
+pub fn notify_completed(fd: &impl AsRawFd, req: &BaoIoRequest) -> Result<()> {
+    // SAFETY: The ioctl only reads the `BaoIoRequest`.
+    check_ioctl(
+        unsafe { ioctl_with_ref(fd, BAO_IOCTL_IO_REQUEST_NOTIFY_COMPLETED(), req) },
+        "BAO_IOCTL_IO_REQUEST_NOTIFY_COMPLETED",
+    )
+    .map(|_| ())
+}
+
+/// Injects the device interrupt into the guest.
+///
+/// # Arguments
+///
+/// * `fd` - Device model file descriptor.
+pub fn notify_guest(fd: &impl AsRawFd) -> Result<()> {
+    // SAFETY: The ioctl takes no argument.
+    check_ioctl(
+        unsafe { ioctl(fd, BAO_IOCTL_IO_NOTIFY_GUEST()) },
+        "BAO_IOCTL_IO_NOTIFY_GUEST",
+    )
+    .map(|_| ())
+}
+
+/// Assigns (or deassigns) an I/O eventfd.
+///
+/// # Arguments
+///
+/// * `fd` - Device model file descriptor.
+/// * `ioeventfd` - The I/O eventfd.
+pub fn ioeventfd(fd: &impl AsRawFd, ioeventfd: &BaoIoEventFd) -> Result<()> {
+    // SAFETY: The ioctl only reads the `BaoIoEventFd`.
+    check_ioctl(
+        unsafe { ioctl_with_ref(fd, BAO_IOCTL_IOEVENTFD(), ioeventfd) },
+        "BAO_IOCTL_IOEVENTFD",
+    )
+    .map(|_| ())
+}
+
+/// Assigns (or deassigns) an IRQ eventfd.
+///
+/// # Arguments
+///
+/// * `fd` - Device model file descriptor.
+/// * `irqfd` - The IRQ eventfd.
+pub fn irqfd(fd: &impl AsRawFd, irqfd: &BaoIrqFd) -> Result<()> {
+    // SAFETY: The ioctl only reads the `BaoIrqFd`.
+    check_ioctl(
+        unsafe { ioctl_with_ref(fd, BAO_IOCTL_IRQFD(), irqfd) },
+        "BAO_IOCTL_IRQFD",
+    )
+    .map(|_| ())
+}
+
+/// Struct representing the Bao I/O dispatcher of a device model.
+///
+/// # Attributes
+///
+/// * `file` - Device model file.
+pub struct BaoHypervisor {
+    file: File,
+}
+
+impl BaoHypervisor {
+    /// Creates the I/O client of a device model.
+    ///
+    /// # Arguments
+    ///
+    /// * `dm_id` - Device model ID.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<BaoHypervisor>` - The I/O dispatcher of the device model.
+    pub fn new(dm_id: u32) -> Result<Self> {
+        Ok(BaoHypervisor {
+            file: create_backend(dm_id)?,
+        })
+    }
+}
+
+impl Hypervisor for BaoHypervisor {
+    fn attach_client(&self) -> Result<()> {
+        attach_client(&self.file)
+    }
+
+    fn wait_request(&self, timeout: Duration) -> Result<bool> {
+        wait_request(&self.file, timeout)
+    }
+
+    fn next_request(&self) -> Result<Option<BaoIoRequest>> {
+        let mut req = BaoIoRequest {
+            virtio_id: 0,
+            reg_off: 0,
+            addr: 0,
+            op: BAO_IO_ASK,
+            value: 0,
+            access_width: 0,
+            cpu_id: 0,
+            vcpu_id: 0,
+            ret: 0,
+        };
+        request(&self.file, &mut req)?;
+        Ok(Some(req))
+    }
+
+    fn complete_request(&self, req: &BaoIoRequest) -> Result<()> {
+        notify_completed(&self.file, req)
+    }
+
+    fn notify_guest(&self) -> Result<()> {
+        notify_guest(&self.file)
+    }
+
+    fn register_ioeventfd(&self, ioeventfd: &BaoIoEventFd) -> Result<()> {
+        self::ioeventfd(&self.file, ioeventfd)
+    }
+
+    fn register_irqfd(&self, irqfd: &BaoIrqFd) -> Result<()> {
+        self::irqfd(&self.file, irqfd)
+    }
+}
@@ -0,0 +1,680 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao built-in I2C backend.
+//!
+//! Passes the requests of a virtio-i2c device through to an I2C adapter of the host,
+//! in-process (`backend: bao-i2c`), the `adapter` backend option naming its character
+//! device (e.g. `/dev/i2c-1`). Every request is a transfer of its own (`I2C_RDWR`).
+//!
+//! The guest shares the physical bus with peripherals of the host, some of which may be
+//! safety relevant, so what it may reach is restricted by the backend options:
+//!
+//! * `allow` - The 7-bit addresses the guest may reach, each either read-only (`0x50:ro`,
+//!   read transfers only, so a register pointer cannot be set) or read-write
+//!   (`0x51:rw`), separated by commas. Without it, every address is reachable read-write.
+//! * `rate` - Maximum number of transactions per second, in bursts of up to a second's
+//!   worth.
+//!
+//! A transaction is a run of requests chained with `VIRTIO_I2C_FLAGS_FAIL_NEXT`. Once one
+//! of its requests is refused (an address that is not allowed, a write to a read-only
+//! address, or a transaction over the rate) or fails, it completes with
+//! `VIRTIO_I2C_MSG_ERR`, as do the remaining requests of the transaction, which never
+//! reach the bus.
+
+#![allow(dead_code)]
+
+use super::backend::InProcessBackend;
+use super::defines::*;
+use super::error::{Error, Result};
+use super::poll::EventPoller;
+use super::types::ConfigDevice;
+use super::vhost_backend::{VhostUserBackend, VirtioDevice};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::str::FromStr;
+use std::time::Instant;
+
+/// Enum representing what the guest may do with an I2C address.
+///
+/// # Variants
+///
+/// * `ReadOnly` - Read transfers only.
+/// * `ReadWrite` - Read and write transfers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum I2cAccess {
+    ReadOnly,
+    ReadWrite,
+}
+
+impl fmt::Display for I2cAccess {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            I2cAccess::ReadOnly => f.write_str("ro"),
+            I2cAccess::ReadWrite => f.write_str("rw"),
+        }
+    }
+}
+
+impl FromStr for I2cAccess {
+    type Err = Error;
+
+    fn from_str(access: &str) -> Result<Self> {
+        match access {
+            "ro" => Ok(I2cAccess::ReadOnly),
+            "rw" => Ok(I2cAccess::ReadWrite),
+            _ => Err(Error::InvalidBackendOption(
+                "allow".to_string(),
+                format!("unknown access {} (ro or rw)", access),
+            )),
+        }
+    }
+}
+
+/// Struct representing what the guest may reach on the bus of an I2C device.
+///
+/// # Attributes
+///
+/// * `allow` - Reachable 7-bit addresses, with their access (every address read-write if
+///   None).
+/// * `rate` - Maximum number of transactions per second (unlimited if None).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct I2cPolicy {
+    pub allow: Option<BTreeMap<u16, I2cAccess>>,
+    pub rate: Option<u32>,
+}
+
+impl I2cPolicy {
+    /// Reads the policy of a device from its backend options.
+    ///
+    /// # Arguments
+    ///
+    /// * `device` - The device, whose `allow` and `rate` options set the policy.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<I2cPolicy>` - The policy, `InvalidBackendOption` if an option is invalid.
+    pub fn from_device(device: &ConfigDevice) -> Result<Self> {
+        let option = |key: &str| device.options.get(key).map(String::as_str);
+        let invalid =
+            |key: &str, reason: String| Error::InvalidBackendOption(key.to_string(), reason);
+
+        let allow = match option("allow") {
+            Some(list) => {
+                let mut allow = BTreeMap::new();
+                for entry in list.split(',').map(str::trim) {
+                    let (addr, access) = entry.split_once(':').ok_or_else(|| {
+                        invalid(
+                            "allow",
+                            format!("{} is not ADDRESS:ro or ADDRESS:rw", entry),
+                        )
+                    })?;
+                    let addr = u16::from_str_radix(addr.trim_start_matches("0x"), 16)
+                        .ok()
+                        .filter(|&addr| addr <= I2C_ADDR_7BIT_MAX)
+                        .ok_or_else(|| {
+                            invalid("allow", format!("{} is not a 7-bit address", addr))
+                        })?;
+                    if allow.insert(addr, access.parse()?).is_some() {
+                        return Err(invalid("allow", format!("{:#04x} is listed twice", addr)));
+                    }
+                }
+                Some(allow)
+            }
+            None => None,
+        };
+        let rate = match option("rate") {
+            Some(rate) => Some(
+                rate.parse::<u32>()
+                    .ok()
+                    .filter(|&rate| rate > 0)
+                    .ok_or_else(|| invalid("rate", format!("{} is not a positive number", rate)))?,
+            ),
+            None => None,
+        };
+        Ok(I2cPolicy { allow, rate })
+    }
+
+    /// Checks whether a transfer is allowed.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - 7-bit address.
+    /// * `read` - Whether the transfer is a read.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - Whether the transfer may reach the bus.
+    pub fn permits(&self, addr: u16, read: bool) -> bool {
+        match &self.allow {
+            None => true,
+            Some(allow) => match allow.get(&addr) {
+                Some(I2cAccess::ReadWrite) => true,
+                Some(I2cAccess::ReadOnly) => read,
+                None => false,
+            },
+        }
+    }
+}
+
+/// Struct representing a token bucket limiting the rate of the transactions.
+///
+/// # Attributes
+///
+/// * `rate` - Transactions per second (also the size of the bucket).
+/// * `tokens` - Transactions that may start right away.
+/// * `last` - When the bucket was last refilled.
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimiter {
+    rate: u32,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    /// Creates a rate limiter, full.
+    ///
+    /// # Arguments
+    ///
+    /// * `rate` - Transactions per second.
+    ///
+    /// # Returns
+    ///
+    /// * `RateLimiter` - The rate limiter.
+    pub fn new(rate: u32) -> Self {
+        RateLimiter {
+            rate,
+            tokens: f64::from(rate),
+            last: Instant::now(),
+        }
+    }
+
+    /// Admits a transaction, if the rate allows it.
+    ///
+    /// # Arguments
+    ///
+    /// * `now` - Current time.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - Whether the transaction may start.
+    pub fn admit(&mut self, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * f64::from(self.rate)).min(f64::from(self.rate));
+        self.last = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// A host I2C bus, on which the requests of a device are transferred.
+pub trait I2cBus: Send {
+    /// Transfers a message.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - 7-bit address.
+    /// * `read` - Whether the message is read (into `buf`) rather than written.
+    /// * `buf` - The message (empty for a zero-length transfer).
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<()>` - Ok once the message was transferred.
+    fn transfer(&mut self, addr: u16, read: bool, buf: &mut [u8]) -> io::Result<()>;
+}
+
+/// Struct representing a message of an `I2C_RDWR` transfer (`struct i2c_msg`).
+#[repr(C)]
+struct I2cMsg {
+    addr: u16,
+    flags: u16,
+    len: u16,
+    buf: *mut u8,
+}
+
+/// Struct representing an `I2C_RDWR` transfer (`struct i2c_rdwr_ioctl_data`).
+#[repr(C)]
+struct I2cRdwrData {
+    msgs: *mut I2cMsg,
+    nmsgs: u32,
+}
+
+/// Struct representing an I2C adapter of the host (`/dev/i2c-N`).
+///
+/// # Attributes
+///
+/// * `file` - The character device of the adapter.
+#[derive(Debug)]
+pub struct I2cAdapter {
+    file: File,
+}
+
+impl I2cAdapter {
+    /// Opens an I2C adapter.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of the character device of the adapter.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<I2cAdapter>` - The adapter, or `I2cAdapterFailed`.
+    pub fn open(path: &str) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .map_err(|e| Error::I2cAdapterFailed(path.to_string(), e))?;
+        Ok(I2cAdapter { file })
+    }
+}
+
+impl I2cBus for I2cAdapter {
+    fn transfer(&mut self, addr: u16, read: bool, buf: &mut [u8]) -> io::Result<()> {
+        let len =
+            u16::try_from(buf.len()).map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+        let mut msg = I2cMsg {
+            addr,
+            flags: if read { I2C_M_RD } else { 0 },
+            len,
+            buf: buf.as_mut_ptr(),
+        };
+        let mut data = I2cRdwrData {
+            msgs: &mut msg,
+            nmsgs: 1,
+        };
+        // SAFETY: The transfer describes a single message, whose buffer is valid for its
+        // length, and both outlive the call.
+        if unsafe { libc::ioctl(self.file.as_raw_fd(), I2C_RDWR as _, &mut data) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Struct representing a virtio-i2c device passed through to a host bus.
+///
+/// # Attributes
+///
+/// * `bus` - The host bus.
+/// * `policy` - What the guest may reach on the bus.
+/// * `limiter` - Rate limiter of the transactions (None if unlimited).
+/// * `in_transaction` - Whether the previous request was chained to the next one.
+/// * `failing` - Whether the current transaction failed.
+/// * `denied` - Number of requests refused by the policy.
+/// * `limited` - Number of transactions refused by the rate limiter.
+pub struct I2cDevice<B: I2cBus> {
+    bus: B,
+    policy: I2cPolicy,
+    limiter: Option<RateLimiter>,
+    in_transaction: bool,
+    failing: bool,
+    denied: u64,
+    limited: u64,
+}
+
+impl<B: I2cBus> I2cDevice<B> {
+    /// Creates an I2C device.
+    ///
+    /// # Arguments
+    ///
+    /// * `bus` - The host bus.
+    /// * `policy` - What the guest may reach on the bus.
+    ///
+    /// # Returns
+    ///
+    /// * `I2cDevice<B>` - The device.
+    pub fn new(bus: B, policy: I2cPolicy) -> Self {
+        I2cDevice {
+            bus,
+            limiter: policy.rate.map(RateLimiter::new),
+            policy,
+            in_transaction: false,
+            failing: false,
+            denied: 0,
+            limited: 0,
+        }
+    }
+
+    /// Returns the number of requests refused by the policy.
+    pub fn denied(&self) -> u64 {
+        self.denied
+    }
+
+    /// Returns the number of transactions refused by the rate limiter.
+    pub fn limited(&self) -> u64 {
+        self.limited
+    }
+
+    /// Serves a request.
+    ///
+    /// # Arguments
+    ///
+    /// * `header` - The request header (`struct virtio_i2c_out_hdr`).
+    /// * `data` - The message written by the guest.
+    /// * `buf` - Where to read the message (empty for a write).
+    ///
+    /// # Returns
+    ///
+    /// * `u8` - The request status.
+    fn serve(&mut self, header: &[u8], data: &[u8], buf: &mut [u8]) -> u8 {
+        // Linux drivers place the 7-bit address above a (zero) direction bit
+        let addr = u16::from_le_bytes([header[0], header[1]]) >> 1;
+        let flags = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        let read = flags & VIRTIO_I2C_FLAGS_M_RD != 0;
+
+        if !self.in_transaction {
+            self.failing = false;
+            let now = Instant::now();
+            if self.limiter.as_mut().is_some_and(|l| !l.admit(now)) {
+                self.limited += 1;
+                self.failing = true;
+            }
+        }
+        self.in_transaction = flags & VIRTIO_I2C_FLAGS_FAIL_NEXT != 0;
+        if self.failing {
+            return VIRTIO_I2C_MSG_ERR;
+        }
+        if !self.policy.permits(addr, read) {
+            self.denied += 1;
+            self.failing = true;
+            return VIRTIO_I2C_MSG_ERR;
+        }
+        let result = match read {
+            true => self.bus.transfer(addr, true, buf),
+            false => self.bus.transfer(addr, false, &mut data.to_vec()),
+        };
+        match result {
+            Ok(()) => VIRTIO_I2C_MSG_OK,
+            Err(_) => {
+                self.failing = true;
+                VIRTIO_I2C_MSG_ERR
+            }
+        }
+    }
+}
+
+impl<B: I2cBus> VirtioDevice for I2cDevice<B> {
+    fn features(&self) -> u64 {
+        VIRTIO_F_VERSION_1 | VIRTIO_I2C_F_ZERO_LENGTH_REQUEST
+    }
+
+    fn num_queues(&self) -> usize {
+        1
+    }
+
+    fn process(&mut self, readable: &[u8], writable: &mut [u8]) -> u32 {
+        // The status is the last writable byte, a chain without one cannot be answered
+        let (status, buf) = match writable.split_last_mut() {
+            Some(split) => split,
+            None => return 0,
+        };
+        *status = match readable.len() >= VIRTIO_I2C_OUT_HDR_SIZE {
+            true => {
+                let (header, data) = readable.split_at(VIRTIO_I2C_OUT_HDR_SIZE);
+                self.serve(header, data, buf)
+            }
+            false => VIRTIO_I2C_MSG_ERR,
+        };
+        writable.len() as u32
+    }
+}
+
+/// Creates the built-in I2C backend of a device.
+///
+/// # Arguments
+///
+/// * `device` - The device, whose `adapter` option names the host adapter and whose
+///   `allow` and `rate` options restrict what the guest may reach.
+///
+/// # Returns
+///
+/// * `Result<Box<dyn InProcessBackend>>` - The backend serving the device,
+///   `InvalidBackendOption` if an option is missing or invalid, `I2cAdapterFailed` if the
+///   adapter cannot be opened.
+pub fn i2c_backend(device: &ConfigDevice) -> Result<Box<dyn InProcessBackend>> {
+    let path = device
+        .options
+        .get("adapter")
+        .ok_or_else(|| Error::InvalidBackendOption("adapter".to_string(), "missing".to_string()))?;
+    let policy = I2cPolicy::from_device(device)?;
+    let poller = EventPoller::new(device);
+    let device = I2cDevice::new(I2cAdapter::open(path)?, policy);
+    Ok(Box::new(
+        VhostUserBackend::new(Box::new(device)).with_poller(poller),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::backend_device;
+    use std::time::Duration;
+
+    fn device(options: &[(&str, &str)]) -> ConfigDevice {
+        backend_device("i2c0", "i2c", Some(BAO_I2C_BACKEND), options)
+    }
+
+    /// A bus recording its transfers, whose reads return the address.
+    #[derive(Default)]
+    struct FakeBus(Vec<(u16, bool, Vec<u8>)>);
+
+    impl I2cBus for FakeBus {
+        fn transfer(&mut self, addr: u16, read: bool, buf: &mut [u8]) -> io::Result<()> {
+            if addr == 0x7f {
+                return Err(io::Error::from_raw_os_error(libc::ENXIO));
+            }
+            if read {
+                buf.fill(addr as u8);
+            }
+            self.0.push((addr, read, buf.to_vec()));
+            Ok(())
+        }
+    }
+
+    /// Serves a request, returning its status and the message read.
+    fn request(
+        device: &mut I2cDevice<FakeBus>,
+        addr: u16,
+        flags: u32,
+        data: &[u8],
+        read_len: usize,
+    ) -> (u8, Vec<u8>) {
+        let mut readable = Vec::new();
+        readable.extend_from_slice(&(addr << 1).to_le_bytes());
+        readable.extend_from_slice(&[0, 0]);
+        readable.extend_from_slice(&flags.to_le_bytes());
+        readable.extend_from_slice(data);
+        let mut writable = vec![0u8; read_len + 1];
+        assert_eq!(
+            device.process(&readable, &mut writable),
+            writable.len() as u32
+        );
+        let status = writable.pop().unwrap();
+        (status, writable)
+    }
+
+    #[test]
+    fn test_i2c_policy() {
+        assert_eq!(
+            I2cPolicy::from_device(&device(&[])).unwrap(),
+            I2cPolicy::default()
+        );
+        let policy = I2cPolicy::from_device(&device(&[
+            ("allow", "0x50:ro, 0x51:rw,7f:ro"),
+            ("rate", "100"),
+        ]))
+        .unwrap();
+        assert_eq!(
+            policy,
+            I2cPolicy {
+                allow: Some(BTreeMap::from([
+                    (0x50, I2cAccess::ReadOnly),
+                    (0x51, I2cAccess::ReadWrite),
+                    (0x7f, I2cAccess::ReadOnly)
+                ])),
+                rate: Some(100),
+            }
+        );
+        assert!(policy.permits(0x50, true));
+        assert!(!policy.permits(0x50, false));
+        assert!(policy.permits(0x51, false));
+        assert!(!policy.permits(0x52, true));
+        assert!(I2cPolicy::default().permits(0x52, false));
+
+        for (key, value) in [
+            ("allow", "0x50"),
+            ("allow", "0x80:ro"),
+            ("allow", "0x5g:ro"),
+            ("allow", "0x50:wo"),
+            ("allow", "0x50:ro,0x50:rw"),
+            ("allow", ""),
+            ("rate", "0"),
+            ("rate", "fast"),
+        ] {
+            assert!(
+                matches!(
+                    I2cPolicy::from_device(&device(&[(key, value)])),
+                    Err(Error::InvalidBackendOption(option, _)) if option == key
+                ),
+                "{} {}",
+                key,
+                value
+            );
+        }
+
+        assert!(matches!(
+            i2c_backend(&device(&[])),
+            Err(Error::InvalidBackendOption(option, _)) if option == "adapter"
+        ));
+        assert!(matches!(
+            i2c_backend(&device(&[("adapter", "/nonexistent/i2c-1")])),
+            Err(Error::I2cAdapterFailed(..))
+        ));
+    }
+
+    #[test]
+    fn test_i2c_requests() {
+        let policy = I2cPolicy {
+            allow: Some(BTreeMap::from([
+                (0x50, I2cAccess::ReadOnly),
+                (0x51, I2cAccess::ReadWrite),
+                (0x7f, I2cAccess::ReadWrite),
+            ])),
+            rate: None,
+        };
+        let mut device = I2cDevice::new(FakeBus::default(), policy);
+        assert_eq!(
+            device.features(),
+            VIRTIO_F_VERSION_1 | VIRTIO_I2C_F_ZERO_LENGTH_REQUEST
+        );
+
+        // Reads of a read-only address, writes and zero-length transfers of a read-write one
+        let read = VIRTIO_I2C_FLAGS_M_RD;
+        assert_eq!(
+            request(&mut device, 0x50, read, &[], 2),
+            (VIRTIO_I2C_MSG_OK, vec![0x50; 2])
+        );
+        assert_eq!(
+            request(&mut device, 0x51, 0, &[1, 2], 0).0,
+            VIRTIO_I2C_MSG_OK
+        );
+        assert_eq!(request(&mut device, 0x51, 0, &[], 0).0, VIRTIO_I2C_MSG_OK);
+        assert_eq!(
+            device.bus.0,
+            [
+                (0x50, true, vec![0x50; 2]),
+                (0x51, false, vec![1, 2]),
+                (0x51, false, vec![])
+            ]
+        );
+
+        // Writes to a read-only address and addresses not listed never reach the bus
+        device.bus.0.clear();
+        assert_eq!(request(&mut device, 0x50, 0, &[0], 0).0, VIRTIO_I2C_MSG_ERR);
+        assert_eq!(
+            request(&mut device, 0x52, read, &[], 1),
+            (VIRTIO_I2C_MSG_ERR, vec![0])
+        );
+        assert_eq!(device.denied(), 2);
+
+        // The rest of a transaction fails with its refused (or failed) request
+        let chained = VIRTIO_I2C_FLAGS_FAIL_NEXT;
+        assert_eq!(
+            request(&mut device, 0x50, chained, &[0], 0).0,
+            VIRTIO_I2C_MSG_ERR
+        );
+        assert_eq!(
+            request(&mut device, 0x51, read, &[], 1).0,
+            VIRTIO_I2C_MSG_ERR
+        );
+        assert_eq!(
+            request(&mut device, 0x7f, chained, &[0], 0).0,
+            VIRTIO_I2C_MSG_ERR
+        );
+        assert_eq!(
+            request(&mut device, 0x51, read, &[], 1).0,
+            VIRTIO_I2C_MSG_ERR
+        );
+        assert!(device.bus.0.is_empty());
+        // The next transaction is unaffected
+        assert_eq!(
+            request(&mut device, 0x51, chained, &[0], 0).0,
+            VIRTIO_I2C_MSG_OK
+        );
+        assert_eq!(
+            request(&mut device, 0x51, read, &[], 1).0,
+            VIRTIO_I2C_MSG_OK
+        );
+        assert_eq!(device.bus.0.len(), 2);
+
+        // Requests too short to be answered
+        assert_eq!(device.process(&[0; 8], &mut []), 0);
+        let mut status = [0xffu8];
+        assert_eq!(device.process(&[0; 4], &mut status), 1);
+        assert_eq!(status, [VIRTIO_I2C_MSG_ERR]);
+    }
+
+    #[test]
+    fn test_i2c_rate_limit() {
+        let policy = I2cPolicy {
+            allow: None,
+            rate: Some(2),
+        };
+        let mut device = I2cDevice::new(FakeBus::default(), policy);
+        // A transaction of 2 requests counts once
+        let chained = VIRTIO_I2C_FLAGS_FAIL_NEXT;
+        assert_eq!(
+            request(&mut device, 0x50, chained, &[0], 0).0,
+            VIRTIO_I2C_MSG_OK
+        );
+        assert_eq!(request(&mut device, 0x50, 0, &[0], 0).0, VIRTIO_I2C_MSG_OK);
+        assert_eq!(request(&mut device, 0x50, 0, &[0], 0).0, VIRTIO_I2C_MSG_OK);
+        assert_eq!(
+            request(&mut device, 0x50, chained, &[0], 0).0,
+            VIRTIO_I2C_MSG_ERR
+        );
+        assert_eq!(request(&mut device, 0x50, 0, &[0], 0).0, VIRTIO_I2C_MSG_ERR);
+        assert_eq!(device.limited(), 1);
+        assert_eq!(device.bus.0.len(), 3);
+
+        // The bucket refills at the rate, up to a second's worth
+        let start = Instant::now();
+        let mut limiter = RateLimiter::new(2);
+        assert!(limiter.admit(start) && limiter.admit(start));
+        assert!(!limiter.admit(start));
+        assert!(!limiter.admit(start + Duration::from_millis(400)));
+        assert!(limiter.admit(start + Duration::from_millis(600)));
+        let later = start + Duration::from_secs(10);
+        assert!(limiter.admit(later) && limiter.admit(later));
+        assert!(!limiter.admit(later));
+    }
+}
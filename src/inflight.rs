@@ -0,0 +1,458 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao in-flight limits.
+//!
+//! A backend that never completes its work makes the descriptors forwarded to it pile up,
+//! and with them the tracking state on both sides. Devices with `max_inflight` have the
+//! queue notifications of the guest held while the backend already has that many
+//! descriptor chains outstanding on the queue (forwarded through the available ring and
+//! not yet returned through the used ring): the guest vCPU resumes at once, and the
+//! notification is replayed once the backend catches up, so the backend never sees more
+//! than the limit plus one batch of descriptors.
+//!
+//! Notifications served by an I/O eventfd never reach the frontend, so the I/O eventfds
+//! of a limited device are not registered and its notifications go through the request
+//! path.
+
+#![allow(dead_code)]
+
+use super::defines::{
+    BAO_INFLIGHT_MAX_QUEUES, BAO_INFLIGHT_REPLAY_VCPU, BAO_IO_WRITE, VIRTIO_MMIO_QUEUE_AVAIL_HIGH,
+    VIRTIO_MMIO_QUEUE_AVAIL_LOW, VIRTIO_MMIO_QUEUE_NOTIFY, VIRTIO_MMIO_QUEUE_SEL,
+    VIRTIO_MMIO_QUEUE_USED_HIGH, VIRTIO_MMIO_QUEUE_USED_LOW, VIRTIO_MMIO_STATUS,
+};
+use super::error::Result;
+use super::hypervisor::Hypervisor;
+use super::memory::{GuestMemory, Le16};
+use super::types::{BaoIoEventFd, BaoIoRequest, BaoIrqFd};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Struct representing the in-flight state of a queue.
+///
+/// # Attributes
+///
+/// * `avail` - Guest address of the available ring (0 until set by the driver).
+/// * `used` - Guest address of the used ring (0 until set by the driver).
+/// * `forwarded` - Available index as of the last notification forwarded to the backend.
+/// * `held` - Latest notification held back, if any.
+#[derive(Debug, Clone, Copy, Default)]
+struct QueueWindow {
+    avail: u64,
+    used: u64,
+    forwarded: u16,
+    held: Option<BaoIoRequest>,
+}
+
+/// Struct representing the in-flight state of a device.
+///
+/// # Attributes
+///
+/// * `queue_sel` - Queue selected by the driver.
+/// * `queues` - Queues.
+/// * `replay` - Held notifications ready to be forwarded.
+/// * `throttled` - Number of notifications held back.
+#[derive(Debug)]
+struct InflightState {
+    queue_sel: u32,
+    queues: Vec<QueueWindow>,
+    replay: VecDeque<BaoIoRequest>,
+    throttled: u64,
+}
+
+/// Struct representing the in-flight limiter of a device.
+///
+/// # Attributes
+///
+/// * `limit` - Maximum number of descriptor chains outstanding on each queue.
+/// * `memory` - Guest memory holding the rings.
+/// * `state` - In-flight state.
+pub struct InflightLimiter<M: GuestMemory> {
+    limit: u16,
+    memory: Arc<Mutex<M>>,
+    state: Mutex<InflightState>,
+}
+
+impl<M: GuestMemory> InflightLimiter<M> {
+    /// Creates an in-flight limiter.
+    ///
+    /// # Arguments
+    ///
+    /// * `limit` - Maximum number of descriptor chains outstanding on each queue (the
+    ///   `max_inflight` of the device).
+    /// * `memory` - Guest memory holding the rings.
+    ///
+    /// # Returns
+    ///
+    /// * `InflightLimiter` - The limiter.
+    pub fn new(limit: u16, memory: Arc<Mutex<M>>) -> Self {
+        InflightLimiter {
+            limit: limit.max(1),
+            memory,
+            state: Mutex::new(InflightState {
+                queue_sel: 0,
+                queues: vec![QueueWindow::default(); BAO_INFLIGHT_MAX_QUEUES],
+                replay: VecDeque::new(),
+                throttled: 0,
+            }),
+        }
+    }
+
+    /// Returns the number of notifications held back.
+    pub fn throttled(&self) -> u64 {
+        self.state.lock().unwrap().throttled
+    }
+
+    /// Returns the indexes of the queues with a notification held back.
+    pub fn held(&self) -> Vec<u32> {
+        let state = self.state.lock().unwrap();
+        (0..state.queues.len() as u32)
+            .filter(|&index| state.queues[index as usize].held.is_some())
+            .collect()
+    }
+
+    /// Returns the number of descriptor chains outstanding on a queue.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - Queue index.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<u16>` - The chains forwarded to the backend and not yet used.
+    pub fn in_flight(&self, index: u32) -> Result<u16> {
+        let state = self.state.lock().unwrap();
+        match state.queues.get(index as usize) {
+            Some(queue) if queue.used != 0 => self.outstanding(queue),
+            _ => Ok(0),
+        }
+    }
+
+    /// Returns the available index of a queue.
+    fn avail_idx(&self, queue: &QueueWindow) -> Result<u16> {
+        let memory = self.memory.lock().unwrap();
+        Ok(memory.read_obj::<Le16>(queue.avail + 2)?.get())
+    }
+
+    /// Returns the number of chains forwarded on a queue and not yet used.
+    fn outstanding(&self, queue: &QueueWindow) -> Result<u16> {
+        let memory = self.memory.lock().unwrap();
+        let used_idx = memory.read_obj::<Le16>(queue.used + 2)?.get();
+        Ok(queue.forwarded.wrapping_sub(used_idx))
+    }
+
+    /// Tracks the ring addresses set by a request of the device.
+    fn observe(&self, req: &BaoIoRequest) {
+        if req.op != BAO_IO_WRITE {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let value = req.value & 0xffff_ffff;
+        let low = |addr: u64| (addr & !0xffff_ffff) | value;
+        let high = |addr: u64| (addr & 0xffff_ffff) | (value << 32);
+        match req.reg_off {
+            VIRTIO_MMIO_QUEUE_SEL => state.queue_sel = value as u32,
+            // A reset drops the held notifications along with the rings
+            VIRTIO_MMIO_STATUS if value == 0 => {
+                state.queues.fill(QueueWindow::default());
+                state.replay.clear();
+            }
+            reg_off => {
+                let index = state.queue_sel as usize;
+                if let Some(queue) = state.queues.get_mut(index) {
+                    match reg_off {
+                        VIRTIO_MMIO_QUEUE_AVAIL_LOW => queue.avail = low(queue.avail),
+                        VIRTIO_MMIO_QUEUE_AVAIL_HIGH => queue.avail = high(queue.avail),
+                        VIRTIO_MMIO_QUEUE_USED_LOW => queue.used = low(queue.used),
+                        VIRTIO_MMIO_QUEUE_USED_HIGH => queue.used = high(queue.used),
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+
+    /// Decides whether a queue notification is forwarded to the backend.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The queue notification.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - True if the notification is forwarded, false if it is held back until
+    ///   the backend catches up.
+    fn admit(&self, req: &BaoIoRequest) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let queue = match state.queues.get(req.value as usize) {
+            // Queues without rings are left to the device model
+            Some(queue) if queue.avail != 0 && queue.used != 0 => *queue,
+            _ => return true,
+        };
+        // Guest memory the rings cannot be read from is left to the device model too
+        let admitted = queue.held.is_none()
+            && self
+                .outstanding(&queue)
+                .map_or(true, |outstanding| outstanding < self.limit);
+        let window = &mut state.queues[req.value as usize];
+        if admitted {
+            window.forwarded = self.avail_idx(&queue).unwrap_or(queue.forwarded);
+        } else {
+            window.held = Some(*req);
+            state.throttled += 1;
+        }
+        admitted
+    }
+
+    /// Releases the held notifications of the queues the backend caught up with.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - True if notifications are ready to be replayed.
+    fn release(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        for queue in state.queues.iter_mut() {
+            let req = match queue.held {
+                Some(req) => req,
+                None => continue,
+            };
+            if self.outstanding(queue).is_ok_and(|n| n < self.limit) {
+                queue.forwarded = self.avail_idx(queue).unwrap_or(queue.forwarded);
+                queue.held = None;
+                state.replay.push_back(BaoIoRequest {
+                    vcpu_id: BAO_INFLIGHT_REPLAY_VCPU,
+                    ..req
+                });
+            }
+        }
+        !state.replay.is_empty()
+    }
+
+    /// Takes the next notification to replay.
+    fn replay(&self) -> Option<BaoIoRequest> {
+        self.state.lock().unwrap().replay.pop_front()
+    }
+}
+
+/// Struct representing a hypervisor enforcing the in-flight limit of a device.
+///
+/// Held notifications are replayed (as requests whose completion is not reported to
+/// any vCPU) on the next completion interrupt or request wait after the backend caught
+/// up.
+///
+/// # Attributes
+///
+/// * `inner` - The hypervisor.
+/// * `virtio_id` - Virtio ID of the device.
+/// * `addr` - Base address of the device.
+/// * `limiter` - The in-flight limiter of the device.
+pub struct InflightLimitHypervisor<H: Hypervisor, M: GuestMemory> {
+    inner: H,
+    virtio_id: u64,
+    addr: u64,
+    limiter: Arc<InflightLimiter<M>>,
+}
+
+impl<H: Hypervisor, M: GuestMemory> InflightLimitHypervisor<H, M> {
+    /// Enforces the in-flight limit of a device.
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - The hypervisor.
+    /// * `virtio_id` - Virtio ID of the device.
+    /// * `addr` - Base address of the device (its I/O eventfds are not registered).
+    /// * `limiter` - The in-flight limiter of the device.
+    ///
+    /// # Returns
+    ///
+    /// * `InflightLimitHypervisor` - The limiting hypervisor.
+    pub fn new(inner: H, virtio_id: u64, addr: u64, limiter: Arc<InflightLimiter<M>>) -> Self {
+        InflightLimitHypervisor {
+            inner,
+            virtio_id,
+            addr,
+            limiter,
+        }
+    }
+}
+
+impl<H: Hypervisor, M: GuestMemory + Send> Hypervisor for InflightLimitHypervisor<H, M> {
+    fn attach_client(&self) -> Result<()> {
+        self.inner.attach_client()
+    }
+
+    fn wait_request(&self, timeout: Duration) -> Result<bool> {
+        if self.limiter.release() {
+            return Ok(true);
+        }
+        self.inner.wait_request(timeout)
+    }
+
+    fn next_request(&self) -> Result<Option<BaoIoRequest>> {
+        if let Some(req) = self.limiter.replay() {
+            return Ok(Some(req));
+        }
+        while let Some(req) = self.inner.next_request()? {
+            if req.virtio_id != self.virtio_id {
+                return Ok(Some(req));
+            }
+            self.limiter.observe(&req);
+            let notify = req.op == BAO_IO_WRITE && req.reg_off == VIRTIO_MMIO_QUEUE_NOTIFY;
+            if !notify || self.limiter.admit(&req) {
+                return Ok(Some(req));
+            }
+            // Held back: the vCPU resumes, the notification is replayed later
+            self.inner.complete_request(&req)?;
+        }
+        Ok(None)
+    }
+
+    fn complete_request(&self, req: &BaoIoRequest) -> Result<()> {
+        if req.virtio_id == self.virtio_id && req.vcpu_id == BAO_INFLIGHT_REPLAY_VCPU {
+            return Ok(());
+        }
+        self.inner.complete_request(req)
+    }
+
+    fn notify_guest(&self) -> Result<()> {
+        self.inner.notify_guest()?;
+        self.limiter.release();
+        Ok(())
+    }
+
+    fn register_ioeventfd(&self, ioeventfd: &BaoIoEventFd) -> Result<()> {
+        if ioeventfd.addr == self.addr + VIRTIO_MMIO_QUEUE_NOTIFY {
+            return Ok(());
+        }
+        self.inner.register_ioeventfd(ioeventfd)
+    }
+
+    fn register_irqfd(&self, irqfd: &BaoIrqFd) -> Result<()> {
+        self.inner.register_irqfd(irqfd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+    use crate::testing::MockHypervisor;
+
+    /// Guest RAM at 0x1000.
+    struct Ram(Vec<u8>);
+
+    impl GuestMemory for Ram {
+        fn read_slice(&self, addr: u64, buf: &mut [u8]) -> Result<()> {
+            let start = addr as usize - 0x1000;
+            let bytes = self
+                .0
+                .get(start..start + buf.len())
+                .ok_or(Error::InvalidGuestAddress(addr, buf.len()))?;
+            buf.copy_from_slice(bytes);
+            Ok(())
+        }
+
+        fn write_slice(&mut self, addr: u64, buf: &[u8]) -> Result<()> {
+            let start = addr as usize - 0x1000;
+            self.0
+                .get_mut(start..start + buf.len())
+                .ok_or(Error::InvalidGuestAddress(addr, buf.len()))?
+                .copy_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_inflight_limit() {
+        let ram = Arc::new(Mutex::new(Ram(vec![0; 0x1000])));
+        let mock = MockHypervisor::new();
+        let limiter = Arc::new(InflightLimiter::new(2, ram.clone()));
+        let hypervisor = InflightLimitHypervisor::new(&mock, 1, 0xa000, limiter.clone());
+        let request = |reg_off, value| {
+            mock.push_request(BaoIoRequest {
+                virtio_id: 1,
+                reg_off,
+                addr: 0xa000 + reg_off,
+                op: BAO_IO_WRITE,
+                value,
+                access_width: 4,
+                cpu_id: 0,
+                vcpu_id: 1,
+                ret: 0,
+            });
+            hypervisor.next_request().unwrap()
+        };
+        let set_idx = |addr, idx: u16| {
+            ram.lock()
+                .unwrap()
+                .write_obj(addr, &Le16::new(idx))
+                .unwrap()
+        };
+
+        // Queue 1 has its available ring at 0x1100 and its used ring at 0x1200
+        for (reg_off, value) in [
+            (VIRTIO_MMIO_QUEUE_SEL, 1),
+            (VIRTIO_MMIO_QUEUE_AVAIL_LOW, 0x1100),
+            (VIRTIO_MMIO_QUEUE_USED_LOW, 0x1200),
+        ] {
+            assert!(request(reg_off, value).is_some());
+        }
+
+        // A batch beyond the limit is forwarded while nothing is outstanding
+        set_idx(0x1102, 3);
+        assert!(request(VIRTIO_MMIO_QUEUE_NOTIFY, 1).is_some());
+        assert_eq!(limiter.in_flight(1).unwrap(), 3);
+
+        // The next notification is held back, the vCPU resuming at once
+        set_idx(0x1102, 5);
+        assert!(request(VIRTIO_MMIO_QUEUE_NOTIFY, 1).is_none());
+        assert_eq!(
+            mock.pop_completed().unwrap().reg_off,
+            VIRTIO_MMIO_QUEUE_NOTIFY
+        );
+        assert_eq!(limiter.held(), [1]);
+        assert_eq!(limiter.throttled(), 1);
+
+        // Still held back until the backend is below the limit
+        set_idx(0x1202, 1);
+        hypervisor.notify_guest().unwrap();
+        assert!(hypervisor.next_request().unwrap().is_none());
+        set_idx(0x1202, 2);
+        assert!(hypervisor.wait_request(Duration::ZERO).unwrap());
+        let replay = hypervisor.next_request().unwrap().unwrap();
+        assert_eq!(
+            (replay.reg_off, replay.value),
+            (VIRTIO_MMIO_QUEUE_NOTIFY, 1)
+        );
+        assert_eq!(limiter.in_flight(1).unwrap(), 3);
+        assert!(limiter.held().is_empty());
+
+        // No vCPU waits for the replayed notification
+        hypervisor.complete_request(&replay).unwrap();
+        assert!(mock.pop_completed().is_none());
+        assert_eq!(mock.interrupts(), 1);
+
+        // Queues without rings and other devices are not limited
+        assert!(request(VIRTIO_MMIO_QUEUE_NOTIFY, 0).is_some());
+        let ioeventfd = |addr| BaoIoEventFd {
+            fd: 0,
+            flags: 0,
+            addr,
+            len: 4,
+            reserved: 0,
+            data: 0,
+        };
+        hypervisor
+            .register_ioeventfd(&ioeventfd(0xb000 + VIRTIO_MMIO_QUEUE_NOTIFY))
+            .unwrap();
+        hypervisor
+            .register_ioeventfd(&ioeventfd(0xa000 + VIRTIO_MMIO_QUEUE_NOTIFY))
+            .unwrap();
+        assert_eq!(mock.ioeventfds().len(), 1);
+    }
+}
@@ -0,0 +1,318 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao device initialization.
+//!
+//! Setting a device up (mapping its memory, connecting to its backend and negotiating
+//! the vhost-user features) is dominated by handshakes with the backend, so the devices of
+//! every guest are initialized concurrently, on a bounded number of threads, instead of
+//! one after the other. A device only starts once the devices it `depends_on` (e.g. the
+//! virtio-iommu translating its DMA, or the console used to debug it) are up.
+
+#![allow(dead_code)]
+
+use super::error::{Error, ErrorContext, Result, ResultExt};
+use super::types::{ConfigDevice, ConfigFrontends, ConfigGuest};
+use std::sync::{Condvar, Mutex};
+use std::thread;
+
+/// Resolves the dependencies of the devices of a guest.
+///
+/// # Arguments
+///
+/// * `guest` - The guest configuration.
+///
+/// # Returns
+///
+/// * `Result<Vec<Vec<usize>>>` - The indices of the dependencies of each device.
+fn dependencies(guest: &ConfigGuest) -> Result<Vec<Vec<usize>>> {
+    guest
+        .devices
+        .iter()
+        .map(|device| {
+            device
+                .depends_on
+                .iter()
+                .map(|name| {
+                    guest
+                        .devices
+                        .iter()
+                        .position(|d| &d.name == name)
+                        .ok_or_else(|| Error::UnknownDependency(device.name.clone(), name.clone()))
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Computes the order in which the devices of a guest are initialized.
+///
+/// # Arguments
+///
+/// * `guest` - The guest configuration.
+///
+/// # Returns
+///
+/// * `Result<Vec<usize>>` - The device indices, each after its dependencies (and otherwise
+///   in configuration order), or the first dependency cycle found.
+pub fn startup_order(guest: &ConfigGuest) -> Result<Vec<usize>> {
+    let dependencies = dependencies(guest)?;
+    let count = dependencies.len();
+    let mut done = vec![false; count];
+    let mut order = Vec::with_capacity(count);
+    while order.len() < count {
+        let ready = (0..count).find(|&i| !done[i] && dependencies[i].iter().all(|&d| done[d]));
+        if let Some(index) = ready {
+            done[index] = true;
+            order.push(index);
+            continue;
+        }
+
+        // Every device left depends on another one left: following them closes a cycle
+        let mut path = vec![(0..count).find(|&i| !done[i]).unwrap()];
+        loop {
+            let last = path[path.len() - 1];
+            let next = *dependencies[last].iter().find(|&&d| !done[d]).unwrap();
+            if let Some(start) = path.iter().position(|&i| i == next) {
+                let cycle = path[start..]
+                    .iter()
+                    .chain([&next])
+                    .map(|&i| guest.devices[i].name.as_str())
+                    .collect::<Vec<_>>();
+                return Err(Error::DependencyCycle(cycle.join(" -> ")));
+            }
+            path.push(next);
+        }
+    }
+    Ok(order)
+}
+
+/// Checks the device dependencies of every guest of a configuration.
+///
+/// # Arguments
+///
+/// * `config` - The frontends configuration.
+///
+/// # Returns
+///
+/// * `Result<()>` - Ok if every dependency exists and none is circular.
+pub fn validate_dependencies(config: &ConfigFrontends) -> Result<()> {
+    for guest in config.frontends.iter().flat_map(|f| &f.guests) {
+        startup_order(guest)?;
+    }
+    Ok(())
+}
+
+/// Struct representing the progress of the device initialization.
+///
+/// # Attributes
+///
+/// * `started` - Whether each device was started.
+/// * `results` - The result of each finished device.
+/// * `failed` - Whether a device failed.
+struct Progress<T> {
+    started: Vec<bool>,
+    results: Vec<Option<Result<T>>>,
+    failed: bool,
+}
+
+/// Initializes every device of a configuration, `init_concurrency` devices at a time.
+///
+/// A device starts once its dependencies are initialized. Once a device fails, the devices
+/// not yet started are skipped.
+///
+/// # Arguments
+///
+/// * `config` - The frontends configuration.
+/// * `init` - Initializes a device of a guest.
+///
+/// # Returns
+///
+/// * `Result<Vec<T>>` - The initialized devices, in configuration order, or the error of
+///   the first failed device (tagged with its identity).
+pub fn init_devices<T, F>(config: &ConfigFrontends, init: F) -> Result<Vec<T>>
+where
+    T: Send,
+    F: Fn(&ConfigGuest, &ConfigDevice) -> Result<T> + Sync,
+{
+    let mut devices = Vec::new();
+    for frontend in &config.frontends {
+        for guest in &frontend.guests {
+            startup_order(guest)?;
+            let base = devices.len();
+            for (device, dependencies) in guest.devices.iter().zip(dependencies(guest)?) {
+                let context = ErrorContext::new(frontend.id, guest.id, &device.name);
+                let dependencies = dependencies.iter().map(|d| base + d).collect::<Vec<_>>();
+                devices.push((guest, device, context, dependencies));
+            }
+        }
+    }
+    let progress = Mutex::new(Progress {
+        started: vec![false; devices.len()],
+        results: devices.iter().map(|_| None).collect(),
+        failed: false,
+    });
+    let finished = Condvar::new();
+
+    // Each thread takes the first device whose dependencies are up, until none is left
+    // (or one failed)
+    let worker = || loop {
+        let index = {
+            let mut progress = progress.lock().unwrap();
+            loop {
+                if progress.failed {
+                    return;
+                }
+                let ready = (0..devices.len()).find(|&i| {
+                    !progress.started[i]
+                        && devices[i].3.iter().all(|&d| progress.results[d].is_some())
+                });
+                match ready {
+                    Some(index) => {
+                        progress.started[index] = true;
+                        break index;
+                    }
+                    None if progress.started.iter().all(|&started| started) => return,
+                    None => progress = finished.wait(progress).unwrap(),
+                }
+            }
+        };
+        let (guest, device, context, _) = &devices[index];
+        let result = init(guest, device).with_context(context);
+        let mut progress = progress.lock().unwrap();
+        progress.failed |= result.is_err();
+        progress.results[index] = Some(result);
+        finished.notify_all();
+    };
+    thread::scope(|scope| {
+        for _ in 1..config.init_concurrency().min(devices.len()) {
+            scope.spawn(worker);
+        }
+        worker();
+    });
+
+    // Devices skipped after a failure have no result
+    progress
+        .into_inner()
+        .unwrap()
+        .results
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_init_devices() {
+        let mut config: ConfigFrontends = serde_yaml::from_str(
+            "frontends:
+  - name: frontend0
+    id: 0
+    guests:
+      - name: guest0
+        id: 1
+        ram_addr: 0x50000000
+        ram_size: 0x1000000
+        shmem_path: /dev/baoipc0
+        socket_path: /tmp/
+        devices:
+          - {name: rng0, id: 0, type: rng, irq: 47, addr: 0xa003e00}
+          - {name: i2c0, id: 1, type: i2c, irq: 46, addr: 0xa003c00}
+          - {name: gpio0, id: 2, type: gpio, irq: 45, addr: 0xa003a00}
+          - {name: rng1, id: 3, type: rng, irq: 44, addr: 0xa003800}
+init_concurrency: 4
+",
+        )
+        .unwrap();
+
+        // The handshakes overlap, and the devices come back in configuration order
+        let start = Instant::now();
+        let names = init_devices(&config, |_, device| {
+            thread::sleep(Duration::from_millis(100));
+            Ok(device.name.clone())
+        })
+        .unwrap();
+        assert!(start.elapsed() < Duration::from_millis(400));
+        assert_eq!(names, ["rng0", "i2c0", "gpio0", "rng1"]);
+
+        // A failure is reported with the identity of the device, and stops the startup
+        config.init_concurrency = Some(1);
+        let started = AtomicUsize::new(0);
+        let err = init_devices(&config, |_, device| {
+            started.fetch_add(1, Ordering::Relaxed);
+            match device.name.as_str() {
+                "i2c0" => Err(Error::DeviceNotFound),
+                _ => Ok(()),
+            }
+        })
+        .unwrap_err();
+        assert_eq!(err.report(), "frontend0/guest1/i2c0: Device not found");
+        assert_eq!(started.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_device_dependencies() {
+        let mut config: ConfigFrontends = serde_yaml::from_str(
+            "frontends:
+  - name: frontend0
+    id: 0
+    guests:
+      - name: guest0
+        id: 1
+        ram_addr: 0x50000000
+        ram_size: 0x1000000
+        shmem_path: /dev/baoipc0
+        socket_path: /tmp/
+        devices:
+          - {name: rng0, id: 0, type: rng, irq: 47, addr: 0xa003e00, depends_on: [iommu0]}
+          - {name: i2c0, id: 1, type: i2c, irq: 46, addr: 0xa003c00, depends_on: [iommu0]}
+          - {name: iommu0, id: 2, type: iommu, irq: 45, addr: 0xa003a00, depends_on: [console0]}
+          - {name: console0, id: 3, type: console, irq: 44, addr: 0xa003800}
+init_concurrency: 4
+",
+        )
+        .unwrap();
+        let guest = &config.frontends[0].guests[0];
+        assert_eq!(startup_order(guest).unwrap(), [3, 2, 0, 1]);
+        validate_dependencies(&config).unwrap();
+
+        // Devices only start once their dependencies are initialized
+        let events = Mutex::new(Vec::new());
+        init_devices(&config, |_, device| {
+            events
+                .lock()
+                .unwrap()
+                .push(format!("start {}", device.name));
+            thread::sleep(Duration::from_millis(20));
+            events.lock().unwrap().push(format!("end {}", device.name));
+            Ok(())
+        })
+        .unwrap();
+        let events = events.into_inner().unwrap();
+        let position = |event: &str| events.iter().position(|e| e == event).unwrap();
+        assert!(position("end console0") < position("start iommu0"));
+        assert!(position("end iommu0") < position("start rng0"));
+        assert!(position("end iommu0") < position("start i2c0"));
+
+        // Unknown and circular dependencies are rejected
+        let devices = &mut config.frontends[0].guests[0].devices;
+        devices[3].depends_on = vec!["rng0".to_string()];
+        assert_eq!(
+            validate_dependencies(&config).unwrap_err().to_string(),
+            "Dependency cycle between devices rng0 -> iommu0 -> console0 -> rng0"
+        );
+        let devices = &mut config.frontends[0].guests[0].devices;
+        devices[3].depends_on = vec!["net0".to_string()];
+        assert!(matches!(
+            init_devices(&config, |_, _| Ok(())),
+            Err(Error::UnknownDependency(..))
+        ));
+    }
+}
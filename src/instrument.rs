@@ -0,0 +1,198 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao hot path instrumentation.
+//!
+//! The counters, latency samples and trace events of the request path go through these
+//! macros, which compile to nothing without the `instrumentation` feature (on by default):
+//! safety builds (`--no-default-features --features std`) ship without the cost of
+//! observability, while development builds keep full visibility. The `instrument/*`
+//! benchmarks (`benches/dispatch.rs`) measure a request path with and without them.
+//!
+//! The disabled macros still borrow their arguments, so the variables only used by the
+//! instrumentation do not trigger warnings in safety builds. Counters that are updated
+//! in more than one statement (e.g. the coalescing counters) go in a `bao_instrumented!`
+//! block, which is still type-checked when compiled out. Only the telemetry is gated:
+//! slow request reports, access traces and audit records are configured behaviour and
+//! always run.
+
+#![allow(dead_code)]
+
+/// Whether the hot path instrumentation is compiled in.
+pub const INSTRUMENTED: bool = cfg!(feature = "instrumentation");
+
+/// Struct standing for the start time of a stage when the instrumentation is compiled out.
+#[derive(Debug, Clone, Copy)]
+pub struct NoStart;
+
+/// Increments a counter (`AtomicU64`).
+#[cfg(feature = "instrumentation")]
+#[macro_export]
+macro_rules! bao_inc {
+    ($counter:expr) => {
+        $crate::stats::inc(&$counter)
+    };
+}
+
+/// Increments a counter (`AtomicU64`).
+#[cfg(not(feature = "instrumentation"))]
+#[macro_export]
+macro_rules! bao_inc {
+    ($counter:expr) => {{
+        let _ = &$counter;
+    }};
+}
+
+/// Returns the start time of a measured stage (`Instant`, `NoStart` when compiled out).
+#[cfg(feature = "instrumentation")]
+#[macro_export]
+macro_rules! bao_now {
+    () => {
+        ::std::time::Instant::now()
+    };
+}
+
+/// Returns the start time of a measured stage (`Instant`, `NoStart` when compiled out).
+#[cfg(not(feature = "instrumentation"))]
+#[macro_export]
+macro_rules! bao_now {
+    () => {
+        $crate::instrument::NoStart
+    };
+}
+
+/// Records the latency of a stage started at `bao_now!()` into a `LatencyHistogram`.
+#[cfg(feature = "instrumentation")]
+#[macro_export]
+macro_rules! bao_latency {
+    ($histogram:expr, $start:expr) => {
+        $histogram.record($start.elapsed())
+    };
+}
+
+/// Records the latency of a stage started at `bao_now!()` into a `LatencyHistogram`.
+#[cfg(not(feature = "instrumentation"))]
+#[macro_export]
+macro_rules! bao_latency {
+    ($histogram:expr, $start:expr) => {{
+        let _ = (&$histogram, &$start);
+    }};
+}
+
+/// Emits a trace span started at `bao_now!()` (see `TraceSink::span`).
+#[cfg(feature = "instrumentation")]
+#[macro_export]
+macro_rules! bao_span {
+    ($sink:expr, $phase:expr, $req:expr, $start:expr) => {
+        $sink.span($phase, $req, $start)
+    };
+}
+
+/// Emits a trace span started at `bao_now!()` (see `TraceSink::span`).
+#[cfg(not(feature = "instrumentation"))]
+#[macro_export]
+macro_rules! bao_span {
+    ($sink:expr, $phase:expr, $req:expr, $start:expr) => {{
+        let _ = (&$sink, &$phase, &$req, &$start);
+        $crate::error::Result::<()>::Ok(())
+    }};
+}
+
+/// Emits an instantaneous trace event (see `TraceSink::instant`).
+#[cfg(feature = "instrumentation")]
+#[macro_export]
+macro_rules! bao_instant {
+    ($sink:expr, $phase:expr, $req:expr) => {
+        $sink.instant($phase, $req)
+    };
+}
+
+/// Emits an instantaneous trace event (see `TraceSink::instant`).
+#[cfg(not(feature = "instrumentation"))]
+#[macro_export]
+macro_rules! bao_instant {
+    ($sink:expr, $phase:expr, $req:expr) => {{
+        let _ = (&$sink, &$phase);
+        let _: ::std::option::Option<&$crate::types::BaoIoRequest> = $req;
+        $crate::error::Result::<()>::Ok(())
+    }};
+}
+
+/// Runs a block of instrumentation (e.g. a report to a sink) on the request path.
+#[cfg(feature = "instrumentation")]
+#[macro_export]
+macro_rules! bao_instrumented {
+    ($($body:tt)*) => {{
+        $($body)*
+    }};
+}
+
+/// Runs a block of instrumentation (e.g. a report to a sink) on the request path.
+#[cfg(not(feature = "instrumentation"))]
+#[macro_export]
+macro_rules! bao_instrumented {
+    ($($body:tt)*) => {{
+        #[allow(unreachable_code)]
+        if false {
+            $($body)*
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Result;
+    use crate::stats::DeviceStats;
+    use crate::trace::{TracePhase, TraceSink};
+    use crate::types::BaoIoRequest;
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::time::Instant;
+    use zerocopy::FromZeroes;
+
+    /// Trace sink counting its events.
+    #[derive(Default)]
+    struct Events(AtomicU64);
+
+    impl TraceSink for Events {
+        fn span(&self, _phase: TracePhase, _req: &BaoIoRequest, _start: Instant) -> Result<()> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn instant(&self, _phase: TracePhase, _req: Option<&BaoIoRequest>) -> Result<()> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn finish(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_instrumentation_macros() {
+        let stats = DeviceStats::new("rng0", 1);
+        let events = Events::default();
+        let req = BaoIoRequest::new_zeroed();
+        let start = bao_now!();
+        bao_inc!(stats.mmio_reads);
+        bao_latency!(stats.latency, start);
+        bao_span!(events, TracePhase::Dispatch, &req, start).unwrap();
+        bao_instant!(events, TracePhase::IrqInject, None).unwrap();
+        let mut reports = 0;
+        bao_instrumented! {
+            reports += 1;
+        }
+
+        // Compiled out, the macros leave the counters alone
+        let expected = INSTRUMENTED as u64;
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.mmio_reads, expected);
+        assert_eq!(snapshot.latency.count, expected);
+        assert_eq!(events.0.load(Ordering::Relaxed), 2 * expected);
+        assert_eq!(reports, expected);
+    }
+}
@@ -4,27 +4,57 @@
 // SPDX-License-Identifier: Apache-2.0
 
 //! Bao IOCTLs.
+//!
+//! The ioctl numbers follow the generic Linux encoding (`asm-generic/ioctl.h`), shared by
+//! every target supported by Bao:
+//!
+//! | Target  | Boards        | ioctl encoding | `BaoIoRequest` / `BaoIoEventFd` / `BaoIrqFd` |
+//! |---------|---------------|----------------|----------------------------------------------|
+//! | aarch64 | Armv8         | generic        | 72 / 32 / 8 bytes, 8 / 8 / 4 aligned         |
+//! | arm     | Armv7, Armv8  | generic        | 72 / 32 / 8 bytes, 8 / 8 / 4 aligned         |
+//! | riscv64 | RISC-V RV64   | generic        | 72 / 32 / 8 bytes, 8 / 8 / 4 aligned         |
+//! | riscv32 | RISC-V RV32   | generic        | 72 / 32 / 8 bytes, 8 / 8 / 4 aligned         |
+//! | x86_64  | (development) | generic        | 72 / 32 / 8 bytes, 8 / 8 / 4 aligned         |
+//!
+//! Other targets fail to build (see the layout assertions of `types`), since some of them
+//! (e.g. PowerPC and MIPS) use different direction bits and a narrower size field.
 
 #![allow(dead_code)]
 
 use super::defines::BAO_IOCTL_TYPE;
 use super::types::{BaoIoEventFd, BaoIoRequest, BaoIrqFd};
-use vmm_sys_util::ioctl::{_IOC_NONE, _IOC_READ, _IOC_WRITE};
+use std::mem::size_of;
+use vmm_sys_util::ioctl::{
+    _IOC_DIRSHIFT, _IOC_NONE, _IOC_NRSHIFT, _IOC_READ, _IOC_SIZEMASK, _IOC_SIZESHIFT,
+    _IOC_TYPESHIFT, _IOC_WRITE,
+};
 use vmm_sys_util::ioctl_ioc_nr;
 
+// The ioctl numbers are encoded by vmm-sys-util with the generic layout, whatever the
+// target: make sure it is the one expected by the Bao kernel module
+const _: () = {
+    assert!(_IOC_NONE == 0 && _IOC_WRITE == 1 && _IOC_READ == 2);
+    assert!(_IOC_NRSHIFT == 0 && _IOC_TYPESHIFT == 8);
+    assert!(_IOC_SIZESHIFT == 16 && _IOC_DIRSHIFT == 30);
+    // The argument sizes fit the 14-bit size field
+    assert!(size_of::<BaoIoRequest>() <= _IOC_SIZEMASK as usize);
+    assert!(size_of::<BaoIoEventFd>() <= _IOC_SIZEMASK as usize);
+    assert!(size_of::<BaoIrqFd>() <= _IOC_SIZEMASK as usize);
+};
+
 ioctl_ioc_nr!(
     BAO_IOCTL_VM_VIRTIO_BACKEND_CREATE,
     _IOC_WRITE,
     BAO_IOCTL_TYPE,
     1 as u32,
-    std::mem::size_of::<u32>() as u32
+    size_of::<u32>() as u32
 );
 ioctl_ioc_nr!(
     BAO_IOCTL_VM_VIRTIO_BACKEND_DESTROY,
     _IOC_WRITE,
     BAO_IOCTL_TYPE,
     2 as u32,
-    std::mem::size_of::<u32>() as u32
+    size_of::<u32>() as u32
 );
 ioctl_ioc_nr!(
     BAO_IOCTL_IO_CREATE_CLIENT,
@@ -52,14 +82,14 @@ ioctl_ioc_nr!(
     _IOC_WRITE | _IOC_READ,
     BAO_IOCTL_TYPE,
     6 as u32,
-    std::mem::size_of::<BaoIoRequest>() as u32
+    size_of::<BaoIoRequest>() as u32
 );
 ioctl_ioc_nr!(
     BAO_IOCTL_IO_REQUEST_NOTIFY_COMPLETED,
     _IOC_WRITE,
     BAO_IOCTL_TYPE,
     7 as u32,
-    std::mem::size_of::<BaoIoRequest>() as u32
+    size_of::<BaoIoRequest>() as u32
 );
 ioctl_ioc_nr!(
     BAO_IOCTL_IO_NOTIFY_GUEST,
@@ -73,14 +103,14 @@ ioctl_ioc_nr!(
     _IOC_WRITE,
     BAO_IOCTL_TYPE,
     9 as u32,
-    std::mem::size_of::<BaoIoEventFd>() as u32
+    size_of::<BaoIoEventFd>() as u32
 );
 ioctl_ioc_nr!(
     BAO_IOCTL_IRQFD,
     _IOC_WRITE,
     BAO_IOCTL_TYPE,
     10 as u32,
-    std::mem::size_of::<BaoIrqFd>() as u32
+    size_of::<BaoIrqFd>() as u32
 );
 
 #[cfg(test)]
@@ -101,4 +131,42 @@ mod tests {
         assert_eq!(0x4020_A609, BAO_IOCTL_IOEVENTFD());
         assert_eq!(0x4008_A60A, BAO_IOCTL_IRQFD());
     }
+
+    /// Tests that the BAO IOCTLs encode the argument sizes of the target.
+    #[test]
+    fn test_ioctl_encoding() {
+        let decode = |ioctl: std::os::raw::c_ulong| {
+            let ioctl = ioctl as u32;
+            (
+                ioctl >> 30,
+                (ioctl >> 16) & 0x3fff,
+                (ioctl >> 8) & 0xff,
+                ioctl & 0xff,
+            )
+        };
+        let request = size_of::<BaoIoRequest>() as u32;
+        assert_eq!(
+            decode(BAO_IOCTL_IO_REQUEST()),
+            (_IOC_WRITE | _IOC_READ, request, BAO_IOCTL_TYPE, 6)
+        );
+        assert_eq!(
+            decode(BAO_IOCTL_IO_REQUEST_NOTIFY_COMPLETED()),
+            (_IOC_WRITE, request, BAO_IOCTL_TYPE, 7)
+        );
+        assert_eq!(
+            decode(BAO_IOCTL_IOEVENTFD()),
+            (
+                _IOC_WRITE,
+                size_of::<BaoIoEventFd>() as u32,
+                BAO_IOCTL_TYPE,
+                9
+            )
+        );
+        assert_eq!(
+            decode(BAO_IOCTL_IRQFD()),
+            (_IOC_WRITE, size_of::<BaoIrqFd>() as u32, BAO_IOCTL_TYPE, 10)
+        );
+        // The numbers fit the 32-bit request argument of the 32-bit targets
+        assert!(u32::try_from(BAO_IOCTL_IO_REQUEST()).is_ok());
+    }
 }
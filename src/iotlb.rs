@@ -0,0 +1,445 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao frontend-side IOTLB.
+//!
+//! Once `VIRTIO_F_ACCESS_PLATFORM` is negotiated, the addresses in the rings are I/O
+//! virtual addresses (IOVAs) translated by the platform, not guest physical addresses.
+//! The backend caches the translations in its IOTLB: on a miss it sends an
+//! `IOTLB_MSG` (`VHOST_IOTLB_MISS`) over the backend request channel, and the frontend
+//! answers with a `VHOST_IOTLB_UPDATE` mapping the IOVA range to its own virtual
+//! addresses (those of the memory table). Unmapped ranges are invalidated
+//! (`VHOST_IOTLB_INVALIDATE`) before the guest may reuse them.
+//!
+//! The `Iotlb` holds the IOVA mappings of a device: the identity over the guest RAM for a
+//! Bao guest without an IOMMU, or those set by the future virtio-iommu device.
+
+#![allow(dead_code)]
+
+use super::defines::{
+    VHOST_ACCESS_RW, VHOST_IOTLB_INVALIDATE, VHOST_IOTLB_MISS, VHOST_IOTLB_MSG_SIZE,
+    VHOST_IOTLB_UPDATE, VHOST_USER_BACKEND_IOTLB_MSG, VHOST_USER_HEADER_SIZE, VHOST_USER_IOTLB_MSG,
+    VHOST_USER_NEED_REPLY_MASK, VHOST_USER_VERSION,
+};
+use super::error::{Error, Result};
+use std::collections::BTreeMap;
+
+/// Struct representing a vhost IOTLB message (`struct vhost_iotlb_msg`).
+///
+/// # Attributes
+///
+/// * `iova` - First I/O virtual address of the range.
+/// * `size` - Size of the range.
+/// * `uaddr` - Frontend virtual address of the range (updates only).
+/// * `perm` - Access permissions (`VHOST_ACCESS_*`).
+/// * `msg_type` - Message type (`VHOST_IOTLB_*`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IotlbMsg {
+    pub iova: u64,
+    pub size: u64,
+    pub uaddr: u64,
+    pub perm: u8,
+    pub msg_type: u8,
+}
+
+impl IotlbMsg {
+    /// Encodes the message (little-endian, padded to `VHOST_IOTLB_MSG_SIZE`).
+    ///
+    /// # Returns
+    ///
+    /// * `[u8; VHOST_IOTLB_MSG_SIZE]` - The encoded message.
+    pub fn to_bytes(&self) -> [u8; VHOST_IOTLB_MSG_SIZE] {
+        let mut bytes = [0u8; VHOST_IOTLB_MSG_SIZE];
+        bytes[0..8].copy_from_slice(&self.iova.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.size.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.uaddr.to_le_bytes());
+        bytes[24] = self.perm;
+        bytes[25] = self.msg_type;
+        bytes
+    }
+
+    /// Decodes a message.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The message payload.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<IotlbMsg>` - The message, or `InvalidVhostUserMessage` if the payload is
+    ///   too short.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if bytes.len() < VHOST_IOTLB_MSG_SIZE {
+            return Err(Error::InvalidVhostUserMessage(VHOST_USER_BACKEND_IOTLB_MSG));
+        }
+        let word =
+            |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+        Ok(IotlbMsg {
+            iova: word(0),
+            size: word(8),
+            uaddr: word(16),
+            perm: bytes[24],
+            msg_type: bytes[25],
+        })
+    }
+
+    /// Encodes the message as a vhost-user `IOTLB_MSG` request to the backend.
+    ///
+    /// The request asks for an acknowledgement (`REPLY_ACK`), so the frontend knows when
+    /// an invalidated range is no longer accessed.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<u8>` - The request (header and payload).
+    pub fn to_request(&self) -> Vec<u8> {
+        let mut request = Vec::with_capacity(VHOST_USER_HEADER_SIZE + VHOST_IOTLB_MSG_SIZE);
+        request.extend_from_slice(&VHOST_USER_IOTLB_MSG.to_le_bytes());
+        request.extend_from_slice(&(VHOST_USER_VERSION | VHOST_USER_NEED_REPLY_MASK).to_le_bytes());
+        request.extend_from_slice(&(VHOST_IOTLB_MSG_SIZE as u32).to_le_bytes());
+        request.extend_from_slice(&self.to_bytes());
+        request
+    }
+}
+
+/// Struct representing an IOVA range mapped to guest physical addresses.
+///
+/// # Attributes
+///
+/// * `size` - Size of the range.
+/// * `gpa` - Guest physical address of the range.
+/// * `perm` - Access permissions (`VHOST_ACCESS_*`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct IotlbMapping {
+    size: u64,
+    gpa: u64,
+    perm: u8,
+}
+
+/// Struct representing a guest memory region shared with the backend.
+///
+/// # Attributes
+///
+/// * `gpa` - Guest physical address of the region.
+/// * `size` - Size of the region.
+/// * `uaddr` - Frontend virtual address of the region (as in the memory table).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct IotlbRegion {
+    gpa: u64,
+    size: u64,
+    uaddr: u64,
+}
+
+/// Struct representing the IOVA translations of a device.
+///
+/// # Attributes
+///
+/// * `mappings` - IOVA mappings, indexed by their first IOVA (they never overlap).
+/// * `regions` - Guest memory regions shared with the backend.
+#[derive(Debug, Default)]
+pub struct Iotlb {
+    mappings: BTreeMap<u64, IotlbMapping>,
+    regions: Vec<IotlbRegion>,
+}
+
+impl Iotlb {
+    /// Creates an IOTLB without mappings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a guest memory region shared with the backend.
+    ///
+    /// # Arguments
+    ///
+    /// * `gpa` - Guest physical address of the region.
+    /// * `size` - Size of the region.
+    /// * `uaddr` - Frontend virtual address of the region.
+    pub fn add_region(&mut self, gpa: u64, size: u64, uaddr: u64) {
+        self.regions.push(IotlbRegion { gpa, size, uaddr });
+    }
+
+    /// Maps every memory region at its guest physical address (a guest without IOMMU).
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the regions were mapped, `IotlbConflict` if one already is.
+    pub fn map_identity(&mut self) -> Result<()> {
+        for region in self.regions.clone() {
+            self.map(region.gpa, region.size, region.gpa, VHOST_ACCESS_RW)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the mapping holding an IOVA, with its first IOVA.
+    fn mapping(&self, iova: u64) -> Option<(u64, IotlbMapping)> {
+        self.mappings
+            .range(..=iova)
+            .next_back()
+            .filter(|(start, mapping)| iova - **start < mapping.size)
+            .map(|(start, mapping)| (*start, *mapping))
+    }
+
+    /// Maps an IOVA range.
+    ///
+    /// # Arguments
+    ///
+    /// * `iova` - First IOVA of the range.
+    /// * `size` - Size of the range.
+    /// * `gpa` - Guest physical address the range maps to.
+    /// * `perm` - Access permissions (`VHOST_ACCESS_*`).
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the range was mapped, `IotlbConflict` if it is empty, wraps
+    ///   around or overlaps a mapped range.
+    pub fn map(&mut self, iova: u64, size: u64, gpa: u64, perm: u8) -> Result<()> {
+        let conflict = || Error::IotlbConflict(iova, size);
+        let last = iova.checked_add(size.checked_sub(1).ok_or_else(conflict)?);
+        let last = last.ok_or_else(conflict)?;
+        if self.mapping(iova).is_some() || self.mappings.range(iova..=last).next().is_some() {
+            return Err(conflict());
+        }
+        self.mappings.insert(iova, IotlbMapping { size, gpa, perm });
+        Ok(())
+    }
+
+    /// Unmaps the mappings overlapping an IOVA range.
+    ///
+    /// # Arguments
+    ///
+    /// * `iova` - First IOVA of the range.
+    /// * `size` - Size of the range.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<IotlbMsg>` - The invalidations to send to the backend, one per mapping.
+    pub fn unmap(&mut self, iova: u64, size: u64) -> Vec<IotlbMsg> {
+        let last = iova.saturating_add(size.saturating_sub(1));
+        let first = self.mapping(iova).map_or(iova, |(start, _)| start);
+        let starts = self
+            .mappings
+            .range(first..=last)
+            .map(|(start, _)| *start)
+            .collect::<Vec<_>>();
+        starts
+            .into_iter()
+            .filter_map(|start| self.mappings.remove(&start).map(|m| (start, m)))
+            .map(|(start, mapping)| IotlbMsg {
+                iova: start,
+                size: mapping.size,
+                msg_type: VHOST_IOTLB_INVALIDATE,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    /// Translates an IOVA range to guest physical addresses.
+    ///
+    /// # Arguments
+    ///
+    /// * `iova` - First IOVA of the range.
+    /// * `len` - Size of the range.
+    /// * `perm` - Access to perform (`VHOST_ACCESS_*`).
+    ///
+    /// # Returns
+    ///
+    /// * `Result<u64>` - The guest physical address, or `IotlbFault` if the range is not
+    ///   mapped by a single mapping allowing the access.
+    pub fn translate(&self, iova: u64, len: u64, perm: u8) -> Result<u64> {
+        let fault = || Error::IotlbFault(iova, len);
+        let (start, mapping) = self.mapping(iova).ok_or_else(fault)?;
+        let offset = iova - start;
+        if len > mapping.size - offset || perm & !mapping.perm != 0 {
+            return Err(fault());
+        }
+        Ok(mapping.gpa + offset)
+    }
+
+    /// Answers an IOTLB miss of the backend.
+    ///
+    /// The update covers the whole mapping holding the missed IOVA (up to the end of its
+    /// memory region), so the backend does not miss again on the next descriptors.
+    ///
+    /// # Arguments
+    ///
+    /// * `miss` - The miss message (from the backend request channel).
+    ///
+    /// # Returns
+    ///
+    /// * `Result<IotlbMsg>` - The update to send to the backend, `IotlbFault` if the IOVA
+    ///   is not mapped with the requested access or outside the shared memory, and
+    ///   `InvalidVhostUserMessage` if the message is not a miss.
+    pub fn handle_miss(&self, miss: &IotlbMsg) -> Result<IotlbMsg> {
+        if miss.msg_type != VHOST_IOTLB_MISS {
+            return Err(Error::InvalidVhostUserMessage(VHOST_USER_BACKEND_IOTLB_MSG));
+        }
+        let fault = || Error::IotlbFault(miss.iova, 1);
+        let (start, mapping) = self.mapping(miss.iova).ok_or_else(fault)?;
+        if miss.perm & !mapping.perm != 0 {
+            return Err(fault());
+        }
+        let region = self
+            .regions
+            .iter()
+            .find(|r| mapping.gpa >= r.gpa && mapping.gpa - r.gpa < r.size)
+            .ok_or_else(fault)?;
+        let offset = mapping.gpa - region.gpa;
+        Ok(IotlbMsg {
+            iova: start,
+            size: mapping.size.min(region.size - offset),
+            uaddr: region.uaddr + offset,
+            perm: mapping.perm,
+            msg_type: VHOST_IOTLB_UPDATE,
+        })
+    }
+
+    /// Handles a message of the backend request channel.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request code.
+    /// * `payload` - The request payload.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<IotlbMsg>` - The update to send to the backend (see `handle_miss`), or
+    ///   `InvalidVhostUserMessage` if the request is not an IOTLB miss.
+    pub fn handle_backend_request(&self, request: u32, payload: &[u8]) -> Result<IotlbMsg> {
+        if request != VHOST_USER_BACKEND_IOTLB_MSG {
+            return Err(Error::InvalidVhostUserMessage(request));
+        }
+        self.handle_miss(&IotlbMsg::from_bytes(payload)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::defines::{VHOST_ACCESS_RO, VHOST_ACCESS_WO, VHOST_IOTLB_ACCESS_FAIL};
+
+    #[test]
+    fn test_iotlb_messages() {
+        let msg = IotlbMsg {
+            iova: 0x1000,
+            size: 0x2000,
+            uaddr: 0x7f00_0000_0000,
+            perm: VHOST_ACCESS_RW,
+            msg_type: VHOST_IOTLB_UPDATE,
+        };
+        assert_eq!(IotlbMsg::from_bytes(&msg.to_bytes()).unwrap(), msg);
+        assert!(IotlbMsg::from_bytes(&[0; 16]).is_err());
+
+        let request = msg.to_request();
+        assert_eq!(request.len(), VHOST_USER_HEADER_SIZE + VHOST_IOTLB_MSG_SIZE);
+        assert_eq!(request[0..4], VHOST_USER_IOTLB_MSG.to_le_bytes());
+        assert_ne!(
+            u32::from_le_bytes(request[4..8].try_into().unwrap()) & VHOST_USER_NEED_REPLY_MASK,
+            0
+        );
+        assert_eq!(
+            IotlbMsg::from_bytes(&request[VHOST_USER_HEADER_SIZE..]).unwrap(),
+            msg
+        );
+    }
+
+    #[test]
+    fn test_iotlb_translation() {
+        let mut iotlb = Iotlb::new();
+        iotlb.add_region(0x5000_0000, 0x100_0000, 0x7f00_0000_0000);
+
+        // Mappings set by the IOMMU: a writable buffer and a read-only one
+        iotlb
+            .map(0x1_0000, 0x2000, 0x5000_4000, VHOST_ACCESS_RW)
+            .unwrap();
+        iotlb
+            .map(0x2_0000, 0x1000, 0x5001_0000, VHOST_ACCESS_RO)
+            .unwrap();
+        for (iova, size) in [(0x1_1000, 0x1000), (0xf000, 0x1001), (0x1_0000, 0)] {
+            assert!(matches!(
+                iotlb.map(iova, size, 0x5000_0000, VHOST_ACCESS_RW),
+                Err(Error::IotlbConflict(..))
+            ));
+        }
+
+        assert_eq!(
+            iotlb.translate(0x1_0800, 0x100, VHOST_ACCESS_WO).unwrap(),
+            0x5000_4800
+        );
+        assert_eq!(
+            iotlb.translate(0x2_0000, 0x1000, VHOST_ACCESS_RO).unwrap(),
+            0x5001_0000
+        );
+        for (iova, len, perm) in [
+            (0x2_0000, 4, VHOST_ACCESS_WO),
+            (0x1_1f00, 0x200, VHOST_ACCESS_RO),
+            (0x3_0000, 4, VHOST_ACCESS_RO),
+        ] {
+            let err = iotlb.translate(iova, len, perm).unwrap_err();
+            assert!(matches!(err, Error::IotlbFault(..)));
+            assert_eq!(err.errno(), libc::EFAULT);
+        }
+
+        // The backend misses in the middle of a mapping and gets all of it
+        let miss = IotlbMsg {
+            iova: 0x1_1234,
+            perm: VHOST_ACCESS_RO,
+            msg_type: VHOST_IOTLB_MISS,
+            ..Default::default()
+        };
+        let update = iotlb
+            .handle_backend_request(VHOST_USER_BACKEND_IOTLB_MSG, &miss.to_bytes())
+            .unwrap();
+        assert_eq!(
+            update,
+            IotlbMsg {
+                iova: 0x1_0000,
+                size: 0x2000,
+                uaddr: 0x7f00_0000_4000,
+                perm: VHOST_ACCESS_RW,
+                msg_type: VHOST_IOTLB_UPDATE,
+            }
+        );
+        let write = IotlbMsg {
+            iova: 0x2_0000,
+            perm: VHOST_ACCESS_WO,
+            ..miss
+        };
+        assert!(matches!(
+            iotlb.handle_miss(&write),
+            Err(Error::IotlbFault(0x2_0000, _))
+        ));
+        let fail = IotlbMsg {
+            msg_type: VHOST_IOTLB_ACCESS_FAIL,
+            ..miss
+        };
+        assert!(matches!(
+            iotlb.handle_miss(&fail),
+            Err(Error::InvalidVhostUserMessage(_))
+        ));
+
+        // Unmapping a range invalidates every mapping it touches
+        let invalidations = iotlb.unmap(0x1_1000, 0x1_0000);
+        assert_eq!(
+            invalidations
+                .iter()
+                .map(|m| (m.iova, m.size, m.msg_type))
+                .collect::<Vec<_>>(),
+            [
+                (0x1_0000, 0x2000, VHOST_IOTLB_INVALIDATE),
+                (0x2_0000, 0x1000, VHOST_IOTLB_INVALIDATE)
+            ]
+        );
+        assert!(iotlb.translate(0x1_0000, 1, VHOST_ACCESS_RO).is_err());
+        assert!(iotlb.unmap(0x1_0000, 0x1000).is_empty());
+
+        // Without an IOMMU, the guest RAM is mapped at its physical addresses
+        iotlb.map_identity().unwrap();
+        assert_eq!(
+            iotlb.translate(0x5000_1000, 8, VHOST_ACCESS_RW).unwrap(),
+            0x5000_1000
+        );
+        assert!(iotlb.map_identity().is_err());
+    }
+}
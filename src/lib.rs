@@ -1,5 +1,158 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao hypervisor kernel interface.
+//!
+//! The ABI types (`defines`, `types`) build without the standard library; the
+//! remaining modules (configuration, ioctls, sandboxing, ...) require the `std` feature.
+//! The `bao-sys` binary is a thin command line consumer of this library.
+
+#![cfg_attr(not(any(feature = "std", test)), no_std)]
+
+#[cfg(feature = "std")]
+pub mod access;
+#[cfg(feature = "std")]
+pub mod alloc;
+#[cfg(feature = "std")]
+pub mod applied;
+#[cfg(feature = "std")]
+pub mod attach;
+#[cfg(feature = "std")]
+pub mod audit;
+#[cfg(feature = "std")]
+pub mod backend;
+#[cfg(feature = "std")]
+pub mod block;
+#[cfg(feature = "std")]
+pub mod bus;
+#[cfg(feature = "std")]
+pub mod can;
+#[cfg(feature = "std")]
+pub mod capture;
+#[cfg(feature = "std")]
+pub mod coalesce;
+#[cfg(feature = "std")]
+pub mod compat;
+#[cfg(feature = "std")]
+pub mod config;
+#[cfg(feature = "std")]
+pub mod console;
+#[cfg(feature = "std")]
+pub mod control;
+#[cfg(feature = "std")]
+pub mod crash;
+#[cfg(feature = "dbus")]
+pub mod dbus;
 pub mod defines;
+#[cfg(feature = "std")]
+pub mod diagnostics;
+#[cfg(feature = "std")]
+pub mod dtb;
+#[cfg(feature = "std")]
 pub mod error;
+#[cfg(feature = "std")]
+pub mod events;
+#[cfg(feature = "std")]
+pub mod fair;
+#[cfg(any(test, feature = "test-support"))]
+pub mod fake_backend;
+#[cfg(feature = "fault-injection")]
+pub mod fault;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "std")]
+pub mod gpio;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+#[cfg(feature = "std")]
+pub mod handoff;
+#[cfg(feature = "std")]
+pub mod hypervisor;
+#[cfg(feature = "std")]
+pub mod i2c;
+#[cfg(feature = "std")]
+pub mod inflight;
+#[cfg(feature = "std")]
+pub mod init;
+#[cfg(feature = "std")]
+pub mod instrument;
+#[cfg(feature = "std")]
 pub mod ioctl;
+#[cfg(feature = "std")]
+pub mod iotlb;
+#[cfg(feature = "std")]
+pub mod logging;
+#[cfg(feature = "std")]
+pub mod mac;
+#[cfg(feature = "std")]
+pub mod management;
+#[cfg(feature = "std")]
+pub mod memory;
+#[cfg(feature = "otel")]
+pub mod otel;
+#[cfg(feature = "std")]
+pub mod overlay;
+#[cfg(feature = "std")]
+pub mod pause;
+#[cfg(feature = "std")]
+pub mod platform;
+#[cfg(feature = "std")]
+pub mod poll;
+#[cfg(feature = "python")]
+pub mod python;
+#[cfg(feature = "std")]
+pub mod ram;
+#[cfg(feature = "std")]
+pub mod record;
+#[cfg(feature = "std")]
+pub mod resources;
+#[cfg(feature = "std")]
+pub mod runtime;
+#[cfg(feature = "std")]
+pub mod sandbox;
+#[cfg(feature = "std")]
+pub mod sched;
+#[cfg(feature = "std")]
+pub mod secrets;
+#[cfg(feature = "simulate")]
+pub mod simulate;
+#[cfg(feature = "std")]
+pub mod slow;
+#[cfg(feature = "std")]
+pub mod snapshot;
+#[cfg(feature = "std")]
+pub mod sound;
+#[cfg(feature = "std")]
+pub mod spawn;
+#[cfg(feature = "std")]
+pub mod spsc;
+#[cfg(feature = "std")]
+pub mod stats;
+#[cfg(feature = "std")]
+pub mod stats_file;
+#[cfg(any(test, feature = "test-support"))]
+pub mod strategies;
+#[cfg(feature = "std")]
+pub mod summary;
+#[cfg(feature = "std")]
+pub mod supervisor;
+#[cfg(feature = "std")]
+pub mod tap;
+#[cfg(any(test, feature = "test-support", feature = "simulate"))]
+pub mod testing;
+#[cfg(feature = "std")]
+pub mod trace;
 pub mod types;
+#[cfg(feature = "std")]
 pub mod utils;
+#[cfg(feature = "std")]
+pub mod uuid;
+#[cfg(feature = "std")]
+pub mod version;
+#[cfg(feature = "std")]
+pub mod vhost_backend;
+pub mod virtio_ids;
+#[cfg(feature = "std")]
+pub mod watch;
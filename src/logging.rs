@@ -0,0 +1,310 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao frontend logging.
+//!
+//! Every message is emitted at the frontend verbosity, except for devices overriding it
+//! with `log_level` and `log_file`: a single misbehaving device can be debugged at `trace`
+//! into its own file, while the frontend log keeps its usual verbosity for every device.
+//!
+//! The messages about a device are labelled with its frontend and UUID
+//! (`frontend0/rng0 (8c2f...)`), so the log of a process serving several frontends can be
+//! split per tenant, and the messages of a device followed across renames. The labels of
+//! the device, if any, follow (`frontend0/rng0 (8c2f...) [function=adas rack=a1]`).
+
+#![allow(dead_code)]
+
+use super::error::{Error, Result};
+use super::types::{ConfigFrontends, LogLevel};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            LogLevel::Error => "ERROR",
+            LogLevel::Warn => "WARN",
+            LogLevel::Info => "INFO",
+            LogLevel::Debug => "DEBUG",
+            LogLevel::Trace => "TRACE",
+        };
+        // Pad to the longest name, so the messages line up
+        f.pad(name)
+    }
+}
+
+/// Struct representing the log overrides of a device.
+///
+/// # Attributes
+///
+/// * `level` - Verbosity of the device messages.
+/// * `file` - File receiving the device messages (the frontend log if None).
+struct DeviceLog {
+    level: LogLevel,
+    file: Option<Mutex<File>>,
+}
+
+/// Struct representing the frontend logger.
+///
+/// # Attributes
+///
+/// * `level` - Frontend verbosity.
+/// * `sink` - The frontend log (e.g. stderr).
+/// * `devices` - Log overrides, by device name.
+/// * `labels` - Label of the configured devices (frontend, name, UUID and labels), by
+///   device name.
+pub struct Logger {
+    level: LogLevel,
+    sink: Mutex<Box<dyn Write + Send>>,
+    devices: BTreeMap<String, DeviceLog>,
+    labels: BTreeMap<String, String>,
+}
+
+impl Logger {
+    /// Creates the logger of a configuration, opening the device log files.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The frontends configuration.
+    /// * `level` - Frontend verbosity.
+    /// * `sink` - The frontend log.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Logger>` - The logger.
+    pub fn new(
+        config: &ConfigFrontends,
+        level: LogLevel,
+        sink: Box<dyn Write + Send>,
+    ) -> Result<Self> {
+        let mut devices = BTreeMap::new();
+        let labels = config
+            .frontends
+            .iter()
+            .flat_map(|f| {
+                f.guests.iter().flat_map(move |g| {
+                    g.devices.iter().map(move |d| {
+                        let mut label = format!("{}/{} ({})", f.name, d.name, d.uuid(g.id));
+                        if !d.labels.is_empty() {
+                            let labels = d
+                                .labels
+                                .iter()
+                                .map(|(key, value)| format!("{}={}", key, value))
+                                .collect::<Vec<_>>();
+                            label = format!("{} [{}]", label, labels.join(" "));
+                        }
+                        (d.name.clone(), label)
+                    })
+                })
+            })
+            .collect();
+        let configured = config
+            .frontends
+            .iter()
+            .flat_map(|f| &f.guests)
+            .flat_map(|g| &g.devices)
+            .filter(|d| d.log_level.is_some() || d.log_file.is_some());
+        for device in configured {
+            let file = match &device.log_file {
+                Some(path) => Some(Mutex::new(
+                    OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(path)
+                        .map_err(|e| Error::LogFileFailed(path.clone(), e))?,
+                )),
+                None => None,
+            };
+            let log = DeviceLog {
+                level: device.log_level.unwrap_or(level),
+                file,
+            };
+            devices.insert(device.name.clone(), log);
+        }
+        Ok(Logger {
+            level,
+            sink: Mutex::new(sink),
+            devices,
+            labels,
+        })
+    }
+
+    /// Checks if the messages of a given verbosity are emitted.
+    ///
+    /// # Arguments
+    ///
+    /// * `device` - Device the messages are about (None for the frontend itself).
+    /// * `level` - Message verbosity.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - True if such messages are emitted, so the caller may skip formatting them.
+    pub fn enabled(&self, device: Option<&str>, level: LogLevel) -> bool {
+        match device.and_then(|name| self.devices.get(name)) {
+            Some(log) => level <= log.level,
+            None => level <= self.level,
+        }
+    }
+
+    /// Emits a message.
+    ///
+    /// Messages of a device with a log file go to that file, and also to the frontend log
+    /// if the frontend verbosity includes them.
+    ///
+    /// # Arguments
+    ///
+    /// * `device` - Device the message is about (None for the frontend itself).
+    /// * `level` - Message verbosity.
+    /// * `args` - The message (see `format_args!`).
+    pub fn log(&self, device: Option<&str>, level: LogLevel, args: fmt::Arguments) {
+        if !self.enabled(device, level) {
+            return;
+        }
+        let file = device
+            .and_then(|name| self.devices.get(name))
+            .and_then(|log| log.file.as_ref())
+            .map(|file| file.lock().unwrap());
+        let frontend = file.is_none() || level <= self.level;
+
+        let label = match device {
+            Some(name) => self
+                .labels
+                .get(name)
+                .cloned()
+                .unwrap_or_else(|| name.to_string()),
+            None => "frontend".to_string(),
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let line = format!(
+            "{}.{:06} {:<5} {}: {}\n",
+            now.as_secs(),
+            now.subsec_micros(),
+            level,
+            label,
+            args
+        );
+        // Logging never fails the caller
+        if let Some(mut file) = file {
+            let _ = file.write_all(line.as_bytes());
+        }
+        if frontend {
+            let _ = self.sink.lock().unwrap().write_all(line.as_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::VmId;
+    use crate::uuid::DeviceUuid;
+    use std::fs;
+    use std::sync::Arc;
+
+    /// A frontend log shared with the test.
+    struct SharedLog(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedLog {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_device_log_overrides() {
+        let path = std::env::temp_dir().join(format!("bao-i2c0-{}.log", std::process::id()));
+        let config: ConfigFrontends = serde_yaml::from_str(&format!(
+            "frontends:
+  - name: frontend0
+    id: 0
+    guests:
+      - name: guest0
+        id: 1
+        ram_addr: 0x50000000
+        ram_size: 0x1000000
+        shmem_path: /dev/baoipc0
+        socket_path: /tmp/
+        devices:
+          - {{name: rng0, id: 0, type: rng, irq: 47, addr: 0xa003e00, log_level: error}}
+          - {{name: i2c0, id: 1, type: i2c, irq: 46, addr: 0xa003c00, log_level: trace, log_file: {}}}
+          - {{name: gpio0, id: 2, type: gpio, irq: 45, addr: 0xa003a00, labels: {{rack: a1, function: adas}}}}
+",
+            path.display()
+        ))
+        .unwrap();
+        let frontend = Arc::new(Mutex::new(Vec::new()));
+        let logger = Logger::new(
+            &config,
+            LogLevel::Info,
+            Box::new(SharedLog(frontend.clone())),
+        )
+        .unwrap();
+
+        assert!(logger.enabled(Some("i2c0"), LogLevel::Trace));
+        assert!(!logger.enabled(Some("gpio0"), LogLevel::Debug));
+        assert!(!logger.enabled(Some("rng0"), LogLevel::Info));
+        for device in ["rng0", "i2c0", "gpio0"] {
+            logger.log(
+                Some(device),
+                LogLevel::Trace,
+                format_args!("read {}", device),
+            );
+            logger.log(
+                Some(device),
+                LogLevel::Info,
+                format_args!("ready {}", device),
+            );
+            logger.log(
+                Some(device),
+                LogLevel::Error,
+                format_args!("failed {}", device),
+            );
+        }
+
+        // The frontend log keeps its verbosity (minus the quieted devices)
+        let label = |name| format!("frontend0/{} ({})", name, DeviceUuid::derive(VmId(1), name));
+        let frontend = String::from_utf8(frontend.lock().unwrap().clone()).unwrap();
+        let messages = frontend
+            .lines()
+            .map(|line| line.split_once(' ').unwrap().1)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            messages,
+            [
+                format!("ERROR {}: failed rng0", label("rng0")),
+                format!("INFO  {}: ready i2c0", label("i2c0")),
+                format!("ERROR {}: failed i2c0", label("i2c0")),
+                format!(
+                    "INFO  {} [function=adas rack=a1]: ready gpio0",
+                    label("gpio0")
+                ),
+                format!(
+                    "ERROR {} [function=adas rack=a1]: failed gpio0",
+                    label("gpio0")
+                )
+            ]
+        );
+
+        // The verbose device gets every message in its own file
+        let file = fs::read_to_string(&path).unwrap();
+        assert_eq!(file.lines().count(), 3);
+        assert!(file
+            .lines()
+            .next()
+            .unwrap()
+            .ends_with(&format!("TRACE {}: read i2c0", label("i2c0"))));
+        fs::remove_file(&path).unwrap();
+    }
+}
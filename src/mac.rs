@@ -0,0 +1,196 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao net device MAC addresses.
+//!
+//! DHCP reservations are keyed on the MAC address of the guest, which must then stay the
+//! same across reboots. Every net device has one: the `mac` backend option if set, or one
+//! derived from its frontend, guest and device IDs otherwise (`02:ba:00:NN:NN:NN`,
+//! locally administered, the NIC bytes being the 64-bit FNV-1a hash of the three IDs
+//! folded to 24 bits). The guest reads it from the configuration space of the device
+//! (`VIRTIO_NET_F_MAC`), and it is reported with the device in the management outputs.
+//!
+//! Derived addresses may still collide (the configuration check reports it), and only
+//! differ across the guests of a host: set `mac` explicitly in either case, or when the
+//! guests of several hosts share a network.
+
+#![allow(dead_code)]
+
+use super::error::{Error, Result};
+use super::types::{DeviceId, VmId};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// First bytes of the derived MAC addresses (a locally administered unicast prefix).
+const BAO_MAC_PREFIX: [u8; 3] = [0x02, 0xba, 0x00];
+
+/// 64-bit FNV-1a offset basis and prime.
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// Struct representing the MAC address of a net device.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize,
+)]
+#[serde(try_from = "String", into = "String")]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    /// Derives the MAC address of a device from its frontend, guest and device IDs.
+    ///
+    /// # Arguments
+    ///
+    /// * `frontend_id` - Frontend ID.
+    /// * `guest_id` - Guest ID.
+    /// * `device_id` - Device ID.
+    ///
+    /// # Returns
+    ///
+    /// * `MacAddress` - The address (`02:ba:00:NN:NN:NN`).
+    pub fn derive(frontend_id: VmId, guest_id: VmId, device_id: DeviceId) -> Self {
+        let ids = [frontend_id.raw(), guest_id.raw(), device_id.raw()];
+        let hash = ids
+            .iter()
+            .flat_map(|id| id.to_le_bytes())
+            .fold(FNV_OFFSET_BASIS, |hash, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME)
+            });
+        // Every bit of the hash lands in the NIC bytes
+        let nic = (hash ^ (hash >> 24) ^ (hash >> 48)).to_be_bytes();
+        MacAddress([
+            BAO_MAC_PREFIX[0],
+            BAO_MAC_PREFIX[1],
+            BAO_MAC_PREFIX[2],
+            nic[5],
+            nic[6],
+            nic[7],
+        ])
+    }
+
+    /// Checks whether the address is a unicast one (the only kind a device may have).
+    pub fn is_unicast(&self) -> bool {
+        self.0[0] & 0x1 == 0
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+impl FromStr for MacAddress {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidMacAddress(s.to_string());
+        let mut bytes = [0u8; 6];
+        let mut parts = s.split(':');
+        for byte in bytes.iter_mut() {
+            let part = parts.next().ok_or_else(invalid)?;
+            if part.len() != 2 {
+                return Err(invalid());
+            }
+            *byte = u8::from_str_radix(part, 16).map_err(|_| invalid())?;
+        }
+        let mac = MacAddress(bytes);
+        if parts.next().is_some() || !mac.is_unicast() {
+            return Err(invalid());
+        }
+        Ok(mac)
+    }
+}
+
+impl TryFrom<String> for MacAddress {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<MacAddress> for String {
+    fn from(mac: MacAddress) -> Self {
+        mac.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::ConfigDevice;
+
+    #[test]
+    fn test_mac_address() {
+        let mac = "52:54:00:12:34:AB".parse::<MacAddress>().unwrap();
+        assert_eq!(mac, MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0xab]));
+        assert_eq!(mac.to_string(), "52:54:00:12:34:ab");
+        for invalid in [
+            "52:54:00:12:34",
+            "52:54:00:12:34:56:78",
+            "52:54:00:12:34:5g",
+            "52:54:00:12:34:5",
+            "5254.0012.3456",
+            // Multicast
+            "01:00:5e:00:00:01",
+        ] {
+            assert!(
+                matches!(
+                    invalid.parse::<MacAddress>(),
+                    Err(Error::InvalidMacAddress(_))
+                ),
+                "{}",
+                invalid
+            );
+        }
+
+        // Derived addresses are stable, and differ across frontends, guests and devices
+        let mac = MacAddress::derive(VmId(0), VmId(1), DeviceId(2));
+        assert_eq!(mac.to_string(), "02:ba:00:cd:07:4a");
+        assert!(mac.is_unicast());
+        assert_ne!(mac, MacAddress::derive(VmId(0), VmId(2), DeviceId(1)));
+        assert_ne!(mac, MacAddress::derive(VmId(1), VmId(1), DeviceId(2)));
+        // No ID bits are dropped
+        assert_ne!(
+            MacAddress::derive(VmId(0), VmId(0x1_0001), DeviceId(0)),
+            MacAddress::derive(VmId(0), VmId(0x0001), DeviceId(0))
+        );
+
+        let mut device = ConfigDevice {
+            name: "net0".to_string(),
+            id: DeviceId(2),
+            device_type: "net".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(device.mac(VmId(0), VmId(1)).unwrap(), Some(mac));
+        device
+            .options
+            .insert("mac".to_string(), "52:54:00:12:34:56".to_string());
+        assert_eq!(
+            device.mac(VmId(0), VmId(1)).unwrap().unwrap().to_string(),
+            "52:54:00:12:34:56"
+        );
+        device
+            .options
+            .insert("mac".to_string(), "00:11".to_string());
+        assert!(matches!(
+            device.mac(VmId(0), VmId(1)),
+            Err(Error::InvalidMacAddress(_))
+        ));
+        device.device_type = "rng".to_string();
+        assert_eq!(device.mac(VmId(0), VmId(1)).unwrap(), None);
+
+        // Serialized as strings
+        let yaml = serde_yaml::to_string(&mac).unwrap();
+        assert_eq!(serde_yaml::from_str::<MacAddress>(&yaml).unwrap(), mac);
+        assert!(serde_yaml::from_str::<MacAddress>("\"ff:ff:ff:ff:ff:ff\"").is_err());
+    }
+}
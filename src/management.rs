@@ -0,0 +1,2041 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao management surface.
+//!
+//! The `Management` trait is the set of operations exposed to management clients; the
+//! transports (e.g. the gRPC service of the `grpc` feature) are thin adapters over it.
+
+#![allow(dead_code)]
+
+use super::access::AccessFilter;
+use super::backend::Reconnect;
+use super::block::Resize;
+use super::capture::{Capture, CaptureOptions, CaptureStats};
+use super::coalesce::IrqCoalescer;
+use super::console::{ConsoleLog, ConsoleOutput};
+use super::error::{Error, ErrorContext, Result, ResultExt};
+use super::events::DeviceEvent;
+#[cfg(feature = "fault-injection")]
+use super::fault::{Fault, FaultInjector, FaultPlan};
+use super::mac::MacAddress;
+use super::pause::{Pause, PauseMode};
+use super::resources::{DeviceResources, ResourceSnapshot};
+use super::snapshot::{FrontendSnapshot, Snapshot, SnapshotDevice};
+use super::stats::{irq_stats, DeviceStats, DeviceStatsSnapshot, IrqStatsSnapshot};
+use super::supervisor::UnitStatus;
+use super::trace::LogFile;
+use super::types::{
+    ConfigCoalesce, ConfigDevice, ConfigFrontends, ConfigRegRange, DeviceId, GuestAddress, IrqLine,
+    VmId,
+};
+use super::uuid::DeviceUuid;
+use super::vhost_backend::{QueueDump, Queues};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+
+/// Struct representing a frontend served by the process.
+///
+/// # Attributes
+///
+/// * `name` - Frontend name.
+/// * `id` - Frontend ID.
+/// * `enabled` - Whether its devices are served (the devices of a disabled frontend are
+///   stalled).
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct FrontendInfo {
+    pub name: String,
+    pub id: VmId,
+    pub enabled: bool,
+}
+
+/// Struct representing a managed device.
+///
+/// # Attributes
+///
+/// * `frontend_id` - Frontend ID.
+/// * `guest_id` - Guest ID.
+/// * `name` - Device name.
+/// * `device_type` - Device type.
+/// * `id` - Device ID.
+/// * `irq` - Device IRQ.
+/// * `addr` - Device address.
+/// * `uuid` - Stable device identifier (nil in the snapshots taken before devices had
+///   one).
+/// * `mac` - MAC address of a net device (None for the other devices).
+/// * `labels` - Deployment metadata of the device (see `ConfigDevice::labels`).
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DeviceInfo {
+    pub frontend_id: VmId,
+    pub guest_id: VmId,
+    pub name: String,
+    pub device_type: String,
+    pub id: DeviceId,
+    pub irq: IrqLine,
+    pub addr: GuestAddress,
+    #[serde(default)]
+    pub uuid: DeviceUuid,
+    #[serde(default)]
+    pub mac: Option<MacAddress>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+impl DeviceInfo {
+    /// Describes a configured device.
+    ///
+    /// # Arguments
+    ///
+    /// * `frontend_id` - Frontend ID.
+    /// * `guest_id` - Guest ID.
+    /// * `device` - The device configuration.
+    ///
+    /// # Returns
+    ///
+    /// * `DeviceInfo` - The device description.
+    pub fn new(frontend_id: VmId, guest_id: VmId, device: &ConfigDevice) -> Self {
+        DeviceInfo {
+            frontend_id,
+            guest_id,
+            name: device.name.clone(),
+            device_type: device.device_type.clone(),
+            id: device.id,
+            irq: device.irq,
+            addr: device.addr,
+            uuid: device.uuid(guest_id),
+            // An invalid `mac` option is rejected with the configuration
+            mac: device.mac(frontend_id, guest_id).unwrap_or_default(),
+            labels: device.labels.clone(),
+        }
+    }
+}
+
+/// Enum representing the state of a managed device.
+///
+/// # Variants
+///
+/// * `Unplugged` - The device is not attached to the guest.
+/// * `Starting` - The device backend is being brought up.
+/// * `Running` - The device is serving the guest.
+/// * `Paused` - The device is paused (see `Management::pause_device`).
+/// * `Failed` - The device backend failed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DeviceState {
+    #[default]
+    Unplugged,
+    Starting,
+    Running,
+    Paused,
+    Failed,
+}
+
+impl DeviceState {
+    /// Returns the name of the device state.
+    pub fn name(&self) -> &'static str {
+        match self {
+            DeviceState::Unplugged => "unplugged",
+            DeviceState::Starting => "starting",
+            DeviceState::Running => "running",
+            DeviceState::Paused => "paused",
+            DeviceState::Failed => "failed",
+        }
+    }
+}
+
+impl fmt::Display for DeviceState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Handles a hot-plug (true) or hot-unplug (false) request for a device.
+pub type HotplugHandler = Box<dyn Fn(&str, bool) -> Result<()> + Send + Sync>;
+
+/// Operations exposed to management clients.
+pub trait Management: Send + Sync + 'static {
+    /// Lists the devices.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<DeviceInfo>` - The devices.
+    fn devices(&self) -> Vec<DeviceInfo>;
+
+    /// Returns the counters of a device.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<DeviceStatsSnapshot>` - The counters, or `DeviceNotFound`.
+    fn device_stats(&self, name: &str) -> Result<DeviceStatsSnapshot>;
+
+    /// Resets the counters of a device.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the device exists.
+    fn reset_device_stats(&self, name: &str) -> Result<()>;
+
+    /// Returns the host resources held by a device.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<ResourceSnapshot>` - The resources, or `DeviceNotFound`.
+    fn device_resources(&self, name: &str) -> Result<ResourceSnapshot>;
+
+    /// Returns the interrupts injected on each IRQ line.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<IrqStatsSnapshot>` - The interrupts of each configured line.
+    fn irq_stats(&self) -> Vec<IrqStatsSnapshot>;
+
+    /// Returns the state of a device.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<DeviceState>` - The state, or `DeviceNotFound`.
+    fn device_state(&self, name: &str) -> Result<DeviceState>;
+
+    /// Hot-plugs (or hot-unplugs) a device.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    /// * `plugged` - True to plug the device, false to unplug it.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok once the request was accepted.
+    fn set_device_plugged(&self, name: &str, plugged: bool) -> Result<()>;
+
+    /// Pauses a running device (e.g. while the host takes its resource offline).
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    /// * `mode` - How the guest sees the paused device.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok once the device is paused, `InvalidDeviceState` if it is not
+    ///   running.
+    fn pause_device(&self, name: &str, mode: PauseMode) -> Result<()>;
+
+    /// Resumes a paused device.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok once the device runs again, `InvalidDeviceState` if it is not
+    ///   paused.
+    fn resume_device(&self, name: &str) -> Result<()>;
+
+    /// Resizes a device (e.g. grows the disk image of a block device), telling the guest.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    /// * `size` - New capacity (in bytes), or None to size the backing storage again.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<u64>` - The capacity of the device (in bytes), `ResizeNotSupported` if
+    ///   the device cannot be resized.
+    fn resize_device(&self, name: &str, size: Option<u64>) -> Result<u64>;
+
+    /// Starts capturing the frames of a device into a pcapng file.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    /// * `options` - Where to write the capture, and its limits.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok once the capture started, `CaptureNotSupported` if the frames
+    ///   of the device cannot be captured.
+    fn start_capture(&self, name: &str, options: &CaptureOptions) -> Result<()>;
+
+    /// Stops the packet capture of a device.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<CaptureStats>` - What was captured (the capture may have ended on its
+    ///   limits already), `CaptureNotRunning` if no capture was started.
+    fn stop_capture(&self, name: &str) -> Result<CaptureStats>;
+
+    /// Returns the console output a device kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<ConsoleOutput>` - The most recent output of the guest,
+    ///   `ConsoleLogNotSupported` if the device keeps none.
+    fn console_log(&self, name: &str) -> Result<ConsoleOutput>;
+
+    /// Returns the state of a virtqueue of a device, to tell whether the guest or the
+    /// backend stopped making progress.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    /// * `queue` - Queue index.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<QueueDump>` - The queue state, `QueueDumpNotSupported` if the queues of
+    ///   the device cannot be inspected, `QueueNotFound` if it has no such queue.
+    fn queue_dump(&self, name: &str, queue: usize) -> Result<QueueDump>;
+
+    /// Moves a running device to another vhost-user backend (e.g. an upgraded daemon),
+    /// without the guest noticing.
+    ///
+    /// The device is drained (its notifications held back, if it can be paused), its
+    /// state saved (if it can be snapshotted), connected to the new backend, its state
+    /// restored and its notifications served again. A device that fails to move is left
+    /// failed.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    /// * `socket` - The vhost-user socket of the new backend.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok once the new backend serves the device, `InvalidDeviceState` if
+    ///   it is not running, `MigrationNotSupported` if it cannot be reconnected.
+    fn migrate_backend(&self, name: &str, socket: &str) -> Result<()>;
+
+    /// Returns the interrupt coalescing of a device.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<ConfigCoalesce>>` - The coalescing parameters (None if disabled),
+    ///   or `DeviceNotFound`.
+    fn device_coalescing(&self, name: &str) -> Result<Option<ConfigCoalesce>>;
+
+    /// Changes the interrupt coalescing of a device.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    /// * `params` - The coalescing parameters (None to disable coalescing).
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the device exists.
+    fn set_device_coalescing(&self, name: &str, params: Option<ConfigCoalesce>) -> Result<()>;
+
+    /// Returns the MMIO access tracing of a device.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<Vec<ConfigRegRange>>>` - The traced register offset ranges (None if
+    ///   tracing is disabled, all registers if empty), or `DeviceNotFound`.
+    fn device_access_trace(&self, name: &str) -> Result<Option<Vec<ConfigRegRange>>>;
+
+    /// Changes the MMIO access tracing of a device.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    /// * `ranges` - The traced register offset ranges (None to disable tracing, all
+    ///   registers if empty).
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the device exists.
+    fn set_device_access_trace(
+        &self,
+        name: &str,
+        ranges: Option<Vec<ConfigRegRange>>,
+    ) -> Result<()>;
+
+    /// Lists the frontend processes of the supervisor.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<UnitStatus>` - The status of every process (empty unless supervising).
+    fn units(&self) -> Vec<UnitStatus>;
+
+    /// Snapshots the state of every device.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - Snapshot directory (created if needed).
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the snapshot was written, `SnapshotNotSupported` if a plugged
+    ///   device cannot be snapshotted.
+    fn snapshot(&self, dir: &str) -> Result<()>;
+
+    /// Restores the state of the devices from a snapshot.
+    ///
+    /// Every snapshotted device is checked against the current devices before any of them
+    /// is restored.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - Snapshot directory.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if every device was restored, `InvalidSnapshot` if the snapshot
+    ///   does not match the devices.
+    fn restore(&self, dir: &str) -> Result<()>;
+
+    /// Flushes the buffered records of the trace and audit files.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if every file was flushed.
+    fn flush_logs(&self) -> Result<()>;
+
+    /// Rotates the trace and audit files, delimiting a measurement window.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if every file was rotated.
+    fn rotate_logs(&self) -> Result<()>;
+
+    /// Subscribes to the device state changes.
+    ///
+    /// # Returns
+    ///
+    /// * `Receiver<(String, DeviceState)>` - Receives the device name and its new state.
+    fn subscribe(&self) -> Receiver<(String, DeviceState)>;
+
+    /// Subscribes to the device events (state changes included).
+    ///
+    /// # Returns
+    ///
+    /// * `Receiver<DeviceEvent>` - Receives the events, in publication order.
+    fn subscribe_events(&self) -> Receiver<DeviceEvent>;
+
+    /// Lists the frontends served by the process.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<FrontendInfo>` - The frontends, in configuration order.
+    fn frontends(&self) -> Vec<FrontendInfo>;
+
+    /// Enables or disables a frontend.
+    ///
+    /// Disabling a frontend stalls its running devices (see `PauseMode::Stall`), enabling
+    /// it resumes them; the other frontends of the process are not affected.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Frontend name.
+    /// * `enabled` - Whether to serve the devices of the frontend.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok once every device of the frontend changed state,
+    ///   `FrontendNotFound` if there is no such frontend.
+    fn set_frontend_enabled(&self, name: &str, enabled: bool) -> Result<()>;
+
+    /// Returns the faults injected into a device.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<FaultPlan>` - The faults, or `DeviceNotFound`.
+    #[cfg(feature = "fault-injection")]
+    fn device_faults(&self, name: &str) -> Result<FaultPlan>;
+
+    /// Injects a fault into a device.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    /// * `fault` - The fault (`Disconnect` fails the device and publishes a
+    ///   `BackendDisconnected` event, as a real disconnect would).
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the device exists.
+    #[cfg(feature = "fault-injection")]
+    fn inject_fault(&self, name: &str, fault: Fault) -> Result<()>;
+}
+
+/// Struct representing the devices of a frontend process and their counters.
+///
+/// # Attributes
+///
+/// * `devices` - Devices, in configuration (then hot-plug) order.
+/// * `stats` - Counters of the devices, indexed by device name.
+/// * `resources` - Resource accounting of the devices, indexed by device name.
+/// * `states` - State of the devices, indexed by device name.
+/// * `subscribers` - Device state change subscribers.
+/// * `event_subscribers` - Device event subscribers.
+/// * `hotplug` - Handler of the hot-plug requests (set by the frontend).
+/// * `coalescers` - Interrupt coalescers of the devices, indexed by device name.
+/// * `access_filters` - MMIO access trace filters of the devices, indexed by device name.
+/// * `units` - Status of the supervised frontend processes (set by the supervisor).
+/// * `snapshots` - Snapshottable models of the devices, indexed by device name.
+/// * `pausables` - Pausable models of the devices, indexed by device name.
+/// * `resizables` - Resizable models of the devices, indexed by device name.
+/// * `captures` - Packet captures of the devices, indexed by device name.
+/// * `console_logs` - Console output logs of the devices, indexed by device name.
+/// * `reconnectables` - Backend connections of the devices, indexed by device name.
+/// * `queues` - Virtqueues of the devices, indexed by device name.
+/// * `frontends` - Frontends of the devices, in configuration order.
+/// * `stalled` - Devices stalled by disabling their frontend, indexed by frontend ID.
+/// * `faults` - Fault injectors of the devices, indexed by device name.
+/// * `logs` - Trace and audit files of the process.
+#[derive(Default)]
+pub struct DeviceRegistry {
+    devices: RwLock<Vec<DeviceInfo>>,
+    stats: RwLock<BTreeMap<String, Arc<DeviceStats>>>,
+    resources: RwLock<BTreeMap<String, Arc<DeviceResources>>>,
+    states: RwLock<BTreeMap<String, DeviceState>>,
+    subscribers: Mutex<Vec<Sender<(String, DeviceState)>>>,
+    event_subscribers: Mutex<Vec<Sender<DeviceEvent>>>,
+    hotplug: RwLock<Option<HotplugHandler>>,
+    coalescers: RwLock<BTreeMap<String, Arc<IrqCoalescer>>>,
+    access_filters: RwLock<BTreeMap<String, Arc<AccessFilter>>>,
+    units: RwLock<Vec<UnitStatus>>,
+    snapshots: RwLock<BTreeMap<String, Arc<Mutex<dyn Snapshot>>>>,
+    pausables: RwLock<BTreeMap<String, Arc<Mutex<dyn Pause>>>>,
+    resizables: RwLock<BTreeMap<String, Arc<Mutex<dyn Resize>>>>,
+    captures: RwLock<BTreeMap<String, Arc<Mutex<dyn Capture>>>>,
+    console_logs: RwLock<BTreeMap<String, Arc<Mutex<dyn ConsoleLog>>>>,
+    reconnectables: RwLock<BTreeMap<String, Arc<Mutex<dyn Reconnect>>>>,
+    queues: RwLock<BTreeMap<String, Arc<Mutex<dyn Queues>>>>,
+    frontends: RwLock<Vec<FrontendInfo>>,
+    stalled: Mutex<BTreeMap<VmId, Vec<String>>>,
+    #[cfg(feature = "fault-injection")]
+    faults: RwLock<BTreeMap<String, Arc<FaultInjector>>>,
+    logs: RwLock<Vec<Arc<dyn LogFile>>>,
+}
+
+impl DeviceRegistry {
+    /// Creates a registry holding the devices of a configuration.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The frontends configuration.
+    ///
+    /// # Returns
+    ///
+    /// * `DeviceRegistry` - The registry (without counters).
+    pub fn new(config: &ConfigFrontends) -> Self {
+        let registry = DeviceRegistry::default();
+        for frontend in &config.frontends {
+            registry.frontends.write().unwrap().push(FrontendInfo {
+                name: frontend.name.clone(),
+                id: frontend.id,
+                enabled: true,
+            });
+            for guest in &frontend.guests {
+                for device in &guest.devices {
+                    // Duplicate names keep their first device
+                    let _ = registry.add_device(frontend.id, guest.id, device);
+                }
+            }
+        }
+        registry
+    }
+
+    /// Adds a device (e.g. a hot-plugged one), in the unplugged state.
+    ///
+    /// # Arguments
+    ///
+    /// * `frontend_id` - Frontend ID.
+    /// * `guest_id` - Guest ID.
+    /// * `device` - The device configuration.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if no device has the same name, `DeviceExists` otherwise.
+    pub fn add_device(
+        &self,
+        frontend_id: VmId,
+        guest_id: VmId,
+        device: &ConfigDevice,
+    ) -> Result<()> {
+        let mut states = self.states.write().unwrap();
+        if states.contains_key(&device.name) {
+            return Err(Error::DeviceExists(device.name.clone()));
+        }
+        states.insert(device.name.clone(), DeviceState::default());
+        self.coalescers.write().unwrap().insert(
+            device.name.clone(),
+            Arc::new(IrqCoalescer::new(device.coalesce)),
+        );
+        self.access_filters.write().unwrap().insert(
+            device.name.clone(),
+            Arc::new(AccessFilter::new(device.mmio_trace.clone())),
+        );
+        #[cfg(feature = "fault-injection")]
+        self.faults
+            .write()
+            .unwrap()
+            .insert(device.name.clone(), Arc::default());
+        self.devices
+            .write()
+            .unwrap()
+            .push(DeviceInfo::new(frontend_id, guest_id, device));
+        Ok(())
+    }
+
+    /// Removes a device (e.g. once hot-unplugged).
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the device existed.
+    pub fn remove_device(&self, name: &str) -> Result<()> {
+        let mut states = self.states.write().unwrap();
+        if states.remove(name).is_none() {
+            return Err(Error::DeviceNotFound);
+        }
+        self.coalescers.write().unwrap().remove(name);
+        self.access_filters.write().unwrap().remove(name);
+        #[cfg(feature = "fault-injection")]
+        self.faults.write().unwrap().remove(name);
+        self.stats.write().unwrap().remove(name);
+        self.resources.write().unwrap().remove(name);
+        self.snapshots.write().unwrap().remove(name);
+        self.pausables.write().unwrap().remove(name);
+        self.resizables.write().unwrap().remove(name);
+        self.captures.write().unwrap().remove(name);
+        self.console_logs.write().unwrap().remove(name);
+        self.reconnectables.write().unwrap().remove(name);
+        self.queues.write().unwrap().remove(name);
+        self.devices.write().unwrap().retain(|d| d.name != name);
+        Ok(())
+    }
+
+    /// Updates the state of a device, notifying the subscribers.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    /// * `state` - New state.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the device exists.
+    pub fn set_device_state(&self, name: &str, state: DeviceState) -> Result<()> {
+        match self.states.write().unwrap().get_mut(name) {
+            Some(current) if *current == state => return Ok(()),
+            Some(current) => *current = state,
+            None => return Err(Error::DeviceNotFound),
+        }
+        // Drop the subscribers that went away
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send((name.to_string(), state)).is_ok());
+        self.publish_event(DeviceEvent::StateChanged(name.to_string(), state));
+        if state == DeviceState::Running {
+            self.publish_event(DeviceEvent::DeviceReady(name.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Publishes a device event to the event subscribers.
+    ///
+    /// # Arguments
+    ///
+    /// * `event` - The event.
+    pub fn publish_event(&self, event: DeviceEvent) {
+        // Drop the subscribers that went away
+        self.event_subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// Sets the handler of the hot-plug requests.
+    ///
+    /// # Arguments
+    ///
+    /// * `handler` - The handler.
+    pub fn set_hotplug_handler(&self, handler: HotplugHandler) {
+        *self.hotplug.write().unwrap() = Some(handler);
+    }
+
+    /// Publishes the status of the supervised frontend processes.
+    ///
+    /// # Arguments
+    ///
+    /// * `units` - The status of every process.
+    pub fn set_units(&self, units: Vec<UnitStatus>) {
+        *self.units.write().unwrap() = units;
+    }
+
+    /// Attaches the counters of a device (once its backend is up).
+    ///
+    /// # Arguments
+    ///
+    /// * `stats` - The device counters (indexed by their name).
+    pub fn attach_stats(&self, stats: Arc<DeviceStats>) {
+        self.stats
+            .write()
+            .unwrap()
+            .insert(stats.name.clone(), stats);
+    }
+
+    /// Attaches the resource accounting of a device (once its backend is up).
+    ///
+    /// # Arguments
+    ///
+    /// * `resources` - The device resources (indexed by their name).
+    pub fn attach_resources(&self, resources: Arc<DeviceResources>) {
+        self.resources
+            .write()
+            .unwrap()
+            .insert(resources.name.clone(), resources);
+    }
+
+    /// Attaches a trace or audit file, to be flushed and rotated on demand.
+    ///
+    /// # Arguments
+    ///
+    /// * `log` - The file.
+    pub fn attach_log(&self, log: Arc<dyn LogFile>) {
+        self.logs.write().unwrap().push(log);
+    }
+
+    /// Attaches the model of a device, to take part in the snapshots (once its backend
+    /// is up).
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    /// * `model` - The device model.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the device exists.
+    pub fn attach_snapshot(&self, name: &str, model: Arc<Mutex<dyn Snapshot>>) -> Result<()> {
+        self.device_state(name)?;
+        self.snapshots
+            .write()
+            .unwrap()
+            .insert(name.to_string(), model);
+        Ok(())
+    }
+
+    /// Attaches the model of a device, to be paused and resumed (once its backend is up).
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    /// * `model` - The device model.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the device exists.
+    pub fn attach_pause(&self, name: &str, model: Arc<Mutex<dyn Pause>>) -> Result<()> {
+        self.device_state(name)?;
+        self.pausables
+            .write()
+            .unwrap()
+            .insert(name.to_string(), model);
+        Ok(())
+    }
+
+    /// Attaches the model of a device, to be resized (once its backend is up).
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    /// * `model` - The device model.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the device exists.
+    pub fn attach_resize(&self, name: &str, model: Arc<Mutex<dyn Resize>>) -> Result<()> {
+        self.device_state(name)?;
+        self.resizables
+            .write()
+            .unwrap()
+            .insert(name.to_string(), model);
+        Ok(())
+    }
+
+    /// Attaches the packet captures of a device (e.g. those of the TAP interface of a net
+    /// device, see `Tap::capture`).
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    /// * `model` - The packet captures.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the device exists.
+    pub fn attach_capture(&self, name: &str, model: Arc<Mutex<dyn Capture>>) -> Result<()> {
+        self.device_state(name)?;
+        self.captures
+            .write()
+            .unwrap()
+            .insert(name.to_string(), model);
+        Ok(())
+    }
+
+    /// Attaches the console output log of a device (e.g. of its built-in backend).
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    /// * `model` - The output log.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the device exists.
+    pub fn attach_console_log(&self, name: &str, model: Arc<Mutex<dyn ConsoleLog>>) -> Result<()> {
+        self.device_state(name)?;
+        self.console_logs
+            .write()
+            .unwrap()
+            .insert(name.to_string(), model);
+        Ok(())
+    }
+
+    /// Attaches the backend connection of a device, to be moved to another backend (once
+    /// the backend is up).
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    /// * `model` - The backend connection.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the device exists.
+    pub fn attach_reconnect(&self, name: &str, model: Arc<Mutex<dyn Reconnect>>) -> Result<()> {
+        self.device_state(name)?;
+        self.reconnectables
+            .write()
+            .unwrap()
+            .insert(name.to_string(), model);
+        Ok(())
+    }
+
+    /// Attaches the virtqueues of a device (e.g. `VhostUserBackend::state`), to be
+    /// inspected.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    /// * `model` - The virtqueues.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the device exists.
+    pub fn attach_queues(&self, name: &str, model: Arc<Mutex<dyn Queues>>) -> Result<()> {
+        self.device_state(name)?;
+        self.queues.write().unwrap().insert(name.to_string(), model);
+        Ok(())
+    }
+
+    /// Returns the packet captures of a device.
+    fn capture(&self, name: &str) -> Result<Arc<Mutex<dyn Capture>>> {
+        self.device_state(name)?;
+        self.captures
+            .read()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Error::CaptureNotSupported(name.to_string()))
+    }
+
+    /// Moves a device from a state to another, through its pausable model.
+    fn transition<F>(&self, name: &str, from: DeviceState, to: DeviceState, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut dyn Pause) -> Result<()>,
+    {
+        let state = self.device_state(name)?;
+        if state != from {
+            return Err(Error::InvalidDeviceState(name.to_string(), state.name()));
+        }
+        let model = self
+            .pausables
+            .read()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Error::PauseNotSupported(name.to_string()))?;
+        f(&mut *model.lock().unwrap()).with_context(&self.context(name))?;
+        self.set_device_state(name, to)
+    }
+
+    /// Returns the identity of a device, to tag the errors of its model with.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    ///
+    /// # Returns
+    ///
+    /// * `ErrorContext` - The frontend, guest and name of the device.
+    fn context(&self, name: &str) -> ErrorContext {
+        let devices = self.devices.read().unwrap();
+        match devices.iter().find(|info| info.name == name) {
+            Some(info) => ErrorContext::new(info.frontend_id, info.guest_id, name),
+            None => ErrorContext {
+                device: name.to_string(),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Returns the interrupt coalescer of a device (to be wired to its notifications).
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Arc<IrqCoalescer>>` - The coalescer, or `DeviceNotFound`.
+    pub fn coalescer(&self, name: &str) -> Result<Arc<IrqCoalescer>> {
+        self.coalescers
+            .read()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or(Error::DeviceNotFound)
+    }
+
+    /// Returns the MMIO access trace filter of a device.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Arc<AccessFilter>>` - The filter, or `DeviceNotFound`.
+    pub fn access_filter(&self, name: &str) -> Result<Arc<AccessFilter>> {
+        self.access_filters
+            .read()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or(Error::DeviceNotFound)
+    }
+
+    /// Returns the fault injector of a device (to be wired to its requests).
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Arc<FaultInjector>>` - The injector, or `DeviceNotFound`.
+    #[cfg(feature = "fault-injection")]
+    pub fn fault_injector(&self, name: &str) -> Result<Arc<FaultInjector>> {
+        self.faults
+            .read()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or(Error::DeviceNotFound)
+    }
+
+    /// Returns the counters of a device.
+    fn stats(&self, name: &str) -> Result<Arc<DeviceStats>> {
+        self.stats
+            .read()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or(Error::DeviceNotFound)
+    }
+}
+
+impl Management for DeviceRegistry {
+    fn devices(&self) -> Vec<DeviceInfo> {
+        self.devices.read().unwrap().clone()
+    }
+
+    fn device_stats(&self, name: &str) -> Result<DeviceStatsSnapshot> {
+        Ok(self.stats(name)?.snapshot())
+    }
+
+    fn device_resources(&self, name: &str) -> Result<ResourceSnapshot> {
+        self.resources
+            .read()
+            .unwrap()
+            .get(name)
+            .map(|resources| resources.snapshot())
+            .ok_or(Error::DeviceNotFound)
+    }
+
+    fn reset_device_stats(&self, name: &str) -> Result<()> {
+        self.stats(name)?.reset();
+        Ok(())
+    }
+
+    fn irq_stats(&self) -> Vec<IrqStatsSnapshot> {
+        let devices = self.devices.read().unwrap();
+        irq_stats(devices.iter().map(|device| {
+            let stats = self.stats(&device.name).ok().map(|stats| stats.snapshot());
+            (device.irq.raw(), device.name.as_str(), stats)
+        }))
+    }
+
+    fn device_state(&self, name: &str) -> Result<DeviceState> {
+        self.states
+            .read()
+            .unwrap()
+            .get(name)
+            .copied()
+            .ok_or(Error::DeviceNotFound)
+    }
+
+    fn set_device_plugged(&self, name: &str, plugged: bool) -> Result<()> {
+        self.device_state(name)?;
+        match self.hotplug.read().unwrap().as_ref() {
+            Some(handler) => handler(name, plugged),
+            None => Err(Error::HotplugNotSupported),
+        }
+    }
+
+    fn pause_device(&self, name: &str, mode: PauseMode) -> Result<()> {
+        self.transition(name, DeviceState::Running, DeviceState::Paused, |model| {
+            model.pause(mode)
+        })
+    }
+
+    fn resume_device(&self, name: &str) -> Result<()> {
+        self.transition(name, DeviceState::Paused, DeviceState::Running, |model| {
+            model.resume()
+        })
+    }
+
+    fn resize_device(&self, name: &str, size: Option<u64>) -> Result<u64> {
+        self.device_state(name)?;
+        let model = self
+            .resizables
+            .read()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Error::ResizeNotSupported(name.to_string()))?;
+        let capacity = model
+            .lock()
+            .unwrap()
+            .resize(size)
+            .with_context(&self.context(name))?;
+        Ok(capacity)
+    }
+
+    fn start_capture(&self, name: &str, options: &CaptureOptions) -> Result<()> {
+        self.capture(name)?
+            .lock()
+            .unwrap()
+            .start(options)
+            .with_context(&self.context(name))
+    }
+
+    fn stop_capture(&self, name: &str) -> Result<CaptureStats> {
+        self.capture(name)?
+            .lock()
+            .unwrap()
+            .stop()
+            .with_context(&self.context(name))
+    }
+
+    fn console_log(&self, name: &str) -> Result<ConsoleOutput> {
+        self.device_state(name)?;
+        let model = self
+            .console_logs
+            .read()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Error::ConsoleLogNotSupported(name.to_string()))?;
+        let output = model.lock().unwrap().output();
+        Ok(output)
+    }
+
+    fn queue_dump(&self, name: &str, queue: usize) -> Result<QueueDump> {
+        self.device_state(name)?;
+        let model = self
+            .queues
+            .read()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Error::QueueDumpNotSupported(name.to_string()))?;
+        let dump = model.lock().unwrap().dump_queue(queue);
+        dump.ok_or_else(|| Error::QueueNotFound(name.to_string(), queue))
+    }
+
+    fn migrate_backend(&self, name: &str, socket: &str) -> Result<()> {
+        let state = self.device_state(name)?;
+        if state != DeviceState::Running {
+            return Err(Error::InvalidDeviceState(name.to_string(), state.name()));
+        }
+        let model = self
+            .reconnectables
+            .read()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or_else(|| Error::MigrationNotSupported(name.to_string()))?;
+        let pausable = self.pausables.read().unwrap().get(name).cloned();
+        let snapshot = self.snapshots.read().unwrap().get(name).cloned();
+        let context = self.context(name);
+
+        // Drain the device, and save its state once nothing is in flight
+        if let Some(pausable) = &pausable {
+            pausable
+                .lock()
+                .unwrap()
+                .pause(PauseMode::Stall)
+                .with_context(&context)?;
+        }
+        let saved = match snapshot.as_ref().map(|model| model.lock().unwrap().save()) {
+            Some(Ok(state)) => Some(state),
+            Some(Err(e)) => {
+                if let Some(pausable) = &pausable {
+                    pausable.lock().unwrap().resume().with_context(&context)?;
+                }
+                return Err(e.with_context(&context));
+            }
+            None => None,
+        };
+
+        self.set_device_state(name, DeviceState::Starting)?;
+        let moved = model
+            .lock()
+            .unwrap()
+            .reconnect(Path::new(socket))
+            .and_then(|()| match (&snapshot, &saved) {
+                (Some(model), Some(state)) => model.lock().unwrap().restore(state),
+                _ => Ok(()),
+            })
+            .and_then(|()| match &pausable {
+                Some(pausable) => pausable.lock().unwrap().resume(),
+                None => Ok(()),
+            });
+        match moved {
+            Ok(()) => self.set_device_state(name, DeviceState::Running),
+            Err(e) => {
+                self.set_device_state(name, DeviceState::Failed)?;
+                Err(e.with_context(&context))
+            }
+        }
+    }
+
+    fn device_coalescing(&self, name: &str) -> Result<Option<ConfigCoalesce>> {
+        Ok(self.coalescer(name)?.params())
+    }
+
+    fn set_device_coalescing(&self, name: &str, params: Option<ConfigCoalesce>) -> Result<()> {
+        self.coalescer(name)?.set_params(params);
+        Ok(())
+    }
+
+    fn device_access_trace(&self, name: &str) -> Result<Option<Vec<ConfigRegRange>>> {
+        Ok(self.access_filter(name)?.ranges())
+    }
+
+    fn set_device_access_trace(
+        &self,
+        name: &str,
+        ranges: Option<Vec<ConfigRegRange>>,
+    ) -> Result<()> {
+        self.access_filter(name)?.set_ranges(ranges);
+        Ok(())
+    }
+
+    fn units(&self) -> Vec<UnitStatus> {
+        self.units.read().unwrap().clone()
+    }
+
+    fn snapshot(&self, dir: &str) -> Result<()> {
+        let snapshots = self.snapshots.read().unwrap();
+        let mut devices = Vec::new();
+        for info in self.devices.read().unwrap().iter() {
+            match snapshots.get(&info.name) {
+                Some(model) => devices.push(SnapshotDevice {
+                    info: info.clone(),
+                    state: model
+                        .lock()
+                        .unwrap()
+                        .save()
+                        .with_context(&ErrorContext::new(
+                            info.frontend_id,
+                            info.guest_id,
+                            &info.name,
+                        ))?,
+                }),
+                // Unplugged devices have no state to keep
+                None if self.device_state(&info.name)? == DeviceState::Unplugged => continue,
+                None => return Err(Error::SnapshotNotSupported(info.name.clone())),
+            }
+        }
+        FrontendSnapshot::new(devices).write(Path::new(dir))
+    }
+
+    fn restore(&self, dir: &str) -> Result<()> {
+        let snapshot = FrontendSnapshot::read(Path::new(dir))?;
+        let snapshots = self.snapshots.read().unwrap();
+        let devices = self.devices.read().unwrap();
+        let mut models = Vec::new();
+        for device in &snapshot.devices {
+            let saved = &device.info;
+            // Devices are matched by UUID, so renamed or renumbered ones keep their state
+            let info = devices.iter().find(|info| match saved.uuid.is_nil() {
+                true => info.name == saved.name,
+                false => info.uuid == saved.uuid,
+            });
+            let info = match info {
+                Some(info)
+                    if (&info.device_type, info.irq, info.addr)
+                        == (&saved.device_type, saved.irq, saved.addr) =>
+                {
+                    info
+                }
+                Some(_) => {
+                    return Err(Error::InvalidSnapshot(format!(
+                        "device {} was reconfigured",
+                        saved.name
+                    )))
+                }
+                None => {
+                    return Err(Error::InvalidSnapshot(format!(
+                        "device {} does not exist",
+                        saved.name
+                    )))
+                }
+            };
+            let model = snapshots
+                .get(&info.name)
+                .ok_or_else(|| Error::SnapshotNotSupported(info.name.clone()))?;
+            let context = ErrorContext::new(info.frontend_id, info.guest_id, &info.name);
+            models.push((model, &device.state, context));
+        }
+        for (model, state, context) in models {
+            model
+                .lock()
+                .unwrap()
+                .restore(state)
+                .with_context(&context)?;
+        }
+        Ok(())
+    }
+
+    fn flush_logs(&self) -> Result<()> {
+        self.logs
+            .read()
+            .unwrap()
+            .iter()
+            .try_for_each(|log| log.flush())
+    }
+
+    fn rotate_logs(&self) -> Result<()> {
+        self.logs
+            .read()
+            .unwrap()
+            .iter()
+            .try_for_each(|log| log.rotate())
+    }
+
+    fn subscribe(&self) -> Receiver<(String, DeviceState)> {
+        let (sender, receiver) = mpsc::channel();
+        self.subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    fn subscribe_events(&self) -> Receiver<DeviceEvent> {
+        let (sender, receiver) = mpsc::channel();
+        self.event_subscribers.lock().unwrap().push(sender);
+        receiver
+    }
+
+    fn frontends(&self) -> Vec<FrontendInfo> {
+        self.frontends.read().unwrap().clone()
+    }
+
+    fn set_frontend_enabled(&self, name: &str, enabled: bool) -> Result<()> {
+        let mut frontends = self.frontends.write().unwrap();
+        let frontend = frontends
+            .iter_mut()
+            .find(|frontend| frontend.name == name)
+            .ok_or_else(|| Error::FrontendNotFound(name.to_string()))?;
+        if frontend.enabled == enabled {
+            return Ok(());
+        }
+        let mut stalled = self.stalled.lock().unwrap();
+        if enabled {
+            // Only the devices stalled by the frontend are resumed
+            for device in stalled.remove(&frontend.id).unwrap_or_default() {
+                match self.resume_device(&device) {
+                    Ok(()) | Err(Error::DeviceNotFound) => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        } else {
+            let running = self
+                .devices()
+                .into_iter()
+                .filter(|device| device.frontend_id == frontend.id)
+                .filter(|device| {
+                    matches!(self.device_state(&device.name), Ok(DeviceState::Running))
+                })
+                .map(|device| device.name)
+                .collect::<Vec<_>>();
+            let mut paused: Vec<String> = Vec::new();
+            for device in running {
+                if let Err(e) = self.pause_device(&device, PauseMode::Stall) {
+                    // Leave the frontend as it was
+                    for device in paused {
+                        let _ = self.resume_device(&device);
+                    }
+                    return Err(e);
+                }
+                paused.push(device);
+            }
+            stalled.insert(frontend.id, paused);
+        }
+        frontend.enabled = enabled;
+        Ok(())
+    }
+
+    #[cfg(feature = "fault-injection")]
+    fn device_faults(&self, name: &str) -> Result<FaultPlan> {
+        Ok(self.fault_injector(name)?.plan())
+    }
+
+    #[cfg(feature = "fault-injection")]
+    fn inject_fault(&self, name: &str, fault: Fault) -> Result<()> {
+        self.fault_injector(name)?.apply(fault);
+        if fault == Fault::Disconnect {
+            self.set_device_state(name, DeviceState::Failed)?;
+            self.publish_event(DeviceEvent::BackendDisconnected(name.to_string()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::ConsoleRing;
+    use crate::defines::BAO_QUEUE_HEADS;
+    use crate::snapshot::{DeviceSnapshot, TransportState};
+    use crate::stats::inc;
+    use crate::types::{ConfigDevice, ConfigFrontend, ConfigGuest};
+    use crate::vhost_backend::{BackendState, VringState};
+
+    /// Builds a configuration with a single device.
+    fn config() -> ConfigFrontends {
+        ConfigFrontends {
+            frontends: vec![ConfigFrontend {
+                name: "frontend0".to_string(),
+                id: VmId(0),
+                control: None,
+                guests: vec![ConfigGuest {
+                    name: "guest0".to_string(),
+                    id: VmId(1),
+                    ram_addr: GuestAddress(0x50000000),
+                    ram_size: 0x1000000,
+                    shmem_path: "/dev/baoipc0".to_string(),
+                    socket_path: "/tmp/".to_string(),
+                    devices: vec![ConfigDevice {
+                        name: "rng0".to_string(),
+                        device_type: "rng".to_string(),
+                        irq: IrqLine(47),
+                        addr: GuestAddress(0xa003e00),
+                        ..Default::default()
+                    }],
+                    sched: None,
+                    watch_dir: None,
+                    dt_overlay: None,
+                    prefault: false,
+                    thp: Default::default(),
+                    dtb: None,
+                    labels: Default::default(),
+                }],
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_device_registry() {
+        let registry = DeviceRegistry::new(&config());
+        assert_eq!(registry.devices()[0].name, "rng0");
+        assert_eq!(registry.devices()[0].guest_id, VmId(1));
+        assert!(matches!(
+            registry.device_stats("rng0"),
+            Err(Error::DeviceNotFound)
+        ));
+
+        let stats = Arc::new(DeviceStats::new("rng0", 1));
+        registry.attach_stats(stats.clone());
+        inc(&stats.mmio_reads);
+        assert_eq!(registry.device_stats("rng0").unwrap().mmio_reads, 1);
+        registry.reset_device_stats("rng0").unwrap();
+        assert_eq!(registry.device_stats("rng0").unwrap().mmio_reads, 0);
+        stats.record_interrupt();
+        assert_eq!(registry.irq_stats()[0].injections, 1);
+
+        assert!(matches!(
+            registry.device_resources("rng0"),
+            Err(Error::DeviceNotFound)
+        ));
+        let resources = Arc::new(DeviceResources::new("rng0"));
+        resources.track_mapping(0x1000);
+        registry.attach_resources(resources);
+        assert_eq!(
+            registry.device_resources("rng0").unwrap().mapped_bytes,
+            0x1000
+        );
+    }
+
+    #[test]
+    fn test_device_state_and_hotplug() {
+        let registry = DeviceRegistry::new(&config());
+        let events = registry.subscribe();
+        let device_events = registry.subscribe_events();
+        assert_eq!(
+            registry.device_state("rng0").unwrap(),
+            DeviceState::Unplugged
+        );
+
+        registry
+            .set_device_state("rng0", DeviceState::Running)
+            .unwrap();
+        registry
+            .set_device_state("rng0", DeviceState::Running)
+            .unwrap();
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            vec![("rng0".to_string(), DeviceState::Running)]
+        );
+        registry.publish_event(DeviceEvent::GuestReset("rng0".to_string()));
+        assert_eq!(
+            device_events.try_iter().collect::<Vec<_>>(),
+            vec![
+                DeviceEvent::StateChanged("rng0".to_string(), DeviceState::Running),
+                DeviceEvent::DeviceReady("rng0".to_string()),
+                DeviceEvent::GuestReset("rng0".to_string()),
+            ]
+        );
+
+        assert!(matches!(
+            registry.set_device_plugged("rng0", false),
+            Err(Error::HotplugNotSupported)
+        ));
+        registry.set_hotplug_handler(Box::new(|name, plugged| {
+            assert_eq!((name, plugged), ("rng0", false));
+            Ok(())
+        }));
+        registry.set_device_plugged("rng0", false).unwrap();
+        assert!(matches!(
+            registry.set_device_plugged("rng1", true),
+            Err(Error::DeviceNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_add_and_remove_devices() {
+        let registry = DeviceRegistry::new(&config());
+        let device = ConfigDevice {
+            name: "i2c0".to_string(),
+            device_type: "i2c".to_string(),
+            ..Default::default()
+        };
+        registry.add_device(VmId(0), VmId(1), &device).unwrap();
+        assert!(matches!(
+            registry.add_device(VmId(0), VmId(1), &device),
+            Err(Error::DeviceExists(_))
+        ));
+        assert_eq!(registry.devices()[1].name, "i2c0");
+        assert_eq!(
+            registry.device_state("i2c0").unwrap(),
+            DeviceState::Unplugged
+        );
+        registry.coalescer("i2c0").unwrap();
+
+        registry.remove_device("i2c0").unwrap();
+        assert_eq!(registry.devices().len(), 1);
+        assert!(matches!(
+            registry.coalescer("i2c0"),
+            Err(Error::DeviceNotFound)
+        ));
+        assert!(matches!(
+            registry.remove_device("i2c0"),
+            Err(Error::DeviceNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_device_coalescing() {
+        let registry = DeviceRegistry::new(&config());
+        let coalescer = registry.coalescer("rng0").unwrap();
+        assert_eq!(registry.device_coalescing("rng0").unwrap(), None);
+
+        let params = ConfigCoalesce {
+            max_completions: 8,
+            max_delay_us: 50,
+        };
+        registry
+            .set_device_coalescing("rng0", Some(params))
+            .unwrap();
+        assert_eq!(coalescer.params(), Some(params));
+        assert_eq!(registry.device_coalescing("rng0").unwrap(), Some(params));
+        assert!(matches!(
+            registry.set_device_coalescing("rng1", None),
+            Err(Error::DeviceNotFound)
+        ));
+    }
+
+    /// Device model holding its state as is.
+    struct Model(DeviceSnapshot);
+
+    impl Snapshot for Model {
+        fn save(&self) -> Result<DeviceSnapshot> {
+            Ok(self.0.clone())
+        }
+
+        fn restore(&mut self, state: &DeviceSnapshot) -> Result<()> {
+            self.0 = state.clone();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_snapshot_and_restore() {
+        let dir = std::env::temp_dir().join(format!("bao-registry-{}", std::process::id()));
+        let dir = dir.to_str().unwrap();
+        let registry = DeviceRegistry::new(&config());
+        registry
+            .set_device_state("rng0", DeviceState::Running)
+            .unwrap();
+        assert!(matches!(
+            registry.snapshot(dir),
+            Err(Error::SnapshotNotSupported(_))
+        ));
+
+        let state = DeviceSnapshot {
+            transport: TransportState {
+                status: 0xf,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let model = Arc::new(Mutex::new(Model(state.clone())));
+        registry.attach_snapshot("rng0", model).unwrap();
+        registry.snapshot(dir).unwrap();
+
+        // The upgraded frontend creates the same device and restores its state
+        let registry = DeviceRegistry::new(&config());
+        let model = Arc::new(Mutex::new(Model(DeviceSnapshot::default())));
+        registry.attach_snapshot("rng0", model.clone()).unwrap();
+        registry.restore(dir).unwrap();
+        assert_eq!(model.lock().unwrap().0, state);
+
+        // Renumbered devices are matched by UUID, as are renamed ones pinning it
+        let mut renamed = config();
+        let device = &mut renamed.frontends[0].guests[0].devices[0];
+        device.id = DeviceId(7);
+        device.uuid = Some(DeviceUuid::derive(VmId(1), "rng0"));
+        device.name = "entropy0".to_string();
+        let registry = DeviceRegistry::new(&renamed);
+        let model = Arc::new(Mutex::new(Model(DeviceSnapshot::default())));
+        registry.attach_snapshot("entropy0", model.clone()).unwrap();
+        registry.restore(dir).unwrap();
+        assert_eq!(model.lock().unwrap().0, state);
+
+        // Devices configured differently are not restored
+        let mut config = config();
+        config.frontends[0].guests[0].devices[0].irq = IrqLine(48);
+        let registry = DeviceRegistry::new(&config);
+        let model = Arc::new(Mutex::new(Model(DeviceSnapshot::default())));
+        registry.attach_snapshot("rng0", model.clone()).unwrap();
+        assert!(matches!(
+            registry.restore(dir),
+            Err(Error::InvalidSnapshot(_))
+        ));
+        assert_eq!(model.lock().unwrap().0, DeviceSnapshot::default());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// Device model recording how it is paused.
+    #[derive(Default)]
+    struct Pausable(Option<PauseMode>);
+
+    impl Pause for Pausable {
+        fn pause(&mut self, mode: PauseMode) -> Result<()> {
+            self.0 = Some(mode);
+            Ok(())
+        }
+
+        fn resume(&mut self) -> Result<()> {
+            self.0 = None;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_pause_and_resume() {
+        let registry = DeviceRegistry::new(&config());
+        let events = registry.subscribe();
+        let model = Arc::new(Mutex::new(Pausable::default()));
+        registry.attach_pause("rng0", model.clone()).unwrap();
+
+        // Only running devices are paused, and only paused ones resumed
+        assert!(matches!(
+            registry.pause_device("rng0", PauseMode::Stall),
+            Err(Error::InvalidDeviceState(_, "unplugged"))
+        ));
+        registry
+            .set_device_state("rng0", DeviceState::Running)
+            .unwrap();
+        assert!(matches!(
+            registry.resume_device("rng0"),
+            Err(Error::InvalidDeviceState(_, "running"))
+        ));
+        registry
+            .pause_device("rng0", PauseMode::NeedsReset)
+            .unwrap();
+        assert_eq!(model.lock().unwrap().0, Some(PauseMode::NeedsReset));
+        assert_eq!(registry.device_state("rng0").unwrap(), DeviceState::Paused);
+        registry.resume_device("rng0").unwrap();
+        assert_eq!(model.lock().unwrap().0, None);
+        let states = events
+            .try_iter()
+            .map(|(_, state)| state)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            states,
+            [
+                DeviceState::Running,
+                DeviceState::Paused,
+                DeviceState::Running
+            ]
+        );
+
+        // Devices without a pausable model cannot be paused
+        let registry = DeviceRegistry::new(&config());
+        registry
+            .set_device_state("rng0", DeviceState::Running)
+            .unwrap();
+        assert!(matches!(
+            registry.pause_device("rng0", PauseMode::Stall),
+            Err(Error::PauseNotSupported(_))
+        ));
+        assert!(matches!(
+            registry.pause_device("rng1", PauseMode::Stall),
+            Err(Error::DeviceNotFound)
+        ));
+    }
+
+    /// Device model recording how its backend is moved.
+    #[derive(Default)]
+    struct Migratable {
+        steps: Vec<String>,
+        state: u32,
+    }
+
+    /// Shares a migratable model as its pausable, snapshottable and reconnectable parts.
+    struct Shared(Arc<Mutex<Migratable>>);
+
+    impl Pause for Shared {
+        fn pause(&mut self, mode: PauseMode) -> Result<()> {
+            self.0.lock().unwrap().steps.push(format!("pause {}", mode));
+            Ok(())
+        }
+
+        fn resume(&mut self) -> Result<()> {
+            self.0.lock().unwrap().steps.push("resume".to_string());
+            Ok(())
+        }
+    }
+
+    impl Snapshot for Shared {
+        fn save(&self) -> Result<DeviceSnapshot> {
+            let mut model = self.0.lock().unwrap();
+            model.steps.push("save".to_string());
+            Ok(DeviceSnapshot {
+                transport: TransportState {
+                    status: model.state,
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+        }
+
+        fn restore(&mut self, state: &DeviceSnapshot) -> Result<()> {
+            let mut model = self.0.lock().unwrap();
+            model.steps.push("restore".to_string());
+            model.state = state.transport.status;
+            Ok(())
+        }
+    }
+
+    impl Reconnect for Shared {
+        fn reconnect(&mut self, socket: &Path) -> Result<()> {
+            let mut model = self.0.lock().unwrap();
+            model.steps.push(format!("reconnect {}", socket.display()));
+            // The new backend starts from scratch, until the state is restored
+            model.state = 0;
+            match socket.to_str() {
+                Some("/missing.sock") => Err(Error::BackendConnectTimedOut("rng0".to_string())),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_migrate_backend() {
+        let registry = DeviceRegistry::new(&config());
+        let model = Arc::new(Mutex::new(Migratable {
+            state: 0xf,
+            ..Default::default()
+        }));
+        registry
+            .attach_reconnect("rng0", Arc::new(Mutex::new(Shared(model.clone()))))
+            .unwrap();
+
+        // Only running devices are moved
+        assert!(matches!(
+            registry.migrate_backend("rng0", "/run/rng-v2.sock"),
+            Err(Error::InvalidDeviceState(_, "unplugged"))
+        ));
+        registry
+            .set_device_state("rng0", DeviceState::Running)
+            .unwrap();
+        let events = registry.subscribe();
+
+        // Without pausable or snapshottable parts, the connection alone moves
+        registry
+            .migrate_backend("rng0", "/run/rng-v2.sock")
+            .unwrap();
+        assert_eq!(
+            std::mem::take(&mut model.lock().unwrap().steps),
+            ["reconnect /run/rng-v2.sock"]
+        );
+        registry
+            .attach_pause("rng0", Arc::new(Mutex::new(Shared(model.clone()))))
+            .unwrap();
+        registry
+            .attach_snapshot("rng0", Arc::new(Mutex::new(Shared(model.clone()))))
+            .unwrap();
+        model.lock().unwrap().state = 0xf;
+        registry
+            .migrate_backend("rng0", "/run/rng-v3.sock")
+            .unwrap();
+        let mut model_state = model.lock().unwrap();
+        assert_eq!(
+            std::mem::take(&mut model_state.steps),
+            [
+                "pause stall",
+                "save",
+                "reconnect /run/rng-v3.sock",
+                "restore",
+                "resume"
+            ]
+        );
+        assert_eq!(model_state.state, 0xf);
+        drop(model_state);
+        let states = events
+            .try_iter()
+            .map(|(_, state)| state)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            states,
+            [
+                DeviceState::Starting,
+                DeviceState::Running,
+                DeviceState::Starting,
+                DeviceState::Running
+            ]
+        );
+
+        // A device that fails to move is left failed, and the error names it
+        let err = registry
+            .migrate_backend("rng0", "/missing.sock")
+            .unwrap_err();
+        assert_eq!(err.context().unwrap().device, "rng0");
+        assert!(matches!(
+            err,
+            Error::Device { source, .. } if matches!(*source, Error::BackendConnectTimedOut(_))
+        ));
+        assert_eq!(registry.device_state("rng0").unwrap(), DeviceState::Failed);
+
+        let registry = DeviceRegistry::new(&config());
+        registry
+            .set_device_state("rng0", DeviceState::Running)
+            .unwrap();
+        assert!(matches!(
+            registry.migrate_backend("rng0", "/run/rng-v2.sock"),
+            Err(Error::MigrationNotSupported(_))
+        ));
+        assert!(matches!(
+            registry.migrate_backend("rng1", "/run/rng-v2.sock"),
+            Err(Error::DeviceNotFound)
+        ));
+    }
+
+    #[derive(Default)]
+    struct Resizable(u64);
+
+    impl Resize for Resizable {
+        fn resize(&mut self, size: Option<u64>) -> Result<u64> {
+            self.0 = size.unwrap_or(self.0);
+            Ok(self.0)
+        }
+    }
+
+    #[test]
+    fn test_resize() {
+        let registry = DeviceRegistry::new(&config());
+        assert!(matches!(
+            registry.resize_device("rng0", Some(4096)),
+            Err(Error::ResizeNotSupported(_))
+        ));
+        let model = Arc::new(Mutex::new(Resizable(512)));
+        registry.attach_resize("rng0", model.clone()).unwrap();
+        assert_eq!(registry.resize_device("rng0", None).unwrap(), 512);
+        assert_eq!(registry.resize_device("rng0", Some(4096)).unwrap(), 4096);
+        assert_eq!(model.lock().unwrap().0, 4096);
+        assert!(matches!(
+            registry.attach_resize("rng1", model),
+            Err(Error::DeviceNotFound)
+        ));
+        assert!(matches!(
+            registry.resize_device("rng1", None),
+            Err(Error::DeviceNotFound)
+        ));
+    }
+
+    #[derive(Default)]
+    struct Capturing(Option<CaptureOptions>);
+
+    impl Capture for Capturing {
+        fn start(&mut self, options: &CaptureOptions) -> Result<()> {
+            self.0 = Some(options.clone());
+            Ok(())
+        }
+
+        fn stop(&mut self) -> Result<CaptureStats> {
+            self.0
+                .take()
+                .map(|_| CaptureStats::default())
+                .ok_or_else(|| Error::CaptureNotRunning("tap0".to_string()))
+        }
+    }
+
+    #[test]
+    fn test_capture() {
+        let registry = DeviceRegistry::new(&config());
+        let options = CaptureOptions::new("/tmp/rng0.pcapng");
+        assert!(matches!(
+            registry.start_capture("rng0", &options),
+            Err(Error::CaptureNotSupported(_))
+        ));
+        let model = Arc::new(Mutex::new(Capturing::default()));
+        registry.attach_capture("rng0", model.clone()).unwrap();
+        registry.start_capture("rng0", &options).unwrap();
+        assert_eq!(model.lock().unwrap().0, Some(options));
+        assert_eq!(
+            registry.stop_capture("rng0").unwrap(),
+            CaptureStats::default()
+        );
+        assert!(matches!(
+            registry.stop_capture("rng0"),
+            Err(Error::Device { source, .. }) if matches!(*source, Error::CaptureNotRunning(_))
+        ));
+        assert!(matches!(
+            registry.attach_capture("rng1", model),
+            Err(Error::DeviceNotFound)
+        ));
+
+        // Captures go away with their device
+        registry.remove_device("rng0").unwrap();
+        assert!(registry.captures.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_console_log() {
+        let registry = DeviceRegistry::new(&config());
+        assert!(matches!(
+            registry.console_log("rng0"),
+            Err(Error::ConsoleLogNotSupported(_))
+        ));
+        let model = Arc::new(Mutex::new(ConsoleRing::new(16)));
+        registry.attach_console_log("rng0", model.clone()).unwrap();
+        model.lock().unwrap().write(b"Kernel panic");
+        assert_eq!(registry.console_log("rng0").unwrap().output, "Kernel panic");
+        assert!(matches!(
+            registry.attach_console_log("rng1", model),
+            Err(Error::DeviceNotFound)
+        ));
+
+        // Logs go away with their device
+        registry.remove_device("rng0").unwrap();
+        assert!(matches!(
+            registry.console_log("rng0"),
+            Err(Error::DeviceNotFound)
+        ));
+        assert!(registry.console_logs.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_queue_dump() {
+        let registry = DeviceRegistry::new(&config());
+        assert!(matches!(
+            registry.queue_dump("rng0", 0),
+            Err(Error::QueueDumpNotSupported(_))
+        ));
+        let model = Arc::new(Mutex::new(BackendState {
+            vrings: vec![VringState {
+                num: 8,
+                avail_idx: 20,
+                last_avail: 18,
+                used_idx: 18,
+                ..Default::default()
+            }],
+            ..Default::default()
+        }));
+        registry.attach_queues("rng0", model.clone()).unwrap();
+        assert!(matches!(
+            registry.queue_dump("rng0", 1),
+            Err(Error::QueueNotFound(_, 1))
+        ));
+
+        // Only the last heads are kept, oldest first
+        {
+            let vring = &mut model.lock().unwrap().vrings[0];
+            for head in 0..BAO_QUEUE_HEADS as u16 + 2 {
+                vring.heads[head as usize % BAO_QUEUE_HEADS] = head;
+                vring.processed += 1;
+            }
+        }
+        let dump = registry.queue_dump("rng0", 0).unwrap();
+        assert_eq!((dump.size, dump.avail_idx, dump.last_avail), (8, 20, 18));
+        assert_eq!(dump.heads.len(), BAO_QUEUE_HEADS);
+        assert_eq!(dump.heads[0], 2);
+        assert_eq!(dump.heads[BAO_QUEUE_HEADS - 1], BAO_QUEUE_HEADS as u16 + 1);
+
+        // Queues go away with their device
+        registry.remove_device("rng0").unwrap();
+        assert!(registry.queues.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_frontend_enable() {
+        let registry = DeviceRegistry::new(&config());
+        let model = Arc::new(Mutex::new(Pausable::default()));
+        registry.attach_pause("rng0", model.clone()).unwrap();
+        registry
+            .set_device_state("rng0", DeviceState::Running)
+            .unwrap();
+        assert_eq!(
+            registry.frontends(),
+            [FrontendInfo {
+                name: "frontend0".to_string(),
+                id: VmId(0),
+                enabled: true
+            }]
+        );
+
+        // Disabling stalls the running devices, enabling resumes them
+        registry.set_frontend_enabled("frontend0", false).unwrap();
+        assert!(!registry.frontends()[0].enabled);
+        assert_eq!(model.lock().unwrap().0, Some(PauseMode::Stall));
+        assert_eq!(registry.device_state("rng0").unwrap(), DeviceState::Paused);
+        registry.set_frontend_enabled("frontend0", false).unwrap();
+        registry.set_frontend_enabled("frontend0", true).unwrap();
+        assert_eq!(model.lock().unwrap().0, None);
+        assert_eq!(registry.device_state("rng0").unwrap(), DeviceState::Running);
+
+        // Devices paused by an operator stay paused
+        registry.pause_device("rng0", PauseMode::Stall).unwrap();
+        registry.set_frontend_enabled("frontend0", false).unwrap();
+        registry.set_frontend_enabled("frontend0", true).unwrap();
+        assert_eq!(registry.device_state("rng0").unwrap(), DeviceState::Paused);
+        assert!(matches!(
+            registry.set_frontend_enabled("frontend1", false),
+            Err(Error::FrontendNotFound(_))
+        ));
+    }
+
+    #[cfg(feature = "fault-injection")]
+    #[test]
+    fn test_inject_fault() {
+        let registry = DeviceRegistry::new(&config());
+        let events = registry.subscribe_events();
+        registry.inject_fault("rng0", Fault::DropEvery(4)).unwrap();
+        assert_eq!(registry.device_faults("rng0").unwrap().drop_every, 4);
+        assert_eq!(
+            registry.fault_injector("rng0").unwrap().plan().drop_every,
+            4
+        );
+
+        // A simulated disconnect looks like a real one to the subscribers
+        registry.inject_fault("rng0", Fault::Disconnect).unwrap();
+        assert_eq!(registry.device_state("rng0").unwrap(), DeviceState::Failed);
+        assert_eq!(
+            events.try_iter().last(),
+            Some(DeviceEvent::BackendDisconnected("rng0".to_string()))
+        );
+        assert!(matches!(
+            registry.inject_fault("rng1", Fault::Clear),
+            Err(Error::DeviceNotFound)
+        ));
+        registry.remove_device("rng0").unwrap();
+        assert!(registry.device_faults("rng0").is_err());
+    }
+}
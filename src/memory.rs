@@ -0,0 +1,161 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao guest memory access.
+//!
+//! Virtio structures are little-endian, whatever the endianness of the host. The
+//! `GuestMemory` object accessors only take types made of explicitly little-endian fields
+//! (`Le16`, `Le32`, `Le64`) and bytes: native integers wider than a byte are not
+//! `Unaligned`, so a virtio field read in host byte order does not compile.
+
+#![allow(dead_code)]
+
+use super::error::Result;
+use zerocopy::{AsBytes, FromBytes, FromZeroes, Unaligned};
+
+pub use zerocopy::byteorder::little_endian::{U16 as Le16, U32 as Le32, U64 as Le64};
+
+/// Struct representing a virtqueue descriptor.
+///
+/// # Attributes
+///
+/// * `addr` - Guest address of the buffer.
+/// * `len` - Length of the buffer.
+/// * `flags` - Descriptor flags (`VRING_DESC_F_*`).
+/// * `next` - Index of the next descriptor of the chain.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromZeroes, FromBytes, AsBytes, Unaligned)]
+pub struct VirtqDesc {
+    pub addr: Le64,
+    pub len: Le32,
+    pub flags: Le16,
+    pub next: Le16,
+}
+
+/// Struct representing a used ring element.
+///
+/// # Attributes
+///
+/// * `id` - Index of the head descriptor of the used chain.
+/// * `len` - Number of bytes written into the chain.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, FromZeroes, FromBytes, AsBytes, Unaligned)]
+pub struct VirtqUsedElem {
+    pub id: Le32,
+    pub len: Le32,
+}
+
+/// Trait implemented by the guest memory views of the device models.
+pub trait GuestMemory {
+    /// Reads bytes.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Guest physical address.
+    /// * `buf` - Buffer to fill.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the range lies within the guest memory.
+    fn read_slice(&self, addr: u64, buf: &mut [u8]) -> Result<()>;
+
+    /// Writes bytes.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Guest physical address.
+    /// * `buf` - The bytes.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the range lies within the guest memory.
+    fn write_slice(&mut self, addr: u64, buf: &[u8]) -> Result<()>;
+
+    /// Reads an object of explicitly little-endian fields.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Guest physical address (no alignment required).
+    ///
+    /// # Returns
+    ///
+    /// * `Result<T>` - The object, or `InvalidGuestAddress`.
+    fn read_obj<T: FromBytes + AsBytes + Unaligned>(&self, addr: u64) -> Result<T> {
+        let mut obj = T::new_zeroed();
+        self.read_slice(addr, obj.as_bytes_mut())?;
+        Ok(obj)
+    }
+
+    /// Writes an object of explicitly little-endian fields.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Guest physical address (no alignment required).
+    /// * `obj` - The object.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the object lies within the guest memory.
+    fn write_obj<T: AsBytes + Unaligned>(&mut self, addr: u64, obj: &T) -> Result<()> {
+        self.write_slice(addr, obj.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Error;
+
+    /// Guest memory starting at address 0x1000.
+    struct Ram(Vec<u8>);
+
+    impl Ram {
+        fn range(&self, addr: u64, len: usize) -> Result<std::ops::Range<usize>> {
+            let start = addr
+                .checked_sub(0x1000)
+                .map(|start| start as usize)
+                .filter(|start| start + len <= self.0.len())
+                .ok_or(Error::InvalidGuestAddress(addr, len))?;
+            Ok(start..start + len)
+        }
+    }
+
+    impl GuestMemory for Ram {
+        fn read_slice(&self, addr: u64, buf: &mut [u8]) -> Result<()> {
+            buf.copy_from_slice(&self.0[self.range(addr, buf.len())?]);
+            Ok(())
+        }
+
+        fn write_slice(&mut self, addr: u64, buf: &[u8]) -> Result<()> {
+            let range = self.range(addr, buf.len())?;
+            self.0[range].copy_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_guest_memory() {
+        let mut ram = Ram(vec![0; 64]);
+        let desc = VirtqDesc {
+            addr: Le64::new(0x1122_3344_5566_7788),
+            len: Le32::new(0x100),
+            flags: Le16::new(3),
+            next: Le16::new(9),
+        };
+        // Unaligned, and laid out in little-endian order on every host
+        ram.write_obj(0x1003, &desc).unwrap();
+        assert_eq!(&ram.0[3..7], &[0x88, 0x77, 0x66, 0x55]);
+        assert_eq!(&ram.0[11..15], &[0x00, 0x01, 0x00, 0x00]);
+        assert_eq!(ram.read_obj::<VirtqDesc>(0x1003).unwrap(), desc);
+        assert_eq!(ram.read_obj::<Le16>(0x1011).unwrap().get(), 9);
+
+        ram.write_obj(0x1020, &Le32::new(0xdead_beef)).unwrap();
+        assert_eq!(&ram.0[0x20..0x24], &[0xef, 0xbe, 0xad, 0xde]);
+        assert!(matches!(
+            ram.read_obj::<VirtqUsedElem>(0x103c),
+            Err(Error::InvalidGuestAddress(0x103c, 8))
+        ));
+    }
+}
@@ -0,0 +1,330 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao OpenTelemetry export.
+//!
+//! Exports the per-request trace events (see the `trace` module) as OTLP spans, and the
+//! number and duration of the request stages (and the heap allocations of the process)
+//! as OTLP metrics, to the collector the backends report to.
+//!
+//! The vhost-user protocol has no room for a trace context (kicks and calls are bare
+//! eventfd signals), so spans are not propagated to the backends: they are correlated in
+//! the collector by time and by the `bao.virtio_id` and `bao.addr` attributes.
+//!
+//! Given the configuration, the spans and metrics of a device are also labelled with its
+//! frontend (`bao.frontend`), to split the telemetry of a process serving several
+//! frontends, its UUID (`bao.device.uuid`), which survives renaming the device, and its
+//! labels (`bao.label.<key>`, see `ConfigDevice::labels`).
+
+#![allow(dead_code)]
+
+use super::alloc;
+use super::error::{Error, Result};
+use super::trace::{TracePhase, TraceSink};
+use super::types::{BaoIoRequest, ConfigFrontends};
+use opentelemetry::metrics::{Counter, Histogram, MeterProvider as _, ObservableCounter};
+use opentelemetry::trace::{Span as _, SpanKind, Tracer as _, TracerProvider as _};
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+use opentelemetry_sdk::trace::{Tracer, TracerProvider};
+use opentelemetry_sdk::{runtime, Resource};
+use std::collections::BTreeMap;
+use std::time::{Instant, SystemTime};
+
+/// Struct representing an OTLP exporter of the request traces and metrics.
+///
+/// # Attributes
+///
+/// * `runtime` - Runtime of the batch exporters (absent if the providers were given).
+/// * `provider` - Span provider.
+/// * `meter_provider` - Metrics provider.
+/// * `tracer` - Tracer of the request stages.
+/// * `stages` - Number of request stages, per stage.
+/// * `durations` - Duration of the request stages (in microseconds), per stage.
+/// * `allocations` - Number of heap allocations of the process (zero unless
+///   `alloc::CountingAllocator` is the global allocator).
+/// * `origin` - Wall clock time matching `start`.
+/// * `start` - Monotonic time the exporter was created.
+/// * `devices` - Attributes of the devices (frontend name, UUID and labels), by device ID.
+pub struct OtlpExporter {
+    runtime: Option<tokio::runtime::Runtime>,
+    provider: TracerProvider,
+    meter_provider: SdkMeterProvider,
+    tracer: Tracer,
+    stages: Counter<u64>,
+    durations: Histogram<f64>,
+    allocations: ObservableCounter<u64>,
+    origin: SystemTime,
+    start: Instant,
+    devices: BTreeMap<u64, Vec<KeyValue>>,
+}
+
+impl OtlpExporter {
+    /// Creates an exporter sending to an OTLP/gRPC collector.
+    ///
+    /// # Arguments
+    ///
+    /// * `endpoint` - The collector endpoint (e.g. `http://localhost:4317`).
+    ///
+    /// # Returns
+    ///
+    /// * `Result<OtlpExporter>` - The exporter.
+    pub fn new(endpoint: &str) -> Result<Self> {
+        // The batch exporters run on their own runtime, off the request path
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(1)
+            .thread_name("bao-otlp")
+            .enable_all()
+            .build()
+            .map_err(|e| Error::OtlpExportFailed(e.to_string()))?;
+        let _guard = runtime.enter();
+
+        let resource = Resource::new([KeyValue::new("service.name", "bao-sys")]);
+        let spans = opentelemetry_otlp::SpanExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .build()
+            .map_err(|e| Error::OtlpExportFailed(e.to_string()))?;
+        let provider = TracerProvider::builder()
+            .with_batch_exporter(spans, runtime::Tokio)
+            .with_resource(resource.clone())
+            .build();
+        let metrics = opentelemetry_otlp::MetricExporter::builder()
+            .with_tonic()
+            .with_endpoint(endpoint)
+            .build()
+            .map_err(|e| Error::OtlpExportFailed(e.to_string()))?;
+        let meter_provider = SdkMeterProvider::builder()
+            .with_reader(PeriodicReader::builder(metrics, runtime::Tokio).build())
+            .with_resource(resource)
+            .build();
+
+        let mut exporter = Self::with_providers(provider, meter_provider);
+        exporter.runtime = Some(runtime);
+        Ok(exporter)
+    }
+
+    /// Creates an exporter from configured providers.
+    ///
+    /// # Arguments
+    ///
+    /// * `provider` - The span provider.
+    /// * `meter_provider` - The metrics provider.
+    ///
+    /// # Returns
+    ///
+    /// * `OtlpExporter` - The exporter.
+    pub fn with_providers(provider: TracerProvider, meter_provider: SdkMeterProvider) -> Self {
+        let meter = meter_provider.meter("bao-sys");
+        OtlpExporter {
+            runtime: None,
+            tracer: provider.tracer("bao-sys"),
+            provider,
+            stages: meter
+                .u64_counter("bao.request.stages")
+                .with_description("Number of request stages")
+                .build(),
+            durations: meter
+                .f64_histogram("bao.request.stage.duration")
+                .with_description("Duration of the request stages")
+                .with_unit("us")
+                .build(),
+            allocations: meter
+                .u64_observable_counter("bao.process.allocations")
+                .with_description("Number of heap allocations")
+                .with_callback(|observer| observer.observe(alloc::allocations(), &[]))
+                .build(),
+            meter_provider,
+            origin: SystemTime::now(),
+            start: Instant::now(),
+            devices: BTreeMap::new(),
+        }
+    }
+
+    /// Labels the spans and metrics of the devices of a configuration with their frontend,
+    /// UUID and labels.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The frontends configuration.
+    ///
+    /// # Returns
+    ///
+    /// * `OtlpExporter` - The exporter.
+    pub fn with_frontends(mut self, config: &ConfigFrontends) -> Self {
+        for frontend in &config.frontends {
+            for guest in &frontend.guests {
+                for device in &guest.devices {
+                    let mut attributes = vec![
+                        KeyValue::new("bao.frontend", frontend.name.clone()),
+                        KeyValue::new("bao.device.uuid", device.uuid(guest.id).to_string()),
+                    ];
+                    attributes.extend(device.labels.iter().map(|(key, value)| {
+                        KeyValue::new(format!("bao.label.{}", key), value.clone())
+                    }));
+                    self.devices.insert(device.id.raw() as u64, attributes);
+                }
+            }
+        }
+        self
+    }
+
+    /// Converts a monotonic time to wall clock time.
+    fn system_time(&self, at: Instant) -> SystemTime {
+        self.origin + at.saturating_duration_since(self.start)
+    }
+
+    /// Exports a stage.
+    fn export(&self, phase: TracePhase, req: Option<&BaoIoRequest>, start: Instant) {
+        let end = Instant::now();
+        let mut attributes = vec![KeyValue::new("bao.stage", phase.name())];
+        let device = req.and_then(|req| self.devices.get(&req.virtio_id));
+        if let Some(device) = device {
+            attributes.extend(device.iter().cloned());
+        }
+        if let Some(req) = req {
+            attributes.extend([
+                KeyValue::new("bao.virtio_id", req.virtio_id as i64),
+                KeyValue::new("bao.reg_off", req.reg_off as i64),
+                KeyValue::new("bao.addr", req.addr as i64),
+                KeyValue::new("bao.op", req.op as i64),
+                KeyValue::new("bao.vcpu_id", req.vcpu_id as i64),
+            ]);
+        }
+
+        // The metrics are only split by stage (and device)
+        let phase_attribute = &attributes[..1 + device.map_or(0, Vec::len)];
+        self.stages.add(1, phase_attribute);
+        self.durations.record(
+            end.saturating_duration_since(start).as_nanos() as f64 / 1000.0,
+            phase_attribute,
+        );
+
+        let mut span = self.tracer.build(
+            self.tracer
+                .span_builder(phase.name())
+                .with_kind(SpanKind::Internal)
+                .with_start_time(self.system_time(start))
+                .with_attributes(attributes),
+        );
+        span.end_with_timestamp(self.system_time(end));
+    }
+}
+
+impl TraceSink for OtlpExporter {
+    fn span(&self, phase: TracePhase, req: &BaoIoRequest, start: Instant) -> Result<()> {
+        self.export(phase, Some(req), start);
+        Ok(())
+    }
+
+    fn instant(&self, phase: TracePhase, req: Option<&BaoIoRequest>) -> Result<()> {
+        self.export(phase, req, Instant::now());
+        Ok(())
+    }
+
+    fn finish(&self) -> Result<()> {
+        // Shutting down flushes the pending spans and metrics
+        let _guard = self.runtime.as_ref().map(|runtime| runtime.enter());
+        self.provider
+            .shutdown()
+            .map_err(|e| Error::OtlpExportFailed(e.to_string()))?;
+        self.meter_provider
+            .shutdown()
+            .map_err(|e| Error::OtlpExportFailed(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::VmId;
+    use crate::uuid::DeviceUuid;
+    use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+
+    /// Span exporter keeping the exported spans.
+    #[derive(Debug, Default, Clone)]
+    struct Collector(Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for Collector {
+        fn export(
+            &mut self,
+            batch: Vec<SpanData>,
+        ) -> Pin<Box<dyn Future<Output = ExportResult> + Send + 'static>> {
+            self.0.lock().unwrap().extend(batch);
+            Box::pin(std::future::ready(Ok(())))
+        }
+    }
+
+    #[test]
+    fn test_otlp_export_spans() {
+        let collector = Collector::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(collector.clone())
+            .build();
+        let config = serde_yaml::from_str(
+            "frontends:
+  - name: frontend0
+    id: 0
+    guests:
+      - name: guest0
+        id: 1
+        ram_addr: 0x50000000
+        ram_size: 0x1000000
+        shmem_path: /dev/baoipc0
+        socket_path: /tmp/
+        devices:
+          - {name: rng0, id: 2, type: rng, irq: 47, addr: 0xa003e00, labels: {rack: a1}}
+",
+        )
+        .unwrap();
+        let exporter = OtlpExporter::with_providers(provider, SdkMeterProvider::default())
+            .with_frontends(&config);
+
+        let req = BaoIoRequest {
+            virtio_id: 2,
+            reg_off: 0x50,
+            addr: 0xa003e50,
+            op: 1,
+            value: 0,
+            access_width: 4,
+            cpu_id: 0,
+            vcpu_id: 1,
+            ret: 0,
+        };
+        let start = Instant::now();
+        exporter.span(TracePhase::Dispatch, &req, start).unwrap();
+        exporter.instant(TracePhase::IrqInject, None).unwrap();
+        exporter.finish().unwrap();
+
+        let spans = collector.0.lock().unwrap();
+        assert_eq!(spans.len(), 2);
+        assert_eq!(spans[0].name, "dispatch");
+        assert!(spans[0].start_time <= spans[0].end_time);
+        assert!(spans[0]
+            .attributes
+            .contains(&KeyValue::new("bao.addr", 0xa003e50i64)));
+        assert_eq!(
+            spans[0].attributes[1],
+            KeyValue::new("bao.frontend", "frontend0")
+        );
+        assert_eq!(
+            spans[0].attributes[2],
+            KeyValue::new(
+                "bao.device.uuid",
+                DeviceUuid::derive(VmId(1), "rng0").to_string()
+            )
+        );
+        assert_eq!(
+            spans[0].attributes[3],
+            KeyValue::new("bao.label.rack", "a1")
+        );
+        assert_eq!(spans[1].name, "irq_inject");
+        assert_eq!(spans[1].attributes.len(), 1);
+    }
+}
@@ -0,0 +1,417 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao device tree overlays.
+//!
+//! A virtio-mmio device hot-plugged into a running Linux guest is only probed once it
+//! shows up in the device tree. For guests with a `dt_overlay`, the frontend generates
+//! the overlay of every hot-plugged device (a flattened device tree blob adding a
+//! `virtio,mmio` node) and applies it through the configfs overlay interface
+//! (`/sys/kernel/config/device-tree/overlays/<name>/dtbo`), then removes it on unplug.
+
+#![allow(dead_code)]
+
+use super::defines::{
+    BAO_DT_ADDRESS_CELLS, BAO_DT_OVERLAY_DIR, BAO_DT_OVERLAY_PREFIX, BAO_DT_SIZE_CELLS,
+    VIRTIO_MMIO_IO_SIZE,
+};
+use super::error::{Error, Result};
+use super::management::DeviceInfo;
+use super::types::{ConfigDtOverlay, InterruptController};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+/// Flattened device tree magic.
+pub(crate) const FDT_MAGIC: u32 = 0xd00d_feed;
+/// Flattened device tree version (and oldest compatible version).
+const FDT_VERSION: u32 = 17;
+const FDT_LAST_COMP_VERSION: u32 = 16;
+/// Size of the flattened device tree header.
+pub(crate) const FDT_HEADER_SIZE: usize = 40;
+/// Structure block tokens.
+pub(crate) const FDT_BEGIN_NODE: u32 = 0x1;
+pub(crate) const FDT_END_NODE: u32 = 0x2;
+pub(crate) const FDT_PROP: u32 = 0x3;
+pub(crate) const FDT_END: u32 = 0x9;
+
+/// GIC shared peripheral interrupt type, and its first interrupt ID.
+pub(crate) const GIC_SPI: u32 = 0;
+pub(crate) const GIC_SPI_BASE: u32 = 32;
+/// Edge-triggered (rising) interrupt flag.
+const IRQ_TYPE_EDGE_RISING: u32 = 1;
+
+/// Struct representing a flattened device tree being written.
+///
+/// # Attributes
+///
+/// * `structure` - Structure block.
+/// * `strings` - Strings block (property names).
+#[derive(Default)]
+pub(crate) struct FdtWriter {
+    structure: Vec<u8>,
+    strings: Vec<u8>,
+}
+
+impl FdtWriter {
+    /// Appends a token to the structure block.
+    fn token(&mut self, token: u32) {
+        self.structure.extend_from_slice(&token.to_be_bytes());
+    }
+
+    /// Pads the structure block to a 4-byte boundary.
+    fn align(&mut self) {
+        self.structure
+            .resize(self.structure.len().next_multiple_of(4), 0);
+    }
+
+    /// Opens a node.
+    pub(crate) fn begin_node(&mut self, name: &str) {
+        self.token(FDT_BEGIN_NODE);
+        self.structure.extend_from_slice(name.as_bytes());
+        self.structure.push(0);
+        self.align();
+    }
+
+    /// Closes the last opened node.
+    pub(crate) fn end_node(&mut self) {
+        self.token(FDT_END_NODE);
+    }
+
+    /// Adds a property to the current node.
+    pub(crate) fn property(&mut self, name: &str, value: &[u8]) {
+        // Property names are shared in the strings block
+        let mut needle = name.as_bytes().to_vec();
+        needle.push(0);
+        let offset = match self
+            .strings
+            .windows(needle.len())
+            .position(|window| window == needle.as_slice())
+        {
+            Some(offset) => offset,
+            None => {
+                self.strings.extend_from_slice(&needle);
+                self.strings.len() - needle.len()
+            }
+        };
+        self.token(FDT_PROP);
+        self.token(value.len() as u32);
+        self.token(offset as u32);
+        self.structure.extend_from_slice(value);
+        self.align();
+    }
+
+    /// Adds a string property to the current node.
+    pub(crate) fn property_string(&mut self, name: &str, value: &str) {
+        let mut bytes = value.as_bytes().to_vec();
+        bytes.push(0);
+        self.property(name, &bytes);
+    }
+
+    /// Adds a cell list property to the current node.
+    pub(crate) fn property_cells(&mut self, name: &str, cells: &[u32]) {
+        let bytes = cells
+            .iter()
+            .flat_map(|cell| cell.to_be_bytes())
+            .collect::<Vec<_>>();
+        self.property(name, &bytes);
+    }
+
+    /// Terminates the tree.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<u8>` - The blob (header, empty memory reservation map, structure and
+    ///   strings blocks).
+    pub(crate) fn finish(mut self) -> Vec<u8> {
+        self.token(FDT_END);
+        // The memory reservation map only holds its (8-byte aligned) terminating entry
+        let off_mem_rsvmap = FDT_HEADER_SIZE.next_multiple_of(8);
+        let off_dt_struct = off_mem_rsvmap + 16;
+        let off_dt_strings = off_dt_struct + self.structure.len();
+        let total_size = off_dt_strings + self.strings.len();
+        let header = [
+            FDT_MAGIC,
+            total_size as u32,
+            off_dt_struct as u32,
+            off_dt_strings as u32,
+            off_mem_rsvmap as u32,
+            FDT_VERSION,
+            FDT_LAST_COMP_VERSION,
+            0,
+            self.strings.len() as u32,
+            self.structure.len() as u32,
+        ];
+        let mut blob = header
+            .iter()
+            .flat_map(|field| field.to_be_bytes())
+            .collect::<Vec<_>>();
+        blob.resize(off_dt_struct, 0);
+        blob.extend_from_slice(&self.structure);
+        blob.extend_from_slice(&self.strings);
+        blob
+    }
+}
+
+/// Splits a value in big-endian cells.
+///
+/// # Arguments
+///
+/// * `value` - The value.
+/// * `cells` - Number of 32-bit cells (1 or 2).
+///
+/// # Returns
+///
+/// * `Option<Vec<u32>>` - The cells, None if the value does not fit.
+fn cells(value: u64, cells: u32) -> Option<Vec<u32>> {
+    match cells {
+        1 => u32::try_from(value).ok().map(|value| vec![value]),
+        2 => Some(vec![(value >> 32) as u32, value as u32]),
+        _ => None,
+    }
+}
+
+/// Generates the overlay adding a device to the device tree.
+///
+/// # Arguments
+///
+/// * `device` - The device.
+/// * `config` - Overlay configuration of the guest.
+///
+/// # Returns
+///
+/// * `Result<Vec<u8>>` - The overlay blob (`dtbo`), `InvalidDtOverlay` if the device
+///   cannot be described with the configured cells or interrupt controller.
+pub fn device_overlay(device: &DeviceInfo, config: &ConfigDtOverlay) -> Result<Vec<u8>> {
+    let invalid = |reason: &str| Error::InvalidDtOverlay(device.name.clone(), reason.to_string());
+    let address_cells = config.address_cells.unwrap_or(BAO_DT_ADDRESS_CELLS);
+    let size_cells = config.size_cells.unwrap_or(BAO_DT_SIZE_CELLS);
+    let mut reg = cells(device.addr.raw(), address_cells)
+        .ok_or_else(|| invalid("the address does not fit the address cells"))?;
+    reg.extend(
+        cells(VIRTIO_MMIO_IO_SIZE, size_cells)
+            .ok_or_else(|| invalid("the size does not fit the size cells"))?,
+    );
+    let irq = device.irq.raw();
+    let interrupts = match config.interrupt_controller {
+        InterruptController::Gic if irq >= GIC_SPI_BASE => {
+            vec![GIC_SPI, irq - GIC_SPI_BASE, IRQ_TYPE_EDGE_RISING]
+        }
+        InterruptController::Gic => return Err(invalid("the interrupt is not a GIC SPI")),
+        InterruptController::Plic => vec![irq],
+    };
+
+    let mut fdt = FdtWriter::default();
+    fdt.begin_node("");
+    fdt.begin_node("fragment@0");
+    fdt.property_string("target-path", config.target_path.as_deref().unwrap_or("/"));
+    fdt.begin_node("__overlay__");
+    fdt.begin_node(&format!("virtio_mmio@{:x}", device.addr.raw()));
+    fdt.property_string("compatible", "virtio,mmio");
+    fdt.property_cells("reg", &reg);
+    fdt.property_cells("interrupts", &interrupts);
+    fdt.end_node();
+    fdt.end_node();
+    fdt.end_node();
+    fdt.end_node();
+    Ok(fdt.finish())
+}
+
+/// Returns the configfs directory of the overlay of a device.
+fn overlay_dir(config: &ConfigDtOverlay, name: &str) -> PathBuf {
+    PathBuf::from(config.configfs_dir.as_deref().unwrap_or(BAO_DT_OVERLAY_DIR))
+        .join(format!("{}{}", BAO_DT_OVERLAY_PREFIX, name))
+}
+
+/// Applies the overlay of a device through configfs.
+///
+/// # Arguments
+///
+/// * `device` - The device.
+/// * `config` - Overlay configuration of the guest.
+///
+/// # Returns
+///
+/// * `Result<()>` - Ok once the kernel applied the overlay, `DtOverlayRejected` if it
+///   did not.
+pub fn apply_overlay(device: &DeviceInfo, config: &ConfigDtOverlay) -> Result<()> {
+    let dtbo = device_overlay(device, config)?;
+    let dir = overlay_dir(config, &device.name);
+    let failed = |e: io::Error| Error::DtOverlayFailed(device.name.clone(), e);
+    // An overlay left behind by a previous run still holds the node
+    remove_overlay(&device.name, config)?;
+    fs::create_dir(&dir).map_err(failed)?;
+    let status = fs::write(dir.join("dtbo"), &dtbo)
+        .and_then(|_| fs::read_to_string(dir.join("status")))
+        .map_err(failed)
+        .inspect_err(|_| {
+            let _ = fs::remove_dir(&dir);
+        })?;
+    if status.trim() != "applied" {
+        let _ = fs::remove_dir(&dir);
+        return Err(Error::DtOverlayRejected(
+            device.name.clone(),
+            status.trim().to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Removes the overlay of a device (if applied).
+///
+/// # Arguments
+///
+/// * `name` - Device name.
+/// * `config` - Overlay configuration of the guest.
+///
+/// # Returns
+///
+/// * `Result<()>` - Ok once the overlay is removed.
+pub fn remove_overlay(name: &str, config: &ConfigDtOverlay) -> Result<()> {
+    match fs::remove_dir(overlay_dir(config, name)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => {
+            Err(Error::DtOverlayFailed(name.to_string(), e))
+        }
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{DeviceId, GuestAddress, IrqLine, VmId};
+
+    fn device() -> DeviceInfo {
+        DeviceInfo {
+            frontend_id: VmId(0),
+            guest_id: VmId(1),
+            name: "rng0".to_string(),
+            device_type: "rng".to_string(),
+            id: DeviceId(0),
+            irq: IrqLine(0x2f),
+            addr: GuestAddress(0xa003e00),
+            uuid: Default::default(),
+            mac: None,
+            labels: Default::default(),
+        }
+    }
+
+    /// Lists the nodes and properties of a blob, as `path` and `path:name=value` lines.
+    fn walk(blob: &[u8]) -> Vec<String> {
+        let word = |offset: usize| u32::from_be_bytes(blob[offset..offset + 4].try_into().unwrap());
+        assert_eq!(word(0), FDT_MAGIC);
+        assert_eq!(word(4) as usize, blob.len());
+        let (mut offset, strings) = (word(8) as usize, word(12) as usize);
+        let c_str = |offset: usize| {
+            let end = blob[offset..].iter().position(|&b| b == 0).unwrap();
+            String::from_utf8(blob[offset..offset + end].to_vec()).unwrap()
+        };
+        let (mut path, mut lines) = (Vec::new(), Vec::new());
+        loop {
+            let token = word(offset);
+            offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = c_str(offset);
+                    offset = (offset + name.len() + 1).next_multiple_of(4);
+                    path.push(name);
+                    lines.push(path.join("/"));
+                }
+                FDT_END_NODE => {
+                    path.pop();
+                }
+                FDT_PROP => {
+                    let (len, name) = (
+                        word(offset) as usize,
+                        c_str(strings + word(offset + 4) as usize),
+                    );
+                    let value = &blob[offset + 8..offset + 8 + len];
+                    let value = match name.as_str() {
+                        "reg" | "interrupts" => format!(
+                            "{:x?}",
+                            value
+                                .chunks(4)
+                                .map(|c| u32::from_be_bytes(c.try_into().unwrap()))
+                                .collect::<Vec<_>>()
+                        ),
+                        _ => c_str(offset + 8),
+                    };
+                    lines.push(format!("{}:{}={}", path.join("/"), name, value));
+                    offset = (offset + 8 + len).next_multiple_of(4);
+                }
+                FDT_END => return lines,
+                token => panic!("unexpected token {}", token),
+            }
+        }
+    }
+
+    #[test]
+    fn test_device_overlay() {
+        let config = ConfigDtOverlay::default();
+        assert_eq!(
+            walk(&device_overlay(&device(), &config).unwrap()),
+            [
+                "",
+                "/fragment@0",
+                "/fragment@0:target-path=/",
+                "/fragment@0/__overlay__",
+                "/fragment@0/__overlay__/virtio_mmio@a003e00",
+                "/fragment@0/__overlay__/virtio_mmio@a003e00:compatible=virtio,mmio",
+                "/fragment@0/__overlay__/virtio_mmio@a003e00:reg=[0, a003e00, 0, 200]",
+                "/fragment@0/__overlay__/virtio_mmio@a003e00:interrupts=[0, f, 1]",
+            ]
+        );
+
+        let config = ConfigDtOverlay {
+            target_path: Some("/soc".to_string()),
+            address_cells: Some(1),
+            size_cells: Some(1),
+            interrupt_controller: InterruptController::Plic,
+            ..Default::default()
+        };
+        let lines = walk(&device_overlay(&device(), &config).unwrap());
+        assert_eq!(lines[2], "/fragment@0:target-path=/soc");
+        assert_eq!(
+            lines[6],
+            "/fragment@0/__overlay__/virtio_mmio@a003e00:reg=[a003e00, 200]"
+        );
+        assert_eq!(
+            lines[7],
+            "/fragment@0/__overlay__/virtio_mmio@a003e00:interrupts=[2f]"
+        );
+
+        let mut low = device();
+        low.irq = IrqLine(16);
+        assert!(matches!(
+            device_overlay(&low, &ConfigDtOverlay::default()),
+            Err(Error::InvalidDtOverlay(..))
+        ));
+    }
+
+    #[test]
+    fn test_apply_overlay() {
+        let dir = std::env::temp_dir().join(format!("bao-overlays-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let config = ConfigDtOverlay {
+            configfs_dir: Some(dir.to_str().unwrap().to_string()),
+            ..Default::default()
+        };
+        // Without the overlay support of configfs, the blob is written but no status is
+        // reported
+        assert!(matches!(
+            apply_overlay(&device(), &config),
+            Err(Error::DtOverlayFailed(..))
+        ));
+        assert_eq!(
+            fs::read(dir.join("bao-rng0/dtbo")).unwrap(),
+            device_overlay(&device(), &config).unwrap()
+        );
+        fs::remove_file(dir.join("bao-rng0/dtbo")).unwrap();
+        remove_overlay("rng0", &config).unwrap();
+        assert!(!dir.join("bao-rng0").exists());
+        remove_overlay("rng0", &config).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
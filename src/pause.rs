@@ -0,0 +1,95 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao device pause and resume.
+//!
+//! A device is paused while the host takes the resource behind its backend offline (e.g.
+//! an SD card being re-flashed, or a CAN bus being reconfigured): its queue notifications
+//! are held back, and served once it is resumed. The guest is either left waiting (its
+//! requests stall) or told that the device needs a reset, so its driver can fail the
+//! pending requests and bring the device up again once it is resumed.
+
+#![allow(dead_code)]
+
+use super::error::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// Enum representing how the guest sees a paused device.
+///
+/// # Variants
+///
+/// * `Stall` - The notifications of the guest are held back until the device is resumed.
+/// * `NeedsReset` - The device additionally sets DEVICE_NEEDS_RESET in its status and
+///   raises a configuration change interrupt.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PauseMode {
+    #[default]
+    Stall,
+    NeedsReset,
+}
+
+impl fmt::Display for PauseMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = match self {
+            PauseMode::Stall => "stall",
+            PauseMode::NeedsReset => "needs-reset",
+        };
+        write!(f, "{}", mode)
+    }
+}
+
+impl FromStr for PauseMode {
+    type Err = Error;
+
+    fn from_str(mode: &str) -> Result<Self> {
+        match mode {
+            "stall" => Ok(PauseMode::Stall),
+            "needs-reset" => Ok(PauseMode::NeedsReset),
+            _ => Err(Error::InvalidPauseMode(mode.to_string())),
+        }
+    }
+}
+
+/// Trait implemented by the device models that can be paused.
+pub trait Pause: Send {
+    /// Pauses the device.
+    ///
+    /// # Arguments
+    ///
+    /// * `mode` - How the guest sees the paused device.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok once the device stopped serving the guest.
+    fn pause(&mut self, mode: PauseMode) -> Result<()>;
+
+    /// Resumes the device, serving the notifications held back while it was paused.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok once the device serves the guest again.
+    fn resume(&mut self) -> Result<()>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pause_mode() {
+        for mode in [PauseMode::Stall, PauseMode::NeedsReset] {
+            assert_eq!(mode.to_string().parse::<PauseMode>().unwrap(), mode);
+        }
+        let mode: PauseMode = serde_yaml::from_str("needs-reset").unwrap();
+        assert_eq!(mode, PauseMode::NeedsReset);
+        assert!(matches!(
+            "reset".parse::<PauseMode>(),
+            Err(Error::InvalidPauseMode(_))
+        ));
+    }
+}
@@ -0,0 +1,222 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao platform descriptions.
+//!
+//! A platform description (`--platform board.yaml`) lists what the board reserves: the
+//! SPI ranges the hypervisor can inject into the guests, the MMIO windows of the physical
+//! devices (interrupt controller, UARTs, ...) and the shared-memory carve-out used by the
+//! frontend. The configuration is checked against it before any device is created, since a
+//! device sitting on a reserved window or interrupt line otherwise only shows up as a
+//! guest hanging at boot.
+
+#![allow(dead_code)]
+
+use super::config::ConfigFrontends;
+use super::defines::VIRTIO_MMIO_IO_SIZE;
+use super::error::{Error, ErrorContext, Result, ResultExt};
+use super::types::{GuestAddress, IrqLine};
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+/// Struct representing a range of interrupt lines.
+///
+/// # Attributes
+///
+/// * `first` - First interrupt line (inclusive).
+/// * `last` - Last interrupt line (inclusive).
+pub struct PlatformIrqRange {
+    pub first: IrqLine,
+    pub last: IrqLine,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize, PartialEq)]
+/// Struct representing a reserved memory window.
+///
+/// # Attributes
+///
+/// * `name` - Window name (reported on collisions).
+/// * `addr` - Start address.
+/// * `size` - Size (in bytes).
+pub struct PlatformRegion {
+    pub name: String,
+    pub addr: GuestAddress,
+    pub size: u64,
+}
+
+impl PlatformRegion {
+    /// Checks if the window overlaps a range.
+    fn overlaps(&self, addr: u64, size: u64) -> bool {
+        addr < self.addr.raw().saturating_add(self.size) && self.addr.raw() < addr + size
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize, PartialEq)]
+/// Struct representing a platform description.
+///
+/// # Attributes
+///
+/// * `name` - Board name.
+/// * `spi_ranges` - Interrupt lines available to the virtio devices (any if empty).
+/// * `reserved` - MMIO windows of the board devices.
+/// * `shmem` - Shared-memory carve-out.
+pub struct Platform {
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub spi_ranges: Vec<PlatformIrqRange>,
+    #[serde(default)]
+    pub reserved: Vec<PlatformRegion>,
+    #[serde(default)]
+    pub shmem: Option<PlatformRegion>,
+}
+
+impl Platform {
+    /// Reads a platform description file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Platform description path.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Platform>` - The platform, or `InvalidPlatform`.
+    pub fn from_file(path: &str) -> Result<Self> {
+        let invalid = |e: String| Error::InvalidPlatform(path.to_string(), e);
+        let yaml = fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+        serde_yaml::from_str(&yaml).map_err(|e| invalid(e.to_string()))
+    }
+
+    /// Checks the devices of a configuration against the platform reservations.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The frontends configuration.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if every device interrupt is an available SPI and no device
+    ///   window overlaps a reserved window, or the first collision (tagged with the
+    ///   identity of the device).
+    pub fn validate(&self, config: &ConfigFrontends) -> Result<()> {
+        for frontend in &config.frontends {
+            for guest in &frontend.guests {
+                for device in &guest.devices {
+                    let context = ErrorContext::new(frontend.id, guest.id, &device.name);
+                    self.validate_device(device.irq, device.addr)
+                        .with_context(&context)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks a device against the platform reservations.
+    ///
+    /// # Arguments
+    ///
+    /// * `irq` - Device interrupt line.
+    /// * `addr` - Device address.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the device does not collide with the platform.
+    pub fn validate_device(&self, irq: IrqLine, addr: GuestAddress) -> Result<()> {
+        if !self.spi_ranges.is_empty()
+            && !self
+                .spi_ranges
+                .iter()
+                .any(|range| range.first <= irq && irq <= range.last)
+        {
+            return Err(Error::PlatformConflict(format!(
+                "IRQ {} is not an available SPI",
+                irq
+            )));
+        }
+        let window = self
+            .reserved
+            .iter()
+            .chain(&self.shmem)
+            .find(|region| region.overlaps(addr.raw(), VIRTIO_MMIO_IO_SIZE));
+        if let Some(region) = window {
+            return Err(Error::PlatformConflict(format!(
+                "window {}+{:#x} overlaps the reserved window {} ({}+{:#x})",
+                addr, VIRTIO_MMIO_IO_SIZE, region.name, region.addr, region.size
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_platform() {
+        let platform: Platform = serde_yaml::from_str(
+            "name: zcu104
+spi_ranges:
+  - {first: 32, last: 63}
+  - {first: 96, last: 127}
+reserved:
+  - {name: gic, addr: 0xf9000000, size: 0x80000}
+  - {name: uart0, addr: 0xff000000, size: 0x1000}
+shmem: {name: shmem, addr: 0x70000000, size: 0x1000000}
+",
+        )
+        .unwrap();
+        let mut config: ConfigFrontends = serde_yaml::from_str(
+            "frontends:
+  - name: frontend0
+    id: 0
+    guests:
+      - name: guest0
+        id: 1
+        ram_addr: 0x50000000
+        ram_size: 0x1000000
+        shmem_path: /dev/baoipc0
+        socket_path: /tmp/
+        devices:
+          - {name: rng0, id: 0, type: rng, irq: 47, addr: 0xa003e00}
+          - {name: i2c0, id: 1, type: i2c, irq: 100, addr: 0xa003c00}
+",
+        )
+        .unwrap();
+        platform.validate(&config).unwrap();
+
+        // Interrupt lines outside the SPI ranges are rejected
+        let devices = &mut config.frontends[0].guests[0].devices;
+        devices[1].irq = IrqLine(64);
+        let err = platform.validate(&config).unwrap_err();
+        assert_eq!(
+            err.report(),
+            "frontend0/guest1/i2c0: Device collides with the platform: IRQ 64 is not an available SPI"
+        );
+        assert_eq!(err.exit_code(), 78);
+
+        // So are windows overlapping a reservation, even partially
+        let devices = &mut config.frontends[0].guests[0].devices;
+        devices[1].irq = IrqLine(127);
+        devices[0].addr = GuestAddress(0xfefffe80);
+        assert!(platform.validate(&config).is_err());
+        assert!(matches!(
+            platform.validate_device(IrqLine(32), GuestAddress(0x70800000)),
+            Err(Error::PlatformConflict(_))
+        ));
+        assert!(platform
+            .validate_device(IrqLine(32), GuestAddress(0xfefffe00))
+            .is_ok());
+
+        // An empty description accepts everything
+        config.frontends[0].guests[0].devices[0].addr = GuestAddress(0x70800000);
+        Platform::default().validate(&config).unwrap();
+        assert!(matches!(
+            Platform::from_file("/nonexistent/board.yaml"),
+            Err(Error::InvalidPlatform(..))
+        ));
+    }
+}
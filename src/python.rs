@@ -0,0 +1,205 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao Python bindings.
+//!
+//! Built as the `bao_sys` extension module (see `pyproject.toml`), mainly to let the
+//! pytest-based test framework build configurations and craft I/O requests.
+
+#![allow(dead_code)]
+
+use super::error::{Error, ErrorClass};
+use super::types::{AbiEncode, BaoIoRequest, ConfigFrontends};
+use pyo3::exceptions::{PyOSError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+use std::fs;
+
+impl From<Error> for PyErr {
+    fn from(e: Error) -> Self {
+        match (&e, e.class()) {
+            (Error::InvalidEncoding(..), _) | (_, ErrorClass::Config | ErrorClass::Guest) => {
+                PyValueError::new_err(e.report())
+            }
+            _ => PyOSError::new_err((e.errno(), e.report())),
+        }
+    }
+}
+
+/// Python wrapper of a Bao I/O request.
+#[pyclass(name = "IoRequest")]
+#[derive(Clone)]
+pub struct PyIoRequest {
+    inner: BaoIoRequest,
+}
+
+#[pymethods]
+impl PyIoRequest {
+    #[new]
+    #[pyo3(signature = (virtio_id=0, reg_off=0, addr=0, op=0, value=0, access_width=4, cpu_id=0, vcpu_id=0, ret=0))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        virtio_id: u64,
+        reg_off: u64,
+        addr: u64,
+        op: u64,
+        value: u64,
+        access_width: u64,
+        cpu_id: u64,
+        vcpu_id: u64,
+        ret: u64,
+    ) -> Self {
+        PyIoRequest {
+            inner: BaoIoRequest {
+                virtio_id,
+                reg_off,
+                addr,
+                op,
+                value,
+                access_width,
+                cpu_id,
+                vcpu_id,
+                ret,
+            },
+        }
+    }
+
+    /// Decodes a request from its compact binary encoding.
+    #[staticmethod]
+    fn decode(bytes: &[u8]) -> PyResult<Self> {
+        Ok(PyIoRequest {
+            inner: BaoIoRequest::decode(bytes)?,
+        })
+    }
+
+    /// Encodes the request (compact binary encoding).
+    fn encode<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        let mut buf = Vec::with_capacity(BaoIoRequest::ENCODED_SIZE);
+        self.inner.encode(&mut buf);
+        PyBytes::new(py, &buf)
+    }
+
+    #[getter]
+    fn virtio_id(&self) -> u64 {
+        self.inner.virtio_id
+    }
+
+    #[getter]
+    fn reg_off(&self) -> u64 {
+        self.inner.reg_off
+    }
+
+    #[getter]
+    fn addr(&self) -> u64 {
+        self.inner.addr
+    }
+
+    #[getter]
+    fn op(&self) -> u64 {
+        self.inner.op
+    }
+
+    #[getter]
+    fn value(&self) -> u64 {
+        self.inner.value
+    }
+
+    #[setter]
+    fn set_value(&mut self, value: u64) {
+        self.inner.value = value;
+    }
+
+    #[getter]
+    fn access_width(&self) -> u64 {
+        self.inner.access_width
+    }
+
+    #[getter]
+    fn cpu_id(&self) -> u64 {
+        self.inner.cpu_id
+    }
+
+    #[getter]
+    fn vcpu_id(&self) -> u64 {
+        self.inner.vcpu_id
+    }
+
+    #[getter]
+    fn ret(&self) -> u64 {
+        self.inner.ret
+    }
+
+    #[setter]
+    fn set_ret(&mut self, ret: u64) {
+        self.inner.ret = ret;
+    }
+
+    fn __eq__(&self, other: &Self) -> bool {
+        self.inner == other.inner
+    }
+
+    fn __repr__(&self) -> String {
+        format!("{:?}", self.inner)
+    }
+}
+
+/// Python wrapper of a Bao frontends configuration.
+#[pyclass(name = "Config")]
+pub struct PyConfig {
+    inner: ConfigFrontends,
+}
+
+#[pymethods]
+impl PyConfig {
+    /// Parses a configuration from a YAML string.
+    #[staticmethod]
+    fn from_yaml(yaml: &str) -> PyResult<Self> {
+        let inner = serde_yaml::from_str(yaml).map_err(|e| PyValueError::new_err(e.to_string()))?;
+        Ok(PyConfig { inner })
+    }
+
+    /// Loads a configuration from a YAML file.
+    #[staticmethod]
+    fn load(path: &str) -> PyResult<Self> {
+        let yaml = fs::read_to_string(path).map_err(Error::from)?;
+        Self::from_yaml(&yaml)
+    }
+
+    /// Serializes the configuration to YAML.
+    fn to_yaml(&self) -> PyResult<String> {
+        serde_yaml::to_string(&self.inner).map_err(|e| PyValueError::new_err(e.to_string()))
+    }
+
+    /// Lists the devices as `(frontend_id, guest_id, name, type, device_id, irq, addr)`.
+    fn devices(&self) -> Vec<(u32, u32, String, String, u32, u32, u64)> {
+        let mut devices = Vec::new();
+        for frontend in &self.inner.frontends {
+            for guest in &frontend.guests {
+                for device in &guest.devices {
+                    devices.push((
+                        frontend.id.raw(),
+                        guest.id.raw(),
+                        device.name.clone(),
+                        device.device_type.clone(),
+                        device.id.raw(),
+                        device.irq.raw(),
+                        device.addr.raw(),
+                    ));
+                }
+            }
+        }
+        devices
+    }
+}
+
+/// The `bao_sys` Python module.
+#[pymodule]
+fn bao_sys(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<PyIoRequest>()?;
+    m.add_class::<PyConfig>()?;
+    m.add("BAO_IO_WRITE", super::defines::BAO_IO_WRITE)?;
+    m.add("BAO_IO_READ", super::defines::BAO_IO_READ)?;
+    Ok(())
+}
@@ -0,0 +1,407 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao I/O request recording and replay.
+
+#![allow(dead_code)]
+
+use super::defines::{BAO_IO_READ, BAO_RECORD_ENTRY_SIZE, BAO_RECORD_MAGIC, BAO_RECORD_VERSION};
+use super::error::{Error, Result};
+use super::hypervisor::Hypervisor;
+use super::types::{AbiEncode, BaoIoEventFd, BaoIoRequest, BaoIrqFd};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Enum representing the kind of a recorded event.
+///
+/// # Variants
+///
+/// * `Request` - I/O request fetched from the hypervisor.
+/// * `Completion` - I/O request completed by the device model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordKind {
+    Request = 0,
+    Completion = 1,
+}
+
+/// Struct representing a recorded event.
+///
+/// # Attributes
+///
+/// * `timestamp_ns` - Time of the event since the recording started (in nanoseconds).
+/// * `kind` - Kind of event.
+/// * `req` - The I/O request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordedEvent {
+    pub timestamp_ns: u64,
+    pub kind: RecordKind,
+    pub req: BaoIoRequest,
+}
+
+/// Struct representing a recording of the I/O request stream.
+///
+/// The file starts with a header (magic, version, entry size) followed by fixed-size
+/// entries (timestamp, kind and ABI encoded request).
+///
+/// # Attributes
+///
+/// * `file` - Recording file.
+/// * `start` - Time the recording started.
+pub struct Recorder {
+    file: BufWriter<File>,
+    start: Instant,
+}
+
+impl Recorder {
+    /// Creates a recording.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Recording file path.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Recorder>` - The recorder.
+    pub fn new(path: &str) -> Result<Self> {
+        let mut file = BufWriter::new(File::create(path).map_err(Error::RecordingFailed)?);
+        let mut header = Vec::with_capacity(16);
+        header.extend_from_slice(BAO_RECORD_MAGIC);
+        header.extend_from_slice(&BAO_RECORD_VERSION.to_le_bytes());
+        header.extend_from_slice(&(BAO_RECORD_ENTRY_SIZE as u32).to_le_bytes());
+        file.write_all(&header).map_err(Error::RecordingFailed)?;
+        Ok(Recorder {
+            file,
+            start: Instant::now(),
+        })
+    }
+
+    /// Records an event, timestamped now.
+    fn record(&mut self, kind: RecordKind, req: &BaoIoRequest) -> Result<()> {
+        let mut entry = Vec::with_capacity(BAO_RECORD_ENTRY_SIZE);
+        entry.extend_from_slice(&(self.start.elapsed().as_nanos() as u64).to_le_bytes());
+        entry.extend_from_slice(&(kind as u64).to_le_bytes());
+        req.encode(&mut entry);
+        self.file.write_all(&entry).map_err(Error::RecordingFailed)
+    }
+
+    /// Records an I/O request fetched from the hypervisor.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The I/O request.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the request was recorded.
+    pub fn record_request(&mut self, req: &BaoIoRequest) -> Result<()> {
+        self.record(RecordKind::Request, req)
+    }
+
+    /// Records an I/O request completed by the device model.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The completed I/O request.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the completion was recorded.
+    pub fn record_completion(&mut self, req: &BaoIoRequest) -> Result<()> {
+        self.record(RecordKind::Completion, req)
+    }
+
+    /// Flushes the buffered events to the file.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the events were flushed.
+    pub fn flush(&mut self) -> Result<()> {
+        self.file.flush().map_err(Error::RecordingFailed)
+    }
+}
+
+/// Struct representing a hypervisor whose requests and completions are recorded.
+///
+/// # Attributes
+///
+/// * `inner` - The recorded hypervisor.
+/// * `recorder` - The recording, shared by the hypervisors of every guest.
+pub struct RecordingHypervisor<H: Hypervisor> {
+    inner: H,
+    recorder: Arc<Mutex<Recorder>>,
+}
+
+impl<H: Hypervisor> RecordingHypervisor<H> {
+    /// Records the requests of a hypervisor.
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - The recorded hypervisor.
+    /// * `recorder` - The recording.
+    ///
+    /// # Returns
+    ///
+    /// * `RecordingHypervisor` - The recording hypervisor.
+    pub fn new(inner: H, recorder: Arc<Mutex<Recorder>>) -> Self {
+        RecordingHypervisor { inner, recorder }
+    }
+}
+
+impl<H: Hypervisor> Hypervisor for RecordingHypervisor<H> {
+    fn attach_client(&self) -> Result<()> {
+        self.inner.attach_client()
+    }
+
+    fn wait_request(&self, timeout: Duration) -> Result<bool> {
+        self.inner.wait_request(timeout)
+    }
+
+    fn next_request(&self) -> Result<Option<BaoIoRequest>> {
+        let req = self.inner.next_request()?;
+        if let Some(req) = &req {
+            self.recorder.lock().unwrap().record_request(req)?;
+        }
+        Ok(req)
+    }
+
+    fn complete_request(&self, req: &BaoIoRequest) -> Result<()> {
+        self.recorder.lock().unwrap().record_completion(req)?;
+        self.inner.complete_request(req)
+    }
+
+    fn notify_guest(&self) -> Result<()> {
+        self.inner.notify_guest()
+    }
+
+    fn register_ioeventfd(&self, ioeventfd: &BaoIoEventFd) -> Result<()> {
+        self.inner.register_ioeventfd(ioeventfd)
+    }
+
+    fn register_irqfd(&self, irqfd: &BaoIrqFd) -> Result<()> {
+        self.inner.register_irqfd(irqfd)
+    }
+}
+
+/// Reads every event of a recording.
+///
+/// # Arguments
+///
+/// * `reader` - The recording file.
+///
+/// # Returns
+///
+/// * `Result<Vec<RecordedEvent>>` - The events, in the order they were recorded.
+pub fn read_recording<R: Read>(mut reader: R) -> Result<Vec<RecordedEvent>> {
+    let mut header = [0u8; 16];
+    reader
+        .read_exact(&mut header)
+        .map_err(Error::RecordingFailed)?;
+    if &header[0..8] != BAO_RECORD_MAGIC
+        || header[8..12] != BAO_RECORD_VERSION.to_le_bytes()
+        || header[12..16] != (BAO_RECORD_ENTRY_SIZE as u32).to_le_bytes()
+    {
+        return Err(Error::InvalidRecording);
+    }
+
+    let mut events = Vec::new();
+    let mut entry = [0u8; BAO_RECORD_ENTRY_SIZE];
+    loop {
+        match reader.read_exact(&mut entry) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(Error::RecordingFailed(e)),
+        }
+        let kind = match u64::from_le_bytes(entry[8..16].try_into().unwrap()) {
+            0 => RecordKind::Request,
+            1 => RecordKind::Completion,
+            _ => return Err(Error::InvalidRecording),
+        };
+        events.push(RecordedEvent {
+            timestamp_ns: u64::from_le_bytes(entry[0..8].try_into().unwrap()),
+            kind,
+            req: BaoIoRequest::decode(&entry[16..])?,
+        });
+    }
+    Ok(events)
+}
+
+/// Struct representing the outcome of a replay.
+///
+/// # Attributes
+///
+/// * `requests` - Number of requests replayed.
+/// * `mismatches` - Completions that differ from the recorded ones (recorded, replayed).
+/// * `recorded` - Duration of the recording.
+/// * `elapsed` - Duration of the replay.
+#[derive(Debug, Default)]
+pub struct ReplayReport {
+    pub requests: u64,
+    pub mismatches: Vec<(BaoIoRequest, BaoIoRequest)>,
+    pub recorded: Duration,
+    pub elapsed: Duration,
+}
+
+/// Replays a recording.
+///
+/// Each recorded request is handed to `handle` (e.g. the MMIO bus of a live device model,
+/// or a mock hypervisor serving one) and its completion compared with the recorded one:
+/// the value of reads and the return code must match.
+///
+/// # Arguments
+///
+/// * `events` - The recorded events.
+/// * `pace` - Whether to reproduce the recorded timing (otherwise requests are replayed
+///   back to back).
+/// * `handle` - Handles an I/O request.
+///
+/// # Returns
+///
+/// * `Result<ReplayReport>` - The replay report.
+pub fn replay<F>(events: &[RecordedEvent], pace: bool, mut handle: F) -> Result<ReplayReport>
+where
+    F: FnMut(&mut BaoIoRequest) -> Result<()>,
+{
+    let mut report = ReplayReport {
+        recorded: Duration::from_nanos(events.last().map_or(0, |e| e.timestamp_ns)),
+        ..Default::default()
+    };
+    let mut pending: VecDeque<BaoIoRequest> = VecDeque::new();
+    let start = Instant::now();
+
+    for event in events {
+        match event.kind {
+            RecordKind::Request => {
+                if pace {
+                    let due = Duration::from_nanos(event.timestamp_ns);
+                    if let Some(delay) = due.checked_sub(start.elapsed()) {
+                        thread::sleep(delay);
+                    }
+                }
+                let mut req = event.req;
+                handle(&mut req)?;
+                report.requests += 1;
+                pending.push_back(req);
+            }
+            RecordKind::Completion => {
+                // Completions are matched with the oldest replayed request of the same access
+                let recorded = event.req;
+                let index = pending.iter().position(|r| {
+                    r.virtio_id == recorded.virtio_id
+                        && r.addr == recorded.addr
+                        && r.op == recorded.op
+                });
+                let replayed = match index.and_then(|i| pending.remove(i)) {
+                    Some(replayed) => replayed,
+                    None => return Err(Error::InvalidRecording),
+                };
+                if replayed.ret != recorded.ret
+                    || (recorded.op == BAO_IO_READ && replayed.value != recorded.value)
+                {
+                    report.mismatches.push((recorded, replayed));
+                }
+            }
+        }
+    }
+
+    report.elapsed = start.elapsed();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::defines::BAO_IO_WRITE;
+    use crate::testing::MockHypervisor;
+
+    fn request(op: u64, value: u64) -> BaoIoRequest {
+        BaoIoRequest {
+            virtio_id: 0,
+            reg_off: 0x70,
+            addr: 0xa003e70,
+            op,
+            value,
+            access_width: 4,
+            cpu_id: 0,
+            vcpu_id: 0,
+            ret: 0,
+        }
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let path = std::env::temp_dir().join(format!("bao-record-{}.bin", std::process::id()));
+        let path = path.to_str().unwrap();
+
+        // Record a status write and read-back
+        let mut recorder = Recorder::new(path).unwrap();
+        for req in [request(BAO_IO_WRITE, 0xf), request(BAO_IO_READ, 0)] {
+            recorder.record_request(&req).unwrap();
+            let mut completed = req;
+            completed.value = 0xf;
+            recorder.record_completion(&completed).unwrap();
+        }
+        recorder.flush().unwrap();
+
+        let events = read_recording(File::open(path).unwrap()).unwrap();
+        assert_eq!(events.len(), 4);
+        assert_eq!(events[1].kind, RecordKind::Completion);
+        assert!(events
+            .windows(2)
+            .all(|w| w[0].timestamp_ns <= w[1].timestamp_ns));
+
+        // Replay against a mock hypervisor serving a one-register device
+        let hypervisor = MockHypervisor::new();
+        let mut status = 0;
+        let mut serve = |hv: &dyn Hypervisor| {
+            while let Some(mut req) = hv.next_request()? {
+                match req.op {
+                    BAO_IO_WRITE => status = req.value,
+                    _ => req.value = status,
+                }
+                hv.complete_request(&req)?;
+            }
+            Ok(())
+        };
+        let report = replay(&events, true, |req| hypervisor.dispatch(req, &mut serve)).unwrap();
+        assert_eq!(report.requests, 2);
+        assert!(report.mismatches.is_empty());
+
+        // A regression is reported
+        let report = replay(&events, false, |req| {
+            req.value = 0;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(report.mismatches.len(), 1);
+        assert_eq!(report.mismatches[0].0.value, 0xf);
+
+        // The requests served through a recording hypervisor are recorded
+        let recorder = Arc::new(Mutex::new(Recorder::new(path).unwrap()));
+        let mut serve_recorded =
+            |hv: &dyn Hypervisor| serve(&RecordingHypervisor::new(hv, recorder.clone()));
+        for req in [request(BAO_IO_WRITE, 0xf), request(BAO_IO_READ, 0)] {
+            let mut req = req;
+            hypervisor.dispatch(&mut req, &mut serve_recorded).unwrap();
+        }
+        recorder.lock().unwrap().flush().unwrap();
+        let recorded = read_recording(File::open(path).unwrap()).unwrap();
+        assert_eq!(recorded.len(), 4);
+        assert_eq!(
+            (recorded[3].kind, recorded[3].req.value),
+            (RecordKind::Completion, 0xf)
+        );
+
+        std::fs::write(path, b"BAOAUDIT").unwrap();
+        assert!(matches!(
+            read_recording(File::open(path).unwrap()),
+            Err(Error::RecordingFailed(_))
+        ));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,280 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao per-device resource accounting.
+//!
+//! On a saturated board, the request counters do not tell which device consumes the host
+//! cores. Every device accounts the host resources it holds: the CPU time of its worker
+//! threads (read from `/proc/self/task/<tid>/stat` while they run, from their final
+//! `RUSAGE_THREAD` once they exit), the memory it maps and the file descriptors it owns.
+
+#![allow(dead_code)]
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs;
+use std::os::unix::io::RawFd;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Struct representing a point-in-time copy of the resources of a device.
+///
+/// # Attributes
+///
+/// * `name` - Device name.
+/// * `cpu_user_ns` - CPU time spent by the device workers in user mode (in nanoseconds).
+/// * `cpu_system_ns` - CPU time spent by the device workers in kernel mode (in nanoseconds).
+/// * `workers` - Number of running worker threads.
+/// * `mapped_bytes` - Memory mapped by the device (in bytes).
+/// * `fds` - Number of open file descriptors owned by the device.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ResourceSnapshot {
+    pub name: String,
+    pub cpu_user_ns: u64,
+    pub cpu_system_ns: u64,
+    pub workers: u64,
+    pub mapped_bytes: u64,
+    pub fds: u64,
+}
+
+/// Struct representing the host resources held by a device.
+///
+/// # Attributes
+///
+/// * `name` - Device name.
+/// * `workers` - Thread IDs of the running worker threads.
+/// * `retired_user_ns` - User CPU time of the worker threads that exited (in nanoseconds).
+/// * `retired_system_ns` - Kernel CPU time of the worker threads that exited (in
+///   nanoseconds).
+/// * `mapped_bytes` - Memory mapped by the device (in bytes).
+/// * `fds` - File descriptors owned by the device.
+#[derive(Debug, Default)]
+pub struct DeviceResources {
+    pub name: String,
+    workers: Mutex<BTreeSet<libc::pid_t>>,
+    retired_user_ns: AtomicU64,
+    retired_system_ns: AtomicU64,
+    mapped_bytes: AtomicU64,
+    fds: Mutex<BTreeSet<RawFd>>,
+}
+
+/// Struct representing a worker thread accounted to a device, until it is dropped.
+///
+/// # Attributes
+///
+/// * `resources` - Resources of the device.
+/// * `tid` - Thread ID of the worker.
+pub struct WorkerGuard {
+    resources: Arc<DeviceResources>,
+    tid: libc::pid_t,
+}
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        // Only the worker itself can read its final usage
+        // SAFETY: `gettid` has no preconditions.
+        if unsafe { libc::gettid() } == self.tid {
+            if let Some((user, system)) = thread_rusage() {
+                self.resources
+                    .retired_user_ns
+                    .fetch_add(user, Ordering::Relaxed);
+                self.resources
+                    .retired_system_ns
+                    .fetch_add(system, Ordering::Relaxed);
+            }
+        }
+        self.resources.workers.lock().unwrap().remove(&self.tid);
+    }
+}
+
+/// Converts a `timeval` to nanoseconds.
+fn timeval_ns(tv: libc::timeval) -> u64 {
+    (tv.tv_sec as u64) * 1_000_000_000 + (tv.tv_usec as u64) * 1_000
+}
+
+/// Returns the CPU time of the calling thread.
+///
+/// # Returns
+///
+/// * `Option<(u64, u64)>` - The user and kernel CPU time (in nanoseconds).
+fn thread_rusage() -> Option<(u64, u64)> {
+    // SAFETY: `usage` is a valid, writable rusage structure.
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    // SAFETY: `usage` is a valid pointer for the duration of the call.
+    if unsafe { libc::getrusage(libc::RUSAGE_THREAD, &mut usage) } != 0 {
+        return None;
+    }
+    Some((timeval_ns(usage.ru_utime), timeval_ns(usage.ru_stime)))
+}
+
+/// Returns the CPU time of a thread of the process.
+///
+/// # Arguments
+///
+/// * `tid` - Thread ID.
+///
+/// # Returns
+///
+/// * `Option<(u64, u64)>` - The user and kernel CPU time (in nanoseconds), None if the
+///   thread exited.
+fn task_cpu_time(tid: libc::pid_t) -> Option<(u64, u64)> {
+    let stat = fs::read_to_string(format!("/proc/self/task/{}/stat", tid)).ok()?;
+    // The command name may contain spaces: the fields follow its closing parenthesis
+    let fields = stat[stat.rfind(')')? + 1..]
+        .split_whitespace()
+        .collect::<Vec<_>>();
+    let utime = fields.get(11)?.parse::<u64>().ok()?;
+    let stime = fields.get(12)?.parse::<u64>().ok()?;
+    // SAFETY: `sysconf` has no preconditions.
+    let ticks = unsafe { libc::sysconf(libc::_SC_CLK_TCK) };
+    let tick_ns = 1_000_000_000 / u64::try_from(ticks).ok().filter(|&t| t > 0)?;
+    Some((utime * tick_ns, stime * tick_ns))
+}
+
+impl DeviceResources {
+    /// Creates the resource accounting of a device.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    ///
+    /// # Returns
+    ///
+    /// * `DeviceResources` - The accounting (holding no resource).
+    pub fn new(name: &str) -> Self {
+        DeviceResources {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Accounts the calling thread as a worker of the device.
+    ///
+    /// # Returns
+    ///
+    /// * `WorkerGuard` - Accounts the thread until it is dropped (by the thread, once it
+    ///   stops serving the device).
+    pub fn track_worker(self: &Arc<Self>) -> WorkerGuard {
+        // SAFETY: `gettid` has no preconditions.
+        let tid = unsafe { libc::gettid() };
+        self.workers.lock().unwrap().insert(tid);
+        WorkerGuard {
+            resources: self.clone(),
+            tid,
+        }
+    }
+
+    /// Accounts a memory mapping of the device.
+    ///
+    /// # Arguments
+    ///
+    /// * `len` - Mapping size (in bytes).
+    pub fn track_mapping(&self, len: u64) {
+        self.mapped_bytes.fetch_add(len, Ordering::Relaxed);
+    }
+
+    /// Stops accounting a memory mapping of the device (once unmapped).
+    ///
+    /// # Arguments
+    ///
+    /// * `len` - Mapping size (in bytes).
+    pub fn untrack_mapping(&self, len: u64) {
+        let _ = self
+            .mapped_bytes
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bytes| {
+                Some(bytes.saturating_sub(len))
+            });
+    }
+
+    /// Accounts a file descriptor owned by the device.
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - The file descriptor.
+    pub fn track_fd(&self, fd: RawFd) {
+        self.fds.lock().unwrap().insert(fd);
+    }
+
+    /// Stops accounting a file descriptor of the device (e.g. before closing it).
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - The file descriptor.
+    pub fn untrack_fd(&self, fd: RawFd) {
+        self.fds.lock().unwrap().remove(&fd);
+    }
+
+    /// Takes a snapshot of the resources of the device.
+    ///
+    /// File descriptors closed without being untracked are dropped from the accounting.
+    ///
+    /// # Returns
+    ///
+    /// * `ResourceSnapshot` - The current resources.
+    pub fn snapshot(&self) -> ResourceSnapshot {
+        let workers = self.workers.lock().unwrap().clone();
+        let (user, system) = workers
+            .iter()
+            .filter_map(|&tid| task_cpu_time(tid))
+            .fold((0, 0), |(user, system), (u, s)| (user + u, system + s));
+        let mut fds = self.fds.lock().unwrap();
+        // SAFETY: F_GETFD only queries the descriptor flags.
+        fds.retain(|&fd| unsafe { libc::fcntl(fd, libc::F_GETFD) } >= 0);
+        ResourceSnapshot {
+            name: self.name.clone(),
+            cpu_user_ns: self.retired_user_ns.load(Ordering::Relaxed) + user,
+            cpu_system_ns: self.retired_system_ns.load(Ordering::Relaxed) + system,
+            workers: workers.len() as u64,
+            mapped_bytes: self.mapped_bytes.load(Ordering::Relaxed),
+            fds: fds.len() as u64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::io::AsRawFd;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_device_resources() {
+        let resources = Arc::new(DeviceResources::new("rng0"));
+        resources.track_mapping(0x10000);
+        resources.track_mapping(0x1000);
+        resources.untrack_mapping(0x10000);
+        let file = fs::File::open("/proc/self/stat").unwrap();
+        resources.track_fd(file.as_raw_fd());
+        let snapshot = resources.snapshot();
+        assert_eq!(snapshot.mapped_bytes, 0x1000);
+        assert_eq!(snapshot.fds, 1);
+        assert_eq!(snapshot.workers, 0);
+        drop(file);
+        assert_eq!(resources.snapshot().fds, 0);
+
+        // The CPU time of the workers outlives them
+        let worker = {
+            let resources = resources.clone();
+            thread::spawn(move || {
+                let _guard = resources.track_worker();
+                let start = Instant::now();
+                while start.elapsed() < Duration::from_millis(50) {
+                    std::hint::spin_loop();
+                }
+            })
+        };
+        worker.join().unwrap();
+        let snapshot = resources.snapshot();
+        assert_eq!(snapshot.workers, 0);
+        assert!(snapshot.cpu_user_ns + snapshot.cpu_system_ns >= 10_000_000);
+
+        let _guard = resources.track_worker();
+        assert_eq!(resources.snapshot().workers, 1);
+        // SAFETY: `gettid` has no preconditions.
+        let tid = unsafe { libc::gettid() };
+        assert!(task_cpu_time(tid).is_some());
+    }
+}
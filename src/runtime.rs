@@ -0,0 +1,688 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao frontend runtime.
+//!
+//! Brings up what a frontend serves once its configuration is applied: the management
+//! registry of its devices, the control sockets exposing it, the stats file written from
+//! it, the in-process backends of the devices (see the `backend` module, confining each
+//! one to its `allowed_paths`, and serving it from its own child process with
+//! `isolation: subprocess`) and the external backends it launches and supervises (see the
+//! `spawn` module, restarting them according to the restart policy of their device). The
+//! runtime is started before the frontend enters its sandbox (the sockets are bound then),
+//! and the frontend runs until everything it serves terminated.
+//!
+//! The devices hot-plugged from the `watch_dir` of their guest (see the `watch` module), or
+//! by a management client, are brought up the same way, and their backend is stopped once
+//! they are hot-unplugged.
+//!
+//! The devices are brought up concurrently, in their dependency order (see the `init`
+//! module). The state of every device follows its backend: `starting` while it is brought
+//! up, `running` once it serves, then `unplugged` once it terminated gracefully or
+//! `failed`.
+
+#![allow(dead_code)]
+
+use super::backend::{spawn_in_process_backend, spawn_isolated_in_process_backend};
+use super::control;
+use super::defines::BAO_SUPERVISE_INTERVAL_MS;
+use super::error::{Error, Result};
+use super::events::DeviceEvent;
+use super::init::init_devices;
+use super::management::{DeviceRegistry, DeviceState, Management};
+use super::spawn::SpawnedBackend;
+use super::stats_file;
+use super::trace::LogFile;
+use super::types::{ConfigDevice, ConfigFrontends, ConfigGuest, IsolationMode, VmId};
+use super::watch::{self, apply_device_event, DeviceEvent as WatchEvent};
+use std::collections::BTreeMap;
+use std::env;
+use std::os::unix::net::UnixStream;
+use std::process::{Child, Command};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Enum representing the backend of a device served by the runtime.
+///
+/// # Variants
+///
+/// * `InProcess` - Backend served on a frontend thread.
+/// * `Isolated` - Backend served by its own child process (`isolation: subprocess`), and
+///   the channel keeping it alive.
+/// * `Spawned` - External backend launched by the frontend (`spawn`), and its device.
+enum Backend {
+    InProcess(JoinHandle<Result<()>>),
+    Isolated(Child, UnixStream),
+    Spawned(SpawnedBackend, Box<ConfigDevice>),
+}
+
+impl Backend {
+    /// Starts the backend of a device, if the frontend serves it.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest` - Guest owning the device.
+    /// * `device` - The device.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<Backend>>` - The backend, or None if the device relies on an
+    ///   external backend.
+    fn start(guest: &ConfigGuest, device: &ConfigDevice) -> Result<Option<Self>> {
+        if device.backend.is_none() {
+            let backend = SpawnedBackend::spawn(guest, device)?;
+            return Ok(backend.map(|backend| Backend::Spawned(backend, Box::new(device.clone()))));
+        }
+        match device.isolation {
+            IsolationMode::InProcess => {
+                Ok(spawn_in_process_backend(guest, device)?.map(Backend::InProcess))
+            }
+            IsolationMode::Subprocess => {
+                // The frontend executable serves the device (see `serve_isolated_backend`)
+                let exe =
+                    env::current_exe().map_err(|e| Error::SpawnBackendFailed("current_exe", e))?;
+                let backend = spawn_isolated_in_process_backend(guest, device, Command::new(exe))?;
+                Ok(backend.map(|(child, channel)| Backend::Isolated(child, channel)))
+            }
+        }
+    }
+
+    /// Checks whether the backend terminated, restarting a spawned backend that exited if
+    /// the restart policy of its device allows it.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<bool>` - True if the backend terminated, or the error of a spawned backend
+    ///   that exited for good.
+    fn supervise(&mut self) -> Result<bool> {
+        match self {
+            Backend::InProcess(thread) => Ok(thread.is_finished()),
+            Backend::Isolated(child, _) => Ok(!matches!(child.try_wait(), Ok(None))),
+            Backend::Spawned(backend, device) => match backend.supervise(device) {
+                Err(Error::BackendExited(_, status)) if status.success() => Ok(true),
+                result => result.map(|_| false),
+            },
+        }
+    }
+
+    /// Waits for the backend to terminate.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the device.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the backend terminated gracefully.
+    fn join(self, name: &str) -> Result<()> {
+        match self {
+            Backend::InProcess(thread) => thread
+                .join()
+                .unwrap_or_else(|_| Err(Error::BackendPanicked(name.to_string()))),
+            Backend::Isolated(mut child, _) => match child.wait() {
+                Ok(status) if status.success() => Ok(()),
+                Ok(status) => Err(Error::BackendExited(name.to_string(), status)),
+                Err(e) => Err(Error::SpawnBackendFailed("wait", e)),
+            },
+            Backend::Spawned(..) => Ok(()),
+        }
+    }
+
+    /// Stops the backend.
+    ///
+    /// An in-process backend thread cannot be stopped: it terminates on its own once its
+    /// frontend disconnected.
+    fn stop(self) {
+        match self {
+            Backend::InProcess(_) => {}
+            Backend::Isolated(mut child, _) => {
+                let _ = child.kill();
+                let _ = child.wait();
+            }
+            // Killed once dropped
+            Backend::Spawned(..) => {}
+        }
+    }
+}
+
+/// Struct representing the devices served by the runtime.
+///
+/// # Attributes
+///
+/// * `registry` - Management registry of the devices.
+/// * `configs` - Guest and configuration of the devices (hot-plugged ones included), by
+///   device name.
+/// * `backends` - Backends served by the frontend, by device name.
+struct Devices {
+    registry: Arc<DeviceRegistry>,
+    configs: Mutex<BTreeMap<String, (ConfigGuest, ConfigDevice)>>,
+    backends: Mutex<BTreeMap<String, Backend>>,
+}
+
+impl Devices {
+    /// Brings a device up.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest` - Guest owning the device.
+    /// * `device` - The device.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok once the device serves (`failed` otherwise).
+    fn start(&self, guest: &ConfigGuest, device: &ConfigDevice) -> Result<()> {
+        self.registry
+            .set_device_state(&device.name, DeviceState::Starting)?;
+        match Backend::start(guest, device) {
+            Ok(backend) => {
+                if let Some(backend) = backend {
+                    self.backends
+                        .lock()
+                        .unwrap()
+                        .insert(device.name.clone(), backend);
+                }
+                self.registry
+                    .set_device_state(&device.name, DeviceState::Running)
+            }
+            Err(e) => {
+                self.registry
+                    .set_device_state(&device.name, DeviceState::Failed)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Handles a hot-plug request, bringing the device up (or stopping its backend).
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    /// * `plugged` - Whether the device is plugged or unplugged.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok once the device serves (or is unplugged), `InvalidDeviceState`
+    ///   if a plugged device is already up.
+    fn plug(&self, name: &str, plugged: bool) -> Result<()> {
+        if !plugged {
+            if let Some(backend) = self.backends.lock().unwrap().remove(name) {
+                backend.stop();
+            }
+            return self.registry.set_device_state(name, DeviceState::Unplugged);
+        }
+        match self.registry.device_state(name)? {
+            DeviceState::Unplugged | DeviceState::Failed => {}
+            state => return Err(Error::InvalidDeviceState(name.to_string(), state.name())),
+        }
+        let (guest, device) = self
+            .configs
+            .lock()
+            .unwrap()
+            .get(name)
+            .cloned()
+            .ok_or(Error::DeviceNotFound)?;
+        self.start(&guest, &device)
+    }
+
+    /// Handles a change of the fragment directory of a guest.
+    ///
+    /// # Arguments
+    ///
+    /// * `frontend_id` - Frontend ID of the guest.
+    /// * `guest` - The guest.
+    /// * `event` - The change.
+    fn watched(&self, frontend_id: VmId, guest: &ConfigGuest, event: WatchEvent) {
+        // The configuration of a hot-plugged device is known before it is plugged
+        if let WatchEvent::Added(device) = &event {
+            self.configs
+                .lock()
+                .unwrap()
+                .entry(device.name.clone())
+                .or_insert_with(|| (guest.clone(), (**device).clone()));
+        }
+        let overlay = guest.dt_overlay.as_ref();
+        let _ = apply_device_event(&self.registry, frontend_id, guest.id, &event, overlay);
+        let name = match &event {
+            WatchEvent::Added(device) => &device.name,
+            WatchEvent::Removed(name) => name,
+        };
+        // Forget the devices the registry forgot (unplugged, or not plugged after all)
+        if self.registry.device_state(name).is_err() {
+            self.configs.lock().unwrap().remove(name);
+        }
+    }
+
+    /// Supervises the backends, accounting the ones that terminated.
+    fn reap(&self) {
+        let mut backends = self.backends.lock().unwrap();
+        let terminated = backends
+            .iter_mut()
+            .filter_map(|(name, backend)| match backend.supervise() {
+                Ok(false) => None,
+                Ok(true) => Some((name.clone(), Ok(()))),
+                Err(e) => Some((name.clone(), Err(e))),
+            })
+            .collect::<Vec<_>>();
+        for (name, result) in terminated {
+            let backend = backends.remove(&name).unwrap();
+            let state = match result.and_then(|_| backend.join(&name)) {
+                Ok(()) => DeviceState::Unplugged,
+                Err(_) => DeviceState::Failed,
+            };
+            self.registry
+                .publish_event(DeviceEvent::BackendDisconnected(name.clone()));
+            let _ = self.registry.set_device_state(&name, state);
+        }
+    }
+}
+
+/// Struct representing the runtime of a frontend.
+///
+/// # Attributes
+///
+/// * `devices` - Devices served by the frontend.
+/// * `servers` - Threads serving the control sockets, writing the stats file and watching
+///   the fragment directories.
+pub struct Runtime {
+    devices: Arc<Devices>,
+    servers: Vec<JoinHandle<()>>,
+}
+
+impl Runtime {
+    /// Starts the runtime of a configuration.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The frontends configuration.
+    /// * `logs` - Log files flushed and rotated on demand (e.g. the audit log).
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Runtime>` - The runtime.
+    pub fn start(config: &ConfigFrontends, logs: Vec<Arc<dyn LogFile>>) -> Result<Self> {
+        let registry = Arc::new(DeviceRegistry::new(config));
+        for log in logs {
+            registry.attach_log(log);
+        }
+        let mut servers = control::serve_all(registry.clone(), config)?;
+        if let Some(stats_file) = &config.stats_file {
+            servers.push(stats_file::serve(registry.clone(), stats_file)?);
+        }
+        let mut configs = BTreeMap::new();
+        for guest in config
+            .frontends
+            .iter()
+            .flat_map(|frontend| &frontend.guests)
+        {
+            for device in &guest.devices {
+                // Duplicate names keep their first device, as in the registry
+                configs
+                    .entry(device.name.clone())
+                    .or_insert_with(|| (guest.clone(), device.clone()));
+            }
+        }
+        let devices = Arc::new(Devices {
+            registry,
+            configs: Mutex::new(configs),
+            backends: Mutex::default(),
+        });
+        init_devices(config, |guest, device| devices.start(guest, device))?;
+
+        // The registry outlives the runtime, which must not be kept alive by it
+        let handler = Arc::downgrade(&devices);
+        devices
+            .registry
+            .set_hotplug_handler(Box::new(move |name, plugged| match handler.upgrade() {
+                Some(devices) => devices.plug(name, plugged),
+                None => Err(Error::HotplugNotSupported),
+            }));
+        for frontend in &config.frontends {
+            for guest in &frontend.guests {
+                let Some(dir) = &guest.watch_dir else {
+                    continue;
+                };
+                let (id, guest, watcher) = (frontend.id, guest.clone(), Arc::downgrade(&devices));
+                servers.push(watch::serve(dir, move |event| {
+                    if let Some(devices) = watcher.upgrade() {
+                        devices.watched(id, &guest, event);
+                    }
+                })?);
+            }
+        }
+        Ok(Runtime { devices, servers })
+    }
+
+    /// Returns the management registry of the devices.
+    ///
+    /// # Returns
+    ///
+    /// * `Arc<DeviceRegistry>` - The registry.
+    pub fn registry(&self) -> Arc<DeviceRegistry> {
+        self.devices.registry.clone()
+    }
+
+    /// Waits for everything the runtime serves to terminate, accounting the backends
+    /// that terminate meanwhile.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok once nothing is left to serve.
+    pub fn wait(self) -> Result<()> {
+        loop {
+            self.devices.reap();
+            let serving = !self.devices.backends.lock().unwrap().is_empty()
+                || self.servers.iter().any(|server| !server.is_finished());
+            if !serving {
+                return Ok(());
+            }
+            thread::sleep(Duration::from_millis(BAO_SUPERVISE_INTERVAL_MS));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::{register_backend, InProcessBackend};
+    use crate::control::{send_command, ControlResponse};
+    use crate::sandbox::restrict_paths;
+    use crate::types::ConfigAllowedPath;
+    use std::fs;
+    use std::path::Path;
+
+    struct ExitBackend(bool);
+
+    impl InProcessBackend for ExitBackend {
+        fn serve(self: Box<Self>, _socket: &Path) -> Result<()> {
+            match self.0 {
+                true => Ok(()),
+                false => Err(Error::InvalidVhostUserMessage(0)),
+            }
+        }
+    }
+
+    fn exit_factory(device: &ConfigDevice) -> Result<Box<dyn InProcessBackend>> {
+        Ok(Box::new(ExitBackend(device.name == "rng0")))
+    }
+
+    struct ReadRootBackend;
+
+    impl InProcessBackend for ReadRootBackend {
+        fn serve(self: Box<Self>, _socket: &Path) -> Result<()> {
+            fs::read_dir("/").map(|_| ()).map_err(Error::Io)
+        }
+    }
+
+    fn read_root_factory(_device: &ConfigDevice) -> Result<Box<dyn InProcessBackend>> {
+        Ok(Box::new(ReadRootBackend))
+    }
+
+    #[test]
+    fn test_runtime_servers() {
+        let path = std::env::temp_dir().join(format!("bao-runtime-{}.sock", std::process::id()));
+        let stats = path.with_extension("json");
+        let config: ConfigFrontends = serde_yaml::from_str(&format!(
+            "control:
+  path: {}
+stats_file:
+  path: {}
+frontends:
+  - name: frontend0
+    id: 0
+    guests:
+      - name: guest0
+        id: 1
+        ram_addr: 0x50000000
+        ram_size: 0x1000000
+        shmem_path: /dev/baoipc0
+        socket_path: /tmp/
+        devices:
+          - {{name: rng0, id: 0, type: rng, irq: 47, addr: 0xa003e00}}
+",
+            path.display(),
+            stats.display()
+        ))
+        .unwrap();
+        let runtime = Runtime::start(&config, Vec::new()).unwrap();
+        assert_eq!(runtime.servers.len(), 2);
+        assert!(fs::read_to_string(&stats)
+            .unwrap()
+            .contains("\"devices\":["));
+
+        // The registry of the runtime is served on the control socket
+        let mut stream = UnixStream::connect(&path).unwrap();
+        let response = send_command(&mut stream, "devices").unwrap();
+        let ControlResponse::Ok(devices) = response else {
+            panic!("unexpected response {:?}", response);
+        };
+        assert_eq!(devices[0]["name"], serde_yaml::Value::from("rng0"));
+        assert_eq!(runtime.registry().devices().len(), 1);
+        drop(stream);
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&stats).unwrap();
+    }
+
+    #[test]
+    fn test_runtime_backends() {
+        register_backend("runtime-exit", exit_factory);
+        let config: ConfigFrontends = serde_yaml::from_str(
+            "frontends:
+  - name: frontend0
+    id: 0
+    guests:
+      - name: guest0
+        id: 1
+        ram_addr: 0x50000000
+        ram_size: 0x1000000
+        shmem_path: /dev/baoipc0
+        socket_path: /tmp/
+        devices:
+          - {name: rng0, id: 0, type: rng, irq: 47, addr: 0xa003e00, backend: runtime-exit}
+          - {name: rng1, id: 1, type: rng, irq: 48, addr: 0xa003f00, backend: runtime-exit}
+          - {name: rng2, id: 2, type: rng, irq: 49, addr: 0xa003d00}
+",
+        )
+        .unwrap();
+        let runtime = Runtime::start(&config, Vec::new()).unwrap();
+        let registry = runtime.registry();
+        assert_eq!(runtime.devices.backends.lock().unwrap().len(), 2);
+        assert_eq!(registry.device_state("rng2").unwrap(), DeviceState::Running);
+
+        // The runtime is done once its backends terminated, which their state tells
+        runtime.wait().unwrap();
+        assert_eq!(
+            registry.device_state("rng0").unwrap(),
+            DeviceState::Unplugged
+        );
+        assert_eq!(registry.device_state("rng1").unwrap(), DeviceState::Failed);
+
+        // A device whose backend cannot be created fails the startup
+        let mut config = config;
+        config.frontends[0].guests[0].devices[2].backend = Some("missing".to_string());
+        let report = Runtime::start(&config, Vec::new())
+            .map(|_| ())
+            .unwrap_err()
+            .report();
+        assert!(report.contains("frontend0/guest1/rng2"));
+        assert!(report.contains("missing"));
+
+        // The devices are brought up in their dependency order, which must exist
+        config.frontends[0].guests[0].devices[0].depends_on = vec!["rng1".to_string()];
+        config.frontends[0].guests[0].devices[1].depends_on = vec!["rng0".to_string()];
+        assert!(Runtime::start(&config, Vec::new()).is_err());
+    }
+
+    #[test]
+    fn test_runtime_backends_confined() {
+        register_backend("runtime-read-root", read_root_factory);
+        let config: ConfigFrontends = serde_yaml::from_str(
+            "frontends:
+  - name: frontend0
+    id: 0
+    guests:
+      - name: guest0
+        id: 1
+        ram_addr: 0x50000000
+        ram_size: 0x1000000
+        shmem_path: /dev/baoipc0
+        socket_path: /tmp/
+        devices:
+          - name: rng0
+            id: 0
+            type: rng
+            irq: 47
+            addr: 0xa003e00
+            backend: runtime-read-root
+            allowed_paths:
+              - {path: /dev/null, read_only: true}
+",
+        )
+        .unwrap();
+        // Confinement is best-effort, depending on the running kernel
+        let enforced = thread::spawn(|| {
+            restrict_paths(&[ConfigAllowedPath {
+                path: "/".to_string(),
+                read_only: false,
+            }])
+            .unwrap()
+        })
+        .join()
+        .unwrap();
+
+        // The backends brought up by the runtime only reach their allowed paths
+        let runtime = Runtime::start(&config, Vec::new()).unwrap();
+        let registry = runtime.registry();
+        runtime.wait().unwrap();
+        let state = match enforced {
+            true => DeviceState::Failed,
+            false => DeviceState::Unplugged,
+        };
+        assert_eq!(registry.device_state("rng0").unwrap(), state);
+    }
+
+    #[test]
+    fn test_runtime_spawned_backends() {
+        let dir = std::env::temp_dir().join(format!("bao-runtime-spawn-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let marker = dir.join("failed");
+        let config: ConfigFrontends = serde_yaml::from_str(&format!(
+            "frontends:
+  - name: frontend0
+    id: 0
+    guests:
+      - name: guest0
+        id: 1
+        ram_addr: 0x50000000
+        ram_size: 0x1000000
+        shmem_path: /dev/baoipc0
+        socket_path: {}
+        devices:
+          - {{name: rng0, id: 0, type: rng, irq: 47, addr: 0xa003e00, spawn: {{command: [\"true\"]}}}}
+          - name: rng1
+            id: 1
+            type: rng
+            irq: 48
+            addr: 0xa003f00
+            spawn: {{command: [sh, -c, \"test -e {} || {{ touch {}; exit 1; }}\"]}}
+            restart: on-failure
+            restart_delay_ms: 1
+          - {{name: rng2, id: 2, type: rng, irq: 49, addr: 0xa003d00, spawn: {{command: [\"false\"]}}}}
+",
+            dir.display(),
+            marker.display(),
+            marker.display()
+        ))
+        .unwrap();
+        let runtime = Runtime::start(&config, Vec::new()).unwrap();
+        let registry = runtime.registry();
+        assert_eq!(runtime.devices.backends.lock().unwrap().len(), 3);
+
+        // The backend that failed is restarted, then every backend exits for good
+        runtime.wait().unwrap();
+        assert!(marker.exists());
+        assert_eq!(
+            registry.device_state("rng0").unwrap(),
+            DeviceState::Unplugged
+        );
+        assert_eq!(
+            registry.device_state("rng1").unwrap(),
+            DeviceState::Unplugged
+        );
+        assert_eq!(registry.device_state("rng2").unwrap(), DeviceState::Failed);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_runtime_hotplug() {
+        let dir = std::env::temp_dir().join(format!("bao-runtime-hotplug-{}", std::process::id()));
+        let (fragments, sockets) = (dir.join("devices.d"), dir.join("sockets"));
+        fs::create_dir_all(&fragments).unwrap();
+        fs::create_dir_all(&sockets).unwrap();
+        fs::write(
+            fragments.join("rng1.yaml"),
+            "{name: rng1, id: 1, type: rng, irq: 48, addr: 0xa003f00, spawn: {command: [sleep, \"60\"]}}",
+        )
+        .unwrap();
+        let config: ConfigFrontends = serde_yaml::from_str(&format!(
+            "frontends:
+  - name: frontend0
+    id: 0
+    guests:
+      - name: guest0
+        id: 1
+        ram_addr: 0x50000000
+        ram_size: 0x1000000
+        shmem_path: /dev/baoipc0
+        socket_path: {}
+        watch_dir: {}
+        devices:
+          - {{name: rng0, id: 0, type: rng, irq: 47, addr: 0xa003e00, spawn: {{command: [sleep, \"60\"]}}}}
+",
+            sockets.display(),
+            fragments.display()
+        ))
+        .unwrap();
+        let runtime = Runtime::start(&config, Vec::new()).unwrap();
+        let registry = runtime.registry();
+        let backends = || runtime.devices.backends.lock().unwrap().len();
+        let wait_for = |done: &dyn Fn() -> bool| {
+            for _ in 0..500 {
+                if done() {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            panic!("timed out");
+        };
+
+        // The fragments in the directory are plugged, their backend brought up
+        wait_for(&|| matches!(registry.device_state("rng1"), Ok(DeviceState::Running)));
+        assert_eq!(backends(), 2);
+
+        // Unplugging a device stops its backend, plugging it brings it up again
+        registry.set_device_plugged("rng0", false).unwrap();
+        assert_eq!(
+            registry.device_state("rng0").unwrap(),
+            DeviceState::Unplugged
+        );
+        assert_eq!(backends(), 1);
+        registry.set_device_plugged("rng0", true).unwrap();
+        assert_eq!(registry.device_state("rng0").unwrap(), DeviceState::Running);
+        assert_eq!(backends(), 2);
+        assert!(matches!(
+            registry.set_device_plugged("rng0", true),
+            Err(Error::InvalidDeviceState(..))
+        ));
+
+        // Deleted fragments are unplugged and forgotten
+        fs::remove_file(fragments.join("rng1.yaml")).unwrap();
+        wait_for(&|| registry.device_state("rng1").is_err());
+        assert_eq!(backends(), 1);
+        assert!(!runtime.devices.configs.lock().unwrap().contains_key("rng1"));
+        drop(runtime);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
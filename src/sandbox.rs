@@ -0,0 +1,532 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao frontend sandboxing.
+
+#![allow(dead_code)]
+
+use super::defines::{BAO_ISOLATION_CHANNEL_ENV, BAO_ISOLATION_CHANNEL_FD};
+use super::error::{Error, Result};
+use super::ioctl::*;
+use super::types::{Capability, ConfigAllowedPath, ConfigFrontends, SeccompMode};
+use landlock::{
+    path_beneath_rules, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr, RulesetStatus,
+    ABI,
+};
+use seccompiler::{
+    BpfProgram, SeccompAction, SeccompCmpArgLen, SeccompCmpOp, SeccompCondition, SeccompFilter,
+    SeccompRule,
+};
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command};
+
+/// Represents the set of syscalls a thread is allowed to perform.
+///
+/// # Variants
+///
+/// * `Frontend` - Frontend event loop (may still spawn device workers).
+/// * `Worker` - Device worker thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeccompProfile {
+    Frontend,
+    Worker,
+}
+
+/// Syscalls allowed for every thread after initialization.
+const COMMON_SYSCALLS: &[i64] = &[
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_close,
+    libc::SYS_epoll_ctl,
+    libc::SYS_epoll_pwait,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_epoll_wait,
+    libc::SYS_sendmsg,
+    libc::SYS_recvmsg,
+    libc::SYS_futex,
+    libc::SYS_brk,
+    libc::SYS_mmap,
+    libc::SYS_munmap,
+    libc::SYS_madvise,
+    libc::SYS_clock_gettime,
+    libc::SYS_sched_yield,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_getpid,
+    libc::SYS_gettid,
+    libc::SYS_tgkill,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+];
+
+/// Additional syscalls allowed for the frontend event loop.
+const FRONTEND_SYSCALLS: &[i64] = &[
+    libc::SYS_mremap,
+    libc::SYS_clone,
+    libc::SYS_clone3,
+    libc::SYS_set_robust_list,
+    libc::SYS_rseq,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_sigaltstack,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_getrandom,
+    libc::SYS_fcntl,
+    libc::SYS_mprotect,
+    // Files (trace rotation, applied configuration record) and the control socket
+    libc::SYS_openat,
+    libc::SYS_lseek,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_rename,
+    libc::SYS_renameat,
+    libc::SYS_renameat2,
+    libc::SYS_accept4,
+    libc::SYS_getsockopt,
+    // Required by the device workers to install their own filter
+    libc::SYS_prctl,
+    libc::SYS_seccomp,
+];
+
+/// Bao IOCTLs allowed for the frontend event loop.
+fn frontend_ioctls() -> Vec<u64> {
+    vec![
+        BAO_IOCTL_IO_ATTACH_CLIENT(),
+        BAO_IOCTL_IO_REQUEST(),
+        BAO_IOCTL_IO_REQUEST_NOTIFY_COMPLETED(),
+        BAO_IOCTL_IO_NOTIFY_GUEST(),
+        BAO_IOCTL_IOEVENTFD(),
+        BAO_IOCTL_IRQFD(),
+    ]
+}
+
+/// Bao IOCTLs allowed for the device workers.
+fn worker_ioctls() -> Vec<u64> {
+    vec![
+        BAO_IOCTL_IO_REQUEST_NOTIFY_COMPLETED(),
+        BAO_IOCTL_IO_NOTIFY_GUEST(),
+    ]
+}
+
+/// Builds the seccomp BPF program of a profile.
+///
+/// # Arguments
+///
+/// * `profile` - Set of syscalls to allow.
+/// * `mismatch_action` - Action to take on a syscall outside the profile.
+///
+/// # Returns
+///
+/// * `Result<BpfProgram>` - The compiled BPF program.
+pub fn build_seccomp_filter(
+    profile: SeccompProfile,
+    mismatch_action: SeccompAction,
+) -> Result<BpfProgram> {
+    let (syscalls, ioctls): (&[i64], Vec<u64>) = match profile {
+        SeccompProfile::Frontend => (FRONTEND_SYSCALLS, frontend_ioctls()),
+        SeccompProfile::Worker => (&[], worker_ioctls()),
+    };
+
+    // Syscalls allowed unconditionally
+    let mut rules: BTreeMap<i64, Vec<SeccompRule>> = COMMON_SYSCALLS
+        .iter()
+        .chain(syscalls.iter())
+        .map(|syscall| (*syscall, vec![]))
+        .collect();
+
+    // IOCTLs restricted to the Bao request codes
+    let ioctl_rules = ioctls
+        .into_iter()
+        .map(|code| {
+            SeccompRule::new(vec![SeccompCondition::new(
+                1,
+                SeccompCmpArgLen::Dword,
+                SeccompCmpOp::Eq,
+                code,
+            )?])
+        })
+        .collect::<std::result::Result<Vec<_>, _>>()
+        .map_err(|e| Error::SeccompError(e.into()))?;
+    rules.insert(libc::SYS_ioctl, ioctl_rules);
+
+    let filter = SeccompFilter::new(
+        rules,
+        mismatch_action,
+        SeccompAction::Allow,
+        std::env::consts::ARCH
+            .try_into()
+            .map_err(|e: seccompiler::BackendError| Error::SeccompError(e.into()))?,
+    )
+    .map_err(|e| Error::SeccompError(e.into()))?;
+
+    filter
+        .try_into()
+        .map_err(|e: seccompiler::BackendError| Error::SeccompError(e.into()))
+}
+
+/// Installs a seccomp filter on the calling thread.
+///
+/// The filter is inherited by every thread spawned afterwards, so the frontend installs
+/// the `Frontend` profile once initialization is complete (see `enter_sandbox`) and each
+/// device worker narrows it further with the `Worker` profile.
+///
+/// # Arguments
+///
+/// * `profile` - Set of syscalls to allow.
+/// * `mode` - Seccomp mode.
+///
+/// # Returns
+///
+/// * `Result<()>` - Ok if the filter was installed (or seccomp is disabled).
+pub fn apply_seccomp_filter(profile: SeccompProfile, mode: SeccompMode) -> Result<()> {
+    let mismatch_action = match mode {
+        SeccompMode::Off => return Ok(()),
+        SeccompMode::Log => SeccompAction::Log,
+        SeccompMode::Enforce => SeccompAction::KillProcess,
+    };
+    let filter = build_seccomp_filter(profile, mismatch_action)?;
+    Ok(seccompiler::apply_filter(&filter)?)
+}
+
+/// Confines the frontend once its privileged setup is complete.
+///
+/// Meant to be called once the devices, sockets and output files are opened, and before
+/// any guest request is served: switches to the configured unprivileged user (see
+/// `drop_privileges`), then installs the `Frontend` seccomp profile in the configured mode.
+///
+/// # Arguments
+///
+/// * `config` - The frontends configuration.
+///
+/// # Returns
+///
+/// * `Result<()>` - Ok if the frontend is confined (or confinement is disabled).
+pub fn enter_sandbox(config: &ConfigFrontends) -> Result<()> {
+    if let Some(user) = &config.user {
+        drop_privileges(user, config.group.as_deref(), &config.capabilities)?;
+    }
+    apply_seccomp_filter(SeccompProfile::Frontend, config.seccomp)
+}
+
+/// Linux capability header version 3 (64-bit capability sets).
+const LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+/// Struct representing the `capset` header (`struct __user_cap_header_struct`).
+#[repr(C)]
+struct CapUserHeader {
+    version: u32,
+    pid: i32,
+}
+
+/// Struct representing the `capset` data (`struct __user_cap_data_struct`).
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct CapUserData {
+    effective: u32,
+    permitted: u32,
+    inheritable: u32,
+}
+
+/// Returns the size of the buffer used by the reentrant `getpw*`/`getgr*` functions.
+fn passwd_buffer_size() -> usize {
+    // SAFETY: `sysconf` has no memory safety requirements.
+    match unsafe { libc::sysconf(libc::_SC_GETPW_R_SIZE_MAX) } {
+        size if size > 0 => size as usize,
+        _ => 16384,
+    }
+}
+
+/// Resolves a user name (or numeric ID) into its user and primary group IDs.
+///
+/// # Arguments
+///
+/// * `user` - User name or numeric user ID.
+///
+/// # Returns
+///
+/// * `Result<(libc::uid_t, Option<libc::gid_t>)>` - The user ID and its primary group ID
+///   (unknown for a numeric ID without a passwd entry).
+fn lookup_user(user: &str) -> Result<(libc::uid_t, Option<libc::gid_t>)> {
+    let name = CString::new(user).map_err(|_| Error::UserNotFound(user.to_string()))?;
+    let mut buf = vec![0 as libc::c_char; passwd_buffer_size()];
+    // SAFETY: `passwd` is plain old data.
+    let mut passwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    // SAFETY: All pointers are valid for the duration of the call.
+    unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut passwd,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if !result.is_null() {
+        return Ok((passwd.pw_uid, Some(passwd.pw_gid)));
+    }
+    // Fall back to a numeric ID, taking its primary group from its passwd entry (if any)
+    let uid = user
+        .parse::<u32>()
+        .map_err(|_| Error::UserNotFound(user.to_string()))?;
+    // SAFETY: All pointers are valid for the duration of the call.
+    unsafe { libc::getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+    Ok((uid, (!result.is_null()).then_some(passwd.pw_gid)))
+}
+
+/// Resolves a group name (or numeric ID) into its group ID.
+///
+/// # Arguments
+///
+/// * `group` - Group name or numeric group ID.
+///
+/// # Returns
+///
+/// * `Result<libc::gid_t>` - The group ID.
+fn lookup_group(group: &str) -> Result<libc::gid_t> {
+    let name = CString::new(group).map_err(|_| Error::GroupNotFound(group.to_string()))?;
+    let mut buf = vec![0 as libc::c_char; passwd_buffer_size()];
+    // SAFETY: `group` is plain old data.
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut result = std::ptr::null_mut();
+    // SAFETY: All pointers are valid for the duration of the call.
+    unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut grp,
+            buf.as_mut_ptr(),
+            buf.len(),
+            &mut result,
+        )
+    };
+    if !result.is_null() {
+        return Ok(grp.gr_gid);
+    }
+    // Fall back to a numeric ID
+    group
+        .parse::<u32>()
+        .map_err(|_| Error::GroupNotFound(group.to_string()))
+}
+
+/// Converts the return value of a libc call into a `Result`.
+fn check_libc(ret: libc::c_int, call: &'static str) -> Result<()> {
+    if ret < 0 {
+        return Err(Error::DropPrivilegesFailed(
+            call,
+            io::Error::last_os_error(),
+        ));
+    }
+    Ok(())
+}
+
+/// Drops the root privileges of the frontend.
+///
+/// Meant to be called once `/dev/bao` is opened, guest memory is mapped and the sockets
+/// are bound. Switches to an unprivileged user and group, keeping only the requested
+/// capabilities in the effective and permitted sets.
+///
+/// # Arguments
+///
+/// * `user` - User name (or numeric ID) to switch to.
+/// * `group` - Group name (or numeric ID) to switch to (defaults to the user's primary group,
+///   required for a numeric user without a passwd entry).
+/// * `capabilities` - Capabilities to retain.
+///
+/// # Returns
+///
+/// * `Result<()>` - Ok if the privileges were dropped.
+pub fn drop_privileges(user: &str, group: Option<&str>, capabilities: &[Capability]) -> Result<()> {
+    // Resolve the user and group IDs
+    let (uid, primary_gid) = lookup_user(user)?;
+    let gid = match (group, primary_gid) {
+        (Some(group), _) => lookup_group(group)?,
+        (None, Some(gid)) => gid,
+        (None, None) => return Err(Error::GroupNotFound(format!("primary group of {}", user))),
+    };
+
+    // Keep the permitted capabilities across the UID change
+    if !capabilities.is_empty() {
+        // SAFETY: `prctl` with PR_SET_KEEPCAPS has no memory safety requirements.
+        check_libc(
+            unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) },
+            "prctl",
+        )?;
+    }
+
+    // Switch the supplementary groups, the group and the user (in this order)
+    // SAFETY: The group list is valid for the duration of the call.
+    check_libc(unsafe { libc::setgroups(1, &gid) }, "setgroups")?;
+    // SAFETY: `setgid` has no memory safety requirements.
+    check_libc(unsafe { libc::setgid(gid) }, "setgid")?;
+    // SAFETY: `setuid` has no memory safety requirements.
+    check_libc(unsafe { libc::setuid(uid) }, "setuid")?;
+
+    if capabilities.is_empty() {
+        return Ok(());
+    }
+
+    // Restore the retained capabilities in the effective set (cleared by setuid)
+    let mut data = [CapUserData::default(); 2];
+    for capability in capabilities {
+        let bit = capability.number();
+        let set = &mut data[(bit / 32) as usize];
+        set.effective |= 1 << (bit % 32);
+        set.permitted |= 1 << (bit % 32);
+    }
+    let mut header = CapUserHeader {
+        version: LINUX_CAPABILITY_VERSION_3,
+        pid: 0,
+    };
+    // SAFETY: The header and the two data structs are valid for the duration of the call.
+    let ret = unsafe { libc::syscall(libc::SYS_capset, &mut header, data.as_ptr()) };
+    check_libc(ret as libc::c_int, "capset")?;
+    // SAFETY: `prctl` with PR_SET_KEEPCAPS has no memory safety requirements.
+    check_libc(
+        unsafe { libc::prctl(libc::PR_SET_KEEPCAPS, 0, 0, 0, 0) },
+        "prctl",
+    )
+}
+
+/// Restricts the filesystem access of the calling thread to a set of paths.
+///
+/// Uses Landlock (best-effort, depending on the running kernel), so every thread spawned
+/// afterwards inherits the restriction. Called at the start of an in-process device
+/// backend thread (see `spawn_in_process_backend`, used by the frontend runtime to bring
+/// the devices up), with the paths declared in the device configuration.
+///
+/// # Arguments
+///
+/// * `paths` - Paths the thread is allowed to access (and everything beneath them).
+///
+/// # Returns
+///
+/// * `Result<bool>` - True if the restriction is enforced, false if the kernel lacks Landlock.
+pub fn restrict_paths(paths: &[ConfigAllowedPath]) -> Result<bool> {
+    let abi = ABI::V2;
+    let read_only = paths.iter().filter(|p| p.read_only).map(|p| &p.path);
+    let read_write = paths.iter().filter(|p| !p.read_only).map(|p| &p.path);
+
+    let status = Ruleset::default()
+        .handle_access(AccessFs::from_all(abi))
+        .and_then(|ruleset| ruleset.create())
+        .and_then(|ruleset| {
+            ruleset.add_rules(path_beneath_rules(read_only, AccessFs::from_read(abi)))
+        })
+        .and_then(|ruleset| {
+            ruleset.add_rules(path_beneath_rules(read_write, AccessFs::from_all(abi)))
+        })
+        .and_then(|ruleset| ruleset.restrict_self())?;
+
+    Ok(status.ruleset != RulesetStatus::NotEnforced)
+}
+
+/// Spawns a device backend in its own child process.
+///
+/// The child inherits one end of a private socket pair as file descriptor
+/// `BAO_ISOLATION_CHANNEL_FD` (advertised in the `BAO_ISOLATION_CHANNEL_ENV` environment
+/// variable), so a crash in the device implementation only takes down its own process.
+///
+/// # Arguments
+///
+/// * `command` - Command that runs the device backend.
+///
+/// # Returns
+///
+/// * `Result<(Child, UnixStream)>` - The child process and the frontend end of the channel.
+pub fn spawn_isolated_backend(mut command: Command) -> Result<(Child, UnixStream)> {
+    let (frontend, backend) =
+        UnixStream::pair().map_err(|e| Error::SpawnBackendFailed("socketpair", e))?;
+    let backend_fd = backend.as_raw_fd();
+
+    // SAFETY: Only async-signal-safe functions are called between fork and exec.
+    unsafe {
+        command.pre_exec(move || {
+            // Move the channel to its well-known descriptor (dup2 clears FD_CLOEXEC, but is
+            // a no-op if the channel already is there)
+            if backend_fd != BAO_ISOLATION_CHANNEL_FD {
+                if libc::dup2(backend_fd, BAO_ISOLATION_CHANNEL_FD) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                return Ok(());
+            }
+            let flags = libc::fcntl(backend_fd, libc::F_GETFD);
+            if flags < 0 || libc::fcntl(backend_fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        })
+    };
+    let child = command
+        .env(
+            BAO_ISOLATION_CHANNEL_ENV,
+            BAO_ISOLATION_CHANNEL_FD.to_string(),
+        )
+        .spawn()
+        .map_err(|e| Error::SpawnBackendFailed("spawn", e))?;
+
+    Ok((child, frontend))
+}
+
+/// Returns the channel to the frontend, when running as an isolated device backend.
+///
+/// # Returns
+///
+/// * `Option<UnixStream>` - The channel, if the process was spawned by `spawn_isolated_backend`.
+pub fn isolated_backend_channel() -> Option<UnixStream> {
+    let fd = std::env::var(BAO_ISOLATION_CHANNEL_ENV)
+        .ok()?
+        .parse::<i32>()
+        .ok()?;
+    std::env::remove_var(BAO_ISOLATION_CHANNEL_ENV);
+    // SAFETY: The descriptor was set up by the frontend and is owned by this process.
+    Some(unsafe { UnixStream::from_raw_fd(fd) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_build_seccomp_filter() {
+        for profile in [SeccompProfile::Frontend, SeccompProfile::Worker] {
+            let filter = build_seccomp_filter(profile, SeccompAction::KillProcess).unwrap();
+            assert!(!filter.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_lookup_user_and_group() {
+        assert_eq!(lookup_user("root").unwrap(), (0, Some(0)));
+        assert_eq!(lookup_user("0").unwrap(), (0, Some(0)));
+        assert_eq!(lookup_user("3999999").unwrap(), (3999999, None));
+        assert!(lookup_user("bao-nonexistent-user").is_err());
+        assert_eq!(lookup_group("4321").unwrap(), 4321);
+        assert!(lookup_group("bao-nonexistent-group").is_err());
+    }
+
+    #[test]
+    fn test_spawn_isolated_backend() {
+        use std::io::Read;
+
+        let mut command = Command::new("sh");
+        command.args(["-c", "echo $BAO_ISOLATION_CHANNEL_FD >&3"]);
+        let (mut child, mut channel) = spawn_isolated_backend(command).unwrap();
+        assert!(child.wait().unwrap().success());
+
+        channel.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut out = String::new();
+        channel.read_to_string(&mut out).unwrap();
+        assert_eq!(out.trim(), BAO_ISOLATION_CHANNEL_FD.to_string());
+    }
+}
@@ -0,0 +1,435 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao device worker scheduling.
+//!
+//! Under the default time-sharing scheduler, I/O workers compete with every other task
+//! of a loaded host and miss the guest deadlines. A guest may instead run its device
+//! workers in a real-time class (SCHED_FIFO or SCHED_RR); the kernel real-time
+//! throttling (`sched_rt_runtime_us`) still keeps a runaway worker from starving the host.
+//!
+//! The workers may also be pinned to CPUs of their own. A pinning the host cannot honor
+//! (CPUs that do not exist or are offline, or that were meant to be isolated from the
+//! host scheduler but are not) only shows up as latency later on, so it is checked
+//! against the host CPUs at startup and warned about.
+
+#![allow(dead_code)]
+
+use super::error::{Error, Result};
+use super::types::{ConfigSched, SchedPolicy};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+impl SchedPolicy {
+    /// Returns the Linux scheduling policy.
+    ///
+    /// # Returns
+    ///
+    /// * `libc::c_int` - The policy (as defined in `linux/sched.h`).
+    pub fn number(&self) -> libc::c_int {
+        match self {
+            SchedPolicy::Other => libc::SCHED_OTHER,
+            SchedPolicy::Fifo => libc::SCHED_FIFO,
+            SchedPolicy::Rr => libc::SCHED_RR,
+        }
+    }
+}
+
+/// Converts the return value of a libc call into a `Result`.
+fn check_libc(ret: libc::c_int, call: &'static str) -> Result<()> {
+    if ret < 0 {
+        return Err(Error::SchedulingFailed(call, io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// Checks the priority of a scheduling configuration.
+///
+/// # Arguments
+///
+/// * `sched` - The scheduling configuration.
+///
+/// # Returns
+///
+/// * `Result<libc::c_int>` - The priority to apply (zero for `other`).
+pub fn sched_priority(sched: &ConfigSched) -> Result<libc::c_int> {
+    let (policy, name) = match sched.policy {
+        SchedPolicy::Other => return Ok(0),
+        SchedPolicy::Fifo => (libc::SCHED_FIFO, "fifo"),
+        SchedPolicy::Rr => (libc::SCHED_RR, "rr"),
+    };
+    // SAFETY: `sched_get_priority_*` have no memory safety requirements.
+    let (min, max) = unsafe {
+        (
+            libc::sched_get_priority_min(policy),
+            libc::sched_get_priority_max(policy),
+        )
+    };
+    match libc::c_int::try_from(sched.priority) {
+        Ok(priority) if (min..=max).contains(&priority) => Ok(priority),
+        _ => Err(Error::InvalidSchedPriority(name, sched.priority)),
+    }
+}
+
+/// Parses a CPU list, as written by the kernel (e.g. `0-3,6`).
+///
+/// # Arguments
+///
+/// * `list` - The CPU list (empty for no CPUs).
+///
+/// # Returns
+///
+/// * `Option<BTreeSet<usize>>` - The CPUs, or None if the list is malformed.
+pub fn parse_cpu_list(list: &str) -> Option<BTreeSet<usize>> {
+    let mut cpus = BTreeSet::new();
+    for range in list.trim().split(',').filter(|range| !range.is_empty()) {
+        let (first, last): (usize, usize) = match range.split_once('-') {
+            Some((first, last)) => (first.trim().parse().ok()?, last.trim().parse().ok()?),
+            None => {
+                let cpu = range.trim().parse().ok()?;
+                (cpu, cpu)
+            }
+        };
+        if first > last {
+            return None;
+        }
+        cpus.extend(first..=last);
+    }
+    Some(cpus)
+}
+
+/// Checks the CPUs of a scheduling configuration.
+///
+/// # Arguments
+///
+/// * `sched` - The scheduling configuration.
+///
+/// # Returns
+///
+/// * `Result<Option<BTreeSet<usize>>>` - The CPUs the workers are pinned to (None if they
+///   are not), or `InvalidSchedCpus`.
+pub fn sched_cpus(sched: &ConfigSched) -> Result<Option<BTreeSet<usize>>> {
+    let list = match &sched.cpus {
+        Some(list) => list,
+        None => return Ok(None),
+    };
+    match parse_cpu_list(list) {
+        Some(cpus)
+            if !cpus.is_empty() && cpus.iter().all(|cpu| *cpu < libc::CPU_SETSIZE as usize) =>
+        {
+            Ok(Some(cpus))
+        }
+        _ => Err(Error::InvalidSchedCpus(list.clone())),
+    }
+}
+
+/// Struct representing the CPUs of the host.
+///
+/// # Attributes
+///
+/// * `present` - CPUs that exist.
+/// * `online` - CPUs that are online.
+/// * `isolated` - CPUs isolated from the scheduler (`isolcpus`).
+/// * `nohz_full` - CPUs running without the scheduler tick (`nohz_full`).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct HostCpus {
+    pub present: BTreeSet<usize>,
+    pub online: BTreeSet<usize>,
+    pub isolated: BTreeSet<usize>,
+    pub nohz_full: BTreeSet<usize>,
+}
+
+impl HostCpus {
+    /// Reads the CPUs of the host.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The sysfs CPU directory (`BAO_SYSFS_CPU_DIR`).
+    ///
+    /// # Returns
+    ///
+    /// * `HostCpus` - The CPUs. The lists missing or unreadable (e.g. `nohz_full` on a
+    ///   kernel built without it) are empty.
+    pub fn read(dir: &Path) -> Self {
+        let list = |name: &str| {
+            fs::read_to_string(dir.join(name))
+                .ok()
+                .and_then(|list| parse_cpu_list(&list))
+                .unwrap_or_default()
+        };
+        HostCpus {
+            present: list("present"),
+            online: list("online"),
+            isolated: list("isolated"),
+            nohz_full: list("nohz_full"),
+        }
+    }
+
+    /// Checks the CPUs of a scheduling configuration against the host.
+    ///
+    /// # Arguments
+    ///
+    /// * `sched` - The scheduling configuration.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<String>>` - A description of every problem found (none if the
+    ///   workers are not pinned), or `InvalidSchedCpus`.
+    pub fn check(&self, sched: &ConfigSched) -> Result<Vec<String>> {
+        let cpus = match sched_cpus(sched)? {
+            Some(cpus) => cpus,
+            None => return Ok(Vec::new()),
+        };
+        let list = |cpus: Vec<&usize>| {
+            cpus.iter()
+                .map(|cpu| cpu.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        let mut problems = Vec::new();
+        let missing = cpus.difference(&self.present).collect::<Vec<_>>();
+        if !missing.is_empty() {
+            problems.push(format!("pinned CPUs {} do not exist", list(missing)));
+        }
+        let offline = cpus
+            .intersection(&self.present)
+            .filter(|cpu| !self.online.contains(cpu))
+            .collect::<Vec<_>>();
+        if !offline.is_empty() {
+            problems.push(format!("pinned CPUs {} are offline", list(offline)));
+        }
+        let shared = cpus
+            .iter()
+            .filter(|cpu| !self.isolated.contains(cpu) && !self.nohz_full.contains(cpu))
+            .collect::<Vec<_>>();
+        if sched.isolated && !shared.is_empty() {
+            problems.push(format!(
+                "pinned CPUs {} are in neither isolcpus nor nohz_full",
+                list(shared)
+            ));
+        }
+        Ok(problems)
+    }
+}
+
+/// Pins the calling thread to CPUs.
+fn set_affinity(cpus: &BTreeSet<usize>) -> Result<()> {
+    // SAFETY: An all-zero `cpu_set_t` is an empty set.
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for cpu in cpus {
+        // SAFETY: The CPUs are below CPU_SETSIZE (checked by `sched_cpus`).
+        unsafe { libc::CPU_SET(*cpu, &mut set) };
+    }
+    // SAFETY: The set is valid for the duration of the call.
+    check_libc(
+        unsafe { libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) },
+        "sched_setaffinity",
+    )
+}
+
+/// Raises the real-time priority soft limit (RLIMIT_RTPRIO) up to a priority.
+///
+/// The soft limit can be raised up to the hard limit without privileges. A limit
+/// below the priority is not an error, as CAP_SYS_NICE bypasses it.
+fn raise_rtprio_limit(priority: libc::c_int) -> Result<()> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // SAFETY: The limit is valid for the duration of the call.
+    check_libc(
+        unsafe { libc::getrlimit(libc::RLIMIT_RTPRIO, &mut limit) },
+        "getrlimit",
+    )?;
+    let wanted = priority as libc::rlim_t;
+    if limit.rlim_cur >= wanted || limit.rlim_cur >= limit.rlim_max {
+        return Ok(());
+    }
+    limit.rlim_cur = wanted.min(limit.rlim_max);
+    // SAFETY: The limit is valid for the duration of the call.
+    check_libc(
+        unsafe { libc::setrlimit(libc::RLIMIT_RTPRIO, &limit) },
+        "setrlimit",
+    )
+}
+
+/// Applies the scheduling of a guest to the calling thread.
+///
+/// Meant to be called at the start of each device worker of the guest, before its
+/// seccomp filter is installed. The worker is marked SCHED_RESET_ON_FORK, so threads and
+/// processes it spawns fall back to the default scheduler. If the frontend lacks the
+/// privilege to use a real-time class (neither CAP_SYS_NICE nor a large enough
+/// RLIMIT_RTPRIO), the worker safely keeps the default scheduler. The worker is pinned
+/// to the configured CPUs first, failing if none of them is usable.
+///
+/// # Arguments
+///
+/// * `sched` - The scheduling configuration.
+///
+/// # Returns
+///
+/// * `Result<bool>` - True if the scheduling was applied, false if it was not permitted.
+pub fn apply_sched(sched: &ConfigSched) -> Result<bool> {
+    let priority = sched_priority(sched)?;
+    if let Some(cpus) = sched_cpus(sched)? {
+        set_affinity(&cpus)?;
+    }
+    if priority > 0 {
+        raise_rtprio_limit(priority)?;
+    }
+
+    let param = libc::sched_param {
+        sched_priority: priority,
+    };
+    // SAFETY: The parameters are valid for the duration of the call.
+    let ret = unsafe {
+        libc::sched_setscheduler(0, sched.policy.number() | libc::SCHED_RESET_ON_FORK, &param)
+    };
+    match check_libc(ret, "sched_setscheduler") {
+        Ok(()) => Ok(true),
+        Err(Error::SchedulingFailed(_, e)) if e.raw_os_error() == Some(libc::EPERM) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_sched_config() {
+        let sched: ConfigSched = serde_yaml::from_str("{policy: fifo, priority: 20}").unwrap();
+        assert_eq!(sched.policy, SchedPolicy::Fifo);
+        assert_eq!(sched_priority(&sched).unwrap(), 20);
+        assert_eq!(sched.cpus, None);
+        let sched: ConfigSched = serde_yaml::from_str("{cpus: 6, isolated: true}").unwrap();
+        assert_eq!((sched.cpus.as_deref(), sched.isolated), (Some("6"), true));
+        let sched: ConfigSched = serde_yaml::from_str("{cpus: 2-3}").unwrap();
+        assert_eq!(sched.cpus.as_deref(), Some("2-3"));
+
+        for priority in [0, 100] {
+            let sched = ConfigSched {
+                policy: SchedPolicy::Rr,
+                priority,
+                ..Default::default()
+            };
+            assert!(matches!(
+                sched_priority(&sched),
+                Err(Error::InvalidSchedPriority("rr", p)) if p == priority
+            ));
+        }
+        let sched = ConfigSched {
+            policy: SchedPolicy::Other,
+            priority: 100,
+            ..Default::default()
+        };
+        assert_eq!(sched_priority(&sched).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_sched_cpus() {
+        assert_eq!(parse_cpu_list(""), Some(BTreeSet::new()));
+        assert_eq!(
+            parse_cpu_list("0-2,5\n"),
+            Some(BTreeSet::from([0, 1, 2, 5]))
+        );
+        for list in ["1-", "3-1", "a", "0,,x"] {
+            assert_eq!(parse_cpu_list(list), None, "{}", list);
+        }
+
+        let sched = |cpus: &str, isolated| ConfigSched {
+            cpus: Some(cpus.to_string()),
+            isolated,
+            ..Default::default()
+        };
+        assert_eq!(sched_cpus(&ConfigSched::default()).unwrap(), None);
+        for cpus in ["", "2-", "4096"] {
+            assert!(matches!(
+                sched_cpus(&sched(cpus, false)),
+                Err(Error::InvalidSchedCpus(list)) if list == cpus
+            ));
+        }
+
+        // A host of 4 CPUs, CPU 3 offline, CPU 1 isolated and CPU 2 tickless
+        let dir = std::env::temp_dir().join(format!("bao-sched-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (name, list) in [
+            ("present", "0-3\n"),
+            ("online", "0-2\n"),
+            ("isolated", "1\n"),
+        ] {
+            fs::write(dir.join(name), list).unwrap();
+        }
+        let host = HostCpus::read(&dir);
+        assert_eq!(host.nohz_full, BTreeSet::new());
+        fs::write(dir.join("nohz_full"), "2\n").unwrap();
+        let host = HostCpus::read(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(host.present, BTreeSet::from([0, 1, 2, 3]));
+        assert_eq!(host.nohz_full, BTreeSet::from([2]));
+
+        assert!(host.check(&ConfigSched::default()).unwrap().is_empty());
+        assert!(host.check(&sched("0-2", false)).unwrap().is_empty());
+        assert!(host.check(&sched("1-2", true)).unwrap().is_empty());
+        assert_eq!(
+            host.check(&sched("0-1,3-5", true)).unwrap(),
+            [
+                "pinned CPUs 4,5 do not exist",
+                "pinned CPUs 3 are offline",
+                "pinned CPUs 0,3,4,5 are in neither isolcpus nor nohz_full",
+            ]
+        );
+        assert!(host.check(&sched("x", false)).is_err());
+    }
+
+    #[test]
+    fn test_apply_sched() {
+        // Run in a thread of its own, the scheduling is per thread
+        thread::spawn(|| {
+            let sched = ConfigSched {
+                policy: SchedPolicy::Fifo,
+                priority: 1,
+                ..Default::default()
+            };
+            let expected = match apply_sched(&sched).unwrap() {
+                true => libc::SCHED_FIFO,
+                false => libc::SCHED_OTHER,
+            };
+            // SAFETY: `sched_getscheduler` has no memory safety requirements.
+            let policy = unsafe { libc::sched_getscheduler(0) };
+            assert_eq!(policy & !libc::SCHED_RESET_ON_FORK, expected);
+
+            assert!(apply_sched(&ConfigSched::default()).unwrap());
+            // SAFETY: `sched_getscheduler` has no memory safety requirements.
+            let policy = unsafe { libc::sched_getscheduler(0) };
+            assert_eq!(policy & !libc::SCHED_RESET_ON_FORK, libc::SCHED_OTHER);
+
+            // Pinned to the CPU it runs on, which is online
+            // SAFETY: `sched_getcpu` has no memory safety requirements.
+            let cpu = unsafe { libc::sched_getcpu() };
+            let sched = ConfigSched {
+                cpus: Some(cpu.to_string()),
+                ..Default::default()
+            };
+            assert!(apply_sched(&sched).unwrap());
+            // SAFETY: An all-zero `cpu_set_t` is an empty set.
+            let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+            // SAFETY: The set is valid for the duration of the call.
+            let ret = unsafe {
+                libc::sched_getaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &mut set)
+            };
+            assert_eq!(ret, 0);
+            // SAFETY: The CPU is below CPU_SETSIZE.
+            assert!(unsafe { libc::CPU_ISSET(cpu as usize, &set) });
+            // SAFETY: `CPU_COUNT` only reads the set.
+            assert_eq!(unsafe { libc::CPU_COUNT(&set) }, 1);
+        })
+        .join()
+        .unwrap();
+    }
+}
@@ -0,0 +1,161 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao configuration secrets.
+//!
+//! Backend options holding secrets (e.g. the key of an encrypted block image or network
+//! credentials) are written as `secret://` references, resolved at startup, so the
+//! secrets never appear in the configuration file baked into images:
+//!
+//! * `secret:///etc/bao/disk.key` - A file readable only by its owner, root or the
+//!   frontend user.
+//! * `secret://disk-key` - A systemd credential (`LoadCredential=disk-key:...`), read from
+//!   `$CREDENTIALS_DIRECTORY`.
+
+#![allow(dead_code)]
+
+use super::defines::{BAO_CREDENTIALS_DIRECTORY_ENV, BAO_SECRET_SCHEME};
+use super::error::{Error, Result};
+use super::types::ConfigFrontends;
+use std::env;
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Returns the file holding a secret.
+///
+/// # Arguments
+///
+/// * `reference` - The secret reference, without its scheme.
+///
+/// # Returns
+///
+/// * `Result<PathBuf>` - The file (absolute references are files, others are systemd
+///   credentials).
+fn secret_path(reference: &str) -> Result<PathBuf> {
+    if reference.starts_with('/') {
+        return Ok(PathBuf::from(reference));
+    }
+    // Credential names cannot escape the credentials directory
+    if reference.is_empty() || reference.contains('/') || reference == ".." {
+        return Err(Error::SecretFailed(
+            reference.to_string(),
+            io::Error::from(io::ErrorKind::InvalidInput),
+        ));
+    }
+    match env::var_os(BAO_CREDENTIALS_DIRECTORY_ENV) {
+        Some(directory) => Ok(Path::new(&directory).join(reference)),
+        None => Err(Error::SecretFailed(
+            reference.to_string(),
+            io::Error::new(io::ErrorKind::NotFound, "no systemd credentials directory"),
+        )),
+    }
+}
+
+/// Reads a secret.
+///
+/// # Arguments
+///
+/// * `reference` - The secret reference, without its scheme.
+///
+/// # Returns
+///
+/// * `Result<String>` - The secret (without its trailing newline).
+pub fn read_secret(reference: &str) -> Result<String> {
+    let path = secret_path(reference)?;
+    let failed = |e| Error::SecretFailed(reference.to_string(), e);
+    let metadata = fs::metadata(&path).map_err(failed)?;
+
+    // Refuse secrets other users may read (or replace)
+    // SAFETY: `geteuid` has no memory safety requirements.
+    let euid = unsafe { libc::geteuid() };
+    if (metadata.uid() != 0 && metadata.uid() != euid) || metadata.mode() & 0o077 != 0 {
+        return Err(Error::InsecureSecret(reference.to_string()));
+    }
+
+    let secret = fs::read_to_string(&path).map_err(failed)?;
+    Ok(secret.strip_suffix('\n').unwrap_or(&secret).to_string())
+}
+
+/// Resolves the `secret://` references of the device backend options.
+///
+/// # Arguments
+///
+/// * `config` - The frontends configuration.
+///
+/// # Returns
+///
+/// * `Result<()>` - Ok if every reference was resolved.
+pub fn resolve_secrets(config: &mut ConfigFrontends) -> Result<()> {
+    let options = config
+        .frontends
+        .iter_mut()
+        .flat_map(|f| &mut f.guests)
+        .flat_map(|g| &mut g.devices)
+        .flat_map(|d| d.options.values_mut());
+    for value in options {
+        if let Some(reference) = value.strip_prefix(BAO_SECRET_SCHEME) {
+            *value = read_secret(reference)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn test_resolve_secrets() {
+        let dir = env::temp_dir().join(format!("bao-secrets-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let key = dir.join("disk.key");
+        fs::write(&key, "s3cr3t\n").unwrap();
+        fs::set_permissions(&key, fs::Permissions::from_mode(0o600)).unwrap();
+
+        let mut config: ConfigFrontends = serde_yaml::from_str(&format!(
+            "frontends:
+  - name: frontend0
+    id: 0
+    guests:
+      - name: guest0
+        id: 1
+        ram_addr: 0x50000000
+        ram_size: 0x1000000
+        shmem_path: /dev/baoipc0
+        socket_path: /tmp/
+        devices:
+          - name: blk0
+            id: 2
+            type: block
+            irq: 47
+            addr: 0xa003e00
+            options: {{image: /var/lib/bao/disk.img, key: 'secret://{}'}}
+",
+            key.display()
+        ))
+        .unwrap();
+        resolve_secrets(&mut config).unwrap();
+        let options = &config.frontends[0].guests[0].devices[0].options;
+        assert_eq!(options["key"], "s3cr3t");
+        assert_eq!(options["image"], "/var/lib/bao/disk.img");
+
+        // Secrets other users can read are refused
+        fs::set_permissions(&key, fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(matches!(
+            read_secret(key.to_str().unwrap()),
+            Err(Error::InsecureSecret(_))
+        ));
+
+        // Credential names cannot escape the credentials directory
+        assert!(matches!(
+            read_secret("../disk.key"),
+            Err(Error::SecretFailed(..))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
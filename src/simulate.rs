@@ -0,0 +1,1626 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao simulation mode.
+//!
+//! Runs the configured devices without the Bao kernel module: each guest gets an
+//! in-memory RAM buffer and a mock hypervisor, every device a virtio-mmio register model,
+//! and a scripted driver initializes the devices and serves synthetic queue traffic.
+//! The simulation is deterministic (no randomness and no timing); stress tests drive the
+//! same models with configurable load and measure it.
+//!
+//! Devices of the `loopback` type echo their buffers: the device-readable part of every
+//! descriptor chain is copied to its device-writable part. The self-test drives such a
+//! device through the same transport, ring and interrupt path as any other, and checks
+//! every echoed byte.
+//!
+//! Devices with `max_inflight` have their requests served through an in-flight limiter.
+//! With an audit log, the served requests are audited (see `AuditHypervisor`).
+
+#![allow(dead_code)]
+
+use super::audit::{AuditHypervisor, AuditLog};
+use super::bus::BaoMmioBus;
+use super::defines::*;
+use super::error::{Error, ErrorContext, Result, ResultExt};
+use super::hypervisor::Hypervisor;
+use super::inflight::{InflightLimitHypervisor, InflightLimiter};
+use super::memory::{GuestMemory, Le16, Le32, Le64, VirtqDesc, VirtqUsedElem};
+use super::pause::{Pause, PauseMode};
+use super::record::{Recorder, RecordingHypervisor};
+use super::snapshot::{DeviceSnapshot, QueueState, Snapshot, TransportState};
+use super::summary::{DeviceSummary, MemoryRegion};
+use super::testing::{MockHypervisor, ScriptedDriver};
+use super::trace::{TracePhase, TraceSink, TracingHypervisor};
+use super::types::{
+    check_queue_num, ConfigDevice, ConfigFrontends, ConfigGuest, DeviceId, GuestAddress,
+    SelfTestOptions, StressOptions, VmId,
+};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+use vm_device::bus::{MmioAddress, MmioAddressOffset};
+use vm_device::MutDeviceMmio;
+
+/// Struct representing the RAM of a simulated guest.
+///
+/// # Attributes
+///
+/// * `base` - Guest physical address of the RAM.
+/// * `bytes` - RAM contents.
+pub struct GuestRam {
+    base: GuestAddress,
+    bytes: Vec<u8>,
+}
+
+impl GuestRam {
+    /// Creates a zeroed guest RAM.
+    ///
+    /// # Arguments
+    ///
+    /// * `base` - Guest physical address of the RAM.
+    /// * `size` - RAM size.
+    ///
+    /// # Returns
+    ///
+    /// * `GuestRam` - The guest RAM.
+    pub fn new(base: GuestAddress, size: usize) -> Self {
+        GuestRam {
+            base,
+            bytes: vec![0; size],
+        }
+    }
+
+    /// Returns the RAM bytes of an address range, if it lies within the RAM.
+    fn range(&self, addr: u64, len: usize) -> Option<std::ops::Range<usize>> {
+        let start = usize::try_from(addr.checked_sub(self.base.raw())?).ok()?;
+        let end = start.checked_add(len)?;
+        (end <= self.bytes.len()).then_some(start..end)
+    }
+
+    /// Reads bytes.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Guest physical address.
+    /// * `len` - Number of bytes.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<&[u8]>` - The bytes, or None if the range is outside the RAM.
+    pub fn read_bytes(&self, addr: u64, len: usize) -> Option<&[u8]> {
+        let range = self.range(addr, len)?;
+        Some(&self.bytes[range])
+    }
+
+    /// Reads a little-endian u16.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Guest physical address.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<u16>` - The value, or None if the address is outside the RAM.
+    pub fn read_u16(&self, addr: u64) -> Option<u16> {
+        let range = self.range(addr, 2)?;
+        Some(u16::from_le_bytes(self.bytes[range].try_into().unwrap()))
+    }
+
+    /// Writes bytes.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Guest physical address.
+    /// * `data` - The bytes.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<()>` - None if the range is outside the RAM.
+    pub fn write_bytes(&mut self, addr: u64, data: &[u8]) -> Option<()> {
+        let range = self.range(addr, data.len())?;
+        self.bytes[range].copy_from_slice(data);
+        Some(())
+    }
+
+    /// Writes a little-endian u16.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Guest physical address.
+    /// * `value` - The value.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<()>` - None if the address is outside the RAM.
+    pub fn write_u16(&mut self, addr: u64, value: u16) -> Option<()> {
+        let range = self.range(addr, 2)?;
+        self.bytes[range].copy_from_slice(&value.to_le_bytes());
+        Some(())
+    }
+}
+
+impl GuestMemory for GuestRam {
+    fn read_slice(&self, addr: u64, buf: &mut [u8]) -> Result<()> {
+        let bytes = self
+            .read_bytes(addr, buf.len())
+            .ok_or(Error::InvalidGuestAddress(addr, buf.len()))?;
+        buf.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn write_slice(&mut self, addr: u64, buf: &[u8]) -> Result<()> {
+        self.write_bytes(addr, buf)
+            .ok_or(Error::InvalidGuestAddress(addr, buf.len()))
+    }
+}
+
+/// Struct representing the state of a simulated virtqueue.
+#[derive(Debug, Clone, Copy, Default)]
+struct SimulatedQueue {
+    num: u32,
+    ready: u32,
+    desc: u64,
+    avail: u64,
+    used: u64,
+    last_avail: u16,
+}
+
+/// Struct representing a simulated virtio-mmio device.
+///
+/// Every notified queue has its available buffers consumed at once (the used index
+/// catches up with the available index) and raises a used buffer interrupt. Echoing
+/// devices walk the descriptor chains instead, copying their readable buffers to their
+/// writable ones, and report the copied lengths in the used ring.
+///
+/// # Attributes
+///
+/// * `device_id` - Virtio device ID.
+/// * `status` - Device status.
+/// * `device_features_sel` - Device features word selected by the driver.
+/// * `driver_features_sel` - Driver features word selected by the driver.
+/// * `driver_features` - Features accepted by the driver.
+/// * `queue_sel` - Queue selected by the driver.
+/// * `queues` - Virtqueues.
+/// * `queue_num_max` - Maximum queue size advertised to the driver.
+/// * `interrupt_status` - Pending interrupts.
+/// * `notifications` - Number of queue notifications served.
+/// * `echo` - Whether the device echoes its buffers (loopback device).
+/// * `features` - Device-specific features offered besides `VIRTIO_F_VERSION_1`.
+/// * `config` - Device configuration space.
+/// * `paused` - How the device is paused (None if it is running).
+/// * `held` - Queues notified while the device was paused.
+/// * `ram` - Guest RAM.
+/// * `hypervisor` - Hypervisor injecting the interrupts.
+pub struct SimulatedDevice {
+    device_id: u32,
+    status: u32,
+    device_features_sel: u32,
+    driver_features_sel: u32,
+    driver_features: u64,
+    queue_sel: u32,
+    queues: Vec<SimulatedQueue>,
+    queue_num_max: u16,
+    interrupt_status: u32,
+    notifications: u64,
+    echo: bool,
+    features: u64,
+    config: Vec<u8>,
+    paused: Option<PauseMode>,
+    held: Vec<u32>,
+    ram: Arc<Mutex<GuestRam>>,
+    hypervisor: Arc<dyn Hypervisor>,
+}
+
+impl SimulatedDevice {
+    /// Creates a simulated device.
+    ///
+    /// # Arguments
+    ///
+    /// * `device_id` - Virtio device ID.
+    /// * `num_queues` - Number of virtqueues.
+    /// * `ram` - Guest RAM.
+    /// * `hypervisor` - Hypervisor injecting the interrupts.
+    ///
+    /// # Returns
+    ///
+    /// * `SimulatedDevice` - The simulated device.
+    pub fn new(
+        device_id: u32,
+        num_queues: usize,
+        ram: Arc<Mutex<GuestRam>>,
+        hypervisor: Arc<dyn Hypervisor>,
+    ) -> Self {
+        SimulatedDevice {
+            device_id,
+            status: 0,
+            device_features_sel: 0,
+            driver_features_sel: 0,
+            driver_features: 0,
+            queue_sel: 0,
+            queues: vec![SimulatedQueue::default(); num_queues],
+            queue_num_max: BAO_SIMULATE_QUEUE_SIZE,
+            interrupt_status: 0,
+            notifications: 0,
+            echo: false,
+            features: 0,
+            config: Vec::new(),
+            paused: None,
+            held: Vec::new(),
+            ram,
+            hypervisor,
+        }
+    }
+
+    /// Makes the device echo its buffers, as a loopback device.
+    ///
+    /// # Returns
+    ///
+    /// * `SimulatedDevice` - The echoing device.
+    pub fn echo(mut self) -> Self {
+        self.echo = true;
+        self
+    }
+
+    /// Limits the queue size advertised to the driver.
+    ///
+    /// # Arguments
+    ///
+    /// * `max` - Maximum queue size (see `ConfigDevice::queue_num_max`).
+    ///
+    /// # Returns
+    ///
+    /// * `SimulatedDevice` - The limited device.
+    pub fn queue_num_max(mut self, max: u16) -> Self {
+        self.queue_num_max = max.min(BAO_SIMULATE_QUEUE_SIZE);
+        self
+    }
+
+    /// Sets the device-specific features and configuration space of the device.
+    ///
+    /// # Arguments
+    ///
+    /// * `features` - Features offered besides `VIRTIO_F_VERSION_1`.
+    /// * `config` - Configuration space, read by the driver from `VIRTIO_MMIO_CONFIG`.
+    ///
+    /// # Returns
+    ///
+    /// * `SimulatedDevice` - The configured device.
+    pub fn config_space(mut self, features: u64, config: Vec<u8>) -> Self {
+        self.features = features;
+        self.config = config;
+        self
+    }
+
+    /// Returns the device status.
+    pub fn status(&self) -> u32 {
+        self.status
+    }
+
+    /// Returns the number of queue notifications served.
+    pub fn notifications(&self) -> u64 {
+        self.notifications
+    }
+
+    /// Summarizes what was negotiated with the driver.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest` - Guest owning the device.
+    /// * `device` - The device configuration.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<DeviceSummary>` - The summary, or None until the driver sets DRIVER_OK.
+    pub fn summary(&self, guest: &ConfigGuest, device: &ConfigDevice) -> Option<DeviceSummary> {
+        if u64::from(self.status) & VIRTIO_CONFIG_S_DRIVER_OK == 0 {
+            return None;
+        }
+        let ram = self.ram.lock().unwrap();
+        Some(DeviceSummary {
+            guest: guest.name.clone(),
+            device: device.name.clone(),
+            device_type: device.device_type.clone(),
+            features: self.driver_features,
+            queues: self
+                .queues
+                .iter()
+                .filter(|q| q.ready == 1)
+                .map(|q| q.num)
+                .collect(),
+            // Simulated devices are served in-process, without a vhost-user backend
+            protocol_features: None,
+            regions: vec![MemoryRegion {
+                addr: ram.base,
+                size: ram.bytes.len() as u64,
+            }],
+        })
+    }
+
+    /// Resets the device (a paused device stays paused).
+    fn reset(&mut self) {
+        let num_queues = self.queues.len();
+        let (echo, queue_num_max) = (self.echo, self.queue_num_max);
+        let (features, config) = (self.features, std::mem::take(&mut self.config));
+        let paused = self.paused;
+        *self = SimulatedDevice::new(
+            self.device_id,
+            num_queues,
+            self.ram.clone(),
+            self.hypervisor.clone(),
+        );
+        self.echo = echo;
+        self.queue_num_max = queue_num_max;
+        self.features = features;
+        self.config = config;
+        self.paused = paused;
+    }
+
+    /// Returns the selected queue, if valid.
+    fn queue(&mut self) -> Option<&mut SimulatedQueue> {
+        self.queues.get_mut(self.queue_sel as usize)
+    }
+
+    /// Consumes the available buffers of a queue and interrupts the guest.
+    fn notify(&mut self, index: u32) {
+        if u64::from(self.status) & VIRTIO_CONFIG_S_DRIVER_OK == 0 {
+            return;
+        }
+        // Served once the device is resumed
+        if self.paused.is_some() {
+            if !self.held.contains(&index) {
+                self.held.push(index);
+            }
+            return;
+        }
+        let queue = match self.queues.get_mut(index as usize) {
+            Some(queue) if queue.ready == 1 => queue,
+            _ => return,
+        };
+        let mut ram = self.ram.lock().unwrap();
+        let used = match self.echo {
+            true => Self::echo_buffers(&mut ram, queue).ok(),
+            false => ram
+                .read_u16(queue.avail + 2)
+                .and_then(|avail_idx| ram.write_u16(queue.used + 2, avail_idx)),
+        };
+        if used.is_some() {
+            self.interrupt_status |= VIRTIO_MMIO_INT_VRING as u32;
+            self.notifications += 1;
+            let _ = self.hypervisor.notify_guest();
+        }
+    }
+
+    /// Echoes the available descriptor chains of a queue: the bytes of the readable
+    /// descriptors of each chain are copied to its writable descriptors, and the chain is
+    /// used with the number of bytes copied.
+    fn echo_buffers(ram: &mut GuestRam, queue: &mut SimulatedQueue) -> Result<()> {
+        let num = (queue.num as u16).max(1);
+        let avail_idx = ram.read_obj::<Le16>(queue.avail + 2)?.get();
+        let mut used_idx = ram.read_obj::<Le16>(queue.used + 2)?.get();
+        while queue.last_avail != avail_idx {
+            let slot = queue.avail + 4 + 2 * u64::from(queue.last_avail % num);
+            let head = ram.read_obj::<Le16>(slot)?.get();
+            let mut data = Vec::new();
+            let mut written = 0;
+            let mut index = head;
+            // A chain cannot be longer than the queue, even if the driver loops it
+            for _ in 0..num {
+                let desc = ram.read_obj::<VirtqDesc>(queue.desc + 16 * u64::from(index))?;
+                let (addr, len) = (desc.addr.get(), desc.len.get() as usize);
+                if desc.flags.get() & VRING_DESC_F_WRITE == 0 {
+                    let start = data.len();
+                    data.resize(start + len, 0);
+                    ram.read_slice(addr, &mut data[start..])?;
+                } else {
+                    let start = written.min(data.len());
+                    let end = data.len().min(start + len);
+                    ram.write_slice(addr, &data[start..end])?;
+                    written = end;
+                }
+                if desc.flags.get() & VRING_DESC_F_NEXT == 0 {
+                    break;
+                }
+                index = desc.next.get();
+            }
+            let elem = VirtqUsedElem {
+                id: Le32::new(u32::from(head)),
+                len: Le32::new(written as u32),
+            };
+            ram.write_obj(queue.used + 4 + 8 * u64::from(used_idx % num), &elem)?;
+            used_idx = used_idx.wrapping_add(1);
+            queue.last_avail = queue.last_avail.wrapping_add(1);
+        }
+        ram.write_obj(queue.used + 2, &Le16::new(used_idx))
+    }
+
+    /// Reads a register.
+    fn read(&mut self, offset: u64) -> u32 {
+        match offset {
+            VIRTIO_MMIO_MAGIC_VALUE => VIRTIO_MMIO_MAGIC as u32,
+            VIRTIO_MMIO_VERSION => VIRTIO_MMIO_VERSION_2 as u32,
+            VIRTIO_MMIO_DEVICE_ID => self.device_id,
+            VIRTIO_MMIO_DEVICE_FEATURES => {
+                let features = VIRTIO_F_VERSION_1 | self.features;
+                match self.device_features_sel {
+                    0 => features as u32,
+                    1 => (features >> 32) as u32,
+                    _ => 0,
+                }
+            }
+            VIRTIO_MMIO_QUEUE_NUM_MAX => {
+                let max = self.queue_num_max;
+                self.queue().map_or(0, |_| u32::from(max))
+            }
+            VIRTIO_MMIO_QUEUE_READY => self.queue().map_or(0, |q| q.ready),
+            VIRTIO_MMIO_INTERRUPT_STATUS => self.interrupt_status,
+            VIRTIO_MMIO_STATUS => self.status,
+            // Little-endian, zero past the end of the configuration space
+            offset if offset >= VIRTIO_MMIO_CONFIG => {
+                let start = (offset - VIRTIO_MMIO_CONFIG) as usize;
+                let mut bytes = [0u8; 4];
+                for (i, byte) in bytes.iter_mut().enumerate() {
+                    *byte = self.config.get(start + i).copied().unwrap_or(0);
+                }
+                u32::from_le_bytes(bytes)
+            }
+            _ => 0,
+        }
+    }
+
+    /// Writes a register.
+    fn write(&mut self, offset: u64, value: u32) {
+        let low = |addr: u64| (addr & !0xffff_ffff) | u64::from(value);
+        let high = |addr: u64| (addr & 0xffff_ffff) | (u64::from(value) << 32);
+        let queue_num_max = self.queue_num_max;
+        match offset {
+            VIRTIO_MMIO_DEVICE_FEATURES_SEL => self.device_features_sel = value,
+            VIRTIO_MMIO_DRIVER_FEATURES_SEL => self.driver_features_sel = value,
+            VIRTIO_MMIO_DRIVER_FEATURES => match self.driver_features_sel {
+                0 => self.driver_features = low(self.driver_features),
+                1 => self.driver_features = high(self.driver_features),
+                _ => {}
+            },
+            VIRTIO_MMIO_QUEUE_SEL => self.queue_sel = value,
+            VIRTIO_MMIO_QUEUE_NOTIFY => self.notify(value),
+            VIRTIO_MMIO_INTERRUPT_ACK => self.interrupt_status &= !value,
+            VIRTIO_MMIO_STATUS if value == 0 => self.reset(),
+            VIRTIO_MMIO_STATUS => {
+                self.status = value;
+                // Modern devices refuse drivers not accepting VIRTIO_F_VERSION_1
+                if self.driver_features & VIRTIO_F_VERSION_1 == 0 {
+                    self.status &= !(VIRTIO_CONFIG_S_FEATURES_OK as u32);
+                }
+            }
+            _ => {
+                if let Some(queue) = self.queue() {
+                    match offset {
+                        // A size the device cannot serve is ignored, leaving the queue
+                        // unusable until the driver writes a valid one
+                        VIRTIO_MMIO_QUEUE_NUM => {
+                            if let Ok(num) = check_queue_num(value, queue_num_max) {
+                                queue.num = u32::from(num);
+                            }
+                        }
+                        VIRTIO_MMIO_QUEUE_READY => queue.ready = value,
+                        VIRTIO_MMIO_QUEUE_DESC_LOW => queue.desc = low(queue.desc),
+                        VIRTIO_MMIO_QUEUE_DESC_HIGH => queue.desc = high(queue.desc),
+                        VIRTIO_MMIO_QUEUE_AVAIL_LOW => queue.avail = low(queue.avail),
+                        VIRTIO_MMIO_QUEUE_AVAIL_HIGH => queue.avail = high(queue.avail),
+                        VIRTIO_MMIO_QUEUE_USED_LOW => queue.used = low(queue.used),
+                        VIRTIO_MMIO_QUEUE_USED_HIGH => queue.used = high(queue.used),
+                        _ => {}
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Snapshot for SimulatedDevice {
+    fn save(&self) -> Result<DeviceSnapshot> {
+        Ok(DeviceSnapshot {
+            transport: TransportState {
+                status: self.status,
+                device_features_sel: self.device_features_sel,
+                driver_features_sel: self.driver_features_sel,
+                driver_features: self.driver_features,
+                queue_sel: self.queue_sel,
+                interrupt_status: self.interrupt_status,
+            },
+            queues: self
+                .queues
+                .iter()
+                .map(|q| QueueState {
+                    num: q.num,
+                    ready: q.ready == 1,
+                    desc: q.desc,
+                    avail: q.avail,
+                    used: q.used,
+                    last_avail: q.last_avail,
+                })
+                .collect(),
+            // Simulated devices are served in-process, without a backend to snapshot
+            backend: None,
+        })
+    }
+
+    fn restore(&mut self, state: &DeviceSnapshot) -> Result<()> {
+        if state.queues.len() != self.queues.len() {
+            return Err(Error::InvalidSnapshot(format!(
+                "{} queues instead of {}",
+                state.queues.len(),
+                self.queues.len()
+            )));
+        }
+        let transport = &state.transport;
+        self.status = transport.status;
+        self.device_features_sel = transport.device_features_sel;
+        self.driver_features_sel = transport.driver_features_sel;
+        self.driver_features = transport.driver_features;
+        self.queue_sel = transport.queue_sel;
+        self.interrupt_status = transport.interrupt_status;
+        for (queue, q) in self.queues.iter_mut().zip(&state.queues) {
+            *queue = SimulatedQueue {
+                num: q.num,
+                ready: u32::from(q.ready),
+                desc: q.desc,
+                avail: q.avail,
+                used: q.used,
+                last_avail: q.last_avail,
+            };
+        }
+        Ok(())
+    }
+}
+
+impl Pause for SimulatedDevice {
+    fn pause(&mut self, mode: PauseMode) -> Result<()> {
+        self.paused = Some(mode);
+        let active = u64::from(self.status) & VIRTIO_CONFIG_S_DRIVER_OK != 0;
+        if mode == PauseMode::NeedsReset && active {
+            self.status |= VIRTIO_CONFIG_S_NEEDS_RESET as u32;
+            self.interrupt_status |= VIRTIO_MMIO_INT_CONFIG as u32;
+            self.hypervisor.notify_guest()?;
+        }
+        Ok(())
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        self.paused = None;
+        for index in std::mem::take(&mut self.held) {
+            self.notify(index);
+        }
+        Ok(())
+    }
+}
+
+impl MutDeviceMmio for SimulatedDevice {
+    fn mmio_read(&mut self, _base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+        let value = self.read(offset);
+        let len = data.len().min(4);
+        data[..len].copy_from_slice(&value.to_le_bytes()[..len]);
+    }
+
+    fn mmio_write(&mut self, _base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+        let mut bytes = [0u8; 4];
+        let len = data.len().min(4);
+        bytes[..len].copy_from_slice(&data[..len]);
+        self.write(offset, u32::from_le_bytes(bytes));
+    }
+}
+
+/// Struct representing the outcome of the simulation of a device.
+///
+/// # Attributes
+///
+/// * `guest` - Guest name.
+/// * `device` - Device name.
+/// * `status` - Final device status.
+/// * `requests` - Number of MMIO requests served.
+/// * `notifications` - Number of queue notifications served.
+/// * `summary` - What was negotiated at DRIVER_OK (None if the driver never set it).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimulationReport {
+    pub guest: String,
+    pub device: String,
+    pub status: u32,
+    pub requests: u64,
+    pub notifications: u64,
+    pub summary: Option<DeviceSummary>,
+}
+
+/// Struct representing the outcome of a stress test of a device.
+///
+/// # Attributes
+///
+/// * `guest` - Guest name.
+/// * `device` - Device name.
+/// * `requests` - Number of buffers used by the device.
+/// * `bytes` - Number of payload bytes made available.
+/// * `elapsed` - Duration of the test.
+/// * `latencies` - Sorted notification round-trip latencies (notify, interrupt, ack).
+#[derive(Debug, Clone)]
+pub struct StressReport {
+    pub guest: String,
+    pub device: String,
+    pub requests: u64,
+    pub bytes: u64,
+    pub elapsed: Duration,
+    pub latencies: Vec<Duration>,
+}
+
+impl StressReport {
+    /// Returns the number of buffers used per second.
+    pub fn throughput(&self) -> f64 {
+        self.requests as f64 / self.elapsed.as_secs_f64().max(f64::EPSILON)
+    }
+
+    /// Returns a latency percentile.
+    ///
+    /// # Arguments
+    ///
+    /// * `percentile` - The percentile (0 to 100).
+    ///
+    /// # Returns
+    ///
+    /// * `Duration` - The latency (zero without samples).
+    pub fn latency(&self, percentile: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let rank = (percentile / 100.0 * (self.latencies.len() - 1) as f64).round() as usize;
+        self.latencies[rank.min(self.latencies.len() - 1)]
+    }
+}
+
+/// Struct representing the outcome of a loopback self-test.
+///
+/// # Attributes
+///
+/// * `rounds` - Number of notification rounds.
+/// * `buffers` - Number of buffers echoed.
+/// * `bytes` - Number of payload bytes echoed.
+/// * `requests` - Number of MMIO requests served.
+/// * `interrupts` - Number of interrupts injected.
+/// * `elapsed` - Duration of the test.
+#[derive(Debug, Clone)]
+pub struct SelfTestReport {
+    pub rounds: u16,
+    pub buffers: u64,
+    pub bytes: u64,
+    pub requests: u64,
+    pub interrupts: u64,
+    pub elapsed: Duration,
+}
+
+/// Struct representing a simulated guest.
+///
+/// # Attributes
+///
+/// * `guest` - Guest configuration.
+/// * `ram` - Guest RAM.
+/// * `hypervisor` - Mock hypervisor relaying the guest requests.
+/// * `bus` - MMIO bus of the guest.
+/// * `models` - Device models, in configuration order.
+/// * `avail_idx` - Available index of the queue of each device.
+/// * `limiters` - In-flight limiter of each device with `max_inflight` (by device index).
+/// * `tracer` - Tracer of the served requests, if any.
+/// * `recorder` - Recording of the served requests, if any.
+/// * `audit` - Audit log of the served requests, if any.
+pub struct SimulatedGuest<'a> {
+    guest: &'a ConfigGuest,
+    ram: Arc<Mutex<GuestRam>>,
+    hypervisor: Arc<MockHypervisor>,
+    bus: BaoMmioBus,
+    models: Vec<Arc<Mutex<SimulatedDevice>>>,
+    avail_idx: Vec<u16>,
+    limiters: Vec<(usize, Arc<InflightLimiter<GuestRam>>)>,
+    tracer: Option<Arc<dyn TraceSink>>,
+    recorder: Option<Arc<Mutex<Recorder>>>,
+    audit: Option<Arc<Mutex<AuditLog>>>,
+}
+
+impl<'a> SimulatedGuest<'a> {
+    /// Instantiates the devices of a guest.
+    ///
+    /// # Arguments
+    ///
+    /// * `frontend_id` - ID of the frontend of the guest.
+    /// * `guest` - The guest.
+    /// * `tracer` - Tracer of the served requests, if any.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<SimulatedGuest>` - The simulated guest.
+    pub fn new(
+        frontend_id: VmId,
+        guest: &'a ConfigGuest,
+        tracer: Option<Arc<dyn TraceSink>>,
+    ) -> Result<Self> {
+        let ram_size = guest.ram_size.min(BAO_SIMULATE_RAM_SIZE) as usize;
+        let ram = Arc::new(Mutex::new(GuestRam::new(guest.ram_addr, ram_size)));
+        let hypervisor = Arc::new(MockHypervisor::new());
+        let mut bus = BaoMmioBus::new();
+        let mut models = Vec::new();
+        let mut limiters = Vec::new();
+        for (index, device) in guest.devices.iter().enumerate() {
+            // The rings of each device get their own 64 KiB of guest RAM
+            let rings = Self::rings(guest, index);
+            if ram.lock().unwrap().range(rings, 0x10000).is_none() {
+                return Err(Error::InvalidMmioAddr("ring", rings));
+            }
+            // Interrupts injected by traced devices are traced as well
+            let injector: Arc<dyn Hypervisor> = match &tracer {
+                Some(tracer) => {
+                    Arc::new(TracingHypervisor::new(hypervisor.clone(), tracer.clone()))
+                }
+                None => hypervisor.clone(),
+            };
+            let mut model = SimulatedDevice::new(device.id.raw(), 1, ram.clone(), injector)
+                .queue_num_max(device.queue_num_max(BAO_SIMULATE_QUEUE_SIZE));
+            if device.device_type == BAO_LOOPBACK_DEVICE_TYPE {
+                model = model.echo();
+            }
+            // The MAC address leads the configuration space of net devices
+            if let Some(mac) = device.mac(frontend_id, guest.id)? {
+                model = model.config_space(VIRTIO_NET_F_MAC, mac.0.to_vec());
+            }
+            let model = Arc::new(Mutex::new(model));
+            bus.register(device.addr, VIRTIO_MMIO_IO_SIZE, model.clone())?;
+            models.push(model);
+            if let Some(limit) = device.max_inflight {
+                limiters.push((index, Arc::new(InflightLimiter::new(limit, ram.clone()))));
+            }
+        }
+        Ok(SimulatedGuest {
+            guest,
+            ram,
+            hypervisor,
+            bus,
+            models,
+            avail_idx: vec![0; guest.devices.len()],
+            limiters,
+            tracer,
+            recorder: None,
+            audit: None,
+        })
+    }
+
+    /// Records the requests served from now on.
+    ///
+    /// # Arguments
+    ///
+    /// * `recorder` - The recording.
+    ///
+    /// # Returns
+    ///
+    /// * `SimulatedGuest` - The simulated guest.
+    pub fn with_recorder(mut self, recorder: Arc<Mutex<Recorder>>) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Audits the requests served from now on.
+    ///
+    /// # Arguments
+    ///
+    /// * `audit` - The audit log.
+    ///
+    /// # Returns
+    ///
+    /// * `SimulatedGuest` - The simulated guest.
+    pub fn with_audit(mut self, audit: Arc<Mutex<AuditLog>>) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+
+    /// Returns the guest address of the rings of a device (descriptor table, then
+    /// available ring at +0x4000, used ring at +0x8000 and buffers at +0xc000).
+    fn rings(guest: &ConfigGuest, index: usize) -> u64 {
+        guest.ram_addr.raw() + index as u64 * 0x10000
+    }
+
+    /// Returns the queue size the driver sets up for a device.
+    fn queue_size(&self, index: usize) -> u16 {
+        self.guest.devices[index].queue_num_max(BAO_SIMULATE_QUEUE_SIZE)
+    }
+
+    /// Serves the pending requests of a hypervisor.
+    fn serve(bus: &BaoMmioBus, hv: &dyn Hypervisor, tracer: Option<&dyn TraceSink>) -> Result<()> {
+        while let Some(mut req) = hv.next_request()? {
+            let start = crate::bao_now!();
+            bus.handle_request(&mut req)?;
+            if let Some(tracer) = tracer {
+                let phase = if req.op == BAO_IO_WRITE && req.reg_off == VIRTIO_MMIO_QUEUE_NOTIFY {
+                    TracePhase::BackendKick
+                } else {
+                    TracePhase::Dispatch
+                };
+                crate::bao_span!(tracer, phase, &req, start)?;
+            }
+            hv.complete_request(&req)?;
+        }
+        Ok(())
+    }
+
+    /// Serves the pending requests of a hypervisor through the in-flight limiters of the
+    /// devices.
+    ///
+    /// # Arguments
+    ///
+    /// * `hv` - The hypervisor.
+    /// * `limiters` - In-flight limiters not yet stacked on the hypervisor.
+    /// * `serve` - Serves the requests admitted by every limiter.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the requests were served.
+    fn limited(
+        &self,
+        hv: &dyn Hypervisor,
+        limiters: &[(usize, Arc<InflightLimiter<GuestRam>>)],
+        serve: &dyn Fn(&dyn Hypervisor) -> Result<()>,
+    ) -> Result<()> {
+        match limiters.split_first() {
+            Some(((index, limiter), rest)) => {
+                let addr = self.guest.devices[*index].addr.raw();
+                let hv = InflightLimitHypervisor::new(hv, *index as u64, addr, limiter.clone());
+                self.limited(&hv, rest, serve)
+            }
+            None => serve(hv),
+        }
+    }
+
+    /// Runs a driver script.
+    fn run(&self, driver: ScriptedDriver) -> Result<u64> {
+        let bus = &self.bus;
+        let traced = |hv: &dyn Hypervisor| match &self.tracer {
+            Some(tracer) => Self::serve(
+                bus,
+                &TracingHypervisor::new(hv, tracer.clone()),
+                Some(tracer.as_ref()),
+            ),
+            None => Self::serve(bus, hv, None),
+        };
+        let recorded = |hv: &dyn Hypervisor| match &self.recorder {
+            Some(recorder) => traced(&RecordingHypervisor::new(hv, recorder.clone())),
+            None => traced(hv),
+        };
+        let serve = |hv: &dyn Hypervisor| match &self.audit {
+            Some(audit) => recorded(&AuditHypervisor::new(hv, audit.clone())),
+            None => recorded(hv),
+        };
+        let limited = |hv: &dyn Hypervisor| self.limited(hv, &self.limiters, &serve);
+        Ok(driver.run_on(&self.hypervisor, limited)?.len() as u64)
+    }
+
+    /// Initializes a device.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - Index of the device in the guest configuration.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<u64>` - The number of MMIO requests served.
+    pub fn init_device(&mut self, index: usize) -> Result<u64> {
+        let device = &self.guest.devices[index];
+        self.avail_idx[index] = 0;
+        self.run(ScriptedDriver::virtio_init_at(
+            index as u64,
+            device.addr,
+            device.id.raw(),
+            VIRTIO_F_VERSION_1,
+            &[self.queue_size(index)],
+            GuestAddress(Self::rings(self.guest, index)),
+        ))
+    }
+
+    /// Makes buffers available to a device, notifies it and waits for them to be used.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - Index of the device in the guest configuration.
+    /// * `buffers` - Number of buffers made available.
+    /// * `payload` - Payload written to each buffer.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<u64>` - The number of MMIO requests served.
+    pub fn round(&mut self, index: usize, buffers: u16, payload: &[u8]) -> Result<u64> {
+        let rings = Self::rings(self.guest, index);
+        let avail_idx = self.avail_idx[index].wrapping_add(buffers);
+        {
+            let mut ram = self.ram.lock().unwrap();
+            let payload = &payload[..payload.len().min(0x4000)];
+            for _ in 0..buffers {
+                ram.write_bytes(rings + 0xc000, payload);
+            }
+            ram.write_u16(rings + 0x4000 + 2, avail_idx);
+        }
+        self.avail_idx[index] = avail_idx;
+
+        let device = &self.guest.devices[index];
+        let requests = self.run(
+            ScriptedDriver::new(index as u64, device.addr)
+                .write(VIRTIO_MMIO_QUEUE_NOTIFY, 0)
+                .expect(VIRTIO_MMIO_INTERRUPT_STATUS, 1)
+                .write(VIRTIO_MMIO_INTERRUPT_ACK, 1),
+        )?;
+        let used_idx = self
+            .ram
+            .lock()
+            .unwrap()
+            .read_u16(rings + 0x8000 + 2)
+            .unwrap_or(0);
+        if used_idx != avail_idx {
+            return Err(Error::ScriptMismatch {
+                reg_off: VIRTIO_MMIO_QUEUE_NOTIFY,
+                expected: u64::from(avail_idx),
+                actual: u64::from(used_idx),
+            });
+        }
+        Ok(requests)
+    }
+
+    /// Makes descriptor chains available to an echoing device, notifies it and checks
+    /// that every chain was echoed.
+    ///
+    /// Each chain is made of a readable buffer holding the payload (scrambled with the
+    /// chain position, so that misplaced buffers are told apart) and a zeroed writable
+    /// buffer of the same size.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - Index of the device in the guest configuration.
+    /// * `buffers` - Number of chains made available (at most `BAO_SELF_TEST_DEPTH`).
+    /// * `payload` - Payload (at most `BAO_SELF_TEST_MAX_PAYLOAD` bytes).
+    ///
+    /// # Returns
+    ///
+    /// * `Result<u64>` - The number of MMIO requests served.
+    pub fn echo(&mut self, index: usize, buffers: u16, payload: &[u8]) -> Result<u64> {
+        let rings = Self::rings(self.guest, index);
+        let queue_size = self.queue_size(index);
+        // Each chain takes two descriptors
+        let buffers = buffers.clamp(1, BAO_SELF_TEST_DEPTH.min(queue_size / 2).max(1));
+        let payload = &payload[..payload.len().min(BAO_SELF_TEST_MAX_PAYLOAD)];
+        let size = payload.len() as u64;
+        let first = self.avail_idx[index];
+        let scrambled = |position: u16| {
+            payload
+                .iter()
+                .map(|b| b ^ position as u8)
+                .collect::<Vec<_>>()
+        };
+        let chain = |i: u16| {
+            let out = rings + 0xc000 + 2 * u64::from(i) * size;
+            (2 * i, out, out + size)
+        };
+        let avail_idx = first.wrapping_add(buffers);
+        {
+            let mut ram = self.ram.lock().unwrap();
+            for i in 0..buffers {
+                let position = first.wrapping_add(i);
+                let (head, out, input) = chain(i);
+                ram.write_bytes(out, &scrambled(position));
+                ram.write_bytes(input, &vec![0; payload.len()]);
+                let descs = [
+                    VirtqDesc {
+                        addr: Le64::new(out),
+                        len: Le32::new(size as u32),
+                        flags: Le16::new(VRING_DESC_F_NEXT),
+                        next: Le16::new(head + 1),
+                    },
+                    VirtqDesc {
+                        addr: Le64::new(input),
+                        len: Le32::new(size as u32),
+                        flags: Le16::new(VRING_DESC_F_WRITE),
+                        next: Le16::new(0),
+                    },
+                ];
+                ram.write_obj(rings + 16 * u64::from(head), &descs)?;
+                let slot = u64::from(position % queue_size);
+                ram.write_u16(rings + 0x4000 + 4 + 2 * slot, head);
+            }
+            ram.write_u16(rings + 0x4000 + 2, avail_idx);
+        }
+        self.avail_idx[index] = avail_idx;
+
+        let device = &self.guest.devices[index];
+        let requests = self.run(
+            ScriptedDriver::new(index as u64, device.addr)
+                .write(VIRTIO_MMIO_QUEUE_NOTIFY, 0)
+                .expect(VIRTIO_MMIO_INTERRUPT_STATUS, 1)
+                .write(VIRTIO_MMIO_INTERRUPT_ACK, 1),
+        )?;
+        let ram = self.ram.lock().unwrap();
+        let used_idx = ram.read_u16(rings + 0x8000 + 2).unwrap_or(0);
+        if used_idx != avail_idx {
+            return Err(Error::SelfTestFailed(format!(
+                "used index {} (expected {})",
+                used_idx, avail_idx
+            )));
+        }
+        for i in 0..buffers {
+            let position = first.wrapping_add(i);
+            let (head, _, input) = chain(i);
+            let elem = rings + 0x8000 + 4 + 8 * u64::from(position % queue_size);
+            let used = ram.read_obj::<VirtqUsedElem>(elem)?;
+            let (id, len) = (used.id.get(), used.len.get());
+            if id != u32::from(head) || u64::from(len) != size {
+                return Err(Error::SelfTestFailed(format!(
+                    "buffer {} used as descriptor {} of {} bytes (expected {} of {} bytes)",
+                    position, id, len, head, size
+                )));
+            }
+            if ram.read_bytes(input, payload.len()) != Some(&scrambled(position)[..]) {
+                return Err(Error::SelfTestFailed(format!(
+                    "buffer {} was not echoed",
+                    position
+                )));
+            }
+        }
+        Ok(requests)
+    }
+
+    /// Returns the number of interrupts injected into the guest.
+    pub fn interrupts(&self) -> u64 {
+        self.hypervisor.interrupts()
+    }
+
+    /// Returns the model of a device.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - Index of the device in the guest configuration.
+    ///
+    /// # Returns
+    ///
+    /// * `Arc<Mutex<SimulatedDevice>>` - The device model.
+    pub fn model(&self, index: usize) -> Arc<Mutex<SimulatedDevice>> {
+        self.models[index].clone()
+    }
+}
+
+/// Simulates the devices of a guest.
+///
+/// # Arguments
+///
+/// * `frontend_id` - ID of the frontend of the guest (tagging the errors of its devices).
+/// * `guest` - The guest.
+/// * `rounds` - Number of traffic rounds (one buffer made available and notified per round).
+/// * `tracer` - Tracer of the served requests, if any.
+/// * `recorder` - Recording of the served requests, if any.
+/// * `audit` - Audit log of the served requests, if any.
+///
+/// # Returns
+///
+/// * `Result<Vec<SimulationReport>>` - The outcome of every device of the guest.
+pub fn simulate_guest(
+    frontend_id: VmId,
+    guest: &ConfigGuest,
+    rounds: u16,
+    tracer: Option<Arc<dyn TraceSink>>,
+    recorder: Option<Arc<Mutex<Recorder>>>,
+    audit: Option<Arc<Mutex<AuditLog>>>,
+) -> Result<Vec<SimulationReport>> {
+    let mut simulated = SimulatedGuest::new(frontend_id, guest, tracer)?;
+    if let Some(recorder) = recorder {
+        simulated = simulated.with_recorder(recorder);
+    }
+    if let Some(audit) = audit {
+        simulated = simulated.with_audit(audit);
+    }
+    let mut reports = Vec::new();
+    for (index, device) in guest.devices.iter().enumerate() {
+        let context = ErrorContext::new(frontend_id, guest.id, &device.name);
+        let mut requests = simulated.init_device(index).with_context(&context)?;
+        let model = simulated.model(index);
+        // Summarize the negotiation as of DRIVER_OK
+        let summary = model.lock().unwrap().summary(guest, device);
+        for _ in 0..rounds {
+            requests += simulated.round(index, 1, &[]).with_context(&context)?;
+        }
+        let model = model.lock().unwrap();
+        reports.push(SimulationReport {
+            guest: guest.name.clone(),
+            device: device.name.clone(),
+            status: model.status(),
+            requests,
+            notifications: model.notifications(),
+            summary,
+        });
+    }
+    Ok(reports)
+}
+
+/// Stress tests the selected devices of a configuration.
+///
+/// Each device is driven with `options.requests` buffers of `options.payload` bytes,
+/// made available `options.queue_depth` at a time, optionally paced at `options.rate`
+/// buffers per second.
+///
+/// # Arguments
+///
+/// * `config` - The frontends configuration.
+/// * `options` - The stress test options.
+/// * `tracer` - Tracer of the served requests, if any.
+///
+/// # Returns
+///
+/// * `Result<Vec<StressReport>>` - The outcome of every selected device.
+pub fn stress(
+    config: &ConfigFrontends,
+    options: &StressOptions,
+    tracer: Option<Arc<dyn TraceSink>>,
+) -> Result<Vec<StressReport>> {
+    let depth = options.queue_depth.clamp(1, BAO_SIMULATE_QUEUE_SIZE);
+    let payload = (0..options.payload).map(|i| i as u8).collect::<Vec<_>>();
+    let mut reports = Vec::new();
+    let guests = config
+        .frontends
+        .iter()
+        .flat_map(|f| f.guests.iter().map(move |guest| (f.id, guest)));
+    for (frontend_id, guest) in guests {
+        let mut simulated = SimulatedGuest::new(frontend_id, guest, tracer.clone())?;
+        for (index, device) in guest.devices.iter().enumerate() {
+            if !options.devices.is_empty() && !options.devices.contains(&device.name) {
+                continue;
+            }
+            let context = ErrorContext::new(frontend_id, guest.id, &device.name);
+            simulated.init_device(index).with_context(&context)?;
+            // The driver cannot have more buffers outstanding than the queue holds
+            let depth = depth.min(simulated.queue_size(index));
+
+            let mut report = StressReport {
+                guest: guest.name.clone(),
+                device: device.name.clone(),
+                requests: 0,
+                bytes: 0,
+                elapsed: Duration::ZERO,
+                latencies: Vec::new(),
+            };
+            let start = Instant::now();
+            while report.requests < options.requests {
+                // Pace the rounds to the requested rate
+                if options.rate > 0 {
+                    let due = Duration::from_secs_f64(report.requests as f64 / options.rate as f64);
+                    if let Some(delay) = due.checked_sub(start.elapsed()) {
+                        thread::sleep(delay);
+                    }
+                }
+                let buffers = (options.requests - report.requests).min(u64::from(depth)) as u16;
+                let round = Instant::now();
+                simulated
+                    .round(index, buffers, &payload)
+                    .with_context(&context)?;
+                report.latencies.push(round.elapsed());
+                report.requests += u64::from(buffers);
+                report.bytes += u64::from(buffers) * payload.len() as u64;
+            }
+            report.elapsed = start.elapsed();
+            report.latencies.sort();
+            reports.push(report);
+        }
+    }
+    Ok(reports)
+}
+
+/// Simulates every guest of a configuration.
+///
+/// # Arguments
+///
+/// * `config` - The frontends configuration.
+/// * `rounds` - Number of traffic rounds per device.
+/// * `tracer` - Tracer of the served requests, if any.
+/// * `recorder` - Recording of the served requests, if any.
+/// * `audit` - Audit log of the served requests, if any.
+///
+/// # Returns
+///
+/// * `Result<Vec<SimulationReport>>` - The outcome of every device.
+pub fn simulate(
+    config: &ConfigFrontends,
+    rounds: u16,
+    tracer: Option<Arc<dyn TraceSink>>,
+    recorder: Option<Arc<Mutex<Recorder>>>,
+    audit: Option<Arc<Mutex<AuditLog>>>,
+) -> Result<Vec<SimulationReport>> {
+    let mut reports = Vec::new();
+    for frontend in &config.frontends {
+        for guest in &frontend.guests {
+            reports.extend(simulate_guest(
+                frontend.id,
+                guest,
+                rounds,
+                tracer.clone(),
+                recorder.clone(),
+                audit.clone(),
+            )?);
+        }
+    }
+    Ok(reports)
+}
+
+/// Runs the loopback self-test.
+///
+/// A built-in guest with a single loopback device is initialized by the scripted driver,
+/// which then echoes `options.rounds` batches of buffers through it, one notification
+/// and interrupt per batch. The batch size cycles from 1 to `BAO_SELF_TEST_DEPTH`
+/// buffers, so the default rounds wrap the rings around.
+///
+/// # Arguments
+///
+/// * `options` - The self-test options.
+/// * `tracer` - Tracer of the served requests, if any.
+///
+/// # Returns
+///
+/// * `Result<SelfTestReport>` - The outcome of the self-test, or `SelfTestFailed` at the
+///   first buffer not echoed correctly.
+pub fn self_test(
+    options: &SelfTestOptions,
+    tracer: Option<Arc<dyn TraceSink>>,
+) -> Result<SelfTestReport> {
+    let guest = ConfigGuest {
+        name: "self-test".to_string(),
+        id: VmId(0),
+        ram_addr: GuestAddress(0x6000_0000),
+        ram_size: 0x10000,
+        shmem_path: String::new(),
+        socket_path: String::new(),
+        devices: vec![ConfigDevice {
+            name: "loopback0".to_string(),
+            id: DeviceId(BAO_LOOPBACK_DEVICE_ID),
+            device_type: BAO_LOOPBACK_DEVICE_TYPE.to_string(),
+            addr: GuestAddress(0xa003e00),
+            ..Default::default()
+        }],
+        sched: None,
+        watch_dir: None,
+        dt_overlay: None,
+        prefault: false,
+        thp: Default::default(),
+        dtb: None,
+        labels: Default::default(),
+    };
+    let payload = (0..options.payload.min(BAO_SELF_TEST_MAX_PAYLOAD))
+        .map(|i| i as u8)
+        .collect::<Vec<_>>();
+    let start = Instant::now();
+    let mut simulated = SimulatedGuest::new(VmId(0), &guest, tracer)?;
+    let mut report = SelfTestReport {
+        rounds: options.rounds,
+        buffers: 0,
+        bytes: 0,
+        requests: simulated.init_device(0)?,
+        interrupts: 0,
+        elapsed: Duration::ZERO,
+    };
+    for round in 0..options.rounds {
+        let buffers = round % BAO_SELF_TEST_DEPTH + 1;
+        report.requests += simulated.echo(0, buffers, &payload)?;
+        report.buffers += u64::from(buffers);
+        report.bytes += u64::from(buffers) * payload.len() as u64;
+    }
+    report.interrupts = simulated.interrupts();
+    if report.interrupts != u64::from(options.rounds) {
+        return Err(Error::SelfTestFailed(format!(
+            "{} interrupts injected (expected {})",
+            report.interrupts, options.rounds
+        )));
+    }
+    report.elapsed = start.elapsed();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mac::MacAddress;
+    use crate::virtio_ids::{VIRTIO_ID_I2C, VIRTIO_ID_RNG};
+
+    fn guest(ram_size: u64) -> ConfigGuest {
+        let device = |name: &str, id, addr| ConfigDevice {
+            name: name.to_string(),
+            id: DeviceId(id),
+            device_type: "rng".to_string(),
+            addr: GuestAddress(addr),
+            ..Default::default()
+        };
+        ConfigGuest {
+            name: "guest0".to_string(),
+            id: VmId(0),
+            ram_addr: GuestAddress(0x6000_0000),
+            ram_size,
+            shmem_path: "/dev/baoipc0".to_string(),
+            socket_path: "/tmp/".to_string(),
+            devices: vec![
+                device("rng0", VIRTIO_ID_RNG, 0xa003e00),
+                device("i2c0", VIRTIO_ID_I2C, 0xa003c00),
+            ],
+            sched: None,
+            watch_dir: None,
+            dt_overlay: None,
+            prefault: false,
+            thp: Default::default(),
+            dtb: None,
+            labels: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_simulate_guest() {
+        let reports = simulate_guest(VmId(0), &guest(0x0100_0000), 4, None, None, None).unwrap();
+        assert_eq!(reports.len(), 2);
+        for report in &reports {
+            assert_eq!(report.status, 0xf);
+            assert_eq!(report.notifications, 4);
+        }
+        assert_eq!(reports[1].device, "i2c0");
+        let summary = reports[1].summary.as_ref().unwrap();
+        assert_eq!(summary.features, VIRTIO_F_VERSION_1);
+        assert_eq!(summary.queues, [u32::from(BAO_SIMULATE_QUEUE_SIZE)]);
+        assert_eq!(summary.regions[0].size, 0x0100_0000);
+
+        // Deterministic
+        assert_eq!(
+            simulate_guest(VmId(0), &guest(0x0100_0000), 4, None, None, None).unwrap(),
+            reports
+        );
+
+        // Devices with an in-flight limit are served through it
+        let mut limited = guest(0x0100_0000);
+        limited.devices[1].max_inflight = Some(1);
+        let simulated = SimulatedGuest::new(VmId(0), &limited, None).unwrap();
+        assert_eq!(simulated.limiters.len(), 1);
+        assert_eq!(
+            simulate_guest(VmId(0), &limited, 4, None, None, None).unwrap(),
+            reports
+        );
+
+        // The rings of the second device do not fit in the guest RAM
+        assert!(matches!(
+            simulate_guest(VmId(0), &guest(0x10000), 1, None, None, None),
+            Err(Error::InvalidMmioAddr("ring", 0x6001_0000))
+        ));
+    }
+
+    #[test]
+    fn test_simulate_record() {
+        let path = std::env::temp_dir().join(format!("bao-simulate-{}.bin", std::process::id()));
+        let recorder = Arc::new(Mutex::new(Recorder::new(path.to_str().unwrap()).unwrap()));
+        let audit_path = path.with_extension("audit");
+        let audit = Arc::new(Mutex::new(
+            AuditLog::new(crate::types::ConfigAudit {
+                path: audit_path.to_str().unwrap().to_string(),
+                ..Default::default()
+            })
+            .unwrap(),
+        ));
+        let reports = simulate_guest(
+            VmId(0),
+            &guest(0x0100_0000),
+            4,
+            None,
+            Some(recorder.clone()),
+            Some(audit.clone()),
+        )
+        .unwrap();
+        recorder.lock().unwrap().flush().unwrap();
+        audit.lock().unwrap().flush().unwrap();
+
+        // Every served request is recorded along with its completion, and audited
+        let events = crate::record::read_recording(std::fs::File::open(&path).unwrap()).unwrap();
+        let requests: u64 = reports.iter().map(|report| report.requests).sum();
+        assert_eq!(events.len() as u64, 2 * requests);
+        let audited =
+            crate::audit::read_audit_records(std::fs::File::open(&audit_path).unwrap()).unwrap();
+        assert_eq!(audited.len() as u64, requests);
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&audit_path).unwrap();
+    }
+
+    #[test]
+    fn test_stress() {
+        let config = ConfigFrontends {
+            frontends: vec![crate::types::ConfigFrontend {
+                name: "frontend0".to_string(),
+                id: VmId(0),
+                control: None,
+                guests: vec![guest(0x0100_0000)],
+            }],
+            ..Default::default()
+        };
+        let options = StressOptions {
+            devices: vec!["i2c0".to_string()],
+            requests: 100,
+            rate: 0,
+            payload: 512,
+            queue_depth: 8,
+        };
+        let reports = stress(&config, &options, None).unwrap();
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].device, "i2c0");
+        assert_eq!(reports[0].requests, 100);
+        assert_eq!(reports[0].bytes, 100 * 512);
+        // 12 full rounds and a last one of 4 buffers
+        assert_eq!(reports[0].latencies.len(), 13);
+        assert!(reports[0].latency(50.0) <= reports[0].latency(99.0));
+        assert!(reports[0].throughput() > 0.0);
+    }
+
+    #[test]
+    fn test_self_test() {
+        let options = SelfTestOptions {
+            rounds: BAO_SELF_TEST_ROUNDS,
+            payload: 256,
+        };
+        let report = self_test(&options, None).unwrap();
+        assert_eq!(report.interrupts, u64::from(BAO_SELF_TEST_ROUNDS));
+        // 8 batches of 1 to 8 buffers, which wraps the 256-entry rings
+        assert_eq!(report.buffers, 288);
+        assert_eq!(report.bytes, 288 * 256);
+
+        // A device that does not echo is caught at the first buffer
+        let mut guest = guest(0x0100_0000);
+        guest.devices.truncate(1);
+        let mut simulated = SimulatedGuest::new(VmId(0), &guest, None).unwrap();
+        simulated.init_device(0).unwrap();
+        assert!(matches!(
+            simulated.echo(0, 1, &[1, 2, 3]),
+            Err(Error::SelfTestFailed(message)) if message.starts_with("buffer 0 used")
+        ));
+    }
+
+    #[test]
+    fn test_queue_size_max() {
+        let mut guest = guest(0x0100_0000);
+        guest.devices[0].device_type = BAO_LOOPBACK_DEVICE_TYPE.to_string();
+        guest.devices[0].queue_size_max = Some(16);
+        guest.devices[1].queue_size_max = Some(1024);
+        let mut simulated = SimulatedGuest::new(VmId(0), &guest, None).unwrap();
+        simulated.init_device(0).unwrap();
+        simulated.init_device(1).unwrap();
+        let summary = |index: usize| {
+            let model = simulated.model(index);
+            let model = model.lock().unwrap();
+            model.summary(&guest, &guest.devices[index]).unwrap().queues
+        };
+        // A limit above the device maximum leaves it as is
+        assert_eq!(summary(0), [16]);
+        assert_eq!(summary(1), [u32::from(BAO_SIMULATE_QUEUE_SIZE)]);
+
+        // Batches of up to 8 chains (16 descriptors) wrap the 16-entry rings
+        for _ in 0..5 {
+            simulated.echo(0, BAO_SELF_TEST_DEPTH, &[1, 2, 3]).unwrap();
+        }
+
+        // Sizes above the limit, or not powers of 2, are ignored
+        let model = simulated.model(0);
+        let mut model = model.lock().unwrap();
+        model.write(VIRTIO_MMIO_QUEUE_SEL, 0);
+        assert_eq!(model.read(VIRTIO_MMIO_QUEUE_NUM_MAX), 16);
+        for (num, expected) in [(32, 16), (12, 16), (0, 16), (8, 8)] {
+            model.write(VIRTIO_MMIO_QUEUE_NUM, num);
+            assert_eq!(model.save().unwrap().queues[0].num, expected, "{}", num);
+        }
+        assert!(matches!(
+            check_queue_num(0x1_0000, u16::MAX),
+            Err(Error::InvalidQueueSize(0x1_0000, u16::MAX))
+        ));
+    }
+
+    #[test]
+    fn test_net_config_space() {
+        let mut guest = guest(0x0100_0000);
+        guest.devices[0].device_type = "net".to_string();
+        guest.devices[1].device_type = "net".to_string();
+        guest.devices[1]
+            .options
+            .insert("mac".to_string(), "52:54:00:12:34:56".to_string());
+        let simulated = SimulatedGuest::new(VmId(0), &guest, None).unwrap();
+        let mac = |index: usize| {
+            let model = simulated.model(index);
+            let mut model = model.lock().unwrap();
+            model.write(VIRTIO_MMIO_DEVICE_FEATURES_SEL, 0);
+            assert_ne!(
+                u64::from(model.read(VIRTIO_MMIO_DEVICE_FEATURES)) & VIRTIO_NET_F_MAC,
+                0
+            );
+            let low = model.read(VIRTIO_MMIO_CONFIG).to_le_bytes();
+            let high = model.read(VIRTIO_MMIO_CONFIG + 4).to_le_bytes();
+            // Past the end of the configuration space
+            assert_eq!(high[2..], [0, 0]);
+            [low[0], low[1], low[2], low[3], high[0], high[1]]
+        };
+        // Derived from the frontend, guest and device IDs, or set explicitly
+        let derived = MacAddress::derive(VmId(0), VmId(0), DeviceId(VIRTIO_ID_RNG));
+        assert_eq!(mac(0), derived.0);
+        assert_eq!(mac(1), [0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+
+        // Kept across a reset
+        let model = simulated.model(1);
+        model.lock().unwrap().write(VIRTIO_MMIO_STATUS, 0);
+        drop(model);
+        assert_eq!(mac(1), [0x52, 0x54, 0x00, 0x12, 0x34, 0x56]);
+
+        // Other devices have neither
+        let guest = self::guest(0x0100_0000);
+        let simulated = SimulatedGuest::new(VmId(0), &guest, None).unwrap();
+        let model = simulated.model(0);
+        let mut model = model.lock().unwrap();
+        assert_eq!(
+            u64::from(model.read(VIRTIO_MMIO_DEVICE_FEATURES)) & VIRTIO_NET_F_MAC,
+            0
+        );
+        assert_eq!(model.read(VIRTIO_MMIO_CONFIG), 0);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut guest = guest(0x0100_0000);
+        guest.devices[0].device_type = BAO_LOOPBACK_DEVICE_TYPE.to_string();
+        let mut simulated = SimulatedGuest::new(VmId(0), &guest, None).unwrap();
+        simulated.init_device(0).unwrap();
+        simulated.echo(0, 3, &[1, 2, 3]).unwrap();
+        let state = simulated.model(0).lock().unwrap().save().unwrap();
+        assert_eq!(state.transport.status, 0xf);
+        assert_eq!(state.transport.driver_features, VIRTIO_F_VERSION_1);
+        assert!(state.queues[0].ready);
+        assert_eq!(state.queues[0].last_avail, 3);
+
+        // The replacement device picks up where the first one stopped
+        let replacement = SimulatedGuest::new(VmId(0), &guest, None).unwrap();
+        let model = replacement.model(0);
+        model.lock().unwrap().restore(&state).unwrap();
+        assert_eq!(model.lock().unwrap().save().unwrap(), state);
+        assert!(model
+            .lock()
+            .unwrap()
+            .summary(&guest, &guest.devices[0])
+            .is_some());
+
+        let mut other = state.clone();
+        other.queues.push(QueueState::default());
+        assert!(matches!(
+            model.lock().unwrap().restore(&other),
+            Err(Error::InvalidSnapshot(_))
+        ));
+    }
+
+    #[test]
+    fn test_pause_and_resume() {
+        let guest = guest(0x0100_0000);
+        let mut simulated = SimulatedGuest::new(VmId(0), &guest, None).unwrap();
+        simulated.init_device(0).unwrap();
+        let model = simulated.model(0);
+
+        // Notifications of a stalled device are held back, then served on resume
+        model.lock().unwrap().pause(PauseMode::Stall).unwrap();
+        assert!(matches!(
+            simulated.round(0, 1, &[0; 16]),
+            Err(Error::ScriptMismatch { .. }) | Err(Error::RequestNotCompleted(_))
+        ));
+        assert_eq!(model.lock().unwrap().notifications(), 0);
+        model.lock().unwrap().resume().unwrap();
+        assert_eq!(model.lock().unwrap().notifications(), 1);
+        simulated.round(0, 1, &[0; 16]).unwrap();
+
+        // A device needing a reset tells the guest, and stays paused across the reset
+        let interrupts = simulated.interrupts();
+        model.lock().unwrap().pause(PauseMode::NeedsReset).unwrap();
+        let status = u64::from(model.lock().unwrap().status());
+        assert_ne!(status & VIRTIO_CONFIG_S_NEEDS_RESET, 0);
+        assert_eq!(simulated.interrupts(), interrupts + 1);
+        simulated.init_device(0).unwrap();
+        let status = u64::from(model.lock().unwrap().status());
+        assert_eq!(status & VIRTIO_CONFIG_S_NEEDS_RESET, 0);
+        model.lock().unwrap().resume().unwrap();
+        simulated.round(0, 1, &[0; 16]).unwrap();
+    }
+}
@@ -0,0 +1,274 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao slow request reporting.
+//!
+//! Devices with a `slow_request_ms` threshold get a structured warning for every request
+//! taking longer than that from fetch to completion: much cheaper than tracing every
+//! request, and enough to tell which register and backend stalled.
+
+#![allow(dead_code)]
+
+use super::defines::{BAO_IO_READ, BAO_IO_WRITE, VIRTIO_MMIO_IO_SIZE};
+use super::error::Result;
+use super::hypervisor::Hypervisor;
+use super::types::{BaoIoEventFd, BaoIoRequest, BaoIrqFd, ConfigGuest};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Struct representing a request that exceeded the slow request threshold of its device.
+///
+/// # Attributes
+///
+/// * `guest` - Guest name.
+/// * `device` - Device name.
+/// * `backend` - In-process backend serving the device (`external` if none).
+/// * `reg_off` - Register offset.
+/// * `op` - Request direction (`BAO_IO_*`).
+/// * `elapsed` - Time from fetch to completion.
+/// * `threshold` - Slow request threshold of the device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlowRequest {
+    pub guest: String,
+    pub device: String,
+    pub backend: String,
+    pub reg_off: u64,
+    pub op: u64,
+    pub elapsed: Duration,
+    pub threshold: Duration,
+}
+
+impl fmt::Display for SlowRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let direction = match self.op {
+            BAO_IO_WRITE => "write",
+            BAO_IO_READ => "read",
+            _ => "other",
+        };
+        write!(
+            f,
+            "slow request: guest={} device={} backend={} reg_off={:#x} direction={} elapsed_us={} threshold_us={}",
+            self.guest,
+            self.device,
+            self.backend,
+            self.reg_off,
+            direction,
+            self.elapsed.as_micros(),
+            self.threshold.as_micros()
+        )
+    }
+}
+
+/// Reports a slow request (e.g. logs it).
+pub type SlowRequestReporter = Arc<dyn Fn(&SlowRequest) + Send + Sync>;
+
+/// Struct representing a device whose slow requests are reported.
+///
+/// # Attributes
+///
+/// * `name` - Device name.
+/// * `backend` - In-process backend serving the device (`external` if none).
+/// * `addr` - Device MMIO base address.
+/// * `threshold` - Slow request threshold.
+struct SlowDevice {
+    name: String,
+    backend: String,
+    addr: u64,
+    threshold: Duration,
+}
+
+/// Struct representing a hypervisor reporting the slow requests of a guest.
+///
+/// # Attributes
+///
+/// * `inner` - The hypervisor.
+/// * `guest` - Guest name.
+/// * `devices` - Devices with a slow request threshold.
+/// * `pending` - Fetch time of the requests in flight, by vCPU and address.
+/// * `reporter` - Reporter of the slow requests.
+/// * `count` - Number of slow requests reported.
+pub struct SlowRequestHypervisor<H: Hypervisor> {
+    inner: H,
+    guest: String,
+    devices: Vec<SlowDevice>,
+    pending: Mutex<Vec<(u64, u64, Instant)>>,
+    reporter: SlowRequestReporter,
+    count: AtomicU64,
+}
+
+impl<H: Hypervisor> SlowRequestHypervisor<H> {
+    /// Reports the slow requests of a guest.
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - The hypervisor.
+    /// * `guest` - The guest configuration.
+    /// * `reporter` - Reporter of the slow requests.
+    ///
+    /// # Returns
+    ///
+    /// * `SlowRequestHypervisor` - The reporting hypervisor.
+    pub fn new(inner: H, guest: &ConfigGuest, reporter: SlowRequestReporter) -> Self {
+        let devices = guest
+            .devices
+            .iter()
+            .filter_map(|device| {
+                Some(SlowDevice {
+                    name: device.name.clone(),
+                    backend: device
+                        .backend
+                        .clone()
+                        .unwrap_or_else(|| "external".to_string()),
+                    addr: device.addr.raw(),
+                    threshold: device.slow_request_threshold()?,
+                })
+            })
+            .collect();
+        SlowRequestHypervisor {
+            inner,
+            guest: guest.name.clone(),
+            devices,
+            pending: Mutex::new(Vec::new()),
+            reporter,
+            count: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns the number of slow requests reported.
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Returns the device a request targets, if its slow requests are reported.
+    fn device(&self, req: &BaoIoRequest) -> Option<&SlowDevice> {
+        self.devices
+            .iter()
+            .find(|d| (d.addr..d.addr + VIRTIO_MMIO_IO_SIZE).contains(&req.addr))
+    }
+}
+
+impl<H: Hypervisor> Hypervisor for SlowRequestHypervisor<H> {
+    fn attach_client(&self) -> Result<()> {
+        self.inner.attach_client()
+    }
+
+    fn wait_request(&self, timeout: Duration) -> Result<bool> {
+        self.inner.wait_request(timeout)
+    }
+
+    fn next_request(&self) -> Result<Option<BaoIoRequest>> {
+        let req = self.inner.next_request()?;
+        if let Some(req) = req.filter(|req| self.device(req).is_some()) {
+            self.pending
+                .lock()
+                .unwrap()
+                .push((req.vcpu_id, req.addr, Instant::now()));
+        }
+        Ok(req)
+    }
+
+    fn complete_request(&self, req: &BaoIoRequest) -> Result<()> {
+        self.inner.complete_request(req)?;
+        let device = match self.device(req) {
+            Some(device) => device,
+            None => return Ok(()),
+        };
+        let start = {
+            let mut pending = self.pending.lock().unwrap();
+            match pending
+                .iter()
+                .position(|&(vcpu_id, addr, _)| vcpu_id == req.vcpu_id && addr == req.addr)
+            {
+                Some(index) => pending.swap_remove(index).2,
+                None => return Ok(()),
+            }
+        };
+        let elapsed = start.elapsed();
+        if elapsed > device.threshold {
+            self.count.fetch_add(1, Ordering::Relaxed);
+            (self.reporter)(&SlowRequest {
+                guest: self.guest.clone(),
+                device: device.name.clone(),
+                backend: device.backend.clone(),
+                reg_off: req.reg_off,
+                op: req.op,
+                elapsed,
+                threshold: device.threshold,
+            });
+        }
+        Ok(())
+    }
+
+    fn notify_guest(&self) -> Result<()> {
+        self.inner.notify_guest()
+    }
+
+    fn register_ioeventfd(&self, ioeventfd: &BaoIoEventFd) -> Result<()> {
+        self.inner.register_ioeventfd(ioeventfd)
+    }
+
+    fn register_irqfd(&self, irqfd: &BaoIrqFd) -> Result<()> {
+        self.inner.register_irqfd(irqfd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockHypervisor;
+    use std::thread;
+
+    #[test]
+    fn test_slow_requests() {
+        let guest: ConfigGuest = serde_yaml::from_str(
+            "name: guest0
+id: 1
+ram_addr: 0x50000000
+ram_size: 0x1000000
+shmem_path: /dev/baoipc0
+socket_path: /tmp/
+devices:
+  - {name: rng0, id: 0, type: rng, irq: 47, addr: 0xa003e00, slow_request_ms: 5}
+  - {name: i2c0, id: 1, type: i2c, irq: 46, addr: 0xa003c00}
+",
+        )
+        .unwrap();
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let reported = reports.clone();
+        let mock = MockHypervisor::new();
+        let hypervisor = SlowRequestHypervisor::new(
+            &mock,
+            &guest,
+            Arc::new(move |slow: &SlowRequest| reported.lock().unwrap().push(slow.to_string())),
+        );
+        let request = |addr, reg_off| BaoIoRequest {
+            virtio_id: 0,
+            reg_off,
+            addr: addr + reg_off,
+            op: BAO_IO_WRITE,
+            value: 0,
+            access_width: 4,
+            cpu_id: 0,
+            vcpu_id: 1,
+            ret: 0,
+        };
+
+        // Only the slow requests of devices with a threshold are reported
+        for (addr, delay) in [(0xa003e00, 0), (0xa003e00, 10), (0xa003c00, 10)] {
+            mock.push_request(request(addr, 0x50));
+            let req = hypervisor.next_request().unwrap().unwrap();
+            thread::sleep(Duration::from_millis(delay));
+            hypervisor.complete_request(&req).unwrap();
+        }
+        assert_eq!(hypervisor.count(), 1);
+        let reports = reports.lock().unwrap();
+        assert!(reports[0].starts_with(
+            "slow request: guest=guest0 device=rng0 backend=external reg_off=0x50 direction=write"
+        ));
+        assert!(reports[0].ends_with("threshold_us=5000"));
+    }
+}
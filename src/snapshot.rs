@@ -0,0 +1,246 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao frontend snapshots.
+//!
+//! A snapshot holds, for every device of the frontend, its virtio-mmio transport
+//! registers, the negotiated features, the state of its virtqueues and (if the backend
+//! supports it) an opaque blob of backend state. It is taken by the running frontend and
+//! restored by its replacement, once the same devices were created again, so the frontend
+//! binary can be upgraded in place while the guests keep running. The guests are expected
+//! to be quiescent (e.g. paused) while the snapshot is taken.
+//!
+//! The snapshot is stored as YAML in `BAO_SNAPSHOT_FILE`, inside the snapshot directory.
+
+#![allow(dead_code)]
+
+use super::defines::{BAO_SNAPSHOT_FILE, BAO_SNAPSHOT_VERSION};
+use super::error::{Error, Result};
+use super::management::DeviceInfo;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+
+/// Struct representing the virtio-mmio transport registers of a device.
+///
+/// # Attributes
+///
+/// * `status` - Device status.
+/// * `device_features_sel` - Device features word selected by the driver.
+/// * `driver_features_sel` - Driver features word selected by the driver.
+/// * `driver_features` - Features negotiated with the driver.
+/// * `queue_sel` - Queue selected by the driver.
+/// * `interrupt_status` - Pending interrupts.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TransportState {
+    pub status: u32,
+    pub device_features_sel: u32,
+    pub driver_features_sel: u32,
+    pub driver_features: u64,
+    pub queue_sel: u32,
+    pub interrupt_status: u32,
+}
+
+/// Struct representing the state of a virtqueue.
+///
+/// # Attributes
+///
+/// * `num` - Queue size.
+/// * `ready` - Whether the queue is ready.
+/// * `desc` - Guest address of the descriptor table.
+/// * `avail` - Guest address of the available ring.
+/// * `used` - Guest address of the used ring.
+/// * `last_avail` - Index of the next available buffer to be consumed by the device.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct QueueState {
+    pub num: u32,
+    pub ready: bool,
+    pub desc: u64,
+    pub avail: u64,
+    pub used: u64,
+    pub last_avail: u16,
+}
+
+/// Struct representing the state of a device.
+///
+/// # Attributes
+///
+/// * `transport` - Transport registers.
+/// * `queues` - Virtqueues, in queue index order.
+/// * `backend` - Opaque state of the device backend (None if it does not support it).
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DeviceSnapshot {
+    pub transport: TransportState,
+    pub queues: Vec<QueueState>,
+    #[serde(default)]
+    pub backend: Option<Vec<u8>>,
+}
+
+/// Struct representing a snapshotted device.
+///
+/// # Attributes
+///
+/// * `info` - The device, as configured (must match on restore).
+/// * `state` - Its state.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SnapshotDevice {
+    pub info: DeviceInfo,
+    pub state: DeviceSnapshot,
+}
+
+/// Struct representing a snapshot of the whole frontend.
+///
+/// # Attributes
+///
+/// * `version` - Snapshot format version.
+/// * `devices` - Snapshotted devices.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct FrontendSnapshot {
+    pub version: u32,
+    pub devices: Vec<SnapshotDevice>,
+}
+
+impl FrontendSnapshot {
+    /// Creates a snapshot of the current format version.
+    ///
+    /// # Arguments
+    ///
+    /// * `devices` - Snapshotted devices.
+    ///
+    /// # Returns
+    ///
+    /// * `FrontendSnapshot` - The snapshot.
+    pub fn new(devices: Vec<SnapshotDevice>) -> Self {
+        FrontendSnapshot {
+            version: BAO_SNAPSHOT_VERSION,
+            devices,
+        }
+    }
+
+    /// Writes the snapshot to a directory (created if needed).
+    ///
+    /// The snapshot file is replaced atomically, so a failure leaves any previous
+    /// snapshot of the directory intact.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - Snapshot directory.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the snapshot was written.
+    pub fn write(&self, dir: &Path) -> Result<()> {
+        let yaml =
+            serde_yaml::to_string(self).map_err(|e| Error::InvalidSnapshot(e.to_string()))?;
+        fs::create_dir_all(dir).map_err(Error::SnapshotFailed)?;
+        let path = dir.join(BAO_SNAPSHOT_FILE);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, yaml).map_err(Error::SnapshotFailed)?;
+        fs::rename(&tmp, &path).map_err(Error::SnapshotFailed)
+    }
+
+    /// Reads the snapshot of a directory.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - Snapshot directory.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<FrontendSnapshot>` - The snapshot, or `InvalidSnapshot` if it is malformed
+    ///   or of another format version.
+    pub fn read(dir: &Path) -> Result<Self> {
+        let yaml =
+            fs::read_to_string(dir.join(BAO_SNAPSHOT_FILE)).map_err(Error::SnapshotFailed)?;
+        let snapshot: FrontendSnapshot =
+            serde_yaml::from_str(&yaml).map_err(|e| Error::InvalidSnapshot(e.to_string()))?;
+        if snapshot.version != BAO_SNAPSHOT_VERSION {
+            return Err(Error::InvalidSnapshot(format!(
+                "unsupported version {}",
+                snapshot.version
+            )));
+        }
+        Ok(snapshot)
+    }
+}
+
+/// Trait implemented by the device models whose state can be snapshotted.
+pub trait Snapshot: Send {
+    /// Saves the state of the device.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<DeviceSnapshot>` - The device state.
+    fn save(&self) -> Result<DeviceSnapshot>;
+
+    /// Restores the state of the device.
+    ///
+    /// # Arguments
+    ///
+    /// * `state` - The device state.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the state was restored, `InvalidSnapshot` if it does not fit
+    ///   the device.
+    fn restore(&mut self, state: &DeviceSnapshot) -> Result<()>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{DeviceId, GuestAddress, IrqLine, VmId};
+
+    #[test]
+    fn test_frontend_snapshot() {
+        let dir = std::env::temp_dir().join(format!("bao-snapshot-{}", std::process::id()));
+        let snapshot = FrontendSnapshot::new(vec![SnapshotDevice {
+            info: DeviceInfo {
+                frontend_id: VmId(0),
+                guest_id: VmId(1),
+                name: "rng0".to_string(),
+                device_type: "rng".to_string(),
+                id: DeviceId(4),
+                irq: IrqLine(47),
+                addr: GuestAddress(0xa003e00),
+                uuid: Default::default(),
+                mac: None,
+                labels: Default::default(),
+            },
+            state: DeviceSnapshot {
+                transport: TransportState {
+                    status: 0xf,
+                    driver_features: 1 << 32,
+                    ..Default::default()
+                },
+                queues: vec![QueueState {
+                    num: 256,
+                    ready: true,
+                    desc: 0x6000_0000,
+                    avail: 0x6000_4000,
+                    used: 0x6000_8000,
+                    last_avail: 7,
+                }],
+                backend: Some(vec![1, 2, 3]),
+            },
+        }]);
+        snapshot.write(&dir).unwrap();
+        assert_eq!(FrontendSnapshot::read(&dir).unwrap(), snapshot);
+
+        // Snapshots of other format versions are rejected
+        let mut newer = snapshot.clone();
+        newer.version += 1;
+        newer.write(&dir).unwrap();
+        assert!(matches!(
+            FrontendSnapshot::read(&dir),
+            Err(Error::InvalidSnapshot(_))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(
+            FrontendSnapshot::read(&dir),
+            Err(Error::SnapshotFailed(_))
+        ));
+    }
+}
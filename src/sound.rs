@@ -0,0 +1,808 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao built-in sound backend.
+//!
+//! Serves the output PCM streams of a virtio-snd device in-process (`backend: bao-snd`),
+//! each routed to a host sink of its own by the backend options:
+//!
+//! * `streamN` - Sink of stream N (streams being numbered from 0, without gaps): an ALSA
+//!   device (`alsa:DEVICE`, e.g. `alsa:hw:0,0`) or a PipeWire node (`pipewire:NODE`).
+//! * `streamN_volume` - Volume of stream N, in percent (100 by default).
+//! * `streamN_mute` - Whether stream N is muted (`true`) or not (`false`, the default).
+//!
+//! Streams carry interleaved signed 16-bit little-endian frames, of one or two channels,
+//! at 44100 or 48000 Hz. A prepared stream is played by a player process of the host
+//! (`aplay` for ALSA devices, `pw-cat` for PipeWire nodes), until released.
+//!
+//! The volume and mute of a stream are applied to its frames before they reach the
+//! host, so the guest cannot override them: the host keeps the final say on what is
+//! heard alongside its own safety relevant sounds (chimes, warnings).
+
+#![allow(dead_code)]
+
+use super::backend::InProcessBackend;
+use super::defines::*;
+use super::error::{Error, Result};
+use super::poll::EventPoller;
+use super::types::ConfigDevice;
+use super::vhost_backend::{VhostUserBackend, VirtioDevice};
+use std::fmt;
+use std::io::{self, Write};
+use std::process::{Child, Command, Stdio};
+use std::str::FromStr;
+
+/// Enum representing the host sink of a stream.
+///
+/// # Variants
+///
+/// * `Alsa` - An ALSA device.
+/// * `PipeWire` - A PipeWire node.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SoundRoute {
+    Alsa(String),
+    PipeWire(String),
+}
+
+impl fmt::Display for SoundRoute {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SoundRoute::Alsa(device) => write!(f, "alsa:{}", device),
+            SoundRoute::PipeWire(node) => write!(f, "pipewire:{}", node),
+        }
+    }
+}
+
+impl FromStr for SoundRoute {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some(("alsa", device)) if !device.is_empty() => Ok(SoundRoute::Alsa(device.into())),
+            Some(("pipewire", node)) if !node.is_empty() => Ok(SoundRoute::PipeWire(node.into())),
+            _ => Err(()),
+        }
+    }
+}
+
+impl SoundRoute {
+    /// Builds the command playing a stream on the sink.
+    ///
+    /// # Arguments
+    ///
+    /// * `params` - Parameters of the stream.
+    ///
+    /// # Returns
+    ///
+    /// * `Command` - The player, reading the frames from its standard input.
+    pub fn command(&self, params: &PcmParams) -> Command {
+        let (rate, channels) = (params.rate.to_string(), params.channels.to_string());
+        let mut command;
+        match self {
+            SoundRoute::Alsa(device) => {
+                command = Command::new("aplay");
+                command.args(["-q", "-D", device, "-t", "raw", "-f", "S16_LE"]);
+                command.args(["-r", &rate, "-c", &channels]);
+            }
+            SoundRoute::PipeWire(node) => {
+                command = Command::new("pw-cat");
+                command.args(["--playback", "--target", node, "--format", "s16"]);
+                command.args(["--rate", &rate, "--channels", &channels, "-"]);
+            }
+        }
+        command
+    }
+}
+
+/// Struct representing an output stream, as configured.
+///
+/// # Attributes
+///
+/// * `route` - Host sink of the stream.
+/// * `volume` - Volume, in percent.
+/// * `mute` - Whether the stream is muted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SoundStream {
+    pub route: SoundRoute,
+    pub volume: u8,
+    pub mute: bool,
+}
+
+impl SoundStream {
+    /// Applies the volume and mute of the stream to frames.
+    ///
+    /// # Arguments
+    ///
+    /// * `frames` - Signed 16-bit little-endian samples.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<u8>` - The frames to play.
+    pub fn attenuate(&self, frames: &[u8]) -> Vec<u8> {
+        match (self.mute, self.volume) {
+            (true, _) | (false, 0) => vec![0; frames.len()],
+            (false, 100) => frames.to_vec(),
+            (false, volume) => frames
+                .chunks(2)
+                .flat_map(|sample| match sample {
+                    [low, high] => {
+                        let sample = i32::from(i16::from_le_bytes([*low, *high]));
+                        ((sample * i32::from(volume) / 100) as i16).to_le_bytes()
+                    }
+                    // A stray byte (frames are made of whole samples) is silenced
+                    _ => [0, 0],
+                })
+                .take(frames.len())
+                .collect(),
+        }
+    }
+}
+
+/// Reads the output streams of a device from its backend options.
+///
+/// # Arguments
+///
+/// * `device` - The device, whose `streamN` options route its streams.
+///
+/// # Returns
+///
+/// * `Result<Vec<SoundStream>>` - The streams, or `InvalidBackendOption`.
+pub fn sound_streams(device: &ConfigDevice) -> Result<Vec<SoundStream>> {
+    let invalid = |key: &str, reason: String| Error::InvalidBackendOption(key.to_string(), reason);
+    let mut streams = Vec::new();
+    while let Some(route) = device.options.get(&format!("stream{}", streams.len())) {
+        let index = streams.len();
+        let key = |suffix: &str| format!("stream{}_{}", index, suffix);
+        let option = |suffix: &str| device.options.get(&key(suffix)).map(String::as_str);
+        let route = route.parse().map_err(|_| {
+            invalid(
+                &format!("stream{}", index),
+                format!("{} is not alsa:DEVICE or pipewire:NODE", route),
+            )
+        })?;
+        let volume = match option("volume") {
+            None => 100,
+            Some(volume) => volume
+                .parse()
+                .ok()
+                .filter(|volume| *volume <= 100)
+                .ok_or_else(|| {
+                    invalid(&key("volume"), format!("{} is not a percentage", volume))
+                })?,
+        };
+        let mute = match option("mute") {
+            None | Some("false") => false,
+            Some("true") => true,
+            Some(value) => {
+                return Err(invalid(&key("mute"), format!("{} is not a boolean", value)))
+            }
+        };
+        streams.push(SoundStream {
+            route,
+            volume,
+            mute,
+        });
+    }
+
+    // Options of streams past the last one are mistakes (e.g. a gap in the numbering)
+    if let Some(key) = device.options.keys().find(|key| {
+        key.strip_prefix("stream")
+            .and_then(|rest| rest.split('_').next()?.parse::<usize>().ok())
+            .is_some_and(|index| index >= streams.len())
+    }) {
+        return Err(invalid(key, "no such stream".to_string()));
+    }
+    match streams.len() {
+        0 => Err(invalid("stream0", "missing".to_string())),
+        n if n > BAO_SOUND_STREAMS_MAX => Err(invalid(
+            &format!("stream{}", n - 1),
+            format!("more than {} streams", BAO_SOUND_STREAMS_MAX),
+        )),
+        _ => Ok(streams),
+    }
+}
+
+/// Struct representing the parameters of a PCM stream (signed 16-bit little-endian
+/// frames).
+///
+/// # Attributes
+///
+/// * `channels` - Number of channels.
+/// * `rate` - Frame rate, in Hz.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PcmParams {
+    pub channels: u8,
+    pub rate: u32,
+}
+
+/// The host end of a prepared stream.
+pub trait PcmOutput: Send {
+    /// Plays frames.
+    ///
+    /// # Arguments
+    ///
+    /// * `frames` - The frames.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<()>` - Ok once the frames are queued for playback.
+    fn write(&mut self, frames: &[u8]) -> io::Result<()>;
+}
+
+/// The host sinks of the streams.
+pub trait PcmSinks: Send {
+    /// Opens a sink for a stream.
+    ///
+    /// # Arguments
+    ///
+    /// * `route` - The sink.
+    /// * `params` - Parameters of the stream.
+    ///
+    /// # Returns
+    ///
+    /// * `io::Result<Box<dyn PcmOutput>>` - The host end of the stream.
+    fn open(&mut self, route: &SoundRoute, params: &PcmParams) -> io::Result<Box<dyn PcmOutput>>;
+}
+
+/// Struct representing a player process of the host.
+///
+/// # Attributes
+///
+/// * `child` - The player, reading the frames from its standard input.
+#[derive(Debug)]
+pub struct Player {
+    child: Child,
+}
+
+impl PcmOutput for Player {
+    fn write(&mut self, frames: &[u8]) -> io::Result<()> {
+        self.child.stdin.as_mut().unwrap().write_all(frames)
+    }
+}
+
+impl Drop for Player {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Struct representing the host sinks played by player processes.
+#[derive(Debug, Default)]
+pub struct PlayerSinks;
+
+impl PcmSinks for PlayerSinks {
+    fn open(&mut self, route: &SoundRoute, params: &PcmParams) -> io::Result<Box<dyn PcmOutput>> {
+        let child = route
+            .command(params)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        Ok(Box::new(Player { child }))
+    }
+}
+
+/// Struct representing the state of a stream.
+///
+/// # Attributes
+///
+/// * `params` - Parameters set by the guest.
+/// * `output` - Host end of the stream, once prepared.
+/// * `running` - Whether the stream is started.
+#[derive(Default)]
+struct PcmState {
+    params: Option<PcmParams>,
+    output: Option<Box<dyn PcmOutput>>,
+    running: bool,
+}
+
+/// Struct representing a virtio-snd device with output streams.
+///
+/// # Attributes
+///
+/// * `sinks` - The host sinks.
+/// * `streams` - The streams, as configured.
+/// * `state` - State of the streams.
+pub struct SoundDevice<S: PcmSinks> {
+    sinks: S,
+    streams: Vec<SoundStream>,
+    state: Vec<PcmState>,
+}
+
+impl<S: PcmSinks> SoundDevice<S> {
+    /// Creates a sound device.
+    ///
+    /// # Arguments
+    ///
+    /// * `sinks` - The host sinks.
+    /// * `streams` - The streams.
+    ///
+    /// # Returns
+    ///
+    /// * `SoundDevice<S>` - The device.
+    pub fn new(sinks: S, streams: Vec<SoundStream>) -> Self {
+        SoundDevice {
+            sinks,
+            state: streams.iter().map(|_| PcmState::default()).collect(),
+            streams,
+        }
+    }
+
+    /// Serves a control request.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The request.
+    /// * `response` - The response (status, then information).
+    ///
+    /// # Returns
+    ///
+    /// * `(u32, usize)` - The status, and the size of the information written after it.
+    fn control(&mut self, request: &[u8], response: &mut [u8]) -> (u32, usize) {
+        let le32 = |offset: usize| {
+            request
+                .get(offset..offset + 4)
+                .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+        };
+        let code = match le32(0) {
+            Some(code) => code,
+            None => return (VIRTIO_SND_S_BAD_MSG, 0),
+        };
+        if code == VIRTIO_SND_R_PCM_INFO {
+            return self.pcm_info(request, &mut response[4..]);
+        }
+        if code == VIRTIO_SND_R_JACK_INFO || code == VIRTIO_SND_R_CHMAP_INFO {
+            // No jacks nor channel maps to describe
+            return (VIRTIO_SND_S_BAD_MSG, 0);
+        }
+
+        let stream = match le32(4).map(|id| id as usize) {
+            Some(id) if id < self.streams.len() => id,
+            _ => return (VIRTIO_SND_S_BAD_MSG, 0),
+        };
+        let state = &mut self.state[stream];
+        let status = match code {
+            VIRTIO_SND_R_PCM_SET_PARAMS if request.len() >= VIRTIO_SND_PCM_SET_PARAMS_SIZE => {
+                let (channels, format, rate) = (request[20], request[21], request[22]);
+                let rate = match rate {
+                    VIRTIO_SND_PCM_RATE_44100 => Some(44100),
+                    VIRTIO_SND_PCM_RATE_48000 => Some(48000),
+                    _ => None,
+                };
+                match (channels, format, rate) {
+                    _ if state.running => VIRTIO_SND_S_BAD_MSG,
+                    (1..=2, VIRTIO_SND_PCM_FMT_S16, Some(rate)) => {
+                        state.params = Some(PcmParams { channels, rate });
+                        VIRTIO_SND_S_OK
+                    }
+                    _ => VIRTIO_SND_S_NOT_SUPP,
+                }
+            }
+            VIRTIO_SND_R_PCM_PREPARE => match state.params {
+                _ if state.running => VIRTIO_SND_S_BAD_MSG,
+                Some(params) => {
+                    // A prepared stream starts over from a new player
+                    state.output = None;
+                    match self.sinks.open(&self.streams[stream].route, &params) {
+                        Ok(output) => {
+                            state.output = Some(output);
+                            VIRTIO_SND_S_OK
+                        }
+                        Err(_) => VIRTIO_SND_S_IO_ERR,
+                    }
+                }
+                None => VIRTIO_SND_S_BAD_MSG,
+            },
+            VIRTIO_SND_R_PCM_RELEASE => {
+                state.output = None;
+                state.running = false;
+                VIRTIO_SND_S_OK
+            }
+            VIRTIO_SND_R_PCM_START if state.output.is_some() => {
+                state.running = true;
+                VIRTIO_SND_S_OK
+            }
+            VIRTIO_SND_R_PCM_STOP => {
+                state.running = false;
+                VIRTIO_SND_S_OK
+            }
+            VIRTIO_SND_R_PCM_SET_PARAMS | VIRTIO_SND_R_PCM_START => VIRTIO_SND_S_BAD_MSG,
+            _ => VIRTIO_SND_S_NOT_SUPP,
+        };
+        (status, 0)
+    }
+
+    /// Describes streams.
+    ///
+    /// # Arguments
+    ///
+    /// * `request` - The query (first stream, number of streams and size of their
+    ///   information).
+    /// * `info` - Where to write the information of the streams.
+    ///
+    /// # Returns
+    ///
+    /// * `(u32, usize)` - The status, and the size of the information written.
+    fn pcm_info(&self, request: &[u8], info: &mut [u8]) -> (u32, usize) {
+        if request.len() < VIRTIO_SND_QUERY_INFO_SIZE {
+            return (VIRTIO_SND_S_BAD_MSG, 0);
+        }
+        let le32 = |offset: usize| {
+            u32::from_le_bytes(request[offset..offset + 4].try_into().unwrap()) as usize
+        };
+        let (start, count, size) = (le32(4), le32(8), le32(12));
+        let len = count.saturating_mul(size);
+        if start.saturating_add(count) > self.streams.len()
+            || size < VIRTIO_SND_PCM_INFO_SIZE
+            || len > info.len()
+        {
+            return (VIRTIO_SND_S_BAD_MSG, 0);
+        }
+        let formats = 1u64 << VIRTIO_SND_PCM_FMT_S16;
+        let rates = 1u64 << VIRTIO_SND_PCM_RATE_44100 | 1 << VIRTIO_SND_PCM_RATE_48000;
+        for entry in info[..len].chunks_mut(size) {
+            // No function node and no features
+            entry[8..16].copy_from_slice(&formats.to_le_bytes());
+            entry[16..24].copy_from_slice(&rates.to_le_bytes());
+            entry[24] = VIRTIO_SND_D_OUTPUT;
+            (entry[25], entry[26]) = (1, 2);
+        }
+        (VIRTIO_SND_S_OK, len)
+    }
+
+    /// Plays frames of a stream.
+    ///
+    /// # Arguments
+    ///
+    /// * `xfer` - The transfer (stream ID, then frames).
+    ///
+    /// # Returns
+    ///
+    /// * `u32` - The status of the transfer.
+    fn transfer(&mut self, xfer: &[u8]) -> u32 {
+        let stream = match xfer.get(..4) {
+            Some(id) => u32::from_le_bytes(id.try_into().unwrap()) as usize,
+            None => return VIRTIO_SND_S_BAD_MSG,
+        };
+        let frames = match self.streams.get(stream) {
+            Some(config) => config.attenuate(&xfer[4..]),
+            None => return VIRTIO_SND_S_BAD_MSG,
+        };
+        // Frames are queued once prepared, ahead of the start
+        match self.state[stream].output.as_mut().map(|o| o.write(&frames)) {
+            Some(Ok(())) => VIRTIO_SND_S_OK,
+            Some(Err(_)) => VIRTIO_SND_S_IO_ERR,
+            None => VIRTIO_SND_S_BAD_MSG,
+        }
+    }
+}
+
+impl<S: PcmSinks> VirtioDevice for SoundDevice<S> {
+    fn features(&self) -> u64 {
+        VIRTIO_F_VERSION_1
+    }
+
+    fn num_queues(&self) -> usize {
+        // The control, event, transmit and receive queues
+        4
+    }
+
+    fn config(&self) -> Vec<u8> {
+        // Number of jacks, streams and channel maps
+        let mut config = 0u32.to_le_bytes().to_vec();
+        config.extend_from_slice(&(self.streams.len() as u32).to_le_bytes());
+        config.extend_from_slice(&0u32.to_le_bytes());
+        config
+    }
+
+    fn process(&mut self, readable: &[u8], writable: &mut [u8]) -> u32 {
+        self.process_chain(VIRTIO_SND_VQ_CONTROL, readable, writable)
+    }
+
+    fn process_chain(&mut self, queue: usize, readable: &[u8], writable: &mut [u8]) -> u32 {
+        match queue {
+            VIRTIO_SND_VQ_CONTROL if writable.len() >= 4 => {
+                let (status, len) = self.control(readable, writable);
+                writable[..4].copy_from_slice(&status.to_le_bytes());
+                (4 + len) as u32
+            }
+            VIRTIO_SND_VQ_TX if writable.len() >= VIRTIO_SND_PCM_STATUS_SIZE => {
+                let status = self.transfer(readable);
+                // No latency to report: the frames are handed to the player as they come
+                writable[..4].copy_from_slice(&status.to_le_bytes());
+                VIRTIO_SND_PCM_STATUS_SIZE as u32
+            }
+            // No events (and no input streams): the buffers are kept
+            VIRTIO_SND_VQ_EVENT | VIRTIO_SND_VQ_RX => BAO_CHAIN_DEFERRED,
+            _ => 0,
+        }
+    }
+}
+
+/// Creates the built-in sound backend of a device.
+///
+/// # Arguments
+///
+/// * `device` - The device, whose `streamN` options route its output streams.
+///
+/// # Returns
+///
+/// * `Result<Box<dyn InProcessBackend>>` - The backend serving the device, or
+///   `InvalidBackendOption` if an option is missing or invalid.
+pub fn sound_backend(device: &ConfigDevice) -> Result<Box<dyn InProcessBackend>> {
+    let poller = EventPoller::new(device);
+    let device = SoundDevice::new(PlayerSinks, sound_streams(device)?);
+    Ok(Box::new(
+        VhostUserBackend::new(Box::new(device)).with_poller(poller),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::backend_device;
+    use std::sync::{Arc, Mutex};
+
+    fn device(options: &[(&str, &str)]) -> ConfigDevice {
+        backend_device("snd0", "sound", Some(BAO_SOUND_BACKEND), options)
+    }
+
+    /// Streams played: route, parameters and frames.
+    type Played = Arc<Mutex<Vec<(String, PcmParams, Vec<u8>)>>>;
+
+    /// Sinks recording what is played.
+    #[derive(Default, Clone)]
+    struct FakeSinks(Played);
+
+    struct FakeOutput(Played, usize);
+
+    impl PcmOutput for FakeOutput {
+        fn write(&mut self, frames: &[u8]) -> io::Result<()> {
+            self.0.lock().unwrap()[self.1].2.extend_from_slice(frames);
+            Ok(())
+        }
+    }
+
+    impl PcmSinks for FakeSinks {
+        fn open(
+            &mut self,
+            route: &SoundRoute,
+            params: &PcmParams,
+        ) -> io::Result<Box<dyn PcmOutput>> {
+            if route == &SoundRoute::Alsa("broken".to_string()) {
+                return Err(io::Error::from_raw_os_error(libc::ENOENT));
+            }
+            let mut played = self.0.lock().unwrap();
+            played.push((route.to_string(), *params, Vec::new()));
+            Ok(Box::new(FakeOutput(self.0.clone(), played.len() - 1)))
+        }
+    }
+
+    /// Sends a control request, returning the status and the information.
+    fn control(device: &mut SoundDevice<FakeSinks>, request: &[u8], len: usize) -> (u32, Vec<u8>) {
+        let mut response = vec![0u8; 4 + len];
+        let written = device.process_chain(VIRTIO_SND_VQ_CONTROL, request, &mut response);
+        let status = u32::from_le_bytes(response[..4].try_into().unwrap());
+        (status, response[4..written as usize].to_vec())
+    }
+
+    fn pcm(code: u32, stream: u32) -> Vec<u8> {
+        [code.to_le_bytes(), stream.to_le_bytes()].concat()
+    }
+
+    fn set_params(stream: u32, channels: u8, format: u8, rate: u8) -> Vec<u8> {
+        let mut request = pcm(VIRTIO_SND_R_PCM_SET_PARAMS, stream);
+        request.extend_from_slice(&[0; 12]);
+        request.extend_from_slice(&[channels, format, rate, 0]);
+        request
+    }
+
+    fn transfer(device: &mut SoundDevice<FakeSinks>, stream: u32, frames: &[u8]) -> u32 {
+        let xfer = [&stream.to_le_bytes()[..], frames].concat();
+        let mut status = [0u8; VIRTIO_SND_PCM_STATUS_SIZE];
+        assert_eq!(
+            device.process_chain(VIRTIO_SND_VQ_TX, &xfer, &mut status),
+            8
+        );
+        u32::from_le_bytes(status[..4].try_into().unwrap())
+    }
+
+    #[test]
+    fn test_sound_streams() {
+        let streams = sound_streams(&device(&[
+            ("stream0", "alsa:hw:0,0"),
+            ("stream1", "pipewire:chimes"),
+            ("stream1_volume", "40"),
+            ("stream1_mute", "true"),
+        ]))
+        .unwrap();
+        assert_eq!(
+            streams,
+            [
+                SoundStream {
+                    route: SoundRoute::Alsa("hw:0,0".to_string()),
+                    volume: 100,
+                    mute: false
+                },
+                SoundStream {
+                    route: SoundRoute::PipeWire("chimes".to_string()),
+                    volume: 40,
+                    mute: true
+                },
+            ]
+        );
+
+        let params = PcmParams {
+            channels: 2,
+            rate: 48000,
+        };
+        let command = streams[0].route.command(&params);
+        assert_eq!(command.get_program(), "aplay");
+        let args = command.get_args().collect::<Vec<_>>();
+        assert_eq!(args[..3], ["-q", "-D", "hw:0,0"]);
+        assert_eq!(args[args.len() - 4..], ["-r", "48000", "-c", "2"]);
+        let command = streams[1].route.command(&params);
+        assert_eq!(command.get_program(), "pw-cat");
+        assert!(command.get_args().any(|arg| arg == "chimes"));
+
+        for (options, key) in [
+            (&[][..], "stream0"),
+            (&[("stream0", "oss:/dev/dsp")][..], "stream0"),
+            (&[("stream0", "alsa:")][..], "stream0"),
+            (
+                &[("stream0", "alsa:default"), ("stream0_volume", "101")][..],
+                "stream0_volume",
+            ),
+            (
+                &[("stream0", "alsa:default"), ("stream0_mute", "1")][..],
+                "stream0_mute",
+            ),
+            (
+                &[("stream0", "alsa:default"), ("stream2", "alsa:default")][..],
+                "stream2",
+            ),
+            (
+                &[("stream0", "alsa:default"), ("stream1_volume", "50")][..],
+                "stream1_volume",
+            ),
+        ] {
+            assert!(
+                matches!(
+                    sound_streams(&device(options)),
+                    Err(Error::InvalidBackendOption(option, _)) if option == key
+                ),
+                "{:?}",
+                options
+            );
+        }
+        let many = (0..=BAO_SOUND_STREAMS_MAX)
+            .map(|i| (format!("stream{}", i), "alsa:default".to_string()))
+            .collect::<Vec<_>>();
+        let many = many
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect::<Vec<_>>();
+        assert!(sound_streams(&device(&many)).is_err());
+    }
+
+    #[test]
+    fn test_sound_attenuate() {
+        let frames = [1000i16, -1000, i16::MAX, i16::MIN]
+            .iter()
+            .flat_map(|s| s.to_le_bytes())
+            .collect::<Vec<_>>();
+        let mut stream = SoundStream {
+            route: SoundRoute::Alsa("default".to_string()),
+            volume: 100,
+            mute: false,
+        };
+        assert_eq!(stream.attenuate(&frames), frames);
+        stream.volume = 50;
+        let half = [500i16, -500, i16::MAX / 2, i16::MIN / 2]
+            .iter()
+            .flat_map(|s| s.to_le_bytes())
+            .collect::<Vec<_>>();
+        assert_eq!(stream.attenuate(&frames), half);
+        assert_eq!(stream.attenuate(&frames[..3]), [half[0], half[1], 0]);
+        stream.mute = true;
+        assert_eq!(stream.attenuate(&frames), [0; 8]);
+    }
+
+    #[test]
+    fn test_sound_device() {
+        let streams = sound_streams(&device(&[
+            ("stream0", "alsa:default"),
+            ("stream1", "pipewire:chimes"),
+            ("stream1_volume", "50"),
+            ("stream2", "alsa:broken"),
+        ]))
+        .unwrap();
+        let sinks = FakeSinks::default();
+        let mut device = SoundDevice::new(sinks.clone(), streams);
+        assert_eq!(device.config(), [0, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0]);
+        const OK: u32 = VIRTIO_SND_S_OK;
+        const BAD_MSG: u32 = VIRTIO_SND_S_BAD_MSG;
+
+        // Stream information
+        let query = |start: u32, count: u32| {
+            [VIRTIO_SND_R_PCM_INFO, start, count, 32]
+                .iter()
+                .flat_map(|v| v.to_le_bytes())
+                .collect::<Vec<_>>()
+        };
+        let (status, info) = control(&mut device, &query(1, 2), 64);
+        assert_eq!((status, info.len()), (OK, 64));
+        assert_eq!(info[8..16], (1u64 << 5).to_le_bytes());
+        assert_eq!(info[16..24], (3u64 << 6).to_le_bytes());
+        assert_eq!(info[24..27], [VIRTIO_SND_D_OUTPUT, 1, 2]);
+        assert_eq!(control(&mut device, &query(2, 2), 64).0, BAD_MSG);
+        assert_eq!(control(&mut device, &query(0, 3), 64).0, BAD_MSG);
+        let jacks = [VIRTIO_SND_R_JACK_INFO, 0, 1, 24]
+            .iter()
+            .flat_map(|v| v.to_le_bytes())
+            .collect::<Vec<_>>();
+        assert_eq!(control(&mut device, &jacks, 24).0, BAD_MSG);
+
+        // Parameters, preparation and playback, attenuated host-side
+        let s16 = VIRTIO_SND_PCM_FMT_S16;
+        let unsupported = set_params(1, 2, s16, 0);
+        assert_eq!(
+            control(&mut device, &unsupported, 0).0,
+            VIRTIO_SND_S_NOT_SUPP
+        );
+        let prepare = pcm(VIRTIO_SND_R_PCM_PREPARE, 1);
+        assert_eq!(control(&mut device, &prepare, 0).0, BAD_MSG);
+        let start = pcm(VIRTIO_SND_R_PCM_START, 1);
+        assert_eq!(control(&mut device, &start, 0).0, BAD_MSG);
+        assert_eq!(transfer(&mut device, 1, &[0; 4]), BAD_MSG);
+        let params = set_params(1, 2, s16, VIRTIO_SND_PCM_RATE_48000);
+        assert_eq!(control(&mut device, &params, 0).0, OK);
+        assert_eq!(control(&mut device, &prepare, 0).0, OK);
+        assert_eq!(transfer(&mut device, 1, &1000i16.to_le_bytes()), OK);
+        assert_eq!(control(&mut device, &start, 0).0, OK);
+        assert_eq!(control(&mut device, &params, 0).0, BAD_MSG);
+        assert_eq!(transfer(&mut device, 1, &(-2000i16).to_le_bytes()), OK);
+        assert_eq!(
+            sinks.0.lock().unwrap()[0],
+            (
+                "pipewire:chimes".to_string(),
+                PcmParams {
+                    channels: 2,
+                    rate: 48000
+                },
+                [500i16.to_le_bytes(), (-1000i16).to_le_bytes()].concat()
+            )
+        );
+        let stop = pcm(VIRTIO_SND_R_PCM_STOP, 1);
+        assert_eq!(control(&mut device, &stop, 0).0, OK);
+        let release = pcm(VIRTIO_SND_R_PCM_RELEASE, 1);
+        assert_eq!(control(&mut device, &release, 0).0, OK);
+        assert_eq!(transfer(&mut device, 1, &[0; 2]), BAD_MSG);
+
+        // Sinks that cannot be opened, and unknown streams
+        let params = set_params(2, 1, s16, VIRTIO_SND_PCM_RATE_44100);
+        assert_eq!(control(&mut device, &params, 0).0, OK);
+        let prepare = pcm(VIRTIO_SND_R_PCM_PREPARE, 2);
+        assert_eq!(control(&mut device, &prepare, 0).0, VIRTIO_SND_S_IO_ERR);
+        assert_eq!(
+            control(&mut device, &pcm(VIRTIO_SND_R_PCM_START, 3), 0).0,
+            BAD_MSG
+        );
+        assert_eq!(transfer(&mut device, 3, &[]), BAD_MSG);
+        assert_eq!(control(&mut device, &[1, 0], 0).0, BAD_MSG);
+
+        // Event and receive buffers are kept
+        let mut event = [0u8; 8];
+        assert_eq!(
+            device.process_chain(VIRTIO_SND_VQ_EVENT, &[], &mut event),
+            BAO_CHAIN_DEFERRED
+        );
+        assert_eq!(
+            device.process_chain(VIRTIO_SND_VQ_RX, &[0; 4], &mut event),
+            BAO_CHAIN_DEFERRED
+        );
+    }
+}
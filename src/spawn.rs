@@ -0,0 +1,342 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao spawned device backends.
+//!
+//! A device with a `spawn` command gets its external vhost-user backend launched and
+//! supervised by the frontend. The frontend binds the device socket itself and hands the
+//! listening descriptor to the backend through systemd socket activation (`LISTEN_FDS`,
+//! `LISTEN_PID`, `LISTEN_FDNAMES`), so connecting never races the backend startup. Backends
+//! supporting `sd_notify` can also be waited for until they report `READY=1`. A backend
+//! that exits is restarted according to the restart policy of its device, on the same
+//! socket.
+
+#![allow(dead_code)]
+
+use super::backend::device_socket_path;
+use super::defines::BAO_SPAWN_LISTEN_FD;
+use super::error::{Error, Result};
+use super::types::{ConfigDevice, ConfigGuest, ConfigSpawn};
+use std::fs;
+use std::io;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixDatagram, UnixListener};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Interval at which a backend that has not reported readiness is checked for exit.
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Removes a stale socket left at a path by a previous run.
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(Error::SpawnBackendFailed("bind", e)),
+        _ => Ok(()),
+    }
+}
+
+/// Struct representing an external device backend spawned by the frontend.
+///
+/// # Attributes
+///
+/// * `name` - Device name.
+/// * `socket` - The vhost-user socket path.
+/// * `listener` - The listening vhost-user socket, shared by every backend instance.
+/// * `notify` - The readiness notification socket and its path (if waited for).
+/// * `child` - The running backend process.
+/// * `restarts` - Number of times the backend was restarted.
+pub struct SpawnedBackend {
+    name: String,
+    socket: PathBuf,
+    listener: UnixListener,
+    notify: Option<(UnixDatagram, PathBuf)>,
+    child: Child,
+    restarts: u32,
+}
+
+impl SpawnedBackend {
+    /// Spawns the backend of a device, if the device has a `spawn` command.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest` - Guest owning the device.
+    /// * `device` - The device.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<SpawnedBackend>>` - The backend, once ready, or None if the device
+    ///   backend is started separately.
+    pub fn spawn(guest: &ConfigGuest, device: &ConfigDevice) -> Result<Option<Self>> {
+        let spawn = match &device.spawn {
+            Some(spawn) => spawn,
+            None => return Ok(None),
+        };
+        let socket = device_socket_path(guest, device);
+        remove_stale_socket(&socket)?;
+        let listener =
+            UnixListener::bind(&socket).map_err(|e| Error::SpawnBackendFailed("bind", e))?;
+        let notify = match spawn.notify {
+            true => {
+                let path = socket.with_extension("notify");
+                remove_stale_socket(&path)?;
+                let socket =
+                    UnixDatagram::bind(&path).map_err(|e| Error::SpawnBackendFailed("bind", e))?;
+                Some((socket, path))
+            }
+            false => None,
+        };
+        let child = Self::start(&device.name, spawn, &listener, notify.as_ref())?;
+        let mut backend = SpawnedBackend {
+            name: device.name.clone(),
+            socket,
+            listener,
+            notify,
+            child,
+            restarts: 0,
+        };
+        backend.wait_ready(spawn.ready_timeout())?;
+        Ok(Some(backend))
+    }
+
+    /// Returns the process ID of the running backend.
+    pub fn pid(&self) -> u32 {
+        self.child.id()
+    }
+
+    /// Returns the number of times the backend was restarted.
+    pub fn restarts(&self) -> u32 {
+        self.restarts
+    }
+
+    /// Checks the backend, restarting it if it exited and its restart policy allows it.
+    ///
+    /// # Arguments
+    ///
+    /// * `device` - The device.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<bool>` - True if the backend was restarted, or `BackendExited` if it
+    ///   exited for good.
+    pub fn supervise(&mut self, device: &ConfigDevice) -> Result<bool> {
+        let status = match self.child.try_wait() {
+            Ok(Some(status)) => status,
+            Ok(None) => return Ok(false),
+            Err(e) => return Err(Error::SpawnBackendFailed("wait", e)),
+        };
+        let spawn = match &device.spawn {
+            Some(spawn) if device.restart.should_restart(!status.success()) => spawn,
+            _ => return Err(Error::BackendExited(self.name.clone(), status)),
+        };
+        thread::sleep(device.restart_delay(self.restarts));
+        self.restarts += 1;
+        self.child = Self::start(&self.name, spawn, &self.listener, self.notify.as_ref())?;
+        self.wait_ready(spawn.ready_timeout())?;
+        Ok(true)
+    }
+
+    /// Starts a backend instance on the listening socket.
+    fn start(
+        name: &str,
+        spawn: &ConfigSpawn,
+        listener: &UnixListener,
+        notify: Option<&(UnixDatagram, PathBuf)>,
+    ) -> Result<Child> {
+        if spawn.command.is_empty() {
+            return Err(Error::SpawnBackendFailed(
+                "spawn",
+                io::Error::from(io::ErrorKind::InvalidInput),
+            ));
+        }
+        let listen_fd = listener.as_raw_fd();
+        // The shell sets LISTEN_PID to its own PID, which the backend keeps across exec
+        let mut command = Command::new("/bin/sh");
+        command
+            .arg("-c")
+            .arg("export LISTEN_PID=$$; exec \"$0\" \"$@\"")
+            .args(&spawn.command)
+            .env("LISTEN_FDS", "1")
+            .env("LISTEN_FDNAMES", name);
+        if let Some((socket, path)) = notify {
+            // Forget the notifications of a previous instance
+            let mut buffer = [0u8; 256];
+            socket
+                .set_nonblocking(true)
+                .map_err(|e| Error::SpawnBackendFailed("notify", e))?;
+            while socket.recv(&mut buffer).is_ok() {}
+            command.env("NOTIFY_SOCKET", path);
+        }
+
+        // SAFETY: Only async-signal-safe functions are called between fork and exec.
+        unsafe {
+            command.pre_exec(move || {
+                // Move the socket to the first activation descriptor (dup2 clears
+                // FD_CLOEXEC, which must be cleared by hand if it is already there)
+                let result = match listen_fd == BAO_SPAWN_LISTEN_FD {
+                    true => libc::fcntl(listen_fd, libc::F_SETFD, 0),
+                    false => libc::dup2(listen_fd, BAO_SPAWN_LISTEN_FD),
+                };
+                if result < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            })
+        };
+        command
+            .spawn()
+            .map_err(|e| Error::SpawnBackendFailed("spawn", e))
+    }
+
+    /// Waits for the backend to report readiness, if it is expected to.
+    fn wait_ready(&mut self, timeout: Duration) -> Result<()> {
+        let socket = match &self.notify {
+            Some((socket, _)) => socket,
+            None => return Ok(()),
+        };
+        socket
+            .set_nonblocking(false)
+            .map_err(|e| Error::SpawnBackendFailed("notify", e))?;
+        let deadline = Instant::now() + timeout;
+        let mut buffer = [0u8; 256];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                let _ = self.child.kill();
+                let _ = self.child.wait();
+                return Err(Error::BackendNotReady(self.name.clone()));
+            }
+            socket
+                .set_read_timeout(Some(remaining.min(READY_POLL_INTERVAL)))
+                .map_err(|e| Error::SpawnBackendFailed("notify", e))?;
+            match socket.recv(&mut buffer) {
+                // Notifications are newline-separated assignments
+                Ok(len) => {
+                    if buffer[..len]
+                        .split(|&b| b == b'\n')
+                        .any(|l| l == b"READY=1")
+                    {
+                        return Ok(());
+                    }
+                }
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock
+                            | io::ErrorKind::TimedOut
+                            | io::ErrorKind::Interrupted
+                    ) => {}
+                Err(e) => return Err(Error::SpawnBackendFailed("notify", e)),
+            }
+            if let Ok(Some(status)) = self.child.try_wait() {
+                return Err(Error::BackendExited(self.name.clone(), status));
+            }
+        }
+    }
+}
+
+impl Drop for SpawnedBackend {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_file(&self.socket);
+        if let Some((_, path)) = &self.notify {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{RestartPolicy, VmId};
+    use std::os::unix::net::UnixStream;
+
+    fn supervise_until_exit(backend: &mut SpawnedBackend, device: &ConfigDevice) -> Result<bool> {
+        loop {
+            match backend.supervise(device) {
+                Ok(false) => thread::sleep(Duration::from_millis(10)),
+                result => return result,
+            }
+        }
+    }
+
+    #[test]
+    fn test_spawned_backend() {
+        let dir = std::env::temp_dir().join(format!("bao-spawn-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let guest = ConfigGuest {
+            name: "guest0".to_string(),
+            id: VmId(1),
+            ram_addr: Default::default(),
+            ram_size: 0,
+            shmem_path: "/dev/baoipc0".to_string(),
+            socket_path: dir.to_str().unwrap().to_string(),
+            devices: vec![],
+            sched: None,
+            watch_dir: None,
+            dt_overlay: None,
+            prefault: false,
+            thp: Default::default(),
+            dtb: None,
+            labels: Default::default(),
+        };
+        let script = |script: &str| {
+            Some(ConfigSpawn {
+                command: vec!["/bin/sh".to_string(), "-c".to_string(), script.to_string()],
+                notify: false,
+                ready_timeout_ms: Some(200),
+            })
+        };
+        let mut device = ConfigDevice {
+            name: "rng0".to_string(),
+            device_type: "rng".to_string(),
+            ..Default::default()
+        };
+        assert!(SpawnedBackend::spawn(&guest, &device).unwrap().is_none());
+
+        // The backend inherits the listening socket, which accepts connections right away
+        device.spawn = script(
+            "[ \"$LISTEN_FDS\" = 1 ] && [ \"$LISTEN_PID\" = $$ ] && \
+             [ \"$LISTEN_FDNAMES\" = rng0 ] && readlink /proc/$$/fd/3 | grep -q socket",
+        );
+        let mut backend = SpawnedBackend::spawn(&guest, &device).unwrap().unwrap();
+        UnixStream::connect(dir.join("rng0.sock")).unwrap();
+        match supervise_until_exit(&mut backend, &device) {
+            Err(Error::BackendExited(name, status)) => {
+                assert_eq!(name, "rng0");
+                assert!(status.success());
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        drop(backend);
+        assert!(!dir.join("rng0.sock").exists());
+
+        // Failed backends are restarted according to the restart policy
+        device.spawn = script("exit 1");
+        device.restart = RestartPolicy::OnFailure;
+        device.restart_delay_ms = Some(1);
+        let mut backend = SpawnedBackend::spawn(&guest, &device).unwrap().unwrap();
+        assert!(supervise_until_exit(&mut backend, &device).unwrap());
+        assert_eq!(backend.restarts(), 1);
+
+        // Backends not reporting readiness in time are stopped
+        device.spawn = script("sleep 10");
+        device.spawn.as_mut().unwrap().notify = true;
+        assert!(matches!(
+            SpawnedBackend::spawn(&guest, &device),
+            Err(Error::BackendNotReady(_))
+        ));
+        device.spawn = script("exit 3");
+        device.spawn.as_mut().unwrap().notify = true;
+        assert!(matches!(
+            SpawnedBackend::spawn(&guest, &device),
+            Err(Error::BackendExited(..))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,252 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao single-producer single-consumer ring.
+//!
+//! Bounded lock-free queue handing work from a kick receiver to the worker of a device
+//! queue (one ring per queue), so simultaneous kicks on several queues never contend on
+//! a shared lock. The vhost-user backends (`vhost_backend`) hand their kicks this way.
+
+#![allow(dead_code)]
+
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Struct aligning a value to its own cache line (avoids false sharing between the
+/// producer and consumer indexes).
+#[repr(align(64))]
+struct CachePadded<T>(T);
+
+/// Struct representing the storage shared by both ends of a ring.
+///
+/// # Attributes
+///
+/// * `slots` - Ring slots (a power of two).
+/// * `head` - Index of the next slot to pop (written by the consumer only).
+/// * `tail` - Index of the next slot to push (written by the producer only).
+struct Ring<T> {
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    head: CachePadded<AtomicUsize>,
+    tail: CachePadded<AtomicUsize>,
+}
+
+impl<T> Ring<T> {
+    /// Returns the slot of an index.
+    fn slot(&self, index: usize) -> *mut MaybeUninit<T> {
+        self.slots[index & (self.slots.len() - 1)].get()
+    }
+}
+
+impl<T> Drop for Ring<T> {
+    fn drop(&mut self) {
+        // Drop the values still queued
+        let tail = *self.tail.0.get_mut();
+        let mut head = *self.head.0.get_mut();
+        while head != tail {
+            // SAFETY: Slots between head and tail hold initialized values and both ends
+            // are gone.
+            unsafe { (*self.slot(head)).assume_init_drop() };
+            head = head.wrapping_add(1);
+        }
+    }
+}
+
+// SAFETY: Each slot is accessed by a single end at a time, as handed over by the
+// acquire/release ordering of the indexes.
+unsafe impl<T: Send> Sync for Ring<T> {}
+
+/// Struct representing the producer end of a ring.
+///
+/// # Attributes
+///
+/// * `ring` - The shared ring.
+/// * `head` - Cached consumer index (refreshed only when the ring looks full).
+pub struct Producer<T> {
+    ring: Arc<Ring<T>>,
+    head: usize,
+}
+
+/// Struct representing the consumer end of a ring.
+///
+/// # Attributes
+///
+/// * `ring` - The shared ring.
+/// * `tail` - Cached producer index (refreshed only when the ring looks empty).
+pub struct Consumer<T> {
+    ring: Arc<Ring<T>>,
+    tail: usize,
+}
+
+// SAFETY: Each end is owned by a single thread; the values are moved between threads.
+unsafe impl<T: Send> Send for Producer<T> {}
+// SAFETY: Each end is owned by a single thread; the values are moved between threads.
+unsafe impl<T: Send> Send for Consumer<T> {}
+
+/// Creates a ring.
+///
+/// # Arguments
+///
+/// * `capacity` - Minimum number of queued values (rounded up to a power of two).
+///
+/// # Returns
+///
+/// * `(Producer<T>, Consumer<T>)` - Both ends of the ring.
+pub fn ring<T>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    let slots = (0..capacity.max(1).next_power_of_two())
+        .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+        .collect();
+    let ring = Arc::new(Ring {
+        slots,
+        head: CachePadded(AtomicUsize::new(0)),
+        tail: CachePadded(AtomicUsize::new(0)),
+    });
+    (
+        Producer {
+            ring: ring.clone(),
+            head: 0,
+        },
+        Consumer { ring, tail: 0 },
+    )
+}
+
+impl<T> Producer<T> {
+    /// Queues a value.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The value.
+    ///
+    /// # Returns
+    ///
+    /// * `std::result::Result<(), T>` - Ok if the value was queued, or the value back if
+    ///   the ring is full.
+    pub fn push(&mut self, value: T) -> std::result::Result<(), T> {
+        let tail = self.ring.tail.0.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head) == self.capacity() {
+            self.head = self.ring.head.0.load(Ordering::Acquire);
+            if tail.wrapping_sub(self.head) == self.capacity() {
+                return Err(value);
+            }
+        }
+        // SAFETY: The slot is free (the consumer released it) and only the producer
+        // writes to it until the tail is published.
+        unsafe { (*self.ring.slot(tail)).write(value) };
+        self.ring
+            .tail
+            .0
+            .store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// Returns the number of values the ring holds.
+    pub fn capacity(&self) -> usize {
+        self.ring.slots.len()
+    }
+
+    /// Returns whether the consumer end was dropped.
+    pub fn is_abandoned(&self) -> bool {
+        Arc::strong_count(&self.ring) == 1
+    }
+}
+
+impl<T> Consumer<T> {
+    /// Dequeues a value.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<T>` - The oldest queued value, if any.
+    pub fn pop(&mut self) -> Option<T> {
+        let head = self.ring.head.0.load(Ordering::Relaxed);
+        if head == self.tail {
+            self.tail = self.ring.tail.0.load(Ordering::Acquire);
+            if head == self.tail {
+                return None;
+            }
+        }
+        // SAFETY: The slot was initialized by the producer before publishing the tail and
+        // only the consumer reads it until the head is published.
+        let value = unsafe { (*self.ring.slot(head)).assume_init_read() };
+        self.ring
+            .head
+            .0
+            .store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+
+    /// Returns the number of queued values.
+    pub fn len(&self) -> usize {
+        let tail = self.ring.tail.0.load(Ordering::Acquire);
+        tail.wrapping_sub(self.ring.head.0.load(Ordering::Relaxed))
+    }
+
+    /// Returns whether no value is queued.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns whether the producer end was dropped.
+    pub fn is_abandoned(&self) -> bool {
+        Arc::strong_count(&self.ring) == 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_spsc_ring() {
+        let (mut producer, mut consumer) = ring(3);
+        assert_eq!(producer.capacity(), 4);
+        assert_eq!(consumer.pop(), None);
+        for i in 0..4 {
+            producer.push(i).unwrap();
+        }
+        assert_eq!(producer.push(4), Err(4));
+        assert_eq!(consumer.len(), 4);
+        assert_eq!(consumer.pop(), Some(0));
+        producer.push(4).unwrap();
+        assert_eq!(
+            (1..5).map(|_| consumer.pop().unwrap()).collect::<Vec<_>>(),
+            [1, 2, 3, 4]
+        );
+        assert!(consumer.is_empty());
+
+        // Queued values are dropped with the ring
+        let value = Arc::new(());
+        let (mut producer, consumer) = ring(2);
+        producer.push(value.clone()).unwrap();
+        drop(consumer);
+        assert!(producer.is_abandoned());
+        drop(producer);
+        assert_eq!(Arc::strong_count(&value), 1);
+
+        // Values cross threads in order, with the ring wrapping many times
+        let (mut producer, mut consumer) = ring(16);
+        let sender = thread::spawn(move || {
+            for mut i in 0..100_000u64 {
+                while let Err(v) = producer.push(i) {
+                    i = v;
+                    thread::yield_now();
+                }
+            }
+        });
+        let mut expected = 0;
+        while expected < 100_000 {
+            match consumer.pop() {
+                Some(i) => {
+                    assert_eq!(i, expected);
+                    expected += 1;
+                }
+                None => thread::yield_now(),
+            }
+        }
+        sender.join().unwrap();
+        assert!(consumer.is_abandoned());
+    }
+}
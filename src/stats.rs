@@ -0,0 +1,481 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao runtime statistics.
+
+#![allow(dead_code)]
+
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Number of bits used to index the linear sub-buckets of each power of two.
+const HISTOGRAM_SUB_BUCKET_BITS: u32 = 5;
+/// Number of linear sub-buckets of each power of two.
+const HISTOGRAM_SUB_BUCKETS: u64 = 1 << HISTOGRAM_SUB_BUCKET_BITS;
+/// Total number of buckets needed to cover the whole `u64` range.
+const HISTOGRAM_BUCKETS: usize =
+    ((64 - HISTOGRAM_SUB_BUCKET_BITS as usize) + 1) * HISTOGRAM_SUB_BUCKETS as usize;
+
+/// Struct representing a lock-free, HDR-style latency histogram.
+///
+/// Values are recorded in nanoseconds into log-linear buckets (32 linear sub-buckets per
+/// power of two), which bounds the relative error of any reported percentile to ~3%.
+///
+/// # Attributes
+///
+/// * `buckets` - Bucket counters.
+/// * `count` - Total number of recorded values.
+/// * `max` - Highest recorded value (in nanoseconds).
+#[derive(Debug)]
+pub struct LatencyHistogram {
+    buckets: Vec<AtomicU64>,
+    count: AtomicU64,
+    max: AtomicU64,
+}
+
+/// Struct representing a point-in-time summary of a latency histogram.
+///
+/// # Attributes
+///
+/// * `count` - Total number of recorded values.
+/// * `p50_ns` - 50th percentile (in nanoseconds).
+/// * `p95_ns` - 95th percentile (in nanoseconds).
+/// * `p99_ns` - 99th percentile (in nanoseconds).
+/// * `max_ns` - Highest recorded value (in nanoseconds).
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct LatencySnapshot {
+    pub count: u64,
+    pub p50_ns: u64,
+    pub p95_ns: u64,
+    pub p99_ns: u64,
+    pub max_ns: u64,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        LatencyHistogram {
+            buckets: (0..HISTOGRAM_BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+        }
+    }
+}
+
+impl LatencyHistogram {
+    /// Returns the bucket index of a value.
+    fn bucket_index(value: u64) -> usize {
+        if value < HISTOGRAM_SUB_BUCKETS {
+            return value as usize;
+        }
+        let exp = 63 - value.leading_zeros();
+        let shift = exp - HISTOGRAM_SUB_BUCKET_BITS;
+        let sub = (value >> shift) - HISTOGRAM_SUB_BUCKETS;
+        ((shift as u64 + 1) * HISTOGRAM_SUB_BUCKETS + sub) as usize
+    }
+
+    /// Returns the highest value that falls into a bucket.
+    fn bucket_high(index: usize) -> u64 {
+        let index = index as u64;
+        if index < HISTOGRAM_SUB_BUCKETS {
+            return index;
+        }
+        let shift = index / HISTOGRAM_SUB_BUCKETS - 1;
+        let sub = index % HISTOGRAM_SUB_BUCKETS;
+        ((HISTOGRAM_SUB_BUCKETS + sub + 1) << shift).wrapping_sub(1)
+    }
+
+    /// Records a latency sample.
+    ///
+    /// # Arguments
+    ///
+    /// * `latency` - The latency to record.
+    pub fn record(&self, latency: Duration) {
+        let value = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX);
+        self.buckets[Self::bucket_index(value)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.max.fetch_max(value, Ordering::Relaxed);
+    }
+
+    /// Computes a percentile of the recorded samples.
+    ///
+    /// # Arguments
+    ///
+    /// * `percentile` - The percentile to compute (0.0 to 100.0).
+    ///
+    /// # Returns
+    ///
+    /// * `u64` - The highest value equivalent to the percentile (in nanoseconds), or 0 if
+    ///   no samples were recorded.
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        let count = self.count.load(Ordering::Relaxed);
+        if count == 0 {
+            return 0;
+        }
+        let target = ((percentile.clamp(0.0, 100.0) / 100.0 * count as f64).ceil() as u64).max(1);
+        let mut total = 0;
+        for (index, bucket) in self.buckets.iter().enumerate() {
+            total += bucket.load(Ordering::Relaxed);
+            if total >= target {
+                return Self::bucket_high(index).min(self.max.load(Ordering::Relaxed));
+            }
+        }
+        self.max.load(Ordering::Relaxed)
+    }
+
+    /// Takes a snapshot of the histogram.
+    ///
+    /// # Returns
+    ///
+    /// * `LatencySnapshot` - The count, p50/p95/p99 and maximum of the recorded samples.
+    pub fn snapshot(&self) -> LatencySnapshot {
+        LatencySnapshot {
+            count: self.count.load(Ordering::Relaxed),
+            p50_ns: self.percentile(50.0),
+            p95_ns: self.percentile(95.0),
+            p99_ns: self.percentile(99.0),
+            max_ns: self.max.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Resets the histogram.
+    pub fn reset(&self) {
+        self.buckets
+            .iter()
+            .for_each(|b| b.store(0, Ordering::Relaxed));
+        self.count.store(0, Ordering::Relaxed);
+        self.max.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Struct representing the runtime counters of a virtqueue.
+///
+/// # Attributes
+///
+/// * `avail_notifications` - Number of available buffer notifications (queue kicks) received.
+/// * `used_completions` - Number of used buffers completed.
+/// * `interrupt_suppressions` - Number of interrupts suppressed (e.g. VIRTQ_AVAIL_F_NO_INTERRUPT).
+/// * `descriptor_errors` - Number of malformed descriptor chains.
+#[derive(Debug, Default)]
+pub struct QueueStats {
+    pub avail_notifications: AtomicU64,
+    pub used_completions: AtomicU64,
+    pub interrupt_suppressions: AtomicU64,
+    pub descriptor_errors: AtomicU64,
+}
+
+/// Struct representing a point-in-time copy of the virtqueue counters.
+///
+/// # Attributes
+///
+/// * `avail_notifications` - Number of available buffer notifications (queue kicks) received.
+/// * `used_completions` - Number of used buffers completed.
+/// * `interrupt_suppressions` - Number of interrupts suppressed.
+/// * `descriptor_errors` - Number of malformed descriptor chains.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct QueueStatsSnapshot {
+    pub avail_notifications: u64,
+    pub used_completions: u64,
+    pub interrupt_suppressions: u64,
+    pub descriptor_errors: u64,
+}
+
+impl QueueStats {
+    /// Takes a snapshot of the virtqueue counters.
+    ///
+    /// # Returns
+    ///
+    /// * `QueueStatsSnapshot` - The current value of the counters.
+    pub fn snapshot(&self) -> QueueStatsSnapshot {
+        QueueStatsSnapshot {
+            avail_notifications: self.avail_notifications.load(Ordering::Relaxed),
+            used_completions: self.used_completions.load(Ordering::Relaxed),
+            interrupt_suppressions: self.interrupt_suppressions.load(Ordering::Relaxed),
+            descriptor_errors: self.descriptor_errors.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Resets the virtqueue counters.
+    pub fn reset(&self) {
+        self.avail_notifications.store(0, Ordering::Relaxed);
+        self.used_completions.store(0, Ordering::Relaxed);
+        self.interrupt_suppressions.store(0, Ordering::Relaxed);
+        self.descriptor_errors.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Struct representing the runtime counters of a device.
+///
+/// # Attributes
+///
+/// * `name` - Device name.
+/// * `mmio_reads` - Number of MMIO read accesses handled.
+/// * `mmio_writes` - Number of MMIO write accesses handled.
+/// * `interrupts` - Number of interrupts injected into the guest.
+/// * `last_interrupt_ns` - Time of the last interrupt injected (in nanoseconds since the
+///   Unix epoch, 0 if none).
+/// * `latency` - Request latency, from request fetch to completion notify.
+/// * `queues` - Per-virtqueue counters.
+#[derive(Debug)]
+pub struct DeviceStats {
+    pub name: String,
+    pub mmio_reads: AtomicU64,
+    pub mmio_writes: AtomicU64,
+    pub interrupts: AtomicU64,
+    pub last_interrupt_ns: AtomicU64,
+    pub latency: LatencyHistogram,
+    pub queues: Vec<QueueStats>,
+}
+
+/// Struct representing a point-in-time copy of the device counters.
+///
+/// # Attributes
+///
+/// * `name` - Device name.
+/// * `mmio_reads` - Number of MMIO read accesses handled.
+/// * `mmio_writes` - Number of MMIO write accesses handled.
+/// * `interrupts` - Number of interrupts injected into the guest.
+/// * `last_interrupt_ns` - Time of the last interrupt injected (in nanoseconds since the
+///   Unix epoch, 0 if none).
+/// * `latency` - Request latency summary.
+/// * `queues` - Per-virtqueue counters.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DeviceStatsSnapshot {
+    pub name: String,
+    pub mmio_reads: u64,
+    pub mmio_writes: u64,
+    pub interrupts: u64,
+    pub last_interrupt_ns: u64,
+    pub latency: LatencySnapshot,
+    pub queues: Vec<QueueStatsSnapshot>,
+}
+
+impl DeviceStats {
+    /// Creates a new set of device counters.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device name.
+    /// * `num_queues` - Number of virtqueues of the device.
+    ///
+    /// # Returns
+    ///
+    /// * `DeviceStats` - The zeroed device counters.
+    pub fn new(name: &str, num_queues: usize) -> Self {
+        DeviceStats {
+            name: name.to_string(),
+            mmio_reads: AtomicU64::new(0),
+            mmio_writes: AtomicU64::new(0),
+            interrupts: AtomicU64::new(0),
+            last_interrupt_ns: AtomicU64::new(0),
+            latency: LatencyHistogram::default(),
+            queues: (0..num_queues).map(|_| QueueStats::default()).collect(),
+        }
+    }
+
+    /// Returns the counters of a virtqueue.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - Virtqueue index.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<&QueueStats>` - The virtqueue counters, if the queue exists.
+    pub fn queue(&self, index: usize) -> Option<&QueueStats> {
+        self.queues.get(index)
+    }
+
+    /// Accounts an interrupt injected into the guest.
+    pub fn record_interrupt(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.interrupts.fetch_add(1, Ordering::Relaxed);
+        self.last_interrupt_ns.store(
+            u64::try_from(now.as_nanos()).unwrap_or(u64::MAX),
+            Ordering::Relaxed,
+        );
+    }
+
+    /// Takes a snapshot of the device and virtqueue counters.
+    ///
+    /// # Returns
+    ///
+    /// * `DeviceStatsSnapshot` - The current value of the counters.
+    pub fn snapshot(&self) -> DeviceStatsSnapshot {
+        DeviceStatsSnapshot {
+            name: self.name.clone(),
+            mmio_reads: self.mmio_reads.load(Ordering::Relaxed),
+            mmio_writes: self.mmio_writes.load(Ordering::Relaxed),
+            interrupts: self.interrupts.load(Ordering::Relaxed),
+            last_interrupt_ns: self.last_interrupt_ns.load(Ordering::Relaxed),
+            latency: self.latency.snapshot(),
+            queues: self.queues.iter().map(|q| q.snapshot()).collect(),
+        }
+    }
+
+    /// Resets the device and virtqueue counters.
+    pub fn reset(&self) {
+        self.mmio_reads.store(0, Ordering::Relaxed);
+        self.mmio_writes.store(0, Ordering::Relaxed);
+        self.interrupts.store(0, Ordering::Relaxed);
+        self.last_interrupt_ns.store(0, Ordering::Relaxed);
+        self.latency.reset();
+        self.queues.iter().for_each(|q| q.reset());
+    }
+}
+
+/// Struct representing the interrupts injected on an IRQ line.
+///
+/// Compared with the guest `/proc/interrupts`, it tells whether the configured IRQ lines
+/// match the ones the guest kernel claimed for the devices.
+///
+/// # Attributes
+///
+/// * `irq` - IRQ line.
+/// * `devices` - Devices configured on the line.
+/// * `injections` - Number of interrupts injected on the line.
+/// * `last_injection_ns` - Time of the last interrupt injected on the line (in nanoseconds
+///   since the Unix epoch, 0 if none).
+#[derive(Debug, Default, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct IrqStatsSnapshot {
+    pub irq: u32,
+    pub devices: Vec<String>,
+    pub injections: u64,
+    pub last_injection_ns: u64,
+}
+
+/// Aggregates the interrupts of the devices per IRQ line.
+///
+/// # Arguments
+///
+/// * `devices` - The IRQ line of each device, with its counters (if attached).
+///
+/// # Returns
+///
+/// * `Vec<IrqStatsSnapshot>` - The interrupts of each line, by line number.
+pub fn irq_stats<'a>(
+    devices: impl IntoIterator<Item = (u32, &'a str, Option<DeviceStatsSnapshot>)>,
+) -> Vec<IrqStatsSnapshot> {
+    let mut lines = BTreeMap::<u32, IrqStatsSnapshot>::new();
+    for (irq, name, stats) in devices {
+        let line = lines.entry(irq).or_insert_with(|| IrqStatsSnapshot {
+            irq,
+            ..Default::default()
+        });
+        line.devices.push(name.to_string());
+        if let Some(stats) = stats {
+            line.injections += stats.interrupts;
+            line.last_injection_ns = line.last_injection_ns.max(stats.last_interrupt_ns);
+        }
+    }
+    lines.into_values().collect()
+}
+
+/// Increments a counter.
+///
+/// # Arguments
+///
+/// * `counter` - The counter to increment.
+pub fn inc(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_stats_snapshot_and_reset() {
+        let stats = DeviceStats::new("device0", 2);
+        inc(&stats.mmio_writes);
+        inc(&stats.interrupts);
+        inc(&stats.queue(1).unwrap().avail_notifications);
+        inc(&stats.queue(1).unwrap().used_completions);
+        inc(&stats.queue(1).unwrap().used_completions);
+        assert!(stats.queue(2).is_none());
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.name, "device0");
+        assert_eq!(snapshot.mmio_reads, 0);
+        assert_eq!(snapshot.mmio_writes, 1);
+        assert_eq!(snapshot.interrupts, 1);
+        assert_eq!(snapshot.queues[0], QueueStatsSnapshot::default());
+        assert_eq!(snapshot.queues[1].avail_notifications, 1);
+        assert_eq!(snapshot.queues[1].used_completions, 2);
+
+        stats.reset();
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.mmio_writes, 0);
+        assert_eq!(snapshot.interrupts, 0);
+        assert_eq!(snapshot.queues[1], QueueStatsSnapshot::default());
+    }
+
+    #[test]
+    fn test_irq_stats() {
+        let rng = DeviceStats::new("rng0", 1);
+        let i2c = DeviceStats::new("i2c0", 1);
+        rng.record_interrupt();
+        i2c.record_interrupt();
+        i2c.record_interrupt();
+        let (rng, i2c) = (rng.snapshot(), i2c.snapshot());
+        assert!(rng.last_interrupt_ns > 0 && rng.last_interrupt_ns <= i2c.last_interrupt_ns);
+
+        // Devices sharing a line add up, and lines without counters are still listed
+        let lines = irq_stats([
+            (47, "rng0", Some(rng)),
+            (46, "gpio0", None),
+            (47, "i2c0", Some(i2c.clone())),
+        ]);
+        assert_eq!(
+            lines,
+            [
+                IrqStatsSnapshot {
+                    irq: 46,
+                    devices: vec!["gpio0".to_string()],
+                    ..Default::default()
+                },
+                IrqStatsSnapshot {
+                    irq: 47,
+                    devices: vec!["rng0".to_string(), "i2c0".to_string()],
+                    injections: 3,
+                    last_injection_ns: i2c.last_interrupt_ns,
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn test_latency_histogram_percentiles() {
+        let histogram = LatencyHistogram::default();
+        assert_eq!(histogram.snapshot(), LatencySnapshot::default());
+
+        // 1..=1000 microseconds
+        for us in 1..=1000 {
+            histogram.record(Duration::from_micros(us));
+        }
+        let snapshot = histogram.snapshot();
+        assert_eq!(snapshot.count, 1000);
+        assert_eq!(snapshot.max_ns, 1_000_000);
+        for (value, expected) in [
+            (snapshot.p50_ns, 500_000),
+            (snapshot.p95_ns, 950_000),
+            (snapshot.p99_ns, 990_000),
+        ] {
+            // Reported values never underestimate and stay within the bucket precision
+            assert!(value >= expected);
+            assert!(value <= expected + expected / 16);
+        }
+
+        // Buckets cover the whole range
+        histogram.record(Duration::MAX);
+        assert_eq!(histogram.percentile(100.0), u64::MAX);
+
+        histogram.reset();
+        assert_eq!(histogram.snapshot(), LatencySnapshot::default());
+    }
+}
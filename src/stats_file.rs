@@ -0,0 +1,350 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao stats file output.
+//!
+//! On targets without a management transport, the counters of every device and IRQ line
+//! are periodically written as a JSON document to a file, for file-scraping telemetry
+//! agents. The file is replaced atomically, so a reader never sees a partial document.
+//! Devices carry their labels (see `ConfigDevice::labels`), for dashboards to slice by.
+
+#![allow(dead_code)]
+
+use super::error::{Error, Result};
+use super::mac::MacAddress;
+use super::management::Management;
+use super::resources::ResourceSnapshot;
+use super::stats::{DeviceStatsSnapshot, IrqStatsSnapshot, LatencySnapshot};
+use super::types::ConfigStatsFile;
+use super::uuid::DeviceUuid;
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Encodes a JSON string.
+pub(crate) fn json_string(value: &str) -> String {
+    let mut json = String::with_capacity(value.len() + 2);
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+/// Encodes a latency summary as a JSON object.
+fn latency_json(latency: &LatencySnapshot) -> String {
+    format!(
+        "{{\"count\":{},\"p50_ns\":{},\"p95_ns\":{},\"p99_ns\":{},\"max_ns\":{}}}",
+        latency.count, latency.p50_ns, latency.p95_ns, latency.p99_ns, latency.max_ns
+    )
+}
+
+/// Encodes the resources of a device as a JSON object.
+fn resources_json(resources: &ResourceSnapshot) -> String {
+    format!(
+        "{{\"cpu_user_ns\":{},\"cpu_system_ns\":{},\"workers\":{},\"mapped_bytes\":{},\"fds\":{}}}",
+        resources.cpu_user_ns,
+        resources.cpu_system_ns,
+        resources.workers,
+        resources.mapped_bytes,
+        resources.fds
+    )
+}
+
+/// Encodes the labels of a device as a JSON object.
+fn labels_json(labels: &BTreeMap<String, String>) -> String {
+    let labels = labels
+        .iter()
+        .map(|(key, value)| format!("{}:{}", json_string(key), json_string(value)))
+        .collect::<Vec<_>>();
+    format!("{{{}}}", labels.join(","))
+}
+
+/// Encodes the counters (and resources, if accounted) of a device as a JSON object.
+fn device_json(
+    stats: &DeviceStatsSnapshot,
+    uuid: DeviceUuid,
+    mac: Option<MacAddress>,
+    labels: &BTreeMap<String, String>,
+    frontend: &str,
+    state: &str,
+    resources: Option<&ResourceSnapshot>,
+) -> String {
+    let queues = stats
+        .queues
+        .iter()
+        .map(|q| {
+            format!(
+                "{{\"avail_notifications\":{},\"used_completions\":{},\"interrupt_suppressions\":{},\"descriptor_errors\":{}}}",
+                q.avail_notifications, q.used_completions, q.interrupt_suppressions, q.descriptor_errors
+            )
+        })
+        .collect::<Vec<_>>();
+    let resources = resources
+        .map(|r| format!(",\"resources\":{}", resources_json(r)))
+        .unwrap_or_default();
+    let mac = mac
+        .map(|mac| format!(",\"mac\":\"{}\"", mac))
+        .unwrap_or_default();
+    let labels = match labels.is_empty() {
+        true => String::new(),
+        false => format!(",\"labels\":{}", labels_json(labels)),
+    };
+    format!(
+        "{{\"name\":{},\"uuid\":\"{}\"{}{},\"frontend\":{},\"state\":{},\"mmio_reads\":{},\"mmio_writes\":{},\"interrupts\":{},\"last_interrupt_ns\":{},\"latency\":{},\"queues\":[{}]{}}}",
+        json_string(&stats.name),
+        uuid,
+        mac,
+        labels,
+        json_string(frontend),
+        json_string(state),
+        stats.mmio_reads,
+        stats.mmio_writes,
+        stats.interrupts,
+        stats.last_interrupt_ns,
+        latency_json(&stats.latency),
+        queues.join(","),
+        resources
+    )
+}
+
+/// Encodes the interrupts of an IRQ line as a JSON object.
+fn irq_json(line: &IrqStatsSnapshot) -> String {
+    let devices = line
+        .devices
+        .iter()
+        .map(|name| json_string(name))
+        .collect::<Vec<_>>();
+    format!(
+        "{{\"irq\":{},\"devices\":[{}],\"injections\":{},\"last_injection_ns\":{}}}",
+        line.irq,
+        devices.join(","),
+        line.injections,
+        line.last_injection_ns
+    )
+}
+
+/// Encodes the counters of every device and IRQ line as a JSON document.
+///
+/// # Arguments
+///
+/// * `management` - The management implementation.
+///
+/// # Returns
+///
+/// * `String` - The document (devices without counters yet are left out).
+pub fn stats_json<M: Management>(management: &M) -> String {
+    let timestamp_ns = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let frontends = management.frontends();
+    let devices = management
+        .devices()
+        .iter()
+        .filter_map(|device| {
+            let stats = management.device_stats(&device.name).ok()?;
+            let state = management.device_state(&device.name).ok()?;
+            let resources = management.device_resources(&device.name).ok();
+            let frontend = frontends
+                .iter()
+                .find(|frontend| frontend.id == device.frontend_id)
+                .map_or("", |frontend| frontend.name.as_str());
+            Some(device_json(
+                &stats,
+                device.uuid,
+                device.mac,
+                &device.labels,
+                frontend,
+                &state.to_string(),
+                resources.as_ref(),
+            ))
+        })
+        .collect::<Vec<_>>();
+    let irqs = management
+        .irq_stats()
+        .iter()
+        .map(irq_json)
+        .collect::<Vec<_>>();
+    format!(
+        "{{\"timestamp_ns\":{},\"devices\":[{}],\"irqs\":[{}]}}\n",
+        timestamp_ns,
+        devices.join(","),
+        irqs.join(",")
+    )
+}
+
+/// Writes the stats file.
+///
+/// # Arguments
+///
+/// * `management` - The management implementation.
+/// * `path` - Stats file path.
+///
+/// # Returns
+///
+/// * `Result<()>` - Ok if the file was replaced.
+pub fn write_stats_file<M: Management>(management: &M, path: &Path) -> Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    fs::write(&tmp, stats_json(management)).map_err(Error::StatsFileFailed)?;
+    fs::rename(&tmp, path).map_err(Error::StatsFileFailed)
+}
+
+/// Writes the stats file every `interval_ms`.
+///
+/// The first write happens before returning, so an unwritable path is reported to the
+/// caller; later failures are retried on the next interval.
+///
+/// # Arguments
+///
+/// * `management` - The management implementation.
+/// * `config` - The stats file configuration.
+///
+/// # Returns
+///
+/// * `Result<JoinHandle<()>>` - The thread writing the file.
+pub fn serve<M: Management>(
+    management: Arc<M>,
+    config: &ConfigStatsFile,
+) -> Result<JoinHandle<()>> {
+    let path = PathBuf::from(&config.path);
+    let interval = config.interval();
+    write_stats_file(&*management, &path)?;
+    thread::Builder::new()
+        .name("stats-file".to_string())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            let _ = write_stats_file(&*management, &path);
+        })
+        .map_err(Error::StatsFileFailed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::management::{DeviceRegistry, DeviceState};
+    use crate::resources::DeviceResources;
+    use crate::stats::{inc, DeviceStats};
+    use crate::types::VmId;
+    use std::time::Duration;
+
+    #[test]
+    fn test_stats_file() {
+        assert_eq!(json_string("a\"b\\c\n"), "\"a\\\"b\\\\c\\u000a\"");
+
+        let config = serde_yaml::from_str(
+            "frontends:
+  - name: frontend0
+    id: 0
+    guests:
+      - name: guest0
+        id: 1
+        ram_addr: 0x50000000
+        ram_size: 0x1000000
+        shmem_path: /dev/baoipc0
+        socket_path: /tmp/
+        devices:
+          - {name: rng0, id: 0, type: rng, irq: 47, addr: 0xa003e00}
+          - {name: i2c0, id: 1, type: i2c, irq: 47, addr: 0xa003c00}
+          - {name: net0, id: 2, type: net, irq: 47, addr: 0xa003a00, options: {mac: \"52:54:00:12:34:56\"}, \
+             labels: {rack: a1, function: adas}}
+",
+        )
+        .unwrap();
+        let registry = Arc::new(DeviceRegistry::new(&config));
+        let stats = Arc::new(DeviceStats::new("rng0", 1));
+        inc(&stats.interrupts);
+        inc(&stats.queue(0).unwrap().used_completions);
+        registry.attach_stats(stats.clone());
+        registry
+            .set_device_state("rng0", DeviceState::Running)
+            .unwrap();
+
+        let path = std::env::temp_dir().join(format!("bao-stats-{}.json", std::process::id()));
+        let config = ConfigStatsFile {
+            path: path.to_str().unwrap().to_string(),
+            interval_ms: Some(10),
+        };
+        serve(registry.clone(), &config).unwrap();
+        let json = fs::read_to_string(&path).unwrap();
+        assert!(json.starts_with("{\"timestamp_ns\":"));
+        let uuid = DeviceUuid::derive(VmId(1), "rng0").to_string();
+        assert!(json.ends_with(&[
+            "\"devices\":[{\"name\":\"rng0\",\"uuid\":\"",
+            &uuid,
+            "\",\"frontend\":\"frontend0\",\"state\":\"running\",\"mmio_reads\":0,\"mmio_writes\":0,\
+             \"interrupts\":1,\"last_interrupt_ns\":0,\"latency\":{\"count\":0,\"p50_ns\":0,\
+             \"p95_ns\":0,\"p99_ns\":0,\"max_ns\":0},\"queues\":[{\"avail_notifications\":0,\
+             \"used_completions\":1,\"interrupt_suppressions\":0,\"descriptor_errors\":0}]}],\
+             \"irqs\":[{\"irq\":47,\"devices\":[\"rng0\",\"i2c0\",\"net0\"],\"injections\":1,\
+             \"last_injection_ns\":0}]}\n"
+        ]
+        .concat()));
+
+        // The file follows the counters and the resources
+        inc(&stats.interrupts);
+        let resources = Arc::new(DeviceResources::new("rng0"));
+        resources.track_mapping(0x1000);
+        registry.attach_resources(resources);
+        let mut updated = false;
+        for _ in 0..100 {
+            thread::sleep(Duration::from_millis(10));
+            let json = fs::read_to_string(&path).unwrap();
+            updated = json.contains("\"interrupts\":2")
+                && json.contains(
+                    "\"resources\":{\"cpu_user_ns\":0,\"cpu_system_ns\":0,\"workers\":0,\
+                     \"mapped_bytes\":4096,\"fds\":0}",
+                );
+            if updated {
+                break;
+            }
+        }
+        assert!(updated);
+
+        // Net devices report their MAC address, and labelled devices their labels
+        registry.attach_stats(Arc::new(DeviceStats::new("net0", 1)));
+        let uuid = DeviceUuid::derive(VmId(1), "net0").to_string();
+        let net = [
+            "\"uuid\":\"",
+            &uuid,
+            "\",\"mac\":\"52:54:00:12:34:56\",\"labels\":{\"function\":\"adas\",\"rack\":\"a1\"},",
+        ]
+        .concat();
+        let mut updated = false;
+        for _ in 0..100 {
+            thread::sleep(Duration::from_millis(10));
+            updated = fs::read_to_string(&path).unwrap().contains(&net);
+            if updated {
+                break;
+            }
+        }
+        assert!(updated);
+
+        // Unwritable paths are reported upfront
+        let config = ConfigStatsFile {
+            path: "/nonexistent/stats.json".to_string(),
+            interval_ms: None,
+        };
+        let registry = Arc::new(DeviceRegistry::default());
+        assert!(matches!(
+            serve(registry, &config),
+            Err(Error::StatsFileFailed(_))
+        ));
+    }
+}
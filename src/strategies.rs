@@ -0,0 +1,399 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao proptest strategies.
+//!
+//! Generators for configurations, command line parameters and I/O request sequences,
+//! shared with downstream device implementations through the `test-support` feature.
+
+#![allow(dead_code)]
+
+use super::defines::{BAO_IO_READ, BAO_IO_WRITE, SUPPORTED_DEVICES, VIRTIO_MMIO_IO_SIZE};
+use super::types::*;
+use super::uuid::DeviceUuid;
+use proptest::collection::{btree_map, vec};
+use proptest::option;
+use proptest::prelude::*;
+use proptest::sample::Index;
+
+/// Generates an identifier (lowercase alphanumeric, never a YAML keyword).
+pub fn name() -> impl Strategy<Value = String> {
+    "[a-z][a-z0-9]{0,7}_[a-z0-9]{1,8}"
+}
+
+/// Generates an absolute filesystem path.
+pub fn path() -> impl Strategy<Value = String> {
+    vec("[a-z0-9]{1,8}", 1..4).prop_map(|parts| format!("/{}", parts.join("/")))
+}
+
+/// Generates a device configuration of one of the supported device types.
+pub fn config_device() -> impl Strategy<Value = ConfigDevice> {
+    (
+        name(),
+        0..SUPPORTED_DEVICES.len(),
+        any::<u32>(),
+        any::<u64>(),
+        prop_oneof![
+            Just(RestartPolicy::Never),
+            Just(RestartPolicy::OnFailure),
+            Just(RestartPolicy::Always)
+        ],
+        option::of(any::<u64>()),
+        vec((path(), any::<bool>()), 0..3),
+        prop_oneof![
+            Just(IsolationMode::InProcess),
+            Just(IsolationMode::Subprocess)
+        ],
+        option::of(name()),
+        prop_oneof![
+            Just(PollMode::Irq),
+            Just(PollMode::Busy),
+            Just(PollMode::Adaptive),
+            Just(PollMode::Hybrid)
+        ],
+        (
+            option::of(0..1000u64),
+            option::of(1..10_000u64),
+            btree_map(name(), "[a-z0-9]{0,8}", 0..3),
+            option::of(0..60_000u64),
+            any::<bool>(),
+            btree_map(name(), name(), 0..3),
+        ),
+        (
+            option::of((any::<u32>(), any::<u64>())),
+            option::of((vec(path(), 1..3), any::<bool>(), option::of(0..10_000u64))),
+            option::of(prop_oneof![
+                Just(LogLevel::Error),
+                Just(LogLevel::Warn),
+                Just(LogLevel::Info),
+                Just(LogLevel::Debug),
+                Just(LogLevel::Trace)
+            ]),
+            option::of(path()),
+            option::of(vec((any::<u64>(), any::<u64>()), 0..3)),
+            option::of(1..=1024u16),
+            option::of(any::<u128>()),
+            option::of(0..16u32),
+            option::of((0..16u32).prop_map(|shift| 1u16 << shift)),
+        ),
+    )
+        .prop_map(
+            |(
+                name,
+                index,
+                irq,
+                addr,
+                restart,
+                delay,
+                paths,
+                isolation,
+                backend,
+                poll_mode,
+                (budget, slow, options, connect_timeout, wait_for_socket, labels),
+                (
+                    coalesce,
+                    spawn,
+                    log_level,
+                    log_file,
+                    mmio_trace,
+                    max_inflight,
+                    uuid,
+                    weight,
+                    queue_size_max,
+                ),
+            )| {
+                let (device_type, id) = SUPPORTED_DEVICES[index];
+                ConfigDevice {
+                    name,
+                    id: DeviceId(id),
+                    device_type: device_type.to_string(),
+                    irq: IrqLine(irq),
+                    addr: GuestAddress(addr),
+                    restart,
+                    restart_delay_ms: delay,
+                    restart_max_delay_ms: delay.map(|d| d.saturating_mul(2)),
+                    allowed_paths: paths
+                        .into_iter()
+                        .map(|(path, read_only)| ConfigAllowedPath { path, read_only })
+                        .collect(),
+                    isolation,
+                    backend,
+                    poll_mode,
+                    poll_budget_us: budget,
+                    coalesce: coalesce.map(|(max_completions, max_delay_us)| ConfigCoalesce {
+                        max_completions,
+                        max_delay_us,
+                    }),
+                    slow_request_ms: slow,
+                    options,
+                    spawn: spawn.map(|(command, notify, timeout)| ConfigSpawn {
+                        command,
+                        notify,
+                        ready_timeout_ms: timeout,
+                    }),
+                    depends_on: Vec::new(),
+                    connect_timeout_ms: connect_timeout,
+                    wait_for_socket,
+                    log_level,
+                    log_file,
+                    mmio_trace: mmio_trace.map(|ranges| {
+                        ranges
+                            .into_iter()
+                            .map(|(start, end)| ConfigRegRange { start, end })
+                            .collect()
+                    }),
+                    max_inflight,
+                    uuid: uuid.map(DeviceUuid),
+                    weight,
+                    queue_size_max,
+                    labels,
+                }
+            },
+        )
+}
+
+/// Generates a worker scheduling configuration.
+pub fn config_sched() -> impl Strategy<Value = ConfigSched> {
+    (
+        prop_oneof![
+            Just(SchedPolicy::Other),
+            Just(SchedPolicy::Fifo),
+            Just(SchedPolicy::Rr)
+        ],
+        0..100u32,
+        option::of(prop_oneof![Just("0"), Just("1-3"), Just("0,2-3")]),
+        any::<bool>(),
+    )
+        .prop_map(|(policy, priority, cpus, isolated)| ConfigSched {
+            policy,
+            priority,
+            cpus: cpus.map(str::to_string),
+            isolated,
+        })
+}
+
+/// Generates a device tree overlay configuration.
+pub fn config_dt_overlay() -> impl Strategy<Value = ConfigDtOverlay> {
+    (
+        option::of(path()),
+        option::of(path()),
+        option::of(1..=2u32),
+        option::of(1..=2u32),
+        prop_oneof![
+            Just(InterruptController::Gic),
+            Just(InterruptController::Plic)
+        ],
+    )
+        .prop_map(
+            |(configfs_dir, target_path, address_cells, size_cells, interrupt_controller)| {
+                ConfigDtOverlay {
+                    configfs_dir,
+                    target_path,
+                    address_cells,
+                    size_cells,
+                    interrupt_controller,
+                }
+            },
+        )
+}
+
+/// Generates a device tree configuration.
+pub fn config_dtb() -> impl Strategy<Value = ConfigDtb> {
+    (path(), btree_map(name(), name(), 0..3))
+        .prop_map(|(path, compatibles)| ConfigDtb { path, compatibles })
+}
+
+/// Generates a guest configuration with up to `max_devices` devices.
+pub fn config_guest(max_devices: usize) -> impl Strategy<Value = ConfigGuest> {
+    (
+        name(),
+        any::<u32>(),
+        any::<u64>(),
+        any::<u64>(),
+        path(),
+        path(),
+        vec(config_device(), 0..=max_devices),
+        (
+            option::of(config_sched()),
+            option::of(path()),
+            vec(option::of(any::<Index>()), max_devices),
+            option::of(config_dt_overlay()),
+            any::<bool>(),
+            prop_oneof![
+                Just(ThpMode::Always),
+                Just(ThpMode::Never),
+                Just(ThpMode::Madvise)
+            ],
+            option::of(config_dtb()),
+            btree_map(name(), name(), 0..3),
+        ),
+    )
+        .prop_map(
+            |(
+                name,
+                id,
+                ram_addr,
+                ram_size,
+                shmem_path,
+                socket_path,
+                mut devices,
+                (sched, watch_dir, dependencies, dt_overlay, prefault, thp, dtb, labels),
+            )| {
+                // Devices only depend on earlier ones, so the dependencies never form a cycle
+                for (index, dependency) in dependencies.iter().enumerate().take(devices.len()) {
+                    if let (Some(dependency), true) = (dependency, index > 0) {
+                        let name = devices[dependency.index(index)].name.clone();
+                        devices[index].depends_on.push(name);
+                    }
+                }
+                ConfigGuest {
+                    name,
+                    id: VmId(id),
+                    ram_addr: GuestAddress(ram_addr),
+                    ram_size,
+                    shmem_path,
+                    socket_path,
+                    devices,
+                    sched,
+                    watch_dir,
+                    dt_overlay,
+                    prefault,
+                    thp,
+                    dtb,
+                    labels,
+                }
+            },
+        )
+}
+
+/// Generates a frontends configuration (up to 2 frontends of 2 guests of 4 devices).
+pub fn config_frontends() -> impl Strategy<Value = ConfigFrontends> {
+    let frontend =
+        (name(), any::<u32>(), vec(config_guest(4), 0..=2)).prop_map(|(name, id, guests)| {
+            ConfigFrontend {
+                name,
+                id: VmId(id),
+                guests,
+                control: None,
+            }
+        });
+    (
+        vec(frontend, 0..=2),
+        prop_oneof![
+            Just(SeccompMode::Off),
+            Just(SeccompMode::Log),
+            Just(SeccompMode::Enforce)
+        ],
+        option::of(name()),
+    )
+        .prop_map(|(frontends, seccomp, user)| ConfigFrontends {
+            frontends,
+            seccomp,
+            user,
+            ..ConfigFrontends::default()
+        })
+}
+
+/// Generates the parameters of up to `max_devices` devices, one row per device
+/// (`vm_id`, `dev_id`, `dev_irq`, `dev_addr`, `ram_addr`, `ram_size`), along with the
+/// matching command line arguments.
+pub fn device_params(max_devices: usize) -> impl Strategy<Value = (Vec<String>, Vec<Vec<u64>>)> {
+    vec(vec(any::<u64>(), 6), 1..=max_devices).prop_map(|rows| {
+        let args = [
+            "vm_id", "dev_id", "dev_irq", "dev_addr", "ram_addr", "ram_size",
+        ]
+        .iter()
+        .enumerate()
+        .map(|(column, key)| {
+            let values = rows
+                .iter()
+                .map(|row| row[column].to_string())
+                .collect::<Vec<_>>();
+            format!("{}={}", key, values.join(","))
+        })
+        .collect();
+        (args, rows)
+    })
+}
+
+/// Generates a well-formed virtio-mmio access of a device (aligned, with the value
+/// fitting the access width).
+///
+/// # Arguments
+///
+/// * `virtio_id` - Virtio instance ID of the device.
+/// * `base` - Base address of the device region.
+pub fn io_request(virtio_id: u64, base: GuestAddress) -> impl Strategy<Value = BaoIoRequest> {
+    (
+        prop_oneof![Just(1u64), Just(2), Just(4), Just(8)],
+        0..VIRTIO_MMIO_IO_SIZE,
+        prop_oneof![Just(BAO_IO_READ), Just(BAO_IO_WRITE)],
+        any::<u64>(),
+        0..8u64,
+    )
+        .prop_map(move |(width, reg_off, op, value, vcpu_id)| {
+            let reg_off = reg_off & !(width - 1);
+            BaoIoRequest {
+                virtio_id,
+                reg_off,
+                addr: base.raw() + reg_off,
+                op,
+                value: match op {
+                    BAO_IO_WRITE if width < 8 => value & ((1 << (width * 8)) - 1),
+                    BAO_IO_WRITE => value,
+                    _ => 0,
+                },
+                access_width: width,
+                cpu_id: 0,
+                vcpu_id,
+                ret: 0,
+            }
+        })
+}
+
+/// Generates a sequence of up to `max_len` accesses of a device.
+///
+/// # Arguments
+///
+/// * `virtio_id` - Virtio instance ID of the device.
+/// * `base` - Base address of the device region.
+/// * `max_len` - Maximum number of accesses.
+pub fn io_requests(
+    virtio_id: u64,
+    base: GuestAddress,
+    max_len: usize,
+) -> impl Strategy<Value = Vec<BaoIoRequest>> {
+    vec(io_request(virtio_id, base), 0..=max_len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::parse_parameters;
+
+    proptest! {
+        #[test]
+        fn test_config_yaml_roundtrip(config in config_frontends()) {
+            let yaml = serde_yaml::to_string(&config).unwrap();
+            prop_assert_eq!(serde_yaml::from_str::<ConfigFrontends>(&yaml).unwrap(), config);
+        }
+
+        #[test]
+        fn test_device_params_parse((args, rows) in device_params(4)) {
+            prop_assert_eq!(parse_parameters(&args), Some(rows));
+        }
+
+        #[test]
+        fn test_io_request_abi_roundtrip(reqs in io_requests(0, GuestAddress(0xa003e00), 16)) {
+            for req in reqs {
+                prop_assert!(req.io_op().is_ok());
+                prop_assert!(req.width().is_ok());
+                let mut bytes = Vec::new();
+                req.encode(&mut bytes);
+                prop_assert_eq!(BaoIoRequest::decode(&bytes).unwrap(), req);
+            }
+        }
+    }
+}
@@ -0,0 +1,260 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao device negotiation summary.
+//!
+//! Once the driver of a device sets DRIVER_OK, what was negotiated (feature bits, queues,
+//! vhost-user protocol features and shared memory) is summarized in a stable, indented
+//! text form, so the summaries of two boots can be diffed line by line.
+
+#![allow(dead_code)]
+
+use super::types::GuestAddress;
+use std::fmt;
+
+/// Names of the device-independent virtio feature bits.
+const VIRTIO_FEATURE_NAMES: &[(u32, &str)] = &[
+    (24, "VIRTIO_F_NOTIFY_ON_EMPTY"),
+    (27, "VIRTIO_F_ANY_LAYOUT"),
+    (28, "VIRTIO_F_INDIRECT_DESC"),
+    (29, "VIRTIO_F_EVENT_IDX"),
+    (30, "VHOST_USER_F_PROTOCOL_FEATURES"),
+    (32, "VIRTIO_F_VERSION_1"),
+    (33, "VIRTIO_F_ACCESS_PLATFORM"),
+    (34, "VIRTIO_F_RING_PACKED"),
+    (35, "VIRTIO_F_IN_ORDER"),
+    (36, "VIRTIO_F_ORDER_PLATFORM"),
+    (37, "VIRTIO_F_SR_IOV"),
+    (38, "VIRTIO_F_NOTIFICATION_DATA"),
+    (39, "VIRTIO_F_NOTIF_CONFIG_DATA"),
+    (40, "VIRTIO_F_RING_RESET"),
+];
+
+/// Names of the vhost-user protocol feature bits.
+const PROTOCOL_FEATURE_NAMES: &[(u32, &str)] = &[
+    (0, "MQ"),
+    (1, "LOG_SHMFD"),
+    (2, "RARP"),
+    (3, "REPLY_ACK"),
+    (4, "MTU"),
+    (5, "BACKEND_REQ"),
+    (6, "CROSS_ENDIAN"),
+    (7, "CRYPTO_SESSION"),
+    (8, "PAGEFAULT"),
+    (9, "CONFIG"),
+    (10, "BACKEND_SEND_FD"),
+    (11, "HOST_NOTIFIER"),
+    (12, "INFLIGHT_SHMFD"),
+    (13, "RESET_DEVICE"),
+    (14, "INBAND_NOTIFICATIONS"),
+    (15, "CONFIGURE_MEM_SLOTS"),
+    (16, "STATUS"),
+    (17, "XEN_MMAP"),
+    (18, "SHARED_OBJECT"),
+    (19, "DEVICE_STATE"),
+];
+
+/// Names of the vhost-user requests.
+const REQUEST_NAMES: &[(u32, &str)] = &[
+    (1, "GET_FEATURES"),
+    (2, "SET_FEATURES"),
+    (3, "SET_OWNER"),
+    (4, "RESET_OWNER"),
+    (5, "SET_MEM_TABLE"),
+    (6, "SET_LOG_BASE"),
+    (7, "SET_LOG_FD"),
+    (8, "SET_VRING_NUM"),
+    (9, "SET_VRING_ADDR"),
+    (10, "SET_VRING_BASE"),
+    (11, "GET_VRING_BASE"),
+    (12, "SET_VRING_KICK"),
+    (13, "SET_VRING_CALL"),
+    (14, "SET_VRING_ERR"),
+    (15, "GET_PROTOCOL_FEATURES"),
+    (16, "SET_PROTOCOL_FEATURES"),
+    (17, "GET_QUEUE_NUM"),
+    (18, "SET_VRING_ENABLE"),
+    (19, "SEND_RARP"),
+    (20, "NET_SET_MTU"),
+    (21, "SET_BACKEND_REQ_FD"),
+    (22, "IOTLB_MSG"),
+    (23, "SET_VRING_ENDIAN"),
+    (24, "GET_CONFIG"),
+    (25, "SET_CONFIG"),
+    (31, "GET_INFLIGHT_FD"),
+    (32, "SET_INFLIGHT_FD"),
+    (34, "RESET_DEVICE"),
+    (36, "GET_MAX_MEM_SLOTS"),
+    (37, "ADD_MEM_REG"),
+    (38, "REM_MEM_REG"),
+    (39, "SET_STATUS"),
+    (40, "GET_STATUS"),
+];
+
+/// Decodes a feature word into the names of its bits.
+///
+/// # Arguments
+///
+/// * `features` - The feature bits.
+/// * `names` - Known bit names.
+///
+/// # Returns
+///
+/// * `Vec<String>` - The name of every set bit (`bit N` if unknown), lowest bit first.
+fn decode(features: u64, names: &[(u32, &str)]) -> Vec<String> {
+    (0..64)
+        .filter(|bit| features & (1 << bit) != 0)
+        .map(|bit| match names.iter().find(|(b, _)| *b == bit) {
+            Some((_, name)) => name.to_string(),
+            None => format!("bit {}", bit),
+        })
+        .collect()
+}
+
+/// Decodes virtio feature bits (device-specific bits are reported by number).
+///
+/// # Arguments
+///
+/// * `features` - The feature bits.
+///
+/// # Returns
+///
+/// * `Vec<String>` - The name of every set bit, lowest bit first.
+pub fn feature_names(features: u64) -> Vec<String> {
+    decode(features, VIRTIO_FEATURE_NAMES)
+}
+
+/// Decodes vhost-user protocol feature bits.
+///
+/// # Arguments
+///
+/// * `features` - The protocol feature bits.
+///
+/// # Returns
+///
+/// * `Vec<String>` - The name of every set bit, lowest bit first.
+pub fn protocol_feature_names(features: u64) -> Vec<String> {
+    decode(features, PROTOCOL_FEATURE_NAMES)
+}
+
+/// Returns the name of a vhost-user request.
+///
+/// # Arguments
+///
+/// * `request` - The request code.
+///
+/// # Returns
+///
+/// * `&'static str` - The request name (`UNKNOWN` if the code is not known).
+pub fn request_name(request: u32) -> &'static str {
+    REQUEST_NAMES
+        .iter()
+        .find(|(code, _)| *code == request)
+        .map_or("UNKNOWN", |(_, name)| name)
+}
+
+/// Struct representing a memory region shared with a device backend.
+///
+/// # Attributes
+///
+/// * `addr` - Guest physical address of the region.
+/// * `size` - Region size (in bytes).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub addr: GuestAddress,
+    pub size: u64,
+}
+
+/// Struct representing what was negotiated with a device at DRIVER_OK.
+///
+/// # Attributes
+///
+/// * `guest` - Guest name.
+/// * `device` - Device name.
+/// * `device_type` - Device type.
+/// * `features` - Features negotiated with the driver.
+/// * `queues` - Size of each enabled queue.
+/// * `protocol_features` - Vhost-user protocol features (None without a vhost-user backend).
+/// * `regions` - Memory regions shared with the backend.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceSummary {
+    pub guest: String,
+    pub device: String,
+    pub device_type: String,
+    pub features: u64,
+    pub queues: Vec<u32>,
+    pub protocol_features: Option<u64>,
+    pub regions: Vec<MemoryRegion>,
+}
+
+impl fmt::Display for DeviceSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}/{} ({})", self.guest, self.device, self.device_type)?;
+        writeln!(f, "  features {:#x}", self.features)?;
+        for name in feature_names(self.features) {
+            writeln!(f, "    {}", name)?;
+        }
+        writeln!(f, "  queues {}", self.queues.len())?;
+        for (index, size) in self.queues.iter().enumerate() {
+            writeln!(f, "    {}: size {}", index, size)?;
+        }
+        if let Some(features) = self.protocol_features {
+            writeln!(f, "  protocol features {:#x}", features)?;
+            for name in protocol_feature_names(features) {
+                writeln!(f, "    {}", name)?;
+            }
+        }
+        writeln!(f, "  memory regions {}", self.regions.len())?;
+        for region in &self.regions {
+            writeln!(f, "    {:#x} size {:#x}", region.addr.raw(), region.size)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::defines::{
+        VHOST_USER_PROTOCOL_F_MQ, VHOST_USER_PROTOCOL_F_REPLY_ACK, VIRTIO_F_VERSION_1,
+    };
+
+    #[test]
+    fn test_device_summary() {
+        assert_eq!(
+            feature_names(VIRTIO_F_VERSION_1 | 1 << 29 | 1),
+            ["bit 0", "VIRTIO_F_EVENT_IDX", "VIRTIO_F_VERSION_1"]
+        );
+        assert_eq!(request_name(24), "GET_CONFIG");
+        assert_eq!(request_name(99), "UNKNOWN");
+
+        let summary = DeviceSummary {
+            guest: "guest0".to_string(),
+            device: "rng0".to_string(),
+            device_type: "rng".to_string(),
+            features: VIRTIO_F_VERSION_1,
+            queues: vec![16],
+            protocol_features: Some(VHOST_USER_PROTOCOL_F_MQ | VHOST_USER_PROTOCOL_F_REPLY_ACK),
+            regions: vec![MemoryRegion {
+                addr: GuestAddress(0x50000000),
+                size: 0x1000000,
+            }],
+        };
+        assert_eq!(
+            summary.to_string(),
+            "guest0/rng0 (rng)
+  features 0x100000000
+    VIRTIO_F_VERSION_1
+  queues 1
+    0: size 16
+  protocol features 0x9
+    MQ
+    REPLY_ACK
+  memory regions 1
+    0x50000000 size 0x1000000
+"
+        );
+    }
+}
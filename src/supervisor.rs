@@ -0,0 +1,403 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao process supervisor.
+//!
+//! In `--supervise` mode the frontend process only supervises: every frontend (or guest,
+//! see `ConfigSupervisor::unit`) runs in a forked child of its own, with the configuration
+//! reduced to that unit, so a crash only takes down the guests of that child. Children
+//! that exit are restarted according to the supervisor restart policy, with an exponential
+//! backoff, and the status of every child is available to the management clients.
+
+#![allow(dead_code)]
+
+use super::error::{Error, Result};
+use super::types::{ConfigFrontends, ConfigSupervisor, SuperviseUnit};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io;
+use std::time::Instant;
+
+/// Enum representing the state of a supervised process.
+///
+/// # Variants
+///
+/// * `Running` - The process is running.
+/// * `Restarting` - The process exited and is waiting to be restarted.
+/// * `Exited` - The process exited successfully and is not restarted.
+/// * `Failed` - The process failed and is not restarted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum UnitState {
+    Running,
+    Restarting,
+    Exited,
+    Failed,
+}
+
+impl fmt::Display for UnitState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = match self {
+            UnitState::Running => "running",
+            UnitState::Restarting => "restarting",
+            UnitState::Exited => "exited",
+            UnitState::Failed => "failed",
+        };
+        write!(f, "{}", state)
+    }
+}
+
+/// Struct representing the status of a supervised process.
+///
+/// # Attributes
+///
+/// * `name` - Unit name (`frontend`, or `frontend/guest`).
+/// * `pid` - Process ID (None while not running).
+/// * `state` - Process state.
+/// * `restarts` - Number of times the process was restarted.
+/// * `exit_code` - Exit code of the last run (negated signal number if it was killed).
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct UnitStatus {
+    pub name: String,
+    pub pid: Option<u32>,
+    pub state: UnitState,
+    pub restarts: u32,
+    pub exit_code: Option<i32>,
+}
+
+/// Struct representing a supervised unit.
+///
+/// # Attributes
+///
+/// * `config` - Configuration of the unit.
+/// * `status` - Status of its process.
+/// * `restart_at` - When the process is restarted (if it is waiting to be).
+struct Unit {
+    config: ConfigFrontends,
+    status: UnitStatus,
+    restart_at: Option<Instant>,
+}
+
+/// Splits a configuration into the configurations of its supervised units.
+///
+/// # Arguments
+///
+/// * `config` - The frontends configuration.
+/// * `unit` - What runs in its own process.
+///
+/// # Returns
+///
+/// * `Vec<(String, ConfigFrontends)>` - The name and configuration of every unit.
+pub fn partition(config: &ConfigFrontends, unit: SuperviseUnit) -> Vec<(String, ConfigFrontends)> {
+    // Only the supervisor records the applied configuration and serves the process
+    // control socket, not each of its units
+    let shared = ConfigFrontends {
+        frontends: Vec::new(),
+        applied_config: None,
+        control: None,
+        ..config.clone()
+    };
+    let mut units = Vec::new();
+    for frontend in &config.frontends {
+        match unit {
+            SuperviseUnit::Frontend => units.push((
+                frontend.name.clone(),
+                ConfigFrontends {
+                    frontends: vec![frontend.clone()],
+                    ..shared.clone()
+                },
+            )),
+            SuperviseUnit::Guest => {
+                for guest in &frontend.guests {
+                    let mut frontend = frontend.clone();
+                    frontend.guests = vec![guest.clone()];
+                    units.push((
+                        format!("{}/{}", frontend.name, guest.name),
+                        ConfigFrontends {
+                            frontends: vec![frontend],
+                            ..shared.clone()
+                        },
+                    ));
+                }
+            }
+        }
+    }
+    // Each unit writes the stats of its own devices, to a file of its own
+    for (name, config) in &mut units {
+        if let Some(stats_file) = &mut config.stats_file {
+            stats_file.path = format!("{}.{}", stats_file.path, name.replace('/', "."));
+        }
+    }
+    units
+}
+
+/// Struct representing the supervisor of the frontend processes.
+///
+/// # Attributes
+///
+/// * `config` - Supervisor configuration.
+/// * `units` - Supervised units, in configuration order.
+pub struct Supervisor {
+    config: ConfigSupervisor,
+    units: Vec<Unit>,
+}
+
+impl Supervisor {
+    /// Forks the process of every unit of a configuration.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The frontends configuration.
+    /// * `run` - Runs a unit in its child process, returning the process exit code.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Supervisor>` - The supervisor.
+    pub fn start<F>(config: &ConfigFrontends, run: &F) -> Result<Self>
+    where
+        F: Fn(&ConfigFrontends) -> i32,
+    {
+        let mut supervisor = Supervisor {
+            config: config.supervisor.clone(),
+            units: Vec::new(),
+        };
+        for (name, config) in partition(config, config.supervisor.unit) {
+            let pid = fork_unit(&config, run)?;
+            supervisor.units.push(Unit {
+                config,
+                status: UnitStatus {
+                    name,
+                    pid: Some(pid),
+                    state: UnitState::Running,
+                    restarts: 0,
+                    exit_code: None,
+                },
+                restart_at: None,
+            });
+        }
+        Ok(supervisor)
+    }
+
+    /// Reaps the exited processes and restarts the ones that are due.
+    ///
+    /// # Arguments
+    ///
+    /// * `run` - Runs a unit in its child process, returning the process exit code.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<UnitStatus>>` - The status of the units whose state changed.
+    pub fn supervise<F>(&mut self, run: &F) -> Result<Vec<UnitStatus>>
+    where
+        F: Fn(&ConfigFrontends) -> i32,
+    {
+        let mut changed = Vec::new();
+        for unit in &mut self.units {
+            match (unit.status.pid, unit.restart_at) {
+                (Some(pid), _) => {
+                    let exit_code = match try_wait(pid)? {
+                        Some(exit_code) => exit_code,
+                        None => continue,
+                    };
+                    let failed = exit_code != 0;
+                    unit.status.pid = None;
+                    unit.status.exit_code = Some(exit_code);
+                    if self.config.restart.should_restart(failed) {
+                        unit.status.state = UnitState::Restarting;
+                        unit.restart_at =
+                            Some(Instant::now() + self.config.restart_delay(unit.status.restarts));
+                    } else if failed {
+                        unit.status.state = UnitState::Failed;
+                    } else {
+                        unit.status.state = UnitState::Exited;
+                    }
+                }
+                (None, Some(restart_at)) if restart_at <= Instant::now() => {
+                    unit.status.pid = Some(fork_unit(&unit.config, run)?);
+                    unit.status.state = UnitState::Running;
+                    unit.status.restarts += 1;
+                    unit.restart_at = None;
+                }
+                _ => continue,
+            }
+            changed.push(unit.status.clone());
+        }
+        Ok(changed)
+    }
+
+    /// Checks if any unit is running or waiting to be restarted.
+    pub fn active(&self) -> bool {
+        self.units.iter().any(|unit| {
+            matches!(
+                unit.status.state,
+                UnitState::Running | UnitState::Restarting
+            )
+        })
+    }
+
+    /// Returns the status of every unit.
+    pub fn status(&self) -> Vec<UnitStatus> {
+        self.units.iter().map(|unit| unit.status.clone()).collect()
+    }
+}
+
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        for pid in self.units.iter().filter_map(|unit| unit.status.pid) {
+            // SAFETY: The process is a child of the supervisor, not reaped yet.
+            unsafe {
+                libc::kill(pid as libc::pid_t, libc::SIGTERM);
+                libc::waitpid(pid as libc::pid_t, std::ptr::null_mut(), 0);
+            }
+        }
+    }
+}
+
+/// Forks the process of a unit.
+fn fork_unit<F>(config: &ConfigFrontends, run: &F) -> Result<u32>
+where
+    F: Fn(&ConfigFrontends) -> i32,
+{
+    // SAFETY: The child only runs the unit and exits, without returning to the caller.
+    match unsafe { libc::fork() } {
+        -1 => Err(Error::SuperviseFailed("fork", io::Error::last_os_error())),
+        0 => {
+            let code = run(config);
+            // SAFETY: Exits the child without running the destructors of the parent state.
+            unsafe { libc::_exit(code) }
+        }
+        pid => Ok(pid as u32),
+    }
+}
+
+/// Reaps a process, if it exited.
+///
+/// # Returns
+///
+/// * `Result<Option<i32>>` - The exit code (negated signal number if it was killed), or
+///   None if the process is still running.
+fn try_wait(pid: u32) -> Result<Option<i32>> {
+    let mut status = 0;
+    // SAFETY: `status` is valid for writes for the duration of the call.
+    match unsafe { libc::waitpid(pid as libc::pid_t, &mut status, libc::WNOHANG) } {
+        -1 => Err(Error::SuperviseFailed("wait", io::Error::last_os_error())),
+        0 => Ok(None),
+        _ if libc::WIFSIGNALED(status) => Ok(Some(-libc::WTERMSIG(status))),
+        _ => Ok(Some(libc::WEXITSTATUS(status))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{
+        ConfigControl, ConfigFrontend, ConfigGuest, ConfigStatsFile, RestartPolicy, VmId,
+    };
+    use std::thread;
+    use std::time::Duration;
+
+    fn config() -> ConfigFrontends {
+        let guest = |name: &str| ConfigGuest {
+            name: name.to_string(),
+            id: VmId(1),
+            ram_addr: Default::default(),
+            ram_size: 0,
+            shmem_path: "/dev/baoipc0".to_string(),
+            socket_path: "/tmp/".to_string(),
+            devices: vec![],
+            sched: None,
+            watch_dir: None,
+            dt_overlay: None,
+            prefault: false,
+            thp: Default::default(),
+            dtb: None,
+            labels: Default::default(),
+        };
+        let frontend = |name: &str, guests| ConfigFrontend {
+            name: name.to_string(),
+            id: VmId(0),
+            control: None,
+            guests,
+        };
+        ConfigFrontends {
+            frontends: vec![
+                frontend("frontend0", vec![guest("guest0"), guest("guest1")]),
+                frontend("frontend1", vec![guest("guest2")]),
+            ],
+            supervisor: ConfigSupervisor {
+                restart: RestartPolicy::OnFailure,
+                restart_delay_ms: Some(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_partition() {
+        let config = config();
+        let units = partition(&config, SuperviseUnit::Frontend);
+        assert_eq!(units.len(), 2);
+        assert_eq!(units[1].0, "frontend1");
+        assert_eq!(units[1].1.frontends[0].guests[0].name, "guest2");
+        assert_eq!(units[1].1.supervisor, config.supervisor);
+
+        let units = partition(&config, SuperviseUnit::Guest);
+        let names = units
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            ["frontend0/guest0", "frontend0/guest1", "frontend1/guest2"]
+        );
+        assert_eq!(units[1].1.frontends[0].guests.len(), 1);
+        assert_eq!(units[1].1.frontends[0].guests[0].name, "guest1");
+
+        // The units do not share the process control socket nor the stats file
+        let config = ConfigFrontends {
+            control: Some(ConfigControl {
+                path: "/run/bao.sock".to_string(),
+                ..Default::default()
+            }),
+            stats_file: Some(ConfigStatsFile {
+                path: "/run/bao.json".to_string(),
+                interval_ms: None,
+            }),
+            ..config
+        };
+        let units = partition(&config, SuperviseUnit::Guest);
+        assert_eq!(units[1].1.control, None);
+        assert_eq!(
+            units[1].1.stats_file.as_ref().unwrap().path,
+            "/run/bao.json.frontend0.guest1"
+        );
+    }
+
+    #[test]
+    fn test_supervisor() {
+        // The second frontend keeps failing, the first one exits successfully
+        let run = |config: &ConfigFrontends| match config.frontends[0].name.as_str() {
+            "frontend0" => 0,
+            _ => 3,
+        };
+        let mut supervisor = Supervisor::start(&config(), &run).unwrap();
+        assert!(supervisor.active());
+        let mut restarted = false;
+        while !restarted || supervisor.status()[0].state != UnitState::Exited {
+            for status in supervisor.supervise(&run).unwrap() {
+                restarted |= status.name == "frontend1" && status.restarts > 0;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let status = supervisor.status();
+        assert_eq!(status[0].state, UnitState::Exited);
+        assert_eq!(status[0].exit_code, Some(0));
+        assert_eq!(status[0].restarts, 0);
+        assert_eq!(status[1].exit_code, Some(3));
+        assert!(supervisor.active());
+    }
+}
@@ -0,0 +1,378 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao TAP network interfaces.
+//!
+//! A net device served in-process may have its TAP interface set up by the frontend at
+//! startup, instead of relying on host networking scripts: the `tap` backend option names
+//! the interface (a `%d` being numbered by the kernel), `tap_mac` sets its hardware
+//! address (that of the host side, the guest having its own, see the `mac` module),
+//! `master` enslaves it to a bridge (or any other master, e.g. a bond) and `mtu` sets its
+//! MTU. The interface is brought up once configured.
+//!
+//! The interface is a multi-queue TAP that is not persistent. The backend attaches its
+//! queues by opening the interface by name (with `IFF_TAP | IFF_NO_PI | IFF_VNET_HDR |
+//! IFF_MULTI_QUEUE`), while the queue of the frontend is detached, only holding the
+//! interface. The kernel removes the interface once its last queue is closed, so it is
+//! torn down with the backend, or with the process however it exits.
+
+#![allow(dead_code)]
+
+use super::capture::PacketCapture;
+use super::defines::*;
+use super::error::{Error, Result};
+use super::mac::MacAddress;
+use super::types::ConfigDevice;
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use vmm_sys_util::ioctl::_IOC_WRITE;
+use vmm_sys_util::ioctl_ioc_nr;
+
+ioctl_ioc_nr!(
+    TUNSETIFF,
+    _IOC_WRITE,
+    TUN_IOCTL_TYPE,
+    TUN_SETIFF_NR,
+    size_of::<i32>() as u32
+);
+ioctl_ioc_nr!(
+    TUNSETQUEUE,
+    _IOC_WRITE,
+    TUN_IOCTL_TYPE,
+    TUN_SETQUEUE_NR,
+    size_of::<i32>() as u32
+);
+
+/// Struct representing the TAP interface of a device.
+///
+/// # Attributes
+///
+/// * `name` - Interface name (a `%d` being numbered by the kernel).
+/// * `tap_mac` - Hardware address (kernel-assigned if None).
+/// * `master` - Master the interface is enslaved to (e.g. a bridge).
+/// * `mtu` - MTU (the kernel default if None).
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TapConfig {
+    pub name: String,
+    pub tap_mac: Option<MacAddress>,
+    pub master: Option<String>,
+    pub mtu: Option<u32>,
+}
+
+impl TapConfig {
+    /// Reads the TAP interface of a device from its backend options.
+    ///
+    /// # Arguments
+    ///
+    /// * `device` - The device, whose `tap`, `tap_mac`, `master` and `mtu` options describe
+    ///   the interface.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Option<TapConfig>>` - The interface, None if the device sets no `tap`
+    ///   option, `InvalidBackendOption` if an option is invalid.
+    pub fn from_device(device: &ConfigDevice) -> Result<Option<Self>> {
+        let option = |key: &str| device.options.get(key).map(String::as_str);
+        let invalid =
+            |key: &str, reason: String| Error::InvalidBackendOption(key.to_string(), reason);
+        let name = match option("tap") {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+        if name.is_empty() || name.len() >= IFNAMSIZ || name.contains(['/', ' ']) {
+            return Err(invalid("tap", format!("{} is not an interface name", name)));
+        }
+        let tap_mac = match option("tap_mac") {
+            Some(mac) => Some(
+                mac.parse()
+                    .map_err(|_| invalid("tap_mac", format!("{} is not a MAC address", mac)))?,
+            ),
+            None => None,
+        };
+        let mtu = match option("mtu") {
+            Some(mtu) => Some(
+                mtu.parse()
+                    .map_err(|_| invalid("mtu", format!("{} is not an MTU", mtu)))?,
+            ),
+            None => None,
+        };
+        Ok(Some(TapConfig {
+            name: name.to_string(),
+            tap_mac,
+            master: option("master").map(str::to_string),
+            mtu,
+        }))
+    }
+}
+
+/// Returns the index of a network interface.
+fn if_index(name: &str) -> io::Result<u32> {
+    let name = CString::new(name).map_err(|_| io::Error::from_raw_os_error(libc::EINVAL))?;
+    // SAFETY: The name is a valid C string.
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(io::Error::last_os_error()),
+        index => Ok(index),
+    }
+}
+
+/// Issues a TUN/TAP ioctl taking an interface request.
+pub(crate) fn tun_ioctl(
+    file: &File,
+    request: libc::c_ulong,
+    ifreq: &mut [u8; IFREQ_SIZE],
+) -> io::Result<()> {
+    // SAFETY: The request is an interface request, the buffer being large enough for it.
+    if unsafe { libc::ioctl(file.as_raw_fd(), request as _, ifreq.as_mut_ptr()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Configures a network interface and brings it up, through rtnetlink.
+///
+/// # Arguments
+///
+/// * `index` - Interface index.
+/// * `config` - The interface configuration.
+///
+/// # Returns
+///
+/// * `io::Result<()>` - Ok once the kernel acknowledged the configuration.
+fn set_link(index: u32, config: &TapConfig) -> io::Result<()> {
+    let mut attrs = Vec::new();
+    let mut attr = |kind: u16, data: &[u8]| {
+        attrs.extend_from_slice(&((4 + data.len()) as u16).to_ne_bytes());
+        attrs.extend_from_slice(&kind.to_ne_bytes());
+        attrs.extend_from_slice(data);
+        attrs.resize(attrs.len().next_multiple_of(4), 0);
+    };
+    if let Some(mac) = &config.tap_mac {
+        attr(IFLA_ADDRESS, &mac.0);
+    }
+    if let Some(mtu) = config.mtu {
+        attr(IFLA_MTU, &mtu.to_ne_bytes());
+    }
+    if let Some(master) = &config.master {
+        attr(IFLA_MASTER, &if_index(master)?.to_ne_bytes());
+    }
+
+    let len = NLMSG_HEADER_SIZE + IFINFOMSG_SIZE + attrs.len();
+    let mut msg = Vec::with_capacity(len);
+    msg.extend_from_slice(&(len as u32).to_ne_bytes());
+    msg.extend_from_slice(&RTM_NEWLINK.to_ne_bytes());
+    msg.extend_from_slice(&(NLM_F_REQUEST | NLM_F_ACK).to_ne_bytes());
+    msg.extend_from_slice(&1u32.to_ne_bytes()); // Sequence number
+    msg.extend_from_slice(&0u32.to_ne_bytes()); // Port ID (assigned by the kernel)
+    msg.extend_from_slice(&[libc::AF_UNSPEC as u8, 0, 0, 0]); // Family, padding and type
+    msg.extend_from_slice(&index.to_ne_bytes());
+    msg.extend_from_slice(&IFF_UP.to_ne_bytes()); // Flags
+    msg.extend_from_slice(&IFF_UP.to_ne_bytes()); // Changed flags
+    msg.extend_from_slice(&attrs);
+
+    // SAFETY: The arguments are valid and the result is checked.
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            libc::NETLINK_ROUTE,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `fd` is a valid file descriptor owned by nothing else.
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    // SAFETY: The message is valid for its length.
+    if unsafe { libc::send(socket.as_raw_fd(), msg.as_ptr() as *const _, msg.len(), 0) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // The acknowledgement carries the error code (0 on success) after its header
+    let mut reply = [0u8; 1024];
+    // SAFETY: The buffer is valid for its length.
+    let received = unsafe {
+        libc::recv(
+            socket.as_raw_fd(),
+            reply.as_mut_ptr() as *mut _,
+            reply.len(),
+            0,
+        )
+    };
+    if received < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let kind = u16::from_ne_bytes([reply[4], reply[5]]);
+    if (received as usize) < NLMSG_HEADER_SIZE + 4 || kind != NLMSG_ERROR {
+        return Err(io::Error::from_raw_os_error(libc::EPROTO));
+    }
+    let error = i32::from_ne_bytes(reply[16..20].try_into().unwrap());
+    match error {
+        0 => Ok(()),
+        _ => Err(io::Error::from_raw_os_error(-error)),
+    }
+}
+
+/// Struct representing a TAP interface set up by the frontend.
+///
+/// # Attributes
+///
+/// * `file` - The detached queue of the frontend, holding the interface.
+/// * `name` - Interface name (as numbered by the kernel).
+#[derive(Debug)]
+pub struct Tap {
+    file: File,
+    name: String,
+}
+
+impl Tap {
+    /// Creates and configures a TAP interface, which is removed once dropped (and its
+    /// backend closed its queues).
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The interface configuration.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Tap>` - The interface, or `TapFailed`.
+    pub fn create(config: &TapConfig) -> Result<Self> {
+        let failed = |e| Error::TapFailed(config.name.clone(), e);
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(TUN_DEVICE_PATH)
+            .map_err(failed)?;
+
+        let mut ifreq = [0u8; IFREQ_SIZE];
+        ifreq[..config.name.len()].copy_from_slice(config.name.as_bytes());
+        let flags = TUN_IFF_TAP | TUN_IFF_NO_PI | TUN_IFF_VNET_HDR | TUN_IFF_MULTI_QUEUE;
+        ifreq[IFNAMSIZ..IFNAMSIZ + 2].copy_from_slice(&flags.to_ne_bytes());
+        tun_ioctl(&file, TUNSETIFF(), &mut ifreq).map_err(failed)?;
+        // The kernel numbered the name, if templated
+        let len = ifreq[..IFNAMSIZ]
+            .iter()
+            .position(|&b| b == 0)
+            .unwrap_or(IFNAMSIZ);
+        let name = String::from_utf8_lossy(&ifreq[..len]).into_owned();
+        let failed = |e| Error::TapFailed(name.clone(), e);
+
+        // Leave the traffic to the queues of the backend
+        let mut ifreq = [0u8; IFREQ_SIZE];
+        ifreq[IFNAMSIZ..IFNAMSIZ + 2].copy_from_slice(&TUN_IFF_DETACH_QUEUE.to_ne_bytes());
+        tun_ioctl(&file, TUNSETQUEUE(), &mut ifreq).map_err(failed)?;
+
+        set_link(if_index(&name).map_err(failed)?, config).map_err(failed)?;
+        Ok(Tap { file, name })
+    }
+
+    /// Returns the name of the interface.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the packet captures of the interface (see `DeviceRegistry::attach_capture`).
+    pub fn capture(&self) -> PacketCapture {
+        PacketCapture::new(&self.name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::backend_device;
+    use std::fs;
+    use std::path::Path;
+
+    fn device(options: &[(&str, &str)]) -> ConfigDevice {
+        backend_device("net0", "net", None, options)
+    }
+
+    #[test]
+    fn test_tap_options() {
+        assert_eq!(TapConfig::from_device(&device(&[])).unwrap(), None);
+        let options = [
+            ("tap", "bao-tap%d"),
+            ("tap_mac", "52:54:00:12:34:AB"),
+            ("master", "br0"),
+            ("mtu", "9000"),
+        ];
+        assert_eq!(
+            TapConfig::from_device(&device(&options)).unwrap(),
+            Some(TapConfig {
+                name: "bao-tap%d".to_string(),
+                tap_mac: Some(MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0xab])),
+                master: Some("br0".to_string()),
+                mtu: Some(9000),
+            })
+        );
+
+        for (key, value) in [
+            ("tap", "bao-tap-interface%d"),
+            ("tap", ""),
+            ("tap_mac", "52:54:00:12:34"),
+            ("tap_mac", "01:00:5e:00:00:01"),
+            ("mtu", "jumbo"),
+        ] {
+            let mut options = vec![("tap", "bao-tap%d")];
+            options.retain(|(option, _)| *option != key);
+            options.push((key, value));
+            assert!(
+                matches!(
+                    TapConfig::from_device(&device(&options)),
+                    Err(Error::InvalidBackendOption(option, _)) if option == key
+                ),
+                "{} {}",
+                key,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn test_tap_create() {
+        let config = TapConfig {
+            name: "bao-test%d".to_string(),
+            tap_mac: Some(MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56])),
+            master: None,
+            mtu: Some(1400),
+        };
+        let tap = match Tap::create(&config) {
+            Ok(tap) => tap,
+            // Creating interfaces needs CAP_NET_ADMIN and the tun module
+            Err(Error::TapFailed(_, e))
+                if matches!(
+                    e.raw_os_error(),
+                    Some(libc::EPERM | libc::EACCES | libc::ENOENT | libc::ENODEV)
+                ) =>
+            {
+                return
+            }
+            Err(e) => panic!("{}", e),
+        };
+        assert!(tap.name().starts_with("bao-test"));
+        let sysfs = Path::new("/sys/class/net").join(tap.name());
+        let read = |attr: &str| fs::read_to_string(sysfs.join(attr)).unwrap();
+        assert_eq!(read("address").trim(), "52:54:00:12:34:56");
+        assert_eq!(read("mtu").trim(), "1400");
+        let flags = u32::from_str_radix(read("flags").trim().trim_start_matches("0x"), 16);
+        assert_ne!(flags.unwrap() & IFF_UP, 0);
+
+        // The interface goes away with its last queue
+        drop(tap);
+        assert!(!sysfs.exists());
+
+        let config = TapConfig {
+            master: Some("bao-missing0".to_string()),
+            ..config
+        };
+        assert!(matches!(
+            Tap::create(&config),
+            Err(Error::TapFailed(_, e)) if e.raw_os_error() == Some(libc::ENODEV)
+        ));
+    }
+}
@@ -0,0 +1,568 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao testing utilities.
+//!
+//! `MockHypervisor` implements the `Hypervisor` trait in memory and `ScriptedDriver`
+//! replays virtio-mmio driver sequences against it, so the device model can be exercised
+//! entirely in `cargo test`. `backend_device` builds the device configurations the
+//! backend tests parse their options from.
+
+#![allow(dead_code)]
+
+use super::config::ConfigDevice;
+use super::defines::*;
+use super::error::{Error, Result};
+use super::hypervisor::Hypervisor;
+use super::types::{
+    BaoIoEventFd, BaoIoRequest, BaoIrqFd, GuestAddress, IoEventFdFlags, IrqFdFlags,
+};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Struct representing an in-memory Bao I/O dispatcher.
+///
+/// # Attributes
+///
+/// * `pending` - I/O requests waiting to be fetched by the device model.
+/// * `completed` - I/O requests completed by the device model.
+/// * `interrupts` - Number of interrupts injected into the guest.
+/// * `ioeventfds` - Assigned I/O eventfds.
+/// * `irqfds` - Assigned IRQ eventfds.
+#[derive(Default)]
+pub struct MockHypervisor {
+    pending: Mutex<VecDeque<BaoIoRequest>>,
+    completed: Mutex<VecDeque<BaoIoRequest>>,
+    interrupts: AtomicU64,
+    ioeventfds: Mutex<Vec<BaoIoEventFd>>,
+    irqfds: Mutex<Vec<BaoIrqFd>>,
+}
+
+impl MockHypervisor {
+    /// Creates an idle mock hypervisor.
+    ///
+    /// # Returns
+    ///
+    /// * `MockHypervisor` - The mock hypervisor.
+    pub fn new() -> Self {
+        MockHypervisor::default()
+    }
+
+    /// Queues a guest I/O request.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The I/O request.
+    pub fn push_request(&self, req: BaoIoRequest) {
+        self.pending.lock().unwrap().push_back(req);
+    }
+
+    /// Takes the oldest completed I/O request.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<BaoIoRequest>` - The completed request, if any.
+    pub fn pop_completed(&self) -> Option<BaoIoRequest> {
+        self.completed.lock().unwrap().pop_front()
+    }
+
+    /// Hands a guest I/O request to the device model and waits for its completion.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The I/O request, updated with its completion.
+    /// * `serve` - Serves the pending requests of the hypervisor.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the request was completed, or `RequestNotCompleted`.
+    pub fn dispatch<F>(&self, req: &mut BaoIoRequest, mut serve: F) -> Result<()>
+    where
+        F: FnMut(&dyn Hypervisor) -> Result<()>,
+    {
+        self.push_request(*req);
+        serve(self)?;
+        *req = self
+            .pop_completed()
+            .ok_or(Error::RequestNotCompleted(req.reg_off))?;
+        Ok(())
+    }
+
+    /// Returns the number of I/O requests waiting to be fetched.
+    ///
+    /// # Returns
+    ///
+    /// * `usize` - The number of pending requests.
+    pub fn pending(&self) -> usize {
+        self.pending.lock().unwrap().len()
+    }
+
+    /// Returns the number of interrupts injected into the guest.
+    ///
+    /// # Returns
+    ///
+    /// * `u64` - The number of interrupts.
+    pub fn interrupts(&self) -> u64 {
+        self.interrupts.load(Ordering::Relaxed)
+    }
+
+    /// Returns the assigned I/O eventfds.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<BaoIoEventFd>` - The I/O eventfds.
+    pub fn ioeventfds(&self) -> Vec<BaoIoEventFd> {
+        self.ioeventfds.lock().unwrap().clone()
+    }
+
+    /// Returns the assigned IRQ eventfds.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<BaoIrqFd>` - The IRQ eventfds.
+    pub fn irqfds(&self) -> Vec<BaoIrqFd> {
+        self.irqfds.lock().unwrap().clone()
+    }
+}
+
+impl Hypervisor for MockHypervisor {
+    fn attach_client(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn wait_request(&self, _timeout: Duration) -> Result<bool> {
+        Ok(!self.pending.lock().unwrap().is_empty())
+    }
+
+    fn next_request(&self) -> Result<Option<BaoIoRequest>> {
+        Ok(self.pending.lock().unwrap().pop_front())
+    }
+
+    fn complete_request(&self, req: &BaoIoRequest) -> Result<()> {
+        self.completed.lock().unwrap().push_back(*req);
+        Ok(())
+    }
+
+    fn notify_guest(&self) -> Result<()> {
+        self.interrupts.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    }
+
+    fn register_ioeventfd(&self, ioeventfd: &BaoIoEventFd) -> Result<()> {
+        let mut ioeventfds = self.ioeventfds.lock().unwrap();
+        ioeventfds.retain(|e| e.fd != ioeventfd.fd || e.addr != ioeventfd.addr);
+        if !ioeventfd.flags().contains(IoEventFdFlags::DEASSIGN) {
+            ioeventfds.push(*ioeventfd);
+        }
+        Ok(())
+    }
+
+    fn register_irqfd(&self, irqfd: &BaoIrqFd) -> Result<()> {
+        let mut irqfds = self.irqfds.lock().unwrap();
+        irqfds.retain(|e| e.fd != irqfd.fd);
+        if !irqfd.flags().contains(IrqFdFlags::DEASSIGN) {
+            irqfds.push(*irqfd);
+        }
+        Ok(())
+    }
+}
+
+/// Represents a register access performed by the scripted driver.
+///
+/// # Variants
+///
+/// * `Read` - Reads a register, optionally checking the returned value.
+/// * `Write` - Writes a register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriverStep {
+    Read { reg_off: u64, expect: Option<u64> },
+    Write { reg_off: u64, value: u64 },
+}
+
+/// Struct representing a guest driver replaying a sequence of 32-bit register accesses.
+///
+/// # Attributes
+///
+/// * `virtio_id` - Virtio instance ID of the device.
+/// * `base` - Base address of the device region.
+/// * `steps` - Register accesses, in order.
+#[derive(Debug, Clone)]
+pub struct ScriptedDriver {
+    virtio_id: u64,
+    base: GuestAddress,
+    steps: Vec<DriverStep>,
+}
+
+impl ScriptedDriver {
+    /// Creates an empty script.
+    ///
+    /// # Arguments
+    ///
+    /// * `virtio_id` - Virtio instance ID of the device.
+    /// * `base` - Base address of the device region.
+    ///
+    /// # Returns
+    ///
+    /// * `ScriptedDriver` - The driver.
+    pub fn new(virtio_id: u64, base: GuestAddress) -> Self {
+        ScriptedDriver {
+            virtio_id,
+            base,
+            steps: Vec::new(),
+        }
+    }
+
+    /// Appends a register read.
+    pub fn read(mut self, reg_off: u64) -> Self {
+        self.steps.push(DriverStep::Read {
+            reg_off,
+            expect: None,
+        });
+        self
+    }
+
+    /// Appends a register read, checking the returned value.
+    pub fn expect(mut self, reg_off: u64, value: u64) -> Self {
+        self.steps.push(DriverStep::Read {
+            reg_off,
+            expect: Some(value),
+        });
+        self
+    }
+
+    /// Appends a register write.
+    pub fn write(mut self, reg_off: u64, value: u64) -> Self {
+        self.steps.push(DriverStep::Write { reg_off, value });
+        self
+    }
+
+    /// Creates the virtio-mmio (version 2) initialization sequence of a device.
+    ///
+    /// The driver resets the device, negotiates `features`, sets up every virtqueue (the
+    /// rings of queue `i` are placed at `0x1000_0000 + i * 0x10000`) and sets DRIVER_OK.
+    ///
+    /// # Arguments
+    ///
+    /// * `virtio_id` - Virtio instance ID of the device.
+    /// * `base` - Base address of the device region.
+    /// * `device_id` - Expected virtio device ID (e.g. 4 for rng).
+    /// * `features` - Features accepted by the driver.
+    /// * `queue_sizes` - Size of each virtqueue.
+    ///
+    /// # Returns
+    ///
+    /// * `ScriptedDriver` - The driver.
+    pub fn virtio_init(
+        virtio_id: u64,
+        base: GuestAddress,
+        device_id: u32,
+        features: u64,
+        queue_sizes: &[u16],
+    ) -> Self {
+        Self::virtio_init_at(
+            virtio_id,
+            base,
+            device_id,
+            features,
+            queue_sizes,
+            GuestAddress(0x1000_0000),
+        )
+    }
+
+    /// Creates the virtio-mmio (version 2) initialization sequence of a device, placing
+    /// the rings of queue `i` at `rings + i * 0x10000` (descriptor table, then available
+    /// ring at +0x4000 and used ring at +0x8000).
+    ///
+    /// # Arguments
+    ///
+    /// * `virtio_id` - Virtio instance ID of the device.
+    /// * `base` - Base address of the device region.
+    /// * `device_id` - Expected virtio device ID (e.g. 4 for rng).
+    /// * `features` - Features accepted by the driver.
+    /// * `queue_sizes` - Size of each virtqueue.
+    /// * `rings` - Guest address of the rings of the first queue.
+    ///
+    /// # Returns
+    ///
+    /// * `ScriptedDriver` - The driver.
+    pub fn virtio_init_at(
+        virtio_id: u64,
+        base: GuestAddress,
+        device_id: u32,
+        features: u64,
+        queue_sizes: &[u16],
+        rings: GuestAddress,
+    ) -> Self {
+        let status = VIRTIO_CONFIG_S_ACKNOWLEDGE | VIRTIO_CONFIG_S_DRIVER;
+        let mut driver = ScriptedDriver::new(virtio_id, base)
+            .expect(VIRTIO_MMIO_MAGIC_VALUE, VIRTIO_MMIO_MAGIC)
+            .expect(VIRTIO_MMIO_VERSION, VIRTIO_MMIO_VERSION_2)
+            .expect(VIRTIO_MMIO_DEVICE_ID, u64::from(device_id))
+            .read(VIRTIO_MMIO_VENDOR_ID)
+            .write(VIRTIO_MMIO_STATUS, 0)
+            .write(VIRTIO_MMIO_STATUS, VIRTIO_CONFIG_S_ACKNOWLEDGE)
+            .write(VIRTIO_MMIO_STATUS, status)
+            .write(VIRTIO_MMIO_DEVICE_FEATURES_SEL, 0)
+            .read(VIRTIO_MMIO_DEVICE_FEATURES)
+            .write(VIRTIO_MMIO_DEVICE_FEATURES_SEL, 1)
+            .read(VIRTIO_MMIO_DEVICE_FEATURES)
+            .write(VIRTIO_MMIO_DRIVER_FEATURES_SEL, 0)
+            .write(VIRTIO_MMIO_DRIVER_FEATURES, features & 0xffff_ffff)
+            .write(VIRTIO_MMIO_DRIVER_FEATURES_SEL, 1)
+            .write(VIRTIO_MMIO_DRIVER_FEATURES, features >> 32)
+            .write(VIRTIO_MMIO_STATUS, status | VIRTIO_CONFIG_S_FEATURES_OK)
+            .expect(VIRTIO_MMIO_STATUS, status | VIRTIO_CONFIG_S_FEATURES_OK);
+        for (index, size) in queue_sizes.iter().enumerate() {
+            let desc = rings.raw() + index as u64 * 0x10000;
+            let (avail, used) = (desc + 0x4000, desc + 0x8000);
+            driver = driver
+                .write(VIRTIO_MMIO_QUEUE_SEL, index as u64)
+                .expect(VIRTIO_MMIO_QUEUE_READY, 0)
+                .read(VIRTIO_MMIO_QUEUE_NUM_MAX)
+                .write(VIRTIO_MMIO_QUEUE_NUM, u64::from(*size))
+                .write(VIRTIO_MMIO_QUEUE_DESC_LOW, desc & 0xffff_ffff)
+                .write(VIRTIO_MMIO_QUEUE_DESC_HIGH, desc >> 32)
+                .write(VIRTIO_MMIO_QUEUE_AVAIL_LOW, avail & 0xffff_ffff)
+                .write(VIRTIO_MMIO_QUEUE_AVAIL_HIGH, avail >> 32)
+                .write(VIRTIO_MMIO_QUEUE_USED_LOW, used & 0xffff_ffff)
+                .write(VIRTIO_MMIO_QUEUE_USED_HIGH, used >> 32)
+                .write(VIRTIO_MMIO_QUEUE_READY, 1);
+        }
+        driver.write(
+            VIRTIO_MMIO_STATUS,
+            status | VIRTIO_CONFIG_S_FEATURES_OK | VIRTIO_CONFIG_S_DRIVER_OK,
+        )
+    }
+
+    /// Returns the register accesses of the script.
+    ///
+    /// # Returns
+    ///
+    /// * `&[DriverStep]` - The register accesses.
+    pub fn steps(&self) -> &[DriverStep] {
+        &self.steps
+    }
+
+    /// Builds the I/O request of a step.
+    fn request(&self, step: &DriverStep) -> BaoIoRequest {
+        let (op, reg_off, value) = match *step {
+            DriverStep::Read { reg_off, .. } => (BAO_IO_READ, reg_off, 0),
+            DriverStep::Write { reg_off, value } => (BAO_IO_WRITE, reg_off, value),
+        };
+        BaoIoRequest {
+            virtio_id: self.virtio_id,
+            reg_off,
+            addr: self.base.raw() + reg_off,
+            op,
+            value,
+            access_width: 4,
+            cpu_id: 0,
+            vcpu_id: 0,
+            ret: 0,
+        }
+    }
+
+    /// Runs the script, handing each access to a handler.
+    ///
+    /// # Arguments
+    ///
+    /// * `handle` - Handles an I/O request (setting the value of reads).
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<BaoIoRequest>>` - The completed requests, or `ScriptMismatch` if a read
+    ///   returned an unexpected value.
+    pub fn run<F>(&self, mut handle: F) -> Result<Vec<BaoIoRequest>>
+    where
+        F: FnMut(&mut BaoIoRequest) -> Result<()>,
+    {
+        let mut completed = Vec::with_capacity(self.steps.len());
+        for step in &self.steps {
+            let mut req = self.request(step);
+            handle(&mut req)?;
+            if let DriverStep::Read {
+                reg_off,
+                expect: Some(expected),
+            } = *step
+            {
+                if req.value != expected {
+                    return Err(Error::ScriptMismatch {
+                        reg_off,
+                        expected,
+                        actual: req.value,
+                    });
+                }
+            }
+            completed.push(req);
+        }
+        Ok(completed)
+    }
+
+    /// Runs the script through a mock hypervisor.
+    ///
+    /// Each access is queued on the hypervisor, then `serve` is expected to fetch and
+    /// complete it through the `Hypervisor` trait, as the device model does.
+    ///
+    /// # Arguments
+    ///
+    /// * `hypervisor` - The mock hypervisor.
+    /// * `serve` - Serves the pending requests of the hypervisor.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<BaoIoRequest>>` - The completed requests.
+    pub fn run_on<F>(&self, hypervisor: &MockHypervisor, mut serve: F) -> Result<Vec<BaoIoRequest>>
+    where
+        F: FnMut(&dyn Hypervisor) -> Result<()>,
+    {
+        self.run(|req| hypervisor.dispatch(req, &mut serve))
+    }
+}
+
+/// Builds the configuration of a device with backend options, as the backend tests use.
+///
+/// # Arguments
+///
+/// * `name` - Name of the device.
+/// * `device_type` - Type of the device.
+/// * `backend` - Built-in backend serving the device, if any.
+/// * `options` - Backend options, as key-value pairs.
+///
+/// # Returns
+///
+/// * `ConfigDevice` - The device configuration.
+pub fn backend_device(
+    name: &str,
+    device_type: &str,
+    backend: Option<&str>,
+    options: &[(&str, &str)],
+) -> ConfigDevice {
+    let mut device = ConfigDevice {
+        name: name.to_string(),
+        device_type: device_type.to_string(),
+        backend: backend.map(str::to_string),
+        ..Default::default()
+    };
+    for (key, value) in options {
+        device.options.insert(key.to_string(), value.to_string());
+    }
+    device
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::BaoMmioBus;
+    use std::sync::{Arc, Mutex};
+    use vm_device::bus::{MmioAddress, MmioAddressOffset};
+    use vm_device::MutDeviceMmio;
+
+    /// Register file of a virtio-mmio rng device (no virtqueue processing).
+    #[derive(Default)]
+    struct RngRegisters {
+        status: u32,
+        features_sel: u32,
+        queue_sel: u32,
+        queue_ready: [u32; 1],
+    }
+
+    impl MutDeviceMmio for RngRegisters {
+        fn mmio_read(&mut self, _base: MmioAddress, offset: MmioAddressOffset, data: &mut [u8]) {
+            let value = match offset {
+                VIRTIO_MMIO_MAGIC_VALUE => VIRTIO_MMIO_MAGIC as u32,
+                VIRTIO_MMIO_VERSION => 2,
+                VIRTIO_MMIO_DEVICE_ID => 4,
+                VIRTIO_MMIO_DEVICE_FEATURES => self.features_sel,
+                VIRTIO_MMIO_QUEUE_NUM_MAX => 256,
+                VIRTIO_MMIO_QUEUE_READY => self.queue_ready[self.queue_sel as usize],
+                VIRTIO_MMIO_STATUS => self.status,
+                _ => 0,
+            };
+            data.copy_from_slice(&value.to_le_bytes());
+        }
+
+        fn mmio_write(&mut self, _base: MmioAddress, offset: MmioAddressOffset, data: &[u8]) {
+            let value = u32::from_le_bytes(data.try_into().unwrap());
+            match offset {
+                VIRTIO_MMIO_STATUS => self.status = value,
+                VIRTIO_MMIO_DEVICE_FEATURES_SEL => self.features_sel = value,
+                VIRTIO_MMIO_QUEUE_SEL => self.queue_sel = value,
+                VIRTIO_MMIO_QUEUE_READY => self.queue_ready[self.queue_sel as usize] = value,
+                _ => {}
+            }
+        }
+    }
+
+    #[test]
+    fn test_scripted_virtio_init() {
+        let registers = Arc::new(Mutex::new(RngRegisters::default()));
+        let mut bus = BaoMmioBus::new();
+        bus.register(
+            GuestAddress(0xa003e00),
+            VIRTIO_MMIO_IO_SIZE,
+            registers.clone(),
+        )
+        .unwrap();
+        let hypervisor = MockHypervisor::new();
+
+        let driver = ScriptedDriver::virtio_init(0, GuestAddress(0xa003e00), 4, 1 << 32, &[64]);
+        let completed = driver
+            .run_on(&hypervisor, |hv| {
+                while let Some(mut req) = hv.next_request()? {
+                    bus.handle_request(&mut req)?;
+                    hv.complete_request(&req)?;
+                }
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(completed.len(), driver.steps().len());
+        assert_eq!(registers.lock().unwrap().status, 0xf);
+        assert_eq!(registers.lock().unwrap().queue_ready, [1]);
+
+        // A wrong device ID is reported
+        let driver = ScriptedDriver::virtio_init(0, GuestAddress(0xa003e00), 22, 0, &[]);
+        assert!(matches!(
+            driver.run(|req| bus.handle_request(req)),
+            Err(Error::ScriptMismatch {
+                reg_off: VIRTIO_MMIO_DEVICE_ID,
+                expected: 22,
+                actual: 4
+            })
+        ));
+        // A request never completed is reported
+        assert!(matches!(
+            driver.run_on(&hypervisor, |_| Ok(())),
+            Err(Error::RequestNotCompleted(VIRTIO_MMIO_MAGIC_VALUE))
+        ));
+    }
+
+    #[test]
+    fn test_mock_hypervisor_fds() {
+        let hypervisor = MockHypervisor::new();
+        hypervisor
+            .register_irqfd(&BaoIrqFd::new(5, IrqFdFlags::empty()))
+            .unwrap();
+        hypervisor
+            .register_ioeventfd(&BaoIoEventFd::new(
+                6,
+                IoEventFdFlags::empty(),
+                GuestAddress(0xa003e50),
+                4,
+                0,
+            ))
+            .unwrap();
+        assert_eq!(hypervisor.irqfds().len(), 1);
+        assert_eq!(hypervisor.ioeventfds().len(), 1);
+
+        hypervisor
+            .register_irqfd(&BaoIrqFd::new(5, IrqFdFlags::DEASSIGN))
+            .unwrap();
+        assert!(hypervisor.irqfds().is_empty());
+        hypervisor.notify_guest().unwrap();
+        assert_eq!(hypervisor.interrupts(), 1);
+    }
+}
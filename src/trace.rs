@@ -0,0 +1,402 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao request trace export.
+//!
+//! Writes per-request events in the Chrome trace event format (JSON), which both
+//! `chrome://tracing` and the Perfetto UI load, so latency outliers can be inspected on a
+//! timeline next to guest and backend traces. Events go to a `TraceSink`, so other
+//! exporters (e.g. OTLP, see the `otel` module) can receive the same events.
+//!
+//! The trace and audit files are `LogFile`s: a soak test delimits its measurement windows
+//! by flushing or rotating them from the control socket, without restarting the frontend.
+
+#![allow(dead_code)]
+
+use super::error::{Error, Result};
+use super::hypervisor::Hypervisor;
+use super::types::{BaoIoEventFd, BaoIoRequest, BaoIrqFd};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Enum representing the stage of a request recorded in a trace.
+///
+/// # Variants
+///
+/// * `Fetch` - The request is fetched from the hypervisor.
+/// * `Dispatch` - The request is dispatched to the device model.
+/// * `BackendKick` - The device backend is notified (queue notification).
+/// * `Completion` - The request completion is delivered to the hypervisor.
+/// * `IrqInject` - The device interrupt is injected into the guest.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TracePhase {
+    Fetch,
+    Dispatch,
+    BackendKick,
+    Completion,
+    IrqInject,
+}
+
+impl TracePhase {
+    /// Returns the event name of the stage.
+    pub fn name(&self) -> &'static str {
+        match self {
+            TracePhase::Fetch => "fetch",
+            TracePhase::Dispatch => "dispatch",
+            TracePhase::BackendKick => "backend_kick",
+            TracePhase::Completion => "completion",
+            TracePhase::IrqInject => "irq_inject",
+        }
+    }
+}
+
+/// Trait representing a destination of trace events.
+pub trait TraceSink: Send + Sync {
+    /// Records a stage lasting from `start` until now.
+    ///
+    /// # Arguments
+    ///
+    /// * `phase` - The stage.
+    /// * `req` - The I/O request.
+    /// * `start` - Time the stage started.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the event was recorded.
+    fn span(&self, phase: TracePhase, req: &BaoIoRequest, start: Instant) -> Result<()>;
+
+    /// Records an instantaneous stage.
+    ///
+    /// # Arguments
+    ///
+    /// * `phase` - The stage.
+    /// * `req` - The I/O request, if any.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the event was recorded.
+    fn instant(&self, phase: TracePhase, req: Option<&BaoIoRequest>) -> Result<()>;
+
+    /// Terminates the trace, flushing the recorded events.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the trace was terminated.
+    fn finish(&self) -> Result<()>;
+}
+
+/// Trait representing a file recording the requests (e.g. a trace or an audit log).
+pub trait LogFile: Send + Sync {
+    /// Flushes the buffered records to the file.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the records were flushed.
+    fn flush(&self) -> Result<()>;
+
+    /// Closes the file, keeping it aside, and starts a new one.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<()>` - Ok if the file was rotated.
+    fn rotate(&self) -> Result<()>;
+}
+
+/// Several sinks receive every event.
+impl TraceSink for Vec<Arc<dyn TraceSink>> {
+    fn span(&self, phase: TracePhase, req: &BaoIoRequest, start: Instant) -> Result<()> {
+        self.iter()
+            .try_for_each(|sink| sink.span(phase, req, start))
+    }
+
+    fn instant(&self, phase: TracePhase, req: Option<&BaoIoRequest>) -> Result<()> {
+        self.iter().try_for_each(|sink| sink.instant(phase, req))
+    }
+
+    fn finish(&self) -> Result<()> {
+        self.iter().try_for_each(|sink| sink.finish())
+    }
+}
+
+/// Struct representing a trace file.
+///
+/// Events are appended to a JSON array, closed by `finish`; an unterminated trace (e.g.
+/// after a crash) is still accepted by the trace viewers. Rotating the trace closes it as
+/// `path.<n>` (`path.1` first), so every measurement window is kept.
+///
+/// # Attributes
+///
+/// * `path` - Trace file path.
+/// * `file` - Trace file.
+/// * `start` - Time origin of the trace.
+/// * `pid` - Process ID of the events.
+/// * `rotations` - Number of times the trace was rotated.
+pub struct Tracer {
+    path: String,
+    file: Mutex<BufWriter<File>>,
+    start: Instant,
+    pid: u32,
+    rotations: AtomicUsize,
+}
+
+impl Tracer {
+    /// Creates a trace file.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Trace file path.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Tracer>` - The tracer.
+    pub fn new(path: &str) -> Result<Self> {
+        Ok(Tracer {
+            path: path.to_string(),
+            file: Mutex::new(Self::create_file(path)?),
+            start: Instant::now(),
+            pid: std::process::id(),
+            rotations: AtomicUsize::new(0),
+        })
+    }
+
+    /// Creates a trace file and opens its event array.
+    fn create_file(path: &str) -> Result<BufWriter<File>> {
+        let mut file = BufWriter::new(File::create(path).map_err(Error::TraceFailed)?);
+        file.write_all(b"[\n").map_err(Error::TraceFailed)?;
+        Ok(file)
+    }
+
+    /// Closes the event array of a trace file.
+    fn close_file(&self, file: &mut BufWriter<File>) -> Result<()> {
+        // Metadata event closing the array (no trailing comma after the last event)
+        write!(
+            file,
+            "{{\"name\":\"process_name\",\"ph\":\"M\",\"pid\":{},\"args\":{{\"name\":\"bao-sys\"}}}}\n]\n",
+            self.pid
+        )
+        .map_err(Error::TraceFailed)?;
+        file.flush().map_err(Error::TraceFailed)
+    }
+
+    /// Writes an event.
+    fn event(
+        &self,
+        phase: TracePhase,
+        kind: char,
+        at: Instant,
+        duration: Option<Duration>,
+        req: Option<&BaoIoRequest>,
+    ) -> Result<()> {
+        let ts = at.saturating_duration_since(self.start).as_nanos() as f64 / 1000.0;
+        let mut event = format!(
+            "{{\"name\":\"{}\",\"cat\":\"bao\",\"ph\":\"{}\",\"ts\":{:.3},\"pid\":{},\"tid\":{}",
+            phase.name(),
+            kind,
+            ts,
+            self.pid,
+            req.map_or(0, |r| r.virtio_id)
+        );
+        if let Some(duration) = duration {
+            event += &format!(",\"dur\":{:.3}", duration.as_nanos() as f64 / 1000.0);
+        }
+        if kind == 'i' {
+            event += ",\"s\":\"t\"";
+        }
+        if let Some(req) = req {
+            event += &format!(
+                ",\"args\":{{\"virtio_id\":{},\"reg_off\":{},\"addr\":{},\"op\":{},\"value\":{},\"vcpu_id\":{}}}",
+                req.virtio_id, req.reg_off, req.addr, req.op, req.value, req.vcpu_id
+            );
+        }
+        event += "},\n";
+        self.file
+            .lock()
+            .unwrap()
+            .write_all(event.as_bytes())
+            .map_err(Error::TraceFailed)
+    }
+}
+
+impl TraceSink for Tracer {
+    fn span(&self, phase: TracePhase, req: &BaoIoRequest, start: Instant) -> Result<()> {
+        self.event(phase, 'X', start, Some(start.elapsed()), Some(req))
+    }
+
+    fn instant(&self, phase: TracePhase, req: Option<&BaoIoRequest>) -> Result<()> {
+        self.event(phase, 'i', Instant::now(), None, req)
+    }
+
+    fn finish(&self) -> Result<()> {
+        self.close_file(&mut self.file.lock().unwrap())
+    }
+}
+
+impl LogFile for Tracer {
+    fn flush(&self) -> Result<()> {
+        self.file
+            .lock()
+            .unwrap()
+            .flush()
+            .map_err(Error::TraceFailed)
+    }
+
+    fn rotate(&self) -> Result<()> {
+        let mut file = self.file.lock().unwrap();
+        self.close_file(&mut file)?;
+        let index = self.rotations.fetch_add(1, Ordering::Relaxed) + 1;
+        fs::rename(&self.path, format!("{}.{}", self.path, index)).map_err(Error::TraceFailed)?;
+        *file = Self::create_file(&self.path)?;
+        Ok(())
+    }
+}
+
+/// Struct representing a hypervisor whose requests are traced.
+///
+/// Without the `instrumentation` feature, the events are compiled out and the requests
+/// go straight to the inner hypervisor.
+///
+/// # Attributes
+///
+/// * `inner` - The traced hypervisor.
+/// * `tracer` - The trace sink.
+pub struct TracingHypervisor<H: Hypervisor> {
+    inner: H,
+    tracer: Arc<dyn TraceSink>,
+}
+
+impl<H: Hypervisor> TracingHypervisor<H> {
+    /// Traces the requests of a hypervisor.
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - The traced hypervisor.
+    /// * `tracer` - The trace sink.
+    ///
+    /// # Returns
+    ///
+    /// * `TracingHypervisor` - The tracing hypervisor.
+    pub fn new(inner: H, tracer: Arc<dyn TraceSink>) -> Self {
+        TracingHypervisor { inner, tracer }
+    }
+}
+
+impl<H: Hypervisor> Hypervisor for TracingHypervisor<H> {
+    fn attach_client(&self) -> Result<()> {
+        self.inner.attach_client()
+    }
+
+    fn wait_request(&self, timeout: Duration) -> Result<bool> {
+        self.inner.wait_request(timeout)
+    }
+
+    fn next_request(&self) -> Result<Option<BaoIoRequest>> {
+        let start = crate::bao_now!();
+        let req = self.inner.next_request()?;
+        if let Some(req) = &req {
+            crate::bao_span!(self.tracer, TracePhase::Fetch, req, start)?;
+        }
+        Ok(req)
+    }
+
+    fn complete_request(&self, req: &BaoIoRequest) -> Result<()> {
+        let start = crate::bao_now!();
+        self.inner.complete_request(req)?;
+        crate::bao_span!(self.tracer, TracePhase::Completion, req, start)
+    }
+
+    fn notify_guest(&self) -> Result<()> {
+        self.inner.notify_guest()?;
+        crate::bao_instant!(self.tracer, TracePhase::IrqInject, None)
+    }
+
+    fn register_ioeventfd(&self, ioeventfd: &BaoIoEventFd) -> Result<()> {
+        self.inner.register_ioeventfd(ioeventfd)
+    }
+
+    fn register_irqfd(&self, irqfd: &BaoIrqFd) -> Result<()> {
+        self.inner.register_irqfd(irqfd)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockHypervisor;
+
+    #[test]
+    #[cfg_attr(not(feature = "instrumentation"), ignore)]
+    fn test_trace_hypervisor_events() {
+        let path = std::env::temp_dir().join(format!("bao-trace-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let tracer: Arc<dyn TraceSink> = Arc::new(Tracer::new(path).unwrap());
+
+        let mock = MockHypervisor::new();
+        mock.push_request(BaoIoRequest {
+            virtio_id: 2,
+            reg_off: 0x50,
+            addr: 0xa003e50,
+            op: 0,
+            value: 0,
+            access_width: 4,
+            cpu_id: 0,
+            vcpu_id: 1,
+            ret: 0,
+        });
+        let hypervisor = TracingHypervisor::new(&mock, tracer.clone());
+        let req = hypervisor.next_request().unwrap().unwrap();
+        let start = Instant::now();
+        tracer.span(TracePhase::BackendKick, &req, start).unwrap();
+        hypervisor.complete_request(&req).unwrap();
+        hypervisor.notify_guest().unwrap();
+        assert!(hypervisor.next_request().unwrap().is_none());
+        tracer.finish().unwrap();
+
+        let trace = std::fs::read_to_string(path).unwrap();
+        let names = [
+            "fetch",
+            "backend_kick",
+            "completion",
+            "irq_inject",
+            "process_name",
+        ];
+        let lines = trace.lines().collect::<Vec<_>>();
+        assert_eq!(lines.first(), Some(&"["));
+        assert_eq!(lines.last(), Some(&"]"));
+        assert_eq!(lines.len(), names.len() + 2);
+        for (line, name) in lines[1..].iter().zip(names) {
+            assert!(line.starts_with(&format!("{{\"name\":\"{}\"", name)));
+        }
+        assert!(lines[1].contains("\"tid\":2"));
+        assert!(lines[1].contains("\"reg_off\":80"));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_trace_rotation() {
+        let path = std::env::temp_dir().join(format!("bao-rotate-{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        let tracer = Tracer::new(path).unwrap();
+
+        // Every window is a complete trace of its own events
+        for window in 1..=2 {
+            for _ in 0..window {
+                tracer.instant(TracePhase::IrqInject, None).unwrap();
+            }
+            LogFile::flush(&tracer).unwrap();
+            tracer.rotate().unwrap();
+            let trace = std::fs::read_to_string(format!("{}.{}", path, window)).unwrap();
+            let lines = trace.lines().collect::<Vec<_>>();
+            assert_eq!(lines.len(), window + 3);
+            assert_eq!(lines.last(), Some(&"]"));
+            std::fs::remove_file(format!("{}.{}", path, window)).unwrap();
+        }
+        tracer.finish().unwrap();
+        assert_eq!(std::fs::read_to_string(path).unwrap().lines().count(), 3);
+        std::fs::remove_file(path).unwrap();
+    }
+}
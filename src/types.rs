@@ -7,7 +7,173 @@
 
 #![allow(dead_code)]
 
+#[cfg(feature = "std")]
+pub use super::config::*;
+use super::defines::{
+    BAO_IOEVENTFD_FLAG_DATAMATCH, BAO_IOEVENTFD_FLAG_DEASSIGN, BAO_IO_ASK, BAO_IO_NOTIFY,
+    BAO_IO_READ, BAO_IO_WRITE, BAO_IRQFD_FLAG_DEASSIGN,
+};
+#[cfg(feature = "std")]
+use super::error::{Error, Result};
+use bitflags::bitflags;
+use core::fmt;
+use core::mem::{align_of, offset_of, size_of};
+#[cfg(feature = "std")]
 use serde::{Deserialize, Serialize};
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+#[cfg(not(feature = "std"))]
+pub use self::core_error::{Error, Result};
+
+/// Errors of the core types, when built without the standard library.
+#[cfg(not(feature = "std"))]
+mod core_error {
+    use core::fmt;
+
+    /// Enum representing the core types errors (a subset of `error::Error`).
+    ///
+    /// # Variants
+    ///
+    /// * `InvalidIoReqDirection` - Unknown I/O request operation.
+    /// * `InvalidAccessWidth` - Unsupported I/O request access width.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[non_exhaustive]
+    pub enum Error {
+        InvalidIoReqDirection(u64),
+        InvalidAccessWidth(u64),
+    }
+
+    impl fmt::Display for Error {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                Error::InvalidIoReqDirection(op) => {
+                    write!(f, "Invalid IO Request Direction: {:?}", op)
+                }
+                Error::InvalidAccessWidth(width) => {
+                    write!(f, "Invalid IO Request Access Width: {:?}", width)
+                }
+            }
+        }
+    }
+
+    /// Result of the core types.
+    pub type Result<T> = core::result::Result<T, Error>;
+}
+
+/// Defines a transparent newtype over a raw identifier or address.
+macro_rules! bao_newtype {
+    ($(#[$meta:meta])* $name:ident($inner:ty), $fmt:literal) => {
+        $(#[$meta])*
+        #[repr(transparent)]
+        #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        #[cfg_attr(feature = "std", derive(Deserialize, Serialize), serde(transparent))]
+        pub struct $name(pub $inner);
+
+        impl $name {
+            /// Returns the raw value.
+            pub fn raw(&self) -> $inner {
+                self.0
+            }
+        }
+
+        impl From<$inner> for $name {
+            fn from(value: $inner) -> Self {
+                $name(value)
+            }
+        }
+
+        impl From<$name> for $inner {
+            fn from(value: $name) -> Self {
+                value.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, $fmt, self.0)
+            }
+        }
+    };
+}
+
+bao_newtype!(
+    /// VM (frontend or guest) identifier.
+    VmId(u32),
+    "{}"
+);
+bao_newtype!(
+    /// Device identifier.
+    DeviceId(u32),
+    "{}"
+);
+bao_newtype!(
+    /// Interrupt line (SPI) number.
+    IrqLine(u32),
+    "{}"
+);
+bao_newtype!(
+    /// Guest physical address.
+    GuestAddress(u64),
+    "{:#x}"
+);
+
+#[cfg(feature = "std")]
+/// Compact binary encoding of the Bao ABI structures.
+///
+/// Every field is encoded in order, little-endian and without padding, so request
+/// streams can be captured to disk and replayed on any host.
+pub trait AbiEncode: Sized {
+    /// Size of the encoded structure, in bytes.
+    const ENCODED_SIZE: usize;
+
+    /// Appends the encoded structure to a buffer.
+    ///
+    /// # Arguments
+    ///
+    /// * `buf` - The buffer.
+    fn encode(&self, buf: &mut Vec<u8>);
+
+    /// Decodes a structure.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The encoded structure (exactly `ENCODED_SIZE` bytes).
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Self>` - The decoded structure, or `InvalidEncoding` on a length mismatch.
+    fn decode(bytes: &[u8]) -> Result<Self>;
+}
+
+/// Implements `AbiEncode` for a structure made of integer fields.
+macro_rules! abi_encoding {
+    ($name:ident { $($field:ident: $ty:ty),* $(,)? }) => {
+        #[cfg(feature = "std")]
+        impl AbiEncode for $name {
+            const ENCODED_SIZE: usize = 0 $(+ size_of::<$ty>())*;
+
+            fn encode(&self, buf: &mut Vec<u8>) {
+                $(buf.extend_from_slice(&self.$field.to_le_bytes());)*
+            }
+
+            fn decode(bytes: &[u8]) -> Result<Self> {
+                if bytes.len() != Self::ENCODED_SIZE {
+                    return Err(Error::InvalidEncoding(stringify!($name), bytes.len()));
+                }
+                let mut offset = 0;
+                $(
+                    let size = size_of::<$ty>();
+                    let $field = <$ty>::from_le_bytes(
+                        bytes[offset..offset + size].try_into().unwrap(),
+                    );
+                    offset += size;
+                )*
+                debug_assert_eq!(offset, Self::ENCODED_SIZE);
+                Ok($name { $($field),* })
+            }
+        }
+    };
+}
 
 /// Struct representing a Bao I/O request.
 ///
@@ -23,7 +189,8 @@ use serde::{Deserialize, Serialize};
 /// * `vcpu_id` - Frontend vCPU ID of the I/O request.
 /// * `ret` - Return value.
 #[repr(C)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsBytes, FromBytes, FromZeroes)]
+#[cfg_attr(feature = "std", derive(Deserialize, Serialize))]
 pub struct BaoIoRequest {
     pub virtio_id: u64,
     pub reg_off: u64,
@@ -36,6 +203,139 @@ pub struct BaoIoRequest {
     pub ret: u64,
 }
 
+impl BaoIoRequest {
+    /// Returns the typed operation of the request.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<IoOp>` - The operation, or `InvalidIoReqDirection` if unknown.
+    pub fn io_op(&self) -> Result<IoOp> {
+        IoOp::try_from(self.op)
+    }
+
+    /// Returns the typed access width of the request.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<AccessWidth>` - The access width, or `InvalidAccessWidth` if unsupported.
+    pub fn width(&self) -> Result<AccessWidth> {
+        AccessWidth::try_from(self.access_width)
+    }
+}
+
+/// Enum representing the operation of a Bao I/O request.
+///
+/// # Variants
+///
+/// * `Write` - Guest write access.
+/// * `Read` - Guest read access.
+/// * `Ask` - Request for the next pending I/O request.
+/// * `Notify` - Notification of a pending I/O request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoOp {
+    Write,
+    Read,
+    Ask,
+    Notify,
+}
+
+impl TryFrom<u64> for IoOp {
+    type Error = Error;
+
+    fn try_from(op: u64) -> Result<Self> {
+        match op {
+            BAO_IO_WRITE => Ok(IoOp::Write),
+            BAO_IO_READ => Ok(IoOp::Read),
+            BAO_IO_ASK => Ok(IoOp::Ask),
+            BAO_IO_NOTIFY => Ok(IoOp::Notify),
+            _ => Err(Error::InvalidIoReqDirection(op)),
+        }
+    }
+}
+
+impl From<IoOp> for u64 {
+    fn from(op: IoOp) -> Self {
+        match op {
+            IoOp::Write => BAO_IO_WRITE,
+            IoOp::Read => BAO_IO_READ,
+            IoOp::Ask => BAO_IO_ASK,
+            IoOp::Notify => BAO_IO_NOTIFY,
+        }
+    }
+}
+
+/// Enum representing the access width of a Bao I/O request.
+///
+/// # Variants
+///
+/// * `Byte` - 8-bit access.
+/// * `Half` - 16-bit access.
+/// * `Word` - 32-bit access.
+/// * `Double` - 64-bit access.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessWidth {
+    Byte,
+    Half,
+    Word,
+    Double,
+}
+
+impl AccessWidth {
+    /// Returns the access width in bytes.
+    ///
+    /// # Returns
+    ///
+    /// * `usize` - The number of bytes accessed.
+    pub fn bytes(&self) -> usize {
+        match self {
+            AccessWidth::Byte => 1,
+            AccessWidth::Half => 2,
+            AccessWidth::Word => 4,
+            AccessWidth::Double => 8,
+        }
+    }
+}
+
+impl TryFrom<u64> for AccessWidth {
+    type Error = Error;
+
+    fn try_from(width: u64) -> Result<Self> {
+        match width {
+            1 => Ok(AccessWidth::Byte),
+            2 => Ok(AccessWidth::Half),
+            4 => Ok(AccessWidth::Word),
+            8 => Ok(AccessWidth::Double),
+            _ => Err(Error::InvalidAccessWidth(width)),
+        }
+    }
+}
+
+impl From<AccessWidth> for u64 {
+    fn from(width: AccessWidth) -> Self {
+        width.bytes() as u64
+    }
+}
+
+bitflags! {
+    /// Flags of a Bao I/O event file descriptor.
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+    pub struct IoEventFdFlags: u32 {
+        /// Only signal the eventfd when the written value matches `data`.
+        const DATAMATCH = BAO_IOEVENTFD_FLAG_DATAMATCH;
+        /// Deassign the eventfd.
+        const DEASSIGN = BAO_IOEVENTFD_FLAG_DEASSIGN;
+    }
+}
+
+bitflags! {
+    /// Flags of a Bao IRQ file descriptor (assign if empty).
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+    pub struct IrqFdFlags: u32 {
+        /// Deassign the irqfd.
+        const DEASSIGN = BAO_IRQFD_FLAG_DEASSIGN;
+    }
+}
+
 /// Struct representing a Bao I/O event file descriptor.
 ///
 /// # Attributes
@@ -47,6 +347,8 @@ pub struct BaoIoRequest {
 /// * `reserved` - Reserved.
 /// * `data` - Datamatch.
 #[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsBytes, FromBytes, FromZeroes)]
+#[cfg_attr(feature = "std", derive(Deserialize, Serialize))]
 pub struct BaoIoEventFd {
     pub fd: u32,
     pub flags: u32,
@@ -56,6 +358,41 @@ pub struct BaoIoEventFd {
     pub data: u64,
 }
 
+impl BaoIoEventFd {
+    /// Creates a new I/O event file descriptor request.
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - Eventfd file descriptor.
+    /// * `flags` - Flags.
+    /// * `addr` - Guest address to watch.
+    /// * `len` - Access length.
+    /// * `data` - Datamatch value (used with `IoEventFdFlags::DATAMATCH`).
+    ///
+    /// # Returns
+    ///
+    /// * `BaoIoEventFd` - The I/O event file descriptor request.
+    pub fn new(fd: i32, flags: IoEventFdFlags, addr: GuestAddress, len: u32, data: u64) -> Self {
+        BaoIoEventFd {
+            fd: fd as u32,
+            flags: flags.bits(),
+            addr: addr.raw(),
+            len,
+            reserved: 0,
+            data,
+        }
+    }
+
+    /// Returns the typed flags.
+    ///
+    /// # Returns
+    ///
+    /// * `IoEventFdFlags` - The flags (unknown bits are retained).
+    pub fn flags(&self) -> IoEventFdFlags {
+        IoEventFdFlags::from_bits_retain(self.flags)
+    }
+}
+
 /// Struct representing a Bao IRQ file descriptor.
 ///
 /// # Attributes
@@ -63,72 +400,224 @@ pub struct BaoIoEventFd {
 /// * `fd` - File descriptor.
 /// * `flags` - Flags.
 #[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, AsBytes, FromBytes, FromZeroes)]
+#[cfg_attr(feature = "std", derive(Deserialize, Serialize))]
 pub struct BaoIrqFd {
     pub fd: i32,
     pub flags: u32,
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
-/// Struct representing a Bao device configuration.
-///
-/// # Attributes
-///
-/// * `name` - Device name.
-/// * `id` - Device ID.
-/// * `type` - Device type.
-/// * `irq` - Device IRQ.
-/// * `addr` - Device address.
-pub struct ConfigDevice {
-    pub name: String,
-    pub id: u32,
-    #[serde(rename = "type")]
-    pub device_type: String,
-    pub irq: u32,
-    pub addr: u64,
-}
+impl BaoIrqFd {
+    /// Creates a new IRQ file descriptor request.
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - Eventfd file descriptor.
+    /// * `flags` - Flags.
+    ///
+    /// # Returns
+    ///
+    /// * `BaoIrqFd` - The IRQ file descriptor request.
+    pub fn new(fd: i32, flags: IrqFdFlags) -> Self {
+        BaoIrqFd {
+            fd,
+            flags: flags.bits(),
+        }
+    }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
-/// Struct representing a Bao guest configuration.
-///
-/// # Attributes
-///
-/// * `name` - Guest name.
-/// * `id` - Guest ID.
-/// * `ram_addr` - Guest RAM address.
-/// * `ram_size` - Guest RAM size.
-/// * `shmem_path` - Guest shared memory path.
-/// * `socket_path` - Guest socket path.
-/// * `devices` - Guest devices.
-pub struct ConfigGuest {
-    pub name: String,
-    pub id: u32,
-    pub ram_addr: u64,
-    pub ram_size: u64,
-    pub shmem_path: String,
-    pub socket_path: String,
-    pub devices: Vec<ConfigDevice>,
-}
-
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
-/// Struct representing a Bao frontend configuration.
-///
-/// # Attributes
-///
-/// * `name` - Frontend name.
-/// * `id` - Frontend ID.
-/// * `guests` - Frontend guests.
-pub struct ConfigFrontend {
-    pub name: String,
-    pub id: u32,
-    pub guests: Vec<ConfigGuest>,
+    /// Returns the typed flags.
+    ///
+    /// # Returns
+    ///
+    /// * `IrqFdFlags` - The flags (unknown bits are retained).
+    pub fn flags(&self) -> IrqFdFlags {
+        IrqFdFlags::from_bits_retain(self.flags)
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
-/// Struct representing a Bao frontends configuration.
-///
-/// # Attributes
-///
-/// * `frontends` - Frontends.
-pub struct ConfigFrontends {
-    pub frontends: Vec<ConfigFrontend>,
+// Bao runs on Armv8, Armv7 and RISC-V boards, and x86_64 hosts build the frontend for
+// simulation and tests. Other targets may lay the structures below out, or encode the
+// ioctls, differently from the Bao kernel module, so they are refused outright.
+#[cfg(not(any(
+    target_arch = "aarch64",
+    target_arch = "arm",
+    target_arch = "riscv32",
+    target_arch = "riscv64",
+    target_arch = "x86_64"
+)))]
+compile_error!(
+    "unsupported target architecture (Bao supports aarch64, arm, riscv32, riscv64 and x86_64)"
+);
+
+// Layout of the structures shared with the Bao kernel module (no implicit padding). It is
+// the same on every supported target: 64-bit fields are 8-byte aligned on the 32-bit ones
+// as well (AAPCS and the RISC-V ILP32 ABI).
+const _: () = {
+    assert!(size_of::<BaoIoRequest>() == 72);
+    assert!(align_of::<BaoIoRequest>() == 8);
+    assert!(offset_of!(BaoIoRequest, virtio_id) == 0);
+    assert!(offset_of!(BaoIoRequest, reg_off) == 8);
+    assert!(offset_of!(BaoIoRequest, addr) == 16);
+    assert!(offset_of!(BaoIoRequest, op) == 24);
+    assert!(offset_of!(BaoIoRequest, value) == 32);
+    assert!(offset_of!(BaoIoRequest, access_width) == 40);
+    assert!(offset_of!(BaoIoRequest, cpu_id) == 48);
+    assert!(offset_of!(BaoIoRequest, vcpu_id) == 56);
+    assert!(offset_of!(BaoIoRequest, ret) == 64);
+
+    assert!(size_of::<BaoIoEventFd>() == 32);
+    assert!(align_of::<BaoIoEventFd>() == 8);
+    assert!(offset_of!(BaoIoEventFd, fd) == 0);
+    assert!(offset_of!(BaoIoEventFd, flags) == 4);
+    assert!(offset_of!(BaoIoEventFd, addr) == 8);
+    assert!(offset_of!(BaoIoEventFd, len) == 16);
+    assert!(offset_of!(BaoIoEventFd, reserved) == 20);
+    assert!(offset_of!(BaoIoEventFd, data) == 24);
+
+    assert!(size_of::<BaoIrqFd>() == 8);
+    assert!(align_of::<BaoIrqFd>() == 4);
+    assert!(offset_of!(BaoIrqFd, fd) == 0);
+    assert!(offset_of!(BaoIrqFd, flags) == 4);
+};
+
+abi_encoding!(BaoIoRequest {
+    virtio_id: u64,
+    reg_off: u64,
+    addr: u64,
+    op: u64,
+    value: u64,
+    access_width: u64,
+    cpu_id: u64,
+    vcpu_id: u64,
+    ret: u64,
+});
+abi_encoding!(BaoIoEventFd {
+    fd: u32,
+    flags: u32,
+    addr: u64,
+    len: u32,
+    reserved: u32,
+    data: u64,
+});
+abi_encoding!(BaoIrqFd {
+    fd: i32,
+    flags: u32
+});
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::defines::BAO_IRQFD_FLAG_ASSIGN;
+
+    #[test]
+    fn test_io_request_typed_accessors() {
+        let mut req = BaoIoRequest {
+            virtio_id: 0,
+            reg_off: 0x70,
+            addr: 0xa003e70,
+            op: BAO_IO_READ,
+            value: 0,
+            access_width: 4,
+            cpu_id: 0,
+            vcpu_id: 0,
+            ret: 0,
+        };
+        assert_eq!(req.io_op().unwrap(), IoOp::Read);
+        assert_eq!(req.width().unwrap(), AccessWidth::Word);
+        assert_eq!(req.width().unwrap().bytes(), 4);
+
+        req.op = 4;
+        req.access_width = 3;
+        assert!(matches!(req.io_op(), Err(Error::InvalidIoReqDirection(4))));
+        assert!(matches!(req.width(), Err(Error::InvalidAccessWidth(3))));
+
+        for op in [IoOp::Write, IoOp::Read, IoOp::Ask, IoOp::Notify] {
+            assert_eq!(IoOp::try_from(u64::from(op)).unwrap(), op);
+        }
+        for width in [
+            AccessWidth::Byte,
+            AccessWidth::Half,
+            AccessWidth::Word,
+            AccessWidth::Double,
+        ] {
+            assert_eq!(AccessWidth::try_from(u64::from(width)).unwrap(), width);
+        }
+    }
+
+    #[test]
+    fn test_newtype_display() {
+        assert_eq!(GuestAddress(0xa003e00).to_string(), "0xa003e00");
+        assert_eq!(IrqLine(47).to_string(), "47");
+        assert_eq!(u32::from(VmId::from(1)), 1);
+    }
+
+    #[test]
+    fn test_fd_flags_abi_values() {
+        let ioeventfd = BaoIoEventFd::new(
+            5,
+            IoEventFdFlags::DATAMATCH | IoEventFdFlags::DEASSIGN,
+            GuestAddress(0xa003e50),
+            4,
+            1,
+        );
+        assert_eq!(ioeventfd.flags, 0b110);
+        assert!(ioeventfd.flags().contains(IoEventFdFlags::DATAMATCH));
+
+        let irqfd = BaoIrqFd::new(6, IrqFdFlags::empty());
+        assert_eq!(irqfd.flags, BAO_IRQFD_FLAG_ASSIGN);
+        let irqfd = BaoIrqFd::new(6, IrqFdFlags::DEASSIGN);
+        assert_eq!(irqfd.flags, BAO_IRQFD_FLAG_DEASSIGN);
+    }
+
+    #[test]
+    #[cfg(feature = "std")]
+    fn test_abi_encoding_roundtrip() {
+        let req = BaoIoRequest {
+            virtio_id: 1,
+            reg_off: 0x50,
+            addr: 0xa003e50,
+            op: BAO_IO_WRITE,
+            value: 0xdead_beef,
+            access_width: 4,
+            cpu_id: 2,
+            vcpu_id: 3,
+            ret: 0,
+        };
+        let ioeventfd = BaoIoEventFd::new(5, IoEventFdFlags::DATAMATCH, GuestAddress(0x50), 4, 1);
+        let irqfd = BaoIrqFd::new(-1, IrqFdFlags::DEASSIGN);
+
+        let mut buf = Vec::new();
+        req.encode(&mut buf);
+        assert_eq!(buf.len(), BaoIoRequest::ENCODED_SIZE);
+        assert_eq!(&buf[32..40], &0xdead_beef_u64.to_le_bytes());
+        assert_eq!(BaoIoRequest::decode(&buf).unwrap(), req);
+
+        buf.clear();
+        ioeventfd.encode(&mut buf);
+        assert_eq!(BaoIoEventFd::decode(&buf).unwrap(), ioeventfd);
+        buf.clear();
+        irqfd.encode(&mut buf);
+        assert_eq!(BaoIrqFd::decode(&buf).unwrap(), irqfd);
+
+        assert!(matches!(
+            BaoIoRequest::decode(&buf),
+            Err(Error::InvalidEncoding("BaoIoRequest", 8))
+        ));
+
+        let yaml = serde_yaml::to_string(&req).unwrap();
+        assert_eq!(serde_yaml::from_str::<BaoIoRequest>(&yaml).unwrap(), req);
+    }
+
+    #[test]
+    fn test_zerocopy_views() {
+        let irqfd = BaoIrqFd::new(7, IrqFdFlags::DEASSIGN);
+        let bytes = irqfd.as_bytes();
+        assert_eq!(bytes.len(), size_of::<BaoIrqFd>());
+        assert_eq!(BaoIrqFd::read_from(bytes).unwrap(), irqfd);
+
+        let mut req = BaoIoRequest::new_zeroed();
+        req.as_bytes_mut()[..8].copy_from_slice(&3u64.to_ne_bytes());
+        assert_eq!(req.virtio_id, 3);
+        assert!(BaoIoRequest::read_from(bytes).is_none());
+    }
 }
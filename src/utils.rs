@@ -7,23 +7,37 @@
 
 #![allow(dead_code)]
 
+use super::diagnostics::{check_config, has_errors, ConfigDiagnostics};
+use super::dtb::derive_devices;
+use super::error::{self, Error};
+use super::init::validate_dependencies;
+use super::platform::Platform;
+use super::secrets::resolve_secrets;
 use super::types::*;
-use clap::{App, Arg};
+use super::version::VersionReport;
+use super::virtio_ids::DeviceType;
+use clap::{CommandFactory, Parser};
+use clap_complete::generate;
+use clap_mangen::Man;
 use std::env;
-use std::fs::File;
-use std::io::Read;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::io::AsRawFd;
+
+/// Name of the installed binary (completed by the shell completion scripts).
+const BIN_NAME: &str = "bao-sys";
 
 /// Represents a collection of ParamKey.
 ///
 /// # Attributes
 ///
 /// * `VmId` - Frontend ID
-/// * `DevId` - Device Compatibility ID (e.g. 22 for I2C)
+/// * `DevId` - Device Compatibility ID (virtio device ID, or device type name, e.g. `i2c`)
 /// * `DevIrq` - Device IRQ
 /// * `DevAddr` - Device Address
 /// * `RamAddr` - RAM Address
 /// * `RamSize` - RAM Size
-#[derive(Clone)]
+#[derive(Clone, Copy)]
 enum ParamKey {
     VmId = 0,
     DevId,
@@ -33,6 +47,25 @@ enum ParamKey {
     RamSize,
 }
 
+/// Parses a parameter value (device IDs may be given by their device type name).
+///
+/// # Arguments
+///
+/// * `key` - The parameter key.
+/// * `value` - The value.
+///
+/// # Returns
+///
+/// * `Option<u64>` - The value, or None if it is invalid.
+fn parse_value(key: ParamKey, value: &str) -> Option<u64> {
+    match key {
+        ParamKey::DevId => DeviceType::from_name(value)
+            .map(|t| u64::from(t.to_virtio_id()))
+            .or_else(|| value.parse().ok()),
+        _ => value.parse().ok(),
+    }
+}
+
 /// Function to transpose a matrix.
 ///
 /// # Arguments
@@ -69,29 +102,41 @@ fn transpose_matrix(matrix: &Vec<Vec<u64>>) -> Vec<Vec<u64>> {
 ///
 /// # Examples
 ///
-/// $ bao-vhost-frontend vm_id=0 dev_id=22 dev_irq=47 dev_addr=167788032 ram_addr=1476395008 ram_size=16777216
+/// $ bao-vhost-frontend vm_id=0 dev_id=i2c dev_irq=47 dev_addr=167788032 ram_addr=1476395008 ram_size=16777216
 ///
-/// $ bao-vhost-frontend vm_id=0,1 dev_id=22,29 dev_irq=47,46 dev_addr=167788032,167787520 ram_addr=1476395008,1493172224 ram_size=16777216,16777216
+/// $ bao-vhost-frontend vm_id=0,1 dev_id=i2c,gpio dev_irq=47,46 dev_addr=167788032,167787520 ram_addr=1476395008,1493172224 ram_size=16777216,16777216
 pub fn parse_command_line_arguments() -> Option<Vec<Vec<u64>>> {
-    // Initialize the parameters
-    let mut parameters: Vec<Vec<u64>> = Vec::new();
+    // Get the environment command line arguments (skipping the executable name)
+    let args = env::args().skip(1).collect::<Vec<String>>();
 
-    // Get the environment command line arguments
-    let args = env::args().collect::<Vec<String>>();
+    // Parse the parameters
+    parse_parameters(&args)
+}
 
-    // Pop the first argument (executable name)
-    let args = args[1..].to_vec();
+/// Parses a list of `key=value[,value...]` parameters.
+///
+/// Every key must be given exactly once and in order (`vm_id`, `dev_id`, `dev_irq`,
+/// `dev_addr`, `ram_addr`, `ram_size`), each with the same number of values.
+///
+/// # Arguments
+///
+/// * `args` - The parameters.
+///
+/// # Returns
+///
+/// * `Option<Vec<Vec<u64>>>` - A vector of tuples containing the parameters, or None if
+///   the parameters are invalid.
+pub fn parse_parameters<S: AsRef<str>>(args: &[S]) -> Option<Vec<Vec<u64>>> {
+    // Initialize the parameters
+    let mut parameters: Vec<Vec<u64>> = Vec::new();
 
     // Parse the parameters string
     for arg in args.iter() {
         // Split the parameter into key and value
-        let parts: Vec<&str> = arg.split('=').collect();
-        if parts.len() != 2 {
-            return None; // Invalid format
-        }
+        let (key, value) = arg.as_ref().split_once('=')?;
 
         // Update the key
-        let key = match parts[0] {
+        let key = match key {
             "vm_id" => ParamKey::VmId,
             "dev_id" => ParamKey::DevId,
             "dev_irq" => ParamKey::DevIrq,
@@ -101,48 +146,26 @@ pub fn parse_command_line_arguments() -> Option<Vec<Vec<u64>>> {
             _ => return None, // Unknown key
         };
 
-        // Update the value
-        let value: Option<Vec<u64>> = match key {
-            ParamKey::VmId
-            | ParamKey::DevId
-            | ParamKey::DevIrq
-            | ParamKey::DevAddr
-            | ParamKey::RamAddr
-            | ParamKey::RamSize => {
-                // Split the value into parts
-                let value_parts: Vec<u64> =
-                    parts[1].split(',').filter_map(|s| s.parse().ok()).collect();
-                // Check if the value is empty
-                if value_parts.is_empty() {
-                    return None; // Invalid range format
-                }
-                // Return the value
-                Some(value_parts)
-            }
-        };
-
-        // Clone the key and check if the index > length
-        let key_index = key.clone() as usize;
-        if key_index > parameters.len() {
+        // Only the next parameter is accepted (rejecting duplicated or out of order keys)
+        if key as usize != parameters.len() {
             return None;
         }
+
+        // Split the value into parts (every part must be a number)
+        let value_parts = value
+            .split(',')
+            .map(|s| parse_value(key, s))
+            .collect::<Option<Vec<u64>>>()?;
+
         // Update the corresponding parameter
-        parameters.insert(key as usize, value.unwrap().clone());
+        parameters.push(value_parts);
     }
 
     // Check if all parameters are present and with the same length
     if parameters.len() != 6
-        || parameters[ParamKey::VmId as usize].is_empty()
-        || parameters[ParamKey::DevId as usize].is_empty()
-        || parameters[ParamKey::DevIrq as usize].is_empty()
-        || parameters[ParamKey::DevAddr as usize].is_empty()
-        || parameters[ParamKey::RamAddr as usize].is_empty()
-        || parameters[ParamKey::RamSize as usize].is_empty()
-        || parameters[ParamKey::VmId as usize].len() != parameters[ParamKey::DevId as usize].len()
-        || parameters[ParamKey::VmId as usize].len() != parameters[ParamKey::DevIrq as usize].len()
-        || parameters[ParamKey::VmId as usize].len() != parameters[ParamKey::DevAddr as usize].len()
-        || parameters[ParamKey::VmId as usize].len() != parameters[ParamKey::RamAddr as usize].len()
-        || parameters[ParamKey::VmId as usize].len() != parameters[ParamKey::RamSize as usize].len()
+        || parameters
+            .iter()
+            .any(|p| p.len() != parameters[ParamKey::VmId as usize].len())
     {
         return None;
     }
@@ -162,24 +185,80 @@ pub fn parse_command_line_arguments() -> Option<Vec<Vec<u64>>> {
 ///
 /// # Returns
 ///
-/// * `Result<ConfigFrontends, Box<dyn std::error::Error>>` - A ConfigFrontends struct containing the parsed configuration.
-fn parse_yaml_config_file(file_path: &str) -> Result<ConfigFrontends, Box<dyn std::error::Error>> {
+/// * `error::Result<ConfigFrontends>` - A ConfigFrontends struct containing the parsed configuration,
+///   `ConfigIo` if the file cannot be read, `ConfigParse` if it is not a valid configuration.
+fn parse_yaml_config_file(file_path: &str) -> error::Result<ConfigFrontends> {
+    let unreadable = |e: io::Error| Error::ConfigIo(file_path.to_string(), e);
     // Open the YAML file
-    let mut file = File::open(file_path).unwrap();
+    let mut file = File::open(file_path).map_err(unreadable)?;
     // Read the YAML file
     let mut yaml_content = String::new();
-    file.read_to_string(&mut yaml_content).unwrap();
-    // Parse the YAML file
-    let frontends: ConfigFrontends = serde_yaml::from_str(&yaml_content).unwrap();
-    // Return the configuration
+    file.read_to_string(&mut yaml_content).map_err(unreadable)?;
+    // Parse the YAML file, reporting where the document is invalid
+    let mut frontends = parse_yaml(file_path, &yaml_content)?;
+    // Derive the devices missing from the configuration from the guest device trees, and
+    // label the devices with their guest labels
+    for guest in frontends.frontends.iter_mut().flat_map(|f| &mut f.guests) {
+        derive_devices(guest)?;
+        guest.inherit_labels();
+    }
+    // Resolve the secret references
+    resolve_secrets(&mut frontends)?;
+    // Reject unknown and circular device dependencies
+    validate_dependencies(&frontends)?;
+    // Reject colliding devices, reporting every collision at once
+    let diagnostics = check_config(&frontends);
+    if has_errors(&diagnostics) {
+        return Err(ConfigDiagnostics(diagnostics).into());
+    }
     Ok(frontends)
 }
 
+/// Parses a YAML configuration read from a file.
+///
+/// # Arguments
+///
+/// * `path` - Path the configuration was read from (reported on errors).
+/// * `yaml_content` - A reference to a string containing the YAML configuration.
+///
+/// # Returns
+///
+/// * `error::Result<ConfigFrontends>` - A ConfigFrontends struct containing the parsed configuration,
+///   or `ConfigParse` (line and column being 0 if the position is unknown).
+fn parse_yaml(path: &str, yaml_content: &str) -> error::Result<ConfigFrontends> {
+    serde_yaml::from_str(yaml_content).map_err(|e| {
+        let (line, column) = e
+            .location()
+            .map(|at| (at.line(), at.column()))
+            .unwrap_or_default();
+        Error::ConfigParse {
+            path: path.to_string(),
+            line,
+            column,
+            message: e.to_string(),
+        }
+    })
+}
+
+/// Parses a YAML configuration.
+///
+/// # Arguments
+///
+/// * `yaml_content` - A reference to a string containing the YAML configuration.
+///
+/// # Returns
+///
+/// * `error::Result<ConfigFrontends>` - A ConfigFrontends struct containing the parsed configuration,
+///   or `ConfigParse`.
+pub fn parse_yaml_config(yaml_content: &str) -> error::Result<ConfigFrontends> {
+    parse_yaml("<inline>", yaml_content)
+}
+
 /// Parses the frontend arguments.
 ///
 /// # Returns
 ///
-/// * `Result<ConfigFrontends, Box<dyn std::error::Error>>` - A ConfigFrontends struct containing the parsed configuration.
+/// * `Result<CommandLineArgs, Box<dyn std::error::Error>>` - A CommandLineArgs struct containing the parsed configuration and runtime options.
 ///
 /// # Examples
 ///
@@ -188,33 +267,229 @@ fn parse_yaml_config_file(file_path: &str) -> Result<ConfigFrontends, Box<dyn st
 /// or (short version)
 ///
 /// $ bao-vhost-frontend -c /path/to/your/config.yaml
-pub fn parse_arguments() -> Result<ConfigFrontends, Box<dyn std::error::Error>> {
+///
+/// or (running in the background)
+///
+/// $ bao-vhost-frontend -c /path/to/your/config.yaml --daemon --pidfile /run/bao-frontend.pid
+///
+/// or (rejecting devices that collide with the board reservations)
+///
+/// $ bao-vhost-frontend -c /path/to/your/config.yaml --platform /path/to/board.yaml
+///
+/// or (reporting the configuration diagnostics as JSON, for tooling)
+///
+/// $ bao-vhost-frontend -c /path/to/your/config.yaml --output json
+///
+/// or (every frontend in its own supervised process)
+///
+/// $ bao-vhost-frontend -c /path/to/your/config.yaml --supervise
+///
+/// or (recording the I/O request stream of the simulated devices, then replaying it)
+///
+/// $ bao-vhost-frontend -c /path/to/your/config.yaml --simulate --record /tmp/requests.bin
+///
+/// $ bao-vhost-frontend replay /tmp/requests.bin
+///
+/// or (writing a Chrome/Perfetto trace of every request)
+///
+/// $ bao-vhost-frontend -c /path/to/your/config.yaml --trace /tmp/trace.json
+///
+/// or (exporting the traces and metrics to an OTLP collector, with the `otel` feature)
+///
+/// $ bao-vhost-frontend -c /path/to/your/config.yaml --otlp http://localhost:4317
+///
+/// or (without the Bao module, when built with the `simulate` feature)
+///
+/// $ bao-vhost-frontend -c /path/to/your/config.yaml --simulate
+///
+/// $ bao-vhost-frontend stress -c /path/to/your/config.yaml --device rng0 --requests 100000 --payload 4096 --queue-depth 32
+///
+/// $ bao-vhost-frontend self-test --rounds 64 --payload 256
+///
+/// or (generating the shell completions and the manual page, e.g. when packaging)
+///
+/// $ bao-vhost-frontend completions bash > /usr/share/bash-completion/completions/bao-sys
+///
+/// $ bao-vhost-frontend man > /usr/share/man/man1/bao-sys.1
+///
+/// or (reporting the capabilities of the build, e.g. for a support ticket)
+///
+/// $ bao-vhost-frontend version --abi --output json
+pub fn parse_arguments() -> Result<CommandLineArgs, Box<dyn std::error::Error>> {
     // Get the environment command line arguments
-    let matches = App::new("Bao Vhost Frontend")
-        .arg(
-            Arg::with_name("config")
-                .short('c')
-                .long("config")
-                .value_name("FILE")
-                .help("Sets a custom config file")
-                .takes_value(true)
-                .required(true),
-        )
-        .get_matches();
-
-    // Extract the config file path
-    let config_file = matches.value_of("config").unwrap();
-
-    // Parse the YAML file
-    let frontends = parse_yaml_config_file(config_file)?;
+    let mut args = CommandLineArgs::parse();
+    let output = args.output;
+
+    match args.command.take() {
+        // The completions, the manual page and the version are printed (like the help) to
+        // the standard output
+        Some(FrontendCommand::Completions { shell }) => {
+            generate(
+                shell,
+                &mut CommandLineArgs::command(),
+                BIN_NAME,
+                &mut io::stdout(),
+            );
+            std::process::exit(0);
+        }
+        Some(FrontendCommand::Man) => {
+            Man::new(CommandLineArgs::command()).render(&mut io::stdout())?;
+            std::process::exit(0);
+        }
+        Some(FrontendCommand::Version { abi }) => {
+            if abi {
+                print!("{}", VersionReport::current().render(output));
+            } else {
+                println!("bao-sys {}", env!("CARGO_PKG_VERSION"));
+            }
+            std::process::exit(0);
+        }
+        // A replay does not need a configuration
+        Some(FrontendCommand::Replay { recording }) => {
+            return Ok(CommandLineArgs {
+                replay: Some(recording),
+                output,
+                ..Default::default()
+            });
+        }
+        // A stress test runs against the simulated guests
+        #[cfg(feature = "simulate")]
+        Some(FrontendCommand::Stress {
+            config,
+            options,
+            trace,
+        }) => {
+            return Ok(CommandLineArgs {
+                frontends: parse_yaml_config_file(&config)?,
+                trace,
+                otlp: args.otlp,
+                simulate: true,
+                stress: Some(options),
+                output,
+                ..Default::default()
+            });
+        }
+        // A self-test runs against a built-in simulated guest
+        #[cfg(feature = "simulate")]
+        Some(FrontendCommand::SelfTest { options, trace }) => {
+            return Ok(CommandLineArgs {
+                trace,
+                otlp: args.otlp,
+                simulate: true,
+                self_test: Some(options),
+                output,
+                ..Default::default()
+            });
+        }
+        None => {}
+    }
+
+    // Parse the YAML file (the config file path is required without a subcommand)
+    let config_file = args.config.as_deref().unwrap();
+    args.frontends = parse_yaml_config_file(config_file)?;
+
+    // Reject the devices colliding with the platform reservations
+    if let Some(platform) = &args.platform {
+        Platform::from_file(platform)?.validate(&args.frontends)?;
+    }
 
     // Return the configuration
-    Ok(frontends)
+    Ok(args)
+}
+
+/// Forks the process, terminating the parent.
+///
+/// # Returns
+///
+/// * `Result<()>` - Ok in the child process.
+fn fork_and_exit_parent() -> error::Result<()> {
+    // SAFETY: The frontend daemonizes before spawning any thread.
+    match unsafe { libc::fork() } {
+        -1 => Err(Error::DaemonizeFailed("fork", io::Error::last_os_error())),
+        0 => Ok(()),
+        // SAFETY: `_exit` skips the destructors of the state shared with the child.
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+/// Writes the current process ID to a pidfile.
+///
+/// # Arguments
+///
+/// * `path` - A reference to a string containing the path to the pidfile.
+///
+/// # Returns
+///
+/// * `Result<()>` - Ok if the pidfile was written.
+pub fn write_pidfile(path: &str) -> error::Result<()> {
+    let mut file = File::create(path).map_err(Error::PidFileFailed)?;
+    writeln!(file, "{}", std::process::id()).map_err(Error::PidFileFailed)
+}
+
+/// Detaches the frontend from the controlling terminal and runs it in the background.
+///
+/// Performs the classic double-fork: the first child becomes a session leader (`setsid`)
+/// and the second child, which can never reacquire a terminal, carries on with its
+/// standard file descriptors redirected to `/dev/null`. Must be called before any
+/// thread is spawned.
+///
+/// # Arguments
+///
+/// * `pidfile` - Optional path to the file where the daemon process ID is written.
+///
+/// # Returns
+///
+/// * `Result<()>` - Ok in the daemon process (the intermediate processes exit).
+pub fn daemonize(pidfile: Option<&str>) -> error::Result<()> {
+    // Create the pidfile while still in the working directory (a relative path is
+    // relative to it) and attached to the terminal (a failure is still reported)
+    let pidfile = pidfile
+        .map(|path| File::create(path).map_err(Error::PidFileFailed))
+        .transpose()?;
+
+    // First fork (the parent returns to the shell or init script)
+    fork_and_exit_parent()?;
+
+    // Detach from the controlling terminal
+    // SAFETY: `setsid` has no memory safety requirements.
+    if unsafe { libc::setsid() } < 0 {
+        return Err(Error::DaemonizeFailed("setsid", io::Error::last_os_error()));
+    }
+
+    // Second fork (the session leader exits)
+    fork_and_exit_parent()?;
+
+    // Reset the file mode mask and the working directory
+    // SAFETY: `umask` has no memory safety requirements.
+    unsafe { libc::umask(0o022) };
+    env::set_current_dir("/").map_err(|e| Error::DaemonizeFailed("chdir", e))?;
+
+    // Redirect the standard file descriptors
+    let null = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open("/dev/null")
+        .map_err(|e| Error::OpenFdFailed("/dev/null", e))?;
+    for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        // SAFETY: Both file descriptors are valid.
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } < 0 {
+            return Err(Error::DaemonizeFailed("dup2", io::Error::last_os_error()));
+        }
+    }
+
+    // Write the process ID of the daemon
+    if let Some(mut file) = pidfile {
+        writeln!(file, "{}", std::process::id()).map_err(Error::PidFileFailed)?;
+    }
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::diagnostics::OutputFormat;
+    use clap_complete::Shell;
 
     /// Parses the parameters string.
     ///
@@ -257,8 +532,10 @@ mod tests {
                 | ParamKey::RamAddr
                 | ParamKey::RamSize => {
                     // Split the value into parts
-                    let value_parts: Vec<u64> =
-                        parts[1].split('-').filter_map(|s| s.parse().ok()).collect();
+                    let value_parts: Vec<u64> = parts[1]
+                        .split('-')
+                        .filter_map(|s| parse_value(key, s))
+                        .collect();
                     // Check if the value is empty
                     if value_parts.is_empty() {
                         return None; // Invalid range format
@@ -269,7 +546,7 @@ mod tests {
             };
 
             // Clone the key and check if the index > length
-            let key_index = key.clone() as usize;
+            let key_index = key as usize;
             if key_index > parameters.len() {
                 return None;
             }
@@ -390,6 +667,72 @@ mod tests {
         assert!(parsed.is_none());
     }
 
+    #[test]
+    fn test_parse_parameters_device_type_names() {
+        let params =
+            "vm_id=0-1,dev_id=i2c-41,dev_irq=47-46,dev_addr=167788032-167787520,ram_addr=1476395008-1493172224,ram_size=16777216-16777216";
+        let parsed = parse_string_parameters(params).unwrap();
+        assert_eq!(parsed[ParamKey::DevId as usize], vec![34, 41]);
+
+        // Only device IDs may be given by name
+        let args = [
+            "vm_id=0",
+            "dev_id=gpio",
+            "dev_irq=rng",
+            "dev_addr=167788032",
+            "ram_addr=1476395008",
+            "ram_size=16777216",
+        ];
+        assert_eq!(parse_parameters(&args), None);
+        let args = [
+            "vm_id=0",
+            "dev_id=gpio",
+            "dev_irq=47",
+            "dev_addr=167788032",
+            "ram_addr=1476395008",
+            "ram_size=16777216",
+        ];
+        assert_eq!(
+            parse_parameters(&args),
+            Some(vec![vec![0, 41, 47, 167788032, 1476395008, 16777216]])
+        );
+    }
+
+    #[test]
+    fn test_parse_command_line_parameters() {
+        let args = [
+            "vm_id=0,1",
+            "dev_id=22,29",
+            "dev_irq=47,46",
+            "dev_addr=167788032,167787520",
+            "ram_addr=1476395008,1493172224",
+            "ram_size=16777216,16777216",
+        ];
+        assert_eq!(
+            parse_parameters(&args),
+            Some(vec![
+                vec![0, 22, 47, 167788032, 1476395008, 16777216],
+                vec![1, 29, 46, 167787520, 1493172224, 16777216]
+            ])
+        );
+
+        // Duplicated key
+        let mut duplicated = args.to_vec();
+        duplicated.insert(1, "vm_id=2,3");
+        assert!(parse_parameters(&duplicated).is_none());
+        // Key given before the previous ones
+        let mut reordered = args.to_vec();
+        reordered.swap(2, 3);
+        assert!(parse_parameters(&reordered).is_none());
+        // Partially invalid value
+        let mut invalid = args.to_vec();
+        invalid[0] = "vm_id=0,x";
+        assert!(parse_parameters(&invalid).is_none());
+        // Missing value
+        assert!(parse_parameters(&["vm_id"]).is_none());
+        assert!(parse_parameters::<&str>(&[]).is_none());
+    }
+
     #[test]
     fn test_transpose() {
         let matrix: Vec<Vec<u64>> = vec![
@@ -448,42 +791,162 @@ mod tests {
         let expected_frontends = ConfigFrontends {
             frontends: vec![ConfigFrontend {
                 name: "frontend0".to_string(),
-                id: 0,
+                id: VmId(0),
+                control: None,
                 guests: vec![
                     ConfigGuest {
                         name: "guest0".to_string(),
-                        id: 0,
-                        ram_addr: 0x60000000,
+                        id: VmId(0),
+                        ram_addr: GuestAddress(0x60000000),
                         ram_size: 0x01000000,
                         shmem_path: "/dev/baoipc0".to_string(),
                         socket_path: "/root/".to_string(),
                         devices: vec![ConfigDevice {
                             name: "device0".to_string(),
-                            id: 0,
+                            id: DeviceId(0),
                             device_type: "rng".to_string(),
-                            irq: 0x2f,
-                            addr: 0xa003e00,
+                            irq: IrqLine(0x2f),
+                            addr: GuestAddress(0xa003e00),
+                            ..Default::default()
                         }],
+                        ..Default::default()
                     },
                     ConfigGuest {
                         name: "guest1".to_string(),
-                        id: 1,
-                        ram_addr: 0x61000000,
+                        id: VmId(1),
+                        ram_addr: GuestAddress(0x61000000),
                         ram_size: 0x01000000,
                         shmem_path: "/dev/baoipc0".to_string(),
                         socket_path: "/root/".to_string(),
                         devices: vec![ConfigDevice {
                             name: "device1".to_string(),
-                            id: 1,
+                            id: DeviceId(1),
                             device_type: "i2c".to_string(),
-                            irq: 0x2e,
-                            addr: 0xa003c00,
+                            irq: IrqLine(0x2e),
+                            addr: GuestAddress(0xa003c00),
+                            ..Default::default()
                         }],
+                        ..Default::default()
                     },
                 ],
             }],
+            ..Default::default()
         };
 
         assert_eq!(frontends, expected_frontends);
     }
+
+    #[test]
+    fn test_parse_yaml_restart_policy() {
+        let yaml_content = r#"
+        name: "device0"
+        id: 0
+        type: "i2c"
+        irq: 0x2e
+        addr: 0xa003c00
+        restart: on-failure
+        restart_delay_ms: 200
+        restart_max_delay_ms: 1000
+    "#;
+        let device: ConfigDevice = serde_yaml::from_str(yaml_content).unwrap();
+        assert_eq!(device.restart, RestartPolicy::OnFailure);
+        assert!(device.restart.should_restart(true));
+        assert!(!device.restart.should_restart(false));
+        assert_eq!(device.restart_delay(0).as_millis(), 200);
+        assert_eq!(device.restart_delay(2).as_millis(), 800);
+        assert_eq!(device.restart_delay(3).as_millis(), 1000);
+        assert_eq!(device.restart_delay(64).as_millis(), 1000);
+
+        // Policy defaults to 'never' when not specified
+        let yaml_content = r#"
+        name: "device0"
+        id: 0
+        type: "rng"
+        irq: 0x2f
+        addr: 0xa003e00
+    "#;
+        let device: ConfigDevice = serde_yaml::from_str(yaml_content).unwrap();
+        assert_eq!(device.restart, RestartPolicy::Never);
+        assert!(!device.restart.should_restart(true));
+    }
+
+    #[test]
+    fn test_parse_yaml_config_file_errors() {
+        use crate::diagnostics::Diagnostic;
+
+        let err = parse_yaml_config_file("/nonexistent/config.yaml").unwrap_err();
+        assert!(matches!(err, Error::ConfigIo(..)));
+        assert_eq!(err.errno(), libc::ENOENT);
+        assert_eq!(err.exit_code(), 78);
+
+        let path = env::temp_dir().join(format!("bao-config-{}.yaml", std::process::id()));
+        let path_str = path.to_str().unwrap();
+        std::fs::write(&path, "frontends:\n  - name: frontend0\n    id: zero\n").unwrap();
+        let err = parse_yaml_config_file(path_str).unwrap_err();
+        match &err {
+            Error::ConfigParse {
+                path, line, column, ..
+            } => assert_eq!((path.as_str(), *line, *column), (path_str, 3, 9)),
+            e => panic!("unexpected error {:?}", e),
+        }
+        assert_eq!(
+            Diagnostic::from_error(&err)[0].location,
+            Some(format!("{}:3:9", path_str))
+        );
+        assert!(matches!(
+            parse_yaml_config("frontends: 0"),
+            Err(Error::ConfigParse { .. })
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_command_line_interface() {
+        CommandLineArgs::command().debug_assert();
+
+        let args = CommandLineArgs::try_parse_from([
+            "bao-sys",
+            "-c",
+            "config.yaml",
+            "--daemon",
+            "--output",
+            "json",
+        ])
+        .unwrap();
+        assert_eq!(args.config.as_deref(), Some("config.yaml"));
+        assert!(args.daemon);
+        assert_eq!(args.output, OutputFormat::Json);
+        assert!(CommandLineArgs::try_parse_from(["bao-sys"]).is_err());
+
+        // The subcommands do not need a configuration
+        let args =
+            CommandLineArgs::try_parse_from(["bao-sys", "replay", "requests.bin", "--output=json"])
+                .unwrap();
+        assert_eq!(
+            args.command,
+            Some(FrontendCommand::Replay {
+                recording: "requests.bin".to_string()
+            })
+        );
+        assert_eq!(args.output, OutputFormat::Json);
+
+        let mut bash = Vec::new();
+        generate(
+            Shell::Bash,
+            &mut CommandLineArgs::command(),
+            BIN_NAME,
+            &mut bash,
+        );
+        let bash = String::from_utf8(bash).unwrap();
+        assert!(bash.contains("--config"));
+        assert!(bash.contains("completions"));
+
+        let mut man = Vec::new();
+        Man::new(CommandLineArgs::command())
+            .render(&mut man)
+            .unwrap();
+        let man = String::from_utf8(man).unwrap();
+        assert!(man.contains(".TH bao-sys 1"));
+        assert!(man.contains("Sets a custom config file"));
+    }
 }
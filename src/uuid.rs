@@ -0,0 +1,149 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao stable device identifiers.
+//!
+//! Device names and IDs change when the configuration is reorganized, which breaks the
+//! dashboards and scripts keyed on them. Every device has a UUID instead: the `uuid` of
+//! its configuration if pinned there, or one derived from its guest ID and name (a
+//! version 8 UUID of their 128-bit FNV-1a hash), so renumbering the devices keeps it.
+//! The UUID labels the log messages, metrics and statistics of the device, is accepted
+//! in place of its name by the control commands, and matches snapshots with devices.
+
+#![allow(dead_code)]
+
+use super::error::{Error, Result};
+use super::types::VmId;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+
+/// 128-bit FNV-1a offset basis and prime.
+const FNV_OFFSET_BASIS: u128 = 0x6c62272e07bb014262b821756295c58d;
+const FNV_PRIME: u128 = 0x0000000001000000000000000000013b;
+
+/// Struct representing the UUID of a device.
+#[derive(
+    Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize,
+)]
+#[serde(try_from = "String", into = "String")]
+pub struct DeviceUuid(pub u128);
+
+impl DeviceUuid {
+    /// Derives the UUID of a device from its guest and name.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_id` - Guest ID.
+    /// * `name` - Device name.
+    ///
+    /// # Returns
+    ///
+    /// * `DeviceUuid` - The UUID (version 8, RFC 9562 variant).
+    pub fn derive(guest_id: VmId, name: &str) -> Self {
+        let key = format!("bao:{}/{}", guest_id, name);
+        let hash = key.bytes().fold(FNV_OFFSET_BASIS, |hash, byte| {
+            (hash ^ u128::from(byte)).wrapping_mul(FNV_PRIME)
+        });
+        let versioned = (hash & !(0xf << 76)) | (0x8 << 76);
+        DeviceUuid((versioned & !(0x3 << 62)) | (0x2 << 62))
+    }
+
+    /// Checks if the UUID is the nil UUID (e.g. that of a device snapshotted before
+    /// devices had one).
+    pub fn is_nil(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl fmt::Display for DeviceUuid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hex = format!("{:032x}", self.0);
+        write!(
+            f,
+            "{}-{}-{}-{}-{}",
+            &hex[..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..]
+        )
+    }
+}
+
+impl FromStr for DeviceUuid {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::InvalidUuid(s.to_string());
+        let groups = s.split('-').map(str::len).collect::<Vec<_>>();
+        if groups != [8, 4, 4, 4, 12] {
+            return Err(invalid());
+        }
+        let hex = s.replace('-', "");
+        if !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(invalid());
+        }
+        u128::from_str_radix(&hex, 16)
+            .map(DeviceUuid)
+            .map_err(|_| invalid())
+    }
+}
+
+impl TryFrom<String> for DeviceUuid {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<DeviceUuid> for String {
+    fn from(uuid: DeviceUuid) -> Self {
+        uuid.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_uuid() {
+        let uuid = DeviceUuid::derive(VmId(1), "rng0");
+        assert_eq!(uuid, DeviceUuid::derive(VmId(1), "rng0"));
+        assert_ne!(uuid, DeviceUuid::derive(VmId(2), "rng0"));
+        assert_ne!(uuid, DeviceUuid::derive(VmId(1), "rng1"));
+
+        // Version 8, RFC 9562 variant
+        let text = uuid.to_string();
+        assert_eq!(text.len(), 36);
+        assert_eq!(&text[14..15], "8");
+        assert!(matches!(&text[19..20], "8" | "9" | "a" | "b"));
+        assert_eq!(text.parse::<DeviceUuid>().unwrap(), uuid);
+        assert_eq!(
+            serde_yaml::from_str::<DeviceUuid>(&serde_yaml::to_string(&uuid).unwrap()).unwrap(),
+            uuid
+        );
+
+        assert_eq!(
+            DeviceUuid(0x0123456789abcdef0123456789abcdef).to_string(),
+            "01234567-89ab-cdef-0123-456789abcdef"
+        );
+        for text in [
+            "",
+            "rng0",
+            "0123456789abcdef0123456789abcdef",
+            "01234567-89ab-cdef-0123-456789abcdeg",
+            "+1234567-89ab-cdef-0123-456789abcdef",
+        ] {
+            assert!(matches!(
+                text.parse::<DeviceUuid>(),
+                Err(Error::InvalidUuid(_))
+            ));
+        }
+        assert!(serde_yaml::from_str::<DeviceUuid>("rng0").is_err());
+    }
+}
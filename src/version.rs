@@ -0,0 +1,201 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao version report.
+//!
+//! `bao-sys version --abi` prints what the binary was built with and what it can talk to:
+//! the crate version, the configuration schema and kernel ABI versions, the versions of
+//! the files and protocols it reads and writes, the device types, the built-in backends
+//! and the compiled-in features. Attached to a support ticket, it identifies the build
+//! without ambiguity.
+
+#![allow(dead_code)]
+
+use super::defines::{
+    BAO_AUDIT_VERSION, BAO_CONFIG_SCHEMA_VERSIONS, BAO_HANDOFF_VERSION, BAO_KERNEL_ABI_VERSIONS,
+    BAO_RECORD_VERSION, BAO_SNAPSHOT_VERSION, VHOST_USER_VERSION, VIRTIO_MMIO_VERSION_2,
+};
+use super::diagnostics::OutputFormat;
+use super::stats_file::json_string;
+use super::virtio_ids::DeviceType;
+
+/// Struct representing the capabilities of the binary.
+///
+/// # Attributes
+///
+/// * `version` - Crate version.
+/// * `config_schemas` - Supported configuration schema versions.
+/// * `kernel_abis` - Supported kernel ABI versions (I/O dispatcher).
+/// * `protocols` - Versions of the file formats and protocols, by name.
+/// * `device_types` - Device types that can be configured.
+/// * `backends` - Built-in device backends.
+/// * `features` - Compiled-in features.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VersionReport {
+    pub version: &'static str,
+    pub config_schemas: Vec<u32>,
+    pub kernel_abis: Vec<u32>,
+    pub protocols: Vec<(&'static str, u64)>,
+    pub device_types: Vec<&'static str>,
+    pub backends: Vec<&'static str>,
+    pub features: Vec<&'static str>,
+}
+
+impl VersionReport {
+    /// Describes the running binary.
+    ///
+    /// # Returns
+    ///
+    /// * `VersionReport` - The report.
+    pub fn current() -> Self {
+        // The vhost-user backends run out of process, the others are built in
+        let backends = [
+            ("vhost-user", true),
+            ("simulated", cfg!(feature = "simulate")),
+            ("loopback", cfg!(feature = "simulate")),
+            ("fake-vhost-user", cfg!(feature = "test-support")),
+        ];
+        let features = [
+            ("std", cfg!(feature = "std")),
+            ("ffi", cfg!(feature = "ffi")),
+            ("python", cfg!(feature = "python")),
+            ("grpc", cfg!(feature = "grpc")),
+            ("dbus", cfg!(feature = "dbus")),
+            ("otel", cfg!(feature = "otel")),
+            ("test-support", cfg!(feature = "test-support")),
+            ("simulate", cfg!(feature = "simulate")),
+            ("fault-injection", cfg!(feature = "fault-injection")),
+        ];
+        let enabled = |items: &[(&'static str, bool)]| {
+            items
+                .iter()
+                .filter(|(_, enabled)| *enabled)
+                .map(|(name, _)| *name)
+                .collect()
+        };
+        VersionReport {
+            version: env!("CARGO_PKG_VERSION"),
+            config_schemas: BAO_CONFIG_SCHEMA_VERSIONS.to_vec(),
+            kernel_abis: BAO_KERNEL_ABI_VERSIONS.to_vec(),
+            protocols: vec![
+                ("virtio-mmio", VIRTIO_MMIO_VERSION_2),
+                ("vhost-user", VHOST_USER_VERSION as u64),
+                ("snapshot", BAO_SNAPSHOT_VERSION as u64),
+                ("recording", BAO_RECORD_VERSION as u64),
+                ("audit-log", BAO_AUDIT_VERSION as u64),
+                ("live-update", BAO_HANDOFF_VERSION as u64),
+            ],
+            device_types: DeviceType::ALL.iter().map(|t| t.name()).collect(),
+            backends: enabled(&backends),
+            features: enabled(&features),
+        }
+    }
+
+    /// Renders the report as aligned `key: value` lines.
+    pub fn render_human(&self) -> String {
+        let join = |versions: &[u32]| {
+            versions
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        let protocols = self
+            .protocols
+            .iter()
+            .map(|(name, version)| format!("{} {}", name, version))
+            .collect::<Vec<_>>();
+        format!(
+            "bao-sys {}\n\
+             config schema:  {}\n\
+             kernel ABI:     {}\n\
+             protocols:      {}\n\
+             device types:   {}\n\
+             backends:       {}\n\
+             features:       {}\n",
+            self.version,
+            join(&self.config_schemas),
+            join(&self.kernel_abis),
+            protocols.join(", "),
+            self.device_types.join(", "),
+            self.backends.join(", "),
+            self.features.join(", ")
+        )
+    }
+
+    /// Encodes the report as a JSON object (on a single line).
+    pub fn to_json(&self) -> String {
+        let numbers = |versions: &[u32]| {
+            versions
+                .iter()
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        let strings = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| json_string(name))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        let protocols = self
+            .protocols
+            .iter()
+            .map(|(name, version)| format!("{}:{}", json_string(name), version))
+            .collect::<Vec<_>>();
+        format!(
+            "{{\"version\":{},\"config_schemas\":[{}],\"kernel_abis\":[{}],\"protocols\":{{{}}},\"device_types\":[{}],\"backends\":[{}],\"features\":[{}]}}\n",
+            json_string(self.version),
+            numbers(&self.config_schemas),
+            numbers(&self.kernel_abis),
+            protocols.join(","),
+            strings(&self.device_types),
+            strings(&self.backends),
+            strings(&self.features)
+        )
+    }
+
+    /// Renders the report in a format.
+    ///
+    /// # Arguments
+    ///
+    /// * `format` - The format.
+    ///
+    /// # Returns
+    ///
+    /// * `String` - The rendered report.
+    pub fn render(&self, format: OutputFormat) -> String {
+        match format {
+            OutputFormat::Human => self.render_human(),
+            OutputFormat::Json => self.to_json(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_version_report() {
+        let report = VersionReport::current();
+        assert!(report.features.contains(&"std"));
+        assert_eq!(report.backends[0], "vhost-user");
+        assert!(report.device_types.contains(&"i2c"));
+
+        let human = report.render(OutputFormat::Human);
+        assert!(human.starts_with(&format!("bao-sys {}\n", env!("CARGO_PKG_VERSION"))));
+        assert!(human.contains("kernel ABI:     1\n"));
+
+        let json = report.render(OutputFormat::Json);
+        assert!(json.starts_with(&format!(
+            "{{\"version\":\"{}\",\"config_schemas\":[1],\"kernel_abis\":[1],\"protocols\":{{\"virtio-mmio\":2,",
+            env!("CARGO_PKG_VERSION")
+        )));
+        assert!(json.contains("\"features\":[\"std\""));
+        assert!(json.ends_with("]}\n") && json.matches('\n').count() == 1);
+    }
+}
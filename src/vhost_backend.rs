@@ -0,0 +1,1067 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao vhost-user backend server.
+//!
+//! A minimal vhost-user backend, hosted on a thread, that negotiates features, maps the
+//! memory table and hands every available descriptor chain to a device model
+//! (`VirtioDevice`). The built-in block backend and the fake backend of the tests are
+//! served this way.
+//!
+//! Each kick eventfd is read by its own receiver thread, which hands the kicks to the
+//! connection worker over a per-queue SPSC ring (`spsc`), so the kicks of several queues
+//! never contend on a lock. In `hybrid` poll mode, a receiver keeps polling its kick
+//! eventfd for a window after a kick (see `EventPoller`) before it sleeps again.
+
+#![allow(dead_code)]
+
+use super::backend::InProcessBackend;
+use super::defines::*;
+use super::error::{Error, Result};
+use super::memory::{Le16, Le32, VirtqDesc, VirtqUsedElem};
+use super::poll::EventPoller;
+use super::spsc::{self, Consumer};
+use super::types::ConfigDevice;
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{fence, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+use vmm_sys_util::eventfd::EventFd;
+
+/// A device model served by a vhost-user backend.
+pub trait VirtioDevice: Send {
+    /// Returns the virtio features offered by the device.
+    fn features(&self) -> u64;
+
+    /// Returns the number of virtqueues.
+    fn num_queues(&self) -> usize;
+
+    /// Returns the configuration space of the device (none if empty).
+    fn config(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Serves a descriptor chain.
+    ///
+    /// # Arguments
+    ///
+    /// * `readable` - The device-readable buffers of the chain, gathered.
+    /// * `writable` - The device-writable buffers of the chain, gathered (zeroed).
+    ///
+    /// # Returns
+    ///
+    /// * `u32` - The number of bytes written, from the start of `writable`.
+    fn process(&mut self, readable: &[u8], writable: &mut [u8]) -> u32;
+
+    /// Serves a descriptor chain of a given queue, for the devices whose queues carry
+    /// messages that cannot be told apart (see `process` otherwise).
+    ///
+    /// # Arguments
+    ///
+    /// * `queue` - Index of the queue.
+    /// * `readable` - The device-readable buffers of the chain, gathered.
+    /// * `writable` - The device-writable buffers of the chain, gathered (zeroed).
+    ///
+    /// # Returns
+    ///
+    /// * `u32` - The number of bytes written, from the start of `writable`, or
+    ///   `BAO_CHAIN_DEFERRED` to leave the chain (and those after it) available until the
+    ///   next kick (e.g. event buffers, returned once there is an event).
+    fn process_chain(&mut self, queue: usize, readable: &[u8], writable: &mut [u8]) -> u32 {
+        let _ = queue;
+        self.process(readable, writable)
+    }
+}
+
+// A device model shared with its management (e.g. to be resized while it is served)
+impl<D: VirtioDevice> VirtioDevice for Arc<Mutex<D>> {
+    fn features(&self) -> u64 {
+        self.lock().unwrap().features()
+    }
+
+    fn num_queues(&self) -> usize {
+        self.lock().unwrap().num_queues()
+    }
+
+    fn config(&self) -> Vec<u8> {
+        self.lock().unwrap().config()
+    }
+
+    fn process(&mut self, readable: &[u8], writable: &mut [u8]) -> u32 {
+        self.lock().unwrap().process(readable, writable)
+    }
+
+    fn process_chain(&mut self, queue: usize, readable: &[u8], writable: &mut [u8]) -> u32 {
+        self.lock()
+            .unwrap()
+            .process_chain(queue, readable, writable)
+    }
+}
+
+/// Struct representing a guest memory region shared by the frontend.
+///
+/// # Attributes
+///
+/// * `guest_phys_addr` - Guest physical address of the region.
+/// * `memory_size` - Size of the region.
+/// * `userspace_addr` - Frontend virtual address of the region.
+/// * `mmap_offset` - Offset of the region in its file descriptor.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub guest_phys_addr: u64,
+    pub memory_size: u64,
+    pub userspace_addr: u64,
+    pub mmap_offset: u64,
+}
+
+/// Struct representing the state of a virtqueue.
+///
+/// # Attributes
+///
+/// * `num` - Queue size.
+/// * `last_avail` - Next available ring entry to process.
+/// * `used_idx` - Next used ring entry to fill.
+/// * `desc` - Frontend virtual address of the descriptor table.
+/// * `avail` - Frontend virtual address of the available ring.
+/// * `used` - Frontend virtual address of the used ring.
+/// * `enabled` - Whether the queue is enabled.
+/// * `avail_idx` - Available ring index last read from the guest.
+/// * `processed` - Number of descriptor chains returned to the guest.
+/// * `heads` - Heads of the last `BAO_QUEUE_HEADS` chains returned, indexed by
+///   `processed` modulo their number.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VringState {
+    pub num: u16,
+    pub last_avail: u16,
+    pub used_idx: u16,
+    pub desc: u64,
+    pub avail: u64,
+    pub used: u64,
+    pub enabled: bool,
+    pub avail_idx: u16,
+    pub processed: u64,
+    pub heads: [u16; BAO_QUEUE_HEADS],
+}
+
+impl VringState {
+    /// Records the head of a descriptor chain returned to the guest.
+    ///
+    /// # Arguments
+    ///
+    /// * `head` - Head descriptor index of the chain.
+    fn record_head(&mut self, head: u16) {
+        self.heads[(self.processed % BAO_QUEUE_HEADS as u64) as usize] = head;
+        self.processed += 1;
+    }
+
+    /// Returns the heads of the last descriptor chains returned to the guest.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<u16>` - The heads, oldest first.
+    pub fn last_heads(&self) -> Vec<u16> {
+        let count = self.processed.min(BAO_QUEUE_HEADS as u64);
+        (self.processed - count..self.processed)
+            .map(|index| self.heads[(index % BAO_QUEUE_HEADS as u64) as usize])
+            .collect()
+    }
+}
+
+/// Struct representing the state of a virtqueue, as returned by the `queue-dump` control
+/// command.
+///
+/// The guest stopped making progress if `avail_idx` equals `last_avail` (nothing left to
+/// serve); the backend did if it lags behind.
+///
+/// # Attributes
+///
+/// * `queue` - Queue index.
+/// * `enabled` - Whether the queue is enabled.
+/// * `size` - Negotiated queue size.
+/// * `desc_addr` - Frontend virtual address of the descriptor table.
+/// * `avail_addr` - Frontend virtual address of the available ring.
+/// * `used_addr` - Frontend virtual address of the used ring.
+/// * `avail_idx` - Available ring index last read from the guest.
+/// * `last_avail` - Next available ring entry to process.
+/// * `used_idx` - Next used ring entry to fill.
+/// * `processed` - Number of descriptor chains returned to the guest.
+/// * `heads` - Heads of the last descriptor chains returned, oldest first.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct QueueDump {
+    pub queue: usize,
+    pub enabled: bool,
+    pub size: u16,
+    pub desc_addr: u64,
+    pub avail_addr: u64,
+    pub used_addr: u64,
+    pub avail_idx: u16,
+    pub last_avail: u16,
+    pub used_idx: u16,
+    pub processed: u64,
+    pub heads: Vec<u16>,
+}
+
+/// The virtqueues of a device, as seen by its backend.
+pub trait Queues: Send {
+    /// Returns the state of a virtqueue.
+    ///
+    /// # Arguments
+    ///
+    /// * `queue` - Queue index.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<QueueDump>` - The queue state, `None` if the device has no such queue.
+    fn dump_queue(&self, queue: usize) -> Option<QueueDump>;
+}
+
+/// Struct representing what the backend was told by the frontend.
+///
+/// # Attributes
+///
+/// * `owner` - Whether the frontend took ownership of the backend.
+/// * `acked_features` - Virtio features acknowledged by the frontend.
+/// * `acked_protocol_features` - Vhost-user protocol features acknowledged by the frontend.
+/// * `regions` - Guest memory regions.
+/// * `vrings` - Virtqueues.
+/// * `served` - Number of descriptor chains served.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BackendState {
+    pub owner: bool,
+    pub acked_features: u64,
+    pub acked_protocol_features: u64,
+    pub regions: Vec<MemoryRegion>,
+    pub vrings: Vec<VringState>,
+    pub served: u64,
+}
+
+impl Queues for BackendState {
+    fn dump_queue(&self, queue: usize) -> Option<QueueDump> {
+        let vring = self.vrings.get(queue)?;
+        Some(QueueDump {
+            queue,
+            enabled: vring.enabled,
+            size: vring.num,
+            desc_addr: vring.desc,
+            avail_addr: vring.avail,
+            used_addr: vring.used,
+            avail_idx: vring.avail_idx,
+            last_avail: vring.last_avail,
+            used_idx: vring.used_idx,
+            processed: vring.processed,
+            heads: vring.last_heads(),
+        })
+    }
+}
+
+/// A vhost-user message (request, flags, payload and file descriptors).
+pub type VhostUserMessage = (u32, u32, Vec<u8>, Vec<OwnedFd>);
+
+/// Struct representing a memory region mapped into the backend.
+pub struct Mapping {
+    pub region: MemoryRegion,
+    pub addr: *mut u8,
+    pub len: usize,
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: The mapping was created by `mmap` with this address and length.
+        unsafe { libc::munmap(self.addr as *mut libc::c_void, self.len) };
+    }
+}
+
+/// Struct representing the backend request channel, set up by the frontend to receive
+/// the requests of the backend (e.g. configuration changes).
+#[derive(Debug, Clone, Default)]
+pub struct BackendChannel(Arc<Mutex<Option<UnixStream>>>);
+
+impl BackendChannel {
+    /// Tells the frontend that the configuration space of the device changed, so it
+    /// raises a configuration change interrupt.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<bool>` - True if the frontend was told, false if it set up no backend
+    ///   request channel (the guest then sees the change once it reads the configuration
+    ///   space again).
+    pub fn config_changed(&self) -> Result<bool> {
+        match self.0.lock().unwrap().as_ref() {
+            Some(stream) => {
+                send_message(
+                    stream,
+                    VHOST_USER_BACKEND_CONFIG_CHANGE_MSG,
+                    VHOST_USER_VERSION,
+                    &[],
+                    &[],
+                )?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Sets (or clears) the stream of the channel.
+    fn set(&self, stream: Option<UnixStream>) {
+        *self.0.lock().unwrap() = stream;
+    }
+}
+
+/// Struct representing a vhost-user backend serving a device model.
+///
+/// # Attributes
+///
+/// * `device` - The device model.
+/// * `features` - Virtio features offered (`VHOST_USER_F_PROTOCOL_FEATURES` is always offered).
+/// * `num_queues` - Number of virtqueues.
+/// * `state` - State shared with the caller.
+/// * `channel` - Backend request channel, shared with the caller.
+/// * `poller` - Hybrid polling of the kick eventfds (each receiver starts from a copy).
+pub struct VhostUserBackend {
+    device: Box<dyn VirtioDevice>,
+    features: u64,
+    num_queues: usize,
+    state: Arc<Mutex<BackendState>>,
+    channel: BackendChannel,
+    poller: EventPoller,
+}
+
+impl VhostUserBackend {
+    /// Creates a vhost-user backend.
+    ///
+    /// # Arguments
+    ///
+    /// * `device` - The device model.
+    ///
+    /// # Returns
+    ///
+    /// * `VhostUserBackend` - The backend.
+    pub fn new(device: Box<dyn VirtioDevice>) -> Self {
+        let num_queues = device.num_queues();
+        VhostUserBackend {
+            features: device.features() | VHOST_USER_F_PROTOCOL_FEATURES,
+            num_queues,
+            device,
+            state: Arc::new(Mutex::new(BackendState {
+                vrings: vec![VringState::default(); num_queues],
+                ..Default::default()
+            })),
+            channel: BackendChannel::default(),
+            poller: EventPoller::new(&ConfigDevice::default()),
+        }
+    }
+
+    /// Sets the hybrid polling of the kick eventfds (none by default).
+    ///
+    /// # Arguments
+    ///
+    /// * `poller` - The polling state (see `EventPoller::new`).
+    ///
+    /// # Returns
+    ///
+    /// * `VhostUserBackend` - The backend.
+    pub fn with_poller(mut self, poller: EventPoller) -> Self {
+        self.poller = poller;
+        self
+    }
+
+    /// Returns the state of the backend, updated while it is served.
+    ///
+    /// # Returns
+    ///
+    /// * `Arc<Mutex<BackendState>>` - The backend state.
+    pub fn state(&self) -> Arc<Mutex<BackendState>> {
+        self.state.clone()
+    }
+
+    /// Returns the backend request channel, set up once the frontend connects.
+    ///
+    /// # Returns
+    ///
+    /// * `BackendChannel` - The channel.
+    pub fn channel(&self) -> BackendChannel {
+        self.channel.clone()
+    }
+}
+
+impl InProcessBackend for VhostUserBackend {
+    fn serve(self: Box<Self>, socket: &Path) -> Result<()> {
+        // Remove any stale socket
+        let _ = fs::remove_file(socket);
+        let listener = UnixListener::bind(socket)?;
+        let (stream, _) = listener.accept()?;
+        Connection {
+            backend: *self,
+            stream,
+            mappings: Vec::new(),
+            kicks: Vec::new(),
+            wake: EventFd::new(libc::EFD_CLOEXEC)?,
+            calls: Vec::new(),
+            readable: Vec::new(),
+            writable: Vec::new(),
+            buffers: Vec::new(),
+        }
+        .run()
+    }
+}
+
+/// Struct representing the kick receiver of a queue.
+///
+/// # Attributes
+///
+/// * `kicks` - Consumer end of the ring the receiver hands the kicks over.
+/// * `stop` - Eventfd stopping the receiver.
+/// * `thread` - Receiver thread.
+struct KickReceiver {
+    kicks: Consumer<u64>,
+    stop: EventFd,
+    thread: Option<JoinHandle<Result<()>>>,
+}
+
+impl KickReceiver {
+    /// Spawns the kick receiver of a queue.
+    ///
+    /// # Arguments
+    ///
+    /// * `kick` - Kick eventfd of the queue.
+    /// * `wake` - Eventfd waking the connection worker.
+    /// * `poller` - Hybrid polling of the kick eventfd.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<KickReceiver>` - The kick receiver.
+    fn spawn(mut kick: File, wake: EventFd, mut poller: EventPoller) -> Result<Self> {
+        let stop = EventFd::new(libc::EFD_CLOEXEC)?;
+        let stopped = stop.try_clone()?;
+        let (mut producer, kicks) = spsc::ring(BAO_KICK_RING_SIZE);
+        let thread = thread::Builder::new()
+            .name("bao-kick".to_string())
+            .spawn(move || loop {
+                let mut pollfds = [kick.as_raw_fd(), stopped.as_raw_fd()].map(|fd| libc::pollfd {
+                    fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                });
+                // SAFETY: `pollfds` holds `pollfds.len()` valid entries.
+                if unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, -1) }
+                    < 0
+                {
+                    let err = io::Error::last_os_error();
+                    if err.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return Err(err.into());
+                }
+                if pollfds[1].revents != 0 {
+                    return Ok(());
+                }
+                if pollfds[0].revents & libc::POLLIN != 0 {
+                    poller.event(kick.as_raw_fd(), Instant::now());
+                    loop {
+                        let mut count = [0u8; 8];
+                        kick.read_exact(&mut count)?;
+                        // A full ring still holds kicks the worker has not served, which
+                        // this one is coalesced with
+                        let _ = producer.push(u64::from_le_bytes(count));
+                        wake.write(1)?;
+                        // A bursty queue is kicked again within its window
+                        if !poller.poll(kick.as_raw_fd())? {
+                            break;
+                        }
+                    }
+                }
+            })?;
+        Ok(KickReceiver {
+            kicks,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Takes the pending kicks of the queue.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<bool>` - True if the queue was kicked, or the error that stopped the
+    ///   receiver.
+    fn kicked(&mut self) -> Result<bool> {
+        let mut kicked = false;
+        while self.kicks.pop().is_some() {
+            kicked = true;
+        }
+        // The receiver only stops on its own on an error
+        if self.kicks.is_abandoned() {
+            if let Some(thread) = self.thread.take() {
+                thread.join().unwrap()?;
+            }
+        }
+        Ok(kicked)
+    }
+}
+
+impl Drop for KickReceiver {
+    fn drop(&mut self) {
+        let _ = self.stop.write(1);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Struct representing the connection of a backend with a frontend.
+struct Connection {
+    backend: VhostUserBackend,
+    stream: UnixStream,
+    mappings: Vec<Mapping>,
+    // Kick receivers (one per queue) and the eventfd they wake the worker with
+    kicks: Vec<Option<KickReceiver>>,
+    wake: EventFd,
+    calls: Vec<Option<File>>,
+    // Gathered chain buffers, reused so serving does not allocate in steady state
+    readable: Vec<u8>,
+    writable: Vec<u8>,
+    buffers: Vec<(*mut u8, usize)>,
+}
+
+impl Connection {
+    /// Serves the frontend until it disconnects.
+    fn run(mut self) -> Result<()> {
+        self.calls = (0..self.backend.num_queues).map(|_| None).collect();
+        self.kicks = (0..self.backend.num_queues).map(|_| None).collect();
+        loop {
+            // Wait for a message or a kick
+            let mut pollfds =
+                [self.stream.as_raw_fd(), self.wake.as_raw_fd()].map(|fd| libc::pollfd {
+                    fd,
+                    events: libc::POLLIN,
+                    revents: 0,
+                });
+            // SAFETY: `pollfds` holds `pollfds.len()` valid entries.
+            if unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, -1) } < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err.into());
+            }
+
+            // Handle the frontend messages first (e.g. a queue being enabled), the kicks
+            // stay pending until the next iteration
+            if pollfds[0].revents & (libc::POLLIN | libc::POLLHUP) != 0 {
+                match recv_message(&self.stream)? {
+                    Some((request, flags, payload, fds)) => {
+                        self.handle_message(request, flags, &payload, fds)?
+                    }
+                    None => {
+                        self.backend.channel.set(None);
+                        return Ok(());
+                    }
+                }
+                continue;
+            }
+
+            // Serve the kicked queues
+            if pollfds[1].revents & libc::POLLIN != 0 {
+                self.wake.read()?;
+                for queue in 0..self.kicks.len() {
+                    let kicked = match &mut self.kicks[queue] {
+                        Some(receiver) => receiver.kicked()?,
+                        None => false,
+                    };
+                    if kicked {
+                        self.process_queue(queue)?;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handles a frontend message.
+    fn handle_message(
+        &mut self,
+        request: u32,
+        flags: u32,
+        payload: &[u8],
+        mut fds: Vec<OwnedFd>,
+    ) -> Result<()> {
+        let invalid = || Error::InvalidVhostUserMessage(request);
+        let mut state = self.backend.state.lock().unwrap();
+        match request {
+            VHOST_USER_GET_FEATURES => {
+                drop(state);
+                return self.reply(request, &self.backend.features.to_le_bytes());
+            }
+            VHOST_USER_GET_PROTOCOL_FEATURES => {
+                drop(state);
+                let mut features = VHOST_USER_PROTOCOL_F_MQ | VHOST_USER_PROTOCOL_F_REPLY_ACK;
+                // A configuration space may change, which is told over the request channel
+                if !self.backend.device.config().is_empty() {
+                    features |= VHOST_USER_PROTOCOL_F_CONFIG | VHOST_USER_PROTOCOL_F_BACKEND_REQ;
+                }
+                return self.reply(request, &features.to_le_bytes());
+            }
+            VHOST_USER_GET_QUEUE_NUM => {
+                drop(state);
+                return self.reply(request, &(self.backend.num_queues as u64).to_le_bytes());
+            }
+            VHOST_USER_GET_CONFIG => {
+                drop(state);
+                let header = payload
+                    .get(..VHOST_USER_CONFIG_HEADER_SIZE)
+                    .ok_or_else(invalid)?;
+                let offset = read_u32(header, 0).ok_or_else(invalid)? as usize;
+                let size = read_u32(header, 4).ok_or_else(invalid)? as usize;
+                if offset + size > VHOST_USER_MAX_CONFIG_SIZE {
+                    return Err(invalid());
+                }
+                // The bytes past the end of the configuration space read as zero
+                let mut config = self.backend.device.config();
+                config.resize(VHOST_USER_MAX_CONFIG_SIZE, 0);
+                let mut reply = header.to_vec();
+                reply.extend_from_slice(&config[offset..offset + size]);
+                return self.reply(request, &reply);
+            }
+            VHOST_USER_GET_VRING_BASE => {
+                let index = read_u32(payload, 0).ok_or_else(invalid)? as usize;
+                let vring = state.vrings.get_mut(index).ok_or_else(invalid)?;
+                vring.enabled = false;
+                let mut reply = (index as u32).to_le_bytes().to_vec();
+                reply.extend_from_slice(&u32::from(vring.last_avail).to_le_bytes());
+                drop(state);
+                self.kicks[index] = None;
+                return self.reply(request, &reply);
+            }
+            VHOST_USER_SET_FEATURES => {
+                state.acked_features = read_u64(payload, 0).ok_or_else(invalid)?;
+            }
+            VHOST_USER_SET_PROTOCOL_FEATURES => {
+                state.acked_protocol_features = read_u64(payload, 0).ok_or_else(invalid)?;
+            }
+            VHOST_USER_SET_OWNER => state.owner = true,
+            VHOST_USER_RESET_OWNER => {
+                *state = BackendState {
+                    vrings: vec![VringState::default(); self.backend.num_queues],
+                    ..Default::default()
+                };
+                self.kicks.iter_mut().for_each(|receiver| *receiver = None);
+                self.mappings.clear();
+                self.backend.channel.set(None);
+            }
+            VHOST_USER_SET_BACKEND_REQ_FD => {
+                let fd = fds.pop().ok_or_else(invalid)?;
+                self.backend.channel.set(Some(UnixStream::from(fd)));
+            }
+            VHOST_USER_SET_MEM_TABLE => {
+                let count = read_u32(payload, 0).ok_or_else(invalid)? as usize;
+                if count != fds.len() {
+                    return Err(invalid());
+                }
+                self.mappings.clear();
+                state.regions.clear();
+                for (index, fd) in fds.drain(..).enumerate() {
+                    let offset = 8 + index * 32;
+                    let region = MemoryRegion {
+                        guest_phys_addr: read_u64(payload, offset).ok_or_else(invalid)?,
+                        memory_size: read_u64(payload, offset + 8).ok_or_else(invalid)?,
+                        userspace_addr: read_u64(payload, offset + 16).ok_or_else(invalid)?,
+                        mmap_offset: read_u64(payload, offset + 24).ok_or_else(invalid)?,
+                    };
+                    self.mappings.push(map_region(region, &fd)?);
+                    state.regions.push(region);
+                }
+            }
+            VHOST_USER_SET_VRING_NUM | VHOST_USER_SET_VRING_BASE | VHOST_USER_SET_VRING_ENABLE => {
+                let index = read_u32(payload, 0).ok_or_else(invalid)? as usize;
+                let value = read_u32(payload, 4).ok_or_else(invalid)?;
+                let vring = state.vrings.get_mut(index).ok_or_else(invalid)?;
+                match request {
+                    VHOST_USER_SET_VRING_NUM => vring.num = value as u16,
+                    VHOST_USER_SET_VRING_BASE => {
+                        vring.last_avail = value as u16;
+                        vring.used_idx = value as u16;
+                    }
+                    _ => vring.enabled = value != 0,
+                }
+            }
+            VHOST_USER_SET_VRING_ADDR => {
+                let index = read_u32(payload, 0).ok_or_else(invalid)? as usize;
+                let vring = state.vrings.get_mut(index).ok_or_else(invalid)?;
+                vring.desc = read_u64(payload, 8).ok_or_else(invalid)?;
+                vring.used = read_u64(payload, 16).ok_or_else(invalid)?;
+                vring.avail = read_u64(payload, 24).ok_or_else(invalid)?;
+            }
+            VHOST_USER_SET_VRING_KICK | VHOST_USER_SET_VRING_CALL => {
+                let value = read_u64(payload, 0).ok_or_else(invalid)?;
+                let index = (value & VHOST_USER_VRING_IDX_MASK) as usize;
+                if index >= self.backend.num_queues {
+                    return Err(invalid());
+                }
+                let file = match value & VHOST_USER_VRING_NOFD_MASK {
+                    0 => Some(File::from(fds.pop().ok_or_else(invalid)?)),
+                    _ => None,
+                };
+                if request == VHOST_USER_SET_VRING_KICK {
+                    // Without the protocol features, a kick starts the queue
+                    if state.acked_features & VHOST_USER_F_PROTOCOL_FEATURES == 0 {
+                        state.vrings[index].enabled = true;
+                    }
+                    self.kicks[index] = None;
+                    self.kicks[index] = file
+                        .map(|kick| {
+                            let poller = self.backend.poller.clone();
+                            KickReceiver::spawn(kick, self.wake.try_clone()?, poller)
+                        })
+                        .transpose()?;
+                } else {
+                    self.calls[index] = file;
+                }
+            }
+            _ => return Err(invalid()),
+        }
+
+        // Acknowledge the message, if requested
+        let reply_ack = state.acked_protocol_features & VHOST_USER_PROTOCOL_F_REPLY_ACK != 0;
+        drop(state);
+        if reply_ack && flags & VHOST_USER_NEED_REPLY_MASK != 0 {
+            self.reply(request, &0u64.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Replies to a frontend message.
+    fn reply(&self, request: u32, payload: &[u8]) -> Result<()> {
+        send_message(
+            &self.stream,
+            request,
+            VHOST_USER_VERSION | VHOST_USER_REPLY_MASK,
+            payload,
+            &[],
+        )
+    }
+
+    /// Translates a frontend virtual (or guest physical) address range into the backend.
+    fn translate(&self, addr: u64, len: u64, guest_phys: bool) -> Option<*mut u8> {
+        self.mappings.iter().find_map(|mapping| {
+            let start = match guest_phys {
+                true => mapping.region.guest_phys_addr,
+                false => mapping.region.userspace_addr,
+            };
+            let offset = addr.checked_sub(start)?;
+            if offset.checked_add(len)? > mapping.region.memory_size {
+                return None;
+            }
+            // SAFETY: The offset is within the mapping.
+            Some(unsafe {
+                mapping
+                    .addr
+                    .add((mapping.region.mmap_offset + offset) as usize)
+            })
+        })
+    }
+
+    /// Serves every available descriptor chain of a queue, then signals the frontend.
+    fn process_queue(&mut self, queue: usize) -> Result<()> {
+        let invalid = || Error::InvalidVhostUserMessage(VHOST_USER_SET_VRING_KICK);
+        let mut vring = self.backend.state.lock().unwrap().vrings[queue];
+        if !vring.enabled || vring.num == 0 {
+            return Ok(());
+        }
+        let num = u64::from(vring.num);
+        let desc = self
+            .translate(vring.desc, 16 * num, false)
+            .ok_or_else(invalid)?;
+        let avail = self
+            .translate(vring.avail, 4 + 2 * num, false)
+            .ok_or_else(invalid)?;
+        let used = self
+            .translate(vring.used, 4 + 8 * num, false)
+            .ok_or_else(invalid)?;
+
+        let mut served = 0;
+        let mut readable = mem::take(&mut self.readable);
+        let mut writable = mem::take(&mut self.writable);
+        let mut buffers = mem::take(&mut self.buffers);
+        loop {
+            // SAFETY: The available ring was translated with its full length.
+            let avail_idx = unsafe { ptr::read_volatile(avail.add(2) as *const Le16) }.get();
+            vring.avail_idx = avail_idx;
+            if avail_idx == vring.last_avail {
+                break;
+            }
+            fence(Ordering::Acquire);
+            let slot = (vring.last_avail % vring.num) as usize;
+            // SAFETY: The slot is within the available ring.
+            let head = unsafe { ptr::read_volatile(avail.add(4 + 2 * slot) as *const Le16) }.get();
+
+            // Gather the readable buffers and collect the writable ones
+            readable.clear();
+            buffers.clear();
+            let mut index = head;
+            for _ in 0..vring.num {
+                if index >= vring.num {
+                    return Err(invalid());
+                }
+                // SAFETY: The descriptor is within the descriptor table.
+                let entry = unsafe {
+                    ptr::read_unaligned(desc.add(16 * index as usize) as *const VirtqDesc)
+                };
+                let (addr, len, flags, next) = (
+                    entry.addr.get(),
+                    entry.len.get(),
+                    entry.flags.get(),
+                    entry.next.get(),
+                );
+                let buffer = self
+                    .translate(addr, u64::from(len), true)
+                    .ok_or_else(invalid)?;
+                if flags & VRING_DESC_F_WRITE == 0 {
+                    // SAFETY: The buffer was translated with its full length.
+                    readable.extend_from_slice(unsafe {
+                        std::slice::from_raw_parts(buffer, len as usize)
+                    });
+                } else {
+                    buffers.push((buffer, len as usize));
+                }
+                if flags & VRING_DESC_F_NEXT == 0 {
+                    break;
+                }
+                index = next;
+            }
+
+            // Serve the chain, then scatter what the device wrote into the writable buffers
+            writable.clear();
+            writable.resize(buffers.iter().map(|(_, len)| len).sum(), 0);
+            let written = self
+                .backend
+                .device
+                .process_chain(queue, &readable, &mut writable);
+            if written == BAO_CHAIN_DEFERRED {
+                break;
+            }
+            let written = (written as usize).min(writable.len());
+            let mut offset = 0;
+            for &(buffer, len) in &buffers {
+                let count = len.min(written - offset);
+                // SAFETY: At most `len` bytes are copied into the buffer.
+                unsafe { ptr::copy_nonoverlapping(writable.as_ptr().add(offset), buffer, count) };
+                offset += count;
+            }
+
+            // Return the chain
+            let slot = (vring.used_idx % vring.num) as usize;
+            let elem = VirtqUsedElem {
+                id: Le32::new(u32::from(head)),
+                len: Le32::new(written as u32),
+            };
+            // SAFETY: The slot and the index are within the used ring.
+            unsafe {
+                ptr::write_unaligned(used.add(4 + 8 * slot) as *mut VirtqUsedElem, elem);
+                fence(Ordering::Release);
+                ptr::write_volatile(
+                    used.add(2) as *mut Le16,
+                    Le16::new(vring.used_idx.wrapping_add(1)),
+                );
+            }
+            vring.used_idx = vring.used_idx.wrapping_add(1);
+            vring.last_avail = vring.last_avail.wrapping_add(1);
+            vring.record_head(head);
+            served += 1;
+        }
+        self.readable = readable;
+        self.writable = writable;
+        self.buffers = buffers;
+
+        {
+            let mut state = self.backend.state.lock().unwrap();
+            state.vrings[queue] = vring;
+            state.served += served;
+        }
+        if served > 0 {
+            if let Some(call) = &mut self.calls[queue] {
+                call.write_all(&1u64.to_le_bytes())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads a little-endian u32 from a message payload.
+pub fn read_u32(payload: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        payload.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+/// Reads a little-endian u64 from a message payload.
+pub fn read_u64(payload: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(
+        payload.get(offset..offset + 8)?.try_into().ok()?,
+    ))
+}
+
+/// Maps a memory region shared by the frontend.
+///
+/// # Arguments
+///
+/// * `region` - The memory region.
+/// * `fd` - The file descriptor backing the region.
+///
+/// # Returns
+///
+/// * `Result<Mapping>` - The mapping, unmapped when dropped.
+pub fn map_region(region: MemoryRegion, fd: &OwnedFd) -> Result<Mapping> {
+    let len = (region.mmap_offset + region.memory_size) as usize;
+    // SAFETY: The file descriptor is valid and the result is checked.
+    let addr = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            fd.as_raw_fd(),
+            0,
+        )
+    };
+    if addr == libc::MAP_FAILED {
+        return Err(Error::MmapGuestMemoryFailed);
+    }
+    Ok(Mapping {
+        region,
+        addr: addr as *mut u8,
+        len,
+    })
+}
+/// Receives a vhost-user message.
+///
+/// # Arguments
+///
+/// * `stream` - The vhost-user connection.
+///
+/// # Returns
+///
+/// * `Result<Option<VhostUserMessage>>` - The message, or None if the peer disconnected.
+pub fn recv_message(stream: &UnixStream) -> Result<Option<VhostUserMessage>> {
+    let mut header = [0u8; VHOST_USER_HEADER_SIZE];
+    let mut iov = libc::iovec {
+        iov_base: header.as_mut_ptr() as *mut libc::c_void,
+        iov_len: header.len(),
+    };
+    let mut control = [0u64; VHOST_USER_MAX_FDS];
+    // SAFETY: An all-zero `msghdr` is valid.
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+    msg.msg_controllen = mem::size_of_val(&control) as _;
+
+    // SAFETY: `msg` points to buffers valid for their advertised lengths.
+    let received = unsafe { libc::recvmsg(stream.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if received < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    if received == 0 {
+        return Ok(None);
+    }
+
+    // Collect the file descriptors
+    let mut fds = Vec::new();
+    // SAFETY: `msg` was filled by `recvmsg`.
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+    while !cmsg.is_null() {
+        // SAFETY: `cmsg` points to a control message header of `msg`.
+        unsafe {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg) as *const RawFd;
+                let count = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize)
+                    / mem::size_of::<RawFd>();
+                for index in 0..count {
+                    fds.push(OwnedFd::from_raw_fd(ptr::read_unaligned(data.add(index))));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+
+    // Read the rest of the header and the payload
+    let mut stream = stream;
+    stream.read_exact(&mut header[received as usize..])?;
+    let request = u32::from_le_bytes(header[0..4].try_into().unwrap());
+    let flags = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let size = u32::from_le_bytes(header[8..12].try_into().unwrap()) as usize;
+    let mut payload = vec![0u8; size];
+    stream.read_exact(&mut payload)?;
+    Ok(Some((request, flags, payload, fds)))
+}
+
+/// Sends a vhost-user message.
+///
+/// # Arguments
+///
+/// * `stream` - The vhost-user connection.
+/// * `request` - The request.
+/// * `flags` - The message flags.
+/// * `payload` - The payload.
+/// * `fds` - The file descriptors passed along with the message.
+///
+/// # Returns
+///
+/// * `Result<()>` - Ok if the message was sent.
+pub fn send_message(
+    stream: &UnixStream,
+    request: u32,
+    flags: u32,
+    payload: &[u8],
+    fds: &[RawFd],
+) -> Result<()> {
+    if fds.len() > VHOST_USER_MAX_FDS {
+        return Err(Error::InvalidVhostUserMessage(request));
+    }
+    let mut buffer = Vec::with_capacity(VHOST_USER_HEADER_SIZE + payload.len());
+    buffer.extend_from_slice(&request.to_le_bytes());
+    buffer.extend_from_slice(&flags.to_le_bytes());
+    buffer.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buffer.extend_from_slice(payload);
+
+    let mut iov = libc::iovec {
+        iov_base: buffer.as_mut_ptr() as *mut libc::c_void,
+        iov_len: buffer.len(),
+    };
+    let mut control = [0u64; VHOST_USER_MAX_FDS];
+    // SAFETY: An all-zero `msghdr` is valid.
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    if !fds.is_empty() {
+        let len = mem::size_of_val(fds) as u32;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        // SAFETY: `CMSG_SPACE` has no memory safety requirements.
+        msg.msg_controllen = unsafe { libc::CMSG_SPACE(len) } as _;
+        // SAFETY: The control buffer is large enough for `VHOST_USER_MAX_FDS` descriptors.
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(len) as _;
+            ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg) as *mut RawFd, fds.len());
+        }
+    }
+
+    // SAFETY: `msg` points to buffers valid for their advertised lengths.
+    let sent = unsafe { libc::sendmsg(stream.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) };
+    if sent < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let mut stream = stream;
+    stream.write_all(&buffer[sent as usize..])?;
+    Ok(())
+}
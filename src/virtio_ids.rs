@@ -0,0 +1,228 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Virtio device IDs.
+//!
+//! The device IDs of the virtio specification (section 5, "Device Types"), as reported
+//! in the `DeviceID` register of the virtio-mmio transport, and their mapping to the
+//! device type names used by the configuration (`type: i2c`) and the command line
+//! (`dev_id=i2c`).
+
+#![allow(dead_code)]
+
+use core::fmt;
+
+/// Virtio Network Device ID
+pub const VIRTIO_ID_NET: u32 = 1;
+/// Virtio Block Device ID
+pub const VIRTIO_ID_BLOCK: u32 = 2;
+/// Virtio Console Device ID
+pub const VIRTIO_ID_CONSOLE: u32 = 3;
+/// Virtio Entropy Source (RNG) Device ID
+pub const VIRTIO_ID_RNG: u32 = 4;
+/// Virtio Memory Balloon Device ID
+pub const VIRTIO_ID_BALLOON: u32 = 5;
+/// Virtio SCSI Host Device ID
+pub const VIRTIO_ID_SCSI: u32 = 8;
+/// Virtio 9P Transport Device ID
+pub const VIRTIO_ID_9P: u32 = 9;
+/// Virtio GPU Device ID
+pub const VIRTIO_ID_GPU: u32 = 16;
+/// Virtio Input Device ID
+pub const VIRTIO_ID_INPUT: u32 = 18;
+/// Virtio Socket Device ID
+pub const VIRTIO_ID_VSOCK: u32 = 19;
+/// Virtio Crypto Device ID
+pub const VIRTIO_ID_CRYPTO: u32 = 20;
+/// Virtio IOMMU Device ID
+pub const VIRTIO_ID_IOMMU: u32 = 23;
+/// Virtio Memory Device ID
+pub const VIRTIO_ID_MEM: u32 = 24;
+/// Virtio Sound Device ID
+pub const VIRTIO_ID_SOUND: u32 = 25;
+/// Virtio File System Device ID
+pub const VIRTIO_ID_FS: u32 = 26;
+/// Virtio Persistent Memory Device ID
+pub const VIRTIO_ID_PMEM: u32 = 27;
+/// Virtio RPMB Device ID
+pub const VIRTIO_ID_RPMB: u32 = 28;
+/// Virtio SCMI Device ID
+pub const VIRTIO_ID_SCMI: u32 = 32;
+/// Virtio I2C Adapter Device ID
+pub const VIRTIO_ID_I2C: u32 = 34;
+/// Virtio CAN Device ID
+pub const VIRTIO_ID_CAN: u32 = 36;
+/// Virtio Bluetooth Device ID
+pub const VIRTIO_ID_BT: u32 = 40;
+/// Virtio GPIO Device ID
+pub const VIRTIO_ID_GPIO: u32 = 41;
+
+/// Enum representing a virtio device type.
+///
+/// # Variants
+///
+/// One variant per `VIRTIO_ID_*` constant, named after the device type.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DeviceType {
+    Net,
+    Block,
+    Console,
+    Rng,
+    Balloon,
+    Scsi,
+    P9,
+    Gpu,
+    Input,
+    Vsock,
+    Crypto,
+    Iommu,
+    Mem,
+    Sound,
+    Fs,
+    Pmem,
+    Rpmb,
+    Scmi,
+    I2c,
+    Can,
+    Bt,
+    Gpio,
+}
+
+impl DeviceType {
+    /// Every device type, in device ID order.
+    pub const ALL: [DeviceType; 22] = [
+        DeviceType::Net,
+        DeviceType::Block,
+        DeviceType::Console,
+        DeviceType::Rng,
+        DeviceType::Balloon,
+        DeviceType::Scsi,
+        DeviceType::P9,
+        DeviceType::Gpu,
+        DeviceType::Input,
+        DeviceType::Vsock,
+        DeviceType::Crypto,
+        DeviceType::Iommu,
+        DeviceType::Mem,
+        DeviceType::Sound,
+        DeviceType::Fs,
+        DeviceType::Pmem,
+        DeviceType::Rpmb,
+        DeviceType::Scmi,
+        DeviceType::I2c,
+        DeviceType::Can,
+        DeviceType::Bt,
+        DeviceType::Gpio,
+    ];
+
+    /// Returns the device type of a virtio device ID.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - Virtio device ID.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<DeviceType>` - The device type, or None if the ID is unknown.
+    pub fn from_virtio_id(id: u32) -> Option<Self> {
+        Self::ALL.into_iter().find(|t| t.to_virtio_id() == id)
+    }
+
+    /// Returns the virtio device ID of the device type.
+    pub const fn to_virtio_id(self) -> u32 {
+        match self {
+            DeviceType::Net => VIRTIO_ID_NET,
+            DeviceType::Block => VIRTIO_ID_BLOCK,
+            DeviceType::Console => VIRTIO_ID_CONSOLE,
+            DeviceType::Rng => VIRTIO_ID_RNG,
+            DeviceType::Balloon => VIRTIO_ID_BALLOON,
+            DeviceType::Scsi => VIRTIO_ID_SCSI,
+            DeviceType::P9 => VIRTIO_ID_9P,
+            DeviceType::Gpu => VIRTIO_ID_GPU,
+            DeviceType::Input => VIRTIO_ID_INPUT,
+            DeviceType::Vsock => VIRTIO_ID_VSOCK,
+            DeviceType::Crypto => VIRTIO_ID_CRYPTO,
+            DeviceType::Iommu => VIRTIO_ID_IOMMU,
+            DeviceType::Mem => VIRTIO_ID_MEM,
+            DeviceType::Sound => VIRTIO_ID_SOUND,
+            DeviceType::Fs => VIRTIO_ID_FS,
+            DeviceType::Pmem => VIRTIO_ID_PMEM,
+            DeviceType::Rpmb => VIRTIO_ID_RPMB,
+            DeviceType::Scmi => VIRTIO_ID_SCMI,
+            DeviceType::I2c => VIRTIO_ID_I2C,
+            DeviceType::Can => VIRTIO_ID_CAN,
+            DeviceType::Bt => VIRTIO_ID_BT,
+            DeviceType::Gpio => VIRTIO_ID_GPIO,
+        }
+    }
+
+    /// Returns the device type of a name.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Device type name (e.g. `i2c`).
+    ///
+    /// # Returns
+    ///
+    /// * `Option<DeviceType>` - The device type, or None if the name is unknown.
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|t| t.name() == name)
+    }
+
+    /// Returns the name of the device type.
+    pub const fn name(self) -> &'static str {
+        match self {
+            DeviceType::Net => "net",
+            DeviceType::Block => "block",
+            DeviceType::Console => "console",
+            DeviceType::Rng => "rng",
+            DeviceType::Balloon => "balloon",
+            DeviceType::Scsi => "scsi",
+            DeviceType::P9 => "9p",
+            DeviceType::Gpu => "gpu",
+            DeviceType::Input => "input",
+            DeviceType::Vsock => "vsock",
+            DeviceType::Crypto => "crypto",
+            DeviceType::Iommu => "iommu",
+            DeviceType::Mem => "mem",
+            DeviceType::Sound => "sound",
+            DeviceType::Fs => "fs",
+            DeviceType::Pmem => "pmem",
+            DeviceType::Rpmb => "rpmb",
+            DeviceType::Scmi => "scmi",
+            DeviceType::I2c => "i2c",
+            DeviceType::Can => "can",
+            DeviceType::Bt => "bt",
+            DeviceType::Gpio => "gpio",
+        }
+    }
+}
+
+impl fmt::Display for DeviceType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_type() {
+        for device_type in DeviceType::ALL {
+            let id = device_type.to_virtio_id();
+            assert_eq!(DeviceType::from_virtio_id(id), Some(device_type));
+            assert_eq!(DeviceType::from_name(device_type.name()), Some(device_type));
+        }
+        assert!(DeviceType::ALL
+            .windows(2)
+            .all(|pair| pair[0].to_virtio_id() < pair[1].to_virtio_id()));
+        assert_eq!(DeviceType::from_virtio_id(22), None);
+        assert_eq!(DeviceType::from_name("i2c"), Some(DeviceType::I2c));
+        assert_eq!(DeviceType::I2c.to_virtio_id(), 34);
+        assert_eq!(DeviceType::P9.to_string(), "9p");
+    }
+}
@@ -0,0 +1,353 @@
+// Copyright (c) Bao Project and Contributors. All rights reserved.
+//          João Peixoto <joaopeixotooficial@gmail.com>
+//
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bao device fragment directory.
+//!
+//! A guest with a `watch_dir` (e.g. `/etc/bao/devices.d`) hot-plugs the device described
+//! by every YAML fragment (`*.yaml` or `*.yml`, one `ConfigDevice` each) dropped into the
+//! directory, and hot-unplugs it once the fragment is deleted, like the drop-in
+//! directories of other daemons. Fragments should be written elsewhere and moved in, so
+//! a partially written file is never picked up.
+//!
+//! The frontend runtime watches the directory of each guest from its own thread (see
+//! `serve`), bringing the backend of a hot-plugged device up and stopping it once the
+//! device is hot-unplugged.
+
+#![allow(dead_code)]
+
+use super::error::{Error, Result};
+use super::management::{DeviceInfo, DeviceRegistry, Management};
+use super::overlay::{apply_overlay, remove_overlay};
+use super::types::{ConfigDevice, ConfigDtOverlay, VmId};
+use std::collections::BTreeMap;
+use std::ffi::{CString, OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::thread::{self, JoinHandle};
+
+/// Events of the fragment directory that may add or remove a device.
+const WATCH_MASK: u32 =
+    libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_DELETE | libc::IN_MOVED_FROM;
+
+/// Size of the fixed part of an inotify event (`struct inotify_event`).
+const EVENT_HEADER_SIZE: usize = 16;
+
+/// Enum representing a change of the fragment directory.
+///
+/// # Variants
+///
+/// * `Added` - A device fragment appeared (or was replaced).
+/// * `Removed` - The fragment of a device was deleted.
+#[derive(Debug, PartialEq)]
+pub enum DeviceEvent {
+    Added(Box<ConfigDevice>),
+    Removed(String),
+}
+
+/// Struct representing the watcher of a device fragment directory.
+///
+/// # Attributes
+///
+/// * `dir` - The fragment directory.
+/// * `inotify` - The inotify instance (non-blocking, to be polled from epoll).
+/// * `fragments` - Name of the device of each fragment file.
+pub struct DeviceWatcher {
+    dir: PathBuf,
+    inotify: OwnedFd,
+    fragments: BTreeMap<OsString, String>,
+}
+
+/// Checks if a file name is a device fragment.
+fn is_fragment(name: &OsStr) -> bool {
+    matches!(
+        Path::new(name).extension().and_then(OsStr::to_str),
+        Some("yaml" | "yml")
+    )
+}
+
+impl DeviceWatcher {
+    /// Starts watching a fragment directory.
+    ///
+    /// # Arguments
+    ///
+    /// * `dir` - The fragment directory.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<DeviceWatcher>` - The watcher.
+    pub fn new(dir: &str) -> Result<Self> {
+        let path = CString::new(dir)
+            .map_err(|_| Error::WatchFailed(io::Error::from(io::ErrorKind::InvalidInput)))?;
+        // SAFETY: `inotify_init1` has no memory safety requirements.
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+        if fd < 0 {
+            return Err(Error::WatchFailed(io::Error::last_os_error()));
+        }
+        // SAFETY: The file descriptor was just created and is owned by nobody else.
+        let inotify = unsafe { OwnedFd::from_raw_fd(fd) };
+        // SAFETY: The path is a valid C string for the duration of the call.
+        if unsafe { libc::inotify_add_watch(fd, path.as_ptr(), WATCH_MASK) } < 0 {
+            return Err(Error::WatchFailed(io::Error::last_os_error()));
+        }
+        Ok(DeviceWatcher {
+            dir: PathBuf::from(dir),
+            inotify,
+            fragments: BTreeMap::new(),
+        })
+    }
+
+    /// Loads the fragments already in the directory.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<DeviceEvent>>` - The devices to add, in file name order.
+    pub fn scan(&mut self) -> Result<Vec<DeviceEvent>> {
+        let mut names = fs::read_dir(&self.dir)
+            .map_err(Error::WatchFailed)?
+            .map(|entry| entry.map(|entry| entry.file_name()))
+            .collect::<io::Result<Vec<_>>>()
+            .map_err(Error::WatchFailed)?;
+        names.sort();
+        let mut events = Vec::new();
+        for name in names {
+            events.extend(self.changed(name, true)?);
+        }
+        Ok(events)
+    }
+
+    /// Reads the pending changes of the directory (without blocking).
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<DeviceEvent>>` - The devices to add or remove.
+    pub fn read_events(&mut self) -> Result<Vec<DeviceEvent>> {
+        let mut events = Vec::new();
+        let mut buffer = [0u8; 4096];
+        loop {
+            // SAFETY: The buffer is valid for writes of its length.
+            let len = unsafe {
+                libc::read(
+                    self.inotify.as_raw_fd(),
+                    buffer.as_mut_ptr() as *mut libc::c_void,
+                    buffer.len(),
+                )
+            };
+            if len < 0 {
+                let err = io::Error::last_os_error();
+                match err.kind() {
+                    io::ErrorKind::WouldBlock => return Ok(events),
+                    io::ErrorKind::Interrupted => continue,
+                    _ => return Err(Error::WatchFailed(err)),
+                }
+            }
+
+            // Each event is a header followed by the NUL-padded file name
+            let mut offset = 0;
+            while offset + EVENT_HEADER_SIZE <= len as usize {
+                let word = |at: usize| {
+                    u32::from_ne_bytes(buffer[offset + at..offset + at + 4].try_into().unwrap())
+                };
+                let mask = word(4);
+                let name_len = word(12) as usize;
+                let name = &buffer[offset + EVENT_HEADER_SIZE..][..name_len];
+                let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name_len)];
+                offset += EVENT_HEADER_SIZE + name_len;
+
+                let added = mask & (libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO) != 0;
+                if mask & WATCH_MASK != 0 && !name.is_empty() {
+                    events.extend(self.changed(OsString::from_vec(name.to_vec()), added)?);
+                }
+            }
+        }
+    }
+
+    /// Accounts a fragment written (or moved in) or deleted (or moved out).
+    fn changed(&mut self, name: OsString, added: bool) -> Result<Vec<DeviceEvent>> {
+        if !is_fragment(&name) {
+            return Ok(Vec::new());
+        }
+        let mut events = Vec::new();
+        // A rewritten fragment replaces its device
+        if let Some(device) = self.fragments.remove(&name) {
+            events.push(DeviceEvent::Removed(device));
+        }
+        if added {
+            let path = self.dir.join(&name);
+            let invalid = |e: String| Error::InvalidDeviceFragment(path.display().to_string(), e);
+            let yaml = fs::read_to_string(&path).map_err(|e| invalid(e.to_string()))?;
+            let device: ConfigDevice =
+                serde_yaml::from_str(&yaml).map_err(|e| invalid(e.to_string()))?;
+            self.fragments.insert(name, device.name.clone());
+            events.push(DeviceEvent::Added(Box::new(device)));
+        }
+        Ok(events)
+    }
+}
+
+impl AsRawFd for DeviceWatcher {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inotify.as_raw_fd()
+    }
+}
+
+/// Watches a fragment directory on a thread, handing every change over.
+///
+/// The fragments already in the directory are handed over first. Changes that cannot be
+/// read (e.g. an invalid fragment) are skipped.
+///
+/// # Arguments
+///
+/// * `dir` - The fragment directory.
+/// * `handle` - Handles a change (e.g. with `apply_device_event`).
+///
+/// # Returns
+///
+/// * `Result<JoinHandle<()>>` - The watcher thread, once the directory is scanned.
+pub fn serve<F>(dir: &str, mut handle: F) -> Result<JoinHandle<()>>
+where
+    F: FnMut(DeviceEvent) + Send + 'static,
+{
+    let mut watcher = DeviceWatcher::new(dir)?;
+    let events = watcher.scan()?;
+    thread::Builder::new()
+        .name("bao-watch".to_string())
+        .spawn(move || {
+            events.into_iter().for_each(&mut handle);
+            loop {
+                let mut pollfd = libc::pollfd {
+                    fd: watcher.as_raw_fd(),
+                    events: libc::POLLIN,
+                    revents: 0,
+                };
+                // SAFETY: `pollfd` is valid for the duration of the call.
+                if unsafe { libc::poll(&mut pollfd, 1, -1) } <= 0 {
+                    continue;
+                }
+                if let Ok(events) = watcher.read_events() {
+                    events.into_iter().for_each(&mut handle);
+                }
+            }
+        })
+        .map_err(Error::WatchFailed)
+}
+
+/// Hot-plugs (or hot-unplugs) the device of a fragment directory change.
+///
+/// # Arguments
+///
+/// * `registry` - The device registry (with its hot-plug handler set).
+/// * `frontend_id` - Frontend ID of the guest.
+/// * `guest_id` - Guest ID.
+/// * `event` - The change.
+/// * `overlay` - Device tree overlay configuration of the guest (the device tree is not
+///   amended if None).
+///
+/// # Returns
+///
+/// * `Result<()>` - Ok once the hot-plug request was accepted.
+pub fn apply_device_event(
+    registry: &DeviceRegistry,
+    frontend_id: VmId,
+    guest_id: VmId,
+    event: &DeviceEvent,
+    overlay: Option<&ConfigDtOverlay>,
+) -> Result<()> {
+    match event {
+        DeviceEvent::Added(device) => {
+            registry.add_device(frontend_id, guest_id, device)?;
+            // Forget the device if it could not be plugged
+            registry
+                .set_device_plugged(&device.name, true)
+                .inspect_err(|_| {
+                    let _ = registry.remove_device(&device.name);
+                })?;
+            // The device is serving: let the guest probe it
+            if let Some(overlay) = overlay {
+                let info = DeviceInfo::new(frontend_id, guest_id, device);
+                apply_overlay(&info, overlay).inspect_err(|_| {
+                    let _ = registry.set_device_plugged(&device.name, false);
+                    let _ = registry.remove_device(&device.name);
+                })?;
+            }
+            Ok(())
+        }
+        DeviceEvent::Removed(name) => {
+            // The guest releases the device before it stops serving
+            if let Some(overlay) = overlay {
+                remove_overlay(name, overlay)?;
+            }
+            registry.set_device_plugged(name, false)?;
+            registry.remove_device(name)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::ConfigFrontends;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_device_fragments() {
+        let dir = std::env::temp_dir().join(format!("bao-devices.d-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let fragment = "{name: rng1, id: 4, type: rng, irq: 48, addr: 0xa003a00}";
+        fs::write(dir.join("rng1.yaml"), fragment).unwrap();
+
+        let registry = DeviceRegistry::new(&ConfigFrontends::default());
+        let plugged = Arc::new(Mutex::new(Vec::new()));
+        let requests = plugged.clone();
+        registry.set_hotplug_handler(Box::new(move |name, plugged| {
+            requests.lock().unwrap().push((name.to_string(), plugged));
+            Ok(())
+        }));
+        let apply = |events: Vec<DeviceEvent>| {
+            for event in &events {
+                apply_device_event(&registry, VmId(0), VmId(1), event, None).unwrap();
+            }
+        };
+
+        // Existing fragments are plugged at startup
+        let mut watcher = DeviceWatcher::new(dir.to_str().unwrap()).unwrap();
+        apply(watcher.scan().unwrap());
+        assert_eq!(registry.devices()[0].name, "rng1");
+
+        // Fragments moved in are plugged, other files are ignored
+        let staging = dir.join("i2c0.tmp");
+        fs::write(
+            &staging,
+            "{name: i2c0, id: 22, type: i2c, irq: 49, addr: 0xa003800}",
+        )
+        .unwrap();
+        fs::rename(&staging, dir.join("i2c0.yml")).unwrap();
+        apply(watcher.read_events().unwrap());
+        assert_eq!(registry.devices()[1].name, "i2c0");
+
+        // Deleted fragments are unplugged
+        fs::remove_file(dir.join("rng1.yaml")).unwrap();
+        apply(watcher.read_events().unwrap());
+        assert_eq!(registry.devices().len(), 1);
+        assert_eq!(
+            *plugged.lock().unwrap(),
+            [
+                ("rng1".to_string(), true),
+                ("i2c0".to_string(), true),
+                ("rng1".to_string(), false)
+            ]
+        );
+
+        // Invalid fragments are reported
+        fs::write(dir.join("bad.yaml"), "{name: bad}").unwrap();
+        assert!(matches!(
+            watcher.read_events(),
+            Err(Error::InvalidDeviceFragment(..))
+        ));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}